@@ -0,0 +1,58 @@
+//! Standalone usage of mqtt packet codecs
+//!
+//! Reads hex encoded packets from command line arguments and prints
+//! decoded packets, for example:
+//!
+//! cargo run --example codec -- 3007000474657374ff c000
+use ntex::util::{ByteString, Bytes};
+use ntex_mqtt::{v3, v5};
+
+fn from_hex(s: &str) -> Option<Bytes> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect::<Option<Vec<u8>>>()
+        .map(Bytes::from)
+}
+
+fn main() {
+    let v3_codec = v3::codec::Codec::new().conformance(v3::codec::Conformance::Lenient);
+    let v5_codec = v5::codec::Codec::new().conformance(v5::codec::Conformance::Lenient);
+
+    let mut args: Vec<_> = std::env::args().skip(1).collect();
+    if args.is_empty() {
+        // encode some packets
+        let publish = v3::codec::Publish {
+            dup: false,
+            retain: false,
+            qos: v3::codec::QoS::AtMostOnce,
+            topic: ByteString::from_static("test"),
+            packet_id: None,
+            payload: Bytes::from_static(b"\xff"),
+        };
+        for pkt in [v3::codec::Packet::Publish(publish), v3::codec::Packet::PingRequest] {
+            let buf = v3_codec.encode_packet(pkt).unwrap();
+            args.push(buf.iter().map(|b| format!("{:02x}", b)).collect());
+        }
+    }
+
+    for arg in args {
+        let buf = match from_hex(&arg) {
+            Some(buf) => buf,
+            None => {
+                println!("{}: invalid hex string", arg);
+                continue;
+            }
+        };
+        match v3_codec.decode_packet(&buf) {
+            Ok(Some((pkt, size))) => println!("{}: v3 {:?}, {} bytes", arg, pkt, size),
+            Ok(None) => println!("{}: v3 incomplete packet", arg),
+            Err(err) => println!("{}: v3 error {:?}", arg, err),
+        }
+        match v5_codec.decode_packet(&buf) {
+            Ok(Some((pkt, size))) => println!("{}: v5 {:?}, {} bytes", arg, pkt, size),
+            Ok(None) => println!("{}: v5 incomplete packet", arg),
+            Err(err) => println!("{}: v5 error {:?}", arg, err),
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use std::time::Duration;
+
+use ntex::rt::time::delay_for;
+use ntex::util::{ByteString, Bytes};
+use ntex_mqtt::v3;
+
+const SOCKET_PATH: &str = "/tmp/ntex-mqtt.sock";
+
+#[derive(Clone)]
+struct Session;
+
+#[derive(Debug)]
+struct ServerError;
+
+impl From<()> for ServerError {
+    fn from(_: ()) -> Self {
+        ServerError
+    }
+}
+
+async fn handshake<Io>(
+    handshake: v3::Handshake<Io>,
+) -> Result<v3::HandshakeAck<Io, Session>, ServerError> {
+    log::info!("new connection: {:?}", handshake);
+    Ok(handshake.ack(Session, false))
+}
+
+async fn publish(publish: v3::Publish) -> Result<(), ServerError> {
+    log::info!("incoming publish: {:?} -> {:?}", publish.id(), publish.topic());
+    Ok(())
+}
+
+#[ntex::main]
+async fn main() -> std::io::Result<()> {
+    std::env::set_var("RUST_LOG", "ntex=trace,ntex_mqtt=trace,uds=trace");
+    env_logger::init();
+
+    // start server on unix domain socket
+    let srv = ntex::server::Server::build()
+        .bind_uds("mqtt", SOCKET_PATH, || {
+            v3::MqttServer::new(handshake).publish(publish).finish()
+        })?
+        .workers(1)
+        .run();
+
+    delay_for(Duration::from_millis(100)).await;
+
+    // connect to server
+    let client = v3::client::MqttConnector::new(SOCKET_PATH)
+        .unix()
+        .client_id("user")
+        .connect()
+        .await
+        .unwrap();
+
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish(ByteString::from_static("test"), Bytes::from_static(b"data"))
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sink.close();
+
+    srv.stop(true).await;
+    Ok(())
+}
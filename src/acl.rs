@@ -0,0 +1,116 @@
+//! Topic-level authorization
+use std::task::{Context, Poll};
+use std::{future::Future, pin::Pin, rc::Rc};
+
+use ntex::service::Service;
+use ntex::util::ByteString;
+
+/// Topic authorization provider
+///
+/// Provider is called for every topic filter of incoming subscribe packet
+/// and for topic of every incoming publish packet. `S` is a session type,
+/// `v3::Session<St>` or `v5::Session<St>`.
+///
+/// Denied subscriptions get failed with `Failure` (v3) or `NotAuthorized` (v5)
+/// return code and are not passed to control service. Denied v5 publishes get
+/// acknowledged with `NotAuthorized` reason code, QoS 0 publishes are dropped.
+/// v3 protocol can not report denied publish, so connection gets closed.
+pub trait Acl<S>: 'static {
+    /// The future of the authorization result
+    type Future: Future<Output = bool> + 'static;
+
+    /// Check if session is allowed to publish to the topic
+    fn allow_publish(&self, session: &S, topic: &ByteString) -> Self::Future;
+
+    /// Check if session is allowed to subscribe to the topic filter
+    fn allow_subscribe(&self, session: &S, filter: &ByteString) -> Self::Future;
+}
+
+/// Object safe version of `Acl` trait
+pub(crate) trait DynAcl<S> {
+    fn allow_publish(
+        &self,
+        session: &S,
+        topic: &ByteString,
+    ) -> Pin<Box<dyn Future<Output = bool>>>;
+
+    fn allow_subscribe(
+        &self,
+        session: &S,
+        filter: &ByteString,
+    ) -> Pin<Box<dyn Future<Output = bool>>>;
+}
+
+impl<S, T: Acl<S>> DynAcl<S> for T {
+    fn allow_publish(
+        &self,
+        session: &S,
+        topic: &ByteString,
+    ) -> Pin<Box<dyn Future<Output = bool>>> {
+        Box::pin(Acl::allow_publish(self, session, topic))
+    }
+
+    fn allow_subscribe(
+        &self,
+        session: &S,
+        filter: &ByteString,
+    ) -> Pin<Box<dyn Future<Output = bool>>> {
+        Box::pin(Acl::allow_subscribe(self, session, filter))
+    }
+}
+
+/// Service response future, service is called after authorization check
+///
+/// `f` converts check result to request for the service, or to the response
+/// if request is denied.
+pub(crate) struct AclResponse<S: Service, R> {
+    state: AclState<S, R>,
+}
+
+enum AclState<S: Service, R> {
+    Check {
+        fut: Pin<Box<dyn Future<Output = R>>>,
+        service: Rc<S>,
+        req: Option<S::Request>,
+        f: fn(S::Request, R) -> Result<S::Request, S::Response>,
+    },
+    Call(Pin<Box<S::Future>>),
+}
+
+impl<S: Service, R> AclResponse<S, R> {
+    pub(crate) fn new(
+        fut: Pin<Box<dyn Future<Output = R>>>,
+        service: Rc<S>,
+        req: S::Request,
+        f: fn(S::Request, R) -> Result<S::Request, S::Response>,
+    ) -> Self {
+        AclResponse { state: AclState::Check { fut, service, req: Some(req), f } }
+    }
+}
+
+// request is never pinned
+impl<S: Service, R> Unpin for AclResponse<S, R> {}
+
+impl<S: Service, R> Future for AclResponse<S, R> {
+    type Output = Result<S::Response, S::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            match this.state {
+                AclState::Check { ref mut fut, ref service, ref mut req, f } => {
+                    let res = match fut.as_mut().poll(cx) {
+                        Poll::Ready(res) => res,
+                        Poll::Pending => return Poll::Pending,
+                    };
+                    match f(req.take().unwrap(), res) {
+                        Ok(req) => this.state = AclState::Call(Box::pin(service.call(req))),
+                        Err(res) => return Poll::Ready(Ok(res)),
+                    }
+                }
+                AclState::Call(ref mut fut) => return fut.as_mut().poll(cx),
+            }
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! Authentication provider for v3 and v5 servers
+//!
+//! `AuthProvider` implementation could be used with both protocol versions:
+//!
+//! ```rust,ignore
+//! v3::MqttServer::new(auth::v3_handshake(MyAuth::new())).finish();
+//! v5::MqttServer::new(auth::v5_handshake(MyAuth::new())).finish();
+//! ```
+//!
+//! Provider wrapped into `Rc` could be shared between listeners of the
+//! combined server:
+//!
+//! ```rust,ignore
+//! let auth = Rc::new(MyAuth::new());
+//! MqttServer::new()
+//!     .v3(v3::MqttServer::new(auth::v3_handshake(auth.clone())))
+//!     .v5(v5::MqttServer::new(auth::v5_handshake(auth)));
+//! ```
+use std::{future::Future, net::SocketAddr, rc::Rc, time::Duration};
+
+use ntex::rt::time::delay_for;
+use ntex::service::ServiceFactory;
+use ntex::util::{select, ByteString, Bytes, Either};
+
+use crate::types::QoS;
+use crate::{v3, v5};
+
+/// Protocol version of the connection
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Protocol {
+    /// MQTT 3.1 and 3.1.1
+    V3,
+    /// MQTT 5.0
+    V5,
+}
+
+/// Will message of the connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct LastWill {
+    /// Will topic
+    pub topic: ByteString,
+    /// Will message payload
+    pub message: Bytes,
+    /// QoS level of the will message
+    pub qos: QoS,
+    /// Will message is retained
+    pub retain: bool,
+}
+
+/// Authentication request
+///
+/// Protocol agnostic view of the client's handshake.
+#[derive(Debug, Clone)]
+pub struct AuthRequest {
+    /// Protocol version of the connection
+    pub protocol: Protocol,
+    /// Client identifier
+    pub client_id: ByteString,
+    /// User name
+    pub username: Option<ByteString>,
+    /// Password
+    pub password: Option<Bytes>,
+    /// Enhanced authentication method, v5 only
+    pub method: Option<ByteString>,
+    /// Enhanced authentication data, v5 only
+    ///
+    /// Data of the `CONNECT` packet for first step, data of the client's
+    /// `AUTH` packet for next steps.
+    pub data: Option<Bytes>,
+    /// Clean session (v3) or clean start (v5) flag
+    pub clean_session: bool,
+    /// Keep-alive interval requested by the client, in seconds
+    pub keep_alive: u16,
+    /// Will message of the connection
+    pub last_will: Option<LastWill>,
+    /// Peer address of the connection
+    pub peer_addr: Option<SocketAddr>,
+    /// DER encoded certificate chain of the client, end-entity certificate goes first
+    ///
+    /// Available for tls connections, requires `openssl` or `rustls` feature.
+    pub peer_certificates: Option<Vec<Bytes>>,
+    /// Number of completed enhanced authentication round trips
+    pub step: u16,
+}
+
+impl AuthRequest {
+    /// Create authentication request from v3 handshake
+    pub fn from_v3<Io: 'static>(con: &v3::Handshake<Io>) -> Self {
+        let pkt = con.packet();
+        AuthRequest {
+            protocol: Protocol::V3,
+            client_id: pkt.client_id.clone(),
+            username: pkt.username.clone(),
+            password: pkt.password.clone(),
+            method: None,
+            data: None,
+            clean_session: pkt.clean_session,
+            keep_alive: pkt.keep_alive,
+            last_will: pkt.last_will.as_ref().map(|will| LastWill {
+                topic: will.topic.clone(),
+                message: will.message.clone(),
+                qos: will.qos,
+                retain: will.retain,
+            }),
+            peer_addr: con.peer_addr(),
+            peer_certificates: con.peer_certificates(),
+            step: 0,
+        }
+    }
+
+    /// Create authentication request from v5 handshake
+    pub fn from_v5<Io: 'static>(con: &v5::Handshake<Io>) -> Self {
+        let pkt = con.packet();
+        AuthRequest {
+            protocol: Protocol::V5,
+            client_id: pkt.client_id.clone(),
+            username: pkt.username.clone(),
+            password: pkt.password.clone(),
+            method: pkt.auth_method.clone(),
+            data: pkt.auth_data.clone(),
+            clean_session: pkt.clean_start,
+            keep_alive: pkt.keep_alive,
+            last_will: pkt.last_will.as_ref().map(|will| LastWill {
+                topic: will.topic.clone(),
+                message: will.message.clone(),
+                qos: will.qos,
+                retain: will.retain,
+            }),
+            peer_addr: con.peer_addr(),
+            peer_certificates: con.peer_certificates(),
+            step: 0,
+        }
+    }
+}
+
+/// Authentication result
+#[derive(Debug)]
+pub enum AuthResult<St> {
+    /// Accept connection with session state
+    Accept(St),
+    /// Continue enhanced authentication, v5 only
+    ///
+    /// Server sends `AUTH` packet with `ContinueAuth` reason code and provided
+    /// data. Provider gets called again with client's response data.
+    /// v3 connections are rejected with `NotAuthorized` return code.
+    Continue(Option<Bytes>),
+    /// Reject connection
+    Reject(AuthError),
+}
+
+/// Authentication rejection reason
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    /// Bad user name or password
+    BadCredentials,
+    /// Client is not authorized to connect
+    NotAuthorized,
+    /// Client identifier is not valid
+    IdentifierRejected,
+    /// Server is unavailable
+    ServerUnavailable,
+    /// Authentication method is not supported, v3 clients get `NotAuthorized`
+    BadAuthMethod,
+    /// Client is banned, v3 clients get `NotAuthorized`
+    Banned,
+}
+
+impl From<AuthError> for v3::codec::ConnectAckReason {
+    fn from(err: AuthError) -> Self {
+        match err {
+            AuthError::BadCredentials => v3::codec::ConnectAckReason::BadUserNameOrPassword,
+            AuthError::IdentifierRejected => v3::codec::ConnectAckReason::IdentifierRejected,
+            AuthError::ServerUnavailable => v3::codec::ConnectAckReason::ServiceUnavailable,
+            AuthError::NotAuthorized | AuthError::BadAuthMethod | AuthError::Banned => {
+                v3::codec::ConnectAckReason::NotAuthorized
+            }
+        }
+    }
+}
+
+impl From<AuthError> for v5::codec::ConnectAckReason {
+    fn from(err: AuthError) -> Self {
+        match err {
+            AuthError::BadCredentials => v5::codec::ConnectAckReason::BadUserNameOrPassword,
+            AuthError::NotAuthorized => v5::codec::ConnectAckReason::NotAuthorized,
+            AuthError::IdentifierRejected => {
+                v5::codec::ConnectAckReason::ClientIdentifierNotValid
+            }
+            AuthError::ServerUnavailable => v5::codec::ConnectAckReason::ServerUnavailable,
+            AuthError::BadAuthMethod => v5::codec::ConnectAckReason::BadAuthenticationMethod,
+            AuthError::Banned => v5::codec::ConnectAckReason::Banned,
+        }
+    }
+}
+
+/// Authentication provider
+pub trait AuthProvider: 'static {
+    /// Session state of accepted connection
+    type State;
+    /// Provider error, error closes connection without `CONNACK` packet
+    type Error;
+    /// The future of the authentication result
+    type Future: Future<Output = Result<AuthResult<Self::State>, Self::Error>>;
+
+    /// Authenticate connection
+    fn authenticate(&self, req: AuthRequest) -> Self::Future;
+
+    /// Max number of enhanced authentication round trips, v5 only
+    ///
+    /// Connection is rejected with `NotAuthorized` reason code if
+    /// authentication is not completed in time. By default 16 round trips.
+    fn max_steps(&self) -> u16 {
+        16
+    }
+
+    /// Max time to wait for client's `AUTH` packet, v5 only
+    ///
+    /// Connection is rejected with `NotAuthorized` reason code if client
+    /// does not respond in time. Server's handshake timeout applies to the
+    /// whole handshake. By default 30 seconds.
+    fn auth_timeout(&self) -> Duration {
+        Duration::from_secs(30)
+    }
+}
+
+impl<P: AuthProvider> AuthProvider for Rc<P> {
+    type State = P::State;
+    type Error = P::Error;
+    type Future = P::Future;
+
+    fn authenticate(&self, req: AuthRequest) -> Self::Future {
+        (**self).authenticate(req)
+    }
+
+    fn max_steps(&self) -> u16 {
+        (**self).max_steps()
+    }
+
+    fn auth_timeout(&self) -> Duration {
+        (**self).auth_timeout()
+    }
+}
+
+/// Create v3 handshake service for authentication provider
+pub fn v3_handshake<P, Io>(
+    provider: P,
+) -> impl ServiceFactory<
+    Config = (),
+    Request = v3::Handshake<Io>,
+    Response = v3::HandshakeAck<Io, P::State>,
+    Error = P::Error,
+    InitError = (),
+>
+where
+    P: AuthProvider,
+    Io: 'static,
+{
+    let provider = Rc::new(provider);
+
+    ntex::fn_service(move |con: v3::Handshake<Io>| {
+        let provider = provider.clone();
+        async move {
+            let req = AuthRequest::from_v3(&con);
+
+            Ok(match provider.authenticate(req).await? {
+                AuthResult::Accept(st) => con.ack(st, false),
+                AuthResult::Continue(_) => {
+                    log::trace!("Enhanced authentication is not supported by v3 protocol");
+                    con.not_authorized()
+                }
+                AuthResult::Reject(err) => match err.into() {
+                    v3::codec::ConnectAckReason::BadUserNameOrPassword => {
+                        con.bad_username_or_pwd()
+                    }
+                    v3::codec::ConnectAckReason::IdentifierRejected => {
+                        con.identifier_rejected()
+                    }
+                    v3::codec::ConnectAckReason::ServiceUnavailable => {
+                        con.service_unavailable()
+                    }
+                    _ => con.not_authorized(),
+                },
+            })
+        }
+    })
+}
+
+/// Create v5 handshake service for authentication provider
+///
+/// Service performs enhanced authentication exchange if connect packet
+/// contains authentication method.
+pub fn v5_handshake<P, Io>(
+    provider: P,
+) -> impl ServiceFactory<
+    Config = (),
+    Request = v5::Handshake<Io>,
+    Response = v5::HandshakeAck<Io, P::State>,
+    Error = P::Error,
+    InitError = (),
+>
+where
+    P: AuthProvider,
+    Io: ntex::codec::AsyncRead + ntex::codec::AsyncWrite + Unpin + 'static,
+{
+    let provider = Rc::new(provider);
+
+    ntex::fn_service(move |mut con: v5::Handshake<Io>| {
+        let provider = provider.clone();
+        async move {
+            let mut req = AuthRequest::from_v5(&con);
+
+            loop {
+                match provider.authenticate(req.clone()).await? {
+                    AuthResult::Accept(st) => {
+                        let method = req.method;
+                        return Ok(con.ack(st).with(|ack| ack.auth_method = method));
+                    }
+                    AuthResult::Continue(data) => {
+                        if req.method.is_none() {
+                            log::trace!("Cannot continue authentication without method");
+                            return Ok(con.failed(v5::codec::ConnectAckReason::NotAuthorized));
+                        }
+                        if req.step >= provider.max_steps() {
+                            log::trace!("Max number of authentication steps is reached");
+                            return Ok(con.failed(v5::codec::ConnectAckReason::NotAuthorized));
+                        }
+                        let res =
+                            select(delay_for(provider.auth_timeout()), con.auth_continue(data))
+                                .await;
+                        match res {
+                            Either::Left(_) => {
+                                log::trace!("Client authentication response timeout");
+                                return Ok(
+                                    con.failed(v5::codec::ConnectAckReason::NotAuthorized)
+                                );
+                            }
+                            Either::Right(Some(auth))
+                                if auth.reason_code
+                                    == v5::codec::AuthReasonCode::ContinueAuth
+                                    && auth.auth_method == req.method =>
+                            {
+                                req.data = auth.auth_data;
+                                req.step += 1;
+                            }
+                            Either::Right(_) => {
+                                return Ok(
+                                    con.failed(v5::codec::ConnectAckReason::ProtocolError)
+                                )
+                            }
+                        }
+                    }
+                    AuthResult::Reject(err) => return Ok(con.failed(err.into())),
+                }
+            }
+        }
+    })
+}
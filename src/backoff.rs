@@ -0,0 +1,154 @@
+//! Re-connect backoff strategies
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::{cmp, time::Duration};
+
+/// Re-connect backoff strategy
+///
+/// Strategy defines delay between re-connect attempts of the managed client.
+pub trait BackoffStrategy {
+    /// Returns delay before next connect attempt
+    ///
+    /// `attempt` is number of the attempt since last successful connect,
+    /// starts from 1. Returning `None` stops re-connecting permanently.
+    fn next_delay(&mut self, attempt: u32) -> Option<Duration>;
+}
+
+impl<F> BackoffStrategy for F
+where
+    F: FnMut(u32) -> Option<Duration>,
+{
+    fn next_delay(&mut self, attempt: u32) -> Option<Duration> {
+        (self)(attempt)
+    }
+}
+
+/// Fixed delay between re-connect attempts
+#[derive(Debug, Clone)]
+pub struct FixedBackoff {
+    delay: Duration,
+    max_attempts: u32,
+}
+
+impl FixedBackoff {
+    /// Create fixed backoff strategy
+    pub fn new(delay: Duration) -> Self {
+        FixedBackoff { delay, max_attempts: 0 }
+    }
+
+    /// Set max number of re-connect attempts
+    ///
+    /// By default number of attempts is not limited.
+    pub fn max_attempts(mut self, val: u32) -> Self {
+        self.max_attempts = val;
+        self
+    }
+}
+
+impl BackoffStrategy for FixedBackoff {
+    fn next_delay(&mut self, attempt: u32) -> Option<Duration> {
+        if self.max_attempts != 0 && attempt > self.max_attempts {
+            None
+        } else {
+            Some(self.delay)
+        }
+    }
+}
+
+/// Exponential backoff
+///
+/// Delay starts with `min` value and doubles with each attempt
+/// until it reaches `max` value.
+#[derive(Debug, Clone)]
+pub struct ExponentialBackoff {
+    min: Duration,
+    max: Duration,
+    jitter: bool,
+    max_attempts: u32,
+}
+
+impl ExponentialBackoff {
+    /// Create exponential backoff strategy
+    pub fn new(min: Duration, max: Duration) -> Self {
+        ExponentialBackoff { min, max: cmp::max(min, max), jitter: false, max_attempts: 0 }
+    }
+
+    /// Randomize delay
+    ///
+    /// Delay is randomly selected between half and full value.
+    /// By default jitter is disabled.
+    pub fn jitter(mut self, val: bool) -> Self {
+        self.jitter = val;
+        self
+    }
+
+    /// Set max number of re-connect attempts
+    ///
+    /// By default number of attempts is not limited.
+    pub fn max_attempts(mut self, val: u32) -> Self {
+        self.max_attempts = val;
+        self
+    }
+}
+
+impl Default for ExponentialBackoff {
+    fn default() -> Self {
+        ExponentialBackoff::new(Duration::from_secs(1), Duration::from_secs(30))
+    }
+}
+
+impl BackoffStrategy for ExponentialBackoff {
+    fn next_delay(&mut self, attempt: u32) -> Option<Duration> {
+        if self.max_attempts != 0 && attempt > self.max_attempts {
+            return None;
+        }
+
+        let factor = 2u32.checked_pow(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+        let delay = self.min.checked_mul(factor).map_or(self.max, |d| cmp::min(d, self.max));
+
+        if self.jitter {
+            let half = delay / 2;
+            let rnd = RandomState::new().build_hasher().finish();
+            Some(half + Duration::from_nanos(rnd % (half.as_nanos() as u64 + 1)))
+        } else {
+            Some(delay)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed() {
+        let mut backoff = FixedBackoff::new(Duration::from_millis(100)).max_attempts(2);
+        assert_eq!(backoff.next_delay(1), Some(Duration::from_millis(100)));
+        assert_eq!(backoff.next_delay(2), Some(Duration::from_millis(100)));
+        assert_eq!(backoff.next_delay(3), None);
+    }
+
+    #[test]
+    fn test_exponential() {
+        let mut backoff =
+            ExponentialBackoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        assert_eq!(backoff.next_delay(1), Some(Duration::from_secs(1)));
+        assert_eq!(backoff.next_delay(2), Some(Duration::from_secs(2)));
+        assert_eq!(backoff.next_delay(3), Some(Duration::from_secs(4)));
+        assert_eq!(backoff.next_delay(4), Some(Duration::from_secs(5)));
+        assert_eq!(backoff.next_delay(100), Some(Duration::from_secs(5)));
+
+        let mut backoff = backoff.jitter(true).max_attempts(3);
+        let delay = backoff.next_delay(3).unwrap();
+        assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(4));
+        assert_eq!(backoff.next_delay(4), None);
+    }
+
+    #[test]
+    fn test_custom() {
+        let mut backoff =
+            |attempt: u32| if attempt < 3 { Some(Duration::from_millis(10)) } else { None };
+        assert_eq!(backoff.next_delay(1), Some(Duration::from_millis(10)));
+        assert_eq!(backoff.next_delay(3), None);
+    }
+}
@@ -0,0 +1,586 @@
+//! Minimal embeddable mqtt broker
+//!
+//! Broker is built on top of v5 `MqttServer`, it routes publishes to
+//! subscribed connections, keeps retained messages and disconnects old
+//! connection if client re-connects with the same client id.
+//! Sessions are not persisted, subscriptions get removed when connection
+//! is closed. Will message is published after will delay interval, unless
+//! client connects again.
+//!
+//! Broker state is local to the server worker, server should be configured
+//! with single worker. `SysPublisher` periodically publishes broker
+//! statistics to `$SYS/...` topics.
+//!
+//! ```rust,no_run
+//! use ntex_mqtt::broker::Broker;
+//!
+//! #[ntex::main]
+//! async fn main() -> std::io::Result<()> {
+//!     ntex::server::Server::build()
+//!         .bind("mqtt", "127.0.0.1:1883", || Broker::new().server())?
+//!         .workers(1)
+//!         .run()
+//!         .await
+//! }
+//! ```
+use std::cell::{Cell, RefCell};
+use std::rc::{Rc, Weak};
+use std::{cmp, convert::TryFrom, fmt, time::Duration, time::Instant};
+
+use ntex::codec::{AsyncRead, AsyncWrite};
+use ntex::rt::{task::JoinHandle, time::delay_for};
+use ntex::service::{fn_factory_with_config, fn_service, ServiceFactory};
+use ntex::util::{ByteString, HashMap, Ready};
+
+use crate::v5::codec::{self, RetainHandling};
+use crate::v5::{
+    ControlMessage, ControlResult, Handshake, HandshakeAck, MqttServer, MqttSink, Publish,
+    PublishAck, Session, SessionRegistry,
+};
+use crate::{error::MqttError, topic::Topic, tree::SubscriptionTree, types::QoS};
+
+/// Broker service error
+#[derive(Debug, Copy, Clone)]
+pub struct BrokerError;
+
+impl fmt::Display for BrokerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Broker error")
+    }
+}
+
+impl TryFrom<BrokerError> for PublishAck {
+    type Error = BrokerError;
+
+    fn try_from(err: BrokerError) -> Result<Self, Self::Error> {
+        Err(err)
+    }
+}
+
+/// In-process mqtt broker
+#[derive(Clone, Default)]
+pub struct Broker(Rc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    subs: RefCell<SubscriptionTree<Subscriber>>,
+    retained: RefCell<HashMap<ByteString, codec::Publish>>,
+    // delayed will messages of disconnected clients
+    wills: RefCell<HashMap<ByteString, (codec::Publish, JoinHandle<()>)>>,
+    registry: SessionRegistry,
+    next_id: Cell<usize>,
+    shared_idx: Cell<usize>,
+    counters: Counters,
+}
+
+struct Counters {
+    started: Instant,
+    messages_received: Cell<u64>,
+    messages_sent: Cell<u64>,
+    bytes_received: Cell<u64>,
+    bytes_sent: Cell<u64>,
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Counters {
+            started: Instant::now(),
+            messages_received: Cell::new(0),
+            messages_sent: Cell::new(0),
+            bytes_received: Cell::new(0),
+            bytes_sent: Cell::new(0),
+        }
+    }
+}
+
+impl Counters {
+    fn received(&self, pkt: &codec::Publish) {
+        self.messages_received.set(self.messages_received.get() + 1);
+        self.bytes_received.set(self.bytes_received.get() + pkt.payload.len() as u64);
+    }
+
+    fn sent(&self, pkt: &codec::Publish) {
+        self.messages_sent.set(self.messages_sent.get() + 1);
+        self.bytes_sent.set(self.bytes_sent.get() + pkt.payload.len() as u64);
+    }
+}
+
+/// Broker statistics
+#[derive(Debug, Copy, Clone)]
+pub struct BrokerStats {
+    /// Number of connected clients
+    pub clients: usize,
+    /// Number of active subscriptions
+    pub subscriptions: usize,
+    /// Number of retained messages
+    pub retained: usize,
+    /// Number of received publishes
+    pub messages_received: u64,
+    /// Number of sent publishes
+    pub messages_sent: u64,
+    /// Payload bytes of received publishes
+    pub bytes_received: u64,
+    /// Payload bytes of sent publishes
+    pub bytes_sent: u64,
+    /// Time since broker is created
+    pub uptime: Duration,
+}
+
+#[derive(Clone)]
+struct Subscriber {
+    id: usize,
+    qos: QoS,
+    no_local: bool,
+    sink: MqttSink,
+}
+
+impl PartialEq for Subscriber {
+    fn eq(&self, other: &Subscriber) -> bool {
+        self.id == other.id
+    }
+}
+
+struct Connection {
+    id: usize,
+    client_id: ByteString,
+    session_expiry: Cell<u32>,
+    filters: RefCell<Vec<(ByteString, Topic, Subscriber)>>,
+}
+
+type BrokerSession = Session<Rc<Connection>>;
+
+impl Broker {
+    /// Create new broker
+    pub fn new() -> Self {
+        Broker::default()
+    }
+
+    #[inline]
+    /// Registry of connected clients
+    pub fn registry(&self) -> &SessionRegistry {
+        &self.0.registry
+    }
+
+    /// Number of retained messages
+    pub fn retained(&self) -> usize {
+        self.0.retained.borrow().len()
+    }
+
+    /// Get broker statistics
+    pub fn stats(&self) -> BrokerStats {
+        let counters = &self.0.counters;
+        BrokerStats {
+            clients: self.0.registry.len(),
+            subscriptions: self.0.subs.borrow().len(),
+            retained: self.0.retained.borrow().len(),
+            messages_received: counters.messages_received.get(),
+            messages_sent: counters.messages_sent.get(),
+            bytes_received: counters.bytes_received.get(),
+            bytes_sent: counters.bytes_sent.get(),
+            uptime: counters.started.elapsed(),
+        }
+    }
+
+    /// Publish message to subscribed clients
+    ///
+    /// Retained message get stored if retain flag is set, retained
+    /// message with empty payload removes stored message.
+    pub fn publish(&self, pkt: codec::Publish) {
+        self.route(pkt, 0)
+    }
+
+    /// Create mqtt server factory
+    pub fn server<Io>(
+        &self,
+    ) -> impl ServiceFactory<Config = (), Request = Io, Response = (), Error = MqttError<BrokerError>>
+    where
+        Io: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        let broker = self.clone();
+        let broker2 = self.clone();
+        let broker3 = self.clone();
+
+        MqttServer::new(move |hs: Handshake<Io>| {
+            Ready::<_, BrokerError>::Ok(broker.handshake(hs))
+        })
+        .session_registry(self.0.registry.clone())
+        .publish(fn_factory_with_config(move |session: BrokerSession| {
+            let broker = broker2.clone();
+            Ready::<_, BrokerError>::Ok(fn_service(move |p: Publish| {
+                broker.0.counters.received(p.packet());
+                if let Some(pkt) = p.forward() {
+                    broker.route(pkt, session.id);
+                }
+                Ready::<_, BrokerError>::Ok(p.ack())
+            }))
+        }))
+        .control(fn_factory_with_config(move |session: BrokerSession| {
+            let broker = broker3.clone();
+            Ready::<_, BrokerError>::Ok(fn_service(move |msg| {
+                Ready::<_, BrokerError>::Ok(broker.control(&session, msg))
+            }))
+        }))
+        .finish()
+    }
+
+    fn handshake<Io>(&self, hs: Handshake<Io>) -> HandshakeAck<Io, Rc<Connection>> {
+        let id = self.0.next_id.get() + 1;
+        self.0.next_id.set(id);
+
+        let pkt = hs.packet();
+        let assigned = pkt.client_id.is_empty();
+        let client_id = if assigned {
+            ByteString::from(format!("ntex-mqtt-{}", id))
+        } else {
+            pkt.client_id.clone()
+        };
+
+        // client connects again, delayed will is not published. clean start
+        // ends previous session, will is published immediately
+        let will = self.0.wills.borrow_mut().remove(&client_id);
+        if let Some((will, handle)) = will {
+            handle.abort();
+            if pkt.clean_start {
+                self.publish(will);
+            } else {
+                log::trace!("Will message is cancelled for {:?}", client_id);
+            }
+        }
+
+        let conn = Rc::new(Connection {
+            id,
+            client_id: client_id.clone(),
+            session_expiry: Cell::new(pkt.session_expiry_interval_secs.unwrap_or(0)),
+            filters: RefCell::new(Vec::new()),
+        });
+
+        // assign client id
+        if assigned {
+            hs.ack(conn).with(|pkt| pkt.assigned_client_id = Some(client_id))
+        } else {
+            hs.ack(conn)
+        }
+    }
+
+    fn control(
+        &self,
+        session: &BrokerSession,
+        msg: ControlMessage<BrokerError>,
+    ) -> ControlResult {
+        match msg {
+            ControlMessage::Ping(msg) => msg.ack(),
+            ControlMessage::Disconnect(msg) => {
+                if let Some(expiry) = msg.packet().session_expiry_interval_secs {
+                    session.session_expiry.set(expiry);
+                }
+                msg.ack()
+            }
+            ControlMessage::Subscribe(mut msg) => {
+                let mut retained = Vec::new();
+                for mut sub in &mut msg {
+                    let filter = sub.topic().clone();
+                    let opts = sub.options().clone();
+                    let topic = match filter.parse::<Topic>() {
+                        Ok(topic) => topic,
+                        Err(_) => {
+                            sub.fail(codec::SubscribeAckReason::TopicFilterInvalid);
+                            continue;
+                        }
+                    };
+                    let subscriber = Subscriber {
+                        id: session.id,
+                        qos: opts.qos,
+                        no_local: opts.no_local,
+                        sink: session.sink().clone(),
+                    };
+                    let is_new = self.subscribe(session, filter, topic.clone(), subscriber);
+                    sub.confirm(opts.qos);
+
+                    // retained messages are not sent for shared subscriptions
+                    let send = match opts.retain_handling {
+                        RetainHandling::AtSubscribe => true,
+                        RetainHandling::AtSubscribeNew => is_new,
+                        RetainHandling::NoAtSubscribe => false,
+                    };
+                    if send && !topic.is_shared() {
+                        for pkt in self.0.retained.borrow().values() {
+                            if topic.matches_str(&pkt.topic) {
+                                self.0.counters.sent(pkt);
+                                retained.push((min_qos(pkt.qos, opts.qos), pkt.clone()));
+                            }
+                        }
+                    }
+                }
+
+                // send retained messages after SUBACK
+                if !retained.is_empty() {
+                    let sink = session.sink().clone();
+                    ntex::rt::spawn(async move {
+                        for (qos, pkt) in retained {
+                            deliver(&sink, qos, pkt, true);
+                        }
+                    });
+                }
+                msg.ack()
+            }
+            ControlMessage::Unsubscribe(mut msg) => {
+                for mut item in &mut msg {
+                    if self.unsubscribe(session, item.topic()) {
+                        item.success();
+                    } else {
+                        item.fail(codec::UnsubscribeAckReason::NoSubscriptionExisted);
+                    }
+                }
+                msg.ack()
+            }
+            ControlMessage::Closed(msg) => {
+                let filters = session.filters.borrow_mut().split_off(0);
+                let mut subs = self.0.subs.borrow_mut();
+                for (_, topic, subscriber) in filters {
+                    subs.remove(&topic, &subscriber);
+                }
+                msg.ack()
+            }
+            ControlMessage::PublishRelease(msg) => msg.ack(),
+            ControlMessage::WillRequested(msg) => {
+                self.will(session, msg.publish(), msg.delay_interval().unwrap_or(0));
+                msg.ack()
+            }
+            ControlMessage::SessionExpired(msg) => msg.ack(),
+            ControlMessage::SessionTakenOver(msg) => msg.ack(),
+            ControlMessage::KeepAliveTimeout(msg) => msg.ack(),
+            ControlMessage::SlowConsumer(msg) => msg.ack(),
+            ControlMessage::Event(msg) => msg.ack(),
+            ControlMessage::Auth(_) => msg.disconnect_with(codec::Disconnect::new(
+                codec::DisconnectReasonCode::BadAuthenticationMethod,
+            )),
+            ControlMessage::Error(msg) => {
+                msg.ack(codec::DisconnectReasonCode::ImplementationSpecificError)
+            }
+            ControlMessage::ProtocolError(msg) => msg.ack(),
+        }
+    }
+
+    /// Add subscription, returns `false` if subscription already exists
+    fn subscribe(
+        &self,
+        session: &BrokerSession,
+        filter: ByteString,
+        topic: Topic,
+        subscriber: Subscriber,
+    ) -> bool {
+        let mut subs = self.0.subs.borrow_mut();
+        let mut filters = session.filters.borrow_mut();
+
+        let is_new = if let Some(pos) = filters.iter().position(|item| item.0 == filter) {
+            let (_, topic, subscriber) = filters.remove(pos);
+            subs.remove(&topic, &subscriber);
+            false
+        } else {
+            true
+        };
+        subs.insert(&topic, subscriber.clone());
+        filters.push((filter, topic, subscriber));
+        is_new
+    }
+
+    /// Remove subscription, returns `false` if subscription does not exist
+    fn unsubscribe(&self, session: &BrokerSession, filter: &ByteString) -> bool {
+        let mut filters = session.filters.borrow_mut();
+        if let Some(pos) = filters.iter().position(|item| &item.0 == filter) {
+            let (_, topic, subscriber) = filters.remove(pos);
+            self.0.subs.borrow_mut().remove(&topic, &subscriber);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Publish will message after will delay interval
+    ///
+    /// Will is published when will delay interval elapses or session
+    /// expires, whichever happens first (MQTT-3.1.3.2.2).
+    fn will(&self, session: &BrokerSession, pkt: codec::Publish, delay: u32) {
+        let delay = cmp::min(delay, session.session_expiry.get());
+        if delay == 0 {
+            self.publish(pkt);
+            return;
+        }
+
+        let broker = Rc::downgrade(&self.0);
+        let client_id = session.client_id.clone();
+        let handle = ntex::rt::spawn(async move {
+            delay_for(Duration::from_secs(delay as u64)).await;
+            if let Some(inner) = broker.upgrade() {
+                let will = inner.wills.borrow_mut().remove(&client_id);
+                if let Some((pkt, _)) = will {
+                    Broker(inner).publish(pkt);
+                }
+            }
+        });
+        let prev = self.0.wills.borrow_mut().insert(session.client_id.clone(), (pkt, handle));
+        if let Some((_, handle)) = prev {
+            handle.abort();
+        }
+    }
+
+    /// Route publish to subscribers, `id` is id of publisher's connection
+    fn route(&self, mut pkt: codec::Publish, id: usize) {
+        if pkt.retain {
+            let mut retained = self.0.retained.borrow_mut();
+            if pkt.payload.is_empty() {
+                retained.remove(&pkt.topic);
+            } else {
+                retained.insert(pkt.topic.clone(), pkt.clone());
+            }
+            pkt.retain = false;
+        }
+
+        let subs = self.0.subs.borrow();
+        let matches = subs.matches(&pkt.topic);
+        for sub in matches.subscriptions() {
+            if !(sub.no_local && sub.id == id) {
+                self.0.counters.sent(&pkt);
+                deliver(&sub.sink, min_qos(sub.qos, pkt.qos), pkt.clone(), false);
+            }
+        }
+
+        // shared subscriptions, round-robin between group members
+        for (_, members) in matches.shared() {
+            let idx = self.0.shared_idx.get().wrapping_add(1);
+            self.0.shared_idx.set(idx);
+            let sub = &members[idx % members.len()];
+            self.0.counters.sent(&pkt);
+            deliver(&sub.sink, min_qos(sub.qos, pkt.qos), pkt.clone(), false);
+        }
+    }
+}
+
+/// `$SYS` statistics publisher
+///
+/// Publisher periodically publishes broker statistics as retained messages
+/// to `<prefix>/clients/connected`, `<prefix>/subscriptions/count`,
+/// `<prefix>/retained messages/count`, `<prefix>/messages/received`,
+/// `<prefix>/messages/sent`, `<prefix>/bytes/received`, `<prefix>/bytes/sent`
+/// and `<prefix>/uptime` topics. Publisher stops after broker is dropped.
+///
+/// ```rust,no_run
+/// use ntex_mqtt::broker::{Broker, SysPublisher};
+///
+/// #[ntex::main]
+/// async fn main() -> std::io::Result<()> {
+///     ntex::server::Server::build()
+///         .bind("mqtt", "127.0.0.1:1883", || {
+///             let broker = Broker::new();
+///             ntex::rt::spawn(SysPublisher::new(&broker).run());
+///             broker.server()
+///         })?
+///         .workers(1)
+///         .run()
+///         .await
+/// }
+/// ```
+pub struct SysPublisher {
+    broker: Weak<Inner>,
+    interval: Duration,
+    prefix: String,
+}
+
+impl SysPublisher {
+    /// Create statistics publisher for the broker
+    ///
+    /// By default interval is set to 10 seconds and topic prefix
+    /// is set to `$SYS/broker`.
+    pub fn new(broker: &Broker) -> Self {
+        SysPublisher {
+            broker: Rc::downgrade(&broker.0),
+            interval: Duration::from_secs(10),
+            prefix: "$SYS/broker".to_string(),
+        }
+    }
+
+    /// Set publish interval
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Set topic prefix
+    pub fn prefix<T: Into<String>>(mut self, prefix: T) -> Self {
+        self.prefix = prefix.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Publish statistics until broker is dropped
+    pub async fn run(self) {
+        loop {
+            delay_for(self.interval).await;
+
+            let broker = if let Some(inner) = self.broker.upgrade() {
+                Broker(inner)
+            } else {
+                return;
+            };
+            let stats = broker.stats();
+            let values = [
+                ("clients/connected", stats.clients as u64),
+                ("subscriptions/count", stats.subscriptions as u64),
+                ("retained messages/count", stats.retained as u64),
+                ("messages/received", stats.messages_received),
+                ("messages/sent", stats.messages_sent),
+                ("bytes/received", stats.bytes_received),
+                ("bytes/sent", stats.bytes_sent),
+                ("uptime", stats.uptime.as_secs()),
+            ];
+            for (topic, value) in values.iter() {
+                broker.publish(codec::Publish {
+                    dup: false,
+                    retain: true,
+                    qos: QoS::AtMostOnce,
+                    topic: ByteString::from(format!("{}/{}", self.prefix, topic)),
+                    packet_id: None,
+                    payload: value.to_string().into(),
+                    properties: codec::PublishProperties::default(),
+                });
+            }
+        }
+    }
+}
+
+fn min_qos(qos1: QoS, qos2: QoS) -> QoS {
+    if u8::from(qos1) < u8::from(qos2) {
+        qos1
+    } else {
+        qos2
+    }
+}
+
+fn deliver(sink: &MqttSink, qos: QoS, pkt: codec::Publish, retain: bool) {
+    let codec::Publish { topic, payload, properties, .. } = pkt;
+    let mut builder = sink.publish(topic, payload).properties(|props| *props = properties);
+    if retain {
+        builder = builder.retain();
+    }
+
+    match qos {
+        QoS::AtMostOnce => {
+            if let Err(err) = builder.send_at_most_once() {
+                log::trace!("Cannot deliver publish: {}", err);
+            }
+        }
+        QoS::AtLeastOnce => {
+            ntex::rt::spawn(async move {
+                if let Err(err) = builder.send_at_least_once().await {
+                    log::trace!("Cannot deliver publish: {}", err);
+                }
+            });
+        }
+        QoS::ExactlyOnce => {
+            ntex::rt::spawn(async move {
+                if let Err(err) = builder.send_exactly_once().await {
+                    log::trace!("Cannot deliver publish: {}", err);
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Server drain handle
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{future::Future, rc::Rc, time::Duration};
+
+use futures_channel::{mpsc, oneshot};
+use ntex::util::{join_all, next};
+
+type Request = (Duration, oneshot::Sender<()>);
+
+/// Drain handle of the server
+///
+/// Session registries are local to the server worker. Handle is `Send` and
+/// `Sync`, it is shared by registries of all workers, so sessions of all
+/// workers could be drained from any thread, for example from signal
+/// handler before server is stopped. See `v3::SessionRegistry::with_drain()`
+/// and `v5::SessionRegistry::with_drain()`.
+#[derive(Clone, Default)]
+pub struct Drain(Arc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    draining: AtomicBool,
+    workers: Mutex<Vec<mpsc::UnboundedSender<Request>>>,
+}
+
+impl Drain {
+    /// Create drain handle
+    pub fn new() -> Self {
+        Drain::default()
+    }
+
+    /// Check if server is draining
+    pub fn is_draining(&self) -> bool {
+        self.0.draining.load(Ordering::Acquire)
+    }
+
+    /// Drain sessions of all workers
+    ///
+    /// New connections get rejected. Future resolves when registries of all
+    /// workers complete drain, see `SessionRegistry::drain()`.
+    pub fn drain(&self, timeout: Duration) -> impl Future<Output = ()> + Send {
+        self.0.draining.store(true, Ordering::Release);
+
+        let mut waiters = Vec::new();
+        self.0.workers.lock().unwrap().retain(|tx| {
+            let (wtx, wrx) = oneshot::channel();
+            if tx.unbounded_send((timeout, wtx)).is_ok() {
+                waiters.push(wrx);
+                true
+            } else {
+                false
+            }
+        });
+
+        async move {
+            log::trace!("Draining sessions of {} workers", waiters.len());
+            let _ = join_all(waiters).await;
+        }
+    }
+
+    /// Register worker's drain handler, must be called on worker's thread
+    pub(crate) fn register<F, R>(&self, f: F)
+    where
+        F: Fn(Duration) -> Option<R> + 'static,
+        R: Future<Output = ()> + 'static,
+    {
+        let (tx, mut rx) = mpsc::unbounded::<Request>();
+        self.0.workers.lock().unwrap().push(tx);
+
+        let f = Rc::new(f);
+        ntex::rt::spawn(async move {
+            while let Some((timeout, tx)) = next(&mut rx).await {
+                // registry is dropped, worker is stopped
+                if let Some(fut) = f(timeout) {
+                    let _ = ntex::rt::spawn(async move {
+                        fut.await;
+                        let _ = tx.send(());
+                    });
+                } else {
+                    break;
+                }
+            }
+        });
+    }
+}
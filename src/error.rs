@@ -1,6 +1,9 @@
 use derive_more::{Display, From};
 use ntex::util::Either;
-use std::io;
+use std::{error::Error, fmt, io};
+
+use crate::topic::TopicError;
+use crate::types::packet_type;
 
 /// Errors which can occur when attempting to handle mqtt connection.
 #[derive(Debug)]
@@ -13,10 +16,78 @@ pub enum MqttError<E> {
     HandshakeTimeout,
     /// Peer disconnect
     Disconnected,
+    /// Connection is rejected, maximum number of connections is reached
+    ServerBusy,
+    /// Connection is rejected, server is shutting down
+    ShuttingDown,
+    /// Connection is rejected by accept rate limiter
+    RateLimited,
     /// Protocol specific unhandled error (for v3.1.1 only)
     V3ProtocolError,
 }
 
+#[allow(clippy::match_like_matches_macro)]
+impl<E> MqttError<E> {
+    /// Error is caused by underlying io stream
+    pub fn is_io(&self) -> bool {
+        match self {
+            MqttError::Protocol(err) => err.is_io(),
+            _ => false,
+        }
+    }
+
+    /// Peer violated mqtt protocol
+    pub fn is_protocol(&self) -> bool {
+        match self {
+            MqttError::Protocol(err) => err.is_protocol(),
+            MqttError::V3ProtocolError => true,
+            _ => false,
+        }
+    }
+
+    /// Peer violated authentication exchange
+    pub fn is_auth(&self) -> bool {
+        match self {
+            MqttError::Protocol(err) => err.is_auth(),
+            _ => false,
+        }
+    }
+
+    /// Handshake or keep-alive timeout
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            MqttError::HandshakeTimeout => true,
+            MqttError::Protocol(err) => err.is_timeout(),
+            _ => false,
+        }
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for MqttError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttError::Service(err) => write!(f, "Service error: {}", err),
+            MqttError::Protocol(err) => write!(f, "Protocol error: {}", err),
+            MqttError::HandshakeTimeout => write!(f, "Handshake timeout"),
+            MqttError::Disconnected => write!(f, "Peer disconnected"),
+            MqttError::ServerBusy => write!(f, "Maximum number of connections is reached"),
+            MqttError::ShuttingDown => write!(f, "Server is shutting down"),
+            MqttError::RateLimited => write!(f, "Connection is rejected by rate limiter"),
+            MqttError::V3ProtocolError => write!(f, "Protocol error"),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for MqttError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MqttError::Service(err) => Some(err),
+            MqttError::Protocol(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 /// Protocol level errors
 #[derive(Debug, Display, From)]
 pub enum ProtocolError {
@@ -41,14 +112,69 @@ pub enum ProtocolError {
     /// Unknown topic alias
     #[display(fmt = "Unknown topic alias")]
     UnknownTopicAlias,
+    /// Retained messages are not supported
+    #[display(fmt = "Retained messages are not supported")]
+    RetainNotSupported,
+    /// Subscription identifiers are not supported
+    #[display(fmt = "Subscription identifiers are not supported")]
+    SubscriptionIdentifiersNotSupported,
     /// Keep alive timeout
     #[display(fmt = "Keep alive timeout")]
     KeepAliveTimeout,
+    /// Inbound publish rate is exceeded
+    #[display(fmt = "Inbound publish rate is exceeded")]
+    PublishRateExceeded,
     /// Unexpected io error
     #[display(fmt = "Unexpected io error: {}", _0)]
     Io(io::Error),
 }
 
+#[allow(clippy::match_like_matches_macro)]
+impl ProtocolError {
+    /// Error is caused by underlying io stream
+    pub fn is_io(&self) -> bool {
+        match self {
+            ProtocolError::Io(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Peer violated mqtt protocol
+    pub fn is_protocol(&self) -> bool {
+        match self {
+            ProtocolError::Io(_) | ProtocolError::KeepAliveTimeout => false,
+            _ => true,
+        }
+    }
+
+    /// Peer sent unexpected AUTH packet
+    pub fn is_auth(&self) -> bool {
+        match self {
+            ProtocolError::Unexpected(packet_type::AUTH, _) => true,
+            _ => false,
+        }
+    }
+
+    /// Keep-alive timeout
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            ProtocolError::KeepAliveTimeout => true,
+            _ => false,
+        }
+    }
+}
+
+impl Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProtocolError::Decode(err) => Some(err),
+            ProtocolError::Encode(err) => Some(err),
+            ProtocolError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 impl<E> From<ProtocolError> for MqttError<E> {
     fn from(err: ProtocolError) -> Self {
         MqttError::Protocol(err)
@@ -96,6 +222,102 @@ pub enum DecodeError {
     PacketIdRequired,
     MaxSizeExceeded,
     Utf8Error(std::str::Utf8Error),
+    /// Reserved flags of fixed header are set or dup flag is set for QoS 0 publish
+    InvalidPacketFlags,
+    /// QoS value is not valid
+    InvalidQoS,
+    /// Will QoS or will retain flags are set without will flag
+    InvalidWillFlags,
+    /// Reserved bits of subscription options are set
+    InvalidSubscriptionOptions,
+    /// Password flag is set without username flag (MQTT v3 only)
+    PasswordWithoutUsername,
+    /// Subscribe or unsubscribe packet does not contain topic filters
+    EmptyTopicFilters,
+    /// String contains U+0000 or control characters
+    InvalidUtf8String,
+    /// Decode error with location of malformed data
+    #[display(fmt = "{} at {}", _1, _0)]
+    #[from(ignore)]
+    WithLocation(DecodeErrorLocation, Box<DecodeError>),
+}
+
+impl DecodeError {
+    /// Returns error without location details
+    pub fn kind(&self) -> &DecodeError {
+        match self {
+            DecodeError::WithLocation(_, err) => err.kind(),
+            err => err,
+        }
+    }
+
+    /// Returns location of malformed data, if available
+    pub fn location(&self) -> Option<&DecodeErrorLocation> {
+        match self {
+            DecodeError::WithLocation(loc, _) => Some(loc),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn with_property(self, property: u8, unparsed: usize) -> Self {
+        match self {
+            DecodeError::WithLocation(..) => self,
+            // offset holds size of unparsed properties until packet is known
+            err => DecodeError::WithLocation(
+                DecodeErrorLocation {
+                    packet_type: 0,
+                    property: Some(property),
+                    offset: unparsed,
+                },
+                Box::new(err),
+            ),
+        }
+    }
+
+    pub(crate) fn with_packet(self, packet_type: u8, consumed: usize) -> Self {
+        match self {
+            DecodeError::WithLocation(mut loc, err) => {
+                loc.packet_type = packet_type;
+                loc.offset = consumed.saturating_sub(loc.offset);
+                DecodeError::WithLocation(loc, err)
+            }
+            err => DecodeError::WithLocation(
+                DecodeErrorLocation { packet_type, property: None, offset: consumed },
+                Box::new(err),
+            ),
+        }
+    }
+}
+
+impl Error for DecodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DecodeError::Utf8Error(err) => Some(err),
+            DecodeError::WithLocation(_, err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Location of malformed data within a packet
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DecodeErrorLocation {
+    /// Packet type, first byte of fixed header
+    pub packet_type: u8,
+    /// Property identifier, if error occurred while decoding a property (MQTT v5 only)
+    pub property: Option<u8>,
+    /// Byte offset of malformed data, counted from the start of variable header
+    pub offset: usize,
+}
+
+impl fmt::Display for DecodeErrorLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "packet type {:#04x}", self.packet_type)?;
+        if let Some(property) = self.property {
+            write!(f, ", property {:#04x}", property)?;
+        }
+        write!(f, ", offset {}", self.offset)
+    }
 }
 
 #[derive(Copy, Clone, Debug, Display, PartialEq, Eq, Hash)]
@@ -106,6 +328,8 @@ pub enum EncodeError {
     UnsupportedVersion,
 }
 
+impl Error for EncodeError {}
+
 impl PartialEq for DecodeError {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
@@ -121,6 +345,21 @@ impl PartialEq for DecodeError {
             (DecodeError::PacketIdRequired, DecodeError::PacketIdRequired) => true,
             (DecodeError::MaxSizeExceeded, DecodeError::MaxSizeExceeded) => true,
             (DecodeError::MalformedPacket, DecodeError::MalformedPacket) => true,
+            (DecodeError::InvalidPacketFlags, DecodeError::InvalidPacketFlags) => true,
+            (DecodeError::InvalidQoS, DecodeError::InvalidQoS) => true,
+            (DecodeError::InvalidWillFlags, DecodeError::InvalidWillFlags) => true,
+            (
+                DecodeError::InvalidSubscriptionOptions,
+                DecodeError::InvalidSubscriptionOptions,
+            ) => true,
+            (DecodeError::PasswordWithoutUsername, DecodeError::PasswordWithoutUsername) => {
+                true
+            }
+            (DecodeError::EmptyTopicFilters, DecodeError::EmptyTopicFilters) => true,
+            (DecodeError::InvalidUtf8String, DecodeError::InvalidUtf8String) => true,
+            (DecodeError::WithLocation(l1, e1), DecodeError::WithLocation(l2, e2)) => {
+                l1 == l2 && e1 == e2
+            }
             (DecodeError::Utf8Error(_), _) => false,
             _ => false,
         }
@@ -137,4 +376,106 @@ pub enum SendPacketError {
     /// Peer disconnected
     #[display(fmt = "Peer disconnected")]
     Disconnected,
+    /// Authentication exchange is in progress
+    #[display(fmt = "Authentication exchange is in progress")]
+    AuthInProgress,
+    /// Peer did not acknowledge packet in time
+    #[display(fmt = "Acknowledgement timeout")]
+    AckTimeout,
+    /// Receive credit is not available in time
+    #[display(fmt = "Receive credit timeout")]
+    CreditTimeout,
+    /// Receive credit is not available, packet is not sent
+    #[display(fmt = "Receive credit is not available")]
+    NoCredit,
+    /// All packet ids are used by in-flight packets
+    #[display(fmt = "Packet ids are exhausted")]
+    PacketIdsExhausted,
+    /// Topic name or topic filter is not valid
+    #[display(fmt = "Invalid topic: {}", _0)]
+    InvalidTopic(TopicError),
+    /// Subscription identifier is set in client publish
+    #[display(fmt = "Subscription identifier is not allowed in client publish")]
+    SubscriptionId,
+    /// Payload serialization error
+    #[display(fmt = "Payload serialization error: {}", _0)]
+    Serialize(String),
+}
+
+impl Error for SendPacketError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SendPacketError::Encode(err) => Some(err),
+            SendPacketError::InvalidTopic(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classification() {
+        let err: MqttError<io::Error> = MqttError::HandshakeTimeout;
+        assert!(err.is_timeout() && !err.is_io() && !err.is_protocol());
+
+        let err: MqttError<io::Error> = ProtocolError::KeepAliveTimeout.into();
+        assert!(err.is_timeout() && !err.is_protocol());
+
+        let err: MqttError<io::Error> =
+            ProtocolError::Io(io::Error::new(io::ErrorKind::Other, "test")).into();
+        assert!(err.is_io() && !err.is_protocol());
+        assert_eq!(err.source().unwrap().source().unwrap().to_string(), "test");
+
+        let err: MqttError<io::Error> =
+            ProtocolError::Unexpected(packet_type::AUTH, "AUTH").into();
+        assert!(err.is_auth() && err.is_protocol());
+
+        let decode = DecodeError::InvalidLength.with_packet(packet_type::CONNECT, 2);
+        let err: MqttError<io::Error> = ProtocolError::Decode(decode).into();
+        assert!(err.is_protocol() && !err.is_auth());
+        let source = err.source().unwrap().source().unwrap().source().unwrap();
+        assert_eq!(source.to_string(), "InvalidLength");
+
+        let err: MqttError<io::Error> = MqttError::Service(io::ErrorKind::Other.into());
+        assert!(!err.is_io() && !err.is_protocol() && !err.is_timeout());
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn test_client_auth_classification() {
+        use crate::{v3, v5};
+
+        let err = v3::error::ClientError::Ack {
+            session_present: false,
+            return_code: v3::codec::ConnectAckReason::BadUserNameOrPassword,
+        };
+        assert!(err.is_auth());
+        let err = v3::error::ClientError::Ack {
+            session_present: false,
+            return_code: v3::codec::ConnectAckReason::ServiceUnavailable,
+        };
+        assert!(!err.is_auth());
+
+        let err = v5::error::ClientError::Ack(v5::codec::ConnectAck {
+            reason_code: v5::codec::ConnectAckReason::NotAuthorized,
+            ..Default::default()
+        });
+        assert!(err.is_auth());
+        let err = v5::error::ClientError::Ack(v5::codec::ConnectAck {
+            reason_code: v5::codec::ConnectAckReason::ServerBusy,
+            ..Default::default()
+        });
+        assert!(!err.is_auth());
+        let err = v5::error::ClientError::Protocol(ProtocolError::Unexpected(
+            packet_type::AUTH,
+            "AUTH",
+        ));
+        assert!(err.is_auth());
+
+        assert!(v5::codec::DisconnectReasonCode::BadAuthenticationMethod.is_auth());
+        assert!(!v5::codec::DisconnectReasonCode::ServerBusy.is_auth());
+    }
 }
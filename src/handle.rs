@@ -0,0 +1,101 @@
+//! Thread-safe handle for mqtt sink
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{future::Future, rc::Rc, sync::Arc};
+
+use futures_channel::{mpsc, oneshot};
+use ntex::util::{next, poll_fn, select};
+
+use crate::{error::SendPacketError, io::State};
+
+/// Number of queued requests, callers wait if queue is full
+const BUFFER: usize = 32;
+
+/// Connection state that executes requests of the handle
+pub(crate) trait HandleSink: 'static {
+    type Request: Send + 'static;
+
+    /// Connection's io state
+    fn io_state(&self) -> &State;
+
+    /// Execute request on connection's thread
+    fn execute(self: Rc<Self>, req: Self::Request);
+
+    /// Close connection
+    fn close(self: Rc<Self>);
+}
+
+/// Bounded queue of requests to the connection's thread
+pub(crate) struct Handle<R> {
+    tx: mpsc::Sender<R>,
+    close: Arc<AtomicBool>,
+}
+
+impl<R: Send + 'static> Handle<R> {
+    /// Spawn forwarding task on current thread, must be called on connection's thread
+    ///
+    /// Task holds weak reference to the connection, it stops when connection
+    /// get disconnected or when all handles are dropped.
+    pub(crate) fn new<S>(shared: &Rc<S>) -> Self
+    where
+        S: HandleSink<Request = R>,
+    {
+        let (tx, mut rx) = mpsc::channel(BUFFER);
+        let close = Arc::new(AtomicBool::new(false));
+        let disconnect = shared.io_state().on_disconnect();
+        let shared = Rc::downgrade(shared);
+        let closed = close.clone();
+
+        ntex::rt::spawn(async move {
+            let requests = async {
+                while let Some(req) = next(&mut rx).await {
+                    if let Some(shared) = shared.upgrade() {
+                        shared.execute(req);
+                    } else {
+                        return;
+                    }
+                }
+                if closed.load(Ordering::Acquire) {
+                    if let Some(shared) = shared.upgrade() {
+                        shared.close();
+                    }
+                }
+            };
+            // pending requests fail with `Disconnected` error
+            let _ = select(requests, disconnect).await;
+        });
+
+        Handle { tx, close }
+    }
+
+    /// Check if connection's thread accepts requests
+    pub(crate) fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// Send request, wait for queue capacity and for response
+    pub(crate) fn request<T: Send>(
+        &self,
+        req: R,
+        rx: oneshot::Receiver<Result<T, SendPacketError>>,
+    ) -> impl Future<Output = Result<T, SendPacketError>> + Send {
+        let mut tx = self.tx.clone();
+
+        async move {
+            poll_fn(|cx| tx.poll_ready(cx)).await.map_err(|_| SendPacketError::Disconnected)?;
+            tx.start_send(req).map_err(|_| SendPacketError::Disconnected)?;
+            rx.await.unwrap_or(Err(SendPacketError::Disconnected))
+        }
+    }
+
+    /// Close connection after queued requests
+    pub(crate) fn close(&self) {
+        self.close.store(true, Ordering::Release);
+        self.tx.clone().close_channel();
+    }
+}
+
+impl<R> Clone for Handle<R> {
+    fn clone(&self) -> Self {
+        Handle { tx: self.tx.clone(), close: self.close.clone() }
+    }
+}
@@ -0,0 +1,19 @@
+//! Raw packet inspection hooks
+use std::rc::Rc;
+
+/// Read-only inspector of mqtt packets
+///
+/// Inspector sees every packet decoded from the peer's stream and every
+/// packet before it gets encoded, including handshake packets. `P` is packet
+/// type of protocol version, `v3::codec::Packet` or `v5::codec::Packet`.
+/// All methods have no-op default implementation.
+#[allow(unused_variables)]
+pub trait PacketInspector<P> {
+    /// Packet is received from the peer
+    fn inbound(&self, packet: &P) {}
+
+    /// Packet is about to be sent to the peer
+    fn outbound(&self, packet: &P) {}
+}
+
+pub(crate) type Inspector<P> = Option<Rc<dyn PacketInspector<P>>>;
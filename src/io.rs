@@ -1,10 +1,12 @@
 //! Framed transport dispatcher
 use std::task::{Context, Poll};
-use std::{cell::RefCell, collections::VecDeque, future::Future, pin::Pin, rc::Rc, time};
+use std::{cell::RefCell, collections::VecDeque, future::Future, io, ops::Deref, pin::Pin};
+use std::{rc::Rc, time};
 
 pub(crate) use ntex::framed::{DispatchItem, ReadTask, State, Timer, Write, WriteTask};
 
-use ntex::codec::{AsyncRead, AsyncWrite, Decoder, Encoder};
+use ntex::codec::{AsyncRead, AsyncWrite, Decoder, Encoder, ReadBuf};
+use ntex::rt::time::{sleep, Sleep};
 use ntex::service::{IntoService, Service};
 use ntex::util::Either;
 
@@ -29,6 +31,7 @@ pin_project_lite::pin_project! {
         timer: Timer,
         updated: time::Instant,
         keepalive_timeout: u16,
+        write_timer: Option<WriteTimer>,
         #[pin]
         response: Option<S::Future>,
         response_idx: usize,
@@ -56,6 +59,178 @@ impl<T> ServiceResult<T> {
     }
 }
 
+/// Write buffer limit timer
+struct WriteTimer {
+    limit: usize,
+    timeout: time::Duration,
+    delay: Option<Pin<Box<Sleep>>>,
+    exceeded: bool,
+}
+
+impl WriteTimer {
+    /// Check write buffer size once per timeout period
+    ///
+    /// Returns `true` if buffer size exceeds the limit on two consecutive checks.
+    fn poll_expired(&mut self, state: &State, cx: &mut Context<'_>) -> bool {
+        if let Some(ref mut delay) = self.delay {
+            if delay.as_mut().poll(cx).is_pending() {
+                return false;
+            }
+        }
+
+        let exceeded = state.write().with_buf(|buf| buf.len()) >= self.limit;
+        let expired = exceeded && self.exceeded;
+        self.exceeded = exceeded && !expired;
+
+        let mut delay = Box::pin(sleep(self.timeout));
+        let _ = delay.as_mut().poll(cx);
+        self.delay = Some(delay);
+        expired
+    }
+}
+
+/// Io stream with write coalescing
+///
+/// Write task writes whole write buffer at once, so packets encoded while
+/// write is delayed are sent with single write call. Write is delayed for up to
+/// `delay` if buffer is smaller than `size` bytes and contains less than `packets`
+/// packets. Remaining data of partial write is written without delay.
+pub(crate) struct CoalescedIo<T> {
+    io: T,
+    params: Option<(usize, usize, time::Duration)>,
+    delay: Option<Pin<Box<Sleep>>>,
+    flushing: bool,
+}
+
+impl<T> CoalescedIo<T> {
+    pub(crate) fn new(io: T, params: Option<(usize, usize, time::Duration)>) -> Self {
+        CoalescedIo { io, params, delay: None, flushing: false }
+    }
+
+    /// Wait for write delay, returns `Pending` if write is delayed
+    fn poll_delay<B: Deref<Target = [u8]>>(
+        &mut self,
+        cx: &mut Context<'_>,
+        bufs: &[B],
+    ) -> Poll<()> {
+        if let Some((size, packets, delay)) = self.params {
+            if !self.flushing
+                && bufs.iter().map(|b| b.len()).sum::<usize>() < size
+                && (packets == 0 || count_packets(bufs, packets) < packets)
+            {
+                let delay = self.delay.get_or_insert_with(|| Box::pin(sleep(delay)));
+                if delay.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+            }
+            self.delay = None;
+            self.flushing = true;
+        }
+        Poll::Ready(())
+    }
+
+    fn written(&mut self, result: &Poll<io::Result<usize>>, len: usize) {
+        if let Poll::Ready(Ok(n)) = result {
+            // write buffer is flushed
+            if *n == len {
+                self.flushing = false;
+            }
+        }
+    }
+}
+
+/// Count mqtt packets in buffer, up to `max` packets
+///
+/// Buffer always starts at packet boundary, trailing partially
+/// encoded packet is not counted.
+fn count_packets<B: Deref<Target = [u8]>>(bufs: &[B], max: usize) -> usize {
+    let mut bytes = bufs.iter().flat_map(|b| b.iter().copied());
+    let mut count = 0;
+
+    while count < max {
+        // fixed header, packet type and variable length remaining length
+        if bytes.next().is_none() {
+            break;
+        }
+        let mut len = 0;
+        let mut shift = 0;
+        loop {
+            match bytes.next() {
+                Some(b) => {
+                    len |= ((b & 0x7F) as usize) << shift;
+                    if b & 0x80 == 0 {
+                        break;
+                    }
+                    shift += 7;
+                    if shift > 21 {
+                        return count;
+                    }
+                }
+                None => return count,
+            }
+        }
+        if len > 0 && bytes.nth(len - 1).is_none() {
+            break;
+        }
+        count += 1;
+    }
+    count
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CoalescedIo<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CoalescedIo<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.poll_delay(cx, &[buf]).is_pending() {
+            return Poll::Pending;
+        }
+
+        let result = Pin::new(&mut this.io).poll_write(cx, buf);
+        this.written(&result, buf.len());
+        result
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.poll_delay(cx, bufs).is_pending() {
+            return Poll::Pending;
+        }
+
+        let result = Pin::new(&mut this.io).poll_write_vectored(cx, bufs);
+        this.written(&result, bufs.iter().map(|b| b.len()).sum());
+        result
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 enum IoDispatcherState {
     Processing,
@@ -65,7 +240,6 @@ enum IoDispatcherState {
 
 pub(crate) enum IoDispatcherError<S, U> {
     None,
-    KeepAlive,
     Encoder(U),
     Service(S),
 }
@@ -85,10 +259,6 @@ impl<E1, E2: std::fmt::Debug> IoDispatcherError<E1, E2> {
         U: Encoder<Error = E2> + Decoder,
     {
         match self {
-            IoDispatcherError::KeepAlive => {
-                *self = IoDispatcherError::None;
-                Some(DispatchItem::KeepAliveTimeout)
-            }
             IoDispatcherError::Encoder(_) => {
                 let err = std::mem::replace(self, IoDispatcherError::None);
                 match err {
@@ -147,6 +317,7 @@ where
             timer,
             updated,
             keepalive_timeout,
+            write_timer: None,
         }
     }
 
@@ -170,6 +341,23 @@ where
         self
     }
 
+    /// Set write buffer limit and timeout in seconds.
+    ///
+    /// If write buffer size exceeds the limit for longer than timeout,
+    /// `DispatchItem::WBackPressureEnabled` is emitted.
+    ///
+    /// By default write buffer is not limited.
+    pub(crate) fn write_limit(mut self, limit: Option<(usize, u16)>) -> Self {
+        self.write_timer =
+            limit.filter(|(_, timeout)| *timeout != 0).map(|(limit, timeout)| WriteTimer {
+                limit,
+                timeout: time::Duration::from_secs(timeout as u64),
+                delay: None,
+                exceeded: false,
+            });
+        self
+    }
+
     /// Set connection disconnect timeout in milliseconds.
     ///
     /// Defines a timeout for disconnect connection. If a disconnect procedure does not complete
@@ -272,16 +460,6 @@ where
                             // service is ready, wake io read task
                             read.resume();
 
-                            // check keepalive timeout
-                            if this.state.is_keepalive() {
-                                log::trace!("keepalive timeout");
-                                let mut inner = this.inner.borrow_mut();
-                                if inner.error.is_none() {
-                                    inner.error = Some(IoDispatcherError::KeepAlive);
-                                }
-                                this.state.dispatcher_stopped();
-                            }
-
                             let item = if this.state.is_dispatcher_stopped() {
                                 log::trace!("dispatcher is instructed to stop");
                                 let mut inner = this.inner.borrow_mut();
@@ -309,6 +487,30 @@ where
                                 retry = true;
 
                                 item
+                            } else if this.state.is_keepalive() {
+                                // keepalive timeout, service decides if connection
+                                // must be closed. timer is re-armed for next period
+                                log::trace!("keepalive timeout");
+                                this.state.reset_keepalive();
+                                let updated = this.timer.now();
+                                let ka =
+                                    time::Duration::from_secs(*this.keepalive_timeout as u64);
+                                this.timer.register(
+                                    updated + ka,
+                                    *this.updated + ka,
+                                    this.state,
+                                );
+                                *this.updated = updated;
+
+                                Some(DispatchItem::KeepAliveTimeout)
+                            } else if this
+                                .write_timer
+                                .as_mut()
+                                .map_or(false, |timer| timer.poll_expired(this.state, cx))
+                            {
+                                // peer does not read from connection
+                                log::trace!("write buffer limit is exceeded");
+                                Some(DispatchItem::WBackPressureEnabled)
                             } else {
                                 // decode incoming bytes stream
                                 if read.is_ready() {
@@ -538,6 +740,7 @@ mod tests {
                 codec,
                 updated,
                 keepalive_timeout,
+                write_timer: None,
             }
         }
     }
@@ -686,4 +889,78 @@ mod tests {
         client.close().await;
         assert!(client.is_server_dropped());
     }
+
+    #[ntex::test]
+    async fn test_write_coalesce() {
+        let (client, server) = Io::create();
+        client.remote_buffer_cap(1024);
+
+        let st = State::new();
+        let io = CoalescedIo::new(server, Some((8, 0, time::Duration::from_millis(100))));
+        let disp = Dispatcher::new(
+            io,
+            BytesCodec,
+            st.clone(),
+            ntex::fn_service(|_: DispatchItem<BytesCodec>| async move {
+                Ok::<Option<Bytes>, ()>(None)
+            }),
+        );
+        ntex::rt::spawn(async move {
+            let _ = disp.await;
+        });
+
+        // small writes are delayed and sent together
+        assert!(st.write().encode(Bytes::from_static(b"test"), &BytesCodec).is_ok());
+        sleep(time::Duration::from_millis(25)).await;
+        assert!(client.read_any().is_empty());
+        assert!(st.write().encode(Bytes::from_static(b"test"), &BytesCodec).is_ok());
+        sleep(time::Duration::from_millis(150)).await;
+        assert_eq!(&client.read_any()[..], b"testtest");
+
+        // large buffer is written without delay
+        assert!(st.write().encode(Bytes::from_static(b"large buffer"), &BytesCodec).is_ok());
+        sleep(time::Duration::from_millis(25)).await;
+        assert_eq!(&client.read_any()[..], b"large buffer");
+
+        st.close();
+    }
+
+    #[ntex::test]
+    async fn test_write_coalesce_packets() {
+        let (client, server) = Io::create();
+        client.remote_buffer_cap(1024);
+
+        let st = State::new();
+        let io = CoalescedIo::new(server, Some((1024, 2, time::Duration::from_millis(100))));
+        let disp = Dispatcher::new(
+            io,
+            BytesCodec,
+            st.clone(),
+            ntex::fn_service(|_: DispatchItem<BytesCodec>| async move {
+                Ok::<Option<Bytes>, ()>(None)
+            }),
+        );
+        ntex::rt::spawn(async move {
+            let _ = disp.await;
+        });
+
+        // buffer is written without delay once it contains two packets
+        assert!(st.write().encode(Bytes::from_static(b"\x30\x02ab"), &BytesCodec).is_ok());
+        sleep(time::Duration::from_millis(25)).await;
+        assert!(client.read_any().is_empty());
+        assert!(st.write().encode(Bytes::from_static(b"\xC0\x00"), &BytesCodec).is_ok());
+        sleep(time::Duration::from_millis(25)).await;
+        assert_eq!(&client.read_any()[..], b"\x30\x02ab\xC0\x00");
+
+        st.close();
+    }
+
+    #[test]
+    fn test_count_packets() {
+        let buf: &[u8] = b"\x30\x02ab\xC0\x00\x30\x80\x01";
+        assert_eq!(count_packets(&[buf], 10), 2);
+        assert_eq!(count_packets(&[buf], 1), 1);
+        assert_eq!(count_packets(&[&buf[..3], &buf[3..]], 10), 2);
+        assert_eq!(count_packets(&[&buf[..3]], 10), 0);
+    }
 }
@@ -7,21 +7,61 @@ mod topic;
 #[macro_use]
 mod utils;
 
+pub mod auth;
 pub mod error;
 pub mod v3;
 pub mod v5;
 
+#[cfg(feature = "broker")]
+pub mod broker;
+#[cfg(feature = "mqtt-sn")]
+pub mod sn;
+
+mod acl;
+mod backoff;
+mod drain;
+mod handle;
+mod inspect;
 mod io;
+mod metrics;
+mod offline;
+mod params;
+mod payload;
+mod proxy;
+mod proxy_protocol;
+mod ratelimit;
+mod routes;
+#[cfg(feature = "serialize")]
+mod serialize;
 mod server;
 mod service;
 mod session;
+mod stats;
+mod trace;
+mod tree;
 pub mod types;
+#[cfg(unix)]
+mod uds;
 mod version;
+pub mod ws;
 
+pub use self::acl::Acl;
+pub use self::drain::Drain;
 pub use self::error::MqttError;
+pub use self::inspect::PacketInspector;
+pub use self::metrics::{MqttMetrics, NoopMetrics};
+pub use self::params::Params;
+pub use self::payload::Payload;
+pub use self::proxy_protocol::{ProxyProtocol, ProxyStream};
+pub use self::ratelimit::RateLimitPolicy;
+pub use self::routes::RouteTable;
 pub use self::server::MqttServer;
 pub use self::session::Session;
-pub use self::topic::{Level as TopicLevel, Topic};
+pub use self::stats::ServerStats;
+pub use self::topic::{
+    Level as TopicLevel, Topic, TopicError, TopicFilter, TopicName, TopicRef,
+};
+pub use self::tree::{SubscriptionMatches, SubscriptionTree};
 
 // http://www.iana.org/assignments/service-names-port-numbers/service-names-port-numbers.xhtml
 pub const TCP_PORT: u16 = 1883;
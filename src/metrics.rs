@@ -0,0 +1,68 @@
+//! Connection metrics hooks
+use std::{rc::Rc, time::Duration};
+
+/// Mqtt connection metrics
+///
+/// Metrics are reported for every connection of the server or client connector
+/// they are installed on. All methods have no-op default implementation.
+///
+/// Packet type is the first byte of packet's fixed header, flags of
+/// PUBLISH packet are cleared, i.e. `0x30` for any PUBLISH packet.
+#[allow(unused_variables)]
+pub trait MqttMetrics {
+    /// Packet is received from the peer
+    fn packet_received(&self, packet_type: u8, size: usize) {}
+
+    /// Packet is sent to the peer
+    fn packet_sent(&self, packet_type: u8, size: usize) {}
+
+    /// Handshake is completed, connection is established
+    fn connection_opened(&self) {}
+
+    /// Established connection is closed
+    fn connection_closed(&self) {}
+
+    /// Handshake failed or timed out
+    fn handshake_failed(&self) {}
+
+    /// Peer acknowledged publish packet
+    ///
+    /// Packet type is the type of ack packet, PUBACK or PUBREC.
+    fn ack_latency(&self, packet_type: u8, latency: Duration) {}
+}
+
+/// Metrics that do nothing
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NoopMetrics;
+
+impl MqttMetrics for NoopMetrics {}
+
+pub(crate) type Metrics = Rc<dyn MqttMetrics>;
+
+pub(crate) fn noop() -> Metrics {
+    Rc::new(NoopMetrics)
+}
+
+/// Reports failed handshake if it is dropped before completion
+pub(crate) struct HandshakeGuard(Option<Metrics>);
+
+impl HandshakeGuard {
+    pub(crate) fn new(metrics: Metrics) -> Self {
+        HandshakeGuard(Some(metrics))
+    }
+
+    /// Handshake is completed, connection is opened
+    pub(crate) fn complete(mut self) {
+        if let Some(metrics) = self.0.take() {
+            metrics.connection_opened();
+        }
+    }
+}
+
+impl Drop for HandshakeGuard {
+    fn drop(&mut self) {
+        if let Some(metrics) = self.0.take() {
+            metrics.handshake_failed();
+        }
+    }
+}
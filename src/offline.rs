@@ -0,0 +1,85 @@
+//! Offline publish buffer
+use std::collections::VecDeque;
+
+/// Offline buffer overflow policy
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OfflinePolicy {
+    /// Drop oldest buffered message
+    DropOldest,
+    /// Drop new message
+    DropNewest,
+    /// Reject new message with `Disconnected` error
+    Reject,
+}
+
+/// Bounded buffer for messages published while client is disconnected
+pub(crate) struct OfflineBuffer<T> {
+    items: VecDeque<T>,
+    capacity: usize,
+    policy: OfflinePolicy,
+}
+
+impl<T> OfflineBuffer<T> {
+    pub(crate) fn new(capacity: usize, policy: OfflinePolicy) -> Self {
+        OfflineBuffer { capacity, policy, items: VecDeque::new() }
+    }
+
+    /// Add message to the buffer
+    ///
+    /// Returns message back if it is rejected.
+    pub(crate) fn push(&mut self, item: T) -> Result<(), T> {
+        if self.capacity == 0 {
+            return Err(item);
+        }
+        if self.items.len() >= self.capacity {
+            match self.policy {
+                OfflinePolicy::DropOldest => {
+                    log::trace!("Offline buffer is full, drop oldest message");
+                    self.items.pop_front();
+                }
+                OfflinePolicy::DropNewest => {
+                    log::trace!("Offline buffer is full, drop new message");
+                    return Ok(());
+                }
+                OfflinePolicy::Reject => return Err(item),
+            }
+        }
+        self.items.push_back(item);
+        Ok(())
+    }
+
+    /// Take all buffered messages
+    pub(crate) fn take(&mut self) -> VecDeque<T> {
+        std::mem::take(&mut self.items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy() {
+        let mut buf = OfflineBuffer::new(0, OfflinePolicy::DropOldest);
+        assert_eq!(buf.push(1), Err(1));
+
+        let mut buf = OfflineBuffer::new(2, OfflinePolicy::DropOldest);
+        assert!(buf.push(1).is_ok());
+        assert!(buf.push(2).is_ok());
+        assert!(buf.push(3).is_ok());
+        assert_eq!(buf.take(), vec![2, 3]);
+        assert!(buf.take().is_empty());
+
+        let mut buf = OfflineBuffer::new(2, OfflinePolicy::DropNewest);
+        assert!(buf.push(1).is_ok());
+        assert!(buf.push(2).is_ok());
+        assert!(buf.push(3).is_ok());
+        assert_eq!(buf.take(), vec![1, 2]);
+
+        let mut buf = OfflineBuffer::new(2, OfflinePolicy::Reject);
+        assert!(buf.push(1).is_ok());
+        assert!(buf.push(2).is_ok());
+        assert_eq!(buf.push(3), Err(3));
+        assert_eq!(buf.take(), vec![1, 2]);
+    }
+}
@@ -0,0 +1,74 @@
+//! Typed access to parameters of matched router resource
+use std::str::FromStr;
+
+use ntex::router::Path;
+use ntex::util::ByteString;
+
+/// Named parameters of matched router resource
+///
+/// Parameters are populated for resources registered with `Router::resource()`,
+/// i.e. `devices/{id}/telemetry/{metric}`.
+#[derive(Debug, Copy, Clone)]
+pub struct Params<'a>(&'a Path<ByteString>);
+
+impl<'a> Params<'a> {
+    pub(crate) fn new(path: &'a Path<ByteString>) -> Self {
+        Params(path)
+    }
+
+    #[inline]
+    /// Number of parameters
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    #[inline]
+    /// Check if there are no parameters
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    #[inline]
+    /// Get parameter value without type conversion
+    pub fn get_str(&self, name: &str) -> Option<&'a str> {
+        self.0.get(name)
+    }
+
+    /// Get parameter value converted to `T`
+    ///
+    /// Returns `None` if parameter is not found or could not be converted.
+    pub fn get<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.get_str(name).and_then(|val| val.parse().ok())
+    }
+
+    /// Get parameter value converted to `T`, conversion error is returned as is
+    ///
+    /// Returns `None` if parameter is not found.
+    pub fn parse<T: FromStr>(&self, name: &str) -> Option<Result<T, T::Err>> {
+        self.get_str(name).map(|val| val.parse())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_params() {
+        let mut router = ntex::router::Router::<usize>::build();
+        router.path("devices/{id}/telemetry/{metric}", 0);
+        let router = router.finish();
+
+        let mut path = Path::new(ByteString::from_static("devices/12/telemetry/temp"));
+        assert!(router.recognize(&mut path).is_some());
+
+        let params = Params::new(&path);
+        assert_eq!(params.len(), 2);
+        assert_eq!(params.get::<u64>("id"), Some(12));
+        assert_eq!(params.get::<String>("metric"), Some("temp".to_string()));
+        assert_eq!(params.get::<u64>("metric"), None);
+        assert_eq!(params.get::<u64>("unknown"), None);
+        assert!(params.parse::<u64>("metric").unwrap().is_err());
+        assert_eq!(params.get_str("metric"), Some("temp"));
+    }
+}
@@ -0,0 +1,188 @@
+//! Streaming publish payload
+use std::task::{Context, Poll};
+use std::{cell::Cell, cmp, fmt, io, pin::Pin, rc::Rc};
+
+use ntex::channel::mpsc;
+use ntex::task::LocalWaker;
+use ntex::util::{poll_fn, Bytes, BytesMut};
+use ntex::Stream;
+
+/// Payload of streamed publish packet
+///
+/// Stream yields payload chunks while rest of the packet is still being
+/// received. Chunks are buffered until they get consumed, connection stops
+/// reading from the socket while size of buffered chunks exceeds streaming
+/// threshold. Stream fails with `UnexpectedEof` error if connection gets
+/// closed before payload is received.
+pub struct Payload {
+    rx: mpsc::Receiver<Result<Bytes, io::Error>>,
+    size: usize,
+    buffer: Rc<Buffer>,
+}
+
+/// Size of buffered payload chunks
+struct Buffer {
+    size: Cell<usize>,
+    limit: usize,
+    waker: LocalWaker,
+}
+
+impl Buffer {
+    fn consumed(&self, size: usize) {
+        let prev = self.size.get();
+        self.size.set(prev.saturating_sub(size));
+        if prev >= self.limit && self.size.get() < self.limit {
+            self.waker.wake()
+        }
+    }
+}
+
+impl Payload {
+    /// Payload size
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Read whole payload
+    pub async fn read_all(mut self) -> Result<Bytes, io::Error> {
+        let mut buf = BytesMut::with_capacity(self.size);
+        while let Some(chunk) = poll_fn(|cx| Pin::new(&mut self).poll_next(cx)).await {
+            buf.extend_from_slice(&chunk?);
+        }
+        Ok(buf.freeze())
+    }
+}
+
+impl Stream for Payload {
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = Pin::new(&mut self.rx).poll_next(cx);
+        if let Poll::Ready(Some(Ok(ref chunk))) = item {
+            self.buffer.consumed(chunk.len());
+        }
+        item
+    }
+}
+
+impl Drop for Payload {
+    fn drop(&mut self) {
+        // chunks are not consumed anymore, resume reading
+        self.buffer.consumed(usize::MAX);
+    }
+}
+
+impl fmt::Debug for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Payload").field("size", &self.size).finish()
+    }
+}
+
+/// Sending side of the payload stream, used by codec
+pub(crate) struct PayloadSender {
+    tx: mpsc::Sender<Result<Bytes, io::Error>>,
+    remaining: usize,
+    buffer: Rc<Buffer>,
+}
+
+impl PayloadSender {
+    /// Create payload stream, `limit` is max size of buffered chunks
+    pub(crate) fn create(size: usize, limit: usize) -> (PayloadSender, Payload) {
+        let (tx, rx) = mpsc::channel();
+        let buffer =
+            Rc::new(Buffer { size: Cell::new(0), limit, waker: LocalWaker::default() });
+        (
+            PayloadSender { tx, remaining: size, buffer: buffer.clone() },
+            Payload { rx, size, buffer },
+        )
+    }
+
+    /// Check if stream could buffer more payload chunks
+    pub(crate) fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.buffer.size.get() >= self.buffer.limit {
+            self.buffer.waker.register(cx.waker());
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+
+    /// Move payload bytes from read buffer to the stream
+    ///
+    /// Returns `true` if whole payload is received.
+    pub(crate) fn feed(&mut self, src: &mut BytesMut) -> bool {
+        let size = cmp::min(src.len(), self.remaining);
+        if size > 0 {
+            self.remaining -= size;
+            if self.tx.send(Ok(src.split_to(size).freeze())).is_ok() {
+                let buffer = &self.buffer;
+                buffer.size.set(buffer.size.get() + size);
+            }
+        }
+        if self.remaining == 0 {
+            self.tx.close();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Drop for PayloadSender {
+    fn drop(&mut self) {
+        if self.remaining != 0 {
+            let _ = self.tx.send(Err(io::ErrorKind::UnexpectedEof.into()));
+            self.tx.close();
+        }
+    }
+}
+
+impl fmt::Debug for PayloadSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PayloadSender").field("remaining", &self.remaining).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ntex::test]
+    async fn test_payload() {
+        let (mut tx, rx) = PayloadSender::create(6, 1024);
+        let mut buf = BytesMut::from(&b"abc"[..]);
+        assert!(!tx.feed(&mut buf));
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"defgh"[..]);
+        assert!(tx.feed(&mut buf));
+        assert_eq!(&buf[..], b"gh");
+        assert_eq!(rx.size(), 6);
+        assert_eq!(rx.read_all().await.unwrap(), Bytes::from_static(b"abcdef"));
+
+        let (mut tx, rx) = PayloadSender::create(6, 1024);
+        let mut buf = BytesMut::from(&b"abc"[..]);
+        assert!(!tx.feed(&mut buf));
+        drop(tx);
+        let err = rx.read_all().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[ntex::test]
+    async fn test_payload_buffer_limit() {
+        let (mut tx, mut rx) = PayloadSender::create(6, 3);
+        let mut buf = BytesMut::from(&b"abc"[..]);
+        assert!(!tx.feed(&mut buf));
+        assert!(poll_fn(|cx| Poll::Ready(tx.poll_ready(cx).is_pending())).await);
+
+        let chunk = poll_fn(|cx| Pin::new(&mut rx).poll_next(cx)).await;
+        assert_eq!(chunk.unwrap().unwrap(), Bytes::from_static(b"abc"));
+        assert!(poll_fn(|cx| Poll::Ready(tx.poll_ready(cx).is_ready())).await);
+
+        // dropped stream does not block sender
+        let mut buf = BytesMut::from(&b"def"[..]);
+        assert!(tx.feed(&mut buf));
+        drop(rx);
+        assert!(poll_fn(|cx| Poll::Ready(tx.poll_ready(cx).is_ready())).await);
+    }
+}
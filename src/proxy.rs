@@ -0,0 +1,349 @@
+//! Client proxy support
+use std::task::{Context, Poll};
+use std::{future::Future, io, marker::PhantomData, net::IpAddr, pin::Pin, rc::Rc};
+
+use ntex::codec::{AsyncRead, AsyncWrite, ReadBuf};
+use ntex::connect::{Address, Connect, ConnectError, Connector};
+use ntex::rt::net::TcpStream;
+use ntex::service::Service;
+use ntex::util::{poll_fn, ByteString};
+
+use crate::ws::{base64, write_all};
+
+/// Max size of http proxy response headers
+const MAX_HEADERS_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ProxyKind {
+    Socks5,
+    Http,
+}
+
+/// Proxy configuration
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    kind: ProxyKind,
+    address: String,
+    credentials: Option<(ByteString, ByteString)>,
+}
+
+impl Proxy {
+    /// SOCKS5 proxy, address is in `host:port` format
+    pub fn socks5<U: Into<String>>(address: U) -> Self {
+        Proxy { kind: ProxyKind::Socks5, address: address.into(), credentials: None }
+    }
+
+    /// HTTP proxy with `CONNECT` method support, address is in `host:port` format
+    pub fn http<U: Into<String>>(address: U) -> Self {
+        Proxy { kind: ProxyKind::Http, address: address.into(), credentials: None }
+    }
+
+    /// Set proxy username and password
+    pub fn credentials<U, P>(mut self, username: U, password: P) -> Self
+    where
+        ByteString: From<U> + From<P>,
+    {
+        self.credentials = Some((ByteString::from(username), ByteString::from(password)));
+        self
+    }
+}
+
+/// Proxy connector
+///
+/// Connector opens tcp connection to the proxy server and tunnels
+/// it to the mqtt server.
+pub struct ProxyConnector<A> {
+    proxy: Rc<Proxy>,
+    connector: Connector<String>,
+    _t: PhantomData<A>,
+}
+
+impl<A> ProxyConnector<A> {
+    /// Create proxy connector
+    pub fn new(proxy: Proxy) -> Self {
+        ProxyConnector {
+            proxy: Rc::new(proxy),
+            connector: Connector::default(),
+            _t: PhantomData,
+        }
+    }
+}
+
+impl<A: Address> Service for ProxyConnector<A> {
+    type Request = Connect<A>;
+    type Response = TcpStream;
+    type Error = ConnectError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    #[inline]
+    fn poll_ready(&self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&self, req: Connect<A>) -> Self::Future {
+        let (host, port) = target(&req);
+        let proxy = self.proxy.clone();
+        let fut = self.connector.call(Connect::new(proxy.address.clone()));
+
+        Box::pin(async move {
+            let mut io = fut.await?;
+            tunnel(&mut io, &proxy, &host, port).await.map_err(ConnectError::Io)?;
+            Ok(io)
+        })
+    }
+}
+
+/// Host and port of the mqtt server
+fn target<A: Address>(req: &Connect<A>) -> (String, u16) {
+    if req.host().is_empty() {
+        if let Some(addr) = req.addrs().next() {
+            return (addr.ip().to_string(), addr.port());
+        }
+    }
+    let host = req.host();
+    let host = match host.rfind(':') {
+        Some(pos) if host[pos + 1..].parse::<u16>().is_ok() => &host[..pos],
+        _ => host,
+    };
+    (host.trim_start_matches('[').trim_end_matches(']').to_string(), req.port())
+}
+
+/// Establish tunnel to `host:port` through proxy connection
+pub(crate) async fn tunnel<Io>(
+    io: &mut Io,
+    proxy: &Proxy,
+    host: &str,
+    port: u16,
+) -> io::Result<()>
+where
+    Io: AsyncRead + AsyncWrite + Unpin,
+{
+    match proxy.kind {
+        ProxyKind::Socks5 => socks5(io, proxy, host, port).await,
+        ProxyKind::Http => http(io, proxy, host, port).await,
+    }
+}
+
+async fn socks5<Io>(io: &mut Io, proxy: &Proxy, host: &str, port: u16) -> io::Result<()>
+where
+    Io: AsyncRead + AsyncWrite + Unpin,
+{
+    // auth negotiation
+    if proxy.credentials.is_some() {
+        write_all(io, &[5, 2, 0, 2]).await?;
+    } else {
+        write_all(io, &[5, 1, 0]).await?;
+    }
+    let mut buf = [0u8; 2];
+    read_exact(io, &mut buf).await?;
+    if buf[0] != 5 {
+        return Err(invalid("Unsupported SOCKS version"));
+    }
+    match (buf[1], &proxy.credentials) {
+        (0, _) => (),
+        (2, Some((user, pass))) => {
+            if user.len() > 255 || pass.len() > 255 {
+                return Err(invalid("SOCKS5 credentials are too long"));
+            }
+            let mut req = vec![1, user.len() as u8];
+            req.extend_from_slice(user.as_bytes());
+            req.push(pass.len() as u8);
+            req.extend_from_slice(pass.as_bytes());
+            write_all(io, &req).await?;
+
+            read_exact(io, &mut buf).await?;
+            if buf[1] != 0 {
+                return Err(denied("SOCKS5 authentication failed"));
+            }
+        }
+        _ => return Err(denied("SOCKS5 proxy does not accept authentication method")),
+    }
+
+    // connect request
+    let mut req = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            req.push(1);
+            req.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            req.push(4);
+            req.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(invalid("Host name is too long"));
+            }
+            req.push(3);
+            req.push(host.len() as u8);
+            req.extend_from_slice(host.as_bytes());
+        }
+    }
+    req.extend_from_slice(&port.to_be_bytes());
+    write_all(io, &req).await?;
+
+    let mut buf = [0u8; 4];
+    read_exact(io, &mut buf).await?;
+    if buf[1] != 0 {
+        log::trace!("SOCKS5 connect request is rejected with code: {}", buf[1]);
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "SOCKS5 proxy rejected connect request",
+        ));
+    }
+
+    // skip bound address
+    let len = match buf[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0u8; 1];
+            read_exact(io, &mut len).await?;
+            len[0] as usize
+        }
+        _ => return Err(invalid("Unsupported SOCKS5 address type")),
+    };
+    let mut addr = vec![0u8; len + 2];
+    read_exact(io, &mut addr).await
+}
+
+async fn http<Io>(io: &mut Io, proxy: &Proxy, host: &str, port: u16) -> io::Result<()>
+where
+    Io: AsyncRead + AsyncWrite + Unpin,
+{
+    let addr = if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+    let mut req = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", addr, addr);
+    if let Some((user, pass)) = &proxy.credentials {
+        let auth = base64(format!("{}:{}", user, pass).as_bytes());
+        req.push_str(&format!("Proxy-Authorization: Basic {}\r\n", auth));
+    }
+    req.push_str("\r\n");
+    write_all(io, req.as_bytes()).await?;
+
+    // read response headers, byte by byte to not consume tunneled data
+    let mut buf = Vec::with_capacity(256);
+    let mut b = [0u8; 1];
+    while !buf.ends_with(b"\r\n\r\n") {
+        if buf.len() > MAX_HEADERS_SIZE {
+            return Err(invalid("Proxy response is too large"));
+        }
+        read_exact(io, &mut b).await?;
+        buf.push(b[0]);
+    }
+
+    let status = buf.split(|b| *b == b'\r').next().unwrap_or(&[]);
+    let status = String::from_utf8_lossy(status);
+    let mut parts = status.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(ver), Some("200")) if ver.starts_with("HTTP/1.") => Ok(()),
+        (_, Some("407")) => Err(denied("Proxy authentication required")),
+        _ => {
+            log::trace!("Proxy rejected connect request: {}", status);
+            Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "Proxy rejected connect request",
+            ))
+        }
+    }
+}
+
+pub(crate) async fn read_exact<Io: AsyncRead + Unpin>(
+    io: &mut Io,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        let n = poll_fn(|cx| {
+            let mut rbuf = ReadBuf::new(&mut buf[pos..]);
+            match Pin::new(&mut *io).poll_read(cx, &mut rbuf) {
+                Poll::Ready(Ok(())) => Poll::Ready(Ok(rbuf.filled().len())),
+                Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+                Poll::Pending => Poll::Pending,
+            }
+        })
+        .await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        pos += n;
+    }
+    Ok(())
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn denied(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use ntex::testing::Io;
+
+    use super::*;
+
+    #[ntex::test]
+    async fn test_socks5() {
+        let (peer, mut io) = Io::create();
+        peer.remote_buffer_cap(1024);
+
+        let srv = ntex::rt::spawn(async move {
+            assert_eq!(&peer.read().await.unwrap()[..], b"\x05\x02\x00\x02");
+            peer.write(b"\x05\x02");
+            assert_eq!(&peer.read().await.unwrap()[..], b"\x01\x04user\x04pass");
+            peer.write(b"\x01\x00");
+            assert_eq!(
+                &peer.read().await.unwrap()[..],
+                b"\x05\x01\x00\x03\x09localhost\x07\x5b"
+            );
+            peer.write(b"\x05\x00\x00\x01\x7f\x00\x00\x01\x07\x5b");
+            peer
+        });
+
+        let proxy = Proxy::socks5("proxy:1080").credentials("user", "pass");
+        tunnel(&mut io, &proxy, "localhost", 1883).await.unwrap();
+        let _ = srv.await.unwrap();
+    }
+
+    #[ntex::test]
+    async fn test_http() {
+        let (peer, mut io) = Io::create();
+        peer.remote_buffer_cap(1024);
+
+        let srv = ntex::rt::spawn(async move {
+            let req = peer.read().await.unwrap();
+            assert_eq!(
+                &req[..],
+                &b"CONNECT 127.0.0.1:1883 HTTP/1.1\r\nHost: 127.0.0.1:1883\r\n\
+                   Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n"[..]
+            );
+            peer.write("HTTP/1.1 200 Connection established\r\n\r\n");
+            peer
+        });
+
+        let proxy = Proxy::http("proxy:8080").credentials("user", "pass");
+        tunnel(&mut io, &proxy, "127.0.0.1", 1883).await.unwrap();
+        let _ = srv.await.unwrap();
+
+        let (peer, mut io) = Io::create();
+        peer.remote_buffer_cap(1024);
+        peer.write("HTTP/1.1 407 Proxy Authentication Required\r\n\r\n");
+        let err = tunnel(&mut io, &Proxy::http("proxy:8080"), "localhost", 1883).await;
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_target() {
+        let req = Connect::new("localhost:1883".to_string());
+        assert_eq!(target(&req), ("localhost".to_string(), 1883));
+
+        let req = Connect::new("127.0.0.1:8883".parse::<std::net::SocketAddr>().unwrap());
+        assert_eq!(target(&req), ("127.0.0.1".to_string(), 8883));
+    }
+}
@@ -0,0 +1,283 @@
+//! PROXY protocol header decoder
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::task::{Context, Poll};
+use std::{convert::TryFrom, future::Future, io, marker::PhantomData, pin::Pin};
+
+use ntex::codec::{AsyncRead, AsyncWrite, ReadBuf};
+use ntex::service::{Service, ServiceFactory};
+use ntex::util::Ready;
+
+use crate::proxy::read_exact;
+
+/// PROXY protocol acceptor
+///
+/// Reads PROXY protocol v1 or v2 header from the raw connection and
+/// returns `ProxyStream`. Acceptor is intended for setups where header
+/// precedes tls handshake, in that case it has to be chained before
+/// tls acceptor. Original client address is available via
+/// `Handshake::peer_addr()` for `ProxyStream<TcpStream>` and for tls streams
+/// over `ProxyStream<TcpStream>`. Server's `proxy_protocol()` option must not
+/// be enabled together with acceptor.
+pub struct ProxyProtocol<Io>(PhantomData<Io>);
+
+impl<Io> ProxyProtocol<Io> {
+    /// Create PROXY protocol acceptor
+    pub fn new() -> Self {
+        ProxyProtocol(PhantomData)
+    }
+}
+
+impl<Io> Default for ProxyProtocol<Io> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Io> Clone for ProxyProtocol<Io> {
+    fn clone(&self) -> Self {
+        Self::new()
+    }
+}
+
+impl<Io> ServiceFactory for ProxyProtocol<Io>
+where
+    Io: AsyncRead + Unpin + 'static,
+{
+    type Config = ();
+    type Request = Io;
+    type Response = ProxyStream<Io>;
+    type Error = io::Error;
+    type Service = ProxyProtocol<Io>;
+    type InitError = ();
+    type Future = Ready<Self::Service, Self::InitError>;
+
+    fn new_service(&self, _: ()) -> Self::Future {
+        Ready::Ok(Self::new())
+    }
+}
+
+impl<Io> Service for ProxyProtocol<Io>
+where
+    Io: AsyncRead + Unpin + 'static,
+{
+    type Request = Io;
+    type Response = ProxyStream<Io>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    #[inline]
+    fn poll_ready(&self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&self, mut io: Io) -> Self::Future {
+        Box::pin(async move {
+            let peer_addr = read_header(&mut io).await?;
+            Ok(ProxyStream { io, peer_addr })
+        })
+    }
+}
+
+/// Stream with decoded PROXY protocol header
+pub struct ProxyStream<Io> {
+    io: Io,
+    peer_addr: Option<SocketAddr>,
+}
+
+impl<Io> ProxyStream<Io> {
+    /// Source address of the proxied connection
+    ///
+    /// Returns `None` for `UNKNOWN` (v1) and `LOCAL` (v2) connections.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Get reference to the underlying stream
+    pub fn get_ref(&self) -> &Io {
+        &self.io
+    }
+
+    /// Get mutable reference to the underlying stream
+    pub fn get_mut(&mut self) -> &mut Io {
+        &mut self.io
+    }
+
+    /// Consume stream and return the underlying stream
+    pub fn into_inner(self) -> Io {
+        self.io
+    }
+}
+
+impl<Io: AsyncRead + Unpin> AsyncRead for ProxyStream<Io> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_read(cx, buf)
+    }
+}
+
+impl<Io: AsyncWrite + Unpin> AsyncWrite for ProxyStream<Io> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+/// Signature of v2 header
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Max size of v1 header, including CRLF
+const V1_MAX_SIZE: usize = 107;
+
+/// Read PROXY protocol v1 or v2 header
+///
+/// Returns source address of the proxied connection, `None` is returned
+/// for `UNKNOWN` (v1) and `LOCAL` (v2) connections. Header is read without
+/// look-ahead, so bytes that follow the header stay in the stream.
+pub(crate) async fn read_header<Io>(io: &mut Io) -> io::Result<Option<SocketAddr>>
+where
+    Io: AsyncRead + Unpin,
+{
+    let mut buf = [0u8; 16];
+    read_exact(io, &mut buf[..6]).await?;
+
+    if &buf[..6] == b"PROXY " {
+        let mut line = buf[..6].to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_SIZE {
+                return Err(invalid("PROXY v1 header is too long"));
+            }
+            let mut b = [0u8; 1];
+            read_exact(io, &mut b).await?;
+            line.push(b[0]);
+        }
+        parse_v1(&line[..line.len() - 2])
+    } else if buf[..6] == V2_SIGNATURE[..6] {
+        read_exact(io, &mut buf[6..]).await?;
+        if &buf[..12] != V2_SIGNATURE {
+            return Err(invalid("Invalid PROXY v2 signature"));
+        }
+        let mut data = vec![0; u16::from_be_bytes([buf[14], buf[15]]) as usize];
+        read_exact(io, &mut data).await?;
+        parse_v2(buf[12], buf[13], &data)
+    } else {
+        Err(invalid("PROXY protocol header is expected"))
+    }
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("Invalid PROXY v1 header"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    match parts.get(1).copied() {
+        Some("UNKNOWN") => Ok(None),
+        Some(proto @ "TCP4") | Some(proto @ "TCP6") if parts.len() == 6 => {
+            let src: IpAddr =
+                parts[2].parse().map_err(|_| invalid("Invalid source address"))?;
+            let _: IpAddr = parts[3].parse().map_err(|_| invalid("Invalid dest address"))?;
+            let port: u16 = parts[4].parse().map_err(|_| invalid("Invalid source port"))?;
+            let _: u16 = parts[5].parse().map_err(|_| invalid("Invalid dest port"))?;
+            if src.is_ipv4() != (proto == "TCP4") {
+                return Err(invalid("Address does not match protocol"));
+            }
+            Ok(Some(SocketAddr::new(src, port)))
+        }
+        _ => Err(invalid("Invalid PROXY v1 header")),
+    }
+}
+
+fn parse_v2(ver_cmd: u8, family: u8, data: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY protocol version"));
+    }
+
+    match ver_cmd & 0x0f {
+        // LOCAL, connection established by proxy itself
+        0x00 => Ok(None),
+        0x01 => match family >> 4 {
+            // AF_INET
+            0x01 if data.len() >= 12 => {
+                let ip = <[u8; 4]>::try_from(&data[..4]).unwrap();
+                let port = u16::from_be_bytes([data[8], data[9]]);
+                Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+            }
+            // AF_INET6
+            0x02 if data.len() >= 36 => {
+                let ip = <[u8; 16]>::try_from(&data[..16]).unwrap();
+                let port = u16::from_be_bytes([data[32], data[33]]);
+                Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+            }
+            // AF_UNSPEC, AF_UNIX
+            0x00 | 0x03 => Ok(None),
+            _ => Err(invalid("Invalid PROXY v2 address block")),
+        },
+        _ => Err(invalid("Unsupported PROXY v2 command")),
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ntex::test]
+    async fn test_v1() {
+        let mut io = &b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1883\r\n\x10"[..];
+        let addr = read_header(&mut io).await.unwrap();
+        assert_eq!(addr, Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(io, b"\x10");
+
+        let mut io = &b"PROXY TCP6 ::1 ::1 56324 1883\r\n"[..];
+        let addr = read_header(&mut io).await.unwrap();
+        assert_eq!(addr, Some("[::1]:56324".parse().unwrap()));
+
+        let mut io = &b"PROXY UNKNOWN\r\n\x10"[..];
+        assert_eq!(read_header(&mut io).await.unwrap(), None);
+        assert_eq!(io, b"\x10");
+
+        let mut io = &b"PROXY TCP4 ::1 ::1 56324 1883\r\n"[..];
+        assert!(read_header(&mut io).await.is_err());
+        let mut io = &b"\x10\x0c\x00\x04MQTT\x04\x02\x00\x3c"[..];
+        assert!(read_header(&mut io).await.is_err());
+    }
+
+    #[ntex::test]
+    async fn test_v2() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(b"\x21\x11\x00\x0c");
+        data.extend_from_slice(&[192, 168, 0, 1, 192, 168, 0, 11, 0xdc, 0x04, 0x07, 0x5b]);
+        data.push(0x10);
+        let mut io = &data[..];
+        let addr = read_header(&mut io).await.unwrap();
+        assert_eq!(addr, Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(io, b"\x10");
+
+        // LOCAL command
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(b"\x20\x00\x00\x00");
+        let mut io = &data[..];
+        assert_eq!(read_header(&mut io).await.unwrap(), None);
+
+        // unsupported version
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(b"\x11\x11\x00\x00");
+        let mut io = &data[..];
+        assert!(read_header(&mut io).await.is_err());
+    }
+}
@@ -0,0 +1,274 @@
+//! Token bucket rate limiter
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use std::{cell::Cell, cell::RefCell, convert::TryFrom, future::Future, pin::Pin, rc::Rc};
+
+use ntex::rt::time::{sleep, Sleep};
+
+/// Action for clients that exceed inbound publish rate
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RateLimitPolicy {
+    /// Stop reading from connection until rate drops below the limit
+    Pause,
+    /// Disconnect client
+    ///
+    /// v5 clients receive `DISCONNECT` packet with `QuotaExceeded` reason code.
+    Disconnect,
+}
+
+impl Default for RateLimitPolicy {
+    fn default() -> Self {
+        RateLimitPolicy::Pause
+    }
+}
+
+/// Token bucket rate limiter
+///
+/// Bucket holds up to `burst` tokens and gets refilled with `rate`
+/// tokens per second.
+pub(crate) struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: Cell<f64>,
+    updated: Cell<Instant>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: u32, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        RateLimiter {
+            burst,
+            rate: f64::from(rate),
+            tokens: Cell::new(burst),
+            updated: Cell::new(Instant::now()),
+        }
+    }
+
+    /// Take one token from the bucket
+    ///
+    /// Returns `false` if bucket is empty.
+    pub(crate) fn acquire(&self) -> bool {
+        self.acquire_at(Instant::now(), 1)
+    }
+
+    /// Take `n` tokens from the bucket
+    ///
+    /// Request succeeds if bucket is not empty, bucket could go into debt
+    /// if it holds less than `n` tokens. Returns `false` if bucket is empty.
+    pub(crate) fn acquire_n(&self, n: u32) -> bool {
+        self.acquire_at(Instant::now(), n)
+    }
+
+    /// Time until bucket gets refilled with at least one token
+    pub(crate) fn delay(&self) -> Duration {
+        self.delay_at(Instant::now())
+    }
+
+    fn acquire_at(&self, now: Instant, n: u32) -> bool {
+        let tokens = self.refill(now);
+        if tokens >= 1.0 {
+            self.tokens.set(tokens - f64::from(n));
+            true
+        } else {
+            false
+        }
+    }
+
+    fn delay_at(&self, now: Instant) -> Duration {
+        let tokens = self.refill(now);
+        if tokens >= 1.0 || self.rate == 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64((1.0 - tokens) / self.rate)
+        }
+    }
+
+    fn refill(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated.get()).as_secs_f64();
+        let tokens = (self.tokens.get() + elapsed * self.rate).min(self.burst);
+        self.tokens.set(tokens);
+        self.updated.set(now);
+        tokens
+    }
+}
+
+/// Inbound publish rate limit configuration
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct PublishRate {
+    pub(crate) messages: u32,
+    pub(crate) bytes: u32,
+    pub(crate) policy: RateLimitPolicy,
+}
+
+impl PublishRate {
+    /// Create per-connection limiter, returns `None` if rate is not limited
+    pub(crate) fn limiter(&self) -> Option<PublishLimiter> {
+        if self.messages == 0 && self.bytes == 0 {
+            None
+        } else {
+            Some(PublishLimiter {
+                messages: if self.messages != 0 {
+                    Some(RateLimiter::new(self.messages, self.messages))
+                } else {
+                    None
+                },
+                bytes: if self.bytes != 0 {
+                    Some(RateLimiter::new(self.bytes, self.bytes))
+                } else {
+                    None
+                },
+                policy: self.policy,
+                delay: RefCell::new(None),
+            })
+        }
+    }
+}
+
+/// Per-connection inbound publish rate limiter
+///
+/// Buckets allow one second worth of messages and payload bytes.
+pub(crate) struct PublishLimiter {
+    messages: Option<RateLimiter>,
+    bytes: Option<RateLimiter>,
+    policy: RateLimitPolicy,
+    delay: RefCell<Option<Pin<Box<Sleep>>>>,
+}
+
+impl PublishLimiter {
+    /// Account incoming publish
+    ///
+    /// Returns `false` if rate is exceeded and connection must be closed.
+    pub(crate) fn publish(&self, size: usize) -> bool {
+        let size = u32::try_from(size).unwrap_or(u32::MAX);
+        let res1 = self.messages.as_ref().map_or(true, |limiter| limiter.acquire_n(1));
+        let res2 = self.bytes.as_ref().map_or(true, |limiter| limiter.acquire_n(size));
+
+        match self.policy {
+            RateLimitPolicy::Pause => true,
+            RateLimitPolicy::Disconnect => res1 && res2,
+        }
+    }
+
+    /// Check if connection can read more packets
+    ///
+    /// With `Pause` policy, readiness is delayed until buckets get refilled.
+    pub(crate) fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.policy != RateLimitPolicy::Pause {
+            return Poll::Ready(());
+        }
+
+        let mut delay = self.delay.borrow_mut();
+        loop {
+            if let Some(ref mut fut) = *delay {
+                if fut.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                *delay = None;
+            }
+
+            let d1 = self.messages.as_ref().map_or(Duration::from_secs(0), |l| l.delay());
+            let d2 = self.bytes.as_ref().map_or(Duration::from_secs(0), |l| l.delay());
+            let d = d1.max(d2);
+            if d == Duration::from_secs(0) {
+                return Poll::Ready(());
+            }
+            log::trace!("Publish rate is exceeded, pause reading for {:?}", d);
+            *delay = Some(Box::pin(sleep(d)));
+        }
+    }
+}
+
+/// Active connections counter
+#[derive(Clone)]
+pub(crate) struct ConnectionCounter {
+    max: usize,
+    active: Rc<Cell<usize>>,
+}
+
+/// Active connection, decrements counter on drop
+pub(crate) struct ConnectionGuard(Rc<Cell<usize>>);
+
+impl ConnectionCounter {
+    pub(crate) fn new(max: usize) -> Self {
+        ConnectionCounter { max, active: Rc::new(Cell::new(0)) }
+    }
+
+    /// Register new connection
+    ///
+    /// Returns `None` if maximum number of connections is reached.
+    pub(crate) fn acquire(&self) -> Option<ConnectionGuard> {
+        let active = self.active.get();
+        if active >= self.max {
+            None
+        } else {
+            self.active.set(active + 1);
+            Some(ConnectionGuard(self.active.clone()))
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(10, 2);
+        let now = Instant::now();
+        assert!(limiter.acquire_at(now, 1));
+        assert!(limiter.acquire_at(now, 1));
+        assert!(!limiter.acquire_at(now, 1));
+
+        // one token per 100 millis
+        let now = now + Duration::from_millis(50);
+        assert!(!limiter.acquire_at(now, 1));
+        let now = now + Duration::from_millis(60);
+        assert!(limiter.acquire_at(now, 1));
+        assert!(!limiter.acquire_at(now, 1));
+
+        // bucket does not exceed burst size
+        let now = now + Duration::from_secs(10);
+        assert!(limiter.acquire_at(now, 1));
+        assert!(limiter.acquire_at(now, 1));
+        assert!(!limiter.acquire_at(now, 1));
+    }
+
+    #[test]
+    fn test_rate_limiter_debt() {
+        let limiter = RateLimiter::new(100, 100);
+        let now = Instant::now();
+        assert!(limiter.acquire_at(now, 150));
+        assert!(!limiter.acquire_at(now, 1));
+        let delay = limiter.delay_at(now);
+        assert!(delay > Duration::from_millis(500) && delay <= Duration::from_millis(510));
+
+        let now = now + Duration::from_millis(500);
+        assert!(!limiter.acquire_at(now, 1));
+        let now = now + Duration::from_millis(20);
+        assert_eq!(limiter.delay_at(now), Duration::from_secs(0));
+        assert!(limiter.acquire_at(now, 1));
+    }
+
+    #[test]
+    fn test_connection_counter() {
+        let counter = ConnectionCounter::new(2);
+        let c1 = counter.acquire();
+        let c2 = counter.acquire();
+        assert!(c1.is_some() && c2.is_some());
+        assert!(counter.acquire().is_none());
+
+        // guard releases connection slot
+        drop(c1);
+        let c3 = counter.acquire();
+        assert!(c3.is_some());
+        assert!(counter.acquire().is_none());
+    }
+}
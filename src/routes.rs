@@ -0,0 +1,148 @@
+//! Runtime updatable routing table
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard};
+
+use ntex::util::{ByteString, HashMap};
+
+use crate::topic::{Topic, TopicError};
+use crate::tree::SubscriptionTree;
+
+/// Shared routing table
+///
+/// Routing table maps topic filters to named routes. Table could be
+/// updated at runtime, i.e. from control plane task, changes are visible
+/// to all routers that use the table, including routers of running connections.
+/// Route handlers get registered with `Router::route()` method.
+#[derive(Clone, Default)]
+pub struct RouteTable(Arc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    tree: RwLock<SubscriptionTree<ByteString>>,
+    // incremented on every update, invalidates cached routes
+    generation: AtomicUsize,
+}
+
+impl RouteTable {
+    /// Create empty routing table
+    pub fn new() -> Self {
+        RouteTable::default()
+    }
+
+    /// Number of topic filters in the table
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    /// Check if table is empty
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    // tree is consistent after every operation, panic of other
+    // thread does not invalidate the table
+    fn read(&self) -> RwLockReadGuard<'_, SubscriptionTree<ByteString>> {
+        self.0.tree.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn update<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut SubscriptionTree<ByteString>) -> R,
+    {
+        let mut tree = self.0.tree.write().unwrap_or_else(PoisonError::into_inner);
+        let result = f(&mut tree);
+        self.0.generation.fetch_add(1, Ordering::AcqRel);
+        result
+    }
+
+    /// Version of the table, changes on every update
+    pub(crate) fn generation(&self) -> usize {
+        self.0.generation.load(Ordering::Acquire)
+    }
+
+    /// Route publish packets that match topic filter to named route
+    ///
+    /// Topic filter could contain `+` and `#` wildcards, shared subscription
+    /// filters are not supported.
+    pub fn add(&self, filter: &str, route: &str) -> Result<(), TopicError> {
+        let filter = filter.parse::<Topic>()?;
+        if filter.is_shared() {
+            return Err(TopicError::InvalidTopic);
+        }
+        self.update(|tree| tree.insert(&filter, ByteString::from(route)));
+        Ok(())
+    }
+
+    /// Remove topic filter for named route
+    ///
+    /// Returns `true` if topic filter is found and removed.
+    pub fn remove(&self, filter: &str, route: &str) -> bool {
+        if let Ok(filter) = filter.parse::<Topic>() {
+            self.update(|tree| tree.remove(&filter, &ByteString::from(route)))
+        } else {
+            false
+        }
+    }
+
+    /// Find first registered route handler that matches publish topic
+    pub(crate) fn recognize(
+        &self,
+        topic: &str,
+        routes: &HashMap<ByteString, usize>,
+    ) -> Option<usize> {
+        let mut idx = None;
+        self.read().visit(topic, |_, subs| {
+            for route in subs {
+                if let Some(route_idx) = routes.get(route).copied() {
+                    idx = Some(idx.map_or(route_idx, |idx: usize| idx.min(route_idx)));
+                }
+            }
+        });
+        idx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_table() {
+        let mut routes = HashMap::default();
+        routes.insert(ByteString::from("first"), 0);
+        routes.insert(ByteString::from("second"), 1);
+
+        let table = RouteTable::new();
+        assert!(table.is_empty());
+        assert_eq!(table.recognize("devices/1/state", &routes), None);
+
+        table.add("devices/+/state", "second").unwrap();
+        table.add("devices/#", "unknown").unwrap();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.recognize("devices/1/state", &routes), Some(1));
+
+        table.add("devices/#", "first").unwrap();
+        assert_eq!(table.recognize("devices/1/state", &routes), Some(0));
+        assert_eq!(table.recognize("devices/1", &routes), Some(0));
+
+        let generation = table.generation();
+        assert!(table.remove("devices/#", "first"));
+        assert!(!table.remove("devices/#", "first"));
+        assert_eq!(table.recognize("devices/1", &routes), None);
+        assert_ne!(table.generation(), generation);
+
+        // poisoned lock does not break the table
+        let table2 = table.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = table2.0.tree.write();
+            panic!("poison");
+        })
+        .join();
+        assert!(table.0.tree.is_poisoned());
+        assert_eq!(table.recognize("devices/1/state", &routes), Some(1));
+        assert!(table.remove("devices/+/state", "second"));
+
+        assert_eq!(table.add("devices/#/state", "first"), Err(TopicError::InvalidTopic));
+        assert_eq!(table.add("$share/group/devices/#", "first"), Err(TopicError::InvalidTopic));
+    }
+}
@@ -0,0 +1,70 @@
+//! Serde support for topic types and QoS
+use std::convert::TryFrom;
+
+use serde::de::{self, Deserialize, Deserializer, Unexpected};
+use serde::ser::{Serialize, Serializer};
+
+use crate::topic::{Topic, TopicFilter, TopicName};
+use crate::types::QoS;
+
+macro_rules! topic_serde {
+    ($name:ident, $expected:expr) => {
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+                s.parse().map_err(|_| de::Error::invalid_value(Unexpected::Str(&s), &$expected))
+            }
+        }
+    };
+}
+
+topic_serde!(Topic, "a valid topic filter");
+topic_serde!(TopicFilter, "a valid topic filter");
+topic_serde!(TopicName, "a valid topic name");
+
+impl Serialize for QoS {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(u8::from(*self))
+    }
+}
+
+impl<'de> Deserialize<'de> for QoS {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let val = u8::deserialize(deserializer)?;
+        QoS::try_from(val).map_err(|_| {
+            de::Error::invalid_value(Unexpected::Unsigned(val as u64), &"0, 1 or 2")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serde() {
+        let filter: TopicFilter = serde_json::from_str("\"sensors/+/temp\"").unwrap();
+        assert_eq!(filter.as_str(), "sensors/+/temp");
+        assert_eq!(serde_json::to_string(&filter).unwrap(), "\"sensors/+/temp\"");
+        assert!(serde_json::from_str::<TopicFilter>("\"sensors/#/temp\"").is_err());
+
+        let name: TopicName = serde_json::from_str("\"sensors/1/temp\"").unwrap();
+        assert_eq!(name.as_str(), "sensors/1/temp");
+        assert!(serde_json::from_str::<TopicName>("\"sensors/#\"").is_err());
+
+        let topic: Topic = serde_json::from_str("\"$share/group/sensors/#\"").unwrap();
+        assert!(topic.is_shared());
+        assert_eq!(serde_json::to_string(&topic).unwrap(), "\"$share/group/sensors/#\"");
+
+        let qos: QoS = serde_json::from_str("1").unwrap();
+        assert_eq!(qos, QoS::AtLeastOnce);
+        assert_eq!(serde_json::to_string(&QoS::ExactlyOnce).unwrap(), "2");
+        assert!(serde_json::from_str::<QoS>("3").is_err());
+    }
+}
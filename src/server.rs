@@ -1,10 +1,12 @@
 use std::task::{Context, Poll};
-use std::{convert::TryFrom, fmt, future::Future, io, marker, pin::Pin, rc::Rc, time};
+use std::{
+    cell::Cell, convert::TryFrom, fmt, future::Future, io, marker, pin::Pin, rc::Rc, time,
+};
 
 use ntex::codec::{AsyncRead, AsyncWrite};
 use ntex::rt::time::{sleep, Sleep};
 use ntex::service::{Service, ServiceFactory};
-use ntex::util::{join, Ready};
+use ntex::util::{join, Either, Ready};
 
 use crate::error::{MqttError, ProtocolError};
 use crate::io::State;
@@ -16,6 +18,8 @@ pub struct MqttServer<Io, V3, V5, Err, InitErr> {
     v3: V3,
     v5: V5,
     handshake_timeout: usize,
+    // shared with default protocol servers
+    unsupported_ack: Rc<Cell<bool>>,
     _t: marker::PhantomData<(Io, Err, InitErr)>,
 }
 
@@ -30,10 +34,12 @@ impl<Io, Err, InitErr>
 {
     /// Create mqtt protocol selector server
     pub fn new() -> Self {
+        let unsupported_ack = Rc::new(Cell::new(true));
         MqttServer {
-            v3: DefaultProtocolServer::new(ProtocolVersion::MQTT3),
-            v5: DefaultProtocolServer::new(ProtocolVersion::MQTT5),
+            v3: DefaultProtocolServer::new(ProtocolVersion::MQTT3, unsupported_ack.clone()),
+            v5: DefaultProtocolServer::new(ProtocolVersion::MQTT5, unsupported_ack.clone()),
             handshake_timeout: 0,
+            unsupported_ack,
             _t: marker::PhantomData,
         }
     }
@@ -62,6 +68,18 @@ impl<Io, V3, V5, Err, InitErr> MqttServer<Io, V3, V5, Err, InitErr> {
         self.handshake_timeout = timeout;
         self
     }
+
+    /// Send CONNACK packet for protocol versions that are not served.
+    ///
+    /// If enabled, connections with a protocol version that has no configured service get
+    /// CONNACK with "unsupported protocol version" reason (v3 `0x01`, v5 `0x84`)
+    /// before the connection is closed. Protocol levels other than 3.1, 3.1.1 and 5
+    /// get v3 CONNACK. Otherwise connection is closed silently.
+    /// By default CONNACK is sent.
+    pub fn unsupported_version_ack(self, enabled: bool) -> Self {
+        self.unsupported_ack.set(enabled);
+        self
+    }
 }
 
 impl<Io, V3, V5, Err, InitErr> MqttServer<Io, V3, V5, Err, InitErr>
@@ -125,6 +143,7 @@ where
             v3: service.inner_finish(),
             v5: self.v5,
             handshake_timeout: self.handshake_timeout,
+            unsupported_ack: self.unsupported_ack,
             _t: marker::PhantomData,
         }
     }
@@ -160,6 +179,7 @@ where
                 Request = v5::ControlMessage<C::Error>,
                 Response = v5::ControlResult,
             > + 'static,
+        Cn::Service: 'static,
         P: ServiceFactory<
                 Config = v5::Session<St>,
                 Request = v5::Publish,
@@ -177,6 +197,7 @@ where
             v3: self.v3,
             v5: service.inner_finish(),
             handshake_timeout: self.handshake_timeout,
+            unsupported_ack: self.unsupported_ack,
             _t: marker::PhantomData,
         }
     }
@@ -218,6 +239,7 @@ where
 
     fn new_service(&self, _: ()) -> Self::Future {
         let handshake_timeout = self.handshake_timeout;
+        let unsupported_ack = self.unsupported_ack.get();
         let fut = join(self.v3.new_service(()), self.v5.new_service(()));
         Box::pin(async move {
             let (v3, v5) = fut.await;
@@ -226,6 +248,7 @@ where
             Ok(MqttServerImpl {
                 handlers: Rc::new((v3, v5)),
                 handshake_timeout,
+                unsupported_ack,
                 _t: marker::PhantomData,
             })
         })
@@ -236,6 +259,7 @@ where
 pub struct MqttServerImpl<Io, V3, V5, Err> {
     handlers: Rc<(V3, V5)>,
     handshake_timeout: usize,
+    unsupported_ack: bool,
     _t: marker::PhantomData<(Io, Err)>,
 }
 
@@ -291,6 +315,7 @@ where
             state: MqttServerImplState::Version {
                 item: Some((req, State::new(), VersionCodec, self.handlers.clone(), delay)),
             },
+            unsupported_ack: self.unsupported_ack,
         }
     }
 }
@@ -311,6 +336,7 @@ pin_project_lite::pin_project! {
     {
         #[pin]
         state: MqttServerImplState<Io, V3, V5>,
+        unsupported_ack: bool,
     }
 }
 
@@ -319,6 +345,7 @@ pin_project_lite::pin_project! {
     pub(crate) enum MqttServerImplState<Io, V3: Service, V5: Service> {
         V3 { #[pin] fut: V3::Future },
         V5 { #[pin] fut: V5::Future },
+        Reject { fut: Pin<Box<dyn Future<Output = ()>>>, ver: ProtocolVersion },
         Version { item: Option<(Io, State, VersionCodec, Rc<(V3, V5)>, Option<Pin<Box<Sleep>>>)> },
     }
 }
@@ -346,6 +373,12 @@ where
             match this.state.project() {
                 MqttServerImplStateProject::V3 { fut } => return fut.poll(cx),
                 MqttServerImplStateProject::V5 { fut } => return fut.poll(cx),
+                MqttServerImplStateProject::Reject { fut, ver } => {
+                    return match fut.as_mut().poll(cx) {
+                        Poll::Ready(_) => Poll::Ready(Err(unsupported(*ver))),
+                        Poll::Pending => Poll::Pending,
+                    }
+                }
                 MqttServerImplStateProject::Version { ref mut item } => {
                     if let Some(ref mut delay) = item.as_mut().unwrap().4 {
                         match Pin::new(delay).poll(cx) {
@@ -363,6 +396,15 @@ where
                             let (io, state, _, handlers, delay) = item.take().unwrap();
                             this = self.as_mut().project();
                             match ver {
+                                ProtocolVersion::Unsupported(_) => {
+                                    if !*this.unsupported_ack {
+                                        return Poll::Ready(Err(unsupported(ver)));
+                                    }
+                                    this.state.set(MqttServerImplState::Reject {
+                                        fut: reject(io, state, ver),
+                                        ver,
+                                    })
+                                }
                                 ProtocolVersion::MQTT3 => {
                                     this.state.set(MqttServerImplState::V3 {
                                         fut: handlers.0.call((io, state, delay)),
@@ -388,18 +430,27 @@ where
     }
 }
 
+/// Server for protocol version that is not served
+///
+/// Connection is rejected with "unsupported protocol version" CONNACK,
+/// see `MqttServer::unsupported_version_ack()`.
 pub struct DefaultProtocolServer<Io, Err, InitErr> {
     ver: ProtocolVersion,
+    ack: Rc<Cell<bool>>,
     _t: marker::PhantomData<(Io, Err, InitErr)>,
 }
 
 impl<Io, Err, InitErr> DefaultProtocolServer<Io, Err, InitErr> {
-    fn new(ver: ProtocolVersion) -> Self {
-        Self { ver, _t: marker::PhantomData }
+    fn new(ver: ProtocolVersion, ack: Rc<Cell<bool>>) -> Self {
+        Self { ver, ack, _t: marker::PhantomData }
     }
 }
 
-impl<Io, Err, InitErr> ServiceFactory for DefaultProtocolServer<Io, Err, InitErr> {
+impl<Io, Err, InitErr> ServiceFactory for DefaultProtocolServer<Io, Err, InitErr>
+where
+    Io: AsyncRead + AsyncWrite + Unpin + 'static,
+    Err: 'static,
+{
     type Config = ();
     type Request = (Io, State, Option<Pin<Box<Sleep>>>);
     type Response = ();
@@ -409,24 +460,79 @@ impl<Io, Err, InitErr> ServiceFactory for DefaultProtocolServer<Io, Err, InitErr
     type Future = Ready<Self::Service, Self::InitError>;
 
     fn new_service(&self, _: ()) -> Self::Future {
-        Ready::Ok(DefaultProtocolServer { ver: self.ver, _t: marker::PhantomData })
+        Ready::Ok(DefaultProtocolServer::new(self.ver, self.ack.clone()))
     }
 }
 
-impl<Io, Err, InitErr> Service for DefaultProtocolServer<Io, Err, InitErr> {
+impl<Io, Err, InitErr> Service for DefaultProtocolServer<Io, Err, InitErr>
+where
+    Io: AsyncRead + AsyncWrite + Unpin + 'static,
+    Err: 'static,
+{
     type Request = (Io, State, Option<Pin<Box<Sleep>>>);
     type Response = ();
     type Error = MqttError<Err>;
-    type Future = Ready<Self::Response, Self::Error>;
+    type Future = Either<
+        Ready<Self::Response, Self::Error>,
+        Pin<Box<dyn Future<Output = Result<(), Self::Error>>>>,
+    >;
 
     fn poll_ready(&self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&self, _: Self::Request) -> Self::Future {
-        Ready::Err(MqttError::Protocol(ProtocolError::Io(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Protocol is not supported: {:?}", self.ver),
-        ))))
+    fn call(&self, (io, state, _): Self::Request) -> Self::Future {
+        let ver = self.ver;
+        if self.ack.get() {
+            let fut = reject(io, state, ver);
+            Either::Right(Box::pin(async move {
+                fut.await;
+                Err(unsupported(ver))
+            }))
+        } else {
+            Either::Left(Ready::Err(unsupported(ver)))
+        }
     }
 }
+
+fn unsupported<Err>(ver: ProtocolVersion) -> MqttError<Err> {
+    MqttError::Protocol(ProtocolError::Io(io::Error::new(
+        io::ErrorKind::Other,
+        format!("Protocol is not supported: {:?}", ver),
+    )))
+}
+
+/// Send CONNACK with "unsupported protocol version" reason code
+fn reject<Io>(
+    mut io: Io,
+    state: State,
+    ver: ProtocolVersion,
+) -> Pin<Box<dyn Future<Output = ()>>>
+where
+    Io: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    Box::pin(async move {
+        log::trace!("Protocol is not supported: {:?}, reject connection", ver);
+
+        // unknown protocol levels get v3 CONNACK
+        let res = match ver {
+            ProtocolVersion::MQTT3 | ProtocolVersion::Unsupported(_) => {
+                let pkt = v3::codec::Packet::ConnectAck {
+                    session_present: false,
+                    return_code: v3::codec::ConnectAckReason::UnacceptableProtocolVersion,
+                };
+                state.send(&mut io, &v3::codec::Codec::new(), pkt).await.is_ok()
+            }
+            ProtocolVersion::MQTT5 => {
+                let pkt = v5::codec::Packet::ConnectAck(v5::codec::ConnectAck {
+                    reason_code: v5::codec::ConnectAckReason::UnsupportedProtocolVersion,
+                    ..Default::default()
+                });
+                state.send(&mut io, &v5::codec::Codec::new(), pkt).await.is_ok()
+            }
+        };
+        if !res {
+            log::trace!("Cannot send CONNACK for unsupported protocol");
+        }
+    })
+}
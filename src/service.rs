@@ -6,15 +6,28 @@ use ntex::rt::time::Sleep;
 use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
 use ntex::util::{select, Either};
 
-use super::io::{DispatchItem, Dispatcher, State, Timer};
+use super::io::{CoalescedIo, DispatchItem, Dispatcher, State, Timer};
 
 type ResponseItem<U> = Option<<U as Encoder>::Item>;
 
+/// Keep-alive timeout with grace factor applied, in seconds
+fn keepalive_timeout(keepalive: u16, grace: f32) -> u16 {
+    if keepalive == 0 {
+        0
+    } else {
+        // rounded down to whole seconds, timer resolution is one second
+        ((f32::from(keepalive) * grace) as u16).max(1)
+    }
+}
+
 /// Service builder - structure that follows the builder pattern
 /// for building instances for framed services.
 pub(crate) struct FactoryBuilder<St, C, Io, Codec> {
     connect: C,
     disconnect_timeout: u16,
+    keepalive_grace: f32,
+    write_limit: Option<(usize, u16)>,
+    write_coalesce: Option<(usize, usize, Duration)>,
     _t: PhantomData<(St, Io, Codec)>,
 }
 
@@ -33,6 +46,9 @@ where
         FactoryBuilder {
             connect: connect.into_factory(),
             disconnect_timeout: 3000,
+            keepalive_grace: 1.5,
+            write_limit: None,
+            write_coalesce: None,
             _t: PhantomData,
         }
     }
@@ -50,6 +66,24 @@ where
         self
     }
 
+    /// Set keep-alive grace factor
+    pub(crate) fn keepalive_grace(mut self, val: f32) -> Self {
+        self.keepalive_grace = val;
+        self
+    }
+
+    /// Set write buffer limit and slow consumer timeout
+    pub(crate) fn write_limit(mut self, val: Option<(usize, u16)>) -> Self {
+        self.write_limit = val;
+        self
+    }
+
+    /// Set write coalescing buffer size, number of packets and delay
+    pub(crate) fn write_coalesce(mut self, val: Option<(usize, usize, Duration)>) -> Self {
+        self.write_coalesce = val;
+        self
+    }
+
     pub(crate) fn build<F, T, Cfg>(self, service: F) -> FramedService<St, C, T, Io, Codec, Cfg>
     where
         F: IntoServiceFactory<T>,
@@ -65,6 +99,9 @@ where
             connect: self.connect,
             handler: Rc::new(service.into_factory()),
             disconnect_timeout: self.disconnect_timeout,
+            keepalive_grace: self.keepalive_grace,
+            write_limit: self.write_limit,
+            write_coalesce: self.write_coalesce,
             time: Timer::with(Duration::from_secs(1)),
             _t: PhantomData,
         }
@@ -75,6 +112,9 @@ pub(crate) struct FramedService<St, C, T, Io, Codec, Cfg> {
     connect: C,
     handler: Rc<T>,
     disconnect_timeout: u16,
+    keepalive_grace: f32,
+    write_limit: Option<(usize, u16)>,
+    write_coalesce: Option<(usize, usize, Duration)>,
     time: Timer,
     _t: PhantomData<(St, Io, Codec, Cfg)>,
 }
@@ -111,6 +151,9 @@ where
             fut: self.connect.new_service(()),
             handler: self.handler.clone(),
             disconnect_timeout: self.disconnect_timeout,
+            keepalive_grace: self.keepalive_grace,
+            write_limit: self.write_limit,
+            write_coalesce: self.write_coalesce,
             time: self.time.clone(),
         }
     }
@@ -142,6 +185,9 @@ pin_project_lite::pin_project! {
         fut: C::Future,
         handler: Rc<T>,
         disconnect_timeout: u16,
+        keepalive_grace: f32,
+        write_limit: Option<(usize, u16)>,
+        write_coalesce: Option<(usize, usize, Duration)>,
         time: Timer,
     }
 }
@@ -176,6 +222,9 @@ where
             connect,
             handler: this.handler.clone(),
             disconnect_timeout: *this.disconnect_timeout,
+            keepalive_grace: *this.keepalive_grace,
+            write_limit: *this.write_limit,
+            write_coalesce: *this.write_coalesce,
             time: this.time.clone(),
             _t: PhantomData,
         }))
@@ -186,6 +235,9 @@ pub(crate) struct FramedServiceImpl<St, C, T, Io, Codec> {
     connect: C,
     handler: Rc<T>,
     disconnect_timeout: u16,
+    keepalive_grace: f32,
+    write_limit: Option<(usize, u16)>,
+    write_coalesce: Option<(usize, usize, Duration)>,
     time: Timer,
     _t: PhantomData<(St, Io, Codec)>,
 }
@@ -229,6 +281,9 @@ where
 
         let handler = self.handler.clone();
         let timeout = self.disconnect_timeout;
+        let grace = self.keepalive_grace;
+        let write_limit = self.write_limit;
+        let write_coalesce = self.write_coalesce;
         let handshake = self.connect.call(req);
         let time = self.time.clone();
 
@@ -242,9 +297,10 @@ where
             let handler = handler.new_service(session).await?;
             log::trace!("Connection handler is created, starting dispatcher");
 
-            Dispatcher::with(io, st, codec, handler, time)
-                .keepalive_timeout(keepalive as u16)
+            Dispatcher::with(CoalescedIo::new(io, write_coalesce), st, codec, handler, time)
+                .keepalive_timeout(keepalive_timeout(keepalive, grace))
                 .disconnect_timeout(timeout)
+                .write_limit(write_limit)
                 .await
         })
     }
@@ -255,6 +311,9 @@ where
 pub(crate) struct FactoryBuilder2<St, C, Io, Codec> {
     connect: C,
     disconnect_timeout: u16,
+    keepalive_grace: f32,
+    write_limit: Option<(usize, u16)>,
+    write_coalesce: Option<(usize, usize, Duration)>,
     _t: PhantomData<(St, Io, Codec)>,
 }
 
@@ -277,6 +336,9 @@ where
         FactoryBuilder2 {
             connect: connect.into_factory(),
             disconnect_timeout: 3000,
+            keepalive_grace: 1.5,
+            write_limit: None,
+            write_coalesce: None,
             _t: PhantomData,
         }
     }
@@ -287,6 +349,24 @@ where
         self
     }
 
+    /// Set keep-alive grace factor
+    pub(crate) fn keepalive_grace(mut self, val: f32) -> Self {
+        self.keepalive_grace = val;
+        self
+    }
+
+    /// Set write buffer limit and slow consumer timeout
+    pub(crate) fn write_limit(mut self, val: Option<(usize, u16)>) -> Self {
+        self.write_limit = val;
+        self
+    }
+
+    /// Set write coalescing buffer size, number of packets and delay
+    pub(crate) fn write_coalesce(mut self, val: Option<(usize, usize, Duration)>) -> Self {
+        self.write_coalesce = val;
+        self
+    }
+
     pub(crate) fn build<F, T, Cfg>(self, service: F) -> FramedService2<St, C, T, Io, Codec, Cfg>
     where
         F: IntoServiceFactory<T>,
@@ -302,6 +382,9 @@ where
             connect: self.connect,
             handler: Rc::new(service.into_factory()),
             disconnect_timeout: self.disconnect_timeout,
+            keepalive_grace: self.keepalive_grace,
+            write_limit: self.write_limit,
+            write_coalesce: self.write_coalesce,
             time: Timer::with(Duration::from_secs(1)),
             _t: PhantomData,
         }
@@ -312,6 +395,9 @@ pub(crate) struct FramedService2<St, C, T, Io, Codec, Cfg> {
     connect: C,
     handler: Rc<T>,
     disconnect_timeout: u16,
+    keepalive_grace: f32,
+    write_limit: Option<(usize, u16)>,
+    write_coalesce: Option<(usize, usize, Duration)>,
     time: Timer,
     _t: PhantomData<(St, Io, Codec, Cfg)>,
 }
@@ -352,6 +438,9 @@ where
             fut: self.connect.new_service(()),
             handler: self.handler.clone(),
             disconnect_timeout: self.disconnect_timeout,
+            keepalive_grace: self.keepalive_grace,
+            write_limit: self.write_limit,
+            write_coalesce: self.write_coalesce,
             time: self.time.clone(),
         }
     }
@@ -387,6 +476,9 @@ pin_project_lite::pin_project! {
         fut: C::Future,
         handler: Rc<T>,
         disconnect_timeout: u16,
+        keepalive_grace: f32,
+        write_limit: Option<(usize, u16)>,
+        write_coalesce: Option<(usize, usize, Duration)>,
         time: Timer,
     }
 }
@@ -425,6 +517,9 @@ where
             connect,
             handler: this.handler.clone(),
             disconnect_timeout: *this.disconnect_timeout,
+            keepalive_grace: *this.keepalive_grace,
+            write_limit: *this.write_limit,
+            write_coalesce: *this.write_coalesce,
             time: this.time.clone(),
             _t: PhantomData,
         }))
@@ -435,6 +530,9 @@ pub(crate) struct FramedServiceImpl2<St, C, T, Io, Codec> {
     connect: C,
     handler: Rc<T>,
     disconnect_timeout: u16,
+    keepalive_grace: f32,
+    write_limit: Option<(usize, u16)>,
+    write_coalesce: Option<(usize, usize, Duration)>,
     time: Timer,
     _t: PhantomData<(St, Io, Codec)>,
 }
@@ -478,6 +576,9 @@ where
 
         let handler = self.handler.clone();
         let timeout = self.disconnect_timeout;
+        let grace = self.keepalive_grace;
+        let write_limit = self.write_limit;
+        let write_coalesce = self.write_coalesce;
         let handshake = self.connect.call((req, state));
         let time = self.time.clone();
 
@@ -519,9 +620,10 @@ where
                 (io, state, codec, ka, handler)
             };
 
-            Dispatcher::with(io, state, codec, handler, time)
-                .keepalive_timeout(ka as u16)
+            Dispatcher::with(CoalescedIo::new(io, write_coalesce), state, codec, handler, time)
+                .keepalive_timeout(keepalive_timeout(ka, grace))
                 .disconnect_timeout(timeout)
+                .write_limit(write_limit)
                 .await
         })
     }
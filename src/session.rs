@@ -1,5 +1,4 @@
-use std::ops::Deref;
-use std::rc::Rc;
+use std::{net::SocketAddr, ops::Deref, rc::Rc};
 
 /// Mqtt connection session
 pub struct Session<T, St>(Rc<SessionInner<T, St>>);
@@ -9,6 +8,8 @@ struct SessionInner<T, St> {
     sink: T,
     max_receive: u16,
     max_topic_alias: u16,
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
 }
 
 impl<T, St> Clone for Session<T, St> {
@@ -19,12 +20,36 @@ impl<T, St> Clone for Session<T, St> {
 }
 
 impl<T, St> Session<T, St> {
-    pub(crate) fn new(st: St, sink: T) -> Self {
-        Session(Rc::new(SessionInner { st, sink, max_receive: 0, max_topic_alias: 0 }))
+    pub(crate) fn new(
+        st: St,
+        sink: T,
+        addrs: (Option<SocketAddr>, Option<SocketAddr>),
+    ) -> Self {
+        Session(Rc::new(SessionInner {
+            st,
+            sink,
+            max_receive: 0,
+            max_topic_alias: 0,
+            peer_addr: addrs.0,
+            local_addr: addrs.1,
+        }))
     }
 
-    pub(crate) fn new_v5(st: St, sink: T, max_receive: u16, max_topic_alias: u16) -> Self {
-        Session(Rc::new(SessionInner { st, sink, max_receive, max_topic_alias }))
+    pub(crate) fn new_v5(
+        st: St,
+        sink: T,
+        max_receive: u16,
+        max_topic_alias: u16,
+        addrs: (Option<SocketAddr>, Option<SocketAddr>),
+    ) -> Self {
+        Session(Rc::new(SessionInner {
+            st,
+            sink,
+            max_receive,
+            max_topic_alias,
+            peer_addr: addrs.0,
+            local_addr: addrs.1,
+        }))
     }
 
     #[inline]
@@ -37,6 +62,20 @@ impl<T, St> Session<T, St> {
         &self.0.st
     }
 
+    #[inline]
+    /// Returns peer address of the connection
+    ///
+    /// If PROXY protocol is enabled, original client address is returned.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.0.peer_addr
+    }
+
+    #[inline]
+    /// Returns local address of the connection
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.0.local_addr
+    }
+
     pub(crate) fn params(&self) -> (u16, u16) {
         (self.0.max_receive, self.0.max_topic_alias)
     }
@@ -0,0 +1,234 @@
+use std::cell::Cell;
+
+use ntex::codec::{Decoder, Encoder};
+use ntex::util::{Buf, Bytes, BytesMut};
+
+use super::{decode, encode, Packet};
+use crate::error::{DecodeError, EncodeError};
+
+#[derive(Debug)]
+/// MQTT-SN 1.2 protocol codec
+///
+/// Each datagram carries one message, codec could be used with stream
+/// transports as well.
+pub struct Codec {
+    max_size: Cell<u16>,
+}
+
+impl Codec {
+    /// Create `Codec` instance
+    pub fn new() -> Self {
+        Codec { max_size: Cell::new(0) }
+    }
+
+    /// Set max inbound message size.
+    ///
+    /// If max size is set to `0`, size is unlimited.
+    /// By default max size is set to `0`
+    pub fn max_size(self, size: u16) -> Self {
+        self.max_size.set(size);
+        self
+    }
+
+    /// Set max inbound message size.
+    ///
+    /// If max size is set to `0`, size is unlimited.
+    /// By default max size is set to `0`
+    pub fn set_max_size(&self, size: u16) {
+        self.max_size.set(size);
+    }
+
+    /// Encode single packet
+    pub fn encode_packet(&self, pkt: Packet) -> Result<Bytes, EncodeError> {
+        let mut buf = BytesMut::new();
+        self.encode(pkt, &mut buf)?;
+        Ok(buf.freeze())
+    }
+
+    /// Decode single packet from the start of the buffer
+    ///
+    /// Returns decoded packet and number of consumed bytes, or `None` if buffer
+    /// does not contain complete packet.
+    pub fn decode_packet(&self, src: &Bytes) -> Result<Option<(Packet, usize)>, DecodeError> {
+        match self.decode_header(src)? {
+            Some((start, end)) => {
+                let packet = decode::decode_packet(src.slice(start + 1..end), src[start])?;
+                Ok(Some((packet, end)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns position of message type and length of the message
+    fn decode_header(&self, src: &[u8]) -> Result<Option<(usize, usize)>, DecodeError> {
+        if src.len() < 2 {
+            return Ok(None);
+        }
+        let (start, len) = if src[0] == 0x01 {
+            // three octets length field
+            if src.len() < 4 {
+                return Ok(None);
+            }
+            (3, u16::from_be_bytes([src[1], src[2]]) as usize)
+        } else {
+            (1, src[0] as usize)
+        };
+        ensure!(len > start, DecodeError::InvalidLength);
+
+        let max_size = self.max_size.get() as usize;
+        if max_size != 0 && max_size < len {
+            return Err(DecodeError::MaxSizeExceeded);
+        }
+        if src.len() < len {
+            Ok(None)
+        } else {
+            Ok(Some((start, len)))
+        }
+    }
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for Codec {
+    type Item = Packet;
+    type Error = DecodeError;
+
+    fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, DecodeError> {
+        match self.decode_header(src)? {
+            Some((start, end)) => {
+                let mut buf = src.split_to(end).freeze();
+                buf.advance(start);
+                let msg_type = buf.get_u8();
+                Ok(Some(decode::decode_packet(buf, msg_type)?))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder for Codec {
+    type Item = Packet;
+    type Error = EncodeError;
+
+    fn encode(&self, item: Self::Item, dst: &mut BytesMut) -> Result<(), EncodeError> {
+        let size = encode::get_encoded_size(&item);
+        encode::encode(&item, dst, size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntex::util::ByteString;
+
+    use super::*;
+    use crate::sn::codec::{Connect, Publish, QoS, ReturnCode, Subscribe, Topic, TopicFilter};
+
+    fn assert_roundtrip(packet: Packet, encoded: &[u8]) {
+        let codec = Codec::new();
+        let buf = codec.encode_packet(packet.clone()).unwrap();
+        assert_eq!(buf.as_ref(), encoded);
+        let (decoded, size) = codec.decode_packet(&buf).unwrap().unwrap();
+        assert_eq!(decoded, packet);
+        assert_eq!(size, buf.len());
+    }
+
+    #[test]
+    fn test_codec() {
+        assert_roundtrip(
+            Packet::Connect(Connect {
+                will: false,
+                clean_session: true,
+                duration: 60,
+                client_id: ByteString::from_static("sensor"),
+            }),
+            b"\x0c\x04\x04\x01\x00\x3csensor",
+        );
+        assert_roundtrip(Packet::ConnectAck(ReturnCode::Accepted), b"\x03\x05\x00");
+        assert_roundtrip(
+            Packet::Register {
+                topic_id: 1,
+                msg_id: 2,
+                topic_name: ByteString::from_static("a/b"),
+            },
+            b"\x09\x0a\x00\x01\x00\x02a/b",
+        );
+        assert_roundtrip(
+            Packet::Publish(Publish {
+                dup: false,
+                retain: true,
+                qos: QoS::NoConnection,
+                topic: Topic::Predefined(5),
+                msg_id: 0,
+                data: Bytes::from_static(b"22.5"),
+            }),
+            b"\x0b\x0c\x71\x00\x05\x00\x0022.5",
+        );
+        assert_roundtrip(
+            Packet::Publish(Publish {
+                dup: true,
+                retain: false,
+                qos: QoS::AtLeastOnce,
+                topic: Topic::Short(*b"tt"),
+                msg_id: 7,
+                data: Bytes::new(),
+            }),
+            b"\x07\x0c\xa2tt\x00\x07",
+        );
+        assert_roundtrip(
+            Packet::Subscribe(Subscribe {
+                dup: false,
+                qos: QoS::AtLeastOnce,
+                msg_id: 3,
+                topic: TopicFilter::Name(ByteString::from_static("a/#")),
+            }),
+            b"\x08\x12\x20\x00\x03a/#",
+        );
+        assert_roundtrip(
+            Packet::SubscribeAck {
+                qos: QoS::AtLeastOnce,
+                topic_id: 0,
+                msg_id: 3,
+                return_code: ReturnCode::Accepted,
+            },
+            b"\x08\x13\x20\x00\x00\x00\x03\x00",
+        );
+        assert_roundtrip(Packet::PingRequest { client_id: None }, b"\x02\x16");
+        assert_roundtrip(Packet::Disconnect { duration: Some(10) }, b"\x04\x18\x00\x0a");
+
+        // three octets length field
+        let data = Bytes::from(vec![0u8; 300]);
+        let buf = Codec::new().encode_packet(Packet::WillMessage(data.clone())).unwrap();
+        assert_eq!(&buf[..4], b"\x01\x01\x30\x09");
+        let (pkt, _) = Codec::new().decode_packet(&buf).unwrap().unwrap();
+        assert_eq!(pkt, Packet::WillMessage(data));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let codec = Codec::new();
+        assert_eq!(codec.decode_packet(&Bytes::from_static(b"\x03")).unwrap(), None);
+        assert_eq!(
+            codec.decode_packet(&Bytes::from_static(b"\x01\x00\x01\x16")).unwrap_err(),
+            DecodeError::InvalidLength
+        );
+        assert_eq!(
+            codec.decode_packet(&Bytes::from_static(b"\x04\x04\x04\x01")).unwrap_err(),
+            DecodeError::InvalidLength
+        );
+        assert_eq!(
+            codec.decode_packet(&Bytes::from_static(b"\x02\x03")).unwrap_err(),
+            DecodeError::UnsupportedPacketType
+        );
+        assert_eq!(
+            Codec::new()
+                .max_size(8)
+                .decode_packet(&Bytes::from_static(b"\x0b\x0c\x71\x00\x05\x00\x0022.5"))
+                .unwrap_err(),
+            DecodeError::MaxSizeExceeded
+        );
+    }
+}
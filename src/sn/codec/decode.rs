@@ -0,0 +1,179 @@
+use std::convert::TryFrom;
+
+use ntex::util::{Buf, ByteString, Bytes};
+
+use crate::error::DecodeError;
+use crate::utils::Decode;
+
+use super::packet::{
+    flags, packet_type, Connect, Packet, Publish, QoS, ReturnCode, Subscribe, Topic,
+    TopicFilter,
+};
+
+/// MQTT-SN protocol id of `CONNECT` packet
+const PROTOCOL_ID: u8 = 0x01;
+
+/// Decode variable part of the message
+pub(crate) fn decode_packet(mut src: Bytes, msg_type: u8) -> Result<Packet, DecodeError> {
+    let src = &mut src;
+    match msg_type {
+        packet_type::ADVERTISE => {
+            let gw_id = decode_u8(src)?;
+            let duration = u16::decode(src)?;
+            Ok(Packet::Advertise { gw_id, duration })
+        }
+        packet_type::SEARCHGW => Ok(Packet::SearchGateway { radius: decode_u8(src)? }),
+        packet_type::GWINFO => {
+            let gw_id = decode_u8(src)?;
+            Ok(Packet::GatewayInfo { gw_id, gw_addr: src.split_off(0) })
+        }
+        packet_type::CONNECT => {
+            let fl = decode_u8(src)?;
+            ensure!(decode_u8(src)? == PROTOCOL_ID, DecodeError::InvalidProtocol);
+            let duration = u16::decode(src)?;
+            let client_id = decode_string(src)?;
+            Ok(Packet::Connect(Connect {
+                will: fl & flags::WILL != 0,
+                clean_session: fl & flags::CLEAN_SESSION != 0,
+                duration,
+                client_id,
+            }))
+        }
+        packet_type::CONNACK => Ok(Packet::ConnectAck(decode_return_code(src)?)),
+        packet_type::WILLTOPICREQ => Ok(Packet::WillTopicRequest),
+        packet_type::WILLTOPIC => {
+            let (qos, retain, topic) = decode_will_topic(src)?;
+            Ok(Packet::WillTopic { qos, retain, topic })
+        }
+        packet_type::WILLMSGREQ => Ok(Packet::WillMessageRequest),
+        packet_type::WILLMSG => Ok(Packet::WillMessage(src.split_off(0))),
+        packet_type::REGISTER => {
+            let topic_id = u16::decode(src)?;
+            let msg_id = u16::decode(src)?;
+            let topic_name = decode_string(src)?;
+            Ok(Packet::Register { topic_id, msg_id, topic_name })
+        }
+        packet_type::REGACK => {
+            let topic_id = u16::decode(src)?;
+            let msg_id = u16::decode(src)?;
+            let return_code = decode_return_code(src)?;
+            Ok(Packet::RegisterAck { topic_id, msg_id, return_code })
+        }
+        packet_type::PUBLISH => {
+            let fl = decode_u8(src)?;
+            let topic = decode_topic(fl, src)?;
+            let msg_id = u16::decode(src)?;
+            Ok(Packet::Publish(Publish {
+                dup: fl & flags::DUP != 0,
+                retain: fl & flags::RETAIN != 0,
+                qos: decode_qos(fl),
+                topic,
+                msg_id,
+                data: src.split_off(0),
+            }))
+        }
+        packet_type::PUBACK => {
+            let topic_id = u16::decode(src)?;
+            let msg_id = u16::decode(src)?;
+            let return_code = decode_return_code(src)?;
+            Ok(Packet::PublishAck { topic_id, msg_id, return_code })
+        }
+        packet_type::PUBCOMP => Ok(Packet::PublishComplete { msg_id: u16::decode(src)? }),
+        packet_type::PUBREC => Ok(Packet::PublishReceived { msg_id: u16::decode(src)? }),
+        packet_type::PUBREL => Ok(Packet::PublishRelease { msg_id: u16::decode(src)? }),
+        packet_type::SUBSCRIBE => {
+            let fl = decode_u8(src)?;
+            let msg_id = u16::decode(src)?;
+            let topic = decode_topic_filter(fl, src)?;
+            Ok(Packet::Subscribe(Subscribe {
+                dup: fl & flags::DUP != 0,
+                qos: decode_qos(fl),
+                msg_id,
+                topic,
+            }))
+        }
+        packet_type::SUBACK => {
+            let fl = decode_u8(src)?;
+            let topic_id = u16::decode(src)?;
+            let msg_id = u16::decode(src)?;
+            let return_code = decode_return_code(src)?;
+            Ok(Packet::SubscribeAck { qos: decode_qos(fl), topic_id, msg_id, return_code })
+        }
+        packet_type::UNSUBSCRIBE => {
+            let fl = decode_u8(src)?;
+            let msg_id = u16::decode(src)?;
+            let topic = decode_topic_filter(fl, src)?;
+            Ok(Packet::Unsubscribe { msg_id, topic })
+        }
+        packet_type::UNSUBACK => Ok(Packet::UnsubscribeAck { msg_id: u16::decode(src)? }),
+        packet_type::PINGREQ => {
+            let client_id = if src.has_remaining() { Some(decode_string(src)?) } else { None };
+            Ok(Packet::PingRequest { client_id })
+        }
+        packet_type::PINGRESP => Ok(Packet::PingResponse),
+        packet_type::DISCONNECT => {
+            let duration = if src.has_remaining() { Some(u16::decode(src)?) } else { None };
+            Ok(Packet::Disconnect { duration })
+        }
+        packet_type::WILLTOPICUPD => {
+            let (qos, retain, topic) = decode_will_topic(src)?;
+            Ok(Packet::WillTopicUpdate { qos, retain, topic })
+        }
+        packet_type::WILLTOPICRESP => Ok(Packet::WillTopicResponse(decode_return_code(src)?)),
+        packet_type::WILLMSGUPD => Ok(Packet::WillMessageUpdate(src.split_off(0))),
+        packet_type::WILLMSGRESP => Ok(Packet::WillMessageResponse(decode_return_code(src)?)),
+        _ => Err(DecodeError::UnsupportedPacketType),
+    }
+}
+
+fn decode_u8(src: &mut Bytes) -> Result<u8, DecodeError> {
+    ensure!(src.has_remaining(), DecodeError::InvalidLength);
+    Ok(src.get_u8())
+}
+
+fn decode_return_code(src: &mut Bytes) -> Result<ReturnCode, DecodeError> {
+    ReturnCode::try_from(decode_u8(src)?)
+}
+
+/// Strings are not length prefixed, string takes rest of the packet
+fn decode_string(src: &mut Bytes) -> Result<ByteString, DecodeError> {
+    Ok(ByteString::try_from(src.split_off(0))?)
+}
+
+fn decode_qos(fl: u8) -> QoS {
+    match fl & flags::QOS_MASK {
+        flags::QOS_0 => QoS::AtMostOnce,
+        flags::QOS_1 => QoS::AtLeastOnce,
+        flags::QOS_2 => QoS::ExactlyOnce,
+        _ => QoS::NoConnection,
+    }
+}
+
+fn decode_will_topic(src: &mut Bytes) -> Result<(QoS, bool, ByteString), DecodeError> {
+    // empty will topic packet deletes will topic and will message
+    if !src.has_remaining() {
+        return Ok((QoS::AtMostOnce, false, ByteString::new()));
+    }
+    let fl = decode_u8(src)?;
+    let topic = decode_string(src)?;
+    Ok((decode_qos(fl), fl & flags::RETAIN != 0, topic))
+}
+
+fn decode_topic(fl: u8, src: &mut Bytes) -> Result<Topic, DecodeError> {
+    let id = u16::decode(src)?;
+    match fl & flags::TOPIC_ID_TYPE_MASK {
+        flags::TOPIC_NORMAL => Ok(Topic::Id(id)),
+        flags::TOPIC_PREDEFINED => Ok(Topic::Predefined(id)),
+        flags::TOPIC_SHORT => Ok(Topic::Short(id.to_be_bytes())),
+        _ => Err(DecodeError::MalformedPacket),
+    }
+}
+
+fn decode_topic_filter(fl: u8, src: &mut Bytes) -> Result<TopicFilter, DecodeError> {
+    match fl & flags::TOPIC_ID_TYPE_MASK {
+        flags::TOPIC_NORMAL => Ok(TopicFilter::Name(decode_string(src)?)),
+        flags::TOPIC_PREDEFINED => Ok(TopicFilter::Predefined(u16::decode(src)?)),
+        flags::TOPIC_SHORT => Ok(TopicFilter::Short(u16::decode(src)?.to_be_bytes())),
+        _ => Err(DecodeError::MalformedPacket),
+    }
+}
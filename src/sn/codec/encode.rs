@@ -0,0 +1,194 @@
+use ntex::util::{BufMut, BytesMut};
+
+use crate::error::EncodeError;
+
+use super::packet::{flags, Connect, Packet, Publish, QoS, Subscribe, Topic, TopicFilter};
+
+/// MQTT-SN protocol id of `CONNECT` packet
+const PROTOCOL_ID: u8 = 0x01;
+
+/// Size of variable part of the message
+pub(crate) fn get_encoded_size(packet: &Packet) -> usize {
+    match *packet {
+        Packet::Advertise { .. } => 3,
+        Packet::SearchGateway { .. } => 1,
+        Packet::GatewayInfo { ref gw_addr, .. } => 1 + gw_addr.len(),
+        Packet::Connect(Connect { ref client_id, .. }) => 4 + client_id.len(),
+        Packet::ConnectAck(_)
+        | Packet::WillTopicResponse(_)
+        | Packet::WillMessageResponse(_) => 1,
+        Packet::WillTopicRequest | Packet::WillMessageRequest | Packet::PingResponse => 0,
+        Packet::WillTopic { ref topic, .. } | Packet::WillTopicUpdate { ref topic, .. } => {
+            if topic.is_empty() {
+                0
+            } else {
+                1 + topic.len()
+            }
+        }
+        Packet::WillMessage(ref msg) | Packet::WillMessageUpdate(ref msg) => msg.len(),
+        Packet::Register { ref topic_name, .. } => 4 + topic_name.len(),
+        Packet::RegisterAck { .. } | Packet::PublishAck { .. } => 5,
+        Packet::Publish(Publish { ref data, .. }) => 5 + data.len(),
+        Packet::PublishComplete { .. }
+        | Packet::PublishReceived { .. }
+        | Packet::PublishRelease { .. }
+        | Packet::UnsubscribeAck { .. } => 2,
+        Packet::Subscribe(Subscribe { ref topic, .. })
+        | Packet::Unsubscribe { ref topic, .. } => {
+            3 + match topic {
+                TopicFilter::Name(name) => name.len(),
+                TopicFilter::Predefined(_) | TopicFilter::Short(_) => 2,
+            }
+        }
+        Packet::SubscribeAck { .. } => 6,
+        Packet::PingRequest { ref client_id } => client_id.as_ref().map_or(0, |id| id.len()),
+        Packet::Disconnect { duration } => duration.map_or(0, |_| 2),
+    }
+}
+
+pub(crate) fn encode(
+    packet: &Packet,
+    dst: &mut BytesMut,
+    size: usize,
+) -> Result<(), EncodeError> {
+    // length field includes itself and message type
+    if size + 2 <= u8::MAX as usize {
+        dst.reserve(size + 2);
+        dst.put_u8((size + 2) as u8);
+    } else if size + 4 <= u16::MAX as usize {
+        dst.reserve(size + 4);
+        dst.put_u8(0x01);
+        dst.put_u16((size + 4) as u16);
+    } else {
+        return Err(EncodeError::InvalidLength);
+    }
+    dst.put_u8(packet.packet_type());
+
+    match packet {
+        Packet::Advertise { gw_id, duration } => {
+            dst.put_u8(*gw_id);
+            dst.put_u16(*duration);
+        }
+        Packet::SearchGateway { radius } => dst.put_u8(*radius),
+        Packet::GatewayInfo { gw_id, gw_addr } => {
+            dst.put_u8(*gw_id);
+            dst.extend_from_slice(gw_addr);
+        }
+        Packet::Connect(Connect { will, clean_session, duration, client_id }) => {
+            let mut fl = 0;
+            if *will {
+                fl |= flags::WILL;
+            }
+            if *clean_session {
+                fl |= flags::CLEAN_SESSION;
+            }
+            dst.put_u8(fl);
+            dst.put_u8(PROTOCOL_ID);
+            dst.put_u16(*duration);
+            dst.extend_from_slice(client_id.as_bytes());
+        }
+        Packet::ConnectAck(code)
+        | Packet::WillTopicResponse(code)
+        | Packet::WillMessageResponse(code) => dst.put_u8((*code).into()),
+        Packet::WillTopicRequest | Packet::WillMessageRequest | Packet::PingResponse => (),
+        Packet::WillTopic { qos, retain, topic }
+        | Packet::WillTopicUpdate { qos, retain, topic } => {
+            if !topic.is_empty() {
+                dst.put_u8(encode_qos(*qos) | if *retain { flags::RETAIN } else { 0 });
+                dst.extend_from_slice(topic.as_bytes());
+            }
+        }
+        Packet::WillMessage(msg) | Packet::WillMessageUpdate(msg) => dst.extend_from_slice(msg),
+        Packet::Register { topic_id, msg_id, topic_name } => {
+            dst.put_u16(*topic_id);
+            dst.put_u16(*msg_id);
+            dst.extend_from_slice(topic_name.as_bytes());
+        }
+        Packet::RegisterAck { topic_id, msg_id, return_code }
+        | Packet::PublishAck { topic_id, msg_id, return_code } => {
+            dst.put_u16(*topic_id);
+            dst.put_u16(*msg_id);
+            dst.put_u8((*return_code).into());
+        }
+        Packet::Publish(Publish { dup, retain, qos, topic, msg_id, data }) => {
+            let (fl, id) = match topic {
+                Topic::Id(id) => (flags::TOPIC_NORMAL, *id),
+                Topic::Predefined(id) => (flags::TOPIC_PREDEFINED, *id),
+                Topic::Short(name) => (flags::TOPIC_SHORT, u16::from_be_bytes(*name)),
+            };
+            dst.put_u8(fl | encode_qos(*qos) | encode_dup_retain(*dup, *retain));
+            dst.put_u16(id);
+            dst.put_u16(*msg_id);
+            dst.extend_from_slice(data);
+        }
+        Packet::PublishComplete { msg_id }
+        | Packet::PublishReceived { msg_id }
+        | Packet::PublishRelease { msg_id }
+        | Packet::UnsubscribeAck { msg_id } => dst.put_u16(*msg_id),
+        Packet::Subscribe(Subscribe { dup, qos, msg_id, topic }) => {
+            dst.put_u8(
+                encode_topic_type(topic) | encode_qos(*qos) | encode_dup_retain(*dup, false),
+            );
+            dst.put_u16(*msg_id);
+            encode_topic_filter(topic, dst);
+        }
+        Packet::Unsubscribe { msg_id, topic } => {
+            dst.put_u8(encode_topic_type(topic));
+            dst.put_u16(*msg_id);
+            encode_topic_filter(topic, dst);
+        }
+        Packet::SubscribeAck { qos, topic_id, msg_id, return_code } => {
+            dst.put_u8(encode_qos(*qos));
+            dst.put_u16(*topic_id);
+            dst.put_u16(*msg_id);
+            dst.put_u8((*return_code).into());
+        }
+        Packet::PingRequest { client_id } => {
+            if let Some(id) = client_id {
+                dst.extend_from_slice(id.as_bytes());
+            }
+        }
+        Packet::Disconnect { duration } => {
+            if let Some(duration) = duration {
+                dst.put_u16(*duration);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn encode_qos(qos: QoS) -> u8 {
+    match qos {
+        QoS::AtMostOnce => flags::QOS_0,
+        QoS::AtLeastOnce => flags::QOS_1,
+        QoS::ExactlyOnce => flags::QOS_2,
+        QoS::NoConnection => flags::QOS_NO_CONNECTION,
+    }
+}
+
+fn encode_dup_retain(dup: bool, retain: bool) -> u8 {
+    let mut fl = 0;
+    if dup {
+        fl |= flags::DUP;
+    }
+    if retain {
+        fl |= flags::RETAIN;
+    }
+    fl
+}
+
+fn encode_topic_type(topic: &TopicFilter) -> u8 {
+    match topic {
+        TopicFilter::Name(_) => flags::TOPIC_NORMAL,
+        TopicFilter::Predefined(_) => flags::TOPIC_PREDEFINED,
+        TopicFilter::Short(_) => flags::TOPIC_SHORT,
+    }
+}
+
+fn encode_topic_filter(topic: &TopicFilter, dst: &mut BytesMut) {
+    match topic {
+        TopicFilter::Name(name) => dst.extend_from_slice(name.as_bytes()),
+        TopicFilter::Predefined(id) => dst.put_u16(*id),
+        TopicFilter::Short(name) => dst.extend_from_slice(name),
+    }
+}
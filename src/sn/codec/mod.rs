@@ -0,0 +1,22 @@
+//! MQTT-SN 1.2 Protocol codec
+//!
+//! ```rust
+//! use ntex_mqtt::sn::codec::{Codec, Packet};
+//!
+//! let codec = Codec::new();
+//! let buf = codec.encode_packet(Packet::PingResponse).unwrap();
+//! let (packet, size) = codec.decode_packet(&buf).unwrap().unwrap();
+//! assert_eq!(packet, Packet::PingResponse);
+//! assert_eq!(size, buf.len());
+//! ```
+
+#[allow(clippy::module_inception)]
+mod codec;
+mod decode;
+mod encode;
+mod packet;
+
+pub use self::codec::Codec;
+pub use self::packet::{
+    Connect, Packet, Publish, QoS, ReturnCode, Subscribe, Topic, TopicFilter,
+};
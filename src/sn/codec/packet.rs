@@ -0,0 +1,298 @@
+use std::fmt;
+
+use ntex::util::{ByteString, Bytes};
+
+prim_enum! {
+    /// MQTT-SN Return Code
+    pub enum ReturnCode {
+        /// Accepted
+        Accepted = 0,
+        /// Rejected: congestion
+        Congestion = 1,
+        /// Rejected: invalid topic ID
+        InvalidTopicId = 2,
+        /// Rejected: not supported
+        NotSupported = 3
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+/// Quality of service level of MQTT-SN messages
+pub enum QoS {
+    /// At most once delivery
+    AtMostOnce,
+    /// At least once delivery
+    AtLeastOnce,
+    /// Exactly once delivery
+    ExactlyOnce,
+    /// QoS -1, publish without connection set up
+    ///
+    /// Valid for publishes with predefined topic id or short topic name only.
+    NoConnection,
+}
+
+impl QoS {
+    /// Convert to MQTT QoS level, QoS -1 maps to `AtMostOnce`
+    pub fn to_mqtt(self) -> crate::types::QoS {
+        match self {
+            QoS::AtLeastOnce => crate::types::QoS::AtLeastOnce,
+            QoS::ExactlyOnce => crate::types::QoS::ExactlyOnce,
+            QoS::AtMostOnce | QoS::NoConnection => crate::types::QoS::AtMostOnce,
+        }
+    }
+}
+
+impl From<crate::types::QoS> for QoS {
+    fn from(qos: crate::types::QoS) -> Self {
+        match qos {
+            crate::types::QoS::AtMostOnce => QoS::AtMostOnce,
+            crate::types::QoS::AtLeastOnce => QoS::AtLeastOnce,
+            crate::types::QoS::ExactlyOnce => QoS::ExactlyOnce,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+/// Topic of publish message
+pub enum Topic {
+    /// Topic id registered with `REGISTER` packet
+    Id(u16),
+    /// Predefined topic id
+    Predefined(u16),
+    /// Short, two bytes long, topic name
+    Short([u8; 2]),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+/// Topic of subscribe and unsubscribe packets
+pub enum TopicFilter {
+    /// Topic name or topic filter
+    Name(ByteString),
+    /// Predefined topic id
+    Predefined(u16),
+    /// Short, two bytes long, topic name
+    Short([u8; 2]),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+/// Connect packet content
+pub struct Connect {
+    /// client requests will topic and will message prompting
+    pub will: bool,
+    /// the handling of the Session state.
+    pub clean_session: bool,
+    /// keep alive duration in seconds.
+    pub duration: u16,
+    /// identifies the Client to the Gateway.
+    pub client_id: ByteString,
+}
+
+#[derive(PartialEq, Clone)]
+/// Publish message
+pub struct Publish {
+    /// this might be re-delivery of an earlier attempt to send the Packet.
+    pub dup: bool,
+    pub retain: bool,
+    /// the level of assurance for delivery of an Application Message.
+    pub qos: QoS,
+    /// the information channel to which payload data is published.
+    pub topic: Topic,
+    /// message id, zero for QoS 0 and QoS -1 publishes.
+    pub msg_id: u16,
+    /// the Application Message that is being published.
+    pub data: Bytes,
+}
+
+impl fmt::Debug for Publish {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Publish")
+            .field("msg_id", &self.msg_id)
+            .field("topic", &self.topic)
+            .field("dup", &self.dup)
+            .field("retain", &self.retain)
+            .field("qos", &self.qos)
+            .field("data", &"<REDACTED>")
+            .finish()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+/// Subscribe message
+pub struct Subscribe {
+    /// this might be re-delivery of an earlier attempt to send the Packet.
+    pub dup: bool,
+    /// requested maximum QoS level.
+    pub qos: QoS,
+    /// message id.
+    pub msg_id: u16,
+    /// the topic name, topic filter or topic id.
+    pub topic: TopicFilter,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+/// MQTT-SN Packets
+pub enum Packet {
+    /// Gateway advertisement
+    Advertise {
+        gw_id: u8,
+        /// duration until the next advertisement, in seconds
+        duration: u16,
+    },
+    /// Client's gateway search request
+    SearchGateway { radius: u8 },
+    /// Gateway info
+    GatewayInfo {
+        gw_id: u8,
+        /// address of the gateway, sent by clients only
+        gw_addr: Bytes,
+    },
+
+    /// Client request to connect to Gateway
+    Connect(Connect),
+    /// Connect acknowledgment
+    ConnectAck(ReturnCode),
+
+    /// Will topic request
+    WillTopicRequest,
+    /// Will topic, empty topic deletes will
+    WillTopic { qos: QoS, retain: bool, topic: ByteString },
+    /// Will message request
+    WillMessageRequest,
+    /// Will message
+    WillMessage(Bytes),
+
+    /// Topic name registration
+    Register { topic_id: u16, msg_id: u16, topic_name: ByteString },
+    /// Topic registration acknowledgment
+    RegisterAck { topic_id: u16, msg_id: u16, return_code: ReturnCode },
+
+    /// Publish message
+    Publish(Publish),
+    /// Publish acknowledgment
+    PublishAck { topic_id: u16, msg_id: u16, return_code: ReturnCode },
+    /// Publish complete (assured delivery part 3)
+    PublishComplete { msg_id: u16 },
+    /// Publish received (assured delivery part 1)
+    PublishReceived { msg_id: u16 },
+    /// Publish release (assured delivery part 2)
+    PublishRelease { msg_id: u16 },
+
+    /// Client subscribe request
+    Subscribe(Subscribe),
+    /// Subscribe acknowledgment
+    SubscribeAck { qos: QoS, topic_id: u16, msg_id: u16, return_code: ReturnCode },
+    /// Unsubscribe request
+    Unsubscribe { msg_id: u16, topic: TopicFilter },
+    /// Unsubscribe acknowledgment
+    UnsubscribeAck { msg_id: u16 },
+
+    /// PING request, sleeping clients provide client id
+    PingRequest { client_id: Option<ByteString> },
+    /// PING response
+    PingResponse,
+    /// Disconnect, sleeping clients provide sleep duration
+    Disconnect { duration: Option<u16> },
+
+    /// Will topic update
+    WillTopicUpdate { qos: QoS, retain: bool, topic: ByteString },
+    /// Will topic update response
+    WillTopicResponse(ReturnCode),
+    /// Will message update
+    WillMessageUpdate(Bytes),
+    /// Will message update response
+    WillMessageResponse(ReturnCode),
+}
+
+impl Packet {
+    pub fn packet_type(&self) -> u8 {
+        match self {
+            Packet::Advertise { .. } => packet_type::ADVERTISE,
+            Packet::SearchGateway { .. } => packet_type::SEARCHGW,
+            Packet::GatewayInfo { .. } => packet_type::GWINFO,
+            Packet::Connect(_) => packet_type::CONNECT,
+            Packet::ConnectAck(_) => packet_type::CONNACK,
+            Packet::WillTopicRequest => packet_type::WILLTOPICREQ,
+            Packet::WillTopic { .. } => packet_type::WILLTOPIC,
+            Packet::WillMessageRequest => packet_type::WILLMSGREQ,
+            Packet::WillMessage(_) => packet_type::WILLMSG,
+            Packet::Register { .. } => packet_type::REGISTER,
+            Packet::RegisterAck { .. } => packet_type::REGACK,
+            Packet::Publish(_) => packet_type::PUBLISH,
+            Packet::PublishAck { .. } => packet_type::PUBACK,
+            Packet::PublishComplete { .. } => packet_type::PUBCOMP,
+            Packet::PublishReceived { .. } => packet_type::PUBREC,
+            Packet::PublishRelease { .. } => packet_type::PUBREL,
+            Packet::Subscribe(_) => packet_type::SUBSCRIBE,
+            Packet::SubscribeAck { .. } => packet_type::SUBACK,
+            Packet::Unsubscribe { .. } => packet_type::UNSUBSCRIBE,
+            Packet::UnsubscribeAck { .. } => packet_type::UNSUBACK,
+            Packet::PingRequest { .. } => packet_type::PINGREQ,
+            Packet::PingResponse => packet_type::PINGRESP,
+            Packet::Disconnect { .. } => packet_type::DISCONNECT,
+            Packet::WillTopicUpdate { .. } => packet_type::WILLTOPICUPD,
+            Packet::WillTopicResponse(_) => packet_type::WILLTOPICRESP,
+            Packet::WillMessageUpdate(_) => packet_type::WILLMSGUPD,
+            Packet::WillMessageResponse(_) => packet_type::WILLMSGRESP,
+        }
+    }
+}
+
+impl From<Connect> for Packet {
+    fn from(val: Connect) -> Packet {
+        Packet::Connect(val)
+    }
+}
+
+impl From<Publish> for Packet {
+    fn from(val: Publish) -> Packet {
+        Packet::Publish(val)
+    }
+}
+
+pub(crate) mod packet_type {
+    pub(crate) const ADVERTISE: u8 = 0x00;
+    pub(crate) const SEARCHGW: u8 = 0x01;
+    pub(crate) const GWINFO: u8 = 0x02;
+    pub(crate) const CONNECT: u8 = 0x04;
+    pub(crate) const CONNACK: u8 = 0x05;
+    pub(crate) const WILLTOPICREQ: u8 = 0x06;
+    pub(crate) const WILLTOPIC: u8 = 0x07;
+    pub(crate) const WILLMSGREQ: u8 = 0x08;
+    pub(crate) const WILLMSG: u8 = 0x09;
+    pub(crate) const REGISTER: u8 = 0x0a;
+    pub(crate) const REGACK: u8 = 0x0b;
+    pub(crate) const PUBLISH: u8 = 0x0c;
+    pub(crate) const PUBACK: u8 = 0x0d;
+    pub(crate) const PUBCOMP: u8 = 0x0e;
+    pub(crate) const PUBREC: u8 = 0x0f;
+    pub(crate) const PUBREL: u8 = 0x10;
+    pub(crate) const SUBSCRIBE: u8 = 0x12;
+    pub(crate) const SUBACK: u8 = 0x13;
+    pub(crate) const UNSUBSCRIBE: u8 = 0x14;
+    pub(crate) const UNSUBACK: u8 = 0x15;
+    pub(crate) const PINGREQ: u8 = 0x16;
+    pub(crate) const PINGRESP: u8 = 0x17;
+    pub(crate) const DISCONNECT: u8 = 0x18;
+    pub(crate) const WILLTOPICUPD: u8 = 0x1a;
+    pub(crate) const WILLTOPICRESP: u8 = 0x1b;
+    pub(crate) const WILLMSGUPD: u8 = 0x1c;
+    pub(crate) const WILLMSGRESP: u8 = 0x1d;
+}
+
+pub(crate) mod flags {
+    pub(crate) const DUP: u8 = 0b1000_0000;
+    pub(crate) const QOS_MASK: u8 = 0b0110_0000;
+    pub(crate) const RETAIN: u8 = 0b0001_0000;
+    pub(crate) const WILL: u8 = 0b0000_1000;
+    pub(crate) const CLEAN_SESSION: u8 = 0b0000_0100;
+    pub(crate) const TOPIC_ID_TYPE_MASK: u8 = 0b0000_0011;
+
+    pub(crate) const QOS_0: u8 = 0b0000_0000;
+    pub(crate) const QOS_1: u8 = 0b0010_0000;
+    pub(crate) const QOS_2: u8 = 0b0100_0000;
+    pub(crate) const QOS_NO_CONNECTION: u8 = 0b0110_0000;
+
+    pub(crate) const TOPIC_NORMAL: u8 = 0b00;
+    pub(crate) const TOPIC_PREDEFINED: u8 = 0b01;
+    pub(crate) const TOPIC_SHORT: u8 = 0b10;
+}
@@ -0,0 +1,638 @@
+use std::cell::{Cell, RefCell};
+use std::time::{Duration, Instant};
+use std::{convert::TryFrom, io, net::SocketAddr, rc::Rc};
+
+use futures_channel::oneshot;
+use ntex::connect::Address;
+use ntex::rt::{net::UdpSocket, time::delay_for};
+use ntex::util::{next, select, ByteString, Bytes, Either, HashMap, HashSet, Ready};
+
+use super::codec::{
+    Codec, Connect, Packet, Publish, QoS, ReturnCode, Subscribe, Topic, TopicFilter,
+};
+use crate::v3::client::{ControlMessage, ManagedSink, MqttConnector};
+use crate::v3::codec::SubscribeReturnCode;
+use crate::v3::{MqttSink, PublishBuilder};
+
+/// Time to wait for `REGACK` of gateway initiated topic registration
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// MQTT-SN transparent gateway
+///
+/// Will topic and will message prompting is not supported, connect packets
+/// with will flag are rejected with `NotSupported` return code. Publishes
+/// received from upstream server are forwarded to clients with QoS 0.
+///
+/// Sleeping clients are not supported. `DISCONNECT` with sleep duration
+/// closes client's upstream connection the same way as regular `DISCONNECT`,
+/// messages for sleeping clients are not buffered.
+///
+/// Client is expired if gateway does not receive any packets from it within
+/// one and a half times of keep-alive duration, upstream connection of
+/// expired client get closed.
+pub struct Gateway<A> {
+    address: A,
+    gw_id: u8,
+    client_id: ByteString,
+    max_size: u16,
+    max_clients: usize,
+    max_pending: usize,
+    predefined: HashMap<u16, ByteString>,
+}
+
+impl<A> Gateway<A>
+where
+    A: Address + Clone + 'static,
+{
+    /// Create gateway for upstream mqtt server address
+    pub fn new(address: A) -> Self {
+        Gateway {
+            address,
+            gw_id: 1,
+            client_id: ByteString::from_static("mqtt-sn-gateway"),
+            max_size: 0,
+            max_clients: 1024,
+            max_pending: 32,
+            predefined: HashMap::default(),
+        }
+    }
+
+    /// Set gateway id
+    ///
+    /// Gateway id is sent in `GWINFO` packets. By default gateway id is `1`.
+    pub fn gateway_id(mut self, id: u8) -> Self {
+        self.gw_id = id;
+        self
+    }
+
+    /// Set client id of the gateway's upstream connection
+    ///
+    /// Connection is used for QoS -1 publishes, it is re-connected with
+    /// exponential backoff if upstream server drops it. By default client id is `mqtt-sn-gateway`.
+    pub fn client_id<U>(mut self, client_id: U) -> Self
+    where
+        ByteString: From<U>,
+    {
+        self.client_id = client_id.into();
+        self
+    }
+
+    /// Set max inbound message size.
+    ///
+    /// If max size is set to `0`, size is unlimited.
+    /// By default max size is set to `0`
+    pub fn max_size(mut self, size: u16) -> Self {
+        self.max_size = size;
+        self
+    }
+
+    /// Set max number of connected clients
+    ///
+    /// Connect packets are rejected with `Congestion` return code if number
+    /// of connected clients reaches the limit. If max clients is set to `0`,
+    /// number of clients is unlimited. By default max clients is set to `1024`
+    pub fn max_clients(mut self, max: usize) -> Self {
+        self.max_clients = max;
+        self
+    }
+
+    /// Set max number of QoS 2 publishes of a client waiting for completion
+    ///
+    /// QoS 2 publishes are held by the gateway until `PUBREL` is received and
+    /// upstream server completes delivery. If client reaches the limit, new
+    /// QoS 2 publishes are rejected with `Congestion` return code.
+    /// By default max pending publishes is set to `32`
+    pub fn max_pending(mut self, max: usize) -> Self {
+        self.max_pending = max;
+        self
+    }
+
+    /// Add predefined topic
+    ///
+    /// Predefined topic ids are shared by all clients and could be used
+    /// without registration, including QoS -1 publishes.
+    pub fn predefined_topic<U>(mut self, id: u16, topic: U) -> Self
+    where
+        ByteString: From<U>,
+    {
+        self.predefined.insert(id, topic.into());
+        self
+    }
+
+    /// Run gateway on provided udp socket
+    ///
+    /// Gateway handles MQTT-SN messages until socket error occurs, then
+    /// connections of all clients get closed. Gateway's own upstream
+    /// connection is re-connected with backoff, QoS -1 publishes are
+    /// dropped while it is disconnected.
+    pub async fn run(self, socket: UdpSocket) -> io::Result<()> {
+        let client = MqttConnector::new(self.address.clone())
+            .client_id(self.client_id.clone())
+            .into_managed();
+        let sink = client.sink();
+        ntex::rt::spawn(client.start(|msg: ControlMessage| {
+            Ready::<_, ()>::Ok(match msg {
+                ControlMessage::Publish(msg) => msg.ack(),
+                msg => msg.disconnect(),
+            })
+        }));
+
+        let inner = Rc::new(Inner {
+            sink,
+            socket,
+            codec: Codec::new().max_size(self.max_size),
+            clients: RefCell::new(HashMap::default()),
+            connecting: Cell::new(0),
+            cfg: self,
+        });
+
+        let mut buf = vec![0; u16::MAX as usize];
+        loop {
+            let (size, addr) = match inner.socket.recv_from(&mut buf).await {
+                Ok(res) => res,
+                Err(err) => {
+                    inner.close();
+                    return Err(err);
+                }
+            };
+            match inner.codec.decode_packet(&Bytes::copy_from_slice(&buf[..size])) {
+                Ok(Some((pkt, _))) => {
+                    log::trace!("MQTT-SN packet from {}: {:?}", addr, pkt);
+                    inner.handle(pkt, addr).await
+                }
+                Ok(None) => log::trace!("Incomplete MQTT-SN message from {}", addr),
+                Err(err) => log::trace!("Cannot decode MQTT-SN message from {}: {}", addr, err),
+            }
+        }
+    }
+}
+
+struct Inner<A> {
+    cfg: Gateway<A>,
+    sink: ManagedSink,
+    socket: UdpSocket,
+    codec: Codec,
+    clients: RefCell<HashMap<SocketAddr, Rc<Connection>>>,
+    connecting: Cell<usize>,
+}
+
+/// Connected MQTT-SN client
+struct Connection {
+    sink: MqttSink,
+    topics: RefCell<HashMap<ByteString, u16>>,
+    names: RefCell<HashMap<u16, ByteString>>,
+    topic_id: Cell<u16>,
+    msg_id: Cell<u16>,
+    pending: RefCell<HashMap<u16, PublishBuilder>>,
+    releasing: RefCell<HashSet<u16>>,
+    registering: RefCell<HashMap<u16, oneshot::Sender<ReturnCode>>>,
+    last_seen: Cell<Instant>,
+}
+
+impl Connection {
+    fn new(sink: MqttSink) -> Self {
+        Connection {
+            sink,
+            topics: RefCell::new(HashMap::default()),
+            names: RefCell::new(HashMap::default()),
+            topic_id: Cell::new(1),
+            msg_id: Cell::new(1),
+            pending: RefCell::new(HashMap::default()),
+            releasing: RefCell::new(HashSet::default()),
+            registering: RefCell::new(HashMap::default()),
+            last_seen: Cell::new(Instant::now()),
+        }
+    }
+
+    /// Register topic name, returns topic id and true if topic is newly registered
+    fn register(&self, topic: ByteString) -> (u16, bool) {
+        if let Some(id) = self.topics.borrow().get(&topic).copied() {
+            return (id, false);
+        }
+        let id = self.topic_id.get();
+        self.topic_id.set(next_id(id));
+        self.names.borrow_mut().insert(id, topic.clone());
+        self.topics.borrow_mut().insert(topic, id);
+        (id, true)
+    }
+
+    fn unregister(&self, id: u16) {
+        if let Some(topic) = self.names.borrow_mut().remove(&id) {
+            self.topics.borrow_mut().remove(&topic);
+        }
+    }
+
+    fn take_pending(&self, msg_id: u16) -> Option<PublishBuilder> {
+        self.pending.borrow_mut().remove(&msg_id)
+    }
+
+    fn next_msg_id(&self) -> u16 {
+        let id = self.msg_id.get();
+        self.msg_id.set(next_id(id));
+        id
+    }
+
+    fn touch(&self) {
+        self.last_seen.set(Instant::now())
+    }
+}
+
+/// Ids `0x0000` and `0xffff` are reserved
+fn next_id(id: u16) -> u16 {
+    if id >= 0xfffe {
+        1
+    } else {
+        id + 1
+    }
+}
+
+impl<A> Inner<A>
+where
+    A: Address + Clone + 'static,
+{
+    fn client(&self, addr: &SocketAddr) -> Option<Rc<Connection>> {
+        self.clients.borrow().get(addr).cloned()
+    }
+
+    fn is_current(&self, addr: &SocketAddr, con: &Rc<Connection>) -> bool {
+        self.client(addr).map_or(false, |c| Rc::ptr_eq(&c, con))
+    }
+
+    /// Close upstream connections of the gateway and clients
+    fn close(&self) {
+        self.sink.close();
+        for (_, con) in self.clients.borrow_mut().drain() {
+            con.sink.close();
+        }
+    }
+
+    fn topic_name(&self, con: Option<&Connection>, topic: &Topic) -> Option<ByteString> {
+        match topic {
+            Topic::Id(id) => con.and_then(|con| con.names.borrow().get(id).cloned()),
+            Topic::Predefined(id) => self.cfg.predefined.get(id).cloned(),
+            Topic::Short(name) => ByteString::try_from(Bytes::copy_from_slice(name)).ok(),
+        }
+    }
+
+    async fn send(&self, pkt: Packet, addr: SocketAddr) {
+        match self.codec.encode_packet(pkt) {
+            Ok(buf) => {
+                if let Err(err) = self.socket.send_to(&buf, addr).await {
+                    log::trace!("Cannot send MQTT-SN message to {}: {}", addr, err);
+                }
+            }
+            Err(err) => log::error!("Cannot encode MQTT-SN packet: {:?}", err),
+        }
+    }
+
+    async fn handle(self: &Rc<Self>, pkt: Packet, addr: SocketAddr) {
+        // any packet of connected client resets keep-alive timer
+        if let Some(con) = self.client(&addr) {
+            con.touch();
+        }
+
+        match pkt {
+            Packet::SearchGateway { .. } => {
+                let pkt = Packet::GatewayInfo { gw_id: self.cfg.gw_id, gw_addr: Bytes::new() };
+                self.send(pkt, addr).await
+            }
+            Packet::Connect(pkt) => {
+                if pkt.will {
+                    log::trace!("Will is not supported by MQTT-SN gateway");
+                    return self.send(Packet::ConnectAck(ReturnCode::NotSupported), addr).await;
+                }
+                let con = self.clients.borrow_mut().remove(&addr);
+                if let Some(con) = con {
+                    con.sink.close();
+                }
+                let max = self.cfg.max_clients;
+                if max != 0 && self.clients.borrow().len() + self.connecting.get() >= max {
+                    log::trace!("Max number of MQTT-SN clients is reached, reject {}", addr);
+                    return self.send(Packet::ConnectAck(ReturnCode::Congestion), addr).await;
+                }
+                self.connecting.set(self.connecting.get() + 1);
+                ntex::rt::spawn(self.clone().connect(pkt, addr));
+            }
+            Packet::Register { msg_id, topic_name, .. } => {
+                let (topic_id, return_code) = match self.client(&addr) {
+                    Some(con) => (con.register(topic_name).0, ReturnCode::Accepted),
+                    None => (0, ReturnCode::NotSupported),
+                };
+                self.send(Packet::RegisterAck { topic_id, msg_id, return_code }, addr).await
+            }
+            Packet::Publish(pkt) => self.publish(pkt, addr).await,
+            Packet::PublishRelease { msg_id } => {
+                let con = match self.client(&addr) {
+                    Some(con) => con,
+                    None => return self.send(Packet::PublishComplete { msg_id }, addr).await,
+                };
+                if let Some(builder) = con.take_pending(msg_id) {
+                    // PUBCOMP is sent after upstream server completes QoS 2 flow
+                    con.releasing.borrow_mut().insert(msg_id);
+                    let slf = self.clone();
+                    ntex::rt::spawn(async move {
+                        let res = builder.send_exactly_once().await;
+                        con.releasing.borrow_mut().remove(&msg_id);
+                        match res {
+                            Ok(_) => slf.send(Packet::PublishComplete { msg_id }, addr).await,
+                            Err(err) => {
+                                log::trace!("Cannot publish MQTT-SN message: {:?}", err)
+                            }
+                        }
+                    });
+                } else if !con.releasing.borrow().contains(&msg_id) {
+                    // message is already published, PUBREL is re-transmitted
+                    self.send(Packet::PublishComplete { msg_id }, addr).await
+                }
+            }
+            Packet::Subscribe(pkt) => self.subscribe(pkt, addr).await,
+            Packet::Unsubscribe { msg_id, topic } => {
+                let con = match self.client(&addr) {
+                    Some(con) => con,
+                    None => return,
+                };
+                if let Some(filter) = self.topic_filter(&topic) {
+                    let slf = self.clone();
+                    ntex::rt::spawn(async move {
+                        if let Err(err) =
+                            con.sink.unsubscribe().topic_filter(filter).send().await
+                        {
+                            log::trace!("Cannot unsubscribe: {:?}", err);
+                        }
+                        slf.send(Packet::UnsubscribeAck { msg_id }, addr).await
+                    });
+                } else {
+                    self.send(Packet::UnsubscribeAck { msg_id }, addr).await
+                }
+            }
+            Packet::PingRequest { .. } => self.send(Packet::PingResponse, addr).await,
+            Packet::Disconnect { .. } => {
+                let con = self.clients.borrow_mut().remove(&addr);
+                if let Some(con) = con {
+                    con.sink.close();
+                }
+                self.send(Packet::Disconnect { duration: None }, addr).await
+            }
+            Packet::RegisterAck { msg_id, return_code, .. } => {
+                let tx = self
+                    .client(&addr)
+                    .and_then(|con| con.registering.borrow_mut().remove(&msg_id));
+                if let Some(tx) = tx {
+                    let _ = tx.send(return_code);
+                }
+            }
+            Packet::PublishAck { .. } => (),
+            pkt => log::trace!("Unsupported MQTT-SN packet from {}: {:?}", addr, pkt),
+        }
+    }
+
+    async fn connect(self: Rc<Self>, pkt: Connect, addr: SocketAddr) {
+        let duration = pkt.duration;
+        let mut connector = MqttConnector::new(self.cfg.address.clone())
+            .client_id(pkt.client_id)
+            .keep_alive(pkt.duration);
+        if pkt.clean_session {
+            connector = connector.clean_session();
+        }
+
+        let res = connector.connect().await;
+        self.connecting.set(self.connecting.get() - 1);
+
+        let client = match res {
+            Ok(client) => client,
+            Err(err) => {
+                log::trace!("Cannot connect MQTT-SN client {} to server: {:?}", addr, err);
+                return self.send(Packet::ConnectAck(ReturnCode::Congestion), addr).await;
+            }
+        };
+        let (sink, mut stream) = client.into_stream();
+        let con = Rc::new(Connection::new(sink));
+        let prev = self.clients.borrow_mut().insert(addr, con.clone());
+        if let Some(prev) = prev {
+            prev.sink.close();
+        }
+        self.send(Packet::ConnectAck(ReturnCode::Accepted), addr).await;
+
+        if duration != 0 {
+            let timeout = Duration::from_millis(u64::from(duration) * 1500);
+            ntex::rt::spawn(self.clone().keep_alive(con.clone(), addr, timeout));
+        }
+
+        // forward publishes of upstream server to the client
+        while let Some(publish) = next(&mut stream).await {
+            let name = publish.packet().topic.clone();
+            let predefined = self.cfg.predefined.iter().find(|(_, n)| **n == name);
+            let topic = if let Some((id, _)) = predefined {
+                Topic::Predefined(*id)
+            } else {
+                let (topic_id, registered) = con.register(name.clone());
+                if registered && !self.register(&con, topic_id, name, addr).await {
+                    con.unregister(topic_id);
+                    continue;
+                }
+                Topic::Id(topic_id)
+            };
+            let pkt = Publish {
+                dup: false,
+                retain: publish.retain(),
+                qos: QoS::AtMostOnce,
+                topic,
+                msg_id: 0,
+                data: publish.take_payload(),
+            };
+            self.send(Packet::Publish(pkt), addr).await;
+        }
+
+        // upstream connection is closed
+        if self.is_current(&addr, &con) {
+            self.clients.borrow_mut().remove(&addr);
+            self.send(Packet::Disconnect { duration: None }, addr).await;
+        }
+    }
+
+    /// Expire client if no packets are received within timeout
+    async fn keep_alive(
+        self: Rc<Self>,
+        con: Rc<Connection>,
+        addr: SocketAddr,
+        timeout: Duration,
+    ) {
+        loop {
+            let elapsed = con.last_seen.get().elapsed();
+            if elapsed >= timeout {
+                break;
+            }
+            delay_for(timeout - elapsed).await;
+            if !self.is_current(&addr, &con) {
+                return;
+            }
+        }
+        log::trace!("MQTT-SN client {} is expired", addr);
+        self.clients.borrow_mut().remove(&addr);
+        con.sink.close();
+    }
+
+    /// Register topic on the client, returns true if client accepts registration
+    async fn register(
+        &self,
+        con: &Connection,
+        topic_id: u16,
+        topic_name: ByteString,
+        addr: SocketAddr,
+    ) -> bool {
+        let msg_id = con.next_msg_id();
+        let (tx, rx) = oneshot::channel();
+        con.registering.borrow_mut().insert(msg_id, tx);
+        self.send(Packet::Register { topic_id, msg_id, topic_name }, addr).await;
+
+        let res = select(rx, delay_for(REGISTER_TIMEOUT)).await;
+        con.registering.borrow_mut().remove(&msg_id);
+        match res {
+            Either::Left(Ok(ReturnCode::Accepted)) => true,
+            Either::Left(Ok(code)) => {
+                log::trace!("Topic registration is rejected by {}: {:?}", addr, code);
+                false
+            }
+            _ => {
+                log::trace!("Topic registration is not acknowledged by {}", addr);
+                false
+            }
+        }
+    }
+
+    async fn publish(self: &Rc<Self>, pkt: Publish, addr: SocketAddr) {
+        // QoS -1 publishes do not require connection
+        if pkt.qos == QoS::NoConnection {
+            match (self.topic_name(None, &pkt.topic), self.sink.sink()) {
+                (Some(topic), Some(sink)) => {
+                    let mut builder = sink.publish(topic, pkt.data);
+                    if pkt.retain {
+                        builder = builder.retain();
+                    }
+                    if let Err(err) = builder.send_at_most_once() {
+                        log::trace!("Cannot publish MQTT-SN message: {:?}", err);
+                    }
+                }
+                (None, _) => log::trace!("Unknown topic of QoS -1 publish: {:?}", pkt.topic),
+                (_, None) => log::trace!("Upstream connection is closed, drop QoS -1 publish"),
+            }
+            return;
+        }
+
+        let con = match self.client(&addr) {
+            Some(con) => con,
+            None => {
+                log::trace!("Publish from unknown MQTT-SN client {}", addr);
+                return;
+            }
+        };
+        let topic_id = match pkt.topic {
+            Topic::Id(id) | Topic::Predefined(id) => id,
+            Topic::Short(name) => u16::from_be_bytes(name),
+        };
+        let msg_id = pkt.msg_id;
+        let mut builder = match self.topic_name(Some(&con), &pkt.topic) {
+            Some(topic) => con.sink.publish(topic, pkt.data),
+            None => {
+                let return_code = ReturnCode::InvalidTopicId;
+                return self
+                    .send(Packet::PublishAck { topic_id, msg_id, return_code }, addr)
+                    .await;
+            }
+        };
+        if pkt.retain {
+            builder = builder.retain();
+        }
+
+        match pkt.qos {
+            QoS::AtMostOnce | QoS::NoConnection => {
+                if let Err(err) = builder.send_at_most_once() {
+                    log::trace!("Cannot publish MQTT-SN message: {:?}", err);
+                }
+            }
+            QoS::AtLeastOnce => {
+                let slf = self.clone();
+                ntex::rt::spawn(async move {
+                    let return_code = match builder.send_at_least_once().await {
+                        Ok(_) => ReturnCode::Accepted,
+                        Err(err) => {
+                            log::trace!("Cannot publish MQTT-SN message: {:?}", err);
+                            ReturnCode::Congestion
+                        }
+                    };
+                    slf.send(Packet::PublishAck { topic_id, msg_id, return_code }, addr).await
+                });
+            }
+            QoS::ExactlyOnce => {
+                // message is published after PUBREL is received
+                let full = {
+                    let mut pending = con.pending.borrow_mut();
+                    let count = pending.len() + con.releasing.borrow().len();
+                    if count >= self.cfg.max_pending && !pending.contains_key(&msg_id) {
+                        true
+                    } else {
+                        pending.insert(msg_id, builder);
+                        false
+                    }
+                };
+                if full {
+                    log::trace!("Max number of pending QoS 2 publishes is reached: {}", addr);
+                    let return_code = ReturnCode::Congestion;
+                    self.send(Packet::PublishAck { topic_id, msg_id, return_code }, addr).await
+                } else {
+                    self.send(Packet::PublishReceived { msg_id }, addr).await
+                }
+            }
+        }
+    }
+
+    fn topic_filter(&self, topic: &TopicFilter) -> Option<ByteString> {
+        match topic {
+            TopicFilter::Name(name) => Some(name.clone()),
+            TopicFilter::Predefined(id) => self.cfg.predefined.get(id).cloned(),
+            TopicFilter::Short(name) => ByteString::try_from(Bytes::copy_from_slice(name)).ok(),
+        }
+    }
+
+    async fn subscribe(self: &Rc<Self>, pkt: Subscribe, addr: SocketAddr) {
+        let con = match self.client(&addr) {
+            Some(con) => con,
+            None => return,
+        };
+        let Subscribe { qos, msg_id, topic, .. } = pkt;
+
+        let filter = match self.topic_filter(&topic) {
+            Some(filter) => filter,
+            None => {
+                let topic_id = if let TopicFilter::Predefined(id) = topic { id } else { 0 };
+                let return_code = ReturnCode::InvalidTopicId;
+                let pkt = Packet::SubscribeAck { qos, topic_id, msg_id, return_code };
+                return self.send(pkt, addr).await;
+            }
+        };
+        // topic id is assigned to topic names without wildcards
+        let topic_id = match topic {
+            TopicFilter::Name(ref name) if !name.contains(|c: char| c == '+' || c == '#') => {
+                con.register(name.clone()).0
+            }
+            TopicFilter::Predefined(id) => id,
+            _ => 0,
+        };
+
+        let slf = self.clone();
+        ntex::rt::spawn(async move {
+            let res = con.sink.subscribe().topic_filter(filter, qos.to_mqtt()).send().await;
+            let (qos, return_code) = match res.as_ref().map(|codes| codes.first()) {
+                Ok(Some(SubscribeReturnCode::Success(qos))) => {
+                    (QoS::from(*qos), ReturnCode::Accepted)
+                }
+                Ok(_) => (qos, ReturnCode::NotSupported),
+                Err(err) => {
+                    log::trace!("Cannot subscribe: {:?}", err);
+                    (qos, ReturnCode::Congestion)
+                }
+            };
+            slf.send(Packet::SubscribeAck { qos, topic_id, msg_id, return_code }, addr).await
+        });
+    }
+}
@@ -0,0 +1,25 @@
+//! MQTT-SN 1.2 gateway
+//!
+//! Transparent gateway translates MQTT-SN messages of UDP clients into MQTT
+//! v3.1.1 protocol. Each connected MQTT-SN client gets its own connection to
+//! upstream MQTT server, QoS -1 publishes are sent over the gateway's own
+//! connection. Requires `mqtt-sn` feature.
+//!
+//! ```rust,no_run
+//! use ntex::rt::net::UdpSocket;
+//! use ntex_mqtt::sn::Gateway;
+//!
+//! #[ntex::main]
+//! async fn main() -> std::io::Result<()> {
+//!     let socket = UdpSocket::bind("0.0.0.0:1884").await?;
+//!
+//!     Gateway::new("127.0.0.1:1883")
+//!         .predefined_topic(1, "sensors/temperature")
+//!         .run(socket)
+//!         .await
+//! }
+//! ```
+pub mod codec;
+mod gateway;
+
+pub use self::gateway::Gateway;
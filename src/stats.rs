@@ -0,0 +1,210 @@
+//! Aggregated server statistics
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::{fmt::Write, sync::Arc, time::Duration};
+
+use crate::metrics::MqttMetrics;
+
+const PACKET_NAMES: [&str; 16] = [
+    "reserved",
+    "connect",
+    "connack",
+    "publish",
+    "puback",
+    "pubrec",
+    "pubrel",
+    "pubcomp",
+    "subscribe",
+    "suback",
+    "unsubscribe",
+    "unsuback",
+    "pingreq",
+    "pingresp",
+    "disconnect",
+    "auth",
+];
+
+#[derive(Default)]
+struct Inner {
+    opened: AtomicU64,
+    closed: AtomicU64,
+    failed: AtomicU64,
+    received: [AtomicU64; 16],
+    sent: [AtomicU64; 16],
+    received_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+    ack_count: AtomicU64,
+    ack_latency_us: AtomicU64,
+}
+
+/// Aggregated statistics of mqtt connections
+///
+/// `ServerStats` implements `MqttMetrics`, so it can be installed on servers
+/// and client connectors with `metrics()` builder method. Stats are shared
+/// between clones and server workers.
+///
+/// ```rust
+/// use ntex_mqtt::ServerStats;
+///
+/// let stats = ServerStats::new();
+/// // install with `MqttServer::new(handshake).metrics(stats.clone())`
+///
+/// // body of metrics endpoint
+/// let body = stats.render_prometheus();
+/// ```
+#[derive(Clone, Default)]
+pub struct ServerStats(Arc<Inner>);
+
+impl ServerStats {
+    /// Create new stats registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of currently open connections
+    pub fn active_connections(&self) -> u64 {
+        let closed = self.0.closed.load(Relaxed);
+        self.0.opened.load(Relaxed).saturating_sub(closed)
+    }
+
+    /// Total number of established connections
+    pub fn total_connections(&self) -> u64 {
+        self.0.opened.load(Relaxed)
+    }
+
+    /// Number of received packets of specified packet type
+    pub fn packets_received(&self, packet_type: u8) -> u64 {
+        self.0.received[(packet_type >> 4) as usize].load(Relaxed)
+    }
+
+    /// Number of sent packets of specified packet type
+    pub fn packets_sent(&self, packet_type: u8) -> u64 {
+        self.0.sent[(packet_type >> 4) as usize].load(Relaxed)
+    }
+
+    /// Render stats in Prometheus text exposition format
+    pub fn render_prometheus(&self) -> String {
+        let s = &self.0;
+        let mut buf = String::with_capacity(2048);
+
+        gauge(
+            &mut buf,
+            "mqtt_connections_active",
+            "Open connections",
+            self.active_connections(),
+        );
+        counter(
+            &mut buf,
+            "mqtt_connections_total",
+            "Established connections",
+            s.opened.load(Relaxed),
+        );
+        counter(
+            &mut buf,
+            "mqtt_handshake_failures_total",
+            "Failed handshakes",
+            s.failed.load(Relaxed),
+        );
+        packets(&mut buf, "mqtt_packets_received_total", "Received packets", &s.received);
+        packets(&mut buf, "mqtt_packets_sent_total", "Sent packets", &s.sent);
+        counter(
+            &mut buf,
+            "mqtt_received_bytes_total",
+            "Received bytes",
+            s.received_bytes.load(Relaxed),
+        );
+        counter(&mut buf, "mqtt_sent_bytes_total", "Sent bytes", s.sent_bytes.load(Relaxed));
+
+        let _ =
+            writeln!(buf, "# HELP mqtt_ack_latency_seconds Publish acknowledgement latency");
+        let _ = writeln!(buf, "# TYPE mqtt_ack_latency_seconds summary");
+        let _ = writeln!(
+            buf,
+            "mqtt_ack_latency_seconds_sum {}",
+            s.ack_latency_us.load(Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(buf, "mqtt_ack_latency_seconds_count {}", s.ack_count.load(Relaxed));
+        buf
+    }
+}
+
+impl MqttMetrics for ServerStats {
+    fn packet_received(&self, packet_type: u8, size: usize) {
+        self.0.received[(packet_type >> 4) as usize].fetch_add(1, Relaxed);
+        self.0.received_bytes.fetch_add(size as u64, Relaxed);
+    }
+
+    fn packet_sent(&self, packet_type: u8, size: usize) {
+        self.0.sent[(packet_type >> 4) as usize].fetch_add(1, Relaxed);
+        self.0.sent_bytes.fetch_add(size as u64, Relaxed);
+    }
+
+    fn connection_opened(&self) {
+        self.0.opened.fetch_add(1, Relaxed);
+    }
+
+    fn connection_closed(&self) {
+        self.0.closed.fetch_add(1, Relaxed);
+    }
+
+    fn handshake_failed(&self) {
+        self.0.failed.fetch_add(1, Relaxed);
+    }
+
+    fn ack_latency(&self, _: u8, latency: Duration) {
+        self.0.ack_count.fetch_add(1, Relaxed);
+        self.0.ack_latency_us.fetch_add(latency.as_micros() as u64, Relaxed);
+    }
+}
+
+fn counter(buf: &mut String, name: &str, help: &str, val: u64) {
+    let _ =
+        writeln!(buf, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, val);
+}
+
+fn gauge(buf: &mut String, name: &str, help: &str, val: u64) {
+    let _ = writeln!(buf, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, val);
+}
+
+fn packets(buf: &mut String, name: &str, help: &str, vals: &[AtomicU64; 16]) {
+    let _ = writeln!(buf, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+    for (val, tp) in vals.iter().zip(PACKET_NAMES.iter()).skip(1) {
+        let _ = writeln!(buf, "{}{{type=\"{}\"}} {}", name, tp, val.load(Relaxed));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus() {
+        let stats = ServerStats::new();
+        stats.connection_opened();
+        stats.connection_opened();
+        stats.connection_closed();
+        stats.handshake_failed();
+        stats.packet_received(0x30, 10);
+        stats.packet_received(0x62, 4);
+        stats.packet_sent(0x40, 4);
+        stats.ack_latency(0x40, Duration::from_millis(500));
+
+        assert_eq!(stats.active_connections(), 1);
+        assert_eq!(stats.total_connections(), 2);
+        assert_eq!(stats.packets_received(0x30), 1);
+        assert_eq!(stats.packets_received(0x62), 1);
+        assert_eq!(stats.packets_sent(0x40), 1);
+
+        let text = stats.render_prometheus();
+        assert!(text.contains("# TYPE mqtt_connections_active gauge\n"));
+        assert!(text.contains("mqtt_connections_active 1\n"));
+        assert!(text.contains("mqtt_connections_total 2\n"));
+        assert!(text.contains("mqtt_handshake_failures_total 1\n"));
+        assert!(text.contains("mqtt_packets_received_total{type=\"publish\"} 1\n"));
+        assert!(text.contains("mqtt_packets_received_total{type=\"pubrel\"} 1\n"));
+        assert!(text.contains("mqtt_packets_sent_total{type=\"puback\"} 1\n"));
+        assert!(text.contains("mqtt_received_bytes_total 14\n"));
+        assert!(text.contains("mqtt_ack_latency_seconds_sum 0.5\n"));
+        assert!(text.contains("mqtt_ack_latency_seconds_count 1\n"));
+        assert!(!text.contains("reserved"));
+    }
+}
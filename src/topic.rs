@@ -1,14 +1,35 @@
 use std::fmt::{self, Write};
-use std::{io, ops, str::FromStr};
+use std::{convert::Infallible, convert::TryFrom, io, ops, str::FromStr};
+
+use derive_more::Display;
+use ntex::util::ByteString;
+
+const SHARE_PREFIX: &str = "$share";
 
 fn is_metadata<T: AsRef<str>>(s: T) -> bool {
     s.as_ref().starts_with('$')
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, Display, PartialEq)]
 pub enum TopicError {
+    #[display(fmt = "Invalid topic")]
     InvalidTopic,
+    #[display(fmt = "Invalid topic level")]
     InvalidLevel,
+    /// Topic name or topic filter is empty
+    #[display(fmt = "Topic is empty")]
+    EmptyTopic,
+    /// Topic name contains `+` or `#` wildcard
+    #[display(fmt = "Topic name contains wildcard")]
+    WildcardInName,
+}
+
+impl std::error::Error for TopicError {}
+
+impl From<Infallible> for TopicError {
+    fn from(err: Infallible) -> Self {
+        match err {}
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Hash)]
@@ -67,6 +88,12 @@ impl Level {
         matches!(*self, Level::Metadata(_))
     }
 
+    #[inline]
+    /// Check if level is `+` or `#` wildcard
+    pub fn is_wildcard(&self) -> bool {
+        matches!(*self, Level::SingleWildcard | Level::MultiWildcard)
+    }
+
     #[inline]
     pub fn is_valid(&self) -> bool {
         match *self {
@@ -83,7 +110,7 @@ impl Level {
 
 macro_rules! matches {
     ($topic:expr, $levels:expr) => {{
-        let mut lhs = $topic.0.iter();
+        let mut lhs = $topic.filter().iter();
 
         for rhs in $levels {
             match lhs.next() {
@@ -119,24 +146,89 @@ impl Topic {
 
     #[inline]
     pub fn is_valid(&self) -> bool {
+        let shared = self.is_share_prefix();
+
+        if shared && !(self.0.len() > 2 && self.0[1].is_normal()) {
+            return false;
+        }
+
         self.0
             .iter()
             .position(|level| !level.is_valid())
             .or_else(|| {
                 self.0.iter().enumerate().position(|(pos, level)| match *level {
                     Level::MultiWildcard => pos != self.0.len() - 1,
-                    Level::Metadata(_) => pos != 0,
+                    Level::Metadata(_) => pos != 0 && !(shared && pos == 2),
                     _ => false,
                 })
             })
             .is_none()
     }
 
+    #[inline]
+    fn is_share_prefix(&self) -> bool {
+        if let Some(Level::Metadata(ref s)) = self.0.first() {
+            s == SHARE_PREFIX
+        } else {
+            false
+        }
+    }
+
+    #[inline]
+    /// Check if topic is a shared subscription filter, `$share/<group>/<filter>`
+    pub fn is_shared(&self) -> bool {
+        self.is_share_prefix() && self.0.len() > 2 && self.0[1].is_normal()
+    }
+
+    #[inline]
+    /// Returns share group name for shared subscription filter
+    pub fn share_group(&self) -> Option<&str> {
+        if self.is_shared() {
+            self.0[1].value()
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    /// Returns topic filter levels without `$share/<group>` prefix
+    ///
+    /// For non-shared topics all levels are returned.
+    pub fn filter(&self) -> &[Level] {
+        if self.is_shared() {
+            &self.0[2..]
+        } else {
+            &self.0
+        }
+    }
+
+    /// Split shared subscription filter into share group name and topic filter
+    pub fn split_shared(&self) -> Option<(&str, Topic)> {
+        self.share_group().map(|group| (group, Topic::from(&self.0[2..])))
+    }
+
     pub fn matches(&self, topic: &Topic) -> bool {
         matches!(self, &topic.0)
     }
 
+    /// Check if topic filter matches topic name
+    ///
+    /// Topic filters that start with wildcard do not match topic names
+    /// that start with `$` character, i.e. `#` does not match `$SYS/monitor`.
     pub fn matches_str<S: AsRef<str> + ?Sized>(&self, topic: &S) -> bool {
+        let topic = topic.as_ref();
+        if is_metadata(topic) && self.filter().first().map_or(false, Level::is_wildcard) {
+            false
+        } else {
+            self.matches_str_any(topic)
+        }
+    }
+
+    /// Check if topic filter matches topic name, wildcards match `$` topic names as well
+    ///
+    /// Could be used for server internal subscriptions, i.e. for monitoring
+    /// of all published topics including `$SYS` topics.
+    pub fn matches_str_any<S: AsRef<str> + ?Sized>(&self, topic: &S) -> bool {
         matches!(self, topic.as_ref().split('/'))
     }
 }
@@ -177,6 +269,299 @@ impl ops::DerefMut for Topic {
     }
 }
 
+/// Borrowed topic filter
+///
+/// Topic filter is validated and matched in place, topic levels are
+/// not allocated. Could be used on hot paths, use `Topic` for storage.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct TopicRef<'a>(&'a str);
+
+impl<'a> TopicRef<'a> {
+    /// Validate topic filter
+    pub fn new(filter: &'a str) -> Result<Self, TopicError> {
+        let mut parts = filter.splitn(3, '/');
+        if parts.next() == Some(SHARE_PREFIX) {
+            match (parts.next(), parts.next()) {
+                (Some(group), Some(rest)) if is_normal_level(group) => validate_filter(rest)?,
+                _ => return Err(TopicError::InvalidTopic),
+            }
+        } else {
+            validate_filter(filter)?;
+        }
+        Ok(TopicRef(filter))
+    }
+
+    #[inline]
+    /// Topic filter as str
+    pub fn as_str(&self) -> &'a str {
+        self.0
+    }
+
+    #[inline]
+    /// Check if topic is a shared subscription filter, `$share/<group>/<filter>`
+    pub fn is_shared(&self) -> bool {
+        self.0.starts_with("$share/")
+    }
+
+    /// Returns share group name for shared subscription filter
+    pub fn share_group(&self) -> Option<&'a str> {
+        if self.is_shared() {
+            self.0.split('/').nth(1)
+        } else {
+            None
+        }
+    }
+
+    /// Returns topic filter without `$share/<group>` prefix
+    pub fn filter(&self) -> &'a str {
+        if self.is_shared() {
+            self.0.splitn(3, '/').nth(2).unwrap_or("")
+        } else {
+            self.0
+        }
+    }
+
+    /// Check if topic filter matches topic name
+    ///
+    /// Topic filters that start with wildcard do not match topic names
+    /// that start with `$` character.
+    pub fn matches_str(&self, topic: &str) -> bool {
+        if is_metadata(topic) && self.filter().starts_with(|c| c == '+' || c == '#') {
+            false
+        } else {
+            self.matches_str_any(topic)
+        }
+    }
+
+    /// Check if topic filter matches topic name, wildcards match `$` topic names as well
+    pub fn matches_str_any(&self, topic: &str) -> bool {
+        let mut lhs = self.filter().split('/');
+        let mut rhs = topic.split('/');
+
+        loop {
+            match (lhs.next(), rhs.next()) {
+                (Some("#"), _) => return true,
+                (Some("+"), Some(_)) => continue,
+                (Some(l), Some(r)) if l == r => continue,
+                (None, None) => return true,
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl<'a> From<TopicRef<'a>> for Topic {
+    fn from(topic: TopicRef<'a>) -> Self {
+        Topic(topic.0.split('/').map(|level| Level::from_str(level).unwrap()).collect())
+    }
+}
+
+impl<'a> fmt::Display for TopicRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+/// Topic name, topic of published message
+///
+/// Topic name is not empty and does not contain `+` and `#` wildcards.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TopicName(ByteString);
+
+impl TopicName {
+    #[inline]
+    /// Topic name as str
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    #[inline]
+    /// Convert topic name into `ByteString`
+    pub fn into_inner(self) -> ByteString {
+        self.0
+    }
+}
+
+impl TryFrom<ByteString> for TopicName {
+    type Error = TopicError;
+
+    fn try_from(name: ByteString) -> Result<Self, TopicError> {
+        if name.is_empty() {
+            Err(TopicError::EmptyTopic)
+        } else if name.contains(|c| c == '+' || c == '#') {
+            Err(TopicError::WildcardInName)
+        } else {
+            Ok(TopicName(name))
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a str> for TopicName {
+    type Error = TopicError;
+
+    fn try_from(name: &'a str) -> Result<Self, TopicError> {
+        TopicName::try_from(ByteString::from(name))
+    }
+}
+
+impl TryFrom<String> for TopicName {
+    type Error = TopicError;
+
+    fn try_from(name: String) -> Result<Self, TopicError> {
+        TopicName::try_from(ByteString::from(name))
+    }
+}
+
+impl FromStr for TopicName {
+    type Err = TopicError;
+
+    fn from_str(name: &str) -> Result<Self, TopicError> {
+        TopicName::try_from(name)
+    }
+}
+
+impl From<TopicName> for ByteString {
+    fn from(name: TopicName) -> Self {
+        name.0
+    }
+}
+
+impl AsRef<str> for TopicName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for TopicName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Topic filter, topic of subscription
+///
+/// Topic filter is not empty and could contain `+` and `#` wildcards
+/// or be a shared subscription filter.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TopicFilter(ByteString);
+
+impl TopicFilter {
+    #[inline]
+    /// Topic filter as str
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    #[inline]
+    /// Borrowed topic filter
+    pub fn topic_ref(&self) -> TopicRef<'_> {
+        TopicRef(&self.0)
+    }
+
+    #[inline]
+    /// Check if topic filter is a shared subscription filter
+    pub fn is_shared(&self) -> bool {
+        self.topic_ref().is_shared()
+    }
+
+    /// Check if topic filter matches topic name
+    pub fn matches(&self, name: &TopicName) -> bool {
+        self.topic_ref().matches_str(name.as_str())
+    }
+
+    /// Check if topic filter matches topic name
+    pub fn matches_str(&self, name: &str) -> bool {
+        self.topic_ref().matches_str(name)
+    }
+
+    #[inline]
+    /// Convert topic filter into `ByteString`
+    pub fn into_inner(self) -> ByteString {
+        self.0
+    }
+}
+
+impl TryFrom<ByteString> for TopicFilter {
+    type Error = TopicError;
+
+    fn try_from(filter: ByteString) -> Result<Self, TopicError> {
+        if filter.is_empty() {
+            Err(TopicError::EmptyTopic)
+        } else {
+            TopicRef::new(&filter)?;
+            Ok(TopicFilter(filter))
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a str> for TopicFilter {
+    type Error = TopicError;
+
+    fn try_from(filter: &'a str) -> Result<Self, TopicError> {
+        TopicFilter::try_from(ByteString::from(filter))
+    }
+}
+
+impl TryFrom<String> for TopicFilter {
+    type Error = TopicError;
+
+    fn try_from(filter: String) -> Result<Self, TopicError> {
+        TopicFilter::try_from(ByteString::from(filter))
+    }
+}
+
+impl FromStr for TopicFilter {
+    type Err = TopicError;
+
+    fn from_str(filter: &str) -> Result<Self, TopicError> {
+        TopicFilter::try_from(filter)
+    }
+}
+
+impl From<TopicFilter> for ByteString {
+    fn from(filter: TopicFilter) -> Self {
+        filter.0
+    }
+}
+
+impl<'a> From<&'a TopicFilter> for Topic {
+    fn from(filter: &'a TopicFilter) -> Self {
+        Topic::from(filter.topic_ref())
+    }
+}
+
+impl AsRef<str> for TopicFilter {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for TopicFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+fn is_normal_level(level: &str) -> bool {
+    !level.is_empty() && !is_metadata(level) && !level.contains(|c| c == '+' || c == '#')
+}
+
+fn validate_filter(filter: &str) -> Result<(), TopicError> {
+    let mut levels = filter.split('/').enumerate().peekable();
+    while let Some((pos, level)) = levels.next() {
+        match level {
+            "#" if levels.peek().is_some() => return Err(TopicError::InvalidTopic),
+            "+" | "#" | "" => (),
+            _ if level.contains(|c| c == '+' || c == '#') => {
+                return Err(TopicError::InvalidLevel)
+            }
+            _ if pos != 0 && is_metadata(level) => return Err(TopicError::InvalidTopic),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
 #[macro_export]
 macro_rules! topic {
     ($s:expr) => {
@@ -214,10 +599,9 @@ impl MatchLevel for Level {
 impl<T: AsRef<str>> MatchLevel for T {
     fn match_level(&self, level: &Level) -> bool {
         match *level {
-            Level::Normal(ref lhs) => !is_metadata(self) && lhs == self.as_ref(),
-            Level::Metadata(ref lhs) => is_metadata(self) && lhs == self.as_ref(),
+            Level::Normal(ref lhs) | Level::Metadata(ref lhs) => lhs == self.as_ref(),
             Level::Blank => self.as_ref().is_empty(),
-            Level::SingleWildcard | Level::MultiWildcard => !is_metadata(self),
+            Level::SingleWildcard | Level::MultiWildcard => true,
         }
     }
 }
@@ -488,4 +872,130 @@ mod tests {
         assert!(Topic::from_str(&"$SYS/#").unwrap().matches_str("$SYS/"));
         assert!(Topic::from_str("$SYS/monitor/+").unwrap().matches_str("$SYS/monitor/Clients"));
     }
+
+    #[test]
+    fn test_matches_metadata() {
+        // MQTT-4.7.2-1, only first topic level is checked
+        assert!(!topic!("+/monitor/Clients").matches_str("$SYS/monitor/Clients"));
+        assert!(!topic!("#").matches_str("$SYS/monitor/Clients"));
+        assert!(topic!("sport/+").matches_str("sport/$tennis"));
+        assert!(topic!("sport/#").matches_str("sport/$tennis/player1"));
+        assert!(!topic!("sport/tennis").matches_str("sport/$tennis"));
+
+        assert!(topic!("+/monitor/Clients").matches_str_any("$SYS/monitor/Clients"));
+        assert!(topic!("#").matches_str_any("$SYS/monitor/Clients"));
+        assert!(topic!("#").matches_str_any("sport"));
+        assert!(!topic!("sport/+").matches_str_any("$SYS/monitor"));
+        assert!(topic!("$SYS/#").matches_str_any("$SYS/monitor"));
+    }
+
+    #[test]
+    fn test_shared() {
+        let t = topic!("$share/group1/sport/tennis/+");
+        assert!(t.is_shared());
+        assert_eq!(t.share_group(), Some("group1"));
+        assert_eq!(
+            t.filter(),
+            &[Level::normal("sport"), Level::normal("tennis"), Level::SingleWildcard][..]
+        );
+        assert!(t.matches_str("sport/tennis/player1"));
+        assert!(!t.matches_str("$share/group1/sport/tennis/player1"));
+        assert_eq!(t.to_string(), "$share/group1/sport/tennis/+");
+
+        let (group, filter) = t.split_shared().unwrap();
+        assert_eq!(group, "group1");
+        assert_eq!(filter.to_string(), "sport/tennis/+");
+
+        let t = topic!("$share/group1/$SYS/#");
+        assert!(t.is_shared());
+        assert!(t.matches_str("$SYS/monitor"));
+
+        let t = topic!("$share/group1/#");
+        assert!(t.matches_str("sport"));
+
+        let t = topic!("sport/tennis");
+        assert!(!t.is_shared());
+        assert_eq!(t.share_group(), None);
+        assert!(t.split_shared().is_none());
+        assert_eq!(t.filter().len(), 2);
+
+        assert!("$share".parse::<Topic>().is_err());
+        assert!("$share/group1".parse::<Topic>().is_err());
+        assert!("$share//sport".parse::<Topic>().is_err());
+        assert!("$share/+/sport".parse::<Topic>().is_err());
+        assert!("$share/gr#oup/sport".parse::<Topic>().is_err());
+        assert!("$share/group1/sport/$SYS".parse::<Topic>().is_err());
+    }
+
+    #[test]
+    fn test_topic_ref() {
+        for filter in &[
+            "sport/tennis/player1/#",
+            "sport/tennis/+",
+            "sport/+",
+            "sport/#",
+            "+/+",
+            "/+",
+            "+",
+            "#",
+            "+/monitor/Clients",
+            "$SYS/#",
+            "$SYS/monitor/+",
+            "$share/group1/sport/tennis/+",
+            "$share/group1/#",
+        ] {
+            let t = TopicRef::new(filter).unwrap();
+            let topic = topic!(filter);
+            assert_eq!(t.is_shared(), topic.is_shared());
+            assert_eq!(t.share_group(), topic.share_group());
+            assert_eq!(Topic::from(t).to_string(), *filter);
+
+            for name in &[
+                "sport",
+                "sport/",
+                "sport/tennis/player1",
+                "sport/tennis/player1/ranking",
+                "/finance",
+                "$SYS",
+                "$SYS/",
+                "$SYS/monitor/Clients",
+            ] {
+                assert_eq!(t.matches_str(name), topic.matches_str(name), "{} {}", filter, name);
+                assert_eq!(t.matches_str_any(name), topic.matches_str_any(name));
+            }
+        }
+
+        assert_eq!(TopicRef::new("$share/group1/sport/+").unwrap().filter(), "sport/+");
+        assert_eq!(TopicRef::new("sport/#/player1"), Err(TopicError::InvalidTopic));
+        assert_eq!(TopicRef::new("sport/tennis#"), Err(TopicError::InvalidLevel));
+        assert_eq!(TopicRef::new("sport/$SYS"), Err(TopicError::InvalidTopic));
+        assert!(TopicRef::new("$share").is_err());
+        assert!(TopicRef::new("$share/group1").is_err());
+        assert!(TopicRef::new("$share//sport").is_err());
+        assert!(TopicRef::new("$share/+/sport").is_err());
+        assert!(TopicRef::new("$share/group1/sport/$SYS").is_err());
+        assert!(TopicRef::new("$share/group1/$SYS/#").is_ok());
+    }
+
+    #[test]
+    fn test_topic_name_filter() {
+        let name = TopicName::try_from("sensors/1/temp").unwrap();
+        assert_eq!(name.as_str(), "sensors/1/temp");
+        assert_eq!(TopicName::try_from(""), Err(TopicError::EmptyTopic));
+        assert_eq!(TopicName::try_from("sensors/#"), Err(TopicError::WildcardInName));
+        assert_eq!("sensors/+/temp".parse::<TopicName>(), Err(TopicError::WildcardInName));
+        assert!(TopicName::try_from("$SYS/monitor").is_ok());
+
+        let filter = TopicFilter::try_from("sensors/+/temp").unwrap();
+        assert!(filter.matches(&name));
+        assert!(!filter.is_shared());
+        assert!(!TopicFilter::try_from("sensors/#").unwrap().matches_str("$SYS/sensors"));
+        assert_eq!(Topic::from(&filter).to_string(), "sensors/+/temp");
+        assert_eq!(TopicFilter::try_from(""), Err(TopicError::EmptyTopic));
+        assert_eq!(TopicFilter::try_from("sensors/#/temp"), Err(TopicError::InvalidTopic));
+        assert_eq!("sensors/te#mp".parse::<TopicFilter>(), Err(TopicError::InvalidLevel));
+        assert!("$share/group1/sensors/#".parse::<TopicFilter>().unwrap().is_shared());
+
+        assert_eq!(TopicError::WildcardInName.to_string(), "Topic name contains wildcard");
+    }
 }
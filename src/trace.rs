@@ -0,0 +1,225 @@
+//! Per-connection tracing spans
+//!
+//! Spans are recorded only if `tracing` feature is enabled, otherwise
+//! all types of this module are no-op.
+use std::task::{Context, Poll};
+use std::{future::Future, net::SocketAddr, pin::Pin};
+
+use ntex::codec::{Decoder, Encoder};
+use ntex::service::Service;
+
+use crate::io::DispatchItem;
+use crate::types::packet_type;
+use crate::{v3, v5};
+
+/// Tracing span of mqtt connection or of dispatched message
+#[derive(Clone)]
+pub(crate) struct Span {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl Default for Span {
+    fn default() -> Self {
+        Span {
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::none(),
+        }
+    }
+}
+
+impl Span {
+    /// Connection span, records client id, peer address and protocol version
+    #[allow(unused_variables)]
+    pub(crate) fn connection(
+        client_id: &str,
+        peer_addr: Option<SocketAddr>,
+        protocol: &'static str,
+    ) -> Self {
+        #[cfg(feature = "tracing")]
+        {
+            Span {
+                span: tracing::info_span!(
+                    "mqtt.connection",
+                    client_id,
+                    peer_addr = ?peer_addr,
+                    protocol,
+                ),
+            }
+        }
+        #[cfg(not(feature = "tracing"))]
+        {
+            Span::default()
+        }
+    }
+
+    /// Child span for dispatched message
+    #[allow(unused_variables)]
+    pub(crate) fn message(&self, kind: &'static str) -> Self {
+        #[cfg(feature = "tracing")]
+        {
+            Span { span: tracing::debug_span!(parent: &self.span, "mqtt.message", kind) }
+        }
+        #[cfg(not(feature = "tracing"))]
+        {
+            Span::default()
+        }
+    }
+
+    /// Execute `f` within the span
+    pub(crate) fn in_scope<F: FnOnce() -> R, R>(&self, f: F) -> R {
+        #[cfg(feature = "tracing")]
+        {
+            self.span.in_scope(f)
+        }
+        #[cfg(not(feature = "tracing"))]
+        {
+            f()
+        }
+    }
+
+    /// Poll future within the span
+    pub(crate) fn instrument<F: Future>(&self, fut: F) -> Instrumented<F> {
+        Instrumented { fut, span: self.clone() }
+    }
+}
+
+/// Kind of dispatched message, used as `kind` field of message span
+pub(crate) trait MessageKind {
+    fn kind(&self) -> &'static str;
+}
+
+impl MessageKind for v3::codec::Packet {
+    fn kind(&self) -> &'static str {
+        packet_kind(self.packet_type())
+    }
+}
+
+impl MessageKind for v5::codec::Packet {
+    fn kind(&self) -> &'static str {
+        packet_kind(self.packet_type())
+    }
+}
+
+impl<U> MessageKind for DispatchItem<U>
+where
+    U: Encoder + Decoder,
+    <U as Decoder>::Item: MessageKind,
+{
+    fn kind(&self) -> &'static str {
+        match self {
+            DispatchItem::Item(pkt) => pkt.kind(),
+            DispatchItem::WBackPressureEnabled | DispatchItem::WBackPressureDisabled => {
+                "backpressure"
+            }
+            DispatchItem::KeepAliveTimeout => "keepalive_timeout",
+            DispatchItem::DecoderError(_) | DispatchItem::EncoderError(_) => "protocol_error",
+            DispatchItem::IoError(_) => "io_error",
+        }
+    }
+}
+
+fn packet_kind(packet_type: u8) -> &'static str {
+    match packet_type {
+        packet_type::CONNECT => "connect",
+        packet_type::CONNACK => "connack",
+        packet_type::PUBLISH_START..=packet_type::PUBLISH_END => "publish",
+        packet_type::PUBACK => "puback",
+        packet_type::PUBREC => "pubrec",
+        packet_type::PUBREL => "pubrel",
+        packet_type::PUBCOMP => "pubcomp",
+        packet_type::SUBSCRIBE => "subscribe",
+        packet_type::SUBACK => "suback",
+        packet_type::UNSUBSCRIBE => "unsubscribe",
+        packet_type::UNSUBACK => "unsuback",
+        packet_type::PINGREQ => "pingreq",
+        packet_type::PINGRESP => "pingresp",
+        packet_type::DISCONNECT => "disconnect",
+        packet_type::AUTH => "auth",
+        _ => "unknown",
+    }
+}
+
+/// Dispatcher service wrapper, executes every dispatched message within
+/// child span of connection span
+pub(crate) struct Traced<S> {
+    service: S,
+    span: Span,
+}
+
+impl<S> Traced<S> {
+    pub(crate) fn new(service: S, span: Span) -> Self {
+        Traced { service, span }
+    }
+}
+
+impl<S> Service for Traced<S>
+where
+    S: Service,
+    S::Request: MessageKind,
+{
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Instrumented<S::Future>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        self.span.in_scope(|| self.service.poll_shutdown(cx, is_error))
+    }
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let span = self.span.message(req.kind());
+        let fut = span.in_scope(|| self.service.call(req));
+        Instrumented { fut, span }
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// Future that is polled within the span
+    pub(crate) struct Instrumented<F> {
+        #[pin]
+        fut: F,
+        span: Span,
+    }
+}
+
+impl<F: Future> Future for Instrumented<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let fut = this.fut;
+        this.span.in_scope(|| fut.poll(cx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_kind() {
+        assert_eq!(v3::codec::Packet::PingRequest.kind(), "pingreq");
+        assert_eq!(v5::codec::Packet::PingResponse.kind(), "pingresp");
+        assert_eq!(packet_kind(packet_type::PUBLISH_START | 0b0000_1011), "publish");
+        assert_eq!(packet_kind(0), "unknown");
+
+        let item: DispatchItem<v3::codec::Codec> = DispatchItem::KeepAliveTimeout;
+        assert_eq!(item.kind(), "keepalive_timeout");
+    }
+
+    #[ntex::test]
+    async fn test_span() {
+        let span = Span::default();
+        assert_eq!(span.in_scope(|| 1), 1);
+
+        let span = Span::connection("client", None, "5.0");
+        assert_eq!(span.message("publish").instrument(async { 2 }).await, 2);
+    }
+}
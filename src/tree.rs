@@ -0,0 +1,389 @@
+//! Topic subscription tree
+use std::str::Split;
+
+use ntex::util::HashMap;
+
+use crate::topic::{Level, Topic};
+
+/// Topic subscription tree
+///
+/// Trie over topic filter levels, supports `+` and `#` wildcards and
+/// `$share/<group>/<filter>` shared subscriptions. Matching cost depends
+/// on number of topic levels and wildcard branches, not on total number
+/// of subscriptions.
+pub struct SubscriptionTree<T> {
+    root: Node<T>,
+    len: usize,
+}
+
+struct Node<T> {
+    children: HashMap<String, Node<T>>,
+    single: Option<Box<Node<T>>>,
+    multi: Values<T>,
+    values: Values<T>,
+}
+
+struct Values<T> {
+    subs: Vec<T>,
+    shared: Vec<(String, Vec<T>)>,
+}
+
+/// Subscriptions that match published topic
+pub struct SubscriptionMatches<'a, T> {
+    subs: Vec<&'a T>,
+    shared: Vec<(&'a str, &'a [T])>,
+}
+
+impl<T> SubscriptionTree<T> {
+    /// Create empty subscription tree
+    pub fn new() -> Self {
+        SubscriptionTree { root: Node::default(), len: 0 }
+    }
+
+    #[inline]
+    /// Number of subscriptions in the tree
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    /// Check if tree is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Add subscription for topic filter
+    pub fn insert(&mut self, filter: &Topic, value: T) {
+        let mut node = &mut self.root;
+        let mut levels = filter.filter().iter();
+        let values = loop {
+            match levels.next() {
+                Some(Level::Normal(s)) | Some(Level::Metadata(s)) => {
+                    node = node.children.entry(s.clone()).or_default();
+                }
+                Some(Level::Blank) => node = node.children.entry(String::new()).or_default(),
+                Some(Level::SingleWildcard) => {
+                    node = &mut **node.single.get_or_insert_with(Default::default)
+                }
+                Some(Level::MultiWildcard) => break &mut node.multi,
+                None => break &mut node.values,
+            }
+        };
+        values.insert(filter.share_group(), value);
+        self.len += 1;
+    }
+
+    /// Remove subscription for topic filter
+    ///
+    /// Returns `true` if subscription is found and removed.
+    pub fn remove(&mut self, filter: &Topic, value: &T) -> bool
+    where
+        T: PartialEq,
+    {
+        let removed = self.root.remove(filter.filter(), filter.share_group(), value);
+        if removed {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    /// Find subscriptions that match published topic
+    ///
+    /// Wildcard filters do not match topics that start with `$` character.
+    pub fn matches<'a>(&'a self, topic: &str) -> SubscriptionMatches<'a, T> {
+        let mut matches = SubscriptionMatches { subs: Vec::new(), shared: Vec::new() };
+        self.visit(topic, |group, subs| {
+            if let Some(group) = group {
+                matches.shared.push((group, subs));
+            } else {
+                matches.subs.extend(subs.iter());
+            }
+        });
+        matches
+    }
+
+    /// Visit subscriptions that match published topic, without allocations
+    ///
+    /// `f` is called with non-shared subscriptions of matched filter, or
+    /// with share group name and group members for shared subscriptions.
+    /// Wildcard filters do not match topics that start with `$` character.
+    pub fn visit<'a, F>(&'a self, topic: &str, mut f: F)
+    where
+        F: FnMut(Option<&'a str>, &'a [T]),
+    {
+        let metadata = topic.starts_with('$');
+        self.root.visit(topic.split('/'), metadata, &mut f);
+    }
+}
+
+impl SubscriptionTree<usize> {
+    /// Smallest value of matched non-shared subscriptions
+    ///
+    /// Used by routers to find first registered resource that matches
+    /// publish topic.
+    pub(crate) fn min_match(&self, topic: &str) -> Option<usize> {
+        let mut min = None;
+        self.visit(topic, |group, subs| {
+            if group.is_none() {
+                min = subs.iter().copied().chain(min).min();
+            }
+        });
+        min
+    }
+}
+
+impl<T> Default for SubscriptionTree<T> {
+    fn default() -> Self {
+        SubscriptionTree::new()
+    }
+}
+
+impl<T> Node<T> {
+    fn is_empty(&self) -> bool {
+        self.children.is_empty()
+            && self.single.is_none()
+            && self.multi.is_empty()
+            && self.values.is_empty()
+    }
+
+    fn visit<'a, F>(&'a self, mut levels: Split<'_, char>, metadata: bool, f: &mut F)
+    where
+        F: FnMut(Option<&'a str>, &'a [T]),
+    {
+        // multi-level wildcard matches parent level as well
+        if !metadata {
+            self.multi.visit(f);
+        }
+
+        if let Some(level) = levels.next() {
+            if let Some(node) = self.children.get(level) {
+                node.visit(levels.clone(), false, f);
+            }
+            if !metadata {
+                if let Some(ref node) = self.single {
+                    node.visit(levels, false, f);
+                }
+            }
+        } else {
+            self.values.visit(f);
+        }
+    }
+
+    fn remove(&mut self, levels: &[Level], group: Option<&str>, value: &T) -> bool
+    where
+        T: PartialEq,
+    {
+        let (level, rest) = if let Some(item) = levels.split_first() {
+            item
+        } else {
+            return self.values.remove(group, value);
+        };
+
+        match level {
+            Level::MultiWildcard => self.multi.remove(group, value),
+            Level::SingleWildcard => {
+                let removed =
+                    self.single.as_mut().map_or(false, |node| node.remove(rest, group, value));
+                if self.single.as_ref().map_or(false, |node| node.is_empty()) {
+                    self.single = None;
+                }
+                removed
+            }
+            Level::Normal(_) | Level::Metadata(_) | Level::Blank => {
+                let key = level.value().unwrap_or("");
+                let removed = self
+                    .children
+                    .get_mut(key)
+                    .map_or(false, |node| node.remove(rest, group, value));
+                if self.children.get(key).map_or(false, |node| node.is_empty()) {
+                    self.children.remove(key);
+                }
+                removed
+            }
+        }
+    }
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Node {
+            children: HashMap::default(),
+            single: None,
+            multi: Values::default(),
+            values: Values::default(),
+        }
+    }
+}
+
+impl<T> Values<T> {
+    fn is_empty(&self) -> bool {
+        self.subs.is_empty() && self.shared.is_empty()
+    }
+
+    fn insert(&mut self, group: Option<&str>, value: T) {
+        if let Some(group) = group {
+            if let Some(item) = self.shared.iter_mut().find(|item| item.0 == group) {
+                item.1.push(value);
+            } else {
+                self.shared.push((group.to_string(), vec![value]));
+            }
+        } else {
+            self.subs.push(value);
+        }
+    }
+
+    fn remove(&mut self, group: Option<&str>, value: &T) -> bool
+    where
+        T: PartialEq,
+    {
+        let (subs, idx) = if let Some(group) = group {
+            if let Some(idx) = self.shared.iter().position(|item| item.0 == group) {
+                (&mut self.shared[idx].1, Some(idx))
+            } else {
+                return false;
+            }
+        } else {
+            (&mut self.subs, None)
+        };
+
+        if let Some(pos) = subs.iter().position(|v| v == value) {
+            subs.remove(pos);
+            if let Some(idx) = idx {
+                if self.shared[idx].1.is_empty() {
+                    self.shared.remove(idx);
+                }
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    fn visit<'a, F>(&'a self, f: &mut F)
+    where
+        F: FnMut(Option<&'a str>, &'a [T]),
+    {
+        if !self.subs.is_empty() {
+            f(None, &self.subs);
+        }
+        for (group, subs) in &self.shared {
+            f(Some(group.as_str()), subs);
+        }
+    }
+}
+
+impl<T> Default for Values<T> {
+    fn default() -> Self {
+        Values { subs: Vec::new(), shared: Vec::new() }
+    }
+}
+
+impl<'a, T> SubscriptionMatches<'a, T> {
+    #[inline]
+    /// Check if there are no matched subscriptions
+    pub fn is_empty(&self) -> bool {
+        self.subs.is_empty() && self.shared.is_empty()
+    }
+
+    #[inline]
+    /// Matched non-shared subscriptions
+    pub fn subscriptions(&self) -> &[&'a T] {
+        &self.subs
+    }
+
+    #[inline]
+    /// Matched shared subscriptions, share group name and group members
+    ///
+    /// Same group could be returned multiple times if several filters
+    /// of the group match the topic.
+    pub fn shared(&self) -> &[(&'a str, &'a [T])] {
+        &self.shared
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(filters: &[(&str, usize)]) -> SubscriptionTree<usize> {
+        let mut tree = SubscriptionTree::new();
+        for (filter, val) in filters {
+            tree.insert(&topic!(filter), *val);
+        }
+        tree
+    }
+
+    fn matches(tree: &SubscriptionTree<usize>, topic: &str) -> Vec<usize> {
+        let mut res: Vec<_> = tree.matches(topic).subscriptions().iter().map(|v| **v).collect();
+        res.sort_unstable();
+        res
+    }
+
+    #[test]
+    fn test_matches() {
+        let tree = tree(&[
+            ("sport/tennis/player1", 1),
+            ("sport/tennis/+", 2),
+            ("sport/#", 3),
+            ("#", 4),
+            ("+/+/player1", 5),
+            ("sport/+", 6),
+            ("/finance", 7),
+            ("$SYS/#", 8),
+            ("+", 9),
+        ]);
+        assert_eq!(tree.len(), 9);
+
+        assert_eq!(matches(&tree, "sport/tennis/player1"), vec![1, 2, 3, 4, 5]);
+        assert_eq!(matches(&tree, "sport/tennis/player2"), vec![2, 3, 4]);
+        assert_eq!(matches(&tree, "sport"), vec![3, 4, 9]);
+        assert_eq!(matches(&tree, "sport/"), vec![3, 4, 6]);
+        assert_eq!(matches(&tree, "/finance"), vec![4, 7]);
+        assert_eq!(matches(&tree, "$SYS/uptime"), vec![8]);
+        assert_eq!(matches(&tree, "$SYS"), vec![8]);
+        assert_eq!(matches(&tree, "$other/player1"), Vec::<usize>::new());
+
+        assert_eq!(tree.min_match("sport/tennis/player2"), Some(2));
+        assert_eq!(tree.min_match("$other/player1"), None);
+    }
+
+    #[test]
+    fn test_shared() {
+        let tree = tree(&[
+            ("$share/group1/sport/#", 1),
+            ("$share/group1/sport/#", 2),
+            ("$share/group2/sport/+", 3),
+            ("sport/tennis", 4),
+        ]);
+
+        let m = tree.matches("sport/tennis");
+        assert_eq!(m.subscriptions(), &[&4]);
+        assert_eq!(m.shared(), &[("group1", &[1, 2][..]), ("group2", &[3][..])]);
+        assert!(tree.matches("finance").is_empty());
+
+        let mut visited = Vec::new();
+        tree.visit("sport/tennis", |group, subs| visited.push((group, subs.to_vec())));
+        visited.sort();
+        assert_eq!(
+            visited,
+            vec![(None, vec![4]), (Some("group1"), vec![1, 2]), (Some("group2"), vec![3])]
+        );
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut tree = tree(&[("sport/+/player1", 1), ("sport/#", 2), ("$share/g/sport/+", 3)]);
+
+        assert!(!tree.remove(&topic!("sport/+/player1"), &2));
+        assert!(!tree.remove(&topic!("sport/+"), &3));
+        assert!(tree.remove(&topic!("sport/+/player1"), &1));
+        assert!(tree.remove(&topic!("$share/g/sport/+"), &3));
+        assert_eq!(matches(&tree, "sport/tennis/player1"), vec![2]);
+        assert!(tree.matches("sport/tennis").shared().is_empty());
+        assert!(tree.root.children["sport"].single.is_none());
+
+        assert!(tree.remove(&topic!("sport/#"), &2));
+        assert!(tree.is_empty());
+        assert!(tree.root.is_empty());
+    }
+}
@@ -1,6 +1,10 @@
+use crate::error::DecodeError;
+
 pub const MQTT: &[u8] = b"MQTT";
 pub const MQTT_LEVEL_3: u8 = 4;
 pub const MQTT_LEVEL_5: u8 = 5;
+pub const MQISDP: &[u8] = b"MQIsdp";
+pub const MQISDP_LEVEL: u8 = 3;
 pub const WILL_QOS_SHIFT: u8 = 3;
 
 /// Max possible packet size
@@ -47,6 +51,135 @@ bitflags::bitflags! {
     }
 }
 
+/// Codec conformance mode
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Conformance {
+    /// Reject packets that violate protocol specification
+    Strict,
+    /// Tolerate common real-world deviations from protocol specification
+    ///
+    /// Lenient mode ignores reserved flags of fixed header and of
+    /// connect, connack and subscribe packets, dup flag of QoS 0 publish
+    /// packets, will flags without will and empty client id without
+    /// clean session.
+    ///
+    /// Unknown v5 properties of publish packets and of will message are
+    /// preserved, so they could be forwarded to subscribers. Properties of
+    /// other packets are not forwarded, unknown properties of other packets
+    /// are rejected as malformed in both modes.
+    Lenient,
+}
+
+impl Conformance {
+    pub(crate) fn is_strict(self) -> bool {
+        self == Conformance::Strict
+    }
+}
+
+impl Default for Conformance {
+    fn default() -> Self {
+        Conformance::Strict
+    }
+}
+
+/// In-flight packet handling, if publish future is dropped before ack is received
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CancelPolicy {
+    /// Release packet id and receive credit, late ack from the peer is ignored
+    Forget,
+    /// Keep packet in-flight until ack is received from the peer
+    ///
+    /// Packet stays in session store and gets re-delivered on session restore.
+    Keep,
+}
+
+impl Default for CancelPolicy {
+    fn default() -> Self {
+        CancelPolicy::Forget
+    }
+}
+
+/// Matching of peer's acks to in-flight packets
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AckOrder {
+    /// Acks must arrive in the order packets were sent, out of order ack
+    /// closes connection
+    Strict,
+    /// Acks are matched by packet id regardless of order
+    ///
+    /// Acks for unknown packet ids and acks of unexpected type still
+    /// close connection.
+    Unordered,
+}
+
+impl Default for AckOrder {
+    fn default() -> Self {
+        AckOrder::Strict
+    }
+}
+
+/// Sink pool and connection buffer configuration
+///
+/// Pools are shared by all connections of a server worker or of a client
+/// connector.
+#[derive(Debug, Copy, Clone)]
+pub struct PoolConfig {
+    pub(crate) inflight: usize,
+    pub(crate) read_hw: u16,
+    pub(crate) write_hw: u16,
+    pub(crate) lw: u16,
+    pub(crate) shrink: bool,
+}
+
+impl PoolConfig {
+    /// Create pool configuration with default values
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set initial capacity of per-connection in-flight queues
+    ///
+    /// By default capacity is 8 packets.
+    pub fn inflight_capacity(mut self, capacity: usize) -> Self {
+        self.inflight = capacity;
+        self
+    }
+
+    /// Set initial read/write buffer sizes
+    ///
+    /// Servers use these params until handshake is completed, after that
+    /// params of `HandshakeAck` are applied. By default max read and write
+    /// buffer sizes are 8kb and min buffer size is 1kb.
+    pub fn buffer_params(
+        mut self,
+        max_read_buf: u16,
+        max_write_buf: u16,
+        min_buf: u16,
+    ) -> Self {
+        self.read_hw = max_read_buf;
+        self.write_hw = max_write_buf;
+        self.lw = min_buf;
+        self
+    }
+
+    /// Shrink ack and waiter channel pools when connection is closed
+    ///
+    /// Pools are shrunk only if the closed connection had more in-flight
+    /// packets than `inflight_capacity()`. Shrinking releases memory after
+    /// connection bursts, at the cost of re-allocation for new connections.
+    /// By default pools do not shrink.
+    pub fn shrink(mut self, enabled: bool) -> Self {
+        self.shrink = enabled;
+        self
+    }
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self { inflight: 8, read_hw: 8 * 1024, write_hw: 8 * 1024, lw: 1024, shrink: false }
+    }
+}
+
 pub(super) mod packet_type {
     pub(crate) const CONNECT: u8 = 0b0001_0000;
     pub(crate) const CONNACK: u8 = 0b0010_0000;
@@ -66,6 +199,37 @@ pub(super) mod packet_type {
     pub(crate) const AUTH: u8 = 0b1111_0000;
 }
 
+/// Validate flags of fixed header, returns normalized first byte
+pub(crate) fn check_packet_flags(
+    first_byte: u8,
+    conformance: Conformance,
+) -> Result<u8, DecodeError> {
+    let flags = first_byte & 0b0000_1111;
+    let expected = match first_byte {
+        packet_type::PUBLISH_START..=packet_type::PUBLISH_END => {
+            ensure!(flags & 0b0110 != 0b0110, DecodeError::InvalidQoS);
+            if flags & 0b1110 == 0b1000 {
+                // dup flag must be unset for QoS 0 messages
+                ensure!(!conformance.is_strict(), DecodeError::InvalidPacketFlags);
+                return Ok(first_byte & !0b1000);
+            }
+            return Ok(first_byte);
+        }
+        0b0110_0000..=0b0110_1111 => packet_type::PUBREL,
+        0b1000_0000..=0b1000_1111 => packet_type::SUBSCRIBE,
+        0b1010_0000..=0b1010_1111 => packet_type::UNSUBSCRIBE,
+        0b0001_0000..=0b1111_1111 => first_byte & 0b1111_0000,
+        _ => return Ok(first_byte),
+    };
+    if first_byte == expected {
+        Ok(first_byte)
+    } else if conformance.is_strict() {
+        Err(DecodeError::InvalidPacketFlags)
+    } else {
+        Ok(expected)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub(crate) struct FixedHeader {
     /// Fixed Header byte
@@ -0,0 +1,71 @@
+//! Unix domain socket transport
+use std::task::{Context, Poll};
+use std::{future::Future, marker::PhantomData, pin::Pin};
+
+use ntex::connect::{Address, Connect, ConnectError};
+use ntex::rt::net::UnixStream;
+use ntex::service::Service;
+
+/// Unix domain socket connector
+///
+/// Host of the connect address is used as a socket path,
+/// for example `MqttConnector::new("/var/run/mqtt.sock").unix()`.
+pub struct UnixConnector<A>(PhantomData<A>);
+
+impl<A> UnixConnector<A> {
+    /// Create unix domain socket connector
+    pub fn new() -> Self {
+        UnixConnector(PhantomData)
+    }
+}
+
+impl<A> Default for UnixConnector<A> {
+    fn default() -> Self {
+        UnixConnector::new()
+    }
+}
+
+impl<A: Address> Service for UnixConnector<A> {
+    type Request = Connect<A>;
+    type Response = UnixStream;
+    type Error = ConnectError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    #[inline]
+    fn poll_ready(&self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&self, req: Connect<A>) -> Self::Future {
+        let path = req.host().to_string();
+
+        Box::pin(async move {
+            if path.is_empty() {
+                return Err(ConnectError::Unresolved);
+            }
+            log::trace!("Connecting to unix socket: {:?}", path);
+            UnixStream::connect(path).await.map_err(ConnectError::Io)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[ntex::test]
+    async fn test_connect() {
+        let path = std::env::temp_dir().join(format!("ntex-mqtt-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let lst = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        let connector = UnixConnector::default();
+        let addr = path.to_str().unwrap().to_string();
+        assert!(connector.call(Connect::new(addr)).await.is_ok());
+        assert!(lst.accept().is_ok());
+
+        let _ = std::fs::remove_file(&path);
+        let err = connector.call(Connect::new(String::new())).await;
+        assert!(matches!(err, Err(ConnectError::Unresolved)));
+    }
+}
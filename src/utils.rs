@@ -1,11 +1,20 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
 use std::num::{NonZeroU16, NonZeroU32};
 use std::task::{Context, Poll};
-use std::{convert::TryFrom, future::Future, io::Cursor, pin::Pin};
+use std::{
+    any::Any, convert::TryFrom, future::Future, io, io::Cursor, net::SocketAddr, pin::Pin,
+    rc::Rc,
+};
 
+use ntex::rt::net::TcpStream;
 use ntex::service::Service;
-use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
+use ntex::task::LocalWaker;
+use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut, HashMap};
 
 use crate::error::{DecodeError, EncodeError};
+use crate::io::State;
+use crate::proxy_protocol::ProxyStream;
 
 macro_rules! ensure {
     ($cond:expr, $e:expr) => {
@@ -130,6 +139,15 @@ impl Decode for ByteString {
     }
 }
 
+/// Check string for U+0000 and control characters
+pub(crate) fn check_utf8_string(s: &str) -> Result<(), DecodeError> {
+    if s.chars().any(char::is_control) {
+        Err(DecodeError::InvalidUtf8String)
+    } else {
+        Ok(())
+    }
+}
+
 pub(crate) fn take_properties(src: &mut Bytes) -> Result<Bytes, DecodeError> {
     let prop_len = decode_variable_length_cursor(src)?;
     ensure!(src.remaining() >= prop_len as usize, DecodeError::InvalidLength);
@@ -137,6 +155,50 @@ pub(crate) fn take_properties(src: &mut Bytes) -> Result<Bytes, DecodeError> {
     Ok(src.split_to(prop_len as usize))
 }
 
+/// Decode properties block, property id is attached to decode errors
+pub(crate) fn decode_properties<F>(src: &mut Bytes, mut f: F) -> Result<(), DecodeError>
+where
+    F: FnMut(u8, &mut Bytes) -> Result<(), DecodeError>,
+{
+    let prop_src = &mut take_properties(src)?;
+    while prop_src.has_remaining() {
+        let unparsed = prop_src.len();
+        let prop_id = prop_src.get_u8();
+        f(prop_id, prop_src).map_err(|err| err.with_property(prop_id, unparsed))?;
+    }
+    Ok(())
+}
+
+/// Decode properties block, unknown properties are preserved if `preserve` is set
+///
+/// `f` returns `false` for unknown property. Returns raw remainder of properties
+/// block, starting with first unknown property.
+pub(crate) fn decode_properties_preserve<F>(
+    src: &mut Bytes,
+    preserve: bool,
+    mut f: F,
+) -> Result<Bytes, DecodeError>
+where
+    F: FnMut(u8, &mut Bytes) -> Result<bool, DecodeError>,
+{
+    let prop_src = &mut take_properties(src)?;
+    while prop_src.has_remaining() {
+        let unparsed = prop_src.len();
+        let rest = prop_src.clone();
+        let prop_id = prop_src.get_u8();
+        match f(prop_id, prop_src) {
+            Ok(true) => (),
+            // size of unknown property is not known, keep rest of the block as is
+            Ok(false) if preserve => return Ok(rest),
+            Ok(false) => {
+                return Err(DecodeError::MalformedPacket.with_property(prop_id, unparsed))
+            }
+            Err(err) => return Err(err.with_property(prop_id, unparsed)),
+        }
+    }
+    Ok(Bytes::new())
+}
+
 pub(crate) fn decode_variable_length(src: &[u8]) -> Result<Option<(u32, usize)>, DecodeError> {
     let mut cur = Cursor::new(src);
     match decode_variable_length_cursor(&mut cur) {
@@ -305,6 +367,178 @@ pub(crate) fn write_variable_length(len: u32, dst: &mut BytesMut) {
     }
 }
 
+/// Tracks which side initiated connection close
+#[derive(Default)]
+pub(crate) struct CloseState {
+    local: Cell<bool>,
+    peer: Cell<bool>,
+    io_error: RefCell<Option<Rc<io::Error>>>,
+}
+
+impl CloseState {
+    /// Connection is closed by local side
+    pub(crate) fn local(&self) {
+        self.local.set(true)
+    }
+
+    /// Peer sent DISCONNECT packet
+    pub(crate) fn peer(&self) {
+        self.peer.set(true)
+    }
+
+    /// Transport failed with io error
+    ///
+    /// Original error is kept for `Closed` message, returns copy of the error
+    /// for protocol error message. Raw os error code is preserved.
+    pub(crate) fn io_error(&self, err: io::Error) -> io::Error {
+        let copy = if let Some(code) = err.raw_os_error() {
+            io::Error::from_raw_os_error(code)
+        } else {
+            io::Error::new(err.kind(), err.to_string())
+        };
+        self.peer.set(true);
+        *self.io_error.borrow_mut() = Some(Rc::new(err));
+        copy
+    }
+
+    /// Returns true if close is initiated by the peer, and transport error
+    ///
+    /// Connection closed without error and without local close request
+    /// is closed by the peer.
+    pub(crate) fn take(&self, is_error: bool) -> (bool, Option<Rc<io::Error>>) {
+        let peer = self.peer.get() || !(self.local.get() || is_error);
+        (peer, self.io_error.borrow_mut().take())
+    }
+}
+
+/// Pause of inbound packets processing
+#[derive(Default)]
+pub(crate) struct ReadPause {
+    paused: Cell<bool>,
+    waker: LocalWaker,
+}
+
+impl ReadPause {
+    pub(crate) fn pause(&self) {
+        self.paused.set(true)
+    }
+
+    pub(crate) fn resume(&self) {
+        if self.paused.replace(false) {
+            self.waker.wake()
+        }
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused.get()
+    }
+
+    /// Check if dispatcher can process more packets
+    ///
+    /// Closed connection is never paused, so dispatcher could handle
+    /// connection shutdown.
+    pub(crate) fn poll_ready(&self, state: &State, cx: &mut Context<'_>) -> Poll<()> {
+        if self.paused.get() && state.is_open() {
+            self.waker.register(cx.waker());
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+}
+
+/// Queue of application events of the connection
+///
+/// Events are queued until connection's dispatcher handles them, queue
+/// accepts events only while dispatcher is running.
+pub(crate) struct EventQueue<T> {
+    queue: RefCell<Option<VecDeque<T>>>,
+    waker: LocalWaker,
+}
+
+impl<T> Default for EventQueue<T> {
+    fn default() -> Self {
+        EventQueue { queue: RefCell::new(None), waker: LocalWaker::default() }
+    }
+}
+
+impl<T> EventQueue<T> {
+    /// Start accepting events
+    pub(crate) fn open(&self) {
+        *self.queue.borrow_mut() = Some(VecDeque::new());
+    }
+
+    /// Stop accepting events, queued events are dropped
+    pub(crate) fn close(&self) {
+        self.queue.borrow_mut().take();
+    }
+
+    /// Check if queue accepts events
+    pub(crate) fn is_open(&self) -> bool {
+        self.queue.borrow().is_some()
+    }
+
+    /// Queue event, event is dropped if queue is closed
+    pub(crate) fn push(&self, event: T) {
+        if let Some(ref mut queue) = *self.queue.borrow_mut() {
+            queue.push_back(event);
+        }
+        self.waker.wake();
+    }
+
+    /// Get next event, dispatcher task is woken up when event is queued
+    pub(crate) fn poll_next(&self, cx: &mut Context<'_>) -> Option<T> {
+        self.waker.register(cx.waker());
+        self.queue.borrow_mut().as_mut().and_then(|queue| queue.pop_front())
+    }
+}
+
+/// Set of packet ids in use
+///
+/// Sparse bitmap of 64 ids blocks, only blocks with used ids are allocated.
+#[derive(Default)]
+pub(crate) struct PacketIds(HashMap<u16, u64>);
+
+impl PacketIds {
+    pub(crate) fn contains(&self, idx: u16) -> bool {
+        self.0.get(&(idx >> 6)).map_or(false, |bits| bits & (1u64 << (idx & 63)) != 0)
+    }
+
+    pub(crate) fn insert(&mut self, idx: u16) {
+        *self.0.entry(idx >> 6).or_insert(0) |= 1u64 << (idx & 63);
+    }
+
+    pub(crate) fn remove(&mut self, idx: u16) {
+        let block = idx >> 6;
+        if let Some(bits) = self.0.get_mut(&block) {
+            *bits &= !(1u64 << (idx & 63));
+            if *bits == 0 {
+                self.0.remove(&block);
+            }
+        }
+    }
+
+    /// Find first free id after `idx`, search wraps around, id `0` is never used
+    pub(crate) fn next_free(&self, idx: u16) -> Option<u16> {
+        let mut next = idx.wrapping_add(1);
+
+        // start block is checked twice, its lower ids are checked after wrap-around
+        for _ in 0..=1024 {
+            let block = next >> 6;
+            let mut bits = self.0.get(&block).copied().unwrap_or(0);
+            if block == 0 {
+                bits |= 1;
+            }
+            let free = !bits & (u64::max_value() << (next & 63));
+            if free != 0 {
+                return Some((block << 6) | free.trailing_zeros() as u16);
+            }
+            next = if block == 1023 { 0 } else { (block + 1) << 6 };
+        }
+        None
+    }
+}
+
 /// Check service readiness
 pub(crate) fn ready<S>(service: &S) -> Ready<'_, S> {
     Ready(service)
@@ -322,10 +556,165 @@ impl<'a, S: Service> Future for Ready<'a, S> {
     }
 }
 
+/// Peer and local addresses of the io stream
+///
+/// Addresses are available for tcp streams and for tls streams over tcp,
+/// `None` is returned for other transports. For `ProxyStream` peer address
+/// is the source address from PROXY protocol header.
+pub(crate) fn io_addrs<Io: 'static>(io: &Io) -> (Option<SocketAddr>, Option<SocketAddr>) {
+    fn tcp(io: &TcpStream) -> (Option<SocketAddr>, Option<SocketAddr>) {
+        (io.peer_addr().ok(), io.local_addr().ok())
+    }
+
+    // source address from PROXY protocol header takes precedence
+    fn proxy(io: &ProxyStream<TcpStream>) -> (Option<SocketAddr>, Option<SocketAddr>) {
+        let (peer, local) = tcp(io.get_ref());
+        (io.peer_addr().or(peer), local)
+    }
+
+    let io = io as &dyn Any;
+    if let Some(io) = io.downcast_ref::<TcpStream>() {
+        return tcp(io);
+    }
+    if let Some(io) = io.downcast_ref::<ProxyStream<TcpStream>>() {
+        return proxy(io);
+    }
+    #[cfg(feature = "openssl")]
+    {
+        use ntex::server::openssl::SslStream;
+
+        if let Some(io) = io.downcast_ref::<SslStream<TcpStream>>() {
+            return tcp(io.get_ref());
+        }
+        if let Some(io) = io.downcast_ref::<SslStream<ProxyStream<TcpStream>>>() {
+            return proxy(io.get_ref());
+        }
+    }
+    #[cfg(feature = "rustls")]
+    {
+        use ntex::server::rustls::TlsStream;
+
+        if let Some(io) = io.downcast_ref::<TlsStream<TcpStream>>() {
+            return tcp(io.get_ref().0);
+        }
+        if let Some(io) = io.downcast_ref::<TlsStream<ProxyStream<TcpStream>>>() {
+            return proxy(io.get_ref().0);
+        }
+    }
+    (None, None)
+}
+
+/// Peer certificate chain of the tls stream
+///
+/// Certificates are DER encoded, end-entity certificate goes first.
+/// Returns `None` if io is not a tls stream over tcp or if peer
+/// did not provide certificate.
+#[allow(unused_variables)]
+pub(crate) fn io_peer_certificates<Io: 'static>(io: &Io) -> Option<Vec<Bytes>> {
+    #[cfg(feature = "openssl")]
+    {
+        use ntex::server::openssl::SslStream;
+
+        let io = io as &dyn Any;
+        let ssl = io.downcast_ref::<SslStream<TcpStream>>().map(|io| io.ssl()).or_else(|| {
+            io.downcast_ref::<SslStream<ProxyStream<TcpStream>>>().map(|io| io.ssl())
+        });
+        if let Some(ssl) = ssl {
+            let mut certs = vec![Bytes::from(ssl.peer_certificate()?.to_der().ok()?)];
+            // server side chain does not include peer certificate
+            if let Some(chain) = ssl.peer_cert_chain() {
+                for cert in chain {
+                    certs.push(Bytes::from(cert.to_der().ok()?));
+                }
+            }
+            return Some(certs);
+        }
+    }
+    #[cfg(feature = "rustls")]
+    {
+        use ntex::server::rustls::{Session, TlsStream};
+
+        let io = io as &dyn Any;
+        let certs = if let Some(io) = io.downcast_ref::<TlsStream<TcpStream>>() {
+            Some(io.get_ref().1.get_peer_certificates())
+        } else {
+            io.downcast_ref::<TlsStream<ProxyStream<TcpStream>>>()
+                .map(|io| io.get_ref().1.get_peer_certificates())
+        };
+        if let Some(certs) = certs {
+            let certs = certs?;
+            return Some(certs.into_iter().map(|cert| Bytes::from(cert.0)).collect());
+        }
+    }
+    None
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_packet_ids() {
+        let mut ids = PacketIds::default();
+        assert_eq!(ids.next_free(0), Some(1));
+        assert_eq!(ids.next_free(u16::max_value()), Some(1));
+
+        ids.insert(1);
+        ids.insert(2);
+        assert!(ids.contains(2));
+        assert_eq!(ids.next_free(0), Some(3));
+
+        // full blocks are skipped
+        (64..256).for_each(|idx| ids.insert(idx));
+        assert_eq!(ids.next_free(63), Some(256));
+
+        // search wraps around
+        ids.insert(u16::max_value());
+        assert_eq!(ids.next_free(u16::max_value() - 1), Some(3));
+
+        ids.remove(2);
+        assert!(!ids.contains(2));
+        assert_eq!(ids.next_free(u16::max_value() - 1), Some(2));
+
+        // all ids are in use
+        (1..=u16::max_value()).for_each(|idx| ids.insert(idx));
+        assert_eq!(ids.next_free(100), None);
+        ids.remove(100);
+        assert_eq!(ids.next_free(100), Some(100));
+    }
+
+    #[test]
+    fn test_close_state_io_error() {
+        let state = CloseState::default();
+        state.local();
+        assert!(!state.take(false).0);
+
+        #[derive(Debug)]
+        struct TestError;
+        impl std::fmt::Display for TestError {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "test")
+            }
+        }
+        impl std::error::Error for TestError {}
+
+        let copy = state.io_error(io::Error::new(io::ErrorKind::BrokenPipe, TestError));
+        assert_eq!(copy.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(copy.to_string(), "test");
+
+        // original error is kept, io error closes connection from peer side
+        let (peer, err) = state.take(false);
+        assert!(peer);
+        let err = err.unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert!(err.get_ref().unwrap().is::<TestError>());
+        assert!(state.take(false).1.is_none());
+
+        let copy = state.io_error(io::Error::from_raw_os_error(32));
+        assert_eq!(copy.raw_os_error(), Some(32));
+        assert_eq!(state.take(true).1.unwrap().raw_os_error(), Some(32));
+    }
+
     #[test]
     fn test_decode_variable_length() {
         fn assert_variable_length<B: AsRef<[u8]> + 'static>(bytes: B, res: (u32, usize)) {
@@ -0,0 +1,134 @@
+use std::task::{Context, Poll};
+use std::{future::Future, pin::Pin, rc::Rc};
+
+use ntex::service::Service;
+use ntex::util::{join_all, Either};
+
+use crate::acl::{AclResponse, DynAcl};
+
+use super::control::{ControlMessage, ControlResult};
+use super::{publish::Publish, Session};
+
+pub(super) type AclRef<St> = Rc<dyn DynAcl<Session<St>>>;
+
+/// Publish service with topic authorization
+pub(super) struct AclPublish<T, St> {
+    service: Rc<T>,
+    acl: Option<(AclRef<St>, Session<St>)>,
+}
+
+impl<T, St> AclPublish<T, St> {
+    pub(super) fn new(service: T, acl: Option<AclRef<St>>, session: Session<St>) -> Self {
+        AclPublish { service: Rc::new(service), acl: acl.map(|acl| (acl, session)) }
+    }
+}
+
+impl<T, St> Service for AclPublish<T, St>
+where
+    T: Service<Request = Publish, Response = ()>,
+{
+    type Request = Publish;
+    type Response = ();
+    type Error = T::Error;
+    type Future = Either<T::Future, AclResponse<T, bool>>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        self.service.poll_shutdown(cx, is_error)
+    }
+
+    fn call(&self, req: Publish) -> Self::Future {
+        if let Some((ref acl, ref session)) = self.acl {
+            // v3 protocol can not report denied publish, close connection
+            let fut = acl.allow_publish(session, req.topic().get_ref());
+            let sink = session.sink().clone();
+            let fut: Pin<Box<dyn Future<Output = bool>>> = Box::pin(async move {
+                let allowed = fut.await;
+                if !allowed {
+                    sink.force_close();
+                }
+                allowed
+            });
+
+            Either::Right(AclResponse::new(fut, self.service.clone(), req, |req, allowed| {
+                if allowed {
+                    Ok(req)
+                } else {
+                    log::trace!("Publish to {:?} is not authorized", req.topic().get_ref());
+                    Err(())
+                }
+            }))
+        } else {
+            Either::Left(self.service.call(req))
+        }
+    }
+}
+
+/// Control service with subscription authorization
+pub(super) struct AclControl<C, St> {
+    service: Rc<C>,
+    acl: Option<(AclRef<St>, Session<St>)>,
+}
+
+impl<C, St> AclControl<C, St> {
+    pub(super) fn new(service: C, acl: Option<AclRef<St>>, session: Session<St>) -> Self {
+        AclControl { service: Rc::new(service), acl: acl.map(|acl| (acl, session)) }
+    }
+}
+
+impl<C, St> Service for AclControl<C, St>
+where
+    C: Service<Request = ControlMessage, Response = ControlResult>,
+{
+    type Request = ControlMessage;
+    type Response = ControlResult;
+    type Error = C::Error;
+    type Future = Either<C::Future, AclResponse<C, Vec<bool>>>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        self.service.poll_shutdown(cx, is_error)
+    }
+
+    fn call(&self, req: ControlMessage) -> Self::Future {
+        match (req, &self.acl) {
+            (ControlMessage::Subscribe(mut req), Some((acl, session))) => {
+                let checks = req
+                    .iter_mut()
+                    .map(|sub| acl.allow_subscribe(session, sub.topic()))
+                    .collect::<Vec<_>>();
+                let fut = Box::pin(join_all(checks));
+
+                Either::Right(AclResponse::new(
+                    fut,
+                    self.service.clone(),
+                    ControlMessage::Subscribe(req),
+                    |req, allowed| {
+                        if let ControlMessage::Subscribe(mut req) = req {
+                            for (idx, allowed) in allowed.into_iter().enumerate() {
+                                if !allowed {
+                                    log::trace!("Subscription #{} is not authorized", idx);
+                                    req.reject(idx);
+                                }
+                            }
+                            Ok(ControlMessage::Subscribe(req))
+                        } else {
+                            Ok(req)
+                        }
+                    },
+                ))
+            }
+            (req, _) => Either::Left(self.service.call(req)),
+        }
+    }
+}
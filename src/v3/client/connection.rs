@@ -1,15 +1,19 @@
-use std::{future::Future, marker::PhantomData, rc::Rc, time::Duration, time::Instant};
+use std::task::{Context, Poll};
+use std::{cell::RefCell, cmp, future::Future, marker::PhantomData, pin::Pin, rc::Rc};
+use std::{str::FromStr, time::Duration, time::Instant};
 
+use ntex::channel::mpsc;
 use ntex::codec::{AsyncRead, AsyncWrite};
 use ntex::router::{IntoPattern, Router, RouterBuilder};
-use ntex::rt::time::{delay_until, Instant as RtInstant};
+use ntex::rt::time::{delay_for, delay_until, Instant as RtInstant};
 use ntex::service::{apply_fn, boxed::BoxService, into_service, IntoService, Service};
-use ntex::util::{Either, Ready};
+use ntex::util::{ByteString, Either, Ready};
 
-use crate::error::{MqttError, ProtocolError};
-use crate::io::{DispatchItem, Dispatcher, Timer};
+use crate::error::{MqttError, ProtocolError, SendPacketError};
+use crate::io::{CoalescedIo, DispatchItem, Dispatcher, Timer};
 use crate::v3::{shared::MqttShared, sink::MqttSink};
 use crate::v3::{ControlResult, Publish};
+use crate::{topic::Topic, types::QoS};
 
 use super::control::ControlMessage;
 use super::dispatcher::create_dispatcher;
@@ -20,6 +24,8 @@ pub struct Client<Io> {
     shared: Rc<MqttShared>,
     keepalive: u16,
     disconnect_timeout: u16,
+    write_coalesce: Option<(usize, usize, Duration)>,
+    ping_timeout: u16,
     session_present: bool,
     max_receive: usize,
 }
@@ -29,12 +35,15 @@ where
     T: AsyncRead + AsyncWrite + Unpin,
 {
     /// Construct new `Dispatcher` instance with outgoing messages stream.
+    #[allow(clippy::too_many_arguments)]
     pub(super) fn new(
         io: T,
         shared: Rc<MqttShared>,
         session_present: bool,
         keepalive_timeout: u16,
         disconnect_timeout: u16,
+        write_coalesce: Option<(usize, usize, Duration)>,
+        ping_timeout: u16,
         max_receive: usize,
     ) -> Self {
         Client {
@@ -42,6 +51,8 @@ where
             shared,
             session_present,
             disconnect_timeout,
+            write_coalesce,
+            ping_timeout,
             max_receive,
             keepalive: keepalive_timeout,
         }
@@ -72,17 +83,45 @@ where
         U: Service<Request = Publish, Response = ()> + 'static,
         E: From<U::Error>,
     {
-        let mut builder = Router::build();
-        builder.path(address, 0);
-        let handlers = vec![ntex::boxed::service(service.into_service())];
+        let mut router = self.into_router();
+        router.builder.path(address, 0);
+        router.handlers.push(ntex::boxed::service(service.into_service()));
+        router
+    }
+
+    /// Configure handler for a specific topic filter
+    ///
+    /// Client subscribes to the topic filter on start and dispatches
+    /// matching publishes to the handler.
+    ///
+    /// Panics if topic filter is not valid.
+    pub fn route<T, F, U, E>(
+        self,
+        filter: T,
+        qos: QoS,
+        service: F,
+    ) -> ClientRouter<Io, E, U::Error>
+    where
+        ByteString: From<T>,
+        F: IntoService<U>,
+        U: Service<Request = Publish, Response = ()> + 'static,
+        U::Error: 'static,
+        E: From<U::Error> + 'static,
+    {
+        self.into_router().route(filter, qos, service)
+    }
 
+    fn into_router<E, PErr>(self) -> ClientRouter<Io, E, PErr> {
         ClientRouter {
-            builder,
-            handlers,
+            builder: Router::build(),
+            handlers: Vec::new(),
+            routes: Rc::new(RefCell::new(Vec::new())),
             io: self.io,
             shared: self.shared,
             keepalive: self.keepalive,
             disconnect_timeout: self.disconnect_timeout,
+            write_coalesce: self.write_coalesce,
+            ping_timeout: self.ping_timeout,
             max_receive: self.max_receive,
             _t: PhantomData,
         }
@@ -93,7 +132,11 @@ where
     /// Default handler closes connection on any control message.
     pub async fn start_default(self) {
         if self.keepalive > 0 {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(keepalive(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.ping_timeout,
+            ));
         }
 
         let dispatcher = create_dispatcher(
@@ -104,7 +147,7 @@ where
         );
 
         let _ = Dispatcher::with(
-            self.io,
+            CoalescedIo::new(self.io, self.write_coalesce),
             self.shared.state.clone(),
             self.shared.clone(),
             apply_fn(dispatcher, |req: DispatchItem<Rc<MqttShared>>, srv| match req {
@@ -132,6 +175,73 @@ where
         .await;
     }
 
+    /// Turn client into a stream of incoming publishes
+    ///
+    /// Connection is handled by spawned task. Incoming publish is acknowledged
+    /// after it get pushed to the stream, stream terminates when connection
+    /// get closed. Returned sink could be used for sending packets.
+    pub fn into_stream(self) -> (MqttSink, PublishStream) {
+        let (tx, rx) = mpsc::channel();
+        let sink = MqttSink::new(self.shared.clone());
+
+        ntex::rt::spawn(async move {
+            if self.keepalive > 0 {
+                ntex::rt::spawn(keepalive(
+                    MqttSink::new(self.shared.clone()),
+                    self.keepalive,
+                    self.ping_timeout,
+                ));
+            }
+
+            let tx2 = tx.clone();
+            let dispatcher = create_dispatcher(
+                MqttSink::new(self.shared.clone()),
+                self.max_receive,
+                into_service(move |pkt: Publish| {
+                    if tx2.send(pkt).is_err() {
+                        log::trace!("Publish stream is dropped");
+                    }
+                    Ready::Ok(Either::Left(()))
+                }),
+                into_service(|msg: ControlMessage| {
+                    Ready::<_, MqttError<()>>::Ok(msg.disconnect())
+                }),
+            );
+
+            let _ = Dispatcher::with(
+                CoalescedIo::new(self.io, self.write_coalesce),
+                self.shared.state.clone(),
+                self.shared.clone(),
+                apply_fn(dispatcher, |req: DispatchItem<Rc<MqttShared>>, srv| match req {
+                    DispatchItem::Item(req) => Either::Left(srv.call(req)),
+                    DispatchItem::KeepAliveTimeout => Either::Right(Ready::Err(
+                        MqttError::Protocol(ProtocolError::KeepAliveTimeout),
+                    )),
+                    DispatchItem::EncoderError(e) => {
+                        Either::Right(Ready::Err(MqttError::Protocol(ProtocolError::Encode(e))))
+                    }
+                    DispatchItem::DecoderError(e) => {
+                        Either::Right(Ready::Err(MqttError::Protocol(ProtocolError::Decode(e))))
+                    }
+                    DispatchItem::IoError(e) => {
+                        Either::Right(Ready::Err(MqttError::Protocol(ProtocolError::Io(e))))
+                    }
+                    DispatchItem::WBackPressureEnabled
+                    | DispatchItem::WBackPressureDisabled => Either::Right(Ready::Ok(None)),
+                }),
+                Timer::with(Duration::from_secs(1)),
+            )
+            .keepalive_timeout(0)
+            .disconnect_timeout(self.disconnect_timeout)
+            .await;
+
+            // connection is closed, terminate stream
+            tx.close();
+        });
+
+        (sink, PublishStream(rx))
+    }
+
     /// Run client with provided control messages handler
     pub async fn start<F, S, E>(self, service: F) -> Result<(), MqttError<E>>
     where
@@ -140,7 +250,11 @@ where
         S: Service<Request = ControlMessage, Response = ControlResult, Error = E> + 'static,
     {
         if self.keepalive > 0 {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(keepalive(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.ping_timeout,
+            ));
         }
 
         let dispatcher = create_dispatcher(
@@ -151,7 +265,7 @@ where
         );
 
         Dispatcher::with(
-            self.io,
+            CoalescedIo::new(self.io, self.write_coalesce),
             self.shared.state.clone(),
             self.shared.clone(),
             apply_fn(dispatcher, |req: DispatchItem<Rc<MqttShared>>, srv| match req {
@@ -180,16 +294,37 @@ where
     }
 }
 
+/// Stream of incoming publishes
+pub struct PublishStream(mpsc::Receiver<Publish>);
+
+impl ntex::Stream for PublishStream {
+    type Item = Publish;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Publish>> {
+        Pin::new(&mut self.get_mut().0).poll_next(cx)
+    }
+}
+
 type Handler<E> = BoxService<Publish, (), E>;
 
+struct Route<E> {
+    filter: ByteString,
+    topic: Topic,
+    qos: QoS,
+    handler: Rc<Handler<E>>,
+}
+
 /// Mqtt client with routing capabilities
 pub struct ClientRouter<Io, Err, PErr> {
     builder: RouterBuilder<usize>,
     handlers: Vec<Handler<PErr>>,
+    routes: Rc<RefCell<Vec<Route<PErr>>>>,
     io: Io,
     shared: Rc<MqttShared>,
     keepalive: u16,
     disconnect_timeout: u16,
+    write_coalesce: Option<(usize, usize, Duration)>,
+    ping_timeout: u16,
     max_receive: usize,
     _t: PhantomData<Err>,
 }
@@ -212,23 +347,59 @@ where
         self
     }
 
+    /// Configure handler for a specific topic filter
+    ///
+    /// Client subscribes to the topic filter on start and dispatches
+    /// matching publishes to the handler.
+    ///
+    /// Panics if topic filter is not valid.
+    pub fn route<T, F, S>(self, filter: T, qos: QoS, service: F) -> Self
+    where
+        ByteString: From<T>,
+        F: IntoService<S>,
+        S: Service<Request = Publish, Response = (), Error = PErr> + 'static,
+    {
+        let filter = ByteString::from(filter);
+        let topic = match Topic::from_str(&filter) {
+            Ok(topic) if topic.is_valid() => topic,
+            _ => panic!("Invalid topic filter: {:?}", filter),
+        };
+        self.routes.borrow_mut().push(Route {
+            filter,
+            topic,
+            qos,
+            handler: Rc::new(ntex::boxed::service(service.into_service())),
+        });
+        self
+    }
+
+    /// Get handle for configured topic filter handlers
+    pub fn routes(&self) -> Routes<PErr> {
+        Routes { routes: self.routes.clone(), sink: MqttSink::new(self.shared.clone()) }
+    }
+
     /// Run client with default control messages handler
     pub async fn start_default(self) {
         if self.keepalive > 0 {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(keepalive(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.ping_timeout,
+            ));
         }
+        self.subscribe();
 
         let dispatcher = create_dispatcher(
             MqttSink::new(self.shared.clone()),
             self.max_receive,
-            dispatch(self.builder.finish(), self.handlers),
+            dispatch(self.builder.finish(), self.handlers, self.routes),
             into_service(|msg: ControlMessage| {
                 Ready::<_, MqttError<Err>>::Ok(msg.disconnect())
             }),
         );
 
         let _ = Dispatcher::with(
-            self.io,
+            CoalescedIo::new(self.io, self.write_coalesce),
             self.shared.state.clone(),
             self.shared.clone(),
             apply_fn(dispatcher, |req: DispatchItem<Rc<MqttShared>>, srv| match req {
@@ -263,18 +434,23 @@ where
         S: Service<Request = ControlMessage, Response = ControlResult, Error = Err> + 'static,
     {
         if self.keepalive > 0 {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(keepalive(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.ping_timeout,
+            ));
         }
+        self.subscribe();
 
         let dispatcher = create_dispatcher(
             MqttSink::new(self.shared.clone()),
             self.max_receive,
-            dispatch(self.builder.finish(), self.handlers),
+            dispatch(self.builder.finish(), self.handlers, self.routes),
             service.into_service().map_err(MqttError::Service),
         );
 
         Dispatcher::with(
-            self.io,
+            CoalescedIo::new(self.io, self.write_coalesce),
             self.shared.state.clone(),
             self.shared.clone(),
             apply_fn(dispatcher, |req: DispatchItem<Rc<MqttShared>>, srv| match req {
@@ -301,11 +477,61 @@ where
         .disconnect_timeout(self.disconnect_timeout)
         .await
     }
+
+    /// Subscribe to configured topic filters
+    fn subscribe(&self) {
+        let routes = self.routes.borrow();
+        if routes.is_empty() {
+            return;
+        }
+
+        let mut builder = MqttSink::new(self.shared.clone()).subscribe();
+        for route in routes.iter() {
+            builder = builder.topic_filter(route.filter.clone(), route.qos);
+        }
+        ntex::rt::spawn(async move {
+            match builder.send().await {
+                Ok(codes) => log::trace!("Topic filters are subscribed: {:?}", codes),
+                Err(err) => log::error!("Cannot subscribe topic filters: {}", err),
+            }
+        });
+    }
+}
+
+/// Handle for client topic filter handlers
+pub struct Routes<E> {
+    routes: Rc<RefCell<Vec<Route<E>>>>,
+    sink: MqttSink,
+}
+
+impl<E> Clone for Routes<E> {
+    fn clone(&self) -> Self {
+        Routes { routes: self.routes.clone(), sink: self.sink.clone() }
+    }
+}
+
+impl<E> Routes<E> {
+    /// Remove handler for topic filter and unsubscribe from the topic filter
+    pub async fn remove(&self, filter: &str) -> Result<(), SendPacketError> {
+        let removed = {
+            let mut routes = self.routes.borrow_mut();
+            let len = routes.len();
+            routes.retain(|route| route.filter != filter);
+            len != routes.len()
+        };
+
+        if removed {
+            self.sink.unsubscribe().topic_filter(ByteString::from(filter)).send().await
+        } else {
+            Ok(())
+        }
+    }
 }
 
 fn dispatch<Err, PErr>(
     router: Router<usize>,
     handlers: Vec<Handler<PErr>>,
+    routes: Rc<RefCell<Vec<Route<PErr>>>>,
 ) -> impl Service<Request = Publish, Response = Either<(), Publish>, Error = MqttError<Err>>
 where
     PErr: 'static,
@@ -315,7 +541,20 @@ where
         if let Some((idx, _info)) = router.recognize(req.topic_mut()) {
             // exec handler
             let fut = call(req, &handlers[*idx]);
-            Either::Left(async move { fut.await.map_err(MqttError::Service) })
+            return Either::Left(Either::Left(
+                async move { fut.await.map_err(MqttError::Service) },
+            ));
+        }
+
+        // topic filter handlers
+        let handler = routes
+            .borrow()
+            .iter()
+            .find(|route| route.topic.matches_str(req.publish_topic()))
+            .map(|route| route.handler.clone());
+        if let Some(handler) = handler {
+            let fut = call(req, &*handler);
+            Either::Left(Either::Right(async move { fut.await.map_err(MqttError::Service) }))
         } else {
             Either::Right(Ready::<_, MqttError<Err>>::Ok(Either::Right(req)))
         }
@@ -340,18 +579,30 @@ where
     }
 }
 
-async fn keepalive(sink: MqttSink, timeout: u16) {
+async fn keepalive(sink: MqttSink, timeout: u16, ping_timeout: u16) {
     log::debug!("start mqtt client keep-alive task");
 
     let keepalive = Duration::from_secs(timeout as u64);
+    let ping_timeout = cmp::min(Duration::from_millis(ping_timeout as u64), keepalive);
+    let mut expire = RtInstant::from_std(Instant::now() + keepalive);
     loop {
-        let expire = RtInstant::from_std(Instant::now() + keepalive);
         delay_until(expire).await;
+        expire = RtInstant::from_std(Instant::now() + keepalive);
 
         if !sink.ping() {
             // connection is closed
             log::debug!("mqtt client connection is closed, stopping keep-alive task");
             break;
         }
+
+        // wait for ping response
+        if ping_timeout > Duration::from_millis(0) {
+            delay_for(ping_timeout).await;
+            if sink.is_ping_pending() {
+                log::error!("mqtt ping response timeout, closing connection");
+                sink.close();
+                break;
+            }
+        }
     }
 }
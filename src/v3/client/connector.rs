@@ -7,25 +7,38 @@ use ntex::service::Service;
 use ntex::util::{select, ByteString, Bytes, Either};
 
 #[cfg(feature = "openssl")]
-use ntex::connect::openssl::{OpensslConnector, SslConnector};
+use ntex::connect::openssl::{OpensslConnector, SslConnector, SslMethod};
 
 #[cfg(feature = "rustls")]
 use ntex::connect::rustls::{ClientConfig, RustlsConnector};
 
+use super::managed::ManagedClient;
 use super::{codec, connection::Client, error::ClientError, error::ProtocolError};
-use crate::io::State;
+use crate::inspect::PacketInspector;
+use crate::metrics::{HandshakeGuard, MqttMetrics};
+use crate::proxy::{Proxy, ProxyConnector};
+use crate::trace::Span;
+use crate::types::PoolConfig;
+#[cfg(unix)]
+use crate::uds::UnixConnector;
 use crate::v3::shared::{MqttShared, MqttSinkPool};
+use crate::ws::WsConnector;
 
 /// Mqtt client connector
 pub struct MqttConnector<A, T> {
     address: A,
     connector: T,
     pkt: codec::Connect,
+    conformance: codec::Conformance,
     max_send: usize,
     max_receive: usize,
     max_packet_size: u32,
     handshake_timeout: u16,
     disconnect_timeout: u16,
+    write_coalesce: Option<(usize, usize, Duration)>,
+    ping_timeout: u16,
+    connect_timeout: u16,
+    ack_timeout: Option<Duration>,
     pool: Rc<MqttSinkPool>,
 }
 
@@ -39,12 +52,17 @@ where
         MqttConnector {
             address,
             pkt: codec::Connect::default(),
+            conformance: codec::Conformance::Strict,
             connector: Connector::default(),
             max_send: 16,
             max_receive: 16,
             max_packet_size: 64 * 1024,
             handshake_timeout: 0,
             disconnect_timeout: 3000,
+            write_coalesce: None,
+            ping_timeout: 5000,
+            connect_timeout: 0,
+            ack_timeout: None,
             pool: Rc::new(MqttSinkPool::default()),
         }
     }
@@ -137,6 +155,16 @@ where
         self
     }
 
+    /// Set codec conformance mode.
+    ///
+    /// In lenient mode client tolerates common deviations from
+    /// the protocol specification.
+    /// By default `Conformance::Strict` mode is used.
+    pub fn conformance(mut self, val: codec::Conformance) -> Self {
+        self.conformance = val;
+        self
+    }
+
     #[inline]
     /// Update connect packet
     pub fn packet<F>(mut self, f: F) -> Self
@@ -169,6 +197,77 @@ where
         self
     }
 
+    /// Set connection metrics
+    ///
+    /// By default metrics are not collected.
+    pub fn metrics<M: MqttMetrics + 'static>(mut self, metrics: M) -> Self {
+        self.pool = self.pool.with_metrics(Rc::new(metrics));
+        self
+    }
+
+    /// Set sink pool and buffer configuration
+    pub fn pool_config(mut self, config: PoolConfig) -> Self {
+        self.pool = self.pool.with_config(config);
+        self
+    }
+
+    /// Set packet inspector
+    ///
+    /// Inspector sees every packet received from and sent to the server,
+    /// including handshake packets. By default packets are not inspected.
+    pub fn inspector<I>(mut self, inspector: I) -> Self
+    where
+        I: PacketInspector<codec::Packet> + 'static,
+    {
+        self.pool = self.pool.with_inspector(Rc::new(inspector));
+        self
+    }
+
+    /// Set write coalescing buffer size in bytes, number of packets and delay
+    /// in microseconds
+    ///
+    /// Writing to connection is delayed for up to `delay` microseconds, packets
+    /// encoded in the meantime are sent with single write call. Buffered data
+    /// is written without delay if it is larger than `size` bytes or if it
+    /// contains at least `packets` packets, `0` disables packets limit. Timer
+    /// resolution is one millisecond. By default writes are not delayed.
+    pub fn write_coalesce(mut self, size: usize, packets: usize, delay: u32) -> Self {
+        self.write_coalesce = Some((size, packets, Duration::from_micros(delay as u64)));
+        self
+    }
+
+    /// Set ping response timeout in milliseconds.
+    ///
+    /// Client sends PINGREQ packet every keep-alive interval. If PINGRESP packet
+    /// is not received within this time, the connection get closed.
+    ///
+    /// To disable timeout set value to 0.
+    ///
+    /// By default ping response timeout is set to 5 seconds.
+    pub fn ping_timeout(mut self, timeout: u16) -> Self {
+        self.ping_timeout = timeout;
+        self
+    }
+
+    /// Set connect timeout in milliseconds.
+    ///
+    /// Defines a timeout for establishing network connection to the server,
+    /// before mqtt handshake. Connect fails with `TimedOut` io error.
+    ///
+    /// By default connect timeout is disabled.
+    pub fn connect_timeout(mut self, timeout: u16) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Set default acknowledgement timeout for sinks of new connections.
+    ///
+    /// See `MqttSink::set_ack_timeout()`. By default ack timeout is not set.
+    pub fn ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = Some(timeout);
+        self
+    }
+
     /// Use custom connector
     pub fn connector<U>(self, connector: U) -> MqttConnector<A, U>
     where
@@ -178,12 +277,88 @@ where
         MqttConnector {
             connector,
             pkt: self.pkt,
+            conformance: self.conformance,
+            address: self.address,
+            max_send: self.max_send,
+            max_receive: self.max_receive,
+            max_packet_size: self.max_packet_size,
+            handshake_timeout: self.handshake_timeout,
+            disconnect_timeout: self.disconnect_timeout,
+            write_coalesce: self.write_coalesce,
+            ping_timeout: self.ping_timeout,
+            connect_timeout: self.connect_timeout,
+            ack_timeout: self.ack_timeout,
+            pool: self.pool,
+        }
+    }
+
+    /// Connect to mqtt server through SOCKS5 or HTTP proxy
+    ///
+    /// Proxy tunnel is established before mqtt handshake.
+    pub fn proxy(self, proxy: Proxy) -> MqttConnector<A, ProxyConnector<A>> {
+        MqttConnector {
+            pkt: self.pkt,
+            conformance: self.conformance,
+            address: self.address,
+            max_send: self.max_send,
+            max_receive: self.max_receive,
+            max_packet_size: self.max_packet_size,
+            connector: ProxyConnector::new(proxy),
+            handshake_timeout: self.handshake_timeout,
+            disconnect_timeout: self.disconnect_timeout,
+            write_coalesce: self.write_coalesce,
+            ping_timeout: self.ping_timeout,
+            connect_timeout: self.connect_timeout,
+            ack_timeout: self.ack_timeout,
+            pool: self.pool,
+        }
+    }
+
+    #[cfg(unix)]
+    /// Use unix domain socket connector
+    ///
+    /// Host of the connector address is used as a socket path.
+    pub fn unix(self) -> MqttConnector<A, UnixConnector<A>> {
+        MqttConnector {
+            pkt: self.pkt,
+            conformance: self.conformance,
             address: self.address,
             max_send: self.max_send,
             max_receive: self.max_receive,
             max_packet_size: self.max_packet_size,
+            connector: UnixConnector::new(),
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            write_coalesce: self.write_coalesce,
+            ping_timeout: self.ping_timeout,
+            connect_timeout: self.connect_timeout,
+            ack_timeout: self.ack_timeout,
+            pool: self.pool,
+        }
+    }
+
+    /// Use mqtt over WebSocket transport
+    ///
+    /// WebSocket handshake is performed over connection opened by current
+    /// connector, for example `.rustls(config).websocket("/mqtt")`.
+    pub fn websocket<U>(self, path: U) -> MqttConnector<A, WsConnector<T>>
+    where
+        ByteString: From<U>,
+    {
+        MqttConnector {
+            pkt: self.pkt,
+            conformance: self.conformance,
+            address: self.address,
+            max_send: self.max_send,
+            max_receive: self.max_receive,
+            max_packet_size: self.max_packet_size,
+            connector: WsConnector::new(self.connector).path(path),
+            handshake_timeout: self.handshake_timeout,
+            disconnect_timeout: self.disconnect_timeout,
+            write_coalesce: self.write_coalesce,
+            ping_timeout: self.ping_timeout,
+            connect_timeout: self.connect_timeout,
+            ack_timeout: self.ack_timeout,
             pool: self.pool,
         }
     }
@@ -193,6 +368,7 @@ where
     pub fn openssl(self, connector: SslConnector) -> MqttConnector<A, OpensslConnector<A>> {
         MqttConnector {
             pkt: self.pkt,
+            conformance: self.conformance,
             address: self.address,
             max_send: self.max_send,
             max_receive: self.max_receive,
@@ -200,17 +376,44 @@ where
             connector: OpensslConnector::new(connector),
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            write_coalesce: self.write_coalesce,
+            ping_timeout: self.ping_timeout,
+            connect_timeout: self.connect_timeout,
+            ack_timeout: self.ack_timeout,
             pool: self.pool,
         }
     }
 
+    #[cfg(feature = "openssl")]
+    /// Use openssl connector with default configuration
+    ///
+    /// Connector verifies server certificate with system CA certificates
+    /// and uses "mqtt" ALPN protocol.
+    pub fn openssl_default(self) -> std::io::Result<MqttConnector<A, OpensslConnector<A>>> {
+        use std::io;
+
+        let mut builder = SslConnector::builder(SslMethod::tls())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        builder
+            .set_alpn_protos(b"\x04mqtt")
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Ok(self.openssl(builder.build()))
+    }
+
     #[cfg(feature = "rustls")]
     /// Use rustls connector
-    pub fn rustls(self, config: ClientConfig) -> MqttConnector<A, RustlsConnector<A>> {
+    ///
+    /// ALPN protocol is set to "mqtt" if config does not define any protocols.
+    pub fn rustls(self, mut config: ClientConfig) -> MqttConnector<A, RustlsConnector<A>> {
         use std::sync::Arc;
 
+        if config.alpn_protocols.is_empty() {
+            config.alpn_protocols = vec![b"mqtt".to_vec()];
+        }
+
         MqttConnector {
             pkt: self.pkt,
+            conformance: self.conformance,
             address: self.address,
             max_send: self.max_send,
             max_receive: self.max_receive,
@@ -218,10 +421,21 @@ where
             connector: RustlsConnector::new(Arc::new(config)),
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            write_coalesce: self.write_coalesce,
+            ping_timeout: self.ping_timeout,
+            connect_timeout: self.connect_timeout,
+            ack_timeout: self.ack_timeout,
             pool: self.pool,
         }
     }
 
+    /// Convert connector to managed client
+    ///
+    /// Managed client re-connects to the server if connection get closed.
+    pub fn into_managed(self) -> ManagedClient<A, T> {
+        ManagedClient::new(self)
+    }
+
     /// Connect to mqtt server
     pub fn connect(&self) -> impl Future<Output = Result<Client<T::Response>, ClientError>> {
         if self.handshake_timeout > 0 {
@@ -244,22 +458,47 @@ where
     fn _connect(&self) -> impl Future<Output = Result<Client<T::Response>, ClientError>> {
         let fut = self.connector.call(Connect::new(self.address.clone()));
         let pkt = self.pkt.clone();
+        let conformance = self.conformance;
         let max_send = self.max_send;
         let max_receive = self.max_receive;
         let max_packet_size = self.max_packet_size;
         let keepalive_timeout = pkt.keep_alive;
         let disconnect_timeout = self.disconnect_timeout;
+        let write_coalesce = self.write_coalesce;
+        let ping_timeout = self.ping_timeout;
+        let connect_timeout = self.connect_timeout;
+        let ack_timeout = self.ack_timeout;
         let pool = self.pool.clone();
 
         async move {
-            let mut io = fut.await?;
-            let state = State::new();
-            let codec = codec::Codec::new().max_size(max_packet_size);
+            let mut io = if connect_timeout > 0 {
+                let timeout = delay_for(Duration::from_millis(connect_timeout as u64));
+                match select(timeout, fut).await {
+                    Either::Left(_) => {
+                        return Err(ClientError::Connect(connect::ConnectError::Io(
+                            std::io::Error::new(
+                                std::io::ErrorKind::TimedOut,
+                                "Connect timeout",
+                            ),
+                        )))
+                    }
+                    Either::Right(res) => res?,
+                }
+            } else {
+                fut.await?
+            };
+            let state = pool.state();
+            let codec = codec::Codec::new().max_size(max_packet_size).conformance(conformance);
+            let metrics = HandshakeGuard::new(pool.metrics.clone());
+            let shared = Rc::new(MqttShared::new(state.clone(), codec, max_send, pool));
+            shared.ack_timeout.set(ack_timeout);
+            *shared.span.borrow_mut() =
+                Span::connection(&pkt.client_id, crate::utils::io_addrs(&io).0, "3.1.1");
 
-            state.send(&mut io, &codec, codec::Packet::Connect(pkt)).await?;
+            state.send(&mut io, &*shared, codec::Packet::Connect(pkt)).await?;
 
             let packet = state
-                .next(&mut io, &codec)
+                .next(&mut io, &*shared)
                 .await
                 .map_err(|e| ClientError::from(ProtocolError::from(e)))
                 .and_then(|res| {
@@ -268,18 +507,20 @@ where
                         ClientError::Disconnected
                     })
                 })?;
-            let shared = Rc::new(MqttShared::new(state.clone(), codec, max_send, pool));
 
             match packet {
                 codec::Packet::ConnectAck { session_present, return_code } => {
                     log::trace!("Connect ack response from server: session: present: {:?}, return code: {:?}", session_present, return_code);
                     if return_code == codec::ConnectAckReason::ConnectionAccepted {
+                        metrics.complete();
                         Ok(Client::new(
                             io,
                             shared,
                             session_present,
                             keepalive_timeout,
                             disconnect_timeout,
+                            write_coalesce,
+                            ping_timeout,
                             max_receive,
                         ))
                     } else {
@@ -1,3 +1,4 @@
+use crate::utils::CloseState;
 pub use crate::v3::control::{Closed, ControlResult, Disconnect};
 use crate::v3::{codec, control::ControlResultKind};
 
@@ -19,8 +20,8 @@ impl ControlMessage {
         ControlMessage::Disconnect(Disconnect)
     }
 
-    pub(super) fn closed(is_error: bool) -> Self {
-        ControlMessage::Closed(Closed::new(is_error))
+    pub(super) fn closed(is_error: bool, state: &CloseState) -> Self {
+        ControlMessage::Closed(Closed::new(is_error, state))
     }
 
     pub fn disconnect(&self) -> ControlResult {
@@ -6,8 +6,10 @@ use ntex::service::Service;
 use ntex::util::{inflight::InFlightService, Either, HashSet, Ready};
 
 use crate::v3::shared::Ack;
-use crate::v3::{codec, control::ControlResultKind, publish::Publish, sink::MqttSink};
-use crate::{error::MqttError, error::ProtocolError, types::packet_type};
+use crate::v3::{
+    codec, codec::QoS, control::ControlResultKind, publish::Publish, sink::MqttSink,
+};
+use crate::{error::MqttError, error::ProtocolError, trace::Traced, types::packet_type};
 
 use super::control::{ControlMessage, ControlResult};
 
@@ -28,8 +30,13 @@ where
     C: Service<Request = ControlMessage, Response = ControlResult, Error = MqttError<E>>
         + 'static,
 {
+    let span = sink.shared().span.borrow().clone();
+
     // limit number of in-flight messages
-    InFlightService::new(inflight, Dispatcher::<_, _, E>::new(sink, publish, control))
+    InFlightService::new(
+        inflight,
+        Traced::new(Dispatcher::<_, _, E>::new(sink, publish, control), span),
+    )
 }
 
 /// Mqtt protocol dispatcher
@@ -44,6 +51,7 @@ struct Inner<C> {
     control: C,
     sink: MqttSink,
     inflight: RefCell<HashSet<NonZeroU16>>,
+    released: RefCell<HashSet<NonZeroU16>>,
 }
 
 impl<T, C, E> Dispatcher<T, C, E>
@@ -60,7 +68,12 @@ where
             publish,
             sink: sink.clone(),
             shutdown: Cell::new(false),
-            inner: Rc::new(Inner { sink, control, inflight: RefCell::new(HashSet::default()) }),
+            inner: Rc::new(Inner {
+                sink,
+                control,
+                inflight: RefCell::new(HashSet::default()),
+                released: RefCell::new(HashSet::default()),
+            }),
         }
     }
 }
@@ -88,7 +101,11 @@ where
         let res1 = self.publish.poll_ready(cx)?;
         let res2 = self.inner.control.poll_ready(cx)?;
 
-        if res1.is_pending() || res2.is_pending() {
+        // paused by application
+        let shared = self.inner.sink.shared();
+        let res3 = shared.read_pause.poll_ready(&shared.state, cx);
+
+        if res1.is_pending() || res2.is_pending() || res3.is_pending() {
             Poll::Pending
         } else {
             Poll::Ready(Ok(()))
@@ -97,9 +114,11 @@ where
 
     fn poll_shutdown(&self, _: &mut Context<'_>, is_error: bool) -> Poll<()> {
         if !self.shutdown.get() {
+            let closed = ControlMessage::closed(is_error, &self.inner.sink.shared().close);
             self.inner.sink.close();
             self.shutdown.set(true);
-            let fut = self.inner.control.call(ControlMessage::closed(is_error));
+            self.inner.sink.shared().pool.metrics.connection_closed();
+            let fut = self.inner.control.call(closed);
             ntex::rt::spawn(async move {
                 let _ = fut.await;
             });
@@ -113,9 +132,17 @@ where
             codec::Packet::Publish(publish) => {
                 let inner = self.inner.clone();
                 let packet_id = publish.packet_id;
+                let qos = publish.qos;
 
                 // check for duplicated packet id
                 if let Some(pid) = packet_id {
+                    // re-delivery of qos2 publish, message is already delivered
+                    if qos == QoS::ExactlyOnce && inner.released.borrow().contains(&pid) {
+                        log::trace!("Re-delivered publish packet with qos2: {:?}", pid);
+                        return Either::Right(Either::Left(Ready::Ok(Some(
+                            codec::Packet::PublishReceived { packet_id: pid },
+                        ))));
+                    }
                     if !inner.inflight.borrow_mut().insert(pid) {
                         log::trace!("Duplicated packet id for publish packet: {:?}", pid);
                         return Either::Right(Either::Left(Ready::Err(
@@ -124,6 +151,7 @@ where
                     }
                 }
                 Either::Left(PublishResponse {
+                    qos,
                     packet_id,
                     inner,
                     fut: self.publish.call(Publish::new(publish)),
@@ -138,13 +166,46 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
+            codec::Packet::PublishReceived { packet_id } => {
+                if let Err(e) = self.sink.pkt_ack(Ack::Receive(packet_id)) {
+                    Either::Right(Either::Left(Ready::Err(MqttError::Protocol(e))))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            codec::Packet::PublishComplete { packet_id } => {
+                if let Err(e) = self.sink.pkt_ack(Ack::Complete(packet_id)) {
+                    Either::Right(Either::Left(Ready::Err(MqttError::Protocol(e))))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            codec::Packet::PublishRelease { packet_id } => {
+                // unknown packet id, publish is already released
+                if !self.inner.released.borrow_mut().remove(&packet_id) {
+                    log::trace!(
+                        "Unknown packet id for publish release packet: {:?}",
+                        packet_id
+                    );
+                }
+                Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PublishComplete {
+                    packet_id,
+                }))))
+            }
             codec::Packet::PingRequest => {
                 Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PingResponse))))
             }
-            codec::Packet::Disconnect => Either::Right(Either::Right(ControlResponse::new(
-                self.inner.control.call(ControlMessage::dis()),
-                &self.inner,
-            ))),
+            codec::Packet::PingResponse => {
+                self.sink.pkt_ping_response();
+                Either::Right(Either::Left(Ready::Ok(None)))
+            }
+            codec::Packet::Disconnect => {
+                self.inner.sink.shared().close.peer();
+                Either::Right(Either::Right(ControlResponse::new(
+                    self.inner.control.call(ControlMessage::dis()),
+                    &self.inner,
+                )))
+            }
             codec::Packet::SubscribeAck { packet_id, status } => {
                 if let Err(e) = self.sink.pkt_ack(Ack::Subscribe { packet_id, status }) {
                     Either::Right(Either::Left(Ready::Err(MqttError::Protocol(e))))
@@ -185,6 +246,7 @@ pin_project_lite::pin_project! {
         fut: T::Future,
         #[pin]
         fut_c: Option<ControlResponse<C, E>>,
+        qos: QoS,
         packet_id: Option<NonZeroU16>,
         inner: Rc<Inner<C>>,
         _t: PhantomData<E>,
@@ -203,8 +265,18 @@ where
     type Output = Result<Option<codec::Packet>, MqttError<E>>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if let Some(fut) = self.as_mut().project().fut_c.as_pin_mut() {
-            return fut.poll(cx);
+        let this = self.as_mut().project();
+        if let Some(fut) = this.fut_c.as_pin_mut() {
+            return match fut.poll(cx)? {
+                // publish is acknowledged by control service
+                Poll::Ready(Some(codec::Packet::PublishAck { packet_id }))
+                    if *this.qos == QoS::ExactlyOnce =>
+                {
+                    this.inner.released.borrow_mut().insert(packet_id);
+                    Poll::Ready(Ok(Some(codec::Packet::PublishReceived { packet_id })))
+                }
+                res => res.map(Ok),
+            };
         }
 
         let mut this = self.as_mut().project();
@@ -218,7 +290,16 @@ where
 
                 if let Some(packet_id) = this.packet_id {
                     this.inner.inflight.borrow_mut().remove(&packet_id);
-                    Poll::Ready(Ok(Some(codec::Packet::PublishAck { packet_id: *packet_id })))
+                    if *this.qos == QoS::ExactlyOnce {
+                        this.inner.released.borrow_mut().insert(*packet_id);
+                        Poll::Ready(Ok(Some(codec::Packet::PublishReceived {
+                            packet_id: *packet_id,
+                        })))
+                    } else {
+                        Poll::Ready(Ok(Some(codec::Packet::PublishAck {
+                            packet_id: *packet_id,
+                        })))
+                    }
                 } else {
                     Poll::Ready(Ok(None))
                 }
@@ -269,6 +350,7 @@ where
                     this.inner.inflight.borrow_mut().remove(&id);
                     Some(codec::Packet::PublishAck { packet_id: id })
                 }
+                ControlResultKind::PublishRelease(_) => unreachable!(),
                 ControlResultKind::Subscribe(_) => unreachable!(),
                 ControlResultKind::Unsubscribe(_) => unreachable!(),
                 ControlResultKind::Disconnect => {
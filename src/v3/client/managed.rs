@@ -0,0 +1,345 @@
+use std::cell::{Cell, RefCell};
+use std::{rc::Rc, time::Duration};
+
+use futures_channel::oneshot;
+use ntex::channel::condition::Condition;
+use ntex::codec::{AsyncRead, AsyncWrite};
+use ntex::connect::{self, Address, Connect};
+use ntex::rt::time::delay_for;
+use ntex::service::{IntoService, Service};
+use ntex::util::{ByteString, Bytes};
+
+use super::error::ClientError;
+use super::{codec, connector::MqttConnector, control::ControlMessage, ControlResult};
+use crate::backoff::{BackoffStrategy, ExponentialBackoff};
+use crate::offline::{OfflineBuffer, OfflinePolicy};
+use crate::v3::sink::MqttSink;
+use crate::{error::SendPacketError, types::QoS};
+
+/// Managed mqtt client
+///
+/// Managed client re-connects to the server if connection get closed
+/// and replays subscriptions for registered topic filters.
+///
+/// In-flight state is not persisted, unacknowledged publishes are lost after
+/// process restart. Persistent `ClientStore` is supported by v5 managed client only.
+pub struct ManagedClient<A, T> {
+    connector: MqttConnector<A, T>,
+    inner: Rc<Inner>,
+    backoff: Box<dyn BackoffStrategy>,
+    abort: Option<Box<dyn Fn(&ClientError) -> bool>>,
+}
+
+struct Inner {
+    sink: RefCell<Option<MqttSink>>,
+    filters: RefCell<Vec<(ByteString, QoS)>>,
+    offline: RefCell<OfflineBuffer<Buffered>>,
+    connected: Condition,
+    closed: Cell<bool>,
+}
+
+/// Publish buffered while client is disconnected
+struct Buffered {
+    topic: ByteString,
+    payload: Bytes,
+    qos: QoS,
+    tx: Option<oneshot::Sender<Result<(), SendPacketError>>>,
+}
+
+impl<A, T> ManagedClient<A, T>
+where
+    A: Address + Clone,
+    T: Service<Request = Connect<A>, Error = connect::ConnectError>,
+    T::Response: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    pub(super) fn new(connector: MqttConnector<A, T>) -> Self {
+        ManagedClient {
+            connector,
+            inner: Rc::new(Inner {
+                sink: RefCell::new(None),
+                filters: RefCell::new(Vec::new()),
+                offline: RefCell::new(OfflineBuffer::new(0, OfflinePolicy::Reject)),
+                connected: Condition::new(),
+                closed: Cell::new(false),
+            }),
+            backoff: Box::new(ExponentialBackoff::default()),
+            abort: None,
+        }
+    }
+
+    /// Set re-connect backoff
+    ///
+    /// Delay between re-connect attempts starts with `min` value and doubles
+    /// after each failed attempt until it reaches `max` value.
+    /// By default min delay is 1 second and max delay is 30 seconds.
+    pub fn backoff(self, min: Duration, max: Duration) -> Self {
+        self.backoff_strategy(ExponentialBackoff::new(min, max))
+    }
+
+    /// Set re-connect backoff strategy
+    pub fn backoff_strategy<B>(mut self, strategy: B) -> Self
+    where
+        B: BackoffStrategy + 'static,
+    {
+        self.backoff = Box::new(strategy);
+        self
+    }
+
+    /// Set connect error hook
+    ///
+    /// If hook returns true, client stops re-connecting permanently,
+    /// for example on authentication failures.
+    pub fn abort_if<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&ClientError) -> bool + 'static,
+    {
+        self.abort = Some(Box::new(f));
+        self
+    }
+
+    /// Set offline buffer
+    ///
+    /// Publishes are buffered while client is disconnected and get sent in
+    /// order after client re-connects. `policy` defines behavior if buffer
+    /// is full, dropped publishes fail with `Disconnected` error.
+    /// By default buffering is disabled.
+    pub fn offline_buffer(self, capacity: usize, policy: OfflinePolicy) -> Self {
+        *self.inner.offline.borrow_mut() = OfflineBuffer::new(capacity, policy);
+        self
+    }
+
+    #[inline]
+    /// Get managed client sink
+    pub fn sink(&self) -> ManagedSink {
+        ManagedSink(self.inner.clone())
+    }
+
+    /// Run client and handle control messages
+    ///
+    /// Client connects to the server and re-connects every time connection
+    /// get closed. Future resolves after `ManagedSink::close()` get called
+    /// or re-connecting is stopped by backoff strategy or connect error hook.
+    pub async fn start<F, S, E>(self, service: F)
+    where
+        E: 'static,
+        F: IntoService<S>,
+        S: Service<Request = ControlMessage, Response = ControlResult, Error = E> + 'static,
+    {
+        let service = Rc::new(service.into_service());
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+
+        while !self.inner.closed.get() {
+            match self.connector.connect().await {
+                Ok(client) => {
+                    attempt = 0;
+
+                    let sink = client.sink();
+                    if self.inner.closed.get() {
+                        sink.close();
+                        break;
+                    }
+                    *self.inner.sink.borrow_mut() = Some(sink.clone());
+
+                    // replay offline buffer, tasks get started in order
+                    let buffered = self.inner.offline.borrow_mut().take();
+                    for item in buffered {
+                        ntex::rt::spawn(replay(sink.clone(), item));
+                    }
+                    self.inner.connected.notify();
+
+                    // replay subscriptions for registered topic filters
+                    let filters = self.inner.filters.borrow().clone();
+                    if !filters.is_empty() {
+                        ntex::rt::spawn(resubscribe(sink, filters));
+                    }
+
+                    if client.start(service.clone()).await.is_err() {
+                        log::trace!("Mqtt client connection is closed with error");
+                    }
+                    self.inner.sink.borrow_mut().take();
+                }
+                Err(err) => {
+                    log::error!("Cannot connect to mqtt server: {}", err);
+                    if self.abort.as_ref().map(|f| f(&err)).unwrap_or(false) {
+                        log::error!("Stop re-connecting to mqtt server");
+                        break;
+                    }
+                }
+            }
+
+            if self.inner.closed.get() {
+                break;
+            }
+            attempt += 1;
+            if let Some(delay) = backoff.next_delay(attempt) {
+                log::trace!("Re-connecting to mqtt server in {:?}", delay);
+                delay_for(delay).await;
+            } else {
+                log::error!("Max re-connect attempts reached");
+                break;
+            }
+        }
+        self.inner.closed.set(true);
+        self.inner.offline.borrow_mut().take();
+        self.inner.connected.notify();
+    }
+}
+
+async fn send(
+    sink: MqttSink,
+    topic: ByteString,
+    payload: Bytes,
+    qos: QoS,
+) -> Result<(), SendPacketError> {
+    let builder = sink.publish(topic, payload);
+    match qos {
+        QoS::AtMostOnce => builder.send_at_most_once(),
+        QoS::AtLeastOnce => builder.send_at_least_once().await,
+        QoS::ExactlyOnce => builder.send_exactly_once().await,
+    }
+}
+
+async fn replay(sink: MqttSink, item: Buffered) {
+    let res = send(sink, item.topic, item.payload, item.qos).await;
+    if let Some(tx) = item.tx {
+        let _ = tx.send(res);
+    } else if let Err(err) = res {
+        log::error!("Cannot send buffered publish: {}", err);
+    }
+}
+
+async fn resubscribe(sink: MqttSink, filters: Vec<(ByteString, QoS)>) {
+    let mut builder = sink.subscribe();
+    for (filter, qos) in filters {
+        builder = builder.topic_filter(filter, qos);
+    }
+    match builder.send().await {
+        Ok(codes) => log::trace!("Topic filters are re-subscribed: {:?}", codes),
+        Err(err) => log::error!("Cannot re-subscribe topic filters: {}", err),
+    }
+}
+
+/// Managed client sink
+///
+/// Sink stays valid across re-connects.
+#[derive(Clone)]
+pub struct ManagedSink(Rc<Inner>);
+
+impl ManagedSink {
+    /// Check if client is connected to the server
+    pub fn is_connected(&self) -> bool {
+        self.0.sink.borrow().as_ref().map(|sink| sink.is_open()).unwrap_or(false)
+    }
+
+    /// Get sink of the current connection
+    pub fn sink(&self) -> Option<MqttSink> {
+        self.0.sink.borrow().as_ref().filter(|sink| sink.is_open()).cloned()
+    }
+
+    /// Wait until client get connected to the server
+    ///
+    /// Returns `None` if client is closed.
+    pub async fn connected(&self) -> Option<MqttSink> {
+        loop {
+            if self.0.closed.get() {
+                return None;
+            }
+            if let Some(sink) = self.sink() {
+                return Some(sink);
+            }
+            self.0.connected.wait().await;
+        }
+    }
+
+    /// Send publish packet
+    ///
+    /// If client is disconnected and offline buffer is enabled, publish
+    /// get buffered and sent after client re-connects. Future resolves
+    /// when publish is acknowledged by the server.
+    pub async fn publish(
+        &self,
+        topic: ByteString,
+        payload: Bytes,
+        qos: QoS,
+    ) -> Result<(), SendPacketError> {
+        if let Some(sink) = self.sink() {
+            return send(sink, topic, payload, qos).await;
+        }
+        let (tx, rx) = oneshot::channel();
+        self.buffer(Buffered { topic, payload, qos, tx: Some(tx) })?;
+        rx.await.unwrap_or(Err(SendPacketError::Disconnected))
+    }
+
+    /// Send publish packet with QoS 0
+    ///
+    /// If client is disconnected and offline buffer is enabled, publish
+    /// get buffered and sent after client re-connects.
+    pub fn publish_at_most_once(
+        &self,
+        topic: ByteString,
+        payload: Bytes,
+    ) -> Result<(), SendPacketError> {
+        if let Some(sink) = self.sink() {
+            sink.publish(topic, payload).send_at_most_once()
+        } else {
+            self.buffer(Buffered { topic, payload, qos: QoS::AtMostOnce, tx: None })
+        }
+    }
+
+    fn buffer(&self, item: Buffered) -> Result<(), SendPacketError> {
+        if self.0.closed.get() {
+            Err(SendPacketError::Disconnected)
+        } else {
+            self.0.offline.borrow_mut().push(item).map_err(|_| SendPacketError::Disconnected)
+        }
+    }
+
+    /// Subscribe to a topic filter
+    ///
+    /// Topic filter get registered and re-subscribed after each re-connect,
+    /// even if client is not connected at the moment.
+    pub async fn subscribe(
+        &self,
+        filter: ByteString,
+        qos: QoS,
+    ) -> Result<codec::SubscribeReturnCode, SendPacketError> {
+        {
+            let mut filters = self.0.filters.borrow_mut();
+            if let Some(item) = filters.iter_mut().find(|item| item.0 == filter) {
+                item.1 = qos;
+            } else {
+                filters.push((filter.clone(), qos));
+            }
+        }
+
+        if let Some(sink) = self.sink() {
+            let mut codes = sink.subscribe().topic_filter(filter, qos).send().await?;
+            Ok(codes.pop().unwrap_or(codec::SubscribeReturnCode::Failure))
+        } else {
+            Err(SendPacketError::Disconnected)
+        }
+    }
+
+    /// Unsubscribe from a topic filter
+    pub async fn unsubscribe(&self, filter: ByteString) -> Result<(), SendPacketError> {
+        self.0.filters.borrow_mut().retain(|item| item.0 != filter);
+
+        if let Some(sink) = self.sink() {
+            sink.unsubscribe().topic_filter(filter).send().await
+        } else {
+            Err(SendPacketError::Disconnected)
+        }
+    }
+
+    /// Close current connection and stop re-connecting
+    pub fn close(&self) {
+        self.0.closed.set(true);
+        if let Some(sink) = self.0.sink.borrow_mut().take() {
+            sink.close();
+        }
+        // buffered publishes fail with `Disconnected` error
+        self.0.offline.borrow_mut().take();
+        self.0.connected.notify();
+    }
+}
@@ -2,11 +2,19 @@ mod connection;
 mod connector;
 pub mod control;
 mod dispatcher;
+mod managed;
 
-pub use self::connection::Client;
+pub use self::connection::{Client, PublishStream, Routes};
 pub use self::connector::MqttConnector;
 pub use self::control::{ControlMessage, ControlResult};
+pub use self::managed::{ManagedClient, ManagedSink};
 
+pub use crate::backoff::{BackoffStrategy, ExponentialBackoff, FixedBackoff};
+pub use crate::offline::OfflinePolicy;
+pub use crate::proxy::{Proxy, ProxyConnector};
 pub use crate::topic::Topic;
 pub use crate::types::QoS;
+#[cfg(unix)]
+pub use crate::uds::UnixConnector;
 pub use crate::v3::{codec, error, error::ClientError, sink::MqttSink};
+pub use crate::ws::{WsConnector, WsStream};
@@ -1,11 +1,13 @@
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::task::{Context, Poll};
 
 use ntex::codec::{Decoder, Encoder};
-use ntex::util::{Buf, BytesMut};
+use ntex::util::{Buf, Bytes, BytesMut};
 
 use super::{decode, encode, Packet, Publish};
 use crate::error::{DecodeError, EncodeError};
-use crate::types::{FixedHeader, QoS};
+use crate::payload::{Payload, PayloadSender};
+use crate::types::{check_packet_flags, packet_type, Conformance, FixedHeader, QoS};
 use crate::utils::decode_variable_length;
 
 #[derive(Debug)]
@@ -13,18 +15,35 @@ use crate::utils::decode_variable_length;
 pub struct Codec {
     state: Cell<DecodeState>,
     max_size: Cell<u32>,
+    mqisdp: Cell<bool>,
+    conformance: Cell<Conformance>,
+    strict_utf8: Cell<bool>,
+    stream_threshold: Cell<u32>,
+    sender: RefCell<Option<PayloadSender>>,
+    payload: RefCell<Option<Payload>>,
 }
 
 #[derive(Debug, Clone, Copy)]
 enum DecodeState {
     FrameHeader,
     Frame(FixedHeader),
+    PublishHeader(FixedHeader),
+    Payload,
 }
 
 impl Codec {
     /// Create `Codec` instance
     pub fn new() -> Self {
-        Codec { state: Cell::new(DecodeState::FrameHeader), max_size: Cell::new(0) }
+        Codec {
+            state: Cell::new(DecodeState::FrameHeader),
+            max_size: Cell::new(0),
+            mqisdp: Cell::new(false),
+            conformance: Cell::new(Conformance::Strict),
+            strict_utf8: Cell::new(true),
+            stream_threshold: Cell::new(0),
+            sender: RefCell::new(None),
+            payload: RefCell::new(None),
+        }
     }
 
     /// Set max inbound frame size.
@@ -43,6 +62,120 @@ impl Codec {
     pub fn set_max_size(&self, size: u32) {
         self.max_size.set(size);
     }
+
+    /// Accept mqtt 3.1 connect packets.
+    ///
+    /// Mqtt 3.1 uses "MQIsdp" protocol name with protocol level 3.
+    /// By default only mqtt 3.1.1 connect packets are accepted.
+    pub fn mqisdp(self, val: bool) -> Self {
+        self.mqisdp.set(val);
+        self
+    }
+
+    /// Set conformance mode of inbound packets decoding.
+    ///
+    /// By default codec works in `Conformance::Strict` mode
+    pub fn conformance(self, val: Conformance) -> Self {
+        self.conformance.set(val);
+        self
+    }
+
+    /// Set conformance mode of inbound packets decoding.
+    ///
+    /// By default codec works in `Conformance::Strict` mode
+    pub fn set_conformance(&self, val: Conformance) {
+        self.conformance.set(val);
+    }
+
+    /// Reject topics and strings that contain U+0000 or control characters.
+    ///
+    /// By default strict utf-8 checks are enabled
+    pub fn strict_utf8(self, val: bool) -> Self {
+        self.strict_utf8.set(val);
+        self
+    }
+
+    /// Reject topics and strings that contain U+0000 or control characters.
+    ///
+    /// By default strict utf-8 checks are enabled
+    pub fn set_strict_utf8(&self, val: bool) {
+        self.strict_utf8.set(val);
+    }
+
+    /// Set streaming threshold for inbound publish packets.
+    ///
+    /// Publish packets larger than threshold are decoded as soon as variable
+    /// header is received, payload is available via `take_payload()`.
+    /// If threshold is set to `0`, streaming is disabled.
+    /// By default threshold is set to `0`
+    pub fn stream_threshold(self, size: u32) -> Self {
+        self.stream_threshold.set(size);
+        self
+    }
+
+    /// Set streaming threshold for inbound publish packets.
+    ///
+    /// If threshold is set to `0`, streaming is disabled.
+    /// By default threshold is set to `0`
+    pub fn set_stream_threshold(&self, size: u32) {
+        self.stream_threshold.set(size);
+    }
+
+    /// Take payload stream of last decoded publish packet
+    ///
+    /// Payload of streamed publish packet is empty, packet's payload is
+    /// delivered via returned stream.
+    pub fn take_payload(&self) -> Option<Payload> {
+        self.payload.borrow_mut().take()
+    }
+
+    /// Check if payload stream of streamed publish packet could buffer more chunks
+    pub(crate) fn poll_payload_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.sender.borrow().as_ref().map_or(Poll::Ready(()), |s| s.poll_ready(cx))
+    }
+
+    /// Encode single packet
+    pub fn encode_packet(&self, pkt: Packet) -> Result<Bytes, EncodeError> {
+        let mut buf = BytesMut::new();
+        self.encode(pkt, &mut buf)?;
+        Ok(buf.freeze())
+    }
+
+    /// Decode single packet from the start of the buffer
+    ///
+    /// Returns decoded packet and number of consumed bytes, or `None` if buffer
+    /// does not contain complete packet. Unlike `Decoder::decode()` this method
+    /// does not change decoder state and never streams packet's payload.
+    pub fn decode_packet(&self, src: &Bytes) -> Result<Option<(Packet, usize)>, DecodeError> {
+        if src.len() < 2 {
+            return Ok(None);
+        }
+        let first_byte = check_packet_flags(src[0], self.conformance.get())?;
+        let (remaining_length, consumed) = match decode_variable_length(&src[1..])? {
+            Some(res) => res,
+            None => return Ok(None),
+        };
+        let max_size = self.max_size.get();
+        if max_size != 0 && max_size < remaining_length {
+            return Err(DecodeError::MaxSizeExceeded);
+        }
+        let start = consumed + 1;
+        let end = start + remaining_length as usize;
+        if src.len() < end {
+            return Ok(None);
+        }
+        let packet = self.decode_frame(src.slice(start..end), first_byte)?;
+        Ok(Some((packet, end)))
+    }
+
+    fn decode_frame(&self, src: Bytes, first_byte: u8) -> Result<Packet, DecodeError> {
+        let packet =
+            decode::decode_packet(src, first_byte, self.mqisdp.get(), self.conformance.get())?;
+        if self.strict_utf8.get() {
+            decode::check_utf8_strings(&packet)?;
+        }
+        Ok(packet)
+    }
 }
 
 impl Default for Codec {
@@ -63,7 +196,7 @@ impl Decoder for Codec {
                         return Ok(None);
                     }
                     let src_slice = src.as_ref();
-                    let first_byte = src_slice[0];
+                    let first_byte = check_packet_flags(src_slice[0], self.conformance.get())?;
                     match decode_variable_length(&src_slice[1..])? {
                         Some((remaining_length, consumed)) => {
                             // check max message size
@@ -72,11 +205,25 @@ impl Decoder for Codec {
                                 return Err(DecodeError::MaxSizeExceeded);
                             }
                             src.advance(consumed + 1);
+
+                            // stream large publish packets
+                            let threshold = self.stream_threshold.get();
+                            if threshold != 0
+                                && remaining_length > threshold
+                                && (packet_type::PUBLISH_START..=packet_type::PUBLISH_END)
+                                    .contains(&first_byte)
+                            {
+                                self.state.set(DecodeState::PublishHeader(FixedHeader {
+                                    first_byte,
+                                    remaining_length,
+                                }));
+                                continue;
+                            }
+
                             self.state.set(DecodeState::Frame(FixedHeader {
                                 first_byte,
                                 remaining_length,
                             }));
-                            // todo: validate remaining_length against max frame size config
                             let remaining_length = remaining_length as usize;
                             if src.len() < remaining_length {
                                 // todo: subtract?
@@ -94,16 +241,72 @@ impl Decoder for Codec {
                         return Ok(None);
                     }
                     let packet_buf = src.split_to(fixed.remaining_length as usize);
-                    let packet = decode::decode_packet(packet_buf.freeze(), fixed.first_byte)?;
+                    let packet = self.decode_frame(packet_buf.freeze(), fixed.first_byte)?;
                     self.state.set(DecodeState::FrameHeader);
                     src.reserve(2);
                     return Ok(Some(packet));
                 }
+                DecodeState::PublishHeader(fixed) => {
+                    let remaining_length = fixed.remaining_length as usize;
+                    let header_len =
+                        match publish_header_len(src, fixed.first_byte, remaining_length)? {
+                            Some(len) => len,
+                            None => return Ok(None),
+                        };
+                    let packet =
+                        self.decode_frame(src.split_to(header_len).freeze(), fixed.first_byte)?;
+
+                    // payload chunks are buffered up to streaming threshold
+                    let (mut sender, payload) = PayloadSender::create(
+                        remaining_length - header_len,
+                        self.stream_threshold.get() as usize,
+                    );
+                    if sender.feed(src) {
+                        self.state.set(DecodeState::FrameHeader);
+                    } else {
+                        self.state.set(DecodeState::Payload);
+                        *self.sender.borrow_mut() = Some(sender);
+                    }
+                    *self.payload.borrow_mut() = Some(payload);
+                    return Ok(Some(packet));
+                }
+                DecodeState::Payload => {
+                    let mut sender = self.sender.borrow_mut();
+                    if sender.as_mut().map(|s| s.feed(src)).unwrap_or(true) {
+                        sender.take();
+                        self.state.set(DecodeState::FrameHeader);
+                    } else {
+                        return Ok(None);
+                    }
+                }
             }
         }
     }
 }
 
+/// Size of publish packet's variable header
+fn publish_header_len(
+    src: &[u8],
+    first_byte: u8,
+    remaining_length: usize,
+) -> Result<Option<usize>, DecodeError> {
+    if src.len() < 2 {
+        return Ok(None);
+    }
+    let mut len = 2 + u16::from_be_bytes([src[0], src[1]]) as usize;
+    if first_byte & 0b0110 != 0 {
+        // packet id
+        len += 2;
+    }
+    if len > remaining_length {
+        Err(DecodeError::InvalidLength)
+    } else if src.len() < len {
+        Ok(None)
+    } else {
+        Ok(Some(len))
+    }
+}
+
 impl Encoder for Codec {
     type Item = Packet;
     type Error = EncodeError;
@@ -124,7 +327,7 @@ impl Encoder for Codec {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ntex::util::{ByteString, Bytes};
+    use ntex::util::ByteString;
 
     #[test]
     fn test_max_size() {
@@ -135,6 +338,88 @@ mod tests {
         assert_eq!(codec.decode(&mut buf), Err(DecodeError::MaxSizeExceeded));
     }
 
+    #[test]
+    fn test_conformance() {
+        let codec = Codec::new();
+        let mut buf = BytesMut::from(&b"\x60\x02\x00\x01"[..]);
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::InvalidPacketFlags));
+        let mut buf = BytesMut::from(&b"\x38\x07\x00\x05topic"[..]);
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::InvalidPacketFlags));
+        let mut buf = BytesMut::from(&b"\x36\x07\x00\x05topic"[..]);
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::InvalidQoS));
+
+        let codec = Codec::new().conformance(Conformance::Lenient);
+        let mut buf = BytesMut::from(&b"\x60\x02\x00\x01\x38\x07\x00\x05topic"[..]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Packet::PublishRelease { packet_id: std::num::NonZeroU16::new(1).unwrap() })
+        );
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Packet::Publish(Publish {
+                dup: false,
+                retain: false,
+                qos: QoS::AtMostOnce,
+                topic: ByteString::from_static("topic"),
+                packet_id: None,
+                payload: Bytes::new(),
+            }))
+        );
+    }
+
+    #[test]
+    fn test_strict_utf8() {
+        let codec = Codec::new();
+        let mut buf = BytesMut::from(&b"\x30\x07\x00\x05to\x00ic"[..]);
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::InvalidUtf8String));
+        let mut buf = BytesMut::from(&b"\xa2\x08\x00\x01\x00\x04\x1bcmd"[..]);
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::InvalidUtf8String));
+
+        let codec = Codec::new().strict_utf8(false);
+        let mut buf = BytesMut::from(&b"\x30\x07\x00\x05to\x00ic"[..]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Packet::Publish(Publish {
+                dup: false,
+                retain: false,
+                qos: QoS::AtMostOnce,
+                topic: ByteString::from_static("to\u{0}ic"),
+                packet_id: None,
+                payload: Bytes::new(),
+            }))
+        );
+    }
+
+    #[test]
+    fn test_standalone() {
+        let codec = Codec::new();
+        let mut buf = BytesMut::new();
+        let pkt = Packet::Publish(Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtLeastOnce,
+            topic: ByteString::from_static("/test"),
+            packet_id: Some(std::num::NonZeroU16::new(1).unwrap()),
+            payload: Bytes::from_static(b"data"),
+        });
+        buf.extend_from_slice(&codec.encode_packet(pkt.clone()).unwrap());
+        buf.extend_from_slice(&codec.encode_packet(Packet::PingRequest).unwrap());
+        let buf = buf.freeze();
+
+        assert_eq!(codec.decode_packet(&buf.slice(..10)).unwrap(), None);
+        let (pkt2, size) = codec.decode_packet(&buf).unwrap().unwrap();
+        assert_eq!(pkt2, pkt);
+        assert_eq!(size, 15);
+        assert_eq!(
+            codec.decode_packet(&buf.slice(size..)).unwrap(),
+            Some((Packet::PingRequest, 2))
+        );
+        assert_eq!(
+            Codec::new().max_size(5).decode_packet(&buf),
+            Err(DecodeError::MaxSizeExceeded)
+        );
+    }
+
     #[test]
     fn test_packet() {
         let codec = Codec::new();
@@ -157,4 +442,41 @@ mod tests {
         };
         assert_eq!(pkt, pkt2);
     }
+
+    #[ntex::test]
+    async fn test_stream_payload() {
+        let codec = Codec::new().stream_threshold(16);
+        let mut buf = BytesMut::new();
+
+        let pkt = Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtLeastOnce,
+            topic: ByteString::from_static("/test"),
+            packet_id: Some(std::num::NonZeroU16::new(1).unwrap()),
+            payload: Bytes::from(Vec::from("a".repeat(1024))),
+        };
+        codec.encode(Packet::Publish(pkt.clone()), &mut buf).unwrap();
+        codec.encode(Packet::PingRequest, &mut buf).unwrap();
+        let mut tail = buf.split_off(100);
+
+        let pkt2 = if let Packet::Publish(v) = codec.decode(&mut buf).unwrap().unwrap() {
+            v
+        } else {
+            panic!()
+        };
+        assert_eq!(pkt2.topic, pkt.topic);
+        assert_eq!(pkt2.packet_id, pkt.packet_id);
+        assert!(pkt2.payload.is_empty());
+        assert!(buf.is_empty());
+
+        let payload = codec.take_payload().unwrap();
+        assert_eq!(payload.size(), 1024);
+
+        buf.extend_from_slice(&tail.split_to(500));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&tail);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Packet::PingRequest));
+        assert_eq!(payload.read_all().await.unwrap(), pkt.payload);
+    }
 }
@@ -3,18 +3,64 @@ use std::{convert::TryFrom, convert::TryInto, num::NonZeroU16};
 use ntex::util::{Buf, ByteString, Bytes};
 
 use crate::error::DecodeError;
-use crate::types::{packet_type, QoS, MQTT, MQTT_LEVEL_3, WILL_QOS_SHIFT};
-use crate::utils::Decode;
+use crate::types::{
+    packet_type, Conformance, QoS, MQISDP, MQISDP_LEVEL, MQTT, MQTT_LEVEL_3, WILL_QOS_SHIFT,
+};
+use crate::utils::{check_utf8_string, Decode};
 
 use super::packet::{Connect, LastWill, Packet, Publish, SubscribeReturnCode};
 use super::{ConnectAckFlags, ConnectFlags};
 
-pub(crate) fn decode_packet(mut src: Bytes, first_byte: u8) -> Result<Packet, DecodeError> {
+pub(crate) fn decode_packet(
+    mut src: Bytes,
+    first_byte: u8,
+    mqisdp: bool,
+    conformance: Conformance,
+) -> Result<Packet, DecodeError> {
+    let len = src.len();
+    decode_packet_inner(&mut src, first_byte, mqisdp, conformance)
+        .map_err(|err| err.with_packet(first_byte, len - src.len()))
+}
+
+/// Check topics and strings of decoded packet for U+0000 and control characters
+pub(crate) fn check_utf8_strings(packet: &Packet) -> Result<(), DecodeError> {
+    match packet {
+        Packet::Connect(pkt) => {
+            check_utf8_string(&pkt.client_id)?;
+            if let Some(ref username) = pkt.username {
+                check_utf8_string(username)?;
+            }
+            if let Some(ref will) = pkt.last_will {
+                check_utf8_string(&will.topic)?;
+            }
+        }
+        Packet::Publish(pkt) => check_utf8_string(&pkt.topic)?,
+        Packet::Subscribe { topic_filters, .. } => {
+            for (filter, _) in topic_filters {
+                check_utf8_string(filter)?;
+            }
+        }
+        Packet::Unsubscribe { topic_filters, .. } => {
+            for filter in topic_filters {
+                check_utf8_string(filter)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+fn decode_packet_inner(
+    src: &mut Bytes,
+    first_byte: u8,
+    mqisdp: bool,
+    conformance: Conformance,
+) -> Result<Packet, DecodeError> {
     match first_byte {
-        packet_type::CONNECT => decode_connect_packet(&mut src),
-        packet_type::CONNACK => decode_connect_ack_packet(&mut src),
+        packet_type::CONNECT => decode_connect_packet(src, mqisdp, conformance),
+        packet_type::CONNACK => decode_connect_ack_packet(src, conformance),
         packet_type::PUBLISH_START..=packet_type::PUBLISH_END => {
-            decode_publish_packet(&mut src, first_byte & 0b0000_1111)
+            decode_publish_packet(src, first_byte & 0b0000_1111)
         }
         packet_type::PUBACK => decode_ack(src, |packet_id| Packet::PublishAck { packet_id }),
         packet_type::PUBREC => {
@@ -26,9 +72,9 @@ pub(crate) fn decode_packet(mut src: Bytes, first_byte: u8) -> Result<Packet, De
         packet_type::PUBCOMP => {
             decode_ack(src, |packet_id| Packet::PublishComplete { packet_id })
         }
-        packet_type::SUBSCRIBE => decode_subscribe_packet(&mut src),
-        packet_type::SUBACK => decode_subscribe_ack_packet(&mut src),
-        packet_type::UNSUBSCRIBE => decode_unsubscribe_packet(&mut src),
+        packet_type::SUBSCRIBE => decode_subscribe_packet(src, conformance),
+        packet_type::SUBACK => decode_subscribe_ack_packet(src),
+        packet_type::UNSUBSCRIBE => decode_unsubscribe_packet(src, conformance),
         packet_type::UNSUBACK => {
             decode_ack(src, |packet_id| Packet::UnsubscribeAck { packet_id })
         }
@@ -40,30 +86,58 @@ pub(crate) fn decode_packet(mut src: Bytes, first_byte: u8) -> Result<Packet, De
 }
 
 #[inline]
-fn decode_ack(mut src: Bytes, f: impl Fn(NonZeroU16) -> Packet) -> Result<Packet, DecodeError> {
-    let packet_id = NonZeroU16::decode(&mut src)?;
+fn decode_ack(
+    src: &mut Bytes,
+    f: impl Fn(NonZeroU16) -> Packet,
+) -> Result<Packet, DecodeError> {
+    let packet_id = NonZeroU16::decode(src)?;
     ensure!(!src.has_remaining(), DecodeError::InvalidLength);
     Ok(f(packet_id))
 }
 
-fn decode_connect_packet(src: &mut Bytes) -> Result<Packet, DecodeError> {
+fn decode_connect_packet(
+    src: &mut Bytes,
+    mqisdp: bool,
+    conformance: Conformance,
+) -> Result<Packet, DecodeError> {
     ensure!(src.remaining() >= 10, DecodeError::InvalidLength);
     let len = src.get_u16();
 
-    ensure!(len == 4 && &src.as_ref()[0..4] == MQTT, DecodeError::InvalidProtocol);
-    src.advance(4);
-
-    let level = src.get_u8();
-    ensure!(level == MQTT_LEVEL_3, DecodeError::UnsupportedProtocolLevel);
-
-    let flags =
-        ConnectFlags::from_bits(src.get_u8()).ok_or(DecodeError::ConnectReservedFlagSet)?;
+    // mqtt 3.1 uses "MQIsdp" protocol name with protocol level 3
+    let level = if mqisdp && len == 6 && src.as_ref().starts_with(MQISDP) {
+        src.advance(6);
+        ensure!(src.remaining() >= 6, DecodeError::InvalidLength);
+        MQISDP_LEVEL
+    } else {
+        ensure!(len == 4 && &src.as_ref()[0..4] == MQTT, DecodeError::InvalidProtocol);
+        src.advance(4);
+        MQTT_LEVEL_3
+    };
+    ensure!(src.get_u8() == level, DecodeError::UnsupportedProtocolLevel);
+
+    let strict = conformance.is_strict();
+    let flags = if strict {
+        let flags =
+            ConnectFlags::from_bits(src.get_u8()).ok_or(DecodeError::ConnectReservedFlagSet)?;
+        ensure!(
+            flags.contains(ConnectFlags::WILL)
+                || !flags.intersects(ConnectFlags::WILL_QOS | ConnectFlags::WILL_RETAIN),
+            DecodeError::InvalidWillFlags
+        );
+        ensure!(
+            flags.contains(ConnectFlags::USERNAME) || !flags.contains(ConnectFlags::PASSWORD),
+            DecodeError::PasswordWithoutUsername
+        );
+        flags
+    } else {
+        ConnectFlags::from_bits_truncate(src.get_u8())
+    };
 
     let keep_alive = u16::decode(src)?;
     let client_id = ByteString::decode(src)?;
 
     ensure!(
-        !client_id.is_empty() || flags.contains(ConnectFlags::CLEAN_START),
+        !strict || !client_id.is_empty() || flags.contains(ConnectFlags::CLEAN_START),
         DecodeError::InvalidClientId
     );
 
@@ -71,7 +145,8 @@ fn decode_connect_packet(src: &mut Bytes) -> Result<Packet, DecodeError> {
         let topic = ByteString::decode(src)?;
         let message = Bytes::decode(src)?;
         Some(LastWill {
-            qos: QoS::try_from((flags & ConnectFlags::WILL_QOS).bits() >> WILL_QOS_SHIFT)?,
+            qos: QoS::try_from((flags & ConnectFlags::WILL_QOS).bits() >> WILL_QOS_SHIFT)
+                .map_err(|_| DecodeError::InvalidQoS)?,
             retain: flags.contains(ConnectFlags::WILL_RETAIN),
             topic,
             message,
@@ -96,10 +171,16 @@ fn decode_connect_packet(src: &mut Bytes) -> Result<Packet, DecodeError> {
     }))
 }
 
-fn decode_connect_ack_packet(src: &mut Bytes) -> Result<Packet, DecodeError> {
+fn decode_connect_ack_packet(
+    src: &mut Bytes,
+    conformance: Conformance,
+) -> Result<Packet, DecodeError> {
     ensure!(src.remaining() >= 2, DecodeError::InvalidLength);
-    let flags =
-        ConnectAckFlags::from_bits(src.get_u8()).ok_or(DecodeError::ConnAckReservedFlagSet)?;
+    let flags = if conformance.is_strict() {
+        ConnectAckFlags::from_bits(src.get_u8()).ok_or(DecodeError::ConnAckReservedFlagSet)?
+    } else {
+        ConnectAckFlags::from_bits_truncate(src.get_u8())
+    };
 
     let return_code = src.get_u8().try_into()?;
     Ok(Packet::ConnectAck {
@@ -127,15 +208,22 @@ fn decode_publish_packet(src: &mut Bytes, packet_flags: u8) -> Result<Packet, De
     }))
 }
 
-fn decode_subscribe_packet(src: &mut Bytes) -> Result<Packet, DecodeError> {
+fn decode_subscribe_packet(
+    src: &mut Bytes,
+    conformance: Conformance,
+) -> Result<Packet, DecodeError> {
+    let strict = conformance.is_strict();
     let packet_id = NonZeroU16::decode(src)?;
     let mut topic_filters = Vec::new();
     while src.has_remaining() {
         let topic = ByteString::decode(src)?;
         ensure!(src.remaining() >= 1, DecodeError::InvalidLength);
-        let qos = (src.get_u8() & 0b0000_0011).try_into()?;
+        let opts = src.get_u8();
+        ensure!(!strict || opts & 0b1111_1100 == 0, DecodeError::InvalidSubscriptionOptions);
+        let qos = (opts & 0b0000_0011).try_into().map_err(|_| DecodeError::InvalidQoS)?;
         topic_filters.push((topic, qos));
     }
+    ensure!(!strict || !topic_filters.is_empty(), DecodeError::EmptyTopicFilters);
 
     Ok(Packet::Subscribe { packet_id, topic_filters })
 }
@@ -153,12 +241,19 @@ fn decode_subscribe_ack_packet(src: &mut Bytes) -> Result<Packet, DecodeError> {
     Ok(Packet::SubscribeAck { packet_id, status })
 }
 
-fn decode_unsubscribe_packet(src: &mut Bytes) -> Result<Packet, DecodeError> {
+fn decode_unsubscribe_packet(
+    src: &mut Bytes,
+    conformance: Conformance,
+) -> Result<Packet, DecodeError> {
     let packet_id = NonZeroU16::decode(src)?;
     let mut topic_filters = Vec::new();
     while src.remaining() > 0 {
         topic_filters.push(ByteString::decode(src)?);
     }
+    ensure!(
+        !conformance.is_strict() || !topic_filters.is_empty(),
+        DecodeError::EmptyTopicFilters
+    );
     Ok(Packet::Unsubscribe { packet_id, topic_filters })
 }
 
@@ -173,7 +268,7 @@ mod tests {
             let first_byte = $bytes.as_ref()[0];
             let (_len, consumed) = decode_variable_length(&$bytes[1..]).unwrap().unwrap();
             let cur = Bytes::from_static(&$bytes[consumed + 1..]);
-            assert_eq!(decode_packet(cur, first_byte), Ok($res));
+            assert_eq!(decode_packet(cur, first_byte, false, Conformance::Strict), Ok($res));
         }};
     );
 
@@ -184,9 +279,13 @@ mod tests {
     #[test]
     fn test_decode_connect_packets() {
         assert_eq!(
-            decode_connect_packet(&mut Bytes::from_static(
-                b"\x00\x04MQTT\x04\xC0\x00\x3C\x00\x0512345\x00\x04user\x00\x04pass"
-            )),
+            decode_connect_packet(
+                &mut Bytes::from_static(
+                    b"\x00\x04MQTT\x04\xC0\x00\x3C\x00\x0512345\x00\x04user\x00\x04pass"
+                ),
+                false,
+                Conformance::Strict
+            ),
             Ok(Packet::Connect(Connect {
                 clean_session: false,
                 keep_alive: 60,
@@ -198,9 +297,13 @@ mod tests {
         );
 
         assert_eq!(
-            decode_connect_packet(&mut Bytes::from_static(
-                b"\x00\x04MQTT\x04\x14\x00\x3C\x00\x0512345\x00\x05topic\x00\x07message"
-            )),
+            decode_connect_packet(
+                &mut Bytes::from_static(
+                    b"\x00\x04MQTT\x04\x14\x00\x3C\x00\x0512345\x00\x05topic\x00\x07message"
+                ),
+                false,
+                Conformance::Strict
+            ),
             Ok(Packet::Connect(Connect {
                 clean_session: false,
                 keep_alive: 60,
@@ -217,32 +320,83 @@ mod tests {
         );
 
         assert_eq!(
-            decode_connect_packet(&mut Bytes::from_static(b"\x00\x02MQ00000000000000000000")),
+            decode_connect_packet(
+                &mut Bytes::from_static(b"\x00\x02MQ00000000000000000000"),
+                false,
+                Conformance::Strict
+            ),
             Err(DecodeError::InvalidProtocol),
         );
         assert_eq!(
-            decode_connect_packet(&mut Bytes::from_static(b"\x00\x10MQ00000000000000000000")),
+            decode_connect_packet(
+                &mut Bytes::from_static(b"\x00\x10MQ00000000000000000000"),
+                false,
+                Conformance::Strict
+            ),
             Err(DecodeError::InvalidProtocol),
         );
         assert_eq!(
-            decode_connect_packet(&mut Bytes::from_static(b"\x00\x04MQAA00000000000000000000")),
+            decode_connect_packet(
+                &mut Bytes::from_static(b"\x00\x04MQAA00000000000000000000"),
+                false,
+                Conformance::Strict
+            ),
             Err(DecodeError::InvalidProtocol),
         );
         assert_eq!(
-            decode_connect_packet(&mut Bytes::from_static(
-                b"\x00\x04MQTT\x0300000000000000000000"
-            )),
+            decode_connect_packet(
+                &mut Bytes::from_static(b"\x00\x04MQTT\x0300000000000000000000"),
+                false,
+                Conformance::Strict
+            ),
             Err(DecodeError::UnsupportedProtocolLevel),
         );
         assert_eq!(
-            decode_connect_packet(&mut Bytes::from_static(
-                b"\x00\x04MQTT\x04\xff00000000000000000000"
-            )),
+            decode_connect_packet(
+                &mut Bytes::from_static(b"\x00\x04MQTT\x04\xff00000000000000000000"),
+                false,
+                Conformance::Strict
+            ),
             Err(DecodeError::ConnectReservedFlagSet)
         );
 
         assert_eq!(
-            decode_connect_ack_packet(&mut Bytes::from_static(b"\x01\x04")),
+            decode_connect_packet(
+                &mut Bytes::from_static(b"\x00\x06MQIsdp\x03\x02\x00\x3C\x00\x0512345"),
+                true,
+                Conformance::Strict
+            ),
+            Ok(Packet::Connect(Connect {
+                clean_session: true,
+                keep_alive: 60,
+                client_id: ByteString::try_from(Bytes::from_static(b"12345")).unwrap(),
+                last_will: None,
+                username: None,
+                password: None,
+            }))
+        );
+        assert_eq!(
+            decode_connect_packet(
+                &mut Bytes::from_static(b"\x00\x06MQIsdp\x03\x02\x00\x3C\x00\x0512345"),
+                false,
+                Conformance::Strict
+            ),
+            Err(DecodeError::InvalidProtocol),
+        );
+        assert_eq!(
+            decode_connect_packet(
+                &mut Bytes::from_static(b"\x00\x06MQIsdp\x04\x02\x00\x3C\x00\x0512345"),
+                true,
+                Conformance::Strict
+            ),
+            Err(DecodeError::UnsupportedProtocolLevel),
+        );
+
+        assert_eq!(
+            decode_connect_ack_packet(
+                &mut Bytes::from_static(b"\x01\x04"),
+                Conformance::Strict
+            ),
             Ok(Packet::ConnectAck {
                 session_present: true,
                 return_code: ConnectAckReason::BadUserNameOrPassword
@@ -250,7 +404,10 @@ mod tests {
         );
 
         assert_eq!(
-            decode_connect_ack_packet(&mut Bytes::from_static(b"\x03\x04")),
+            decode_connect_ack_packet(
+                &mut Bytes::from_static(b"\x03\x04"),
+                Conformance::Strict
+            ),
             Err(DecodeError::ConnAckReservedFlagSet)
         );
 
@@ -327,9 +484,10 @@ mod tests {
         };
 
         assert_eq!(
-            decode_subscribe_packet(&mut Bytes::from_static(
-                b"\x12\x34\x00\x04test\x01\x00\x06filter\x02"
-            )),
+            decode_subscribe_packet(
+                &mut Bytes::from_static(b"\x12\x34\x00\x04test\x01\x00\x06filter\x02"),
+                Conformance::Strict
+            ),
             Ok(p.clone())
         );
         assert_decode_packet!(b"\x82\x12\x12\x34\x00\x04test\x01\x00\x06filter\x02", p);
@@ -358,9 +516,10 @@ mod tests {
         };
 
         assert_eq!(
-            decode_unsubscribe_packet(&mut Bytes::from_static(
-                b"\x12\x34\x00\x04test\x00\x06filter"
-            )),
+            decode_unsubscribe_packet(
+                &mut Bytes::from_static(b"\x12\x34\x00\x04test\x00\x06filter"),
+                Conformance::Strict
+            ),
             Ok(p.clone())
         );
         assert_decode_packet!(b"\xa2\x10\x12\x34\x00\x04test\x00\x06filter", p);
@@ -376,4 +535,93 @@ mod tests {
         assert_decode_packet!(b"\xc0\x00", Packet::PingRequest);
         assert_decode_packet!(b"\xd0\x00", Packet::PingResponse);
     }
+
+    #[test]
+    fn test_decode_conformance() {
+        // will qos without will flag
+        let src = Bytes::from_static(b"\x00\x04MQTT\x04\x0A\x00\x3C\x00\x0512345");
+        assert_eq!(
+            decode_connect_packet(&mut src.clone(), false, Conformance::Strict),
+            Err(DecodeError::InvalidWillFlags)
+        );
+        assert!(decode_connect_packet(&mut src.clone(), false, Conformance::Lenient).is_ok());
+
+        // password without username
+        let src = Bytes::from_static(b"\x00\x04MQTT\x04\x42\x00\x3C\x00\x0512345\x00\x04pass");
+        assert_eq!(
+            decode_connect_packet(&mut src.clone(), false, Conformance::Strict),
+            Err(DecodeError::PasswordWithoutUsername)
+        );
+        assert!(decode_connect_packet(&mut src.clone(), false, Conformance::Lenient).is_ok());
+
+        // empty client id without clean session, reserved flag
+        let src = Bytes::from_static(b"\x00\x04MQTT\x04\x01\x00\x3C\x00\x00");
+        assert_eq!(
+            decode_connect_packet(&mut src.clone(), false, Conformance::Strict),
+            Err(DecodeError::ConnectReservedFlagSet)
+        );
+        assert_eq!(
+            decode_connect_packet(&mut src.clone(), false, Conformance::Lenient),
+            Ok(Packet::Connect(Connect {
+                clean_session: false,
+                keep_alive: 60,
+                client_id: ByteString::new(),
+                last_will: None,
+                username: None,
+                password: None,
+            }))
+        );
+
+        // reserved bits of subscription options
+        let src = Bytes::from_static(b"\x12\x34\x00\x04test\x11");
+        assert_eq!(
+            decode_subscribe_packet(&mut src.clone(), Conformance::Strict),
+            Err(DecodeError::InvalidSubscriptionOptions)
+        );
+        assert_eq!(
+            decode_subscribe_packet(&mut src.clone(), Conformance::Lenient),
+            Ok(Packet::Subscribe {
+                packet_id: packet_id(0x1234),
+                topic_filters: vec![(ByteString::from_static("test"), QoS::AtLeastOnce)],
+            })
+        );
+        assert_eq!(
+            decode_subscribe_packet(
+                &mut Bytes::from_static(b"\x12\x34\x00\x04test\x03"),
+                Conformance::Lenient
+            ),
+            Err(DecodeError::InvalidQoS)
+        );
+        assert_eq!(
+            decode_subscribe_packet(&mut Bytes::from_static(b"\x12\x34"), Conformance::Strict),
+            Err(DecodeError::EmptyTopicFilters)
+        );
+        assert_eq!(
+            decode_unsubscribe_packet(
+                &mut Bytes::from_static(b"\x12\x34"),
+                Conformance::Strict
+            ),
+            Err(DecodeError::EmptyTopicFilters)
+        );
+    }
+
+    #[test]
+    fn test_decode_error_location() {
+        let err = decode_packet(
+            Bytes::from_static(b"\x12\x34\x00\x04test\x03"),
+            packet_type::SUBSCRIBE,
+            false,
+            Conformance::Strict,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), &DecodeError::InvalidQoS);
+        assert_eq!(
+            err.location(),
+            Some(&crate::error::DecodeErrorLocation {
+                packet_type: packet_type::SUBSCRIBE,
+                property: None,
+                offset: 9
+            })
+        );
+    }
 }
@@ -1,4 +1,17 @@
 //! MQTT v3.1.1 Protocol codec
+//!
+//! Codec could be used standalone, without server and client machinery,
+//! for example to build packet analyzers, fuzzers or proxies.
+//!
+//! ```rust
+//! use ntex_mqtt::v3::codec::{Codec, Packet};
+//!
+//! let codec = Codec::new();
+//! let buf = codec.encode_packet(Packet::PingRequest).unwrap();
+//! let (packet, size) = codec.decode_packet(&buf).unwrap().unwrap();
+//! assert_eq!(packet, Packet::PingRequest);
+//! assert_eq!(size, buf.len());
+//! ```
 
 #[allow(clippy::module_inception)]
 mod codec;
@@ -11,4 +24,4 @@ pub use self::packet::{
     Connect, ConnectAckReason, LastWill, Packet, Publish, SubscribeReturnCode,
 };
 pub use crate::topic::{Level, Topic, TopicError};
-pub use crate::types::{ConnectAckFlags, ConnectFlags, QoS};
+pub use crate::types::{Conformance, ConnectAckFlags, ConnectFlags, QoS};
@@ -40,6 +40,14 @@ impl ConnectAckReason {
             _ => "Connection Refused",
         }
     }
+
+    /// Connection is refused because of failed authentication or authorization
+    pub fn is_auth(self) -> bool {
+        matches!(
+            self,
+            ConnectAckReason::BadUserNameOrPassword | ConnectAckReason::NotAuthorized
+        )
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -1,8 +1,8 @@
-use ntex::util::ByteString;
-use std::{marker::PhantomData, num::NonZeroU16};
+use ntex::util::{ByteString, Bytes};
+use std::{any::Any, io, marker::PhantomData, num::NonZeroU16, rc::Rc};
 
 use super::codec;
-use crate::types::QoS;
+use crate::{types::QoS, utils::CloseState};
 
 #[derive(Debug)]
 pub enum ControlMessage {
@@ -14,6 +14,16 @@ pub enum ControlMessage {
     Subscribe(Subscribe),
     /// Unsubscribe packet
     Unsubscribe(Unsubscribe),
+    /// Publish release packet (assured delivery part 2)
+    PublishRelease(PublishRelease),
+    /// Will message of the connection must be published or discarded
+    Will(Will),
+    /// Keep-alive timeout, connection get closed unless keep-alive is extended
+    KeepAliveTimeout(KeepAliveTimeout),
+    /// Write buffer limit is exceeded, client does not read from connection
+    SlowConsumer(SlowConsumer),
+    /// Application event
+    Event(Event),
     /// Connection dropped
     Closed(Closed),
 }
@@ -27,10 +37,12 @@ pub struct ControlResult {
 pub(crate) enum ControlResultKind {
     Nothing,
     PublishAck(NonZeroU16),
+    PublishRelease(NonZeroU16),
     Ping,
     Disconnect,
     Subscribe(SubscribeResult),
     Unsubscribe(UnsubscribeResult),
+    KeepAlive,
     Closed,
 }
 
@@ -43,8 +55,28 @@ impl ControlMessage {
         ControlMessage::Disconnect(Disconnect)
     }
 
-    pub(crate) fn closed(is_error: bool) -> Self {
-        ControlMessage::Closed(Closed::new(is_error))
+    pub(crate) fn pkt_publish_release(packet_id: NonZeroU16) -> Self {
+        ControlMessage::PublishRelease(PublishRelease { packet_id })
+    }
+
+    pub(crate) fn will(will: codec::LastWill, triggered: bool) -> Self {
+        ControlMessage::Will(Will { will, triggered })
+    }
+
+    pub(crate) fn keepalive_timeout() -> Self {
+        ControlMessage::KeepAliveTimeout(KeepAliveTimeout)
+    }
+
+    pub(crate) fn slow_consumer() -> Self {
+        ControlMessage::SlowConsumer(SlowConsumer)
+    }
+
+    pub(crate) fn event(event: Event) -> Self {
+        ControlMessage::Event(event)
+    }
+
+    pub(crate) fn closed(is_error: bool, state: &CloseState) -> Self {
+        ControlMessage::Closed(Closed::new(is_error, state))
     }
 
     pub fn disconnect(&self) -> ControlResult {
@@ -70,12 +102,134 @@ impl Disconnect {
     }
 }
 
+/// Publish release message
+#[derive(Debug)]
+pub struct PublishRelease {
+    packet_id: NonZeroU16,
+}
+
+impl PublishRelease {
+    #[inline]
+    /// Packet identifier of the released publish
+    pub fn packet_id(&self) -> NonZeroU16 {
+        self.packet_id
+    }
+
+    #[inline]
+    /// convert packet to a result, server responds with PUBCOMP
+    pub fn ack(self) -> ControlResult {
+        ControlResult { result: ControlResultKind::PublishRelease(self.packet_id) }
+    }
+}
+
+/// Will message
+///
+/// Will message must be published if connection is closed without
+/// receiving DISCONNECT packet, otherwise it must be discarded.
+#[derive(Debug)]
+pub struct Will {
+    will: codec::LastWill,
+    triggered: bool,
+}
+
+impl Will {
+    #[inline]
+    /// Returns true if will message must be published
+    pub fn is_triggered(&self) -> bool {
+        self.triggered
+    }
+
+    #[inline]
+    /// Will topic
+    pub fn topic(&self) -> &ByteString {
+        &self.will.topic
+    }
+
+    #[inline]
+    /// Will message payload
+    pub fn message(&self) -> &Bytes {
+        &self.will.message
+    }
+
+    #[inline]
+    /// the QoS level to be used when publishing the Will Message
+    pub fn qos(&self) -> QoS {
+        self.will.qos
+    }
+
+    #[inline]
+    /// the Will Message is to be Retained when it is published
+    pub fn retain(&self) -> bool {
+        self.will.retain
+    }
+
+    #[inline]
+    /// Returns reference to will message
+    pub fn packet(&self) -> &codec::LastWill {
+        &self.will
+    }
+
+    #[inline]
+    /// Consume will and return will message
+    pub fn into_inner(self) -> codec::LastWill {
+        self.will
+    }
+
+    #[inline]
+    /// convert packet to a result
+    pub fn ack(self) -> ControlResult {
+        ControlResult { result: ControlResultKind::Nothing }
+    }
+}
+
+/// Keep-alive timeout message
+///
+/// Client did not send any packet within keep-alive period.
+#[derive(Debug)]
+pub struct KeepAliveTimeout;
+
+impl KeepAliveTimeout {
+    #[inline]
+    /// convert packet to a result, connection get closed
+    pub fn ack(self) -> ControlResult {
+        ControlResult { result: ControlResultKind::Disconnect }
+    }
+
+    #[inline]
+    /// Keep connection open for one more keep-alive period
+    pub fn extend(self) -> ControlResult {
+        ControlResult { result: ControlResultKind::KeepAlive }
+    }
+}
+
+/// Slow consumer message
+///
+/// Client does not read from connection and write buffer size
+/// exceeds configured limit.
+#[derive(Debug)]
+pub struct SlowConsumer;
+
+impl SlowConsumer {
+    #[inline]
+    /// convert packet to a result, connection get closed
+    pub fn ack(self) -> ControlResult {
+        ControlResult { result: ControlResultKind::Disconnect }
+    }
+
+    #[inline]
+    /// Keep connection open for one more timeout period
+    pub fn extend(self) -> ControlResult {
+        ControlResult { result: ControlResultKind::KeepAlive }
+    }
+}
+
 /// Subscribe message
 #[derive(Debug)]
 pub struct Subscribe {
     packet_id: NonZeroU16,
     topics: Vec<(ByteString, QoS)>,
     codes: Vec<codec::SubscribeReturnCode>,
+    rejected: Vec<bool>,
 }
 
 /// Result of a subscribe message
@@ -90,7 +244,14 @@ impl Subscribe {
         let mut codes = Vec::with_capacity(topics.len());
         (0..topics.len()).for_each(|_| codes.push(codec::SubscribeReturnCode::Failure));
 
-        Self { topics, codes, packet_id }
+        Self { topics, codes, packet_id, rejected: Vec::new() }
+    }
+
+    /// Reject topic filter, rejected filters are skipped by iterator
+    pub(crate) fn reject(&mut self, idx: usize) {
+        self.rejected.resize(self.topics.len(), false);
+        self.rejected[idx] = true;
+        self.codes[idx] = codec::SubscribeReturnCode::Failure;
     }
 
     #[inline]
@@ -99,6 +260,20 @@ impl Subscribe {
         SubscribeIter { subs: self as *const _ as *mut _, entry: 0, lt: PhantomData }
     }
 
+    #[inline]
+    /// Set return code for each topic filter and convert subscription to a result
+    ///
+    /// Topic filters rejected by authorization provider are skipped.
+    pub fn ack_with<F>(mut self, mut f: F) -> ControlResult
+    where
+        F: FnMut(&ByteString, QoS) -> codec::SubscribeReturnCode,
+    {
+        for mut sub in &mut self {
+            *sub.code = f(sub.topic(), sub.qos());
+        }
+        self.ack()
+    }
+
     #[inline]
     /// convert subscription to a result
     pub fn ack(self) -> ControlResult {
@@ -131,6 +306,11 @@ impl<'a> SubscribeIter<'a> {
     fn next_unsafe(&mut self) -> Option<Subscription<'a>> {
         let subs = unsafe { &mut *self.subs };
 
+        // skip topic filters rejected by authorization provider
+        while subs.rejected.get(self.entry).copied().unwrap_or(false) {
+            self.entry += 1;
+        }
+
         if self.entry < subs.topics.len() {
             let s = Subscription {
                 topic: &subs.topics[self.entry].0,
@@ -187,6 +367,22 @@ impl<'a> Subscription<'a> {
         *self.code = codec::SubscribeReturnCode::Success(qos)
     }
 
+    #[inline]
+    /// confirm subscription with requested qos downgraded to `max_qos`
+    pub fn grant(&mut self, max_qos: QoS) {
+        if u8::from(max_qos) < u8::from(self.qos) {
+            self.confirm(max_qos)
+        } else {
+            self.confirm(self.qos)
+        }
+    }
+
+    #[inline]
+    /// current return code of the topic filter
+    pub fn code(&self) -> codec::SubscribeReturnCode {
+        *self.code
+    }
+
     #[inline]
     #[doc(hidden)]
     /// confirm subscription to a topic with specific qos
@@ -229,15 +425,54 @@ impl Unsubscribe {
     }
 }
 
+/// Application event
+///
+/// Event is pushed to connection's control service with `EventSender`,
+/// see `MqttSink::events()`.
+#[derive(Debug)]
+pub struct Event(Box<dyn Any>);
+
+impl Event {
+    pub(super) fn new<T: 'static>(event: T) -> Self {
+        Event(Box::new(event))
+    }
+
+    #[inline]
+    /// Returns true if event is of type `T`
+    pub fn is<T: 'static>(&self) -> bool {
+        self.0.is::<T>()
+    }
+
+    #[inline]
+    /// Returns reference to event if it is of type `T`
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.0.downcast_ref()
+    }
+
+    /// Consume message and return event if it is of type `T`
+    pub fn into_inner<T: 'static>(self) -> Result<T, Self> {
+        self.0.downcast().map(|ev| *ev).map_err(Event)
+    }
+
+    #[inline]
+    /// convert event to a result
+    pub fn ack(self) -> ControlResult {
+        ControlResult { result: ControlResultKind::Nothing }
+    }
+}
+
 /// Connection closed message
 #[derive(Debug)]
 pub struct Closed {
     is_error: bool,
+    peer: bool,
+    io_error: Option<Rc<io::Error>>,
 }
 
 impl Closed {
-    pub(crate) fn new(is_error: bool) -> Self {
-        Self { is_error }
+    pub(crate) fn new(is_error: bool, state: &CloseState) -> Self {
+        let (peer, io_error) = state.take(is_error);
+        Self { is_error, peer, io_error }
     }
 
     /// Returns error state on connection close
@@ -245,6 +480,18 @@ impl Closed {
         self.is_error
     }
 
+    /// Returns true if connection is closed by the peer
+    ///
+    /// Peer sent DISCONNECT packet, closed the transport, or transport failed.
+    pub fn is_peer_initiated(&self) -> bool {
+        self.peer
+    }
+
+    /// Returns transport error, if connection is closed because of io error
+    pub fn io_error(&self) -> Option<&io::Error> {
+        self.io_error.as_deref()
+    }
+
     #[inline]
     /// convert packet to a result
     pub fn ack(self) -> ControlResult {
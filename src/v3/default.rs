@@ -98,6 +98,11 @@ impl<S, E> Service for DefaultControlService<S, E> {
                 log::warn!("MQTT Unsubscribe is not supported");
                 unsubs.ack()
             }
+            ControlMessage::PublishRelease(msg) => msg.ack(),
+            ControlMessage::Will(msg) => msg.ack(),
+            ControlMessage::KeepAliveTimeout(msg) => msg.ack(),
+            ControlMessage::SlowConsumer(msg) => msg.ack(),
+            ControlMessage::Event(msg) => msg.ack(),
             ControlMessage::Closed(msg) => msg.ack(),
         })
     }
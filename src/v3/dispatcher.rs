@@ -3,23 +3,32 @@ use std::task::{Context, Poll};
 use std::{future::Future, marker::PhantomData, num::NonZeroU16, pin::Pin, rc::Rc};
 
 use ntex::service::{fn_factory_with_config, Service, ServiceFactory};
-use ntex::util::{inflight::InFlightService, join, Either, HashSet, Ready};
+use ntex::util::{inflight::InFlightService, join, ByteString, Either, HashSet, Ready};
 
-use crate::error::MqttError;
+use crate::error::{MqttError, ProtocolError};
+use crate::io::DispatchItem;
+use crate::ratelimit::{PublishLimiter, PublishRate};
+use crate::trace::Traced;
 
+use super::acl::{AclControl, AclPublish, AclRef};
 use super::control::{
     ControlMessage, ControlResult, ControlResultKind, Subscribe, Unsubscribe,
 };
-use super::{codec, publish::Publish, shared::Ack, sink::MqttSink, Session};
+use super::shared::{Ack, MqttShared};
+use super::{codec, publish::Publish, sink::MqttSink, Session};
+use crate::types::QoS;
 
 /// mqtt3 protocol dispatcher
 pub(super) fn factory<St, T, C, E>(
     publish: T,
     control: C,
     inflight: usize,
+    rate: PublishRate,
+    acl: Option<AclRef<St>>,
+    stream_threshold: u32,
 ) -> impl ServiceFactory<
     Config = Session<St>,
-    Request = codec::Packet,
+    Request = DispatchItem<Rc<MqttShared>>,
     Response = Option<codec::Packet>,
     Error = MqttError<E>,
     InitError = MqttError<E>,
@@ -43,17 +52,26 @@ where
         > + 'static,
 {
     fn_factory_with_config(move |cfg: Session<St>| {
+        cfg.sink().shared().codec.set_stream_threshold(stream_threshold);
+
         // create services
         let fut = join(publish.new_service(cfg.clone()), control.new_service(cfg.clone()));
+        let acl = acl.clone();
+        let span = cfg.sink().shared().span.borrow().clone();
 
         async move {
             let (publish, control) = fut.await;
+            let publish = AclPublish::new(publish?, acl.clone(), cfg.clone());
+            let control = AclControl::new(control?, acl, cfg.clone());
 
             Ok(
                 // limit number of in-flight messages
                 InFlightService::new(
                     inflight,
-                    Dispatcher::<_, _, _, E>::new(cfg, publish?, control?),
+                    Traced::new(
+                        Dispatcher::<_, _, _, E>::new(cfg, publish, control, rate.limiter()),
+                        span,
+                    ),
                 ),
             )
         }
@@ -61,17 +79,23 @@ where
 }
 
 /// Mqtt protocol dispatcher
-pub(crate) struct Dispatcher<St, T: Service<Error = MqttError<E>>, C, E> {
+pub(crate) struct Dispatcher<St, T: Service<Error = MqttError<E>>, C: Service, E> {
     session: Session<St>,
     publish: T,
     control: C,
+    // in-flight application event
+    event: RefCell<Option<Pin<Box<C::Future>>>>,
     shutdown: Cell<bool>,
+    disconnected: Cell<bool>,
+    limiter: Option<PublishLimiter>,
     inner: Rc<Inner>,
 }
 
 struct Inner {
     sink: MqttSink,
     inflight: RefCell<HashSet<NonZeroU16>>,
+    // QoS 2 publishes acknowledged with PUBREC, awaiting PUBREL
+    released: RefCell<HashSet<NonZeroU16>>,
 }
 
 impl<St, T, C, E> Dispatcher<St, T, C, E>
@@ -79,15 +103,74 @@ where
     T: Service<Request = Publish, Response = (), Error = MqttError<E>>,
     C: Service<Request = ControlMessage, Response = ControlResult, Error = MqttError<E>>,
 {
-    pub(crate) fn new(session: Session<St>, publish: T, control: C) -> Self {
+    pub(crate) fn new(
+        session: Session<St>,
+        publish: T,
+        control: C,
+        limiter: Option<PublishLimiter>,
+    ) -> Self {
         let sink = session.sink().clone();
 
+        // not released qos 2 publishes of restored session
+        let mut released = HashSet::default();
+        sink.shared().with_store(|store| released.extend(store.take_received()));
+
+        // application events are handled by dispatcher
+        sink.shared().events.open();
+
         Self {
             session,
             publish,
             control,
+            event: RefCell::new(None),
             shutdown: Cell::new(false),
-            inner: Rc::new(Inner { sink, inflight: RefCell::new(HashSet::default()) }),
+            disconnected: Cell::new(false),
+            limiter,
+            inner: Rc::new(Inner {
+                sink,
+                inflight: RefCell::new(HashSet::default()),
+                released: RefCell::new(released),
+            }),
+        }
+    }
+
+    /// Handle queued application events
+    ///
+    /// Events are handled one at a time, next event is passed to control
+    /// service when it is ready. Returns true if control service is called.
+    fn poll_event(&self, cx: &mut Context<'_>, ready: bool) -> Result<bool, MqttError<E>> {
+        let mut event = self.event.borrow_mut();
+        if let Some(fut) = event.as_mut() {
+            match fut.as_mut().poll(cx) {
+                Poll::Ready(result) => {
+                    *event = None;
+                    self.event_result(result?);
+                }
+                Poll::Pending => return Ok(false),
+            }
+        }
+
+        if !ready {
+            return Ok(false);
+        }
+        if let Some(ev) = self.inner.sink.shared().events.poll_next(cx) {
+            let mut fut = Box::pin(self.control.call(ControlMessage::event(ev)));
+            if let Poll::Ready(result) = fut.as_mut().poll(cx) {
+                self.event_result(result?);
+                // handle next queued event
+                cx.waker().wake_by_ref();
+            } else {
+                *event = Some(fut);
+            }
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn event_result(&self, result: ControlResult) {
+        if let ControlResultKind::Disconnect = result.result {
+            self.inner.sink.close();
         }
     }
 }
@@ -99,7 +182,7 @@ where
     C::Future: 'static,
     E: 'static,
 {
-    type Request = codec::Packet;
+    type Request = DispatchItem<Rc<MqttShared>>;
     type Response = Option<codec::Packet>;
     type Error = MqttError<E>;
     type Future = Either<
@@ -109,9 +192,34 @@ where
 
     fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         let res1 = self.publish.poll_ready(cx)?;
-        let res2 = self.control.poll_ready(cx)?;
+        let mut res2 = self.control.poll_ready(cx)?;
+
+        // application events, control service readiness is checked again
+        // after event is passed to it
+        if self.poll_event(cx, res2.is_ready())? {
+            res2 = self.control.poll_ready(cx)?;
+        }
+
+        // inbound publish rate limit
+        let res3 = self.limiter.as_ref().map_or(Poll::Ready(()), |l| l.poll_ready(cx));
 
-        if res1.is_pending() || res2.is_pending() {
+        // paused by application
+        let shared = self.inner.sink.shared();
+        let res4 = shared.read_pause.poll_ready(&shared.state, cx);
+
+        // payload stream of streamed publish is not consumed
+        let res5 = if shared.state.is_open() {
+            shared.codec.poll_payload_ready(cx)
+        } else {
+            Poll::Ready(())
+        };
+
+        if res1.is_pending()
+            || res2.is_pending()
+            || res3.is_pending()
+            || res4.is_pending()
+            || res5.is_pending()
+        {
             Poll::Pending
         } else {
             Poll::Ready(Ok(()))
@@ -120,25 +228,65 @@ where
 
     fn poll_shutdown(&self, _: &mut Context<'_>, is_error: bool) -> Poll<()> {
         if !self.shutdown.get() {
+            let closed = ControlMessage::closed(is_error, &self.inner.sink.shared().close);
             self.inner.sink.close();
             self.shutdown.set(true);
-            let fut = self.control.call(ControlMessage::closed(is_error));
+            self.inner.sink.shared().events.close();
+            self.event.borrow_mut().take();
+            self.inner.sink.shared().with_store(|store| store.closed());
+            self.inner.sink.shared().connection.borrow_mut().take();
+            if let Some((registry, client_id)) =
+                self.inner.sink.shared().registry.borrow_mut().take()
+            {
+                registry.unregister(&client_id, self.inner.sink.shared());
+            }
+            self.inner.sink.shared().pool.metrics.connection_closed();
+
+            // will message is discarded if DISCONNECT packet is received
+            let will = self.inner.sink.shared().last_will.borrow_mut().take().map(|will| {
+                self.control.call(ControlMessage::will(will, !self.disconnected.get()))
+            });
+            let fut = self.control.call(closed);
             ntex::rt::spawn(async move {
+                if let Some(will) = will {
+                    let _ = will.await;
+                }
                 let _ = fut.await;
             });
         }
         Poll::Ready(())
     }
 
-    fn call(&self, packet: codec::Packet) -> Self::Future {
-        log::trace!("Dispatch packet: {:#?}", packet);
-        match packet {
-            codec::Packet::Publish(publish) => {
+    fn call(&self, request: Self::Request) -> Self::Future {
+        log::trace!("Dispatch packet: {:#?}", request);
+        match request {
+            DispatchItem::Item(codec::Packet::Publish(publish)) => {
                 let inner = self.inner.clone();
                 let packet_id = publish.packet_id;
+                let qos = publish.qos;
+                let stream = self.session.sink().shared().codec.take_payload();
+
+                // check inbound publish rate
+                if let Some(ref limiter) = self.limiter {
+                    let size =
+                        stream.as_ref().map(|s| s.size()).unwrap_or(publish.payload.len());
+                    if !limiter.publish(size) {
+                        log::trace!("Inbound publish rate is exceeded");
+                        return Either::Right(Either::Left(Ready::Err(MqttError::Protocol(
+                            ProtocolError::PublishRateExceeded,
+                        ))));
+                    }
+                }
 
                 // check for duplicated packet id
                 if let Some(pid) = packet_id {
+                    // re-delivery of qos2 publish, message is already delivered
+                    if qos == QoS::ExactlyOnce && inner.released.borrow().contains(&pid) {
+                        log::trace!("Re-delivered publish packet with qos2: {:?}", pid);
+                        return Either::Right(Either::Left(Ready::Ok(Some(
+                            codec::Packet::PublishReceived { packet_id: pid },
+                        ))));
+                    }
                     if !inner.inflight.borrow_mut().insert(pid) {
                         log::trace!("Duplicated packet id for publish packet: {:?}", pid);
                         return Either::Right(Either::Left(Ready::Err(
@@ -146,66 +294,152 @@ where
                         )));
                     }
                 }
+                let mut publish = Publish::new(publish);
+                publish.set_payload_stream(stream);
+
                 Either::Left(PublishResponse {
+                    qos,
                     packet_id,
                     inner,
-                    fut: self.publish.call(Publish::new(publish)),
+                    fut: self.publish.call(publish),
                     _t: PhantomData,
                 })
             }
-            codec::Packet::PublishAck { packet_id } => {
+            DispatchItem::Item(codec::Packet::PublishAck { packet_id }) => {
                 if let Err(e) = self.session.sink().pkt_ack(Ack::Publish(packet_id)) {
                     Either::Right(Either::Left(Ready::Err(MqttError::Protocol(e))))
                 } else {
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
-            codec::Packet::PingRequest => Either::Right(Either::Right(ControlResponse::new(
-                self.control.call(ControlMessage::ping()),
-                &self.inner,
-            ))),
-            codec::Packet::Disconnect => Either::Right(Either::Right(ControlResponse::new(
-                self.control.call(ControlMessage::pkt_disconnect()),
-                &self.inner,
-            ))),
-            codec::Packet::Subscribe { packet_id, topic_filters } => {
+            DispatchItem::Item(codec::Packet::PublishReceived { packet_id }) => {
+                if let Err(e) = self.session.sink().pkt_ack(Ack::Receive(packet_id)) {
+                    Either::Right(Either::Left(Ready::Err(MqttError::Protocol(e))))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishComplete { packet_id }) => {
+                if let Err(e) = self.session.sink().pkt_ack(Ack::Complete(packet_id)) {
+                    Either::Right(Either::Left(Ready::Err(MqttError::Protocol(e))))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishRelease { packet_id }) => {
+                if self.inner.released.borrow_mut().remove(&packet_id) {
+                    self.inner.sink.shared().with_store(|store| store.release(packet_id));
+                    Either::Right(Either::Right(ControlResponse::new(
+                        self.control.call(ControlMessage::pkt_publish_release(packet_id)),
+                        &self.inner,
+                    )))
+                } else {
+                    // unknown packet id, publish is already released
+                    log::trace!(
+                        "Unknown packet id for publish release packet: {:?}",
+                        packet_id
+                    );
+                    Either::Right(Either::Left(Ready::Ok(Some(
+                        codec::Packet::PublishComplete { packet_id },
+                    ))))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PingRequest) => Either::Right(Either::Right(
+                ControlResponse::new(self.control.call(ControlMessage::ping()), &self.inner),
+            )),
+            DispatchItem::Item(codec::Packet::Disconnect) => {
+                self.disconnected.set(true);
+                self.inner.sink.shared().close.peer();
+                Either::Right(Either::Right(ControlResponse::new(
+                    self.control.call(ControlMessage::pkt_disconnect()),
+                    &self.inner,
+                )))
+            }
+            DispatchItem::Item(codec::Packet::Subscribe { packet_id, topic_filters }) => {
                 if !self.inner.inflight.borrow_mut().insert(packet_id) {
                     log::trace!("Duplicated packet id for unsubscribe packet: {:?}", packet_id);
                     return Either::Right(Either::Left(Ready::Err(MqttError::V3ProtocolError)));
                 }
 
-                Either::Right(Either::Right(ControlResponse::new(
-                    self.control.call(ControlMessage::Subscribe(Subscribe::new(
-                        packet_id,
-                        topic_filters,
-                    ))),
-                    &self.inner,
-                )))
+                let filters = stored_filters(&self.inner, topic_filters.iter().map(|f| &f.0));
+                Either::Right(Either::Right(
+                    ControlResponse::new(
+                        self.control.call(ControlMessage::Subscribe(Subscribe::new(
+                            packet_id,
+                            topic_filters,
+                        ))),
+                        &self.inner,
+                    )
+                    .filters(filters),
+                ))
             }
-            codec::Packet::Unsubscribe { packet_id, topic_filters } => {
+            DispatchItem::Item(codec::Packet::Unsubscribe { packet_id, topic_filters }) => {
                 if !self.inner.inflight.borrow_mut().insert(packet_id) {
                     log::trace!("Duplicated packet id for unsubscribe packet: {:?}", packet_id);
                     return Either::Right(Either::Left(Ready::Err(MqttError::V3ProtocolError)));
                 }
 
+                let filters = stored_filters(&self.inner, topic_filters.iter());
+                Either::Right(Either::Right(
+                    ControlResponse::new(
+                        self.control.call(ControlMessage::Unsubscribe(Unsubscribe::new(
+                            packet_id,
+                            topic_filters,
+                        ))),
+                        &self.inner,
+                    )
+                    .filters(filters),
+                ))
+            }
+            DispatchItem::Item(_) => Either::Right(Either::Left(Ready::Ok(None))),
+            DispatchItem::KeepAliveTimeout => Either::Right(Either::Right(
+                ControlResponse::new(
+                    self.control.call(ControlMessage::keepalive_timeout()),
+                    &self.inner,
+                )
+                .keepalive(),
+            )),
+            DispatchItem::EncoderError(err) => Either::Right(Either::Left(Ready::Err(
+                MqttError::Protocol(ProtocolError::Encode(err)),
+            ))),
+            DispatchItem::DecoderError(err) => Either::Right(Either::Left(Ready::Err(
+                MqttError::Protocol(ProtocolError::Decode(err)),
+            ))),
+            DispatchItem::IoError(err) => {
+                let err = self.inner.sink.shared().close.io_error(err);
+                Either::Right(Either::Left(Ready::Err(MqttError::Protocol(ProtocolError::Io(
+                    err,
+                )))))
+            }
+            DispatchItem::WBackPressureEnabled => {
                 Either::Right(Either::Right(ControlResponse::new(
-                    self.control.call(ControlMessage::Unsubscribe(Unsubscribe::new(
-                        packet_id,
-                        topic_filters,
-                    ))),
+                    self.control.call(ControlMessage::slow_consumer()),
                     &self.inner,
                 )))
             }
-            _ => Either::Right(Either::Left(Ready::Ok(None))),
+            DispatchItem::WBackPressureDisabled => Either::Right(Either::Left(Ready::Ok(None))),
         }
     }
 }
 
+/// Topic filters of subscribe and unsubscribe packets, if session store is set
+fn stored_filters<'a, I>(inner: &Inner, filters: I) -> Option<Vec<ByteString>>
+where
+    I: Iterator<Item = &'a ByteString>,
+{
+    if inner.sink.shared().store.borrow().is_some() {
+        Some(filters.cloned().collect())
+    } else {
+        None
+    }
+}
+
 pin_project_lite::pin_project! {
     /// Publish service response future
     pub(crate) struct PublishResponse<T, E> {
         #[pin]
         fut: T,
+        qos: QoS,
         packet_id: Option<NonZeroU16>,
         inner: Rc<Inner>,
         _t: PhantomData<E>,
@@ -230,7 +464,13 @@ where
 
         if let Some(packet_id) = this.packet_id {
             this.inner.inflight.borrow_mut().remove(&packet_id);
-            Poll::Ready(Ok(Some(codec::Packet::PublishAck { packet_id: *packet_id })))
+            if *this.qos == QoS::ExactlyOnce {
+                this.inner.released.borrow_mut().insert(*packet_id);
+                this.inner.sink.shared().with_store(|store| store.store_received(*packet_id));
+                Poll::Ready(Ok(Some(codec::Packet::PublishReceived { packet_id: *packet_id })))
+            } else {
+                Poll::Ready(Ok(Some(codec::Packet::PublishAck { packet_id: *packet_id })))
+            }
         } else {
             Poll::Ready(Ok(None))
         }
@@ -246,6 +486,8 @@ pin_project_lite::pin_project! {
         #[pin]
         fut: T,
         inner: Rc<Inner>,
+        filters: Option<Vec<ByteString>>,
+        keepalive: bool,
     }
 }
 
@@ -254,7 +496,18 @@ where
     T: Future<Output = Result<ControlResult, MqttError<E>>>,
 {
     fn new(fut: T, inner: &Rc<Inner>) -> Self {
-        Self { fut, inner: inner.clone() }
+        Self { fut, inner: inner.clone(), filters: None, keepalive: false }
+    }
+
+    fn filters(mut self, filters: Option<Vec<ByteString>>) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    /// Response for keep-alive timeout message
+    fn keepalive(mut self) -> Self {
+        self.keepalive = true;
+        self
     }
 }
 
@@ -268,10 +521,34 @@ where
         let this = self.project();
 
         let packet = match this.fut.poll(cx)? {
+            // keep-alive timeout, connection is closed unless it is extended
+            Poll::Ready(item) if *this.keepalive => match item.result {
+                ControlResultKind::KeepAlive => {
+                    log::trace!("Keep-alive is extended");
+                    None
+                }
+                _ => {
+                    return Poll::Ready(Err(MqttError::Protocol(
+                        ProtocolError::KeepAliveTimeout,
+                    )))
+                }
+            },
             Poll::Ready(item) => match item.result {
                 ControlResultKind::Ping => Some(codec::Packet::PingResponse),
+                ControlResultKind::PublishRelease(packet_id) => {
+                    Some(codec::Packet::PublishComplete { packet_id })
+                }
                 ControlResultKind::Subscribe(res) => {
                     this.inner.inflight.borrow_mut().remove(&res.packet_id);
+                    if let Some(filters) = this.filters.take() {
+                        this.inner.sink.shared().with_store(|store| {
+                            for (filter, code) in filters.iter().zip(res.codes.iter()) {
+                                if let codec::SubscribeReturnCode::Success(qos) = code {
+                                    store.subscribe(filter, *qos);
+                                }
+                            }
+                        });
+                    }
                     Some(codec::Packet::SubscribeAck {
                         status: res.codes,
                         packet_id: res.packet_id,
@@ -279,8 +556,14 @@ where
                 }
                 ControlResultKind::Unsubscribe(res) => {
                     this.inner.inflight.borrow_mut().remove(&res.packet_id);
+                    if let Some(filters) = this.filters.take() {
+                        this.inner.sink.shared().with_store(|store| {
+                            filters.iter().for_each(|filter| store.unsubscribe(filter))
+                        });
+                    }
                     Some(codec::Packet::UnsubscribeAck { packet_id: res.packet_id })
                 }
+                ControlResultKind::KeepAlive => None,
                 ControlResultKind::Disconnect
                 | ControlResultKind::Closed
                 | ControlResultKind::Nothing => {
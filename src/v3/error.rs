@@ -23,7 +23,24 @@ pub enum ClientError {
     Connect(ntex::connect::ConnectError),
 }
 
-impl std::error::Error for ClientError {}
+impl ClientError {
+    /// Connection is refused because of failed authentication or authorization
+    pub fn is_auth(&self) -> bool {
+        match self {
+            ClientError::Ack { return_code, .. } => return_code.is_auth(),
+            _ => false,
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Protocol(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 impl From<Either<EncodeError, std::io::Error>> for ClientError {
     fn from(err: Either<EncodeError, std::io::Error>) -> Self {
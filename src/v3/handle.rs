@@ -0,0 +1,113 @@
+//! Thread-safe handle for mqtt sink
+use std::{future::Future, rc::Rc};
+
+use futures_channel::oneshot;
+use ntex::util::{ByteString, Bytes};
+
+use super::{codec, error::SendPacketError, shared::MqttShared, sink::MqttSink};
+use crate::handle::{Handle, HandleSink};
+use crate::io::State;
+
+type Tx<T> = oneshot::Sender<Result<T, SendPacketError>>;
+
+pub(crate) enum Request {
+    Publish(ByteString, Bytes, codec::QoS, Tx<()>),
+    Subscribe(Vec<(ByteString, codec::QoS)>, Tx<Vec<codec::SubscribeReturnCode>>),
+    Unsubscribe(Vec<ByteString>, Tx<()>),
+}
+
+/// Thread-safe handle for `MqttSink`
+///
+/// Handle is `Send` and `Sync`, requests are forwarded to the connection's
+/// thread and get executed by connection's sink. Handle does not keep connection
+/// alive, requests fail with `Disconnected` error after connection is closed.
+#[derive(Clone)]
+pub struct MqttSinkHandle(Handle<Request>);
+
+impl MqttSinkHandle {
+    /// Spawn forwarding task on current thread, must be called on connection's thread
+    pub(super) fn new(shared: &Rc<MqttShared>) -> Self {
+        MqttSinkHandle(Handle::new(shared))
+    }
+
+    /// Check if connection's thread accepts requests
+    pub fn is_closed(&self) -> bool {
+        self.0.is_closed()
+    }
+
+    /// Publish message, future resolves when publish is acknowledged by the peer
+    pub fn publish(
+        &self,
+        topic: ByteString,
+        payload: Bytes,
+        qos: codec::QoS,
+    ) -> impl Future<Output = Result<(), SendPacketError>> + Send {
+        let (tx, rx) = oneshot::channel();
+        self.0.request(Request::Publish(topic, payload, qos, tx), rx)
+    }
+
+    /// Subscribe to topic filters, future resolves with SUBACK return codes
+    pub fn subscribe(
+        &self,
+        filters: Vec<(ByteString, codec::QoS)>,
+    ) -> impl Future<Output = Result<Vec<codec::SubscribeReturnCode>, SendPacketError>> + Send
+    {
+        let (tx, rx) = oneshot::channel();
+        self.0.request(Request::Subscribe(filters, tx), rx)
+    }
+
+    /// Unsubscribe from topic filters
+    pub fn unsubscribe(
+        &self,
+        filters: Vec<ByteString>,
+    ) -> impl Future<Output = Result<(), SendPacketError>> + Send {
+        let (tx, rx) = oneshot::channel();
+        self.0.request(Request::Unsubscribe(filters, tx), rx)
+    }
+
+    /// Close mqtt connection after queued requests
+    pub fn close(&self) {
+        self.0.close()
+    }
+}
+
+impl HandleSink for MqttShared {
+    type Request = Request;
+
+    fn io_state(&self) -> &State {
+        &self.state
+    }
+
+    fn execute(self: Rc<Self>, req: Request) {
+        let sink = MqttSink::new(self);
+
+        ntex::rt::spawn(async move {
+            match req {
+                Request::Publish(topic, payload, qos, tx) => {
+                    let builder = sink.publish(topic, payload);
+                    let res = match qos {
+                        codec::QoS::AtMostOnce => builder.send_at_most_once(),
+                        codec::QoS::AtLeastOnce => builder.send_at_least_once().await,
+                        codec::QoS::ExactlyOnce => builder.send_exactly_once().await,
+                    };
+                    let _ = tx.send(res);
+                }
+                Request::Subscribe(filters, tx) => {
+                    let builder = filters
+                        .into_iter()
+                        .fold(sink.subscribe(), |b, (f, qos)| b.topic_filter(f, qos));
+                    let _ = tx.send(builder.send().await);
+                }
+                Request::Unsubscribe(filters, tx) => {
+                    let builder =
+                        filters.into_iter().fold(sink.unsubscribe(), |b, f| b.topic_filter(f));
+                    let _ = tx.send(builder.send().await);
+                }
+            }
+        });
+    }
+
+    fn close(self: Rc<Self>) {
+        MqttSink::new(self).close()
+    }
+}
@@ -1,19 +1,28 @@
-use std::{fmt, rc::Rc};
+use std::{fmt, net::SocketAddr, rc::Rc};
+
+use ntex::util::Bytes;
 
 use super::codec as mqtt;
 use super::shared::MqttShared;
 use super::sink::MqttSink;
+use super::store::SessionState;
 
 /// Connect message
 pub struct Handshake<Io> {
     io: Io,
     pkt: mqtt::Connect,
     shared: Rc<MqttShared>,
+    session_state: Option<SessionState>,
 }
 
 impl<Io> Handshake<Io> {
-    pub(crate) fn new(pkt: mqtt::Connect, io: Io, shared: Rc<MqttShared>) -> Self {
-        Self { pkt, io, shared }
+    pub(crate) fn new(
+        pkt: mqtt::Connect,
+        io: Io,
+        shared: Rc<MqttShared>,
+        session_state: Option<SessionState>,
+    ) -> Self {
+        Self { pkt, io, shared, session_state }
     }
 
     pub fn packet(&self) -> &mqtt::Connect {
@@ -24,6 +33,55 @@ impl<Io> Handshake<Io> {
         &mut self.pkt
     }
 
+    #[inline]
+    /// Returns true if client requested clean session
+    ///
+    /// If clean session is requested, server must discard any previous session
+    /// and session present flag is always set to false.
+    pub fn clean_session(&self) -> bool {
+        self.pkt.clean_session
+    }
+
+    #[inline]
+    /// Returns will message of the connection, if any
+    pub fn last_will(&self) -> Option<&mqtt::LastWill> {
+        self.pkt.last_will.as_ref()
+    }
+
+    #[inline]
+    /// Returns stored session state, if server session store is set
+    /// and session of the client exists
+    pub fn session_state(&self) -> Option<&SessionState> {
+        self.session_state.as_ref()
+    }
+
+    #[inline]
+    /// Returns peer address of the connection
+    ///
+    /// If PROXY protocol is enabled, original client address is returned.
+    /// Address is available for tcp and tls over tcp transports.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.shared.peer_addr.get()
+    }
+
+    #[inline]
+    /// Returns local address of the connection
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.shared.local_addr.get()
+    }
+
+    /// Returns DER encoded certificate chain of the client
+    ///
+    /// Certificate chain is available for tls over tcp connections accepted
+    /// with `openssl` or `rustls` acceptors, if client provided certificate.
+    /// End-entity certificate goes first. Requires `openssl` or `rustls` feature.
+    pub fn peer_certificates(&self) -> Option<Vec<Bytes>>
+    where
+        Io: 'static,
+    {
+        crate::utils::io_peer_certificates(&self.io)
+    }
+
     #[inline]
     pub fn io(&mut self) -> &mut Io {
         &mut self.io
@@ -130,6 +188,15 @@ pub struct HandshakeAck<Io, St> {
 }
 
 impl<Io, St> HandshakeAck<Io, St> {
+    /// Set session present flag
+    ///
+    /// Session present flag must be set if server has stored session state
+    /// for the client. Flag is ignored if client requested clean session.
+    pub fn session_present(mut self, val: bool) -> Self {
+        self.session_present = val;
+        self
+    }
+
     /// Set idle time-out for the connection in seconds
     ///
     /// By default idle time-out is set to 30 seconds.
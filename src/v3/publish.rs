@@ -3,16 +3,19 @@ use std::num::NonZeroU16;
 
 use ntex::router::Path;
 use ntex::util::{ByteString, Bytes};
+#[cfg(any(feature = "json", feature = "cbor"))]
 use serde::de::DeserializeOwned;
+#[cfg(feature = "json")]
 use serde_json::Error as JsonError;
 
-use crate::v3::codec;
+use crate::{params::Params, payload::Payload, v3::codec};
 
 /// Publish message
 pub struct Publish {
     publish: codec::Publish,
     topic: Path<ByteString>,
     query: Option<ByteString>,
+    stream: Option<Payload>,
 }
 
 impl Publish {
@@ -31,7 +34,7 @@ impl Publish {
             (publish.topic.clone(), None)
         };
         let topic = Path::new(topic);
-        Self { publish, topic, query }
+        Self { publish, topic, query, stream: None }
     }
 
     #[inline]
@@ -73,6 +76,12 @@ impl Publish {
         &mut self.topic
     }
 
+    #[inline]
+    /// Typed access to parameters of matched router resource
+    pub fn params(&self) -> Params<'_> {
+        Params::new(&self.topic)
+    }
+
     #[inline]
     pub fn query(&self) -> &str {
         self.query.as_ref().map(|s| s.as_ref()).unwrap_or("")
@@ -99,11 +108,38 @@ impl Publish {
         self.publish.payload.clone()
     }
 
+    #[inline]
+    /// Check if payload is delivered as a stream
+    pub fn is_streamed(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Take payload stream of large publish packet
+    ///
+    /// Payload of publish packets larger than server's stream threshold is
+    /// delivered via stream while packet is still being received, `payload()`
+    /// is empty for such packets. Received chunks are buffered until they get
+    /// consumed, dropping the stream discards rest of the payload.
+    pub fn take_payload_stream(&mut self) -> Option<Payload> {
+        self.stream.take()
+    }
+
+    pub(crate) fn set_payload_stream(&mut self, stream: Option<Payload>) {
+        self.stream = stream;
+    }
+
+    #[cfg(feature = "json")]
     /// Loads and parse `application/json` encoded body.
     pub fn json<T: DeserializeOwned>(&mut self) -> Result<T, JsonError> {
         serde_json::from_slice(&self.publish.payload)
     }
 
+    #[cfg(feature = "cbor")]
+    /// Loads and parse `application/cbor` encoded body.
+    pub fn cbor<T: DeserializeOwned>(&mut self) -> Result<T, serde_cbor::Error> {
+        serde_cbor::from_slice(&self.publish.payload)
+    }
+
     pub(super) fn into_inner(self) -> codec::Publish {
         self.publish
     }
@@ -0,0 +1,122 @@
+use std::{cell::Cell, cell::RefCell, future::Future, rc::Rc, time::Duration};
+
+use ntex::rt::time::delay_for;
+use ntex::util::{join_all, select, ByteString, HashMap};
+
+use super::{shared::MqttShared, sink::MqttSink};
+use crate::drain::Drain;
+
+/// Registry of connected clients
+///
+/// Registry tracks active connection per client id. If client connects
+/// with client id that is already in use, old connection get closed
+/// before new connection is acknowledged (MQTT-3.1.4-2).
+///
+/// Registry is local to the server worker, use `Drain` handle to drain
+/// registries of all workers.
+#[derive(Clone, Default)]
+pub struct SessionRegistry(Rc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    sessions: RefCell<HashMap<ByteString, Rc<MqttShared>>>,
+    draining: Cell<bool>,
+    drain: Option<Drain>,
+}
+
+impl SessionRegistry {
+    /// Create empty registry
+    pub fn new() -> Self {
+        SessionRegistry::default()
+    }
+
+    /// Create empty registry that is drained by server drain handle
+    ///
+    /// Registry must be created on worker's thread, for example in server
+    /// factory.
+    pub fn with_drain(drain: &Drain) -> Self {
+        let inner = Rc::new(Inner { drain: Some(drain.clone()), ..Default::default() });
+
+        let registry = Rc::downgrade(&inner);
+        drain.register(move |timeout| {
+            registry.upgrade().map(|inner| SessionRegistry(inner).drain(timeout))
+        });
+        SessionRegistry(inner)
+    }
+
+    /// Get sink of connected client
+    pub fn get(&self, client_id: &str) -> Option<MqttSink> {
+        self.0.sessions.borrow().get(client_id).map(|shared| MqttSink::new(shared.clone()))
+    }
+
+    /// Check if client is connected
+    pub fn contains(&self, client_id: &str) -> bool {
+        self.0.sessions.borrow().contains_key(client_id)
+    }
+
+    /// Number of connected clients
+    pub fn len(&self) -> usize {
+        self.0.sessions.borrow().len()
+    }
+
+    /// Check if registry is empty
+    pub fn is_empty(&self) -> bool {
+        self.0.sessions.borrow().is_empty()
+    }
+
+    /// Client ids and sinks of connected clients
+    pub fn sinks(&self) -> Vec<(ByteString, MqttSink)> {
+        self.0
+            .sessions
+            .borrow()
+            .iter()
+            .map(|(id, shared)| (id.clone(), MqttSink::new(shared.clone())))
+            .collect()
+    }
+
+    /// Check if registry is draining
+    pub fn is_draining(&self) -> bool {
+        self.0.draining.get() || self.0.drain.as_ref().map_or(false, |d| d.is_draining())
+    }
+
+    /// Drain connected clients
+    ///
+    /// Registry stops accepting new connections, they get rejected with
+    /// `ServiceUnavailable` return code. Then registry waits until in-flight
+    /// messages of connected clients get acknowledged, but no longer than
+    /// `timeout`, and closes connections.
+    ///
+    /// Registry only tracks mqtt sessions, listener should be stopped
+    /// with `ntex::server::Server::stop()`.
+    pub fn drain(&self, timeout: Duration) -> impl Future<Output = ()> {
+        self.0.draining.set(true);
+        let sinks = self.sinks();
+
+        async move {
+            log::trace!("Draining {} mqtt sessions", sinks.len());
+            let flushed = join_all(sinks.iter().map(|(_, sink)| sink.flush()));
+            let _ = select(flushed, delay_for(timeout)).await;
+
+            for (_, sink) in sinks {
+                sink.close();
+            }
+        }
+    }
+
+    /// Register connection, returns previous connection of the client
+    pub(super) fn register(
+        &self,
+        client_id: ByteString,
+        shared: Rc<MqttShared>,
+    ) -> Option<Rc<MqttShared>> {
+        self.0.sessions.borrow_mut().insert(client_id, shared)
+    }
+
+    /// Remove connection, if it is still registered for the client id
+    pub(super) fn unregister(&self, client_id: &str, shared: &MqttShared) {
+        let mut sessions = self.0.sessions.borrow_mut();
+        if sessions.get(client_id).map_or(false, |item| std::ptr::eq(&**item, shared)) {
+            sessions.remove(client_id);
+        }
+    }
+}
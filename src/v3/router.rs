@@ -3,19 +3,27 @@ use std::{future::Future, pin::Pin, rc::Rc};
 
 use ntex::router::{IntoPattern, RouterBuilder};
 use ntex::service::boxed::{self, BoxService, BoxServiceFactory};
-use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
+use ntex::service::{apply, IntoServiceFactory, Service, ServiceFactory, Transform};
+use ntex::util::{ByteString, HashMap};
 
 use super::publish::Publish;
+use crate::routes::RouteTable;
+use crate::{topic::TopicRef, tree::SubscriptionTree};
 
 type Handler<S, E> = BoxServiceFactory<S, Publish, (), E, E>;
 type HandlerService<E> = BoxService<Publish, (), E>;
+type Middleware<S, E> = Box<dyn Fn(Handler<S, E>) -> Handler<S, E>>;
 
 /// Router - structure that follows the builder pattern
 /// for building publish packet router instances for mqtt server.
 pub struct Router<S, Err> {
     router: RouterBuilder<usize>,
+    filters: SubscriptionTree<usize>,
     handlers: Vec<Handler<S, Err>>,
     default: Handler<S, Err>,
+    middlewares: Vec<Middleware<S, Err>>,
+    routes: HashMap<ByteString, usize>,
+    table: Option<RouteTable>,
 }
 
 impl<S, Err> Router<S, Err>
@@ -39,11 +47,26 @@ where
     {
         Router {
             router: ntex::router::Router::build(),
+            filters: SubscriptionTree::new(),
             handlers: Vec::new(),
             default: boxed::factory(default_service.into_factory()),
+            middlewares: Vec::new(),
+            routes: HashMap::default(),
+            table: None,
         }
     }
 
+    /// Set default service to be used if no matching resource could be found.
+    pub fn default_service<F, U: 'static>(mut self, service: F) -> Self
+    where
+        F: IntoServiceFactory<U>,
+        U: ServiceFactory<Config = S, Request = Publish, Response = (), Error = Err>,
+        Err: From<U::InitError>,
+    {
+        self.default = boxed::factory(service.into_factory().map_init_err(Err::from));
+        self
+    }
+
     /// Configure mqtt resource for a specific topic.
     pub fn resource<T, F, U: 'static>(mut self, address: T, service: F) -> Self
     where
@@ -56,6 +79,93 @@ where
         self.handlers.push(boxed::factory(service.into_factory().map_init_err(Err::from)));
         self
     }
+
+    /// Register middleware for all resources and default service.
+    ///
+    /// Middleware is applied to each resource separately. Last registered
+    /// middleware is executed first.
+    pub fn wrap<M>(mut self, mw: M) -> Self
+    where
+        M: Transform<
+                HandlerService<Err>,
+                Request = Publish,
+                Response = (),
+                Error = Err,
+                InitError = Err,
+            > + Clone
+            + 'static,
+        M::Transform: 'static,
+        M::Future: 'static,
+    {
+        self.middlewares.push(Box::new(move |hnd| boxed::factory(apply(mw.clone(), hnd))));
+        self
+    }
+
+    /// Register middleware for last registered resource.
+    ///
+    /// Panics if no resources are registered.
+    pub fn wrap_resource<M>(mut self, mw: M) -> Self
+    where
+        M: Transform<
+                HandlerService<Err>,
+                Request = Publish,
+                Response = (),
+                Error = Err,
+                InitError = Err,
+            > + 'static,
+        M::Transform: 'static,
+        M::Future: 'static,
+    {
+        let hnd = self.handlers.pop().expect("No resources are registered");
+        self.handlers.push(boxed::factory(apply(mw, hnd)));
+        self
+    }
+
+    /// Configure mqtt resource for a topic filter.
+    ///
+    /// Topic filter could contain `+` and `#` wildcards. Topic filters are matched
+    /// with a trie of topic levels, so matching cost depends on number of topic levels
+    /// rather than on number of registered resources. If several resources match
+    /// publish topic, resource registered first is used.
+    ///
+    /// Panics if topic filter is not valid or is a shared subscription filter.
+    pub fn topic_filter<F, U: 'static>(mut self, filter: &str, service: F) -> Self
+    where
+        F: IntoServiceFactory<U>,
+        U: ServiceFactory<Config = S, Request = Publish, Response = (), Error = Err>,
+        Err: From<U::InitError>,
+    {
+        let filter = TopicRef::new(filter)
+            .unwrap_or_else(|_| panic!("Invalid topic filter: {:?}", filter));
+        assert!(!filter.is_shared(), "Shared subscription filter is not supported");
+
+        self.filters.insert(&filter.into(), self.handlers.len());
+        self.handlers.push(boxed::factory(service.into_factory().map_init_err(Err::from)));
+        self
+    }
+
+    /// Configure mqtt resource for a named route of routing table.
+    ///
+    /// Routing table is consulted if publish topic does not match any
+    /// of statically configured resources.
+    pub fn route<F, U: 'static>(mut self, name: &str, service: F) -> Self
+    where
+        F: IntoServiceFactory<U>,
+        U: ServiceFactory<Config = S, Request = Publish, Response = (), Error = Err>,
+        Err: From<U::InitError>,
+    {
+        self.routes.insert(ByteString::from(name), self.handlers.len());
+        self.handlers.push(boxed::factory(service.into_factory().map_init_err(Err::from)));
+        self
+    }
+
+    /// Use runtime updatable routing table.
+    ///
+    /// Table maps topic filters to routes registered with `Router::route()` method.
+    pub fn route_table(mut self, table: RouteTable) -> Self {
+        self.table = Some(table);
+        self
+    }
 }
 
 impl<S, Err> IntoServiceFactory<RouterFactory<S, Err>> for Router<S, Err>
@@ -64,16 +174,25 @@ where
     Err: 'static,
 {
     fn into_factory(self) -> RouterFactory<S, Err> {
+        let middlewares = self.middlewares;
+        let wrap = |hnd: Handler<S, Err>| middlewares.iter().fold(hnd, |hnd, mw| mw(hnd));
+
         RouterFactory {
             router: Rc::new(self.router.finish()),
-            handlers: self.handlers,
-            default: self.default,
+            filters: Rc::new(self.filters),
+            routes: Rc::new(self.routes),
+            table: self.table,
+            handlers: self.handlers.into_iter().map(wrap).collect(),
+            default: wrap(self.default),
         }
     }
 }
 
 pub struct RouterFactory<S, Err> {
     router: Rc<ntex::router::Router<usize>>,
+    filters: Rc<SubscriptionTree<usize>>,
+    routes: Rc<HashMap<ByteString, usize>>,
+    table: Option<RouteTable>,
     handlers: Vec<Handler<S, Err>>,
     default: Handler<S, Err>,
 }
@@ -96,6 +215,9 @@ where
             self.handlers.iter().map(|h| h.new_service(session.clone())).collect();
         let default_fut = self.default.new_service(session);
         let router = self.router.clone();
+        let filters = self.filters.clone();
+        let routes = self.routes.clone();
+        let table = self.table.clone();
 
         Box::pin(async move {
             let mut handlers = Vec::new();
@@ -103,17 +225,52 @@ where
                 handlers.push(handler.await?);
             }
 
-            Ok(RouterService { router, handlers, default: default_fut.await? })
+            Ok(RouterService {
+                router,
+                filters,
+                routes,
+                table,
+                handlers,
+                default: default_fut.await?,
+            })
         })
     }
 }
 
 pub struct RouterService<Err> {
     router: Rc<ntex::router::Router<usize>>,
+    filters: Rc<SubscriptionTree<usize>>,
+    routes: Rc<HashMap<ByteString, usize>>,
+    table: Option<RouteTable>,
     handlers: Vec<HandlerService<Err>>,
     default: HandlerService<Err>,
 }
 
+impl<Err> RouterService<Err> {
+    /// Find first registered resource that matches publish topic
+    fn recognize(&self, req: &mut Publish) -> Option<usize> {
+        let filter_idx = if self.filters.is_empty() {
+            None
+        } else {
+            self.filters.min_match(req.publish_topic())
+        };
+
+        match self.router.recognize(req.topic_mut()) {
+            Some((idx, _)) if filter_idx.map_or(true, |f_idx| *idx < f_idx) => Some(*idx),
+            Some(_) => {
+                // topic filter resource is registered first, drop path parameters
+                req.topic_mut().reset();
+                filter_idx
+            }
+            None => filter_idx.or_else(|| {
+                self.table
+                    .as_ref()
+                    .and_then(|table| table.recognize(req.publish_topic(), &self.routes))
+            }),
+        }
+    }
+}
+
 impl<Err> Service for RouterService<Err> {
     type Request = Publish;
     type Response = ();
@@ -140,8 +297,8 @@ impl<Err> Service for RouterService<Err> {
     }
 
     fn call(&self, mut req: Self::Request) -> Self::Future {
-        if let Some((idx, _info)) = self.router.recognize(req.topic_mut()) {
-            self.handlers[*idx].call(req)
+        if let Some(idx) = self.recognize(&mut req) {
+            self.handlers[idx].call(req)
         } else {
             self.default.call(req)
         }
@@ -2,21 +2,30 @@ use std::{fmt, marker::PhantomData, pin::Pin, rc::Rc, time::Duration};
 
 use ntex::codec::{AsyncRead, AsyncWrite};
 use ntex::rt::time::Sleep;
-use ntex::service::{apply_fn_factory, IntoServiceFactory, Service, ServiceFactory};
-use ntex::util::{timeout::Timeout, timeout::TimeoutError, Either, Ready};
+use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
+use ntex::util::{timeout::Timeout, timeout::TimeoutError};
 
 use crate::error::{MqttError, ProtocolError};
-use crate::io::{DispatchItem, State};
+use crate::inspect::PacketInspector;
+use crate::io::State;
+use crate::metrics::{HandshakeGuard, MqttMetrics};
+use crate::ratelimit::{ConnectionCounter, PublishRate, RateLimitPolicy, RateLimiter};
 use crate::service::{FactoryBuilder, FactoryBuilder2};
+use crate::trace::Span;
+use crate::types::PoolConfig;
+use crate::Acl;
 
+use super::acl::AclRef;
 use super::codec as mqtt;
 use super::control::{ControlMessage, ControlResult};
 use super::default::{DefaultControlService, DefaultPublishService};
 use super::dispatcher::factory;
 use super::handshake::{Handshake, HandshakeAck};
 use super::publish::Publish;
+use super::registry::SessionRegistry;
 use super::shared::{MqttShared, MqttSinkPool};
 use super::sink::MqttSink;
+use super::store::{ConnectionStore, SessionStore};
 use super::Session;
 
 /// Mqtt v3.1.1 Server
@@ -25,10 +34,24 @@ pub struct MqttServer<Io, St, C: ServiceFactory, Cn: ServiceFactory, P: ServiceF
     control: Cn,
     publish: P,
     max_size: u32,
+    mqisdp: bool,
+    conformance: mqtt::Conformance,
+    strict_utf8: bool,
     inflight: usize,
     handshake_timeout: u16,
     disconnect_timeout: u16,
+    keepalive_grace: f32,
+    write_limit: Option<(usize, u16)>,
+    write_coalesce: Option<(usize, usize, Duration)>,
     pool: Rc<MqttSinkPool>,
+    store: Option<Rc<dyn SessionStore>>,
+    registry: Option<SessionRegistry>,
+    limiter: Option<Rc<RateLimiter>>,
+    connections: Option<ConnectionCounter>,
+    proxy_protocol: bool,
+    publish_rate: PublishRate,
+    acl: Option<AclRef<St>>,
+    stream_threshold: u32,
     _t: PhantomData<(Io, St)>,
 }
 
@@ -56,10 +79,24 @@ where
             control: DefaultControlService::default(),
             publish: DefaultPublishService::default(),
             max_size: 0,
+            mqisdp: false,
+            conformance: mqtt::Conformance::Strict,
+            strict_utf8: true,
             inflight: 16,
             handshake_timeout: 0,
             disconnect_timeout: 3000,
+            keepalive_grace: 1.5,
+            write_limit: None,
+            write_coalesce: None,
             pool: Default::default(),
+            store: None,
+            registry: None,
+            limiter: None,
+            connections: None,
+            proxy_protocol: false,
+            publish_rate: PublishRate::default(),
+            acl: None,
+            stream_threshold: 0,
             _t: PhantomData,
         }
     }
@@ -102,6 +139,40 @@ where
         self
     }
 
+    /// Set keep-alive grace factor
+    ///
+    /// Connection keep-alive timeout is multiplied by grace factor, if client
+    /// does not send any packet within this time `ControlMessage::KeepAliveTimeout`
+    /// message is emitted. By default grace factor is set to 1.5.
+    pub fn keepalive_grace(mut self, factor: f32) -> Self {
+        self.keepalive_grace = factor;
+        self
+    }
+
+    /// Set write buffer limit in bytes and slow consumer timeout in seconds
+    ///
+    /// If client does not read from connection and write buffer size exceeds
+    /// `size` for longer than `timeout`, `ControlMessage::SlowConsumer` message
+    /// is emitted. Write buffer is checked once per `timeout` period.
+    /// By default write buffer is not limited.
+    pub fn write_buffer_limit(mut self, size: usize, timeout: u16) -> Self {
+        self.write_limit = Some((size, timeout));
+        self
+    }
+
+    /// Set write coalescing buffer size in bytes, number of packets and delay
+    /// in microseconds
+    ///
+    /// Writing to connection is delayed for up to `delay` microseconds, packets
+    /// encoded in the meantime are sent with single write call. Buffered data
+    /// is written without delay if it is larger than `size` bytes or if it
+    /// contains at least `packets` packets, `0` disables packets limit. Timer
+    /// resolution is one millisecond. By default writes are not delayed.
+    pub fn write_coalesce(mut self, size: usize, packets: usize, delay: u32) -> Self {
+        self.write_coalesce = Some((size, packets, Duration::from_micros(delay as u64)));
+        self
+    }
+
     /// Set max inbound frame size.
     ///
     /// If max size is set to `0`, size is unlimited.
@@ -111,6 +182,51 @@ where
         self
     }
 
+    /// Set streaming threshold for inbound publish packets.
+    ///
+    /// Payload of publish packets larger than threshold is delivered via
+    /// `Publish::take_payload_stream()` while packet is still being received,
+    /// instead of buffering whole packet in memory. Up to `size` bytes of
+    /// received chunks are buffered, connection stops reading from the socket
+    /// until they get consumed, so publish service should not wait for other
+    /// messages before reading the stream.
+    /// If threshold is set to `0`, streaming is disabled.
+    /// By default threshold is set to `0`
+    pub fn stream_threshold(mut self, size: u32) -> Self {
+        self.stream_threshold = size;
+        self
+    }
+
+    /// Accept mqtt 3.1 connections.
+    ///
+    /// Mqtt 3.1 clients use "MQIsdp" protocol name with protocol level 3,
+    /// connections are handled as mqtt 3.1.1 connections.
+    /// By default mqtt 3.1 connections are rejected.
+    pub fn mqisdp(mut self, val: bool) -> Self {
+        self.mqisdp = val;
+        self
+    }
+
+    /// Set codec conformance mode.
+    ///
+    /// In lenient mode server tolerates common deviations from
+    /// the protocol specification.
+    /// By default `Conformance::Strict` mode is used.
+    pub fn conformance(mut self, val: mqtt::Conformance) -> Self {
+        self.conformance = val;
+        self
+    }
+
+    /// Set utf-8 strings strictness.
+    ///
+    /// In strict mode packets with topics or strings containing U+0000 or
+    /// control characters are rejected as malformed.
+    /// By default strict mode is enabled.
+    pub fn strict_utf8(mut self, val: bool) -> Self {
+        self.strict_utf8 = val;
+        self
+    }
+
     /// Number of in-flight concurrent messages.
     ///
     /// By default in-flight is set to 16 messages
@@ -119,6 +235,128 @@ where
         self
     }
 
+    /// Set session store
+    ///
+    /// Server loads session state of the client during handshake, session
+    /// present flag is set if stored session exists. Subscriptions, in-flight
+    /// outgoing publishes and incoming qos 2 state are persisted during
+    /// connection lifetime, not acknowledged publishes are re-sent after
+    /// client re-connects. Stored session state is available
+    /// via `Handshake::session_state()`.
+    pub fn session_store<S>(mut self, store: S) -> Self
+    where
+        S: SessionStore + 'static,
+    {
+        self.store = Some(Rc::new(store));
+        self
+    }
+
+    /// Set session registry
+    ///
+    /// Registry tracks connected clients, previous connection of the client
+    /// get closed if client connects again. Registry is used for draining
+    /// sessions on server shutdown, see `SessionRegistry::drain()`.
+    /// By default registry is not set.
+    pub fn session_registry(mut self, registry: SessionRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Set connection accept rate limit
+    ///
+    /// Token bucket limiter allows `per_second` new connections per second
+    /// with bursts of up to `burst` connections. Excess connections get
+    /// closed before `CONNECT` packet is read. Limit is applied per server worker.
+    /// By default rate limit is not set.
+    pub fn connection_rate(mut self, per_second: u32, burst: u32) -> Self {
+        self.limiter = Some(Rc::new(RateLimiter::new(per_second, burst)));
+        self
+    }
+
+    /// Set max number of concurrent connections
+    ///
+    /// If limit is reached, new connections get rejected with `CONNACK`
+    /// packet with `ServiceUnavailable` reason code. Limit is applied
+    /// per server worker. By default number of connections is not limited.
+    pub fn max_connections(mut self, num: usize) -> Self {
+        self.connections = Some(ConnectionCounter::new(num));
+        self
+    }
+
+    /// Set connection metrics
+    ///
+    /// Metrics are reported for every connection handled by server worker.
+    /// By default metrics are not collected.
+    pub fn metrics<M: MqttMetrics + 'static>(mut self, metrics: M) -> Self {
+        self.pool = self.pool.with_metrics(Rc::new(metrics));
+        self
+    }
+
+    /// Set sink pool and buffer configuration
+    ///
+    /// Configuration is shared by all connections of server worker.
+    pub fn pool_config(mut self, config: PoolConfig) -> Self {
+        self.pool = self.pool.with_config(config);
+        self
+    }
+
+    /// Set packet inspector
+    ///
+    /// Inspector sees every packet received from and sent to clients,
+    /// including handshake packets. By default packets are not inspected.
+    pub fn inspector<I>(mut self, inspector: I) -> Self
+    where
+        I: PacketInspector<mqtt::Packet> + 'static,
+    {
+        self.pool = self.pool.with_inspector(Rc::new(inspector));
+        self
+    }
+
+    /// Enable PROXY protocol support
+    ///
+    /// Server expects PROXY protocol v1 or v2 header before `CONNECT` packet,
+    /// connections without valid header get dropped. Original client address
+    /// is available via `Handshake::peer_addr()`. Header is read from the
+    /// transport that is passed to the server, for tls connections use
+    /// `ntex_mqtt::ProxyProtocol` acceptor before tls acceptor instead.
+    /// Protocol selector `ntex_mqtt::MqttServer` does not support PROXY protocol.
+    /// By default PROXY protocol is disabled.
+    pub fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    /// Set topic authorization provider
+    ///
+    /// Provider is called for topic of every incoming publish packet and for
+    /// every topic filter of incoming subscribe packet. See `Acl` for details.
+    pub fn acl<A>(mut self, acl: A) -> Self
+    where
+        A: Acl<Session<St>>,
+    {
+        self.acl = Some(Rc::new(acl));
+        self
+    }
+
+    /// Set inbound publish rate limit
+    ///
+    /// Limits number of publish packets and payload bytes per second for
+    /// each connection, `0` disables the limit. Action for clients that exceed
+    /// the limit is defined by `publish_rate_policy()`. By default rate is not limited.
+    pub fn publish_rate(mut self, messages: u32, bytes: u32) -> Self {
+        self.publish_rate.messages = messages;
+        self.publish_rate.bytes = bytes;
+        self
+    }
+
+    /// Set action for clients that exceed inbound publish rate
+    ///
+    /// By default reading from connection is paused until rate drops below the limit.
+    pub fn publish_rate_policy(mut self, policy: RateLimitPolicy) -> Self {
+        self.publish_rate.policy = policy;
+        self
+    }
+
     /// Service to handle control packets
     ///
     /// All control packets are processed sequentially, max buffered
@@ -138,10 +376,24 @@ where
             publish: self.publish,
             control: service.into_factory(),
             max_size: self.max_size,
+            mqisdp: self.mqisdp,
+            conformance: self.conformance,
+            strict_utf8: self.strict_utf8,
             inflight: self.inflight,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            keepalive_grace: self.keepalive_grace,
+            write_limit: self.write_limit,
+            write_coalesce: self.write_coalesce,
             pool: self.pool,
+            store: self.store,
+            registry: self.registry,
+            limiter: self.limiter,
+            connections: self.connections,
+            proxy_protocol: self.proxy_protocol,
+            publish_rate: self.publish_rate,
+            acl: self.acl,
+            stream_threshold: self.stream_threshold,
             _t: PhantomData,
         }
     }
@@ -158,10 +410,24 @@ where
             publish: publish.into_factory(),
             control: self.control,
             max_size: self.max_size,
+            mqisdp: self.mqisdp,
+            conformance: self.conformance,
+            strict_utf8: self.strict_utf8,
             inflight: self.inflight,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            keepalive_grace: self.keepalive_grace,
+            write_limit: self.write_limit,
+            write_coalesce: self.write_coalesce,
             pool: self.pool,
+            store: self.store,
+            registry: self.registry,
+            limiter: self.limiter,
+            connections: self.connections,
+            proxy_protocol: self.proxy_protocol,
+            publish_rate: self.publish_rate,
+            acl: self.acl,
+            stream_threshold: self.stream_threshold,
             _t: PhantomData,
         }
     }
@@ -186,29 +452,28 @@ where
             FactoryBuilder::new(handshake_service_factory(
                 handshake,
                 self.max_size,
+                self.mqisdp,
+                self.conformance,
+                self.strict_utf8,
                 self.handshake_timeout,
                 self.pool,
+                self.store,
+                self.registry,
+                self.limiter,
+                self.connections,
+                self.proxy_protocol,
             ))
             .disconnect_timeout(self.disconnect_timeout)
-            .build(apply_fn_factory(
-                factory(publish, control, self.inflight),
-                |req: DispatchItem<Rc<MqttShared>>, srv| match req {
-                    DispatchItem::Item(req) => Either::Left(srv.call(req)),
-                    DispatchItem::KeepAliveTimeout => Either::Right(Ready::Err(
-                        MqttError::Protocol(ProtocolError::KeepAliveTimeout),
-                    )),
-                    DispatchItem::EncoderError(e) => {
-                        Either::Right(Ready::Err(MqttError::Protocol(ProtocolError::Encode(e))))
-                    }
-                    DispatchItem::DecoderError(e) => {
-                        Either::Right(Ready::Err(MqttError::Protocol(ProtocolError::Decode(e))))
-                    }
-                    DispatchItem::IoError(e) => {
-                        Either::Right(Ready::Err(MqttError::Protocol(ProtocolError::Io(e))))
-                    }
-                    DispatchItem::WBackPressureEnabled
-                    | DispatchItem::WBackPressureDisabled => Either::Right(Ready::Ok(None)),
-                },
+            .keepalive_grace(self.keepalive_grace)
+            .write_limit(self.write_limit)
+            .write_coalesce(self.write_coalesce)
+            .build(factory(
+                publish,
+                control,
+                self.inflight,
+                self.publish_rate,
+                self.acl,
+                self.stream_threshold,
             )),
         )
     }
@@ -238,39 +503,46 @@ where
             FactoryBuilder2::new(handshake_service_factory2(
                 handshake,
                 self.max_size,
+                self.mqisdp,
+                self.conformance,
+                self.strict_utf8,
                 self.handshake_timeout,
                 self.pool,
+                self.store,
+                self.registry,
+                self.limiter,
+                self.connections,
             ))
             .disconnect_timeout(self.disconnect_timeout)
-            .build(apply_fn_factory(
-                factory(publish, control, self.inflight),
-                |req: DispatchItem<Rc<MqttShared>>, srv| match req {
-                    DispatchItem::Item(req) => Either::Left(srv.call(req)),
-                    DispatchItem::KeepAliveTimeout => Either::Right(Ready::Err(
-                        MqttError::Protocol(ProtocolError::KeepAliveTimeout),
-                    )),
-                    DispatchItem::EncoderError(e) => {
-                        Either::Right(Ready::Err(MqttError::Protocol(ProtocolError::Encode(e))))
-                    }
-                    DispatchItem::DecoderError(e) => {
-                        Either::Right(Ready::Err(MqttError::Protocol(ProtocolError::Decode(e))))
-                    }
-                    DispatchItem::IoError(e) => {
-                        Either::Right(Ready::Err(MqttError::Protocol(ProtocolError::Io(e))))
-                    }
-                    DispatchItem::WBackPressureEnabled
-                    | DispatchItem::WBackPressureDisabled => Either::Right(Ready::Ok(None)),
-                },
+            .keepalive_grace(self.keepalive_grace)
+            .write_limit(self.write_limit)
+            .write_coalesce(self.write_coalesce)
+            .build(factory(
+                publish,
+                control,
+                self.inflight,
+                self.publish_rate,
+                self.acl,
+                self.stream_threshold,
             )),
         )
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handshake_service_factory<Io, St, C>(
     factory: C,
     max_size: u32,
+    mqisdp: bool,
+    conformance: mqtt::Conformance,
+    strict_utf8: bool,
     handshake_timeout: u16,
     pool: Rc<MqttSinkPool>,
+    store: Option<Rc<dyn SessionStore>>,
+    registry: Option<SessionRegistry>,
+    limiter: Option<Rc<RateLimiter>>,
+    connections: Option<ConnectionCounter>,
+    proxy_protocol: bool,
 ) -> impl ServiceFactory<
     Config = (),
     Request = Io,
@@ -278,7 +550,7 @@ fn handshake_service_factory<Io, St, C>(
     Error = MqttError<C::Error>,
 >
 where
-    Io: AsyncRead + AsyncWrite + Unpin,
+    Io: AsyncRead + AsyncWrite + Unpin + 'static,
     C: ServiceFactory<Config = (), Request = Handshake<Io>, Response = HandshakeAck<Io, St>>,
     C::Error: fmt::Debug,
 {
@@ -286,13 +558,31 @@ where
         Timeout::new(Duration::from_millis(handshake_timeout as u64)),
         ntex::fn_factory(move || {
             let pool = pool.clone();
+            let store = store.clone();
+            let registry = registry.clone();
+            let limiter = limiter.clone();
+            let connections = connections.clone();
             let fut = factory.new_service(());
             async move {
                 let service = fut.await?;
                 let pool = pool.clone();
                 let service = Rc::new(service.map_err(MqttError::Service));
                 Ok::<_, C::InitError>(ntex::apply_fn(service, move |conn: Io, service| {
-                    handshake(conn, None, service.clone(), max_size, pool.clone())
+                    handshake(
+                        conn,
+                        None,
+                        service.clone(),
+                        max_size,
+                        mqisdp,
+                        conformance,
+                        strict_utf8,
+                        pool.clone(),
+                        store.clone(),
+                        registry.clone(),
+                        limiter.clone(),
+                        connections.clone(),
+                        proxy_protocol,
+                    )
                 }))
             }
         }),
@@ -303,11 +593,19 @@ where
     })
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handshake_service_factory2<Io, St, C>(
     factory: C,
     max_size: u32,
+    mqisdp: bool,
+    conformance: mqtt::Conformance,
+    strict_utf8: bool,
     handshake_timeout: u16,
     pool: Rc<MqttSinkPool>,
+    store: Option<Rc<dyn SessionStore>>,
+    registry: Option<SessionRegistry>,
+    limiter: Option<Rc<RateLimiter>>,
+    connections: Option<ConnectionCounter>,
 ) -> impl ServiceFactory<
     Config = (),
     Request = (Io, State),
@@ -316,7 +614,7 @@ fn handshake_service_factory2<Io, St, C>(
     InitError = C::InitError,
 >
 where
-    Io: AsyncRead + AsyncWrite + Unpin,
+    Io: AsyncRead + AsyncWrite + Unpin + 'static,
     C: ServiceFactory<Config = (), Request = Handshake<Io>, Response = HandshakeAck<Io, St>>,
     C::Error: fmt::Debug,
 {
@@ -324,13 +622,31 @@ where
         Timeout::new(Duration::from_millis(handshake_timeout as u64)),
         ntex::fn_factory(move || {
             let pool = pool.clone();
+            let store = store.clone();
+            let registry = registry.clone();
+            let limiter = limiter.clone();
+            let connections = connections.clone();
             let fut = factory.new_service(());
             async move {
                 let service = fut.await?;
                 let pool = pool.clone();
                 let service = Rc::new(service.map_err(MqttError::Service));
                 Ok(ntex::apply_fn(service, move |(io, state), service| {
-                    handshake(io, Some(state), service.clone(), max_size, pool.clone())
+                    handshake(
+                        io,
+                        Some(state),
+                        service.clone(),
+                        max_size,
+                        mqisdp,
+                        conformance,
+                        strict_utf8,
+                        pool.clone(),
+                        store.clone(),
+                        registry.clone(),
+                        limiter.clone(),
+                        connections.clone(),
+                        false,
+                    )
                 }))
             }
         }),
@@ -341,30 +657,67 @@ where
     })
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handshake<Io, S, St, E>(
     mut io: Io,
     state: Option<State>,
     service: S,
     max_size: u32,
+    mqisdp: bool,
+    conformance: mqtt::Conformance,
+    strict_utf8: bool,
     pool: Rc<MqttSinkPool>,
+    store: Option<Rc<dyn SessionStore>>,
+    registry: Option<SessionRegistry>,
+    limiter: Option<Rc<RateLimiter>>,
+    connections: Option<ConnectionCounter>,
+    proxy_protocol: bool,
 ) -> Result<(Io, State, Rc<MqttShared>, Session<St>, u16), S::Error>
 where
-    Io: AsyncRead + AsyncWrite + Unpin,
+    Io: AsyncRead + AsyncWrite + Unpin + 'static,
     S: Service<Request = Handshake<Io>, Response = HandshakeAck<Io, St>, Error = MqttError<E>>,
 {
     log::trace!("Starting mqtt handshake");
+    let metrics = HandshakeGuard::new(pool.metrics.clone());
+
+    // connection accept rate limit
+    if let Some(ref limiter) = limiter {
+        if !limiter.acquire() {
+            log::trace!("Connection is rejected by rate limiter");
+            return Err(MqttError::RateLimited);
+        }
+    }
 
-    let state = state.unwrap_or_else(State::new);
+    let state = state.unwrap_or_else(|| pool.state());
     let shared = Rc::new(MqttShared::new(
         state.clone(),
-        mqtt::Codec::default().max_size(max_size),
+        mqtt::Codec::default()
+            .max_size(max_size)
+            .mqisdp(mqisdp)
+            .conformance(conformance)
+            .strict_utf8(strict_utf8),
         16,
         pool,
     ));
 
+    let (peer_addr, local_addr) = crate::utils::io_addrs(&io);
+    shared.peer_addr.set(peer_addr);
+    shared.local_addr.set(local_addr);
+
+    // read PROXY protocol header
+    if proxy_protocol {
+        let addr = crate::proxy_protocol::read_header(&mut io).await.map_err(|err| {
+            log::trace!("Invalid PROXY protocol header: {:?}", err);
+            MqttError::Protocol(ProtocolError::Io(err))
+        })?;
+        if addr.is_some() {
+            shared.peer_addr.set(addr);
+        }
+    }
+
     // read first packet
     let packet = state
-        .next(&mut io, &shared.codec)
+        .next(&mut io, &*shared)
         .await
         .map_err(|err| {
             log::trace!("Error is received during mqtt handshake: {:?}", err);
@@ -379,26 +732,120 @@ where
 
     match packet {
         mqtt::Packet::Connect(connect) => {
-            // authenticate mqtt connection
-            let mut ack = service.call(Handshake::new(connect, io, shared)).await?;
+            *shared.span.borrow_mut() =
+                Span::connection(&connect.client_id, shared.peer_addr.get(), "3.1.1");
+
+            // server is draining connections
+            if registry.as_ref().map_or(false, |r| r.is_draining()) {
+                log::trace!("Server is shutting down, reject connection");
+                let pkt = mqtt::Packet::ConnectAck {
+                    session_present: false,
+                    return_code: mqtt::ConnectAckReason::ServiceUnavailable,
+                };
+                state.send(&mut io, &*shared, pkt).await?;
+                return Err(MqttError::ShuttingDown);
+            }
+
+            // check max number of connections
+            let guard = match connections.map(|c| c.acquire()) {
+                Some(None) => {
+                    log::trace!("Max number of connections is reached");
+                    let pkt = mqtt::Packet::ConnectAck {
+                        session_present: false,
+                        return_code: mqtt::ConnectAckReason::ServiceUnavailable,
+                    };
+                    state.send(&mut io, &*shared, pkt).await?;
+                    return Err(MqttError::ServerBusy);
+                }
+                guard => guard.flatten(),
+            };
+
+            let last_will = connect.last_will.clone();
+            let clean_session = connect.clean_session;
+
+            // load stored session, store is not modified until connection is accepted
+            let client_id = connect.client_id.clone();
+            let stored =
+                store.as_ref().map(
+                    |store| {
+                        if clean_session {
+                            None
+                        } else {
+                            store.load(&client_id)
+                        }
+                    },
+                );
+            let session_state = stored.clone().flatten();
+
+            // authenticate mqtt connection within connection span
+            let span = shared.span.borrow().clone();
+            let mut ack = span
+                .instrument(service.call(Handshake::new(connect, io, shared, session_state)))
+                .await?;
 
             match ack.session {
                 Some(session) => {
+                    // MQTT-3.2.2-1: session present must be false for clean session
+                    if clean_session && ack.session_present {
+                        log::warn!("Session present flag is set for clean session, ignoring");
+                    }
+                    // session store defines session present flag
+                    let session_present = match stored {
+                        Some(ref st) => st.is_some(),
+                        None => ack.session_present,
+                    };
                     let pkt = mqtt::Packet::ConnectAck {
-                        session_present: ack.session_present,
+                        session_present: session_present && !clean_session,
                         return_code: mqtt::ConnectAckReason::ConnectionAccepted,
                     };
 
                     log::trace!("Sending success handshake ack: {:#?}", pkt);
 
+                    // MQTT-3.1.4-2: disconnect previous connection of the client
+                    if let Some(registry) = registry {
+                        if let Some(prev) =
+                            registry.register(client_id.clone(), ack.shared.clone())
+                        {
+                            log::trace!("Session is taken over for client: {:?}", client_id);
+                            MqttSink::new(prev).close();
+                        }
+                        *ack.shared.registry.borrow_mut() = Some((registry, client_id.clone()));
+                    }
+
                     state.set_buffer_params(ack.read_hw, ack.write_hw, ack.lw);
-                    state.send(&mut ack.io, &ack.shared.codec, pkt).await?;
+                    if let Err(err) = state.send(&mut ack.io, &*ack.shared, pkt).await {
+                        if let Some((registry, client_id)) =
+                            ack.shared.registry.borrow_mut().take()
+                        {
+                            registry.unregister(&client_id, &ack.shared);
+                        }
+                        return Err(err.into());
+                    }
+                    *ack.shared.last_will.borrow_mut() = last_will;
+                    *ack.shared.connection.borrow_mut() = guard;
+
+                    // previous session of clean session connection is discarded
+                    if let Some(store) = store {
+                        if clean_session {
+                            store.remove(&client_id);
+                        }
+                        *ack.shared.store.borrow_mut() =
+                            Some(ConnectionStore::new(store, client_id, clean_session));
+                    }
+
+                    // re-send not acknowledged publishes
+                    let sink = MqttSink::new(ack.shared.clone());
+                    if let Some(Some(st)) = stored {
+                        sink.restore(st);
+                    }
 
+                    let addrs = (ack.shared.peer_addr.get(), ack.shared.local_addr.get());
+                    metrics.complete();
                     Ok((
                         ack.io,
                         ack.shared.state.clone(),
                         ack.shared.clone(),
-                        Session::new(session, MqttSink::new(ack.shared)),
+                        Session::new(session, MqttSink::new(ack.shared), addrs),
                         ack.keepalive,
                     ))
                 }
@@ -409,7 +856,7 @@ where
                     };
 
                     log::trace!("Sending failed handshake ack: {:#?}", pkt);
-                    ack.shared.state.send(&mut ack.io, &ack.shared.codec, pkt).await?;
+                    ack.shared.state.send(&mut ack.io, &*ack.shared, pkt).await?;
 
                     Err(MqttError::Disconnected)
                 }
@@ -1,14 +1,25 @@
-use std::{cell::Cell, cell::RefCell, collections::VecDeque, num::NonZeroU16, rc::Rc};
+use std::{cell::Cell, cell::RefCell, collections::VecDeque, net::SocketAddr, num::NonZeroU16};
+use std::{mem, rc::Rc, time::Duration, time::Instant};
 
 use ntex::channel::pool;
 use ntex::codec::{Decoder, Encoder};
-use ntex::util::{BytesMut, HashMap};
+use ntex::rt::time::delay_for;
+use ntex::util::{select, ByteString, BytesMut, Either, HashMap, HashSet};
 
-use crate::error::{DecodeError, EncodeError};
-use crate::{io::State, types::packet_type, v3::codec};
+use super::{control::Event, registry::SessionRegistry, store::ConnectionStore};
+use crate::error::{DecodeError, EncodeError, SendPacketError};
+use crate::inspect::{Inspector, PacketInspector};
+use crate::metrics::{self, Metrics};
+use crate::ratelimit::ConnectionGuard;
+use crate::trace::Span;
+use crate::types::{packet_type, AckOrder, CancelPolicy, PoolConfig};
+use crate::utils::{CloseState, EventQueue, PacketIds, ReadPause};
+use crate::{io::State, v3::codec};
 
 pub(super) enum Ack {
     Publish(NonZeroU16),
+    Receive(NonZeroU16),
+    Complete(NonZeroU16),
     Subscribe { packet_id: NonZeroU16, status: Vec<codec::SubscribeReturnCode> },
     Unsubscribe(NonZeroU16),
 }
@@ -16,6 +27,8 @@ pub(super) enum Ack {
 #[derive(Copy, Clone)]
 pub(super) enum AckType {
     Publish,
+    Receive,
+    Complete,
     Subscribe,
     Unsubscribe,
 }
@@ -23,11 +36,62 @@ pub(super) enum AckType {
 pub(super) struct MqttSinkPool {
     pub(super) queue: pool::Pool<Ack>,
     pub(super) waiters: pool::Pool<()>,
+    pub(super) metrics: Metrics,
+    pub(super) inspector: Inspector<codec::Packet>,
+    pub(super) config: PoolConfig,
 }
 
 impl Default for MqttSinkPool {
     fn default() -> Self {
-        Self { queue: pool::new(), waiters: pool::new() }
+        Self {
+            queue: pool::new(),
+            waiters: pool::new(),
+            metrics: metrics::noop(),
+            inspector: None,
+            config: PoolConfig::default(),
+        }
+    }
+}
+
+impl MqttSinkPool {
+    /// New pool with connection metrics, other hooks are preserved
+    pub(super) fn with_metrics(&self, metrics: Metrics) -> Rc<Self> {
+        Rc::new(Self {
+            metrics,
+            inspector: self.inspector.clone(),
+            config: self.config,
+            ..Default::default()
+        })
+    }
+
+    /// New pool with packet inspector, other hooks are preserved
+    pub(super) fn with_inspector(
+        &self,
+        inspector: Rc<dyn PacketInspector<codec::Packet>>,
+    ) -> Rc<Self> {
+        Rc::new(Self {
+            metrics: self.metrics.clone(),
+            inspector: Some(inspector),
+            config: self.config,
+            ..Default::default()
+        })
+    }
+
+    /// New pool with configuration, hooks are preserved
+    pub(super) fn with_config(&self, config: PoolConfig) -> Rc<Self> {
+        Rc::new(Self {
+            metrics: self.metrics.clone(),
+            inspector: self.inspector.clone(),
+            config,
+            ..Default::default()
+        })
+    }
+
+    /// New connection state with configured buffer params
+    pub(super) fn state(&self) -> State {
+        let state = State::new();
+        state.set_buffer_params(self.config.read_hw, self.config.write_hw, self.config.lw);
+        state
     }
 }
 
@@ -35,6 +99,21 @@ pub(crate) struct MqttShared {
     pub(super) cap: Cell<usize>,
     pub(super) queues: RefCell<MqttSharedQueues>,
     pub(super) inflight_idx: Cell<u16>,
+    pub(super) ack_timeout: Cell<Option<Duration>>,
+    pub(super) cancel_policy: Cell<CancelPolicy>,
+    pub(super) ack_order: Cell<AckOrder>,
+    pub(super) ping_pending: Cell<bool>,
+    pub(super) last_will: RefCell<Option<codec::LastWill>>,
+    pub(super) store: RefCell<Option<ConnectionStore>>,
+    // session registry and client id of the connection
+    pub(super) registry: RefCell<Option<(SessionRegistry, ByteString)>>,
+    pub(super) connection: RefCell<Option<ConnectionGuard>>,
+    pub(super) peer_addr: Cell<Option<SocketAddr>>,
+    pub(super) local_addr: Cell<Option<SocketAddr>>,
+    pub(super) span: RefCell<Span>,
+    pub(super) close: CloseState,
+    pub(super) read_pause: ReadPause,
+    pub(super) events: EventQueue<Event>,
     pub(super) pool: Rc<MqttSinkPool>,
     pub(super) state: State,
     pub(super) codec: codec::Codec,
@@ -42,8 +121,90 @@ pub(crate) struct MqttShared {
 
 pub(super) struct MqttSharedQueues {
     pub(super) inflight: HashMap<u16, (pool::Sender<Ack>, AckType)>,
+    // initial capacity of in-flight queue, see `PoolConfig::inflight_capacity()`
+    pub(super) inflight_cap: usize,
     pub(super) inflight_order: VecDeque<u16>,
+    // released in-flight packets, peer's acks are ignored
+    pub(super) expired: HashSet<u16>,
+    // ids of in-flight and expired packets
+    pub(super) ids: PacketIds,
     pub(super) waiters: VecDeque<pool::Sender<()>>,
+    pub(super) flush: Vec<pool::Sender<()>>,
+}
+
+impl MqttSharedQueues {
+    /// Check if in-flight queue outgrew configured capacity
+    pub(super) fn exceeds_capacity(&self) -> bool {
+        self.inflight.capacity() > self.inflight_cap
+    }
+
+    /// Check if packet id is used by in-flight or expired packet
+    pub(super) fn id_in_use(&self, idx: u16) -> bool {
+        self.ids.contains(idx)
+    }
+
+    /// Register in-flight packet
+    pub(super) fn insert_inflight(&mut self, idx: u16, tx: pool::Sender<Ack>, ack: AckType) {
+        self.inflight.insert(idx, (tx, ack));
+        self.inflight_order.push_back(idx);
+        self.ids.insert(idx);
+    }
+
+    /// Remove in-flight packet, id of expired packet stays in use
+    pub(super) fn remove_inflight(&mut self, idx: u16) -> Option<(pool::Sender<Ack>, AckType)> {
+        let item = self.inflight.remove(&idx);
+        if item.is_some() && !self.expired.contains(&idx) {
+            self.ids.remove(idx);
+        }
+        item
+    }
+
+    /// Remove all in-flight packets, ids of expired packets stay in use
+    pub(super) fn clear_inflight(&mut self) {
+        for idx in self.inflight.keys() {
+            if !self.expired.contains(idx) {
+                self.ids.remove(*idx);
+            }
+        }
+        self.inflight.clear();
+    }
+
+    /// Release packet id of in-flight packet, late ack from the peer is ignored
+    pub(super) fn insert_expired(&mut self, idx: u16) {
+        self.expired.insert(idx);
+        self.ids.insert(idx);
+    }
+
+    /// Remove expired packet id, returns false if id is not expired
+    pub(super) fn remove_expired(&mut self, idx: u16) -> bool {
+        if self.expired.remove(&idx) {
+            if !self.inflight.contains_key(&idx) {
+                self.ids.remove(idx);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Remove packet id from in-flight order queue
+    ///
+    /// Returns id of the next expected packet for strict order,
+    /// or `packet_id` if it is in-flight for unordered acks.
+    pub(super) fn pop_inflight(&mut self, packet_id: u16, order: AckOrder) -> Option<u16> {
+        // skip expired in-flight packets
+        while self.inflight_order.front() == Some(&0) {
+            self.inflight_order.pop_front();
+        }
+
+        match order {
+            AckOrder::Strict => self.inflight_order.pop_front(),
+            AckOrder::Unordered => {
+                let pos = self.inflight_order.iter().position(|idx| *idx == packet_id)?;
+                self.inflight_order.remove(pos)
+            }
+        }
+    }
 }
 
 impl MqttShared {
@@ -53,42 +214,204 @@ impl MqttShared {
         cap: usize,
         pool: Rc<MqttSinkPool>,
     ) -> Self {
+        let inflight =
+            HashMap::with_capacity_and_hasher(pool.config.inflight, Default::default());
+
         Self {
             state,
             pool,
             codec,
             cap: Cell::new(cap),
             queues: RefCell::new(MqttSharedQueues {
-                inflight: HashMap::default(),
-                inflight_order: VecDeque::with_capacity(8),
+                inflight_cap: inflight.capacity(),
+                inflight,
+                inflight_order: VecDeque::with_capacity(pool.config.inflight),
+                expired: HashSet::default(),
+                ids: PacketIds::default(),
                 waiters: VecDeque::new(),
+                flush: Vec::new(),
             }),
             inflight_idx: Cell::new(0),
+            ack_timeout: Cell::new(None),
+            cancel_policy: Cell::new(CancelPolicy::default()),
+            ack_order: Cell::new(AckOrder::default()),
+            ping_pending: Cell::new(false),
+            last_will: RefCell::new(None),
+            store: RefCell::new(None),
+            registry: RefCell::new(None),
+            connection: RefCell::new(None),
+            peer_addr: Cell::new(None),
+            local_addr: Cell::new(None),
+            span: RefCell::new(Span::default()),
+            close: CloseState::default(),
+            read_pause: ReadPause::default(),
+            events: EventQueue::default(),
+        }
+    }
+
+    /// Call `f` with session store of the connection, if store is set
+    pub(super) fn with_store<F: FnOnce(&ConnectionStore)>(&self, f: F) {
+        if let Some(ref store) = *self.store.borrow() {
+            f(store)
         }
     }
 
     pub(super) fn has_credit(&self) -> bool {
-        self.cap.get() - self.queues.borrow().inflight.len() > 0
+        // in-flight queue could exceed cap, for example after session restore
+        self.queues.borrow().inflight.len() < self.cap.get()
     }
 
-    pub(super) fn next_id(&self) -> u16 {
-        let idx = self.inflight_idx.get() + 1;
-        if idx == u16::max_value() {
-            self.inflight_idx.set(0);
-            u16::max_value()
+    /// Wait for receive credit
+    ///
+    /// Returns `CreditTimeout` error if credit is not available in time.
+    pub(super) async fn wait_credit(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<(), SendPacketError> {
+        let (tx, rx) = self.pool.waiters.channel();
+        self.queues.borrow_mut().waiters.push_back(tx);
+
+        if let Some(timeout) = timeout {
+            match select(delay_for(timeout), rx).await {
+                Either::Left(_) => {
+                    // credit could be released at the same time, pass it to next waiter
+                    if self.has_credit() {
+                        let mut queues = self.queues.borrow_mut();
+                        while let Some(tx) = queues.waiters.pop_front() {
+                            if tx.send(()).is_ok() {
+                                break;
+                            }
+                        }
+                    }
+                    Err(SendPacketError::CreditTimeout)
+                }
+                Either::Right(res) => res.map_err(|_| SendPacketError::Disconnected),
+            }
         } else {
-            self.inflight_idx.set(idx);
-            idx
+            rx.await.map_err(|_| SendPacketError::Disconnected)
+        }
+    }
+
+    /// Release in-flight packet, late ack from the peer is ignored
+    pub(super) fn expire_inflight(&self, idx: u16) {
+        let mut queues = self.queues.borrow_mut();
+        if queues.remove_inflight(idx).is_some() {
+            log::trace!("Release in-flight packet with id: {}", idx);
+            if let Some(item) = queues.inflight_order.iter_mut().find(|item| **item == idx) {
+                *item = 0;
+            }
+            queues.insert_expired(idx);
+
+            // wake up queued request (receive max limit)
+            while let Some(tx) = queues.waiters.pop_front() {
+                if tx.send(()).is_ok() {
+                    break;
+                }
+            }
+            // notify flush waiters
+            if queues.inflight.is_empty() {
+                for tx in queues.flush.drain(..) {
+                    let _ = tx.send(());
+                }
+            }
+        }
+    }
+
+    /// Wait ack from the peer
+    ///
+    /// In-flight packet is released if ack is not received in time.
+    pub(super) async fn wait_ack(
+        &self,
+        idx: u16,
+        rx: pool::Receiver<Ack>,
+        timeout: Option<Duration>,
+    ) -> Result<Ack, SendPacketError> {
+        // cancel policy is applied if future is dropped
+        let guard = InflightGuard::new(self, idx);
+        let start = Instant::now();
+
+        let result = if let Some(timeout) = timeout.or_else(|| self.ack_timeout.get()) {
+            match select(delay_for(timeout), rx).await {
+                Either::Left(_) => {
+                    self.expire_inflight(idx);
+                    Err(SendPacketError::AckTimeout)
+                }
+                Either::Right(res) => res.map_err(|_| SendPacketError::Disconnected),
+            }
+        } else {
+            rx.await.map_err(|_| SendPacketError::Disconnected)
+        };
+        guard.disarm();
+
+        if let Ok(ref ack) = result {
+            self.pool.metrics.ack_latency(ack.packet_type(), start.elapsed());
+        }
+        result
+    }
+
+    /// Allocate packet id that is not used by in-flight or expired packets
+    ///
+    /// Returns `PacketIdsExhausted` error if all packet ids are in use.
+    pub(super) fn next_id(&self, queues: &MqttSharedQueues) -> Result<u16, SendPacketError> {
+        let idx = queues
+            .ids
+            .next_free(self.inflight_idx.get())
+            .ok_or(SendPacketError::PacketIdsExhausted)?;
+        self.inflight_idx.set(idx);
+        Ok(idx)
+    }
+}
+
+/// Applies cancel policy to in-flight packet if its future is dropped before ack
+pub(super) struct InflightGuard<'a>(&'a MqttShared, u16);
+
+impl<'a> InflightGuard<'a> {
+    pub(super) fn new(shared: &'a MqttShared, idx: u16) -> Self {
+        InflightGuard(shared, idx)
+    }
+
+    /// Ack is received, nothing to do
+    pub(super) fn disarm(self) {
+        mem::forget(self)
+    }
+}
+
+impl<'a> Drop for InflightGuard<'a> {
+    fn drop(&mut self) {
+        if self.0.cancel_policy.get() == CancelPolicy::Forget {
+            self.0.expire_inflight(self.1);
+            if let Some(packet_id) = NonZeroU16::new(self.1) {
+                self.0.with_store(|store| store.ack_publish(packet_id));
+            }
+        }
+    }
+}
+
+impl Drop for MqttShared {
+    fn drop(&mut self) {
+        // pools grow with peak number of in-flight packets, shrink them
+        // only if the connection exceeded configured capacity
+        if self.pool.config.shrink && self.queues.get_mut().exceeds_capacity() {
+            self.pool.queue.shrink_to_fit();
+            self.pool.waiters.shrink_to_fit();
         }
     }
 }
+
 impl Encoder for MqttShared {
     type Item = codec::Packet;
     type Error = EncodeError;
 
     #[inline]
     fn encode(&self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        self.codec.encode(item, dst)
+        if let Some(ref inspector) = self.pool.inspector {
+            inspector.outbound(&item);
+        }
+        let tp = item.packet_type();
+        let len = dst.len();
+        self.codec.encode(item, dst)?;
+        self.pool.metrics.packet_sent(tp, dst.len() - len);
+        Ok(())
     }
 }
 
@@ -98,7 +421,15 @@ impl Decoder for MqttShared {
 
     #[inline]
     fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        self.codec.decode(src)
+        let len = src.len();
+        let item = self.codec.decode(src)?;
+        if let Some(ref pkt) = item {
+            self.pool.metrics.packet_received(pkt.packet_type(), len - src.len());
+            if let Some(ref inspector) = self.pool.inspector {
+                inspector.inbound(pkt);
+            }
+        }
+        Ok(item)
     }
 }
 
@@ -106,6 +437,8 @@ impl Ack {
     pub(super) fn packet_type(&self) -> u8 {
         match self {
             Ack::Publish(_) => packet_type::PUBACK,
+            Ack::Receive(_) => packet_type::PUBREC,
+            Ack::Complete(_) => packet_type::PUBCOMP,
             Ack::Subscribe { .. } => packet_type::SUBACK,
             Ack::Unsubscribe(_) => packet_type::UNSUBACK,
         }
@@ -114,6 +447,8 @@ impl Ack {
     pub(super) fn packet_id(&self) -> u16 {
         match self {
             Ack::Publish(id) => id.get(),
+            Ack::Receive(id) => id.get(),
+            Ack::Complete(id) => id.get(),
             Ack::Subscribe { packet_id, .. } => packet_id.get(),
             Ack::Unsubscribe(id) => id.get(),
         }
@@ -130,6 +465,8 @@ impl Ack {
     pub(super) fn is_match(&self, tp: AckType) -> bool {
         match (self, tp) {
             (Ack::Publish(_), AckType::Publish) => true,
+            (Ack::Receive(_), AckType::Receive) => true,
+            (Ack::Complete(_), AckType::Complete) => true,
             (Ack::Subscribe { .. }, AckType::Subscribe) => true,
             (Ack::Unsubscribe(_), AckType::Unsubscribe) => true,
             (_, _) => false,
@@ -141,8 +478,92 @@ impl AckType {
     pub(super) fn name(&self) -> &'static str {
         match self {
             AckType::Publish => "PublishAck",
+            AckType::Receive => "PublishReceived",
+            AckType::Complete => "PublishComplete",
             AckType::Subscribe => "SubscribeAck",
             AckType::Unsubscribe => "UnsubscribeAck",
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_id() {
+        let shared = MqttShared::new(
+            State::new(),
+            codec::Codec::default(),
+            16,
+            Rc::new(MqttSinkPool::default()),
+        );
+        let mut queues = shared.queues.borrow_mut();
+        assert_eq!(shared.next_id(&queues), Ok(1));
+
+        // in-use ids are skipped
+        let (tx, _rx) = shared.pool.queue.channel();
+        queues.insert_inflight(2, tx, AckType::Publish);
+        queues.insert_expired(3);
+        assert_eq!(shared.next_id(&queues), Ok(4));
+
+        // wrap-around skips ids that are still in-flight
+        shared.inflight_idx.set(u16::max_value() - 1);
+        queues.insert_expired(1);
+        assert_eq!(shared.next_id(&queues), Ok(u16::max_value()));
+        assert_eq!(shared.next_id(&queues), Ok(4));
+
+        // released ids are available again
+        assert!(queues.remove_inflight(2).is_some());
+        assert!(queues.remove_expired(1));
+        assert_eq!(shared.next_id(&queues), Ok(5));
+        shared.inflight_idx.set(u16::max_value());
+        assert_eq!(shared.next_id(&queues), Ok(1));
+
+        // all ids are in use
+        (1..=u16::max_value()).for_each(|idx| queues.insert_expired(idx));
+        assert_eq!(shared.next_id(&queues), Err(SendPacketError::PacketIdsExhausted));
+    }
+
+    #[test]
+    fn test_credit_overflow() {
+        let shared = Rc::new(MqttShared::new(
+            State::new(),
+            codec::Codec::default(),
+            1,
+            Rc::new(MqttSinkPool::default()),
+        ));
+        let mut rxs = Vec::new();
+        for idx in 1..=2 {
+            let (tx, rx) = shared.pool.queue.channel();
+            shared.queues.borrow_mut().insert_inflight(idx, tx, AckType::Publish);
+            rxs.push(rx);
+        }
+        assert!(!shared.has_credit());
+        assert_eq!(super::super::MqttSink::new(shared).credit(), 0);
+    }
+
+    #[test]
+    fn test_pool_shrink() {
+        let config = PoolConfig::new().inflight_capacity(2).shrink(true);
+        let pool = MqttSinkPool::default().with_config(config);
+        let shared = MqttShared::new(State::new(), codec::Codec::default(), 16, pool.clone());
+        assert!(!shared.queues.borrow().exceeds_capacity());
+
+        let mut rxs = Vec::new();
+        for idx in 1..=32 {
+            let (tx, rx) = pool.queue.channel();
+            shared.queues.borrow_mut().insert_inflight(idx, tx, AckType::Publish);
+            rxs.push(rx);
+        }
+        assert_eq!(format!("{:?}", pool.queue), "Pool { size: 32 }");
+
+        // acked packets release channels, queue keeps capacity
+        shared.queues.borrow_mut().clear_inflight();
+        drop(rxs);
+        assert_eq!(format!("{:?}", pool.queue), "Pool { size: 0 }");
+        assert!(shared.queues.borrow().exceeds_capacity());
+        drop(shared);
+        assert_eq!(format!("{:?}", pool.queue), "Pool { size: 0 }");
+    }
+}
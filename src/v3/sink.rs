@@ -1,8 +1,19 @@
-use ntex::util::{ByteString, Bytes, Either};
-use std::{fmt, future::Future, num::NonZeroU16, rc::Rc};
-
-use super::shared::{Ack, AckType, MqttShared};
-use super::{codec, error::ProtocolError, error::SendPacketError};
+use ntex::channel::pool;
+use ntex::rt::time::delay_for;
+use ntex::util::{join_all, select, ByteString, Bytes, Either};
+#[cfg(any(feature = "json", feature = "cbor"))]
+use serde::Serialize;
+use std::rc::{Rc, Weak};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use std::{convert::TryInto, fmt, future::Future, marker, num::NonZeroU16, pin::Pin};
+
+use super::handle::MqttSinkHandle;
+use super::shared::{Ack, AckType, InflightGuard, MqttShared};
+use super::store::SessionState;
+use super::{codec, control, error::ProtocolError, error::SendPacketError};
+use crate::topic::{TopicError, TopicFilter, TopicName};
+use crate::types::{AckOrder, CancelPolicy};
 
 pub struct MqttSink(Rc<MqttShared>);
 
@@ -17,9 +28,48 @@ impl MqttSink {
         MqttSink(state)
     }
 
+    pub(super) fn shared(&self) -> &MqttShared {
+        &self.0
+    }
+
+    /// Check connection status
+    pub fn is_open(&self) -> bool {
+        self.0.state.is_open()
+    }
+
     /// Get client receive credit
     pub fn credit(&self) -> usize {
-        self.0.cap.get() - self.0.queues.borrow().inflight.len()
+        self.0.cap.get().saturating_sub(self.0.queues.borrow().inflight.len())
+    }
+
+    /// Get number of in-flight packets
+    pub fn inflight(&self) -> usize {
+        self.0.queues.borrow().inflight.len()
+    }
+
+    /// Get number of senders waiting for receive credit
+    ///
+    /// Number could include waiters that are already canceled.
+    pub fn waiters(&self) -> usize {
+        self.0.queues.borrow().waiters.len()
+    }
+
+    /// Get max number of in-flight packets
+    pub fn receive_max(&self) -> usize {
+        self.0.cap.get()
+    }
+
+    /// Get notification when packet could be send to the peer, with timeout
+    ///
+    /// Result is `false` if connection is closed or credit is not available in time
+    pub fn ready_timeout(&self, timeout: Duration) -> impl Future<Output = bool> {
+        let fut = self.ready();
+        async move {
+            match select(delay_for(timeout), fut).await {
+                Either::Left(_) => false,
+                Either::Right(res) => res,
+            }
+        }
     }
 
     /// Get notification when packet could be send to the peer.
@@ -39,34 +89,136 @@ impl MqttSink {
         Either::Left(async move { res })
     }
 
+    /// Get notification when all in-flight packets get acknowledged
+    ///
+    /// Result indicates if connection is alive
+    pub fn flush(&self) -> impl Future<Output = bool> {
+        let mut queues = self.0.queues.borrow_mut();
+        let res = if !self.0.state.is_open() {
+            false
+        } else if !queues.inflight.is_empty() {
+            let (tx, rx) = self.0.pool.waiters.channel();
+            queues.flush.push(tx);
+            return Either::Right(async move { rx.await.is_ok() });
+        } else {
+            true
+        };
+        Either::Left(async move { res })
+    }
+
+    /// Set ack timeout for publish packets
+    ///
+    /// If peer does not acknowledge publish packet in time, publish future resolves
+    /// with `AckTimeout` error and packet id is released. Late ack from the peer
+    /// is ignored. By default ack timeout is not set.
+    pub fn set_ack_timeout(&self, timeout: Option<Duration>) {
+        self.0.ack_timeout.set(timeout);
+    }
+
+    /// Set in-flight packet handling for dropped publish futures
+    ///
+    /// By default packet id and receive credit are released.
+    pub fn set_cancel_policy(&self, policy: CancelPolicy) {
+        self.0.cancel_policy.set(policy);
+    }
+
+    /// Set matching of peer's acks to in-flight packets
+    ///
+    /// Some brokers acknowledge concurrently processed publishes out of
+    /// order. By default acks must arrive in order.
+    pub fn set_ack_order(&self, order: AckOrder) {
+        self.0.ack_order.set(order);
+    }
+
+    /// Create thread-safe handle for the sink
+    ///
+    /// Must be called on connection's thread. Handle forwards requests
+    /// to connection's thread until all handles are dropped.
+    pub fn handle(&self) -> MqttSinkHandle {
+        MqttSinkHandle::new(&self.0)
+    }
+
+    /// Create sender of application events
+    ///
+    /// Events are delivered to connection's control service as
+    /// `ControlMessage::Event` messages. Sender does not keep connection alive.
+    pub fn events<T: 'static>(&self) -> EventSender<T> {
+        EventSender(Rc::downgrade(&self.0), marker::PhantomData)
+    }
+
+    /// Pause processing of inbound packets
+    ///
+    /// Connection stops reading from the socket once read buffer is full,
+    /// peer's packets, including PINGREQ, are not handled until reading is
+    /// resumed with `resume_read()`.
+    pub fn pause_read(&self) {
+        self.0.read_pause.pause()
+    }
+
+    /// Resume processing of inbound packets
+    pub fn resume_read(&self) {
+        self.0.read_pause.resume()
+    }
+
+    /// Check if processing of inbound packets is paused
+    pub fn is_read_paused(&self) -> bool {
+        self.0.read_pause.is_paused()
+    }
+
     /// Close mqtt connection
     pub fn close(&self) {
+        self.0.close.local();
         if self.0.state.is_open() {
             let _ = self.0.state.close();
         }
         let mut queues = self.0.queues.borrow_mut();
-        queues.inflight.clear();
+        queues.clear_inflight();
         queues.waiters.clear();
+        queues.flush.clear();
     }
 
     /// Force close mqtt connection. mqtt dispatcher does not wait for uncompleted
     /// responses, but it flushes buffers.
     pub fn force_close(&self) {
+        self.0.close.local();
         if self.0.state.is_open() {
             let _ = self.0.state.force_close();
         }
         let mut queues = self.0.queues.borrow_mut();
-        queues.inflight.clear();
+        queues.clear_inflight();
         queues.waiters.clear();
+        queues.flush.clear();
     }
 
     /// Send ping
     pub(super) fn ping(&self) -> bool {
-        self.0.state.write().encode(codec::Packet::PingRequest, &self.0.codec).is_ok()
+        self.0.ping_pending.set(true);
+        self.0.state.write().encode(codec::Packet::PingRequest, &*self.0).is_ok()
+    }
+
+    /// Check if ping response is not received yet
+    pub(super) fn is_ping_pending(&self) -> bool {
+        self.0.ping_pending.get()
+    }
+
+    pub(super) fn pkt_ping_response(&self) {
+        self.0.ping_pending.set(false);
     }
 
     /// Create publish message builder
-    pub fn publish(&self, topic: ByteString, payload: Bytes) -> PublishBuilder {
+    ///
+    /// Topic could be `TopicName` or any value that converts to it, for example
+    /// `ByteString` or `&str`. Send methods fail with `InvalidTopic` error if
+    /// topic is empty or contains wildcards.
+    pub fn publish<U>(&self, topic: U, payload: Bytes) -> PublishBuilder
+    where
+        U: TryInto<TopicName>,
+        TopicError: From<U::Error>,
+    {
+        let (topic, error) = match topic.try_into() {
+            Ok(topic) => (topic.into_inner(), None),
+            Err(err) => (ByteString::new(), Some(err.into())),
+        };
         PublishBuilder {
             packet: codec::Publish {
                 topic,
@@ -77,6 +229,61 @@ impl MqttSink {
                 packet_id: None,
             },
             shared: self.0.clone(),
+            ack_timeout: None,
+            credit_timeout: None,
+            error,
+        }
+    }
+
+    /// Publish batch of messages
+    ///
+    /// Publish packets are written to the write buffer in one pass and get flushed
+    /// to the peer together. Future resolves when all QoS 1 and QoS 2 messages
+    /// are acknowledged by the peer. If peer's receive maximum is reached, remaining
+    /// messages are written as soon as credit is available.
+    pub fn publish_batch<I, U>(
+        &self,
+        batch: I,
+    ) -> impl Future<Output = Result<(), SendPacketError>>
+    where
+        I: IntoIterator<Item = (U, Bytes, codec::QoS)>,
+        U: TryInto<TopicName>,
+        TopicError: From<U::Error>,
+    {
+        let sink = self.clone();
+        let batch = batch.into_iter();
+
+        async move {
+            let shared = sink.0.clone();
+            let mut acks = Vec::new();
+            let mut guards = Vec::new();
+
+            for (topic, payload, qos) in batch {
+                let builder = sink.publish(topic, payload);
+                builder.check_topic()?;
+
+                let ack = match qos {
+                    codec::QoS::AtMostOnce => {
+                        builder.send_at_most_once()?;
+                        continue;
+                    }
+                    codec::QoS::AtLeastOnce => AckType::Publish,
+                    codec::QoS::ExactlyOnce => AckType::Receive,
+                };
+
+                // handle client receive maximum
+                if shared.state.is_open() && !shared.has_credit() {
+                    shared.wait_credit(None).await?;
+                }
+                let (idx, rx) = builder.enqueue(qos, ack)?;
+                guards.push(InflightGuard::new(&shared, idx));
+                acks.push(wait_publish_ack(shared.clone(), idx, rx, None));
+            }
+
+            // ack futures take over in-flight guards on first poll
+            let acks = join_all(acks);
+            guards.into_iter().for_each(InflightGuard::disarm);
+            acks.await.into_iter().collect()
         }
     }
 
@@ -84,19 +291,116 @@ impl MqttSink {
     ///
     /// panics if id is 0
     pub fn subscribe(&self) -> SubscribeBuilder {
-        SubscribeBuilder { id: 0, topic_filters: Vec::new(), shared: self.0.clone() }
+        SubscribeBuilder {
+            id: 0,
+            topic_filters: Vec::new(),
+            shared: self.0.clone(),
+            error: None,
+        }
     }
 
     /// Create unsubscribe packet builder
     pub fn unsubscribe(&self) -> UnsubscribeBuilder {
-        UnsubscribeBuilder { id: 0, topic_filters: Vec::new(), shared: self.0.clone() }
+        UnsubscribeBuilder {
+            id: 0,
+            topic_filters: Vec::new(),
+            shared: self.0.clone(),
+            error: None,
+        }
+    }
+
+    /// Restore stored session state
+    pub(super) fn restore(&self, st: SessionState) {
+        self.0.with_store(|store| store.restore_received(st.received));
+
+        // packet ids of stored publishes must not be re-used
+        let ids = st.publishes.iter().filter_map(|p| p.packet_id);
+        if let Some(max) = ids.chain(st.releases.iter().copied()).max() {
+            if max.get() > self.0.inflight_idx.get() {
+                self.0.inflight_idx.set(max.get());
+            }
+        }
+
+        // publishes received by the client get released
+        for packet_id in st.releases {
+            log::trace!("Re-send stored publish release: {:?}", packet_id);
+            let res = self
+                .0
+                .state
+                .write()
+                .encode(codec::Packet::PublishRelease { packet_id }, &*self.0);
+            if let Err(err) = res {
+                log::trace!("Cannot re-send stored publish release: {}", err);
+                continue;
+            }
+
+            // wait for PUBCOMP
+            let idx = packet_id.get();
+            let (tx, rx) = self.0.pool.queue.channel();
+            let mut queues = self.0.queues.borrow_mut();
+            queues.insert_inflight(idx, tx, AckType::Complete);
+            drop(queues);
+
+            let shared = self.0.clone();
+            ntex::rt::spawn(async move {
+                if let Err(err) = wait_publish_ack(shared, idx, rx, None).await {
+                    log::trace!("Stored publish is not completed: {}", err);
+                }
+            });
+        }
+
+        for mut packet in st.publishes {
+            log::trace!("Re-send stored publish: {:?}", packet.packet_id);
+            packet.dup = true;
+            let qos = packet.qos;
+            let builder = PublishBuilder {
+                packet,
+                shared: self.0.clone(),
+                ack_timeout: None,
+                credit_timeout: None,
+                error: None,
+            };
+            ntex::rt::spawn(async move {
+                let res = if qos == codec::QoS::ExactlyOnce {
+                    builder.send_exactly_once().await
+                } else {
+                    builder.send_at_least_once().await
+                };
+                if let Err(err) = res {
+                    log::trace!("Cannot re-send stored publish: {}", err);
+                }
+            });
+        }
     }
 
     pub(super) fn pkt_ack(&self, pkt: Ack) -> Result<(), ProtocolError> {
         let mut queues = self.0.queues.borrow_mut();
 
+        // late ack of expired in-flight packet
+        if queues.remove_expired(pkt.packet_id()) {
+            log::trace!("Ack for expired packet with id: {}", pkt.packet_id());
+            if let Ack::Receive(packet_id) = pkt {
+                // complete qos2 flow, keep packet id until PUBCOMP
+                queues.insert_expired(packet_id.get());
+                drop(queues);
+                self.0.with_store(|store| store.release_publish(packet_id));
+
+                return self
+                    .0
+                    .state
+                    .write()
+                    .encode(codec::Packet::PublishRelease { packet_id }, &*self.0)
+                    .map(|_| ())
+                    .map_err(|err| {
+                        self.close();
+                        ProtocolError::Encode(err)
+                    });
+            }
+            return Ok(());
+        }
+
         // check ack order
-        if let Some(idx) = queues.inflight_order.pop_front() {
+        if let Some(idx) = queues.pop_inflight(pkt.packet_id(), self.0.ack_order.get()) {
             if idx != pkt.packet_id() {
                 log::trace!(
                     "MQTT protocol error, packet_id order does not match, expected {}, got: {}",
@@ -107,12 +411,31 @@ impl MqttSink {
                 // get publish ack channel
                 log::trace!("Ack packet with id: {}", pkt.packet_id());
                 let idx = pkt.packet_id();
-                if let Some((tx, tp)) = queues.inflight.remove(&idx) {
+                if let Some((tx, tp)) = queues.remove_inflight(idx) {
                     if !pkt.is_match(tp) {
                         log::trace!("MQTT protocol error, unexpeted packet");
                         self.close();
                         return Err(ProtocolError::Unexpected(pkt.packet_type(), tp.name()));
                     }
+
+                    // qos2 publish is received, release it and keep credit until PUBCOMP
+                    if let Ack::Receive(packet_id) = pkt {
+                        queues.insert_inflight(idx, tx, AckType::Complete);
+                        drop(queues);
+                        self.0.with_store(|store| store.release_publish(packet_id));
+
+                        log::trace!("Publish release (QoS2) packet id: {}", idx);
+                        return self
+                            .0
+                            .state
+                            .write()
+                            .encode(codec::Packet::PublishRelease { packet_id }, &*self.0)
+                            .map(|_| ())
+                            .map_err(|err| {
+                                self.close();
+                                ProtocolError::Encode(err)
+                            });
+                    }
                     let _ = tx.send(pkt);
 
                     // wake up queued request (receive max limit)
@@ -121,6 +444,13 @@ impl MqttSink {
                             break;
                         }
                     }
+
+                    // notify flush waiters
+                    if queues.inflight.is_empty() {
+                        for tx in queues.flush.drain(..) {
+                            let _ = tx.send(());
+                        }
+                    }
                     return Ok(());
                 } else {
                     log::error!("Inflight state inconsistency")
@@ -143,6 +473,9 @@ impl fmt::Debug for MqttSink {
 pub struct PublishBuilder {
     packet: codec::Publish,
     shared: Rc<MqttShared>,
+    ack_timeout: Option<Duration>,
+    credit_timeout: Option<Duration>,
+    error: Option<TopicError>,
 }
 
 impl PublishBuilder {
@@ -170,8 +503,55 @@ impl PublishBuilder {
         self
     }
 
+    /// Set ack timeout for this packet, overrides sink's ack timeout
+    pub fn ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = Some(timeout);
+        self
+    }
+
+    #[cfg(feature = "json")]
+    /// Set payload to `application/json` encoded value
+    pub fn json<T: Serialize>(mut self, value: &T) -> Result<Self, serde_json::Error> {
+        self.packet.payload = Bytes::from(serde_json::to_vec(value)?);
+        Ok(self)
+    }
+
+    #[cfg(feature = "cbor")]
+    /// Set payload to `application/cbor` encoded value
+    pub fn cbor<T: Serialize>(mut self, value: &T) -> Result<Self, serde_cbor::Error> {
+        self.packet.payload = Bytes::from(serde_cbor::to_vec(value)?);
+        Ok(self)
+    }
+
+    #[cfg(feature = "json")]
+    /// Send publish packet with `application/json` encoded payload and QoS 1
+    ///
+    /// Value is encoded immediately, serialization error is returned as
+    /// `SendPacketError::Serialize` error.
+    pub fn send_json<T: Serialize>(
+        self,
+        value: &T,
+    ) -> impl Future<Output = Result<(), SendPacketError>> {
+        let res = self.json(value).map_err(|e| SendPacketError::Serialize(e.to_string()));
+        async move { res?.send_at_least_once().await }
+    }
+
+    #[cfg(feature = "cbor")]
+    /// Send publish packet with `application/cbor` encoded payload and QoS 1
+    ///
+    /// Value is encoded immediately, serialization error is returned as
+    /// `SendPacketError::Serialize` error.
+    pub fn send_cbor<T: Serialize>(
+        self,
+        value: &T,
+    ) -> impl Future<Output = Result<(), SendPacketError>> {
+        let res = self.cbor(value).map_err(|e| SendPacketError::Serialize(e.to_string()));
+        async move { res?.send_at_least_once().await }
+    }
+
     /// Send publish packet with QoS 0
     pub fn send_at_most_once(self) -> Result<(), SendPacketError> {
+        self.check_topic()?;
         let packet = self.packet;
 
         if self.shared.state.is_open() {
@@ -179,7 +559,7 @@ impl PublishBuilder {
             self.shared
                 .state
                 .write()
-                .encode(codec::Packet::Publish(packet), &self.shared.codec)
+                .encode(codec::Packet::Publish(packet), &*self.shared)
                 .map_err(SendPacketError::Encode)
                 .map(|_| ())
         } else {
@@ -188,62 +568,170 @@ impl PublishBuilder {
         }
     }
 
-    #[allow(clippy::await_holding_refcell_ref)]
     /// Send publish packet with QoS 1
     pub async fn send_at_least_once(self) -> Result<(), SendPacketError> {
+        self.send_with_ack(codec::QoS::AtLeastOnce, AckType::Publish).await
+    }
+
+    /// Send publish packet with QoS 1, wait for receive credit with timeout
+    ///
+    /// Future resolves with `CreditTimeout` error if receive credit is not
+    /// available in time.
+    pub async fn send_at_least_once_timeout(
+        mut self,
+        timeout: Duration,
+    ) -> Result<(), SendPacketError> {
+        self.credit_timeout = Some(timeout);
+        self.send_with_ack(codec::QoS::AtLeastOnce, AckType::Publish).await
+    }
+
+    /// Send publish packet with QoS 1 without waiting for ack
+    ///
+    /// Packet is encoded immediately, returned future resolves when publish
+    /// is acknowledged by the peer. Ack timeout starts when the future is
+    /// polled first time. Returns `NoCredit` error if receive credit
+    /// is not available.
+    pub fn send_at_least_once_detached(self) -> Result<AckFuture, SendPacketError> {
+        self.check_topic()?;
+        if self.shared.state.is_open() && !self.shared.has_credit() {
+            return Err(SendPacketError::NoCredit);
+        }
+        let shared = self.shared.clone();
+        let timeout = self.ack_timeout;
+        let (idx, rx) = self.enqueue(codec::QoS::AtLeastOnce, AckType::Publish)?;
+        Ok(AckFuture(Box::pin(wait_publish_ack(shared, idx, rx, timeout))))
+    }
+
+    /// Send publish packet with QoS 2
+    ///
+    /// Future resolves after PUBCOMP packet is received from the peer.
+    pub async fn send_exactly_once(self) -> Result<(), SendPacketError> {
+        self.send_with_ack(codec::QoS::ExactlyOnce, AckType::Receive).await
+    }
+
+    async fn send_with_ack(self, qos: codec::QoS, ack: AckType) -> Result<(), SendPacketError> {
+        self.check_topic()?;
+
+        // handle client receive maximum
+        if self.shared.state.is_open() && !self.shared.has_credit() {
+            self.shared.wait_credit(self.credit_timeout).await?;
+        }
+        let shared = self.shared.clone();
+        let timeout = self.ack_timeout;
+        let (idx, rx) = self.enqueue(qos, ack)?;
+        wait_publish_ack(shared, idx, rx, timeout).await
+    }
+
+    fn check_topic(&self) -> Result<(), SendPacketError> {
+        if let Some(err) = self.error {
+            Err(SendPacketError::InvalidTopic(err))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Register publish as in-flight and encode it
+    fn enqueue(
+        self,
+        qos: codec::QoS,
+        ack: AckType,
+    ) -> Result<(u16, pool::Receiver<Ack>), SendPacketError> {
         let shared = self.shared;
         let mut packet = self.packet;
-        packet.qos = codec::QoS::AtLeastOnce;
+        packet.qos = qos;
 
-        if shared.state.is_open() {
-            // handle client receive maximum
-            if !shared.has_credit() {
-                let (tx, rx) = shared.pool.waiters.channel();
-                shared.queues.borrow_mut().waiters.push_back(tx);
+        if !shared.state.is_open() {
+            return Err(SendPacketError::Disconnected);
+        }
+        let mut queues = shared.queues.borrow_mut();
 
-                if rx.await.is_err() {
-                    return Err(SendPacketError::Disconnected);
-                }
-            }
-            let mut queues = shared.queues.borrow_mut();
+        // publish ack channel
+        let (tx, rx) = shared.pool.queue.channel();
 
-            // publish ack channel
-            let (tx, rx) = shared.pool.queue.channel();
+        // packet id
+        let mut idx = packet.packet_id.map(|i| i.get()).unwrap_or(0);
+        if idx == 0 {
+            idx = shared.next_id(&queues)?;
+            packet.packet_id = NonZeroU16::new(idx);
+        }
+        if queues.id_in_use(idx) {
+            return Err(SendPacketError::PacketIdInUse(idx));
+        }
+        queues.insert_inflight(idx, tx, ack);
 
-            // packet id
-            let mut idx = packet.packet_id.map(|i| i.get()).unwrap_or(0);
-            if idx == 0 {
-                idx = shared.next_id();
-                packet.packet_id = NonZeroU16::new(idx);
-            }
-            if queues.inflight.contains_key(&idx) {
-                return Err(SendPacketError::PacketIdInUse(idx));
-            }
-            queues.inflight.insert(idx, (tx, AckType::Publish));
-            queues.inflight_order.push_back(idx);
+        log::trace!("Publish ({:?}) to {:#?}", qos, packet);
 
-            log::trace!("Publish (QoS1) to {:#?}", packet);
+        // persist publish until it get acknowledged
+        shared.with_store(|store| store.store_publish(&packet));
 
-            match shared.state.write().encode(codec::Packet::Publish(packet), &shared.codec) {
-                Ok(_) => {
-                    // do not borrow cross yield points
-                    drop(queues);
+        shared
+            .state
+            .write()
+            .encode(codec::Packet::Publish(packet), &*shared)
+            .map_err(SendPacketError::Encode)?;
+        Ok((idx, rx))
+    }
+}
 
-                    rx.await.map(|_| ()).map_err(|_| SendPacketError::Disconnected)
-                }
-                Err(err) => Err(SendPacketError::Encode(err)),
+async fn wait_publish_ack(
+    shared: Rc<MqttShared>,
+    idx: u16,
+    rx: pool::Receiver<Ack>,
+    timeout: Option<Duration>,
+) -> Result<(), SendPacketError> {
+    shared.wait_ack(idx, rx, timeout).await?;
+    if let Some(packet_id) = NonZeroU16::new(idx) {
+        shared.with_store(|store| store.ack_publish(packet_id));
+    }
+    Ok(())
+}
+
+/// Acknowledgement of detached publish
+///
+/// Future resolves when publish is acknowledged by the peer. If future is
+/// dropped before it is polled, packet stays in-flight until ack is received.
+pub struct AckFuture(Pin<Box<dyn Future<Output = Result<(), SendPacketError>>>>);
+
+impl Future for AckFuture {
+    type Output = Result<(), SendPacketError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+/// Sender of application events, see `MqttSink::events()`
+pub struct EventSender<T>(Weak<MqttShared>, marker::PhantomData<T>);
+
+impl<T: 'static> EventSender<T> {
+    /// Push event to connection's control service
+    ///
+    /// Events are queued and get handled by connection's dispatcher in order,
+    /// when control service is ready. Event is returned back if connection
+    /// is closed, queued events are dropped on connection close.
+    pub fn send(&self, event: T) -> Result<(), T> {
+        match self.0.upgrade() {
+            Some(shared) if shared.events.is_open() => {
+                shared.events.push(control::Event::new(event));
+                Ok(())
             }
-        } else {
-            Err(SendPacketError::Disconnected)
+            _ => Err(event),
         }
     }
 }
 
+impl<T> Clone for EventSender<T> {
+    fn clone(&self) -> Self {
+        EventSender(self.0.clone(), marker::PhantomData)
+    }
+}
+
 /// Subscribe packet builder
 pub struct SubscribeBuilder {
     id: u16,
     shared: Rc<MqttShared>,
     topic_filters: Vec<(ByteString, codec::QoS)>,
+    error: Option<TopicError>,
 }
 
 impl SubscribeBuilder {
@@ -259,26 +747,34 @@ impl SubscribeBuilder {
     }
 
     /// Add topic filter
-    pub fn topic_filter(mut self, filter: ByteString, qos: codec::QoS) -> Self {
-        self.topic_filters.push((filter, qos));
+    ///
+    /// Filter could be `TopicFilter` or any value that converts to it. Send fails
+    /// with `InvalidTopic` error if filter is not valid.
+    pub fn topic_filter<U>(mut self, filter: U, qos: codec::QoS) -> Self
+    where
+        U: TryInto<TopicFilter>,
+        TopicError: From<U::Error>,
+    {
+        match filter.try_into() {
+            Ok(filter) => self.topic_filters.push((filter.into_inner(), qos)),
+            Err(err) => self.error = self.error.or_else(|| Some(err.into())),
+        }
         self
     }
 
     #[allow(clippy::await_holding_refcell_ref)]
     /// Send subscribe packet
     pub async fn send(self) -> Result<Vec<codec::SubscribeReturnCode>, SendPacketError> {
+        if let Some(err) = self.error {
+            return Err(SendPacketError::InvalidTopic(err));
+        }
         let shared = self.shared;
         let filters = self.topic_filters;
 
         if shared.state.is_open() {
             // handle client receive maximum
             if !shared.has_credit() {
-                let (tx, rx) = shared.pool.waiters.channel();
-                shared.queues.borrow_mut().waiters.push_back(tx);
-
-                if rx.await.is_err() {
-                    return Err(SendPacketError::Disconnected);
-                }
+                shared.wait_credit(None).await?;
             }
             let mut queues = shared.queues.borrow_mut();
 
@@ -286,12 +782,11 @@ impl SubscribeBuilder {
             let (tx, rx) = shared.pool.queue.channel();
 
             // allocate packet id
-            let idx = if self.id == 0 { shared.next_id() } else { self.id };
-            if queues.inflight.contains_key(&idx) {
+            let idx = if self.id == 0 { shared.next_id(&queues)? } else { self.id };
+            if queues.id_in_use(idx) {
                 return Err(SendPacketError::PacketIdInUse(idx));
             }
-            queues.inflight.insert(idx, (tx, AckType::Subscribe));
-            queues.inflight_order.push_back(idx);
+            queues.insert_inflight(idx, tx, AckType::Subscribe);
 
             // send subscribe to client
             log::trace!("Sending subscribe packet id: {} filters:{:?}", idx, filters);
@@ -301,7 +796,7 @@ impl SubscribeBuilder {
                     packet_id: NonZeroU16::new(idx).unwrap(),
                     topic_filters: filters,
                 },
-                &shared.codec,
+                &*shared,
             ) {
                 Ok(_) => {
                     // do not borrow cross yield points
@@ -325,6 +820,7 @@ pub struct UnsubscribeBuilder {
     id: u16,
     shared: Rc<MqttShared>,
     topic_filters: Vec<ByteString>,
+    error: Option<TopicError>,
 }
 
 impl UnsubscribeBuilder {
@@ -340,26 +836,34 @@ impl UnsubscribeBuilder {
     }
 
     /// Add topic filter
-    pub fn topic_filter(mut self, filter: ByteString) -> Self {
-        self.topic_filters.push(filter);
+    ///
+    /// Filter could be `TopicFilter` or any value that converts to it. Send fails
+    /// with `InvalidTopic` error if filter is not valid.
+    pub fn topic_filter<U>(mut self, filter: U) -> Self
+    where
+        U: TryInto<TopicFilter>,
+        TopicError: From<U::Error>,
+    {
+        match filter.try_into() {
+            Ok(filter) => self.topic_filters.push(filter.into_inner()),
+            Err(err) => self.error = self.error.or_else(|| Some(err.into())),
+        }
         self
     }
 
     #[allow(clippy::await_holding_refcell_ref)]
     /// Send unsubscribe packet
     pub async fn send(self) -> Result<(), SendPacketError> {
+        if let Some(err) = self.error {
+            return Err(SendPacketError::InvalidTopic(err));
+        }
         let shared = self.shared;
         let filters = self.topic_filters;
 
         if shared.state.is_open() {
             // handle client receive maximum
             if !shared.has_credit() {
-                let (tx, rx) = shared.pool.waiters.channel();
-                shared.queues.borrow_mut().waiters.push_back(tx);
-
-                if rx.await.is_err() {
-                    return Err(SendPacketError::Disconnected);
-                }
+                shared.wait_credit(None).await?;
             }
             let mut queues = shared.queues.borrow_mut();
 
@@ -367,12 +871,11 @@ impl UnsubscribeBuilder {
             let (tx, rx) = shared.pool.queue.channel();
 
             // allocate packet id
-            let idx = if self.id == 0 { shared.next_id() } else { self.id };
-            if queues.inflight.contains_key(&idx) {
+            let idx = if self.id == 0 { shared.next_id(&queues)? } else { self.id };
+            if queues.id_in_use(idx) {
                 return Err(SendPacketError::PacketIdInUse(idx));
             }
-            queues.inflight.insert(idx, (tx, AckType::Unsubscribe));
-            queues.inflight_order.push_back(idx);
+            queues.insert_inflight(idx, tx, AckType::Unsubscribe);
 
             // send subscribe to client
             log::trace!("Sending unsubscribe packet id: {} filters:{:?}", idx, filters);
@@ -382,7 +885,7 @@ impl UnsubscribeBuilder {
                     packet_id: NonZeroU16::new(idx).unwrap(),
                     topic_filters: filters,
                 },
-                &shared.codec,
+                &*shared,
             ) {
                 Ok(_) => {
                     // do not borrow cross yield points
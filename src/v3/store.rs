@@ -0,0 +1,251 @@
+use std::{cell::RefCell, num::NonZeroU16, rc::Rc};
+
+use ntex::util::{ByteString, HashMap};
+
+use super::codec;
+use crate::types::QoS;
+
+/// Persisted session state
+#[derive(Debug, Clone, Default)]
+pub struct SessionState {
+    /// Topic filters and granted qos
+    pub subscriptions: Vec<(ByteString, QoS)>,
+    /// Outgoing publishes that are not acknowledged by the client
+    pub publishes: Vec<codec::Publish>,
+    /// Packet ids of outgoing qos 2 publishes that are received by the client
+    /// but not completed yet
+    pub releases: Vec<NonZeroU16>,
+    /// Packet ids of incoming qos 2 publishes that are not released yet
+    pub received: Vec<NonZeroU16>,
+}
+
+/// Server session store
+///
+/// Store persists session state per client id. Server loads state
+/// during handshake and updates it on subscribe, unsubscribe, publish
+/// and acknowledgement packets. Store is updated only for accepted
+/// connections, session state of clean session connection is removed
+/// after handshake and when connection get closed.
+pub trait SessionStore {
+    /// Load session state
+    ///
+    /// Returns `None` if session does not exist.
+    fn load(&self, client_id: &str) -> Option<SessionState>;
+
+    /// Remove session state
+    fn remove(&self, client_id: &str);
+
+    /// Store subscription
+    fn subscribe(&self, client_id: &str, filter: &ByteString, qos: QoS);
+
+    /// Remove subscription
+    fn unsubscribe(&self, client_id: &str, filter: &ByteString);
+
+    /// Store outgoing publish, packet is re-sent if it is not acknowledged
+    fn store_publish(&self, client_id: &str, packet: &codec::Publish);
+
+    /// Outgoing qos 2 publish is received by the client (PUBREC)
+    ///
+    /// Publish is replaced with release state, PUBREL packet is re-sent
+    /// if publish is not completed.
+    fn release_publish(&self, client_id: &str, packet_id: NonZeroU16);
+
+    /// Remove acknowledged or completed outgoing publish
+    fn ack_publish(&self, client_id: &str, packet_id: NonZeroU16);
+
+    /// Store packet id of incoming qos 2 publish
+    fn store_received(&self, client_id: &str, packet_id: NonZeroU16);
+
+    /// Remove packet id of released incoming qos 2 publish
+    fn release(&self, client_id: &str, packet_id: NonZeroU16);
+}
+
+/// In-memory session store
+#[derive(Debug, Default)]
+pub struct MemorySessionStore {
+    sessions: RefCell<HashMap<String, SessionState>>,
+}
+
+impl MemorySessionStore {
+    fn with<F: FnOnce(&mut SessionState)>(&self, client_id: &str, f: F) {
+        let mut sessions = self.sessions.borrow_mut();
+        if let Some(state) = sessions.get_mut(client_id) {
+            f(state)
+        } else {
+            let mut state = SessionState::default();
+            f(&mut state);
+            sessions.insert(client_id.to_string(), state);
+        }
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, client_id: &str) -> Option<SessionState> {
+        self.sessions.borrow().get(client_id).cloned()
+    }
+
+    fn remove(&self, client_id: &str) {
+        self.sessions.borrow_mut().remove(client_id);
+    }
+
+    fn subscribe(&self, client_id: &str, filter: &ByteString, qos: QoS) {
+        self.with(client_id, |state| {
+            if let Some(item) = state.subscriptions.iter_mut().find(|item| &item.0 == filter) {
+                item.1 = qos;
+            } else {
+                state.subscriptions.push((filter.clone(), qos));
+            }
+        })
+    }
+
+    fn unsubscribe(&self, client_id: &str, filter: &ByteString) {
+        self.with(client_id, |state| state.subscriptions.retain(|item| &item.0 != filter))
+    }
+
+    fn store_publish(&self, client_id: &str, packet: &codec::Publish) {
+        self.with(client_id, |state| {
+            state.publishes.retain(|pkt| pkt.packet_id != packet.packet_id);
+            state.publishes.push(packet.clone());
+        })
+    }
+
+    fn release_publish(&self, client_id: &str, packet_id: NonZeroU16) {
+        self.with(client_id, |state| {
+            state.publishes.retain(|pkt| pkt.packet_id != Some(packet_id));
+            if !state.releases.contains(&packet_id) {
+                state.releases.push(packet_id);
+            }
+        })
+    }
+
+    fn ack_publish(&self, client_id: &str, packet_id: NonZeroU16) {
+        self.with(client_id, |state| {
+            state.publishes.retain(|pkt| pkt.packet_id != Some(packet_id));
+            state.releases.retain(|id| *id != packet_id);
+        })
+    }
+
+    fn store_received(&self, client_id: &str, packet_id: NonZeroU16) {
+        self.with(client_id, |state| {
+            if !state.received.contains(&packet_id) {
+                state.received.push(packet_id);
+            }
+        })
+    }
+
+    fn release(&self, client_id: &str, packet_id: NonZeroU16) {
+        self.with(client_id, |state| state.received.retain(|id| *id != packet_id))
+    }
+}
+
+/// Session store of the connection
+pub(super) struct ConnectionStore {
+    store: Rc<dyn SessionStore>,
+    client_id: ByteString,
+    clean_session: bool,
+    received: RefCell<Vec<NonZeroU16>>,
+}
+
+impl ConnectionStore {
+    pub(super) fn new(
+        store: Rc<dyn SessionStore>,
+        client_id: ByteString,
+        clean_session: bool,
+    ) -> Self {
+        ConnectionStore { store, client_id, clean_session, received: RefCell::new(Vec::new()) }
+    }
+
+    /// Set packet ids of not released qos 2 publishes of restored session
+    pub(super) fn restore_received(&self, ids: Vec<NonZeroU16>) {
+        *self.received.borrow_mut() = ids;
+    }
+
+    pub(super) fn take_received(&self) -> Vec<NonZeroU16> {
+        std::mem::take(&mut *self.received.borrow_mut())
+    }
+
+    pub(super) fn subscribe(&self, filter: &ByteString, qos: QoS) {
+        self.store.subscribe(&self.client_id, filter, qos)
+    }
+
+    pub(super) fn unsubscribe(&self, filter: &ByteString) {
+        self.store.unsubscribe(&self.client_id, filter)
+    }
+
+    pub(super) fn store_publish(&self, packet: &codec::Publish) {
+        self.store.store_publish(&self.client_id, packet)
+    }
+
+    pub(super) fn release_publish(&self, packet_id: NonZeroU16) {
+        self.store.release_publish(&self.client_id, packet_id)
+    }
+
+    pub(super) fn ack_publish(&self, packet_id: NonZeroU16) {
+        self.store.ack_publish(&self.client_id, packet_id)
+    }
+
+    pub(super) fn store_received(&self, packet_id: NonZeroU16) {
+        self.store.store_received(&self.client_id, packet_id)
+    }
+
+    pub(super) fn release(&self, packet_id: NonZeroU16) {
+        self.store.release(&self.client_id, packet_id)
+    }
+
+    /// Connection is closed, clean session state is discarded
+    pub(super) fn closed(&self) {
+        if self.clean_session {
+            self.store.remove(&self.client_id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntex::util::Bytes;
+
+    use super::*;
+
+    #[test]
+    fn test_memory_store() {
+        let store = MemorySessionStore::default();
+        assert!(store.load("client").is_none());
+
+        let filter = ByteString::from_static("topic");
+        store.subscribe("client", &filter, QoS::AtMostOnce);
+        store.subscribe("client", &filter, QoS::AtLeastOnce);
+        store.store_received("client", NonZeroU16::new(1).unwrap());
+        store.store_publish(
+            "client",
+            &codec::Publish {
+                dup: false,
+                retain: false,
+                qos: QoS::AtLeastOnce,
+                topic: filter.clone(),
+                packet_id: NonZeroU16::new(2),
+                payload: Bytes::new(),
+            },
+        );
+
+        let state = store.load("client").unwrap();
+        assert_eq!(state.subscriptions, vec![(filter.clone(), QoS::AtLeastOnce)]);
+        assert_eq!(state.received, vec![NonZeroU16::new(1).unwrap()]);
+        assert_eq!(state.publishes.len(), 1);
+
+        store.release_publish("client", NonZeroU16::new(2).unwrap());
+        let state = store.load("client").unwrap();
+        assert!(state.publishes.is_empty());
+        assert_eq!(state.releases, vec![NonZeroU16::new(2).unwrap()]);
+
+        store.unsubscribe("client", &filter);
+        store.release("client", NonZeroU16::new(1).unwrap());
+        store.ack_publish("client", NonZeroU16::new(2).unwrap());
+        let state = store.load("client").unwrap();
+        assert!(state.subscriptions.is_empty());
+        assert!(state.received.is_empty());
+        assert!(state.releases.is_empty());
+
+        store.remove("client");
+        assert!(store.load("client").is_none());
+    }
+}
@@ -0,0 +1,135 @@
+use std::task::{Context, Poll};
+use std::{marker::PhantomData, rc::Rc};
+
+use ntex::service::Service;
+use ntex::util::{join_all, Either};
+
+use crate::acl::{AclResponse, DynAcl};
+
+use super::control::{ControlMessage, ControlResult};
+use super::publish::{Publish, PublishAck};
+use super::{codec, Session};
+
+pub(super) type AclRef<St> = Rc<dyn DynAcl<Session<St>>>;
+
+/// Publish service with topic authorization
+pub(super) struct AclPublish<T, St> {
+    service: Rc<T>,
+    acl: Option<(AclRef<St>, Session<St>)>,
+}
+
+impl<T, St> AclPublish<T, St> {
+    pub(super) fn new(service: T, acl: Option<AclRef<St>>, session: Session<St>) -> Self {
+        AclPublish { service: Rc::new(service), acl: acl.map(|acl| (acl, session)) }
+    }
+}
+
+impl<T, St> Service for AclPublish<T, St>
+where
+    T: Service<Request = Publish, Response = PublishAck>,
+{
+    type Request = Publish;
+    type Response = PublishAck;
+    type Error = T::Error;
+    type Future = Either<T::Future, AclResponse<T, bool>>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        self.service.poll_shutdown(cx, is_error)
+    }
+
+    fn call(&self, req: Publish) -> Self::Future {
+        if let Some((ref acl, ref session)) = self.acl {
+            let fut = acl.allow_publish(session, req.topic().get_ref());
+            Either::Right(AclResponse::new(fut, self.service.clone(), req, |req, allowed| {
+                if allowed {
+                    Ok(req)
+                } else {
+                    log::trace!("Publish to {:?} is not authorized", req.topic().get_ref());
+                    Err(PublishAck::new(codec::PublishAckReason::NotAuthorized))
+                }
+            }))
+        } else {
+            Either::Left(self.service.call(req))
+        }
+    }
+}
+
+/// Control service with subscription authorization
+pub(super) struct AclControl<C, St, E> {
+    service: Rc<C>,
+    acl: Option<(AclRef<St>, Session<St>)>,
+    _t: PhantomData<E>,
+}
+
+impl<C, St, E> AclControl<C, St, E> {
+    pub(super) fn new(service: C, acl: Option<AclRef<St>>, session: Session<St>) -> Self {
+        AclControl {
+            service: Rc::new(service),
+            acl: acl.map(|acl| (acl, session)),
+            _t: PhantomData,
+        }
+    }
+}
+
+impl<C, St, E> Service for AclControl<C, St, E>
+where
+    C: Service<Request = ControlMessage<E>, Response = ControlResult>,
+{
+    type Request = ControlMessage<E>;
+    type Response = ControlResult;
+    type Error = C::Error;
+    type Future = Either<C::Future, AclResponse<C, Vec<bool>>>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        self.service.poll_shutdown(cx, is_error)
+    }
+
+    fn call(&self, req: ControlMessage<E>) -> Self::Future {
+        match (req, &self.acl) {
+            (ControlMessage::Subscribe(req), Some((acl, session))) => {
+                let checks = req
+                    .packet()
+                    .topic_filters
+                    .iter()
+                    .map(|(filter, _)| acl.allow_subscribe(session, filter))
+                    .collect::<Vec<_>>();
+                let fut = Box::pin(join_all(checks));
+
+                Either::Right(AclResponse::new(
+                    fut,
+                    self.service.clone(),
+                    ControlMessage::Subscribe(req),
+                    |req, allowed| {
+                        if let ControlMessage::Subscribe(mut req) = req {
+                            for (idx, allowed) in allowed.into_iter().enumerate() {
+                                if !allowed {
+                                    log::trace!(
+                                        "Subscription to {:?} is not authorized",
+                                        req.packet().topic_filters[idx].0
+                                    );
+                                    req.reject(idx, codec::SubscribeAckReason::NotAuthorized);
+                                }
+                            }
+                            Ok(ControlMessage::Subscribe(req))
+                        } else {
+                            Ok(req)
+                        }
+                    },
+                ))
+            }
+            (req, _) => Either::Left(self.service.call(req)),
+        }
+    }
+}
@@ -1,15 +1,19 @@
+use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
-use std::{cell::RefCell, convert::TryFrom, future::Future, marker, num::NonZeroU16, rc::Rc};
+use std::{cell::RefCell, cmp, convert::TryFrom, future::Future, marker, num::NonZeroU16};
+use std::{pin::Pin, rc::Rc, str::FromStr};
 
+use ntex::channel::mpsc;
 use ntex::codec::{AsyncRead, AsyncWrite};
 use ntex::router::{IntoPattern, Path, Router, RouterBuilder};
-use ntex::rt::time::{delay_until, Instant as RtInstant};
+use ntex::rt::time::{delay_for, delay_until, Instant as RtInstant};
 use ntex::service::boxed::BoxService;
 use ntex::service::{into_service, IntoService, Service};
 use ntex::util::{ByteString, Either, HashMap, Ready};
 
-use crate::error::MqttError;
-use crate::io::{Dispatcher, Timer};
+use crate::error::{MqttError, SendPacketError};
+use crate::io::{CoalescedIo, Dispatcher, Timer};
+use crate::topic::Topic;
 use crate::v5::publish::{Publish, PublishAck};
 use crate::v5::{codec, shared::MqttShared, sink::MqttSink, ControlResult};
 
@@ -22,6 +26,8 @@ pub struct Client<Io> {
     shared: Rc<MqttShared>,
     keepalive: u16,
     disconnect_timeout: u16,
+    write_coalesce: Option<(usize, usize, Duration)>,
+    ping_timeout: u16,
     max_receive: usize,
     pkt: codec::ConnectAck,
 }
@@ -31,6 +37,7 @@ where
     T: AsyncRead + AsyncWrite + Unpin,
 {
     /// Construct new `Dispatcher` instance with outgoing messages stream.
+    #[allow(clippy::too_many_arguments)]
     pub(super) fn new(
         io: T,
         shared: Rc<MqttShared>,
@@ -38,6 +45,8 @@ where
         max_receive: u16,
         keepalive: u16,
         disconnect_timeout: u16,
+        write_coalesce: Option<(usize, usize, Duration)>,
+        ping_timeout: u16,
     ) -> Self {
         Client {
             io,
@@ -45,6 +54,8 @@ where
             shared,
             keepalive,
             disconnect_timeout,
+            write_coalesce,
+            ping_timeout,
             max_receive: max_receive as usize,
         }
     }
@@ -66,6 +77,14 @@ where
         self.pkt.session_present
     }
 
+    #[inline]
+    /// Client identifier assigned by the server
+    ///
+    /// Server assigns client id if connect packet contains empty client id.
+    pub fn assigned_client_id(&self) -> Option<&ByteString> {
+        self.pkt.assigned_client_id.as_ref()
+    }
+
     #[inline]
     /// Get reference to `ConnectAck` packet
     pub fn packet(&self) -> &codec::ConnectAck {
@@ -87,17 +106,46 @@ where
         E: From<U::Error>,
         PublishAck: TryFrom<U::Error, Error = E>,
     {
-        let mut builder = Router::build();
-        builder.path(address, 0);
-        let handlers = vec![ntex::boxed::service(service.into_service())];
+        let mut router = self.into_router();
+        router.builder.path(address, 0);
+        router.handlers.push(ntex::boxed::service(service.into_service()));
+        router
+    }
 
+    /// Configure handler for a specific topic filter
+    ///
+    /// Client subscribes to the topic filter on start and dispatches
+    /// matching publishes to the handler.
+    ///
+    /// Panics if topic filter is not valid.
+    pub fn route<T, F, U, E>(
+        self,
+        filter: T,
+        opts: codec::SubscriptionOptions,
+        service: F,
+    ) -> ClientRouter<Io, E, U::Error>
+    where
+        ByteString: From<T>,
+        F: IntoService<U>,
+        U: Service<Request = Publish, Response = PublishAck> + 'static,
+        U::Error: 'static,
+        E: From<U::Error> + 'static,
+        PublishAck: TryFrom<U::Error, Error = E>,
+    {
+        self.into_router().route(filter, opts, service)
+    }
+
+    fn into_router<E, PErr>(self) -> ClientRouter<Io, E, PErr> {
         ClientRouter {
-            builder,
-            handlers,
+            builder: Router::build(),
+            handlers: Vec::new(),
+            routes: Rc::new(RefCell::new(Vec::new())),
             io: self.io,
             shared: self.shared,
             keepalive: self.keepalive,
             disconnect_timeout: self.disconnect_timeout,
+            write_coalesce: self.write_coalesce,
+            ping_timeout: self.ping_timeout,
             max_receive: self.max_receive,
             _t: marker::PhantomData,
         }
@@ -108,7 +156,11 @@ where
     /// Default handler closes connection on any control message.
     pub async fn start_default(self) {
         if self.keepalive > 0 {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(keepalive(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.ping_timeout,
+            ));
         }
 
         let dispatcher = create_dispatcher(
@@ -122,7 +174,7 @@ where
         );
 
         let _ = Dispatcher::with(
-            self.io,
+            CoalescedIo::new(self.io, self.write_coalesce),
             self.shared.state.clone(),
             self.shared,
             dispatcher,
@@ -133,6 +185,59 @@ where
         .await;
     }
 
+    /// Turn client into a stream of incoming publishes
+    ///
+    /// Connection is handled by spawned task. Incoming publish is acknowledged
+    /// after it get pushed to the stream, stream terminates when connection
+    /// get closed. Returned sink could be used for sending packets.
+    pub fn into_stream(self) -> (MqttSink, PublishStream) {
+        let (tx, rx) = mpsc::channel();
+        let sink = MqttSink::new(self.shared.clone());
+
+        ntex::rt::spawn(async move {
+            if self.keepalive > 0 {
+                ntex::rt::spawn(keepalive(
+                    MqttSink::new(self.shared.clone()),
+                    self.keepalive,
+                    self.ping_timeout,
+                ));
+            }
+
+            let tx2 = tx.clone();
+            let dispatcher = create_dispatcher(
+                MqttSink::new(self.shared.clone()),
+                self.max_receive,
+                16,
+                into_service(move |pkt: Publish| {
+                    if tx2.send(pkt).is_err() {
+                        log::trace!("Publish stream is dropped");
+                    }
+                    let ack = PublishAck::new(codec::PublishAckReason::Success);
+                    Ready::<_, ()>::Ok(Either::Right(ack))
+                }),
+                into_service(|msg: ControlMessage<()>| {
+                    Ready::Ok(msg.disconnect(codec::Disconnect::default()))
+                }),
+            );
+
+            let _ = Dispatcher::with(
+                CoalescedIo::new(self.io, self.write_coalesce),
+                self.shared.state.clone(),
+                self.shared,
+                dispatcher,
+                Timer::with(Duration::from_secs(1)),
+            )
+            .keepalive_timeout(0)
+            .disconnect_timeout(self.disconnect_timeout)
+            .await;
+
+            // connection is closed, terminate stream
+            tx.close();
+        });
+
+        (sink, PublishStream(rx))
+    }
+
     /// Run client with provided control messages handler
     pub async fn start<F, S, E>(self, service: F) -> Result<(), MqttError<E>>
     where
@@ -141,7 +246,11 @@ where
         S: Service<Request = ControlMessage<E>, Response = ControlResult, Error = E> + 'static,
     {
         if self.keepalive > 0 {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(keepalive(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.ping_timeout,
+            ));
         }
 
         let dispatcher = create_dispatcher(
@@ -153,7 +262,7 @@ where
         );
 
         Dispatcher::with(
-            self.io,
+            CoalescedIo::new(self.io, self.write_coalesce),
             self.shared.state.clone(),
             self.shared,
             dispatcher,
@@ -165,16 +274,37 @@ where
     }
 }
 
+/// Stream of incoming publishes
+pub struct PublishStream(mpsc::Receiver<Publish>);
+
+impl ntex::Stream for PublishStream {
+    type Item = Publish;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Publish>> {
+        Pin::new(&mut self.get_mut().0).poll_next(cx)
+    }
+}
+
 type Handler<E> = BoxService<Publish, PublishAck, E>;
 
+struct Route<E> {
+    filter: ByteString,
+    topic: Topic,
+    opts: codec::SubscriptionOptions,
+    handler: Rc<Handler<E>>,
+}
+
 /// Mqtt client with routing capabilities
 pub struct ClientRouter<Io, Err, PErr> {
     builder: RouterBuilder<usize>,
     handlers: Vec<Handler<PErr>>,
+    routes: Rc<RefCell<Vec<Route<PErr>>>>,
     io: Io,
     shared: Rc<MqttShared>,
     keepalive: u16,
     disconnect_timeout: u16,
+    write_coalesce: Option<(usize, usize, Duration)>,
+    ping_timeout: u16,
     max_receive: usize,
     _t: marker::PhantomData<Err>,
 }
@@ -198,24 +328,60 @@ where
         self
     }
 
+    /// Configure handler for a specific topic filter
+    ///
+    /// Client subscribes to the topic filter on start and dispatches
+    /// matching publishes to the handler.
+    ///
+    /// Panics if topic filter is not valid.
+    pub fn route<T, F, S>(self, filter: T, opts: codec::SubscriptionOptions, service: F) -> Self
+    where
+        ByteString: From<T>,
+        F: IntoService<S>,
+        S: Service<Request = Publish, Response = PublishAck, Error = PErr> + 'static,
+    {
+        let filter = ByteString::from(filter);
+        let topic = match Topic::from_str(&filter) {
+            Ok(topic) if topic.is_valid() => topic,
+            _ => panic!("Invalid topic filter: {:?}", filter),
+        };
+        self.routes.borrow_mut().push(Route {
+            filter,
+            topic,
+            opts,
+            handler: Rc::new(ntex::boxed::service(service.into_service())),
+        });
+        self
+    }
+
+    /// Get handle for configured topic filter handlers
+    pub fn routes(&self) -> Routes<PErr> {
+        Routes { routes: self.routes.clone(), sink: MqttSink::new(self.shared.clone()) }
+    }
+
     /// Run client with default control messages handler
     pub async fn start_default(self) {
         if self.keepalive > 0 {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(keepalive(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.ping_timeout,
+            ));
         }
+        self.subscribe();
 
         let dispatcher = create_dispatcher(
             MqttSink::new(self.shared.clone()),
             self.max_receive,
             16,
-            dispatch(self.builder.finish(), self.handlers),
+            dispatch(self.builder.finish(), self.handlers, self.routes),
             into_service(|msg: ControlMessage<Err>| {
                 Ready::Ok(msg.disconnect(codec::Disconnect::default()))
             }),
         );
 
         let _ = Dispatcher::with(
-            self.io,
+            CoalescedIo::new(self.io, self.write_coalesce),
             self.shared.state.clone(),
             self.shared,
             dispatcher,
@@ -234,19 +400,24 @@ where
             + 'static,
     {
         if self.keepalive > 0 {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(keepalive(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.ping_timeout,
+            ));
         }
+        self.subscribe();
 
         let dispatcher = create_dispatcher(
             MqttSink::new(self.shared.clone()),
             self.max_receive,
             16,
-            dispatch(self.builder.finish(), self.handlers),
+            dispatch(self.builder.finish(), self.handlers, self.routes),
             service.into_service(),
         );
 
         Dispatcher::with(
-            self.io,
+            CoalescedIo::new(self.io, self.write_coalesce),
             self.shared.state.clone(),
             self.shared,
             dispatcher,
@@ -256,11 +427,71 @@ where
         .disconnect_timeout(self.disconnect_timeout)
         .await
     }
+
+    /// Subscribe to configured topic filters
+    fn subscribe(&self) {
+        let routes = self.routes.borrow();
+        if routes.is_empty() {
+            return;
+        }
+
+        let mut builder = MqttSink::new(self.shared.clone()).subscribe(None);
+        for route in routes.iter() {
+            builder = builder.topic_filter(route.filter.clone(), route.opts.clone());
+        }
+        ntex::rt::spawn(async move {
+            match builder.send().await {
+                Ok(ack) => log::trace!("Topic filters are subscribed: {:?}", ack),
+                Err(err) => log::error!("Cannot subscribe topic filters: {}", err),
+            }
+        });
+    }
+}
+
+/// Handle for client topic filter handlers
+pub struct Routes<E> {
+    routes: Rc<RefCell<Vec<Route<E>>>>,
+    sink: MqttSink,
+}
+
+impl<E> Clone for Routes<E> {
+    fn clone(&self) -> Self {
+        Routes { routes: self.routes.clone(), sink: self.sink.clone() }
+    }
+}
+
+impl<E> Routes<E> {
+    /// Remove handler for topic filter and unsubscribe from the topic filter
+    ///
+    /// Returns UNSUBACK packet if handler for topic filter existed.
+    pub async fn remove(
+        &self,
+        filter: &str,
+    ) -> Result<Option<codec::UnsubscribeAck>, SendPacketError> {
+        let removed = {
+            let mut routes = self.routes.borrow_mut();
+            let len = routes.len();
+            routes.retain(|route| route.filter != filter);
+            len != routes.len()
+        };
+
+        if removed {
+            self.sink
+                .unsubscribe()
+                .topic_filter(ByteString::from(filter))
+                .send()
+                .await
+                .map(Some)
+        } else {
+            Ok(None)
+        }
+    }
 }
 
 fn dispatch<Err, PErr>(
     router: Router<usize>,
     handlers: Vec<Handler<PErr>>,
+    routes: Rc<RefCell<Vec<Route<PErr>>>>,
 ) -> impl Service<Request = Publish, Response = Either<Publish, PublishAck>, Error = Err>
 where
     PErr: 'static,
@@ -280,6 +511,16 @@ where
                 // exec handler
                 return Either::Left(call(req, &handlers[*idx]));
             }
+
+            // topic filter handlers
+            let handler = routes
+                .borrow()
+                .iter()
+                .find(|route| route.topic.matches_str(req.publish_topic()))
+                .map(|route| route.handler.clone());
+            if let Some(handler) = handler {
+                return Either::Left(call(req, &*handler));
+            }
         }
         // handle publish with topic alias
         else if let Some(ref alias) = req.packet().properties.topic_alias {
@@ -317,18 +558,30 @@ where
     }
 }
 
-async fn keepalive(sink: MqttSink, timeout: u16) {
+async fn keepalive(sink: MqttSink, timeout: u16, ping_timeout: u16) {
     log::debug!("start mqtt client keep-alive task");
 
     let keepalive = Duration::from_secs(timeout as u64);
+    let ping_timeout = cmp::min(Duration::from_millis(ping_timeout as u64), keepalive);
+    let mut expire = RtInstant::from_std(Instant::now() + keepalive);
     loop {
-        let expire = RtInstant::from_std(Instant::now() + keepalive);
         delay_until(expire).await;
+        expire = RtInstant::from_std(Instant::now() + keepalive);
 
         if !sink.ping() {
             // connection is closed
             log::debug!("mqtt client connection is closed, stopping keep-alive task");
             break;
         }
+
+        // wait for ping response
+        if ping_timeout > Duration::from_millis(0) {
+            delay_for(ping_timeout).await;
+            if sink.is_ping_pending() {
+                log::error!("mqtt ping response timeout, closing connection");
+                sink.close();
+                break;
+            }
+        }
     }
 }
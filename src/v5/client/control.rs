@@ -1,4 +1,4 @@
-use crate::{error, v5::codec};
+use crate::{error, utils::CloseState, v5::codec};
 
 pub use crate::v5::control::{Closed, ControlResult, Disconnect, Error, ProtocolError};
 
@@ -24,8 +24,8 @@ impl<E> ControlMessage<E> {
         ControlMessage::Disconnect(Disconnect(pkt))
     }
 
-    pub(super) fn closed(is_error: bool) -> Self {
-        ControlMessage::Closed(Closed::new(is_error))
+    pub(super) fn closed(is_error: bool, state: &CloseState) -> Self {
+        ControlMessage::Closed(Closed::new(is_error, state))
     }
 
     pub(super) fn error(err: E) -> Self {
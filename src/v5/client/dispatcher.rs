@@ -3,12 +3,12 @@ use std::task::{Context, Poll};
 use std::{future::Future, marker::PhantomData, num::NonZeroU16, pin::Pin, rc::Rc};
 
 use ntex::service::Service;
-use ntex::util::{Either, HashSet, Ready};
+use ntex::util::{ByteString, Either, HashMap, HashSet, Ready};
 
 use crate::error::{MqttError, ProtocolError};
 use crate::v5::shared::{Ack, MqttShared};
 use crate::v5::{codec, publish::Publish, publish::PublishAck, sink::MqttSink};
-use crate::{io::DispatchItem, types::packet_type};
+use crate::{io::DispatchItem, trace::Traced, types::packet_type};
 
 use super::control::{ControlMessage, ControlResult};
 
@@ -33,7 +33,17 @@ where
         > + 'static,
     C: Service<Request = ControlMessage<E>, Response = ControlResult, Error = E> + 'static,
 {
-    Dispatcher::<_, _, E>::new(sink, max_receive as usize, max_topic_alias, publish, control)
+    let span = sink.shared().span.borrow().clone();
+    Traced::new(
+        Dispatcher::<_, _, E>::new(
+            sink,
+            max_receive as usize,
+            max_topic_alias,
+            publish,
+            control,
+        ),
+        span,
+    )
 }
 
 /// Mqtt protocol dispatcher
@@ -54,7 +64,7 @@ struct Inner<C> {
 
 struct PublishInfo {
     inflight: HashSet<NonZeroU16>,
-    aliases: HashSet<NonZeroU16>,
+    aliases: HashMap<NonZeroU16, ByteString>,
 }
 
 impl<T, C, E> Dispatcher<T, C, E>
@@ -82,7 +92,7 @@ where
                 control,
                 sink,
                 info: RefCell::new(PublishInfo {
-                    aliases: HashSet::default(),
+                    aliases: HashMap::default(),
                     inflight: HashSet::default(),
                 }),
             }),
@@ -113,7 +123,11 @@ where
         let res1 = self.publish.poll_ready(cx).map_err(MqttError::Service)?;
         let res2 = self.inner.control.poll_ready(cx).map_err(MqttError::Service)?;
 
-        if res1.is_pending() || res2.is_pending() {
+        // paused by application
+        let shared = self.inner.sink.shared();
+        let res3 = shared.read_pause.poll_ready(&shared.state, cx);
+
+        if res1.is_pending() || res2.is_pending() || res3.is_pending() {
             Poll::Pending
         } else {
             Poll::Ready(Ok(()))
@@ -122,9 +136,13 @@ where
 
     fn poll_shutdown(&self, _: &mut Context<'_>, is_error: bool) -> Poll<()> {
         if !self.shutdown.get() {
+            let closed = ControlMessage::closed(is_error, &self.inner.sink.shared().close);
             self.inner.sink.drop_sink();
             self.shutdown.set(true);
-            let fut = self.inner.control.call(ControlMessage::closed(is_error));
+            self.inner.sink.shared().pool.metrics.connection_closed();
+            self.inner.sink.shared().received_hook.borrow_mut().take();
+            self.inner.sink.shared().released.borrow_mut().take();
+            let fut = self.inner.control.call(closed);
             ntex::rt::spawn(async move {
                 let _ = fut.await;
             });
@@ -136,9 +154,23 @@ where
         log::trace!("Dispatch packet: {:#?}", request);
 
         match request {
-            DispatchItem::Item(codec::Packet::Publish(publish)) => {
+            DispatchItem::Item(codec::Packet::Publish(mut publish)) => {
                 let info = self.inner.clone();
                 let packet_id = publish.packet_id;
+                let qos2 = publish.qos == codec::QoS::ExactlyOnce;
+
+                // qos2 publish is already received, peer did not get PUBREC
+                if let Some(pid) = packet_id.filter(|_| qos2) {
+                    if self.inner.sink.shared().received.borrow().contains(&pid) {
+                        log::trace!("Duplicated qos2 publish: {}", pid);
+                        return Either::Right(Either::Left(Ready::Ok(Some(
+                            codec::Packet::PublishReceived(codec::PublishAck {
+                                packet_id: pid,
+                                ..Default::default()
+                            }),
+                        ))));
+                    }
+                }
 
                 {
                     let mut inner = info.info.borrow_mut();
@@ -159,24 +191,43 @@ where
                             )));
                         }
 
-                        // check for duplicated packet id
+                        // check for duplicated packet id, qos2 publish is answered with PUBREC
                         if !inner.inflight.insert(pid) {
-                            self.inner.sink.send(codec::Packet::PublishAck(
-                                codec::PublishAck {
-                                    packet_id: pid,
-                                    reason_code: codec::PublishAckReason::PacketIdentifierInUse,
-                                    ..Default::default()
-                                },
-                            ));
+                            let ack = codec::PublishAck {
+                                packet_id: pid,
+                                reason_code: codec::PublishAckReason::PacketIdentifierInUse,
+                                ..Default::default()
+                            };
+                            self.inner.sink.send(if qos2 {
+                                codec::Packet::PublishReceived(ack)
+                            } else {
+                                codec::Packet::PublishAck(ack)
+                            });
                             return Either::Right(Either::Left(Ready::Ok(None)));
                         }
                     }
 
                     // handle topic aliases
                     if let Some(alias) = publish.properties.topic_alias {
+                        // alias must not exceed advertised topic alias maximum
+                        if alias.get() > self.max_topic_alias {
+                            log::trace!(
+                                "Topic alias is greater than max topic alias: {} > {}",
+                                alias,
+                                self.max_topic_alias
+                            );
+                            return Either::Right(Either::Right(ControlResponse::new(
+                                ControlMessage::proto_error(ProtocolError::MaxTopicAlias),
+                                &self.inner,
+                            )));
+                        }
+
                         // check existing topic
                         if publish.topic.is_empty() {
-                            if !inner.aliases.contains(&alias) {
+                            if let Some(topic) = inner.aliases.get(&alias) {
+                                publish.topic = topic.clone();
+                            } else {
+                                log::trace!("Unknown topic alias: {}", alias);
                                 return Either::Right(Either::Right(ControlResponse::new(
                                     ControlMessage::proto_error(
                                         ProtocolError::UnknownTopicAlias,
@@ -185,24 +236,26 @@ where
                                 )));
                             }
                         } else {
-                            if alias.get() > self.max_topic_alias {
-                                return Either::Right(Either::Right(ControlResponse::new(
-                                    ControlMessage::proto_error(ProtocolError::MaxTopicAlias),
-                                    &self.inner,
-                                )));
-                            }
-
                             // record new alias
-                            inner.aliases.insert(alias);
+                            inner.aliases.insert(alias, publish.topic.clone());
                         }
                     }
                 }
 
                 Either::Left(PublishResponse {
                     packet_id: packet_id.map(|v| v.get()).unwrap_or(0),
+                    qos2,
                     inner: info,
                     state: PublishResponseState::Publish {
-                        fut: self.publish.call(Publish::new(publish)),
+                        fut: match self.inner.sink.pkt_response(publish) {
+                            // response for pending request
+                            Ok(()) => Either::Right(Ready::Ok(ntex::util::Either::Right(
+                                PublishAck::new(codec::PublishAckReason::Success),
+                            ))),
+                            Err(publish) => {
+                                Either::Left(self.publish.call(Publish::new(publish)))
+                            }
+                        },
                     },
                     _t: PhantomData,
                 })
@@ -217,6 +270,46 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
+            DispatchItem::Item(codec::Packet::PublishReceived(packet)) => {
+                if let Err(err) = self.inner.sink.pkt_ack(Ack::Receive(packet)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(err),
+                        &self.inner,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishComplete(packet)) => {
+                if let Err(err) = self.inner.sink.pkt_ack(Ack::Complete(packet)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(err),
+                        &self.inner,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishRelease(ack)) => {
+                let shared = self.inner.sink.shared();
+                let reason_code = if shared.received.borrow_mut().remove(&ack.packet_id) {
+                    if let Some(hook) = &*shared.received_hook.borrow() {
+                        hook(ack.packet_id, false);
+                    }
+                    codec::PublishAck2Reason::Success
+                } else {
+                    log::trace!("Unknown packet id for publish release: {:?}", ack.packet_id);
+                    codec::PublishAck2Reason::PacketIdNotFound
+                };
+                Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PublishComplete(
+                    codec::PublishAck2 {
+                        packet_id: ack.packet_id,
+                        reason_code,
+                        properties: codec::UserProperties::default(),
+                        reason_string: None,
+                    },
+                )))))
+            }
             DispatchItem::Item(codec::Packet::SubscribeAck(packet)) => {
                 if let Err(err) = self.inner.sink.pkt_ack(Ack::Subscribe(packet)) {
                     Either::Right(Either::Right(ControlResponse::new(
@@ -240,10 +333,17 @@ where
             DispatchItem::Item(codec::Packet::PingRequest) => {
                 Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PingResponse))))
             }
-            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => Either::Right(Either::Right(
-                ControlResponse::new(ControlMessage::dis(pkt), &self.inner),
-            )),
-            DispatchItem::Item(codec::Packet::Auth(_)) => {
+            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => {
+                self.inner.sink.shared().close.peer();
+                Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::dis(pkt),
+                    &self.inner,
+                )))
+            }
+            DispatchItem::Item(codec::Packet::Auth(pkt)) => {
+                if self.inner.sink.pkt_auth(pkt).is_ok() {
+                    return Either::Right(Either::Left(Ready::Ok(None)));
+                }
                 Either::Right(Either::Right(ControlResponse::new(
                     ControlMessage::proto_error(ProtocolError::Unexpected(
                         packet_type::AUTH,
@@ -271,6 +371,7 @@ where
                 )))
             }
             DispatchItem::Item(codec::Packet::PingResponse) => {
+                self.inner.sink.pkt_ping_response();
                 Either::Right(Either::Left(Ready::Ok(None)))
             }
             DispatchItem::Item(pkt) => {
@@ -289,10 +390,13 @@ where
                     &self.inner,
                 )))
             }
-            DispatchItem::IoError(err) => Either::Right(Either::Right(ControlResponse::new(
-                ControlMessage::proto_error(ProtocolError::Io(err)),
-                &self.inner,
-            ))),
+            DispatchItem::IoError(err) => {
+                let err = self.inner.sink.shared().close.io_error(err);
+                Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::proto_error(ProtocolError::Io(err)),
+                    &self.inner,
+                )))
+            }
             DispatchItem::KeepAliveTimeout => {
                 Either::Right(Either::Right(ControlResponse::new(
                     ControlMessage::proto_error(ProtocolError::KeepAliveTimeout),
@@ -312,6 +416,7 @@ pin_project_lite::pin_project! {
         #[pin]
         state: PublishResponseState<T, C, E>,
         packet_id: u16,
+        qos2: bool,
         inner: Rc<Inner<C>>,
         _t: PhantomData<E>,
     }
@@ -320,7 +425,7 @@ pin_project_lite::pin_project! {
 pin_project_lite::pin_project! {
     #[project = PublishResponseStateProject]
     enum PublishResponseState<T: Service, C: Service, E> {
-        Publish { #[pin] fut: T::Future },
+        Publish { #[pin] fut: Either<T::Future, Ready<T::Response, T::Error>> },
         Control { #[pin] fut: ControlResponse<C, E> },
     }
 }
@@ -371,7 +476,19 @@ where
                         reason_string: ack.reason_string,
                         properties: ack.properties,
                     };
-                    Poll::Ready(Ok(Some(codec::Packet::PublishAck(ack))))
+                    if *this.qos2 {
+                        // keep packet id until PUBREL
+                        if u8::from(ack.reason_code) < 0x80 {
+                            let shared = this.inner.sink.shared();
+                            shared.received.borrow_mut().insert(id);
+                            if let Some(hook) = &*shared.received_hook.borrow() {
+                                hook(id, true);
+                            }
+                        }
+                        Poll::Ready(Ok(Some(codec::Packet::PublishReceived(ack))))
+                    } else {
+                        Poll::Ready(Ok(Some(codec::Packet::PublishAck(ack))))
+                    }
                 } else {
                     Poll::Ready(Ok(None))
                 }
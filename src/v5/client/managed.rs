@@ -0,0 +1,596 @@
+use std::cell::{Cell, RefCell};
+use std::{num::NonZeroU16, rc::Rc, time::Duration};
+
+use futures_channel::oneshot;
+use ntex::channel::condition::Condition;
+use ntex::codec::{AsyncRead, AsyncWrite};
+use ntex::connect::{self, Address, Connect};
+use ntex::rt::time::delay_for;
+use ntex::service::{IntoService, Service};
+use ntex::util::{ByteString, Bytes};
+
+use super::store::{ClientStore, MemoryStore};
+use super::{codec, connector::MqttConnector, control::ControlMessage, ControlResult};
+use crate::backoff::{BackoffStrategy, ExponentialBackoff};
+use crate::offline::{OfflineBuffer, OfflinePolicy};
+use crate::v5::error::{ClientError, PublishQos1Error, PublishQos2Error, SendPacketError};
+use crate::v5::sink::MqttSink;
+
+/// Managed mqtt client
+///
+/// Managed client re-connects to the server if connection get closed.
+/// Re-connect resumes session (`clean_start` is not set), topic filters are
+/// re-subscribed only if server does not have session state.
+pub struct ManagedClient<A, T> {
+    connector: MqttConnector<A, T>,
+    inner: Rc<Inner>,
+    backoff: Box<dyn BackoffStrategy>,
+    abort: Option<Box<dyn Fn(&ClientError) -> bool>>,
+}
+
+struct Inner {
+    sink: RefCell<Option<MqttSink>>,
+    filters: RefCell<Vec<(ByteString, codec::SubscriptionOptions)>>,
+    packet_id: Cell<u16>,
+    store: RefCell<Box<dyn ClientStore>>,
+    offline: RefCell<OfflineBuffer<Buffered>>,
+    connected: Condition,
+    closed: Cell<bool>,
+}
+
+/// Publish buffered while client is disconnected
+enum Buffered {
+    AtMostOnce(ByteString, Bytes),
+    AtLeastOnce(
+        ByteString,
+        Bytes,
+        oneshot::Sender<Result<codec::PublishAck, PublishQos1Error>>,
+    ),
+    ExactlyOnce(
+        ByteString,
+        Bytes,
+        oneshot::Sender<Result<codec::PublishAck2, PublishQos2Error>>,
+    ),
+}
+
+impl Inner {
+    fn next_id(&self) -> u16 {
+        let idx = self.packet_id.get().wrapping_add(1);
+        let idx = if idx == 0 { 1 } else { idx };
+        self.packet_id.set(idx);
+        idx
+    }
+
+    fn store_publish(
+        &self,
+        qos: codec::QoS,
+        topic: ByteString,
+        payload: Bytes,
+    ) -> codec::Publish {
+        let packet = codec::Publish {
+            qos,
+            topic,
+            payload,
+            dup: false,
+            retain: false,
+            packet_id: NonZeroU16::new(self.next_id()),
+            properties: codec::PublishProperties::default(),
+        };
+        self.store.borrow_mut().store_publish(&packet);
+        packet
+    }
+}
+
+impl<A, T> ManagedClient<A, T>
+where
+    A: Address + Clone,
+    T: Service<Request = Connect<A>, Error = connect::ConnectError>,
+    T::Response: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    pub(super) fn new(connector: MqttConnector<A, T>) -> Self {
+        ManagedClient {
+            connector,
+            inner: Rc::new(Inner {
+                sink: RefCell::new(None),
+                filters: RefCell::new(Vec::new()),
+                packet_id: Cell::new(0),
+                store: RefCell::new(Box::new(MemoryStore::default())),
+                offline: RefCell::new(OfflineBuffer::new(0, OfflinePolicy::Reject)),
+                connected: Condition::new(),
+                closed: Cell::new(false),
+            }),
+            backoff: Box::new(ExponentialBackoff::default()),
+            abort: None,
+        }
+    }
+
+    /// Set re-connect backoff
+    ///
+    /// Delay between re-connect attempts starts with `min` value and doubles
+    /// after each failed attempt until it reaches `max` value.
+    /// By default min delay is 1 second and max delay is 30 seconds.
+    pub fn backoff(self, min: Duration, max: Duration) -> Self {
+        self.backoff_strategy(ExponentialBackoff::new(min, max))
+    }
+
+    /// Set re-connect backoff strategy
+    pub fn backoff_strategy<B>(mut self, strategy: B) -> Self
+    where
+        B: BackoffStrategy + 'static,
+    {
+        self.backoff = Box::new(strategy);
+        self
+    }
+
+    /// Set connect error hook
+    ///
+    /// If hook returns true, client stops re-connecting permanently,
+    /// for example on authentication failures.
+    pub fn abort_if<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&ClientError) -> bool + 'static,
+    {
+        self.abort = Some(Box::new(f));
+        self
+    }
+
+    /// Set in-flight store
+    ///
+    /// Unacknowledged publishes from the store get re-sent after client
+    /// connects to the server. By default in-memory store is used.
+    pub fn store<S>(self, store: S) -> Self
+    where
+        S: ClientStore + 'static,
+    {
+        // packet ids must not collide with stored publishes
+        let max_id = store
+            .publishes()
+            .iter()
+            .filter_map(|pkt| pkt.packet_id)
+            .chain(store.releases())
+            .map(|id| id.get())
+            .max()
+            .unwrap_or(0);
+        self.inner.packet_id.set(max_id);
+        *self.inner.store.borrow_mut() = Box::new(store);
+        self
+    }
+
+    /// Set offline buffer
+    ///
+    /// Publishes are buffered while client is disconnected and get sent in
+    /// order after client re-connects. `policy` defines behavior if buffer
+    /// is full, dropped publishes fail with `Disconnected` error.
+    /// By default buffering is disabled.
+    pub fn offline_buffer(self, capacity: usize, policy: OfflinePolicy) -> Self {
+        *self.inner.offline.borrow_mut() = OfflineBuffer::new(capacity, policy);
+        self
+    }
+
+    #[inline]
+    /// Get managed client sink
+    pub fn sink(&self) -> ManagedSink {
+        ManagedSink(self.inner.clone())
+    }
+
+    /// Run client and handle control messages
+    ///
+    /// Client connects to the server and re-connects every time connection
+    /// get closed. Future resolves after `ManagedSink::close()` get called
+    /// or re-connecting is stopped by backoff strategy or connect error hook.
+    pub async fn start<F, S, E>(self, service: F)
+    where
+        E: 'static,
+        F: IntoService<S>,
+        S: Service<Request = ControlMessage, Response = ControlResult, Error = E> + 'static,
+    {
+        let service = Rc::new(service.into_service());
+        let mut connector = self.connector;
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        let mut restored = false;
+
+        while !self.inner.closed.get() {
+            match connector.connect().await {
+                Ok(client) => {
+                    attempt = 0;
+
+                    let sink = client.sink();
+                    if self.inner.closed.get() {
+                        sink.close();
+                        break;
+                    }
+                    *self.inner.sink.borrow_mut() = Some(sink.clone());
+
+                    // record received qos2 publishes
+                    let inner = Rc::downgrade(&self.inner);
+                    sink.on_release(move |id| {
+                        if let Some(inner) = inner.upgrade() {
+                            inner.store.borrow_mut().release_publish(id);
+                        }
+                    });
+
+                    // restore inbound qos2 state, server re-sends PUBREL for received publishes
+                    let received = self.inner.store.borrow().received();
+                    if client.session_present() {
+                        sink.restore_received(received);
+                    } else {
+                        let mut store = self.inner.store.borrow_mut();
+                        received.into_iter().for_each(|id| store.remove_received(id));
+                    }
+                    let inner = Rc::downgrade(&self.inner);
+                    sink.on_receive(move |id, received| {
+                        if let Some(inner) = inner.upgrade() {
+                            if received {
+                                inner.store.borrow_mut().store_received(id);
+                            } else {
+                                inner.store.borrow_mut().remove_received(id);
+                            }
+                        }
+                    });
+
+                    // replay offline buffer, tasks get started in order
+                    let buffered = self.inner.offline.borrow_mut().take();
+                    for item in buffered {
+                        ntex::rt::spawn(replay(ManagedSink(self.inner.clone()), item));
+                    }
+
+                    // server does not have session state, replay subscriptions
+                    if !client.session_present() {
+                        let filters = self.inner.filters.borrow().clone();
+                        if !filters.is_empty() {
+                            let id = self.inner.next_id();
+                            ntex::rt::spawn(resubscribe(sink, id, filters));
+                        }
+                    }
+                    self.inner.connected.notify();
+
+                    // re-send publishes from the store
+                    if !restored {
+                        restored = true;
+                        let packets = self.inner.store.borrow().publishes();
+                        for packet in packets {
+                            ntex::rt::spawn(restore(ManagedSink(self.inner.clone()), packet));
+                        }
+                        let releases = self.inner.store.borrow().releases();
+                        for id in releases {
+                            ntex::rt::spawn(restore_release(
+                                ManagedSink(self.inner.clone()),
+                                id,
+                            ));
+                        }
+                    }
+
+                    if client.start(service.clone()).await.is_err() {
+                        log::trace!("Mqtt client connection is closed with error");
+                    }
+                    self.inner.sink.borrow_mut().take();
+
+                    // resume session on re-connect
+                    connector = connector.packet(|pkt| pkt.clean_start = false);
+                }
+                Err(err) => {
+                    log::error!("Cannot connect to mqtt server: {}", err);
+                    if self.abort.as_ref().map(|f| f(&err)).unwrap_or(false) {
+                        log::error!("Stop re-connecting to mqtt server");
+                        break;
+                    }
+                }
+            }
+
+            if self.inner.closed.get() {
+                break;
+            }
+            attempt += 1;
+            if let Some(delay) = backoff.next_delay(attempt) {
+                log::trace!("Re-connecting to mqtt server in {:?}", delay);
+                delay_for(delay).await;
+            } else {
+                log::error!("Max re-connect attempts reached");
+                break;
+            }
+        }
+        self.inner.closed.set(true);
+        self.inner.offline.borrow_mut().take();
+        self.inner.connected.notify();
+    }
+}
+
+async fn replay(sink: ManagedSink, item: Buffered) {
+    match item {
+        Buffered::AtMostOnce(topic, payload) => {
+            if let Err(err) = sink.publish_at_most_once(topic, payload) {
+                log::error!("Cannot send buffered publish: {}", err);
+            }
+        }
+        Buffered::AtLeastOnce(topic, payload, tx) => {
+            let packet = sink.0.store_publish(codec::QoS::AtLeastOnce, topic, payload);
+            let _ = tx.send(sink.send_at_least_once(packet).await);
+        }
+        Buffered::ExactlyOnce(topic, payload, tx) => {
+            let packet = sink.0.store_publish(codec::QoS::ExactlyOnce, topic, payload);
+            let _ = tx.send(sink.send_exactly_once(packet).await);
+        }
+    }
+}
+
+async fn resubscribe(
+    sink: MqttSink,
+    id: u16,
+    filters: Vec<(ByteString, codec::SubscriptionOptions)>,
+) {
+    let mut builder = sink.subscribe(None).packet_id(id);
+    for (filter, opts) in filters {
+        builder = builder.topic_filter(filter, opts);
+    }
+    match builder.send().await {
+        Ok(ack) => log::trace!("Topic filters are re-subscribed: {:?}", ack),
+        Err(err) => log::error!("Cannot re-subscribe topic filters: {}", err),
+    }
+}
+
+async fn restore(sink: ManagedSink, mut packet: codec::Publish) {
+    log::trace!("Re-send stored publish packet: {:?}", packet);
+    packet.dup = true;
+
+    match packet.qos {
+        codec::QoS::AtLeastOnce => {
+            if let Err(err) = sink.send_at_least_once(packet).await {
+                log::error!("Cannot re-send stored publish packet: {}", err);
+            }
+        }
+        codec::QoS::ExactlyOnce => {
+            if let Err(err) = sink.send_exactly_once(packet).await {
+                log::error!("Cannot re-send stored publish packet: {}", err);
+            }
+        }
+        codec::QoS::AtMostOnce => {
+            if let Some(id) = packet.packet_id {
+                sink.0.store.borrow_mut().remove_publish(id);
+            }
+        }
+    }
+}
+
+async fn restore_release(sink: ManagedSink, id: NonZeroU16) {
+    log::trace!("Re-send stored publish release packet: {:?}", id);
+    if let Err(err) = sink.send_release(id).await {
+        log::error!("Cannot re-send stored publish release packet: {}", err);
+    }
+}
+
+/// Managed client sink
+///
+/// Sink stays valid across re-connects. Packet ids are managed by the sink,
+/// publish and subscribe packets sent with `ManagedSink::sink()` must use
+/// explicit packet ids, otherwise collisions could occure.
+#[derive(Clone)]
+pub struct ManagedSink(Rc<Inner>);
+
+impl ManagedSink {
+    /// Check if client is connected to the server
+    pub fn is_connected(&self) -> bool {
+        self.0.sink.borrow().as_ref().map(|sink| sink.is_open()).unwrap_or(false)
+    }
+
+    /// Get sink of the current connection
+    pub fn sink(&self) -> Option<MqttSink> {
+        self.0.sink.borrow().as_ref().filter(|sink| sink.is_open()).cloned()
+    }
+
+    /// Wait until client get connected to the server
+    ///
+    /// Returns `None` if client is closed.
+    pub async fn connected(&self) -> Option<MqttSink> {
+        loop {
+            if self.0.closed.get() {
+                return None;
+            }
+            if let Some(sink) = self.sink() {
+                return Some(sink);
+            }
+            self.0.connected.wait().await;
+        }
+    }
+
+    /// Send publish packet with QoS 0
+    ///
+    /// If client is disconnected and offline buffer is enabled, publish
+    /// get buffered and sent after client re-connects.
+    pub fn publish_at_most_once(
+        &self,
+        topic: ByteString,
+        payload: Bytes,
+    ) -> Result<(), SendPacketError> {
+        if let Some(sink) = self.sink() {
+            sink.publish(topic, payload).send_at_most_once()
+        } else {
+            self.buffer(Buffered::AtMostOnce(topic, payload))
+        }
+    }
+
+    fn buffer(&self, item: Buffered) -> Result<(), SendPacketError> {
+        if self.0.closed.get() {
+            Err(SendPacketError::Disconnected)
+        } else {
+            self.0.offline.borrow_mut().push(item).map_err(|_| SendPacketError::Disconnected)
+        }
+    }
+
+    /// Send publish packet with QoS 1
+    ///
+    /// If client is disconnected and offline buffer is enabled, publish
+    /// get buffered and sent after client re-connects. If connection get
+    /// closed before PUBACK is received, publish packet is re-sent with
+    /// DUP flag after client re-connects.
+    pub async fn publish_at_least_once(
+        &self,
+        topic: ByteString,
+        payload: Bytes,
+    ) -> Result<codec::PublishAck, PublishQos1Error> {
+        if !self.is_connected() {
+            let (tx, rx) = oneshot::channel();
+            self.buffer(Buffered::AtLeastOnce(topic, payload, tx))?;
+            return rx.await.unwrap_or(Err(PublishQos1Error::Disconnected));
+        }
+        let packet = self.0.store_publish(codec::QoS::AtLeastOnce, topic, payload);
+        self.send_at_least_once(packet).await
+    }
+
+    async fn send_at_least_once(
+        &self,
+        mut packet: codec::Publish,
+    ) -> Result<codec::PublishAck, PublishQos1Error> {
+        let id = packet.packet_id.unwrap();
+
+        while let Some(sink) = self.connected().await {
+            let props = packet.properties.clone();
+            let res = sink
+                .publish(packet.topic.clone(), packet.payload.clone())
+                .packet_id(id.get())
+                .dup(packet.dup)
+                .properties(|p| *p = props)
+                .send_at_least_once()
+                .await;
+
+            match res {
+                Err(PublishQos1Error::Disconnected) => {
+                    log::trace!("Connection is closed, re-send publish packet {}", id);
+                    packet.dup = true;
+                }
+                res => {
+                    self.0.store.borrow_mut().remove_publish(id);
+                    return res;
+                }
+            }
+        }
+        Err(PublishQos1Error::Disconnected)
+    }
+
+    /// Send publish packet with QoS 2
+    ///
+    /// If client is disconnected and offline buffer is enabled, publish
+    /// get buffered and sent after client re-connects. If connection get
+    /// closed before PUBREC is received, publish packet is re-sent with
+    /// DUP flag after client re-connects. If connection get closed after
+    /// PUBREC is received, PUBREL packet is re-sent.
+    pub async fn publish_exactly_once(
+        &self,
+        topic: ByteString,
+        payload: Bytes,
+    ) -> Result<codec::PublishAck2, PublishQos2Error> {
+        if !self.is_connected() {
+            let (tx, rx) = oneshot::channel();
+            self.buffer(Buffered::ExactlyOnce(topic, payload, tx))?;
+            return rx.await.unwrap_or(Err(PublishQos2Error::Disconnected));
+        }
+        let packet = self.0.store_publish(codec::QoS::ExactlyOnce, topic, payload);
+        self.send_exactly_once(packet).await
+    }
+
+    async fn send_exactly_once(
+        &self,
+        mut packet: codec::Publish,
+    ) -> Result<codec::PublishAck2, PublishQos2Error> {
+        let id = packet.packet_id.unwrap();
+
+        while let Some(sink) = self.connected().await {
+            let props = packet.properties.clone();
+            let res = sink
+                .publish(packet.topic.clone(), packet.payload.clone())
+                .packet_id(id.get())
+                .dup(packet.dup)
+                .properties(|p| *p = props)
+                .send_exactly_once()
+                .await;
+
+            match res {
+                Err(PublishQos2Error::Disconnected) => {
+                    // publish is received by the server, release it
+                    if self.0.store.borrow().releases().contains(&id) {
+                        return self.send_release(id).await;
+                    }
+                    log::trace!("Connection is closed, re-send publish packet {}", id);
+                    packet.dup = true;
+                }
+                res => {
+                    self.0.store.borrow_mut().remove_publish(id);
+                    return res;
+                }
+            }
+        }
+        Err(PublishQos2Error::Disconnected)
+    }
+
+    async fn send_release(
+        &self,
+        id: NonZeroU16,
+    ) -> Result<codec::PublishAck2, PublishQos2Error> {
+        while let Some(sink) = self.connected().await {
+            match sink.publish_release(id).await {
+                Err(PublishQos2Error::Disconnected) => {
+                    log::trace!("Connection is closed, re-send publish release packet {}", id);
+                }
+                res => {
+                    self.0.store.borrow_mut().remove_publish(id);
+                    return res;
+                }
+            }
+        }
+        Err(PublishQos2Error::Disconnected)
+    }
+
+    /// Subscribe to a topic filter
+    ///
+    /// Topic filter get registered and re-subscribed after re-connect if
+    /// server does not have session state.
+    pub async fn subscribe(
+        &self,
+        filter: ByteString,
+        opts: codec::SubscriptionOptions,
+    ) -> Result<codec::SubscribeAck, SendPacketError> {
+        {
+            let mut filters = self.0.filters.borrow_mut();
+            if let Some(item) = filters.iter_mut().find(|item| item.0 == filter) {
+                item.1 = opts.clone();
+            } else {
+                filters.push((filter.clone(), opts.clone()));
+            }
+        }
+
+        if let Some(sink) = self.sink() {
+            sink.subscribe(None)
+                .packet_id(self.0.next_id())
+                .topic_filter(filter, opts)
+                .send()
+                .await
+        } else {
+            Err(SendPacketError::Disconnected)
+        }
+    }
+
+    /// Unsubscribe from a topic filter
+    pub async fn unsubscribe(
+        &self,
+        filter: ByteString,
+    ) -> Result<codec::UnsubscribeAck, SendPacketError> {
+        self.0.filters.borrow_mut().retain(|item| item.0 != filter);
+
+        if let Some(sink) = self.sink() {
+            sink.unsubscribe().packet_id(self.0.next_id()).topic_filter(filter).send().await
+        } else {
+            Err(SendPacketError::Disconnected)
+        }
+    }
+
+    /// Close current connection and stop re-connecting
+    pub fn close(&self) {
+        self.0.closed.set(true);
+        if let Some(sink) = self.0.sink.borrow_mut().take() {
+            sink.close();
+        }
+        // buffered publishes fail with `Disconnected` error
+        self.0.offline.borrow_mut().take();
+        self.0.connected.notify();
+    }
+}
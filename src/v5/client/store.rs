@@ -0,0 +1,140 @@
+use std::num::NonZeroU16;
+
+use super::codec;
+
+/// Client in-flight store
+///
+/// Store keeps outgoing publish packets until they get acknowledged by the
+/// server. Persistent implementation allows managed client to re-send
+/// unacknowledged publishes after process restart.
+///
+/// QoS 2 publish that is received by the server (PUBREC) is replaced by
+/// its packet id, managed client re-sends PUBREL instead of PUBLISH for it.
+/// Store also keeps packet ids of inbound QoS 2 publishes until server releases
+/// them, so re-sent publishes are not delivered to the application twice.
+///
+/// Store is supported by v5 managed client only.
+pub trait ClientStore {
+    /// Store unacknowledged publish packet
+    fn store_publish(&mut self, packet: &codec::Publish);
+
+    /// Mark QoS 2 publish packet as received by the server
+    fn release_publish(&mut self, packet_id: NonZeroU16);
+
+    /// Remove acknowledged publish packet or released packet id
+    fn remove_publish(&mut self, packet_id: NonZeroU16);
+
+    /// Get stored publish packets, in order of storing
+    fn publishes(&self) -> Vec<codec::Publish>;
+
+    /// Get packet ids of released QoS 2 publishes, awaiting PUBCOMP
+    fn releases(&self) -> Vec<NonZeroU16>;
+
+    /// Store packet id of inbound QoS 2 publish, awaiting PUBREL
+    fn store_received(&mut self, packet_id: NonZeroU16);
+
+    /// Remove packet id of inbound QoS 2 publish released by the server
+    fn remove_received(&mut self, packet_id: NonZeroU16);
+
+    /// Get packet ids of inbound QoS 2 publishes that are not released yet
+    fn received(&self) -> Vec<NonZeroU16>;
+}
+
+/// In-memory client store
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    publishes: Vec<codec::Publish>,
+    releases: Vec<NonZeroU16>,
+    received: Vec<NonZeroU16>,
+}
+
+impl ClientStore for MemoryStore {
+    fn store_publish(&mut self, packet: &codec::Publish) {
+        self.remove_publish_id(packet.packet_id);
+        self.publishes.push(packet.clone());
+    }
+
+    fn release_publish(&mut self, packet_id: NonZeroU16) {
+        self.remove_publish_id(Some(packet_id));
+        self.releases.push(packet_id);
+    }
+
+    fn remove_publish(&mut self, packet_id: NonZeroU16) {
+        self.remove_publish_id(Some(packet_id));
+    }
+
+    fn publishes(&self) -> Vec<codec::Publish> {
+        self.publishes.clone()
+    }
+
+    fn releases(&self) -> Vec<NonZeroU16> {
+        self.releases.clone()
+    }
+
+    fn store_received(&mut self, packet_id: NonZeroU16) {
+        if !self.received.contains(&packet_id) {
+            self.received.push(packet_id);
+        }
+    }
+
+    fn remove_received(&mut self, packet_id: NonZeroU16) {
+        self.received.retain(|id| *id != packet_id);
+    }
+
+    fn received(&self) -> Vec<NonZeroU16> {
+        self.received.clone()
+    }
+}
+
+impl MemoryStore {
+    fn remove_publish_id(&mut self, packet_id: Option<NonZeroU16>) {
+        self.publishes.retain(|pkt| pkt.packet_id != packet_id);
+        self.releases.retain(|id| Some(*id) != packet_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntex::util::{ByteString, Bytes};
+
+    use super::*;
+
+    fn publish(id: u16) -> codec::Publish {
+        codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            packet_id: NonZeroU16::new(id),
+            topic: ByteString::from_static("test"),
+            payload: Bytes::new(),
+            properties: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let mut store = MemoryStore::default();
+        store.store_publish(&publish(1));
+        store.store_publish(&publish(2));
+        store.store_publish(&publish(1));
+        let ids: Vec<_> = store.publishes().iter().map(|p| p.packet_id).collect();
+        assert_eq!(ids, vec![NonZeroU16::new(2), NonZeroU16::new(1)]);
+
+        store.remove_publish(NonZeroU16::new(2).unwrap());
+        let ids: Vec<_> = store.publishes().iter().map(|p| p.packet_id).collect();
+        assert_eq!(ids, vec![NonZeroU16::new(1)]);
+
+        store.release_publish(NonZeroU16::new(1).unwrap());
+        assert!(store.publishes().is_empty());
+        assert_eq!(store.releases(), vec![NonZeroU16::new(1).unwrap()]);
+
+        store.remove_publish(NonZeroU16::new(1).unwrap());
+        assert!(store.releases().is_empty());
+
+        store.store_received(NonZeroU16::new(3).unwrap());
+        store.store_received(NonZeroU16::new(3).unwrap());
+        assert_eq!(store.received(), vec![NonZeroU16::new(3).unwrap()]);
+        store.remove_received(NonZeroU16::new(3).unwrap());
+        assert!(store.received().is_empty());
+    }
+}
@@ -0,0 +1,146 @@
+use std::num::NonZeroU32;
+
+use ntex::util::{ByteString, Bytes};
+
+use super::codec;
+
+/// Will message builder
+///
+/// Will message is published with QoS 0 and without retain flag by default.
+///
+/// ```rust,ignore
+/// MqttConnector::new("127.0.0.1:1883").last_will(
+///     WillBuilder::new("status".into(), "offline".into())
+///         .qos(QoS::AtLeastOnce)
+///         .retain(true)
+///         .content_type("text/plain".into()),
+/// )
+/// ```
+#[derive(Debug, Clone)]
+pub struct WillBuilder(codec::LastWill);
+
+impl WillBuilder {
+    /// Create will message builder
+    pub fn new(topic: ByteString, message: Bytes) -> Self {
+        WillBuilder(codec::LastWill {
+            topic,
+            message,
+            qos: codec::QoS::AtMostOnce,
+            retain: false,
+            will_delay_interval_sec: None,
+            correlation_data: None,
+            message_expiry_interval: None,
+            content_type: None,
+            user_properties: Vec::new(),
+            is_utf8_payload: None,
+            response_topic: None,
+            unknown_properties: Bytes::new(),
+        })
+    }
+
+    #[inline]
+    /// Set will message qos
+    pub fn qos(mut self, val: codec::QoS) -> Self {
+        self.0.qos = val;
+        self
+    }
+
+    #[inline]
+    /// Set will message retain flag
+    pub fn retain(mut self, val: bool) -> Self {
+        self.0.retain = val;
+        self
+    }
+
+    #[inline]
+    /// Set will delay interval in seconds
+    pub fn delay_interval(mut self, secs: u32) -> Self {
+        self.0.will_delay_interval_sec = Some(secs);
+        self
+    }
+
+    #[inline]
+    /// Set will message expiry interval in seconds
+    pub fn message_expiry_interval(mut self, secs: NonZeroU32) -> Self {
+        self.0.message_expiry_interval = Some(secs);
+        self
+    }
+
+    #[inline]
+    /// Set payload format indicator
+    ///
+    /// `true` indicates that payload is utf-8 encoded character data.
+    pub fn payload_format_indicator(mut self, is_utf8: bool) -> Self {
+        self.0.is_utf8_payload = Some(is_utf8);
+        self
+    }
+
+    #[inline]
+    /// Set will message content type
+    pub fn content_type(mut self, val: ByteString) -> Self {
+        self.0.content_type = Some(val);
+        self
+    }
+
+    #[inline]
+    /// Set response topic
+    pub fn response_topic(mut self, val: ByteString) -> Self {
+        self.0.response_topic = Some(val);
+        self
+    }
+
+    #[inline]
+    /// Set correlation data
+    pub fn correlation_data(mut self, val: Bytes) -> Self {
+        self.0.correlation_data = Some(val);
+        self
+    }
+
+    #[inline]
+    /// Add user property
+    pub fn user_property(mut self, key: ByteString, val: ByteString) -> Self {
+        self.0.user_properties.push((key, val));
+        self
+    }
+
+    #[inline]
+    /// Create will message
+    pub fn finish(self) -> codec::LastWill {
+        self.0
+    }
+}
+
+impl From<WillBuilder> for codec::LastWill {
+    fn from(builder: WillBuilder) -> Self {
+        builder.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_will_builder() {
+        let will =
+            WillBuilder::new(ByteString::from_static("status"), Bytes::from_static(b"1"))
+                .qos(codec::QoS::AtLeastOnce)
+                .retain(true)
+                .delay_interval(10)
+                .payload_format_indicator(true)
+                .content_type(ByteString::from_static("text/plain"))
+                .response_topic(ByteString::from_static("response"))
+                .correlation_data(Bytes::from_static(b"id"))
+                .user_property(ByteString::from_static("key"), ByteString::from_static("val"))
+                .finish();
+
+        assert_eq!(will.qos, codec::QoS::AtLeastOnce);
+        assert!(will.retain);
+        assert_eq!(will.will_delay_interval_sec, Some(10));
+        assert_eq!(will.is_utf8_payload, Some(true));
+        assert_eq!(will.content_type, Some(ByteString::from_static("text/plain")));
+        assert_eq!(will.response_topic, Some(ByteString::from_static("response")));
+        assert_eq!(will.correlation_data, Some(Bytes::from_static(b"id")));
+        assert_eq!(will.user_properties.len(), 1);
+    }
+}
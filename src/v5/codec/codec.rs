@@ -1,11 +1,15 @@
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::task::{Context, Poll};
 
 use ntex::codec::{Decoder, Encoder};
-use ntex::util::{Buf, BytesMut};
+use ntex::util::{Buf, Bytes, BytesMut};
 
-use super::{decode::decode_packet, encode::EncodeLtd, Packet};
+use super::{decode, encode::EncodeLtd, Packet};
 use crate::error::{DecodeError, EncodeError};
-use crate::types::{FixedHeader, MAX_PACKET_SIZE};
+use crate::payload::{Payload, PayloadSender};
+use crate::types::{
+    check_packet_flags, packet_type, Conformance, FixedHeader, MAX_PACKET_SIZE,
+};
 use crate::utils::decode_variable_length;
 
 #[derive(Debug)]
@@ -14,6 +18,11 @@ pub struct Codec {
     max_in_size: Cell<u32>,
     max_out_size: Cell<u32>,
     flags: Cell<CodecFlags>,
+    conformance: Cell<Conformance>,
+    strict_utf8: Cell<bool>,
+    stream_threshold: Cell<u32>,
+    sender: RefCell<Option<PayloadSender>>,
+    payload: RefCell<Option<Payload>>,
 }
 
 bitflags::bitflags! {
@@ -26,6 +35,8 @@ bitflags::bitflags! {
 enum DecodeState {
     FrameHeader,
     Frame(FixedHeader),
+    PublishHeader(FixedHeader),
+    Payload,
 }
 
 impl Codec {
@@ -36,6 +47,11 @@ impl Codec {
             max_in_size: Cell::new(0),
             max_out_size: Cell::new(0),
             flags: Cell::new(CodecFlags::empty()),
+            conformance: Cell::new(Conformance::Strict),
+            strict_utf8: Cell::new(true),
+            stream_threshold: Cell::new(0),
+            sender: RefCell::new(None),
+            payload: RefCell::new(None),
         }
     }
 
@@ -76,6 +92,124 @@ impl Codec {
     pub fn set_max_outbound_size(&self, size: u32) {
         self.max_out_size.set(size);
     }
+
+    /// Get max outbound frame size, `0` means unlimited
+    pub(crate) fn get_max_outbound_size(&self) -> u32 {
+        self.max_out_size.get()
+    }
+
+    /// Set conformance mode of inbound packets decoding.
+    ///
+    /// By default codec works in `Conformance::Strict` mode
+    pub fn conformance(self, val: Conformance) -> Self {
+        self.conformance.set(val);
+        self
+    }
+
+    /// Set conformance mode of inbound packets decoding.
+    ///
+    /// By default codec works in `Conformance::Strict` mode
+    pub fn set_conformance(&self, val: Conformance) {
+        self.conformance.set(val);
+    }
+
+    /// Reject topics and strings that contain U+0000 or control characters.
+    ///
+    /// By default strict utf-8 checks are enabled
+    pub fn strict_utf8(self, val: bool) -> Self {
+        self.strict_utf8.set(val);
+        self
+    }
+
+    /// Reject topics and strings that contain U+0000 or control characters.
+    ///
+    /// By default strict utf-8 checks are enabled
+    pub fn set_strict_utf8(&self, val: bool) {
+        self.strict_utf8.set(val);
+    }
+
+    /// Set streaming threshold for inbound publish packets.
+    ///
+    /// Publish packets larger than threshold are decoded as soon as variable
+    /// header is received, payload is available via `take_payload()`.
+    /// If threshold is set to `0`, streaming is disabled.
+    /// By default threshold is set to `0`
+    pub fn stream_threshold(self, size: u32) -> Self {
+        self.stream_threshold.set(size);
+        self
+    }
+
+    /// Set streaming threshold for inbound publish packets.
+    ///
+    /// If threshold is set to `0`, streaming is disabled.
+    /// By default threshold is set to `0`
+    pub fn set_stream_threshold(&self, size: u32) {
+        self.stream_threshold.set(size);
+    }
+
+    /// Take payload stream of last decoded publish packet
+    ///
+    /// Payload of streamed publish packet is empty, packet's payload is
+    /// delivered via returned stream.
+    pub fn take_payload(&self) -> Option<Payload> {
+        self.payload.borrow_mut().take()
+    }
+
+    /// Check if payload stream of streamed publish packet could buffer more chunks
+    pub(crate) fn poll_payload_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.sender.borrow().as_ref().map_or(Poll::Ready(()), |s| s.poll_ready(cx))
+    }
+
+    /// Encode single packet
+    pub fn encode_packet(&self, pkt: Packet) -> Result<Bytes, EncodeError> {
+        let mut buf = BytesMut::new();
+        self.encode(pkt, &mut buf)?;
+        Ok(buf.freeze())
+    }
+
+    /// Decode single packet from the start of the buffer
+    ///
+    /// Returns decoded packet and number of consumed bytes, or `None` if buffer
+    /// does not contain complete packet. Unlike `Decoder::decode()` this method
+    /// does not change decoder state and never streams packet's payload.
+    pub fn decode_packet(&self, src: &Bytes) -> Result<Option<(Packet, usize)>, DecodeError> {
+        if src.len() < 2 {
+            return Ok(None);
+        }
+        let first_byte = check_packet_flags(src[0], self.conformance.get())?;
+        let (remaining_length, consumed) = match decode_variable_length(&src[1..])? {
+            Some(res) => res,
+            None => return Ok(None),
+        };
+        let max_in_size = self.max_in_size.get();
+        if max_in_size != 0 && max_in_size < remaining_length {
+            return Err(DecodeError::MaxSizeExceeded);
+        }
+        let start = consumed + 1;
+        let end = start + remaining_length as usize;
+        if src.len() < end {
+            return Ok(None);
+        }
+        let packet = self.decode_frame(src.slice(start..end), first_byte)?;
+        self.update_flags(&packet);
+        Ok(Some((packet, end)))
+    }
+
+    fn decode_frame(&self, src: Bytes, first_byte: u8) -> Result<Packet, DecodeError> {
+        let packet = decode::decode_packet(src, first_byte, self.conformance.get())?;
+        if self.strict_utf8.get() {
+            decode::check_utf8_strings(&packet)?;
+        }
+        Ok(packet)
+    }
+
+    fn update_flags(&self, packet: &Packet) {
+        if let Packet::Connect(ref pkt) = packet {
+            let mut flags = self.flags.get();
+            flags.set(CodecFlags::NO_PROBLEM_INFO, !pkt.request_problem_info);
+            self.flags.set(flags);
+        }
+    }
 }
 
 impl Default for Codec {
@@ -96,7 +230,7 @@ impl Decoder for Codec {
                         return Ok(None);
                     }
                     let src_slice = src.as_ref();
-                    let first_byte = src_slice[0];
+                    let first_byte = check_packet_flags(src_slice[0], self.conformance.get())?;
                     match decode_variable_length(&src_slice[1..])? {
                         Some((remaining_length, consumed)) => {
                             // check max message size
@@ -110,11 +244,25 @@ impl Decoder for Codec {
                                 return Err(DecodeError::MaxSizeExceeded);
                             }
                             src.advance(consumed + 1);
+
+                            // stream large publish packets
+                            let threshold = self.stream_threshold.get();
+                            if threshold != 0
+                                && remaining_length > threshold
+                                && (packet_type::PUBLISH_START..=packet_type::PUBLISH_END)
+                                    .contains(&first_byte)
+                            {
+                                self.state.set(DecodeState::PublishHeader(FixedHeader {
+                                    first_byte,
+                                    remaining_length,
+                                }));
+                                continue;
+                            }
+
                             self.state.set(DecodeState::Frame(FixedHeader {
                                 first_byte,
                                 remaining_length,
                             }));
-                            // todo: validate remaining_length against max frame size config
                             let remaining_length = remaining_length as usize;
                             if src.len() < remaining_length {
                                 // todo: subtract?
@@ -132,22 +280,86 @@ impl Decoder for Codec {
                         return Ok(None);
                     }
                     let packet_buf = src.split_to(fixed.remaining_length as usize).freeze();
-                    let packet = decode_packet(packet_buf, fixed.first_byte)?;
+                    let packet = self.decode_frame(packet_buf, fixed.first_byte)?;
                     self.state.set(DecodeState::FrameHeader);
                     src.reserve(5); // enough to fix 1 fixed header byte + 4 bytes max variable packet length
 
-                    if let Packet::Connect(ref pkt) = packet {
-                        let mut flags = self.flags.get();
-                        flags.set(CodecFlags::NO_PROBLEM_INFO, !pkt.request_problem_info);
-                        self.flags.set(flags);
+                    self.update_flags(&packet);
+                    return Ok(Some(packet));
+                }
+                DecodeState::PublishHeader(fixed) => {
+                    let remaining_length = fixed.remaining_length as usize;
+                    let header_len =
+                        match publish_header_len(src, fixed.first_byte, remaining_length)? {
+                            Some(len) => len,
+                            None => return Ok(None),
+                        };
+                    let packet =
+                        self.decode_frame(src.split_to(header_len).freeze(), fixed.first_byte)?;
+
+                    // payload chunks are buffered up to streaming threshold
+                    let (mut sender, payload) = PayloadSender::create(
+                        remaining_length - header_len,
+                        self.stream_threshold.get() as usize,
+                    );
+                    if sender.feed(src) {
+                        self.state.set(DecodeState::FrameHeader);
+                    } else {
+                        self.state.set(DecodeState::Payload);
+                        *self.sender.borrow_mut() = Some(sender);
                     }
+                    *self.payload.borrow_mut() = Some(payload);
                     return Ok(Some(packet));
                 }
+                DecodeState::Payload => {
+                    let mut sender = self.sender.borrow_mut();
+                    if sender.as_mut().map(|s| s.feed(src)).unwrap_or(true) {
+                        sender.take();
+                        self.state.set(DecodeState::FrameHeader);
+                    } else {
+                        return Ok(None);
+                    }
+                }
             }
         }
     }
 }
 
+/// Size of publish packet's variable header, including properties
+fn publish_header_len(
+    src: &[u8],
+    first_byte: u8,
+    remaining_length: usize,
+) -> Result<Option<usize>, DecodeError> {
+    if src.len() < 2 {
+        return Ok(None);
+    }
+    let mut len = 2 + u16::from_be_bytes([src[0], src[1]]) as usize;
+    if first_byte & 0b0110 != 0 {
+        // packet id
+        len += 2;
+    }
+    if len > remaining_length {
+        return Err(DecodeError::InvalidLength);
+    }
+    if src.len() <= len {
+        return Ok(None);
+    }
+    match decode_variable_length(&src[len..])? {
+        Some((props_len, consumed)) => {
+            len += consumed + props_len as usize;
+            if len > remaining_length {
+                Err(DecodeError::InvalidLength)
+            } else if src.len() < len {
+                Ok(None)
+            } else {
+                Ok(Some(len))
+            }
+        }
+        None => Ok(None),
+    }
+}
+
 impl Encoder for Codec {
     type Item = Packet;
     type Error = EncodeError;
@@ -209,4 +421,63 @@ mod tests {
         buf.extend_from_slice(b"\0\x09");
         assert_eq!(codec.decode(&mut buf), Err(DecodeError::MaxSizeExceeded));
     }
+
+    #[test]
+    fn test_standalone() {
+        use crate::v5::codec::{Disconnect, DisconnectReasonCode};
+
+        let codec = Codec::new();
+        let pkt = Packet::Disconnect(Disconnect {
+            reason_code: DisconnectReasonCode::ServerBusy,
+            ..Disconnect::default()
+        });
+        let buf = codec.encode_packet(pkt.clone()).unwrap();
+
+        assert_eq!(codec.decode_packet(&buf.slice(..1)).unwrap(), None);
+        assert_eq!(codec.decode_packet(&buf).unwrap(), Some((pkt, buf.len())));
+    }
+
+    #[ntex::test]
+    async fn test_stream_payload() {
+        use crate::v5::codec::{Publish, PublishProperties, QoS};
+        use ntex::util::{ByteString, Bytes};
+
+        let codec = Codec::new().stream_threshold(16);
+        let mut buf = BytesMut::new();
+
+        let pkt = Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtLeastOnce,
+            topic: ByteString::from_static("/test"),
+            packet_id: Some(std::num::NonZeroU16::new(1).unwrap()),
+            payload: Bytes::from(Vec::from("a".repeat(1024))),
+            properties: PublishProperties {
+                content_type: Some(ByteString::from_static("application/octet-stream")),
+                ..Default::default()
+            },
+        };
+        codec.encode(Packet::Publish(pkt.clone()), &mut buf).unwrap();
+        codec.encode(Packet::PingRequest, &mut buf).unwrap();
+        let mut tail = buf.split_off(100);
+
+        let pkt2 = if let Packet::Publish(v) = codec.decode(&mut buf).unwrap().unwrap() {
+            v
+        } else {
+            panic!()
+        };
+        assert_eq!(pkt2.topic, pkt.topic);
+        assert_eq!(pkt2.properties, pkt.properties);
+        assert!(pkt2.payload.is_empty());
+        assert!(buf.is_empty());
+
+        let payload = codec.take_payload().unwrap();
+        assert_eq!(payload.size(), 1024);
+
+        buf.extend_from_slice(&tail.split_to(500));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&tail);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Packet::PingRequest));
+        assert_eq!(payload.read_all().await.unwrap(), pkt.payload);
+    }
 }
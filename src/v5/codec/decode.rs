@@ -2,28 +2,94 @@ use ntex::util::{ByteString, Bytes};
 
 use super::{packet::*, UserProperty};
 use crate::error::DecodeError;
-use crate::types::packet_type;
-use crate::utils::Decode;
+use crate::types::{packet_type, Conformance};
+use crate::utils::{check_utf8_string, Decode};
 
-pub(super) fn decode_packet(mut src: Bytes, first_byte: u8) -> Result<Packet, DecodeError> {
+pub(super) fn decode_packet(
+    mut src: Bytes,
+    first_byte: u8,
+    conformance: Conformance,
+) -> Result<Packet, DecodeError> {
+    let len = src.len();
+    decode_packet_inner(&mut src, first_byte, conformance)
+        .map_err(|err| err.with_packet(first_byte, len - src.len()))
+}
+
+/// Check topics and strings of decoded packet for U+0000 and control characters
+pub(super) fn check_utf8_strings(packet: &Packet) -> Result<(), DecodeError> {
+    match packet {
+        Packet::Connect(pkt) => {
+            check_utf8_string(&pkt.client_id)?;
+            check_opt_utf8_string(&pkt.username)?;
+            check_opt_utf8_string(&pkt.auth_method)?;
+            check_user_properties(&pkt.user_properties)?;
+            if let Some(ref will) = pkt.last_will {
+                check_utf8_string(&will.topic)?;
+                check_opt_utf8_string(&will.content_type)?;
+                check_opt_utf8_string(&will.response_topic)?;
+                check_user_properties(&will.user_properties)?;
+            }
+        }
+        Packet::Publish(pkt) => {
+            check_utf8_string(&pkt.topic)?;
+            check_opt_utf8_string(&pkt.properties.content_type)?;
+            check_opt_utf8_string(&pkt.properties.response_topic)?;
+            check_user_properties(&pkt.properties.user_properties)?;
+        }
+        Packet::Subscribe(pkt) => {
+            for (filter, _) in &pkt.topic_filters {
+                check_utf8_string(filter)?;
+            }
+            check_user_properties(&pkt.user_properties)?;
+        }
+        Packet::Unsubscribe(pkt) => {
+            for filter in &pkt.topic_filters {
+                check_utf8_string(filter)?;
+            }
+            check_user_properties(&pkt.user_properties)?;
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+fn check_opt_utf8_string(s: &Option<ByteString>) -> Result<(), DecodeError> {
+    s.as_ref().map_or(Ok(()), |s| check_utf8_string(s))
+}
+
+fn check_user_properties(props: &[UserProperty]) -> Result<(), DecodeError> {
+    for (key, val) in props {
+        check_utf8_string(key)?;
+        check_utf8_string(val)?;
+    }
+    Ok(())
+}
+
+fn decode_packet_inner(
+    src: &mut Bytes,
+    first_byte: u8,
+    conformance: Conformance,
+) -> Result<Packet, DecodeError> {
     match first_byte {
         packet_type::PUBLISH_START..=packet_type::PUBLISH_END => {
-            Ok(Packet::Publish(Publish::decode(src, first_byte & 0b0000_1111)?))
+            Ok(Packet::Publish(Publish::decode(src, first_byte & 0b0000_1111, conformance)?))
         }
-        packet_type::PUBACK => Ok(Packet::PublishAck(PublishAck::decode(&mut src)?)),
+        packet_type::PUBACK => Ok(Packet::PublishAck(PublishAck::decode(src)?)),
         packet_type::PINGREQ => Ok(Packet::PingRequest),
         packet_type::PINGRESP => Ok(Packet::PingResponse),
-        packet_type::SUBSCRIBE => Ok(Packet::Subscribe(Subscribe::decode(&mut src)?)),
-        packet_type::SUBACK => Ok(Packet::SubscribeAck(SubscribeAck::decode(&mut src)?)),
-        packet_type::UNSUBSCRIBE => Ok(Packet::Unsubscribe(Unsubscribe::decode(&mut src)?)),
-        packet_type::UNSUBACK => Ok(Packet::UnsubscribeAck(UnsubscribeAck::decode(&mut src)?)),
-        packet_type::CONNECT => Ok(Packet::Connect(Connect::decode(&mut src)?)),
-        packet_type::CONNACK => Ok(Packet::ConnectAck(ConnectAck::decode(&mut src)?)),
-        packet_type::DISCONNECT => Ok(Packet::Disconnect(Disconnect::decode(&mut src)?)),
-        packet_type::AUTH => Ok(Packet::Auth(Auth::decode(&mut src)?)),
-        packet_type::PUBREC => Ok(Packet::PublishReceived(PublishAck::decode(&mut src)?)),
-        packet_type::PUBREL => Ok(Packet::PublishRelease(PublishAck2::decode(&mut src)?)),
-        packet_type::PUBCOMP => Ok(Packet::PublishComplete(PublishAck2::decode(&mut src)?)),
+        packet_type::SUBSCRIBE => Ok(Packet::Subscribe(Subscribe::decode(src, conformance)?)),
+        packet_type::SUBACK => Ok(Packet::SubscribeAck(SubscribeAck::decode(src)?)),
+        packet_type::UNSUBSCRIBE => {
+            Ok(Packet::Unsubscribe(Unsubscribe::decode(src, conformance)?))
+        }
+        packet_type::UNSUBACK => Ok(Packet::UnsubscribeAck(UnsubscribeAck::decode(src)?)),
+        packet_type::CONNECT => Ok(Packet::Connect(Connect::decode(src, conformance)?)),
+        packet_type::CONNACK => Ok(Packet::ConnectAck(ConnectAck::decode(src, conformance)?)),
+        packet_type::DISCONNECT => Ok(Packet::Disconnect(Disconnect::decode(src)?)),
+        packet_type::AUTH => Ok(Packet::Auth(Auth::decode(src)?)),
+        packet_type::PUBREC => Ok(Packet::PublishReceived(PublishAck::decode(src)?)),
+        packet_type::PUBREL => Ok(Packet::PublishRelease(PublishAck2::decode(src)?)),
+        packet_type::PUBCOMP => Ok(Packet::PublishComplete(PublishAck2::decode(src)?)),
         _ => Err(DecodeError::UnsupportedPacketType),
     }
 }
@@ -62,7 +128,7 @@ mod tests {
             &mut tmp,
         )
         .unwrap();
-        let decoded = decode_packet(cur, fixed);
+        let decoded = decode_packet(cur, fixed, Conformance::Strict);
         let res = Ok(res);
         if decoded != res {
             panic!("decoded packet does not match expectations.\nexpected: {:?}\nactual: {:?}\nencoding output for expected: {:X?}", res, decoded, tmp.as_ref());
@@ -73,9 +139,12 @@ mod tests {
     #[test]
     fn test_decode_connect_packets() {
         assert_eq!(
-            Connect::decode(&mut Bytes::from_static(
-                b"\x00\x04MQTT\x05\xC0\x00\x3C\x00\x00\x0512345\x00\x04user\x00\x04pass"
-            )),
+            Connect::decode(
+                &mut Bytes::from_static(
+                    b"\x00\x04MQTT\x05\xC0\x00\x3C\x00\x00\x0512345\x00\x04user\x00\x04pass"
+                ),
+                Conformance::Strict
+            ),
             Ok(Connect {
                 clean_start: false,
                 keep_alive: 60,
@@ -98,7 +167,7 @@ mod tests {
         assert_eq!(
             Connect::decode(&mut Bytes::from_static(
                 b"\x00\x04MQTT\x05\x14\x00\x3C\x00\x00\x0512345\x00\x00\x05topic\x00\x07message"
-            )),
+            ), Conformance::Strict),
             Ok(Connect {
                 clean_start: false,
                 keep_alive: 60,
@@ -115,6 +184,7 @@ mod tests {
                     user_properties: Vec::new(),
                     is_utf8_payload: None,
                     response_topic: None,
+                    unknown_properties: Bytes::new(),
                 }),
                 username: None,
                 password: None,
@@ -131,26 +201,36 @@ mod tests {
         );
 
         assert_eq!(
-            Connect::decode(&mut Bytes::from_static(b"\x00\x02MQ00000000000000000000")),
+            Connect::decode(
+                &mut Bytes::from_static(b"\x00\x02MQ00000000000000000000"),
+                Conformance::Strict
+            ),
             Err(DecodeError::InvalidProtocol),
         );
         assert_eq!(
-            Connect::decode(&mut Bytes::from_static(b"\x00\x04MQAA00000000000000000000")),
+            Connect::decode(
+                &mut Bytes::from_static(b"\x00\x04MQAA00000000000000000000"),
+                Conformance::Strict
+            ),
             Err(DecodeError::InvalidProtocol),
         );
         assert_eq!(
-            Connect::decode(&mut Bytes::from_static(b"\x00\x04MQTT\x0300000000000000000000")),
+            Connect::decode(
+                &mut Bytes::from_static(b"\x00\x04MQTT\x0300000000000000000000"),
+                Conformance::Strict
+            ),
             Err(DecodeError::UnsupportedProtocolLevel),
         );
         assert_eq!(
-            Connect::decode(&mut Bytes::from_static(
-                b"\x00\x04MQTT\x05\xff00000000000000000000"
-            )),
+            Connect::decode(
+                &mut Bytes::from_static(b"\x00\x04MQTT\x05\xff00000000000000000000"),
+                Conformance::Strict
+            ),
             Err(DecodeError::ConnectReservedFlagSet)
         );
 
         assert_eq!(
-            ConnectAck::decode(&mut Bytes::from_static(b"\x01\x86\x00")),
+            ConnectAck::decode(&mut Bytes::from_static(b"\x01\x86\x00"), Conformance::Strict),
             Ok(ConnectAck {
                 session_present: true,
                 reason_code: ConnectAckReason::BadUserNameOrPassword,
@@ -159,7 +239,7 @@ mod tests {
         );
 
         assert_eq!(
-            ConnectAck::decode(&mut Bytes::from_static(b"\x03\x86\x00")),
+            ConnectAck::decode(&mut Bytes::from_static(b"\x03\x86\x00"), Conformance::Strict),
             Err(DecodeError::ConnAckReservedFlagSet)
         );
 
@@ -311,9 +391,10 @@ mod tests {
 
         assert_eq!(
             Packet::Unsubscribe(
-                Unsubscribe::decode(&mut Bytes::from_static(
-                    b"\x12\x34\x00\x00\x04test\x00\x06filter"
-                ))
+                Unsubscribe::decode(
+                    &mut Bytes::from_static(b"\x12\x34\x00\x00\x04test\x00\x06filter"),
+                    Conformance::Strict
+                )
                 .unwrap()
             ),
             p.clone()
@@ -336,4 +417,103 @@ mod tests {
         assert_decode_packet(b"\xc0\x00", Packet::PingRequest);
         assert_decode_packet(b"\xd0\x00", Packet::PingResponse);
     }
+
+    #[test]
+    fn test_decode_conformance() {
+        // will retain without will flag, empty client id without clean start
+        let src = Bytes::from_static(b"\x00\x04MQTT\x05\x20\x00\x3C\x00\x00\x00");
+        assert_eq!(
+            Connect::decode(&mut src.clone(), Conformance::Strict),
+            Err(DecodeError::InvalidWillFlags)
+        );
+        assert_eq!(
+            Connect::decode(&mut src.clone(), Conformance::Lenient),
+            Ok(Connect { keep_alive: 60, ..Connect::default() })
+        );
+
+        assert_eq!(
+            ConnectAck::decode(&mut Bytes::from_static(b"\x03\x86\x00"), Conformance::Lenient),
+            Ok(ConnectAck {
+                session_present: true,
+                reason_code: ConnectAckReason::BadUserNameOrPassword,
+                ..ConnectAck::default()
+            })
+        );
+
+        // reserved bits of subscription options
+        let src = Bytes::from_static(b"\x12\x34\x00\x00\x04test\x41");
+        assert_eq!(
+            Subscribe::decode(&mut src.clone(), Conformance::Strict),
+            Err(DecodeError::InvalidSubscriptionOptions)
+        );
+        assert_eq!(
+            Subscribe::decode(&mut src.clone(), Conformance::Lenient).unwrap().topic_filters,
+            vec![(
+                ByteString::from_static("test"),
+                SubscriptionOptions {
+                    qos: QoS::AtLeastOnce,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: RetainHandling::AtSubscribe,
+                }
+            )]
+        );
+        assert_eq!(
+            Subscribe::decode(&mut Bytes::from_static(b"\x12\x34\x00"), Conformance::Strict),
+            Err(DecodeError::EmptyTopicFilters)
+        );
+        assert_eq!(
+            Unsubscribe::decode(&mut Bytes::from_static(b"\x12\x34\x00"), Conformance::Strict),
+            Err(DecodeError::EmptyTopicFilters)
+        );
+    }
+
+    #[test]
+    fn test_decode_error_location() {
+        let err = decode_packet(
+            Bytes::from_static(b"\x00\x01t\x02\xff\x00"),
+            0x30,
+            Conformance::Strict,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), &DecodeError::MalformedPacket);
+        assert_eq!(
+            err.location(),
+            Some(&crate::error::DecodeErrorLocation {
+                packet_type: 0x30,
+                property: Some(0xff),
+                offset: 4
+            })
+        );
+        assert_eq!(
+            format!("{}", err),
+            "MalformedPacket at packet type 0x30, property 0xff, offset 4"
+        );
+    }
+
+    #[test]
+    fn test_unknown_properties() {
+        // user property followed by unknown property 0xff
+        let data =
+            Bytes::from_static(b"\x30\x0f\x00\x01t\x0a\x26\x00\x01k\x00\x01v\xff\x01\x02p");
+
+        let err = decode_packet(data.slice(2..), 0x30, Conformance::Strict).unwrap_err();
+        assert_eq!(err.kind(), &DecodeError::MalformedPacket);
+
+        let pkt = decode_packet(data.slice(2..), 0x30, Conformance::Lenient).unwrap();
+        if let Packet::Publish(ref publish) = pkt {
+            assert_eq!(
+                publish.properties.user_properties,
+                vec![(ByteString::from_static("k"), ByteString::from_static("v"))]
+            );
+            assert_eq!(
+                publish.properties.unknown_properties,
+                Bytes::from_static(b"\xff\x01\x02")
+            );
+            assert_eq!(publish.payload, Bytes::from_static(b"p"));
+        } else {
+            panic!("Expected Publish packet");
+        }
+        assert_eq!(Codec::new().encode_packet(pkt).unwrap(), data);
+    }
 }
@@ -355,6 +355,7 @@ mod tests {
                     user_properties: vec![],
                     is_utf8_payload: None,
                     response_topic: None,
+                    unknown_properties: Bytes::new(),
                 }),
                 username: None,
                 password: None,
@@ -1,4 +1,17 @@
 //! MQTT v5 Protocol codec
+//!
+//! Codec could be used standalone, without server and client machinery,
+//! for example to build packet analyzers, fuzzers or proxies.
+//!
+//! ```rust
+//! use ntex_mqtt::v5::codec::{Codec, Disconnect, Packet};
+//!
+//! let codec = Codec::new();
+//! let buf = codec.encode_packet(Packet::Disconnect(Disconnect::default())).unwrap();
+//! let (packet, size) = codec.decode_packet(&buf).unwrap().unwrap();
+//! assert_eq!(packet, Packet::Disconnect(Disconnect::default()));
+//! assert_eq!(size, buf.len());
+//! ```
 
 use ntex::util::ByteString;
 
@@ -13,3 +26,11 @@ pub use self::packet::*;
 
 pub type UserProperty = (ByteString, ByteString);
 pub type UserProperties = Vec<UserProperty>;
+
+/// Find value of the first user property with provided key
+pub(crate) fn get_user_property<'a>(
+    props: &'a [UserProperty],
+    key: &str,
+) -> Option<&'a ByteString> {
+    props.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
@@ -36,16 +36,16 @@ impl Auth {
             let mut user_properties = Vec::new();
 
             if reason_code != AuthReasonCode::Success || src.has_remaining() {
-                let prop_src = &mut utils::take_properties(src)?;
-                while prop_src.has_remaining() {
-                    match prop_src.get_u8() {
+                utils::decode_properties(src, |prop_id, prop_src| {
+                    match prop_id {
                         pt::AUTH_METHOD => auth_method.read_value(prop_src)?,
                         pt::AUTH_DATA => auth_data.read_value(prop_src)?,
                         pt::REASON_STRING => reason_string.read_value(prop_src)?,
                         pt::USER => user_properties.push(UserProperty::decode(prop_src)?),
                         _ => return Err(DecodeError::MalformedPacket),
                     }
-                }
+                    Ok(())
+                })?;
                 ensure!(!src.has_remaining(), DecodeError::InvalidLength);
             }
 
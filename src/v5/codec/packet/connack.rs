@@ -2,7 +2,7 @@ use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
 use std::{convert::TryInto, num::NonZeroU16};
 
 use crate::error::{DecodeError, EncodeError};
-use crate::types::{ConnectAckFlags, QoS};
+use crate::types::{Conformance, ConnectAckFlags, QoS};
 use crate::utils::{self, Decode, Encode, Property};
 use crate::v5::codec::{encode::*, property_type as pt, UserProperties, UserProperty};
 
@@ -99,18 +99,33 @@ impl ConnectAckReason {
             _ => "Connection Refused",
         }
     }
+
+    /// Connection is refused because of failed authentication or authorization
+    pub fn is_auth(self) -> bool {
+        matches!(
+            self,
+            ConnectAckReason::BadUserNameOrPassword
+                | ConnectAckReason::NotAuthorized
+                | ConnectAckReason::BadAuthenticationMethod
+        )
+    }
 }
 
 impl ConnectAck {
-    pub(crate) fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
+    pub(crate) fn decode(
+        src: &mut Bytes,
+        conformance: Conformance,
+    ) -> Result<Self, DecodeError> {
         ensure!(src.remaining() >= 2, DecodeError::InvalidLength);
-        let flags = ConnectAckFlags::from_bits(src.get_u8())
-            .ok_or(DecodeError::ConnAckReservedFlagSet)?;
+        let flags = if conformance.is_strict() {
+            ConnectAckFlags::from_bits(src.get_u8())
+                .ok_or(DecodeError::ConnAckReservedFlagSet)?
+        } else {
+            ConnectAckFlags::from_bits_truncate(src.get_u8())
+        };
 
         let reason_code = src.get_u8().try_into()?;
 
-        let prop_src = &mut utils::take_properties(src)?;
-
         let mut session_expiry_interval_secs = None;
         let mut receive_max = None;
         let mut max_qos = None;
@@ -128,8 +143,8 @@ impl ConnectAck {
         let mut server_reference = None;
         let mut auth_method = None;
         let mut auth_data = None;
-        while prop_src.has_remaining() {
-            match prop_src.get_u8() {
+        utils::decode_properties(src, |prop_id, prop_src| {
+            match prop_id {
                 pt::SESS_EXPIRY_INT => session_expiry_interval_secs.read_value(prop_src)?,
                 pt::RECEIVE_MAX => receive_max.read_value(prop_src)?,
                 pt::MAX_QOS => {
@@ -153,7 +168,8 @@ impl ConnectAck {
                 pt::AUTH_DATA => auth_data.read_value(prop_src)?,
                 _ => return Err(DecodeError::MalformedPacket),
             }
-        }
+            Ok(())
+        })?;
         ensure!(!src.has_remaining(), DecodeError::InvalidLength);
 
         Ok(ConnectAck {
@@ -3,7 +3,7 @@ use std::convert::TryFrom;
 use std::num::{NonZeroU16, NonZeroU32};
 
 use crate::error::{DecodeError, EncodeError};
-use crate::types::{ConnectFlags, QoS, MQTT, MQTT_LEVEL_5, WILL_QOS_SHIFT};
+use crate::types::{Conformance, ConnectFlags, QoS, MQTT, MQTT_LEVEL_5, WILL_QOS_SHIFT};
 use crate::utils::{self, Decode, Encode, Property};
 use crate::v5::codec::{encode::*, property_type as pt, UserProperties, UserProperty};
 
@@ -54,6 +54,11 @@ pub struct LastWill {
     pub user_properties: UserProperties,
     pub is_utf8_payload: Option<bool>,
     pub response_topic: Option<ByteString>,
+    /// Raw encoded will properties unknown to this implementation, starting with
+    /// first unknown property. Preserved in `Conformance::Lenient` mode only.
+    ///
+    /// Only publish and will properties are preserved, see `Conformance::Lenient`.
+    pub unknown_properties: Bytes,
 }
 
 impl LastWill {
@@ -65,6 +70,19 @@ impl LastWill {
             + encoded_property_size(&self.is_utf8_payload)
             + encoded_property_size(&self.response_topic)
             + self.user_properties.encoded_size()
+            + self.unknown_properties.len()
+    }
+
+    fn encode_properties(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
+        encode_property(&self.will_delay_interval_sec, pt::WILL_DELAY_INT, buf)?;
+        encode_property(&self.correlation_data, pt::CORR_DATA, buf)?;
+        encode_property(&self.message_expiry_interval, pt::MSG_EXPIRY_INT, buf)?;
+        encode_property(&self.content_type, pt::CONTENT_TYPE, buf)?;
+        encode_property(&self.is_utf8_payload, pt::UTF8_PAYLOAD, buf)?;
+        encode_property(&self.response_topic, pt::RESP_TOPIC, buf)?;
+        self.user_properties.encode(buf)?;
+        buf.put(self.unknown_properties.as_ref());
+        Ok(())
     }
 }
 
@@ -103,7 +121,10 @@ impl Connect {
         prop_len
     }
 
-    pub(crate) fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
+    pub(crate) fn decode(
+        src: &mut Bytes,
+        conformance: Conformance,
+    ) -> Result<Self, DecodeError> {
         ensure!(src.remaining() >= 10, DecodeError::InvalidLength);
         let len = src.get_u16();
 
@@ -113,8 +134,19 @@ impl Connect {
         let level = src.get_u8();
         ensure!(level == MQTT_LEVEL_5, DecodeError::UnsupportedProtocolLevel);
 
-        let flags =
-            ConnectFlags::from_bits(src.get_u8()).ok_or(DecodeError::ConnectReservedFlagSet)?;
+        let strict = conformance.is_strict();
+        let flags = if strict {
+            let flags = ConnectFlags::from_bits(src.get_u8())
+                .ok_or(DecodeError::ConnectReservedFlagSet)?;
+            ensure!(
+                flags.contains(ConnectFlags::WILL)
+                    || !flags.intersects(ConnectFlags::WILL_QOS | ConnectFlags::WILL_RETAIN),
+                DecodeError::InvalidWillFlags
+            );
+            flags
+        } else {
+            ConnectFlags::from_bits_truncate(src.get_u8())
+        };
         let keep_alive = src.get_u16();
 
         // reading properties
@@ -127,9 +159,8 @@ impl Connect {
         let mut topic_alias_max = None;
         let mut user_properties = Vec::new();
         let mut max_packet_size = None;
-        let prop_src = &mut utils::take_properties(src)?;
-        while prop_src.has_remaining() {
-            match prop_src.get_u8() {
+        utils::decode_properties(src, |prop_id, prop_src| {
+            match prop_id {
                 pt::SESS_EXPIRY_INT => session_expiry_interval_secs.read_value(prop_src)?,
                 pt::AUTH_METHOD => auth_method.read_value(prop_src)?,
                 pt::AUTH_DATA => auth_data.read_value(prop_src)?,
@@ -141,18 +172,19 @@ impl Connect {
                 pt::MAX_PACKET_SIZE => max_packet_size.read_value(prop_src)?,
                 _ => return Err(DecodeError::MalformedPacket),
             }
-        }
+            Ok(())
+        })?;
 
         let client_id = ByteString::decode(src)?;
 
         ensure!(
             // todo: [MQTT-3.1.3-8]?
-            !client_id.is_empty() || flags.contains(ConnectFlags::CLEAN_START),
+            !strict || !client_id.is_empty() || flags.contains(ConnectFlags::CLEAN_START),
             DecodeError::InvalidClientId
         );
 
         let last_will = if flags.contains(ConnectFlags::WILL) {
-            Some(decode_last_will(src, flags)?)
+            Some(decode_last_will(src, flags, conformance)?)
         } else {
             None
         };
@@ -212,7 +244,11 @@ impl Default for Connect {
     }
 }
 
-fn decode_last_will(src: &mut Bytes, flags: ConnectFlags) -> Result<LastWill, DecodeError> {
+fn decode_last_will(
+    src: &mut Bytes,
+    flags: ConnectFlags,
+    conformance: Conformance,
+) -> Result<LastWill, DecodeError> {
     let mut will_delay_interval_sec = None;
     let mut correlation_data = None;
     let mut message_expiry_interval = None;
@@ -220,24 +256,29 @@ fn decode_last_will(src: &mut Bytes, flags: ConnectFlags) -> Result<LastWill, De
     let mut user_properties = Vec::new();
     let mut is_utf8_payload = None;
     let mut response_topic = None;
-    let prop_src = &mut utils::take_properties(src)?;
-    while prop_src.has_remaining() {
-        match prop_src.get_u8() {
-            pt::WILL_DELAY_INT => will_delay_interval_sec.read_value(prop_src)?,
-            pt::CORR_DATA => correlation_data.read_value(prop_src)?,
-            pt::MSG_EXPIRY_INT => message_expiry_interval.read_value(prop_src)?,
-            pt::CONTENT_TYPE => content_type.read_value(prop_src)?,
-            pt::UTF8_PAYLOAD => is_utf8_payload.read_value(prop_src)?,
-            pt::RESP_TOPIC => response_topic.read_value(prop_src)?,
-            pt::USER => user_properties.push(UserProperty::decode(prop_src)?),
-            _ => return Err(DecodeError::MalformedPacket),
-        }
-    }
+    let unknown_properties = utils::decode_properties_preserve(
+        src,
+        !conformance.is_strict(),
+        |prop_id, prop_src| {
+            match prop_id {
+                pt::WILL_DELAY_INT => will_delay_interval_sec.read_value(prop_src)?,
+                pt::CORR_DATA => correlation_data.read_value(prop_src)?,
+                pt::MSG_EXPIRY_INT => message_expiry_interval.read_value(prop_src)?,
+                pt::CONTENT_TYPE => content_type.read_value(prop_src)?,
+                pt::UTF8_PAYLOAD => is_utf8_payload.read_value(prop_src)?,
+                pt::RESP_TOPIC => response_topic.read_value(prop_src)?,
+                pt::USER => user_properties.push(UserProperty::decode(prop_src)?),
+                _ => return Ok(false),
+            }
+            Ok(true)
+        },
+    )?;
 
     let topic = ByteString::decode(src)?;
     let message = Bytes::decode(src)?;
     Ok(LastWill {
-        qos: QoS::try_from((flags & ConnectFlags::WILL_QOS).bits() >> WILL_QOS_SHIFT)?,
+        qos: QoS::try_from((flags & ConnectFlags::WILL_QOS).bits() >> WILL_QOS_SHIFT)
+            .map_err(|_| DecodeError::InvalidQoS)?,
         retain: flags.contains(ConnectFlags::WILL_RETAIN),
         topic,
         message,
@@ -248,6 +289,7 @@ fn decode_last_will(src: &mut Bytes, flags: ConnectFlags) -> Result<LastWill, De
         user_properties,
         is_utf8_payload,
         response_topic,
+        unknown_properties,
     })
 }
 
@@ -319,6 +361,7 @@ impl EncodeLtd for Connect {
         if let Some(will) = self.last_will.as_ref() {
             let prop_len = will.properties_len();
             utils::write_variable_length(prop_len as u32, buf); // safe: whole message size is checked for max already
+            will.encode_properties(buf)?;
 
             will.topic.encode(buf)?;
             will.message.encode(buf)?;
@@ -51,6 +51,16 @@ prim_enum! {
     }
 }
 
+impl DisconnectReasonCode {
+    /// Connection is closed because of failed authentication or authorization
+    pub fn is_auth(self) -> bool {
+        matches!(
+            self,
+            DisconnectReasonCode::NotAuthorized | DisconnectReasonCode::BadAuthenticationMethod
+        )
+    }
+}
+
 impl Disconnect {
     /// Create new instance of `Disconnect` with specified code
     pub fn new(reason_code: DisconnectReasonCode) -> Self {
@@ -72,16 +82,16 @@ impl Disconnect {
             let mut reason_string = None;
             let mut user_properties = Vec::new();
 
-            let prop_src = &mut utils::take_properties(src)?;
-            while prop_src.has_remaining() {
-                match prop_src.get_u8() {
+            utils::decode_properties(src, |prop_id, prop_src| {
+                match prop_id {
                     pt::SESS_EXPIRY_INT => session_expiry_interval_secs.read_value(prop_src)?,
                     pt::REASON_STRING => reason_string.read_value(prop_src)?,
                     pt::USER => user_properties.push(UserProperty::decode(prop_src)?),
                     pt::SERVER_REF => server_reference.read_value(prop_src)?,
                     _ => return Err(DecodeError::MalformedPacket),
                 }
-            }
+                Ok(())
+            })?;
             ensure!(!src.has_remaining(), DecodeError::InvalidLength);
 
             Ok(Disconnect {
@@ -1,12 +1,12 @@
 use derive_more::From;
-use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
+use ntex::util::{BufMut, ByteString, Bytes, BytesMut};
 
-pub use crate::types::{ConnectAckFlags, ConnectFlags, QoS};
+pub use crate::types::{Conformance, ConnectAckFlags, ConnectFlags, QoS};
 
 use super::{encode::*, property_type as pt, UserProperties};
 use crate::error::{DecodeError, EncodeError};
 use crate::types::packet_type;
-use crate::utils::{take_properties, write_variable_length, Decode, Property};
+use crate::utils::{self, write_variable_length, Decode, Property};
 
 mod auth;
 mod connack;
@@ -154,17 +154,16 @@ mod ack_props {
     pub(crate) fn decode(
         src: &mut Bytes,
     ) -> Result<(UserProperties, Option<ByteString>), DecodeError> {
-        let prop_src = &mut take_properties(src)?;
         let mut reason_string = None;
         let mut user_props = Vec::new();
-        while prop_src.has_remaining() {
-            let prop_id = prop_src.get_u8();
+        utils::decode_properties(src, |prop_id, prop_src| {
             match prop_id {
                 pt::REASON_STRING => reason_string.read_value(prop_src)?,
                 pt::USER => user_props.push(<(ByteString, ByteString)>::decode(prop_src)?),
                 _ => return Err(DecodeError::MalformedPacket),
             }
-        }
+            Ok(())
+        })?;
 
         Ok((user_props, reason_string))
     }
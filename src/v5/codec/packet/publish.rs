@@ -1,8 +1,8 @@
-use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
+use ntex::util::{BufMut, ByteString, Bytes, BytesMut};
 use std::{convert::TryFrom, fmt, num::NonZeroU16, num::NonZeroU32};
 
 use crate::error::{DecodeError, EncodeError};
-use crate::types::QoS;
+use crate::types::{Conformance, QoS};
 use crate::utils::{self, Decode, Encode, Property};
 use crate::v5::codec::{encode::*, property_type as pt, UserProperties};
 
@@ -45,6 +45,11 @@ pub struct PublishProperties {
     pub is_utf8_payload: Option<bool>,
     pub response_topic: Option<ByteString>,
     pub subscription_ids: Option<Vec<NonZeroU32>>,
+    /// Raw encoded properties unknown to this implementation, starting with
+    /// first unknown property. Preserved in `Conformance::Lenient` mode only.
+    ///
+    /// Only publish and will properties are preserved, see `Conformance::Lenient`.
+    pub unknown_properties: Bytes,
 }
 
 impl Default for PublishProperties {
@@ -58,22 +63,27 @@ impl Default for PublishProperties {
             is_utf8_payload: None,
             response_topic: None,
             subscription_ids: None,
+            unknown_properties: Bytes::new(),
         }
     }
 }
 
 impl Publish {
-    pub(crate) fn decode(mut src: Bytes, packet_flags: u8) -> Result<Self, DecodeError> {
-        let topic = ByteString::decode(&mut src)?;
+    pub(crate) fn decode(
+        src: &mut Bytes,
+        packet_flags: u8,
+        conformance: Conformance,
+    ) -> Result<Self, DecodeError> {
+        let topic = ByteString::decode(src)?;
         let qos = QoS::try_from((packet_flags & 0b0110) >> 1)?;
         let packet_id = if qos == QoS::AtMostOnce {
             None
         } else {
-            Some(NonZeroU16::decode(&mut src)?) // packet id = 0 encountered
+            Some(NonZeroU16::decode(src)?) // packet id = 0 encountered
         };
 
-        let properties = parse_publish_properties(&mut src)?;
-        let payload = src;
+        let properties = parse_publish_properties(src, conformance)?;
+        let payload = src.split_off(0);
 
         Ok(Self {
             dup: (packet_flags & 0b1000) == 0b1000,
@@ -87,9 +97,10 @@ impl Publish {
     }
 }
 
-fn parse_publish_properties(src: &mut Bytes) -> Result<PublishProperties, DecodeError> {
-    let prop_src = &mut utils::take_properties(src)?;
-
+fn parse_publish_properties(
+    src: &mut Bytes,
+    conformance: Conformance,
+) -> Result<PublishProperties, DecodeError> {
     let mut message_expiry_interval = None;
     let mut topic_alias = None;
     let mut content_type = None;
@@ -99,24 +110,29 @@ fn parse_publish_properties(src: &mut Bytes) -> Result<PublishProperties, Decode
     let mut is_utf8_payload = None;
     let mut user_props = Vec::new();
 
-    while prop_src.has_remaining() {
-        match prop_src.get_u8() {
-            pt::UTF8_PAYLOAD => is_utf8_payload.read_value(prop_src)?,
-            pt::MSG_EXPIRY_INT => message_expiry_interval.read_value(prop_src)?,
-            pt::CONTENT_TYPE => content_type.read_value(prop_src)?,
-            pt::RESP_TOPIC => response_topic.read_value(prop_src)?,
-            pt::CORR_DATA => correlation_data.read_value(prop_src)?,
-            pt::SUB_ID => {
-                let id = utils::decode_variable_length_cursor(prop_src)?;
-                subscription_ids
-                    .get_or_insert_with(Vec::new)
-                    .push(NonZeroU32::new(id).ok_or(DecodeError::MalformedPacket)?);
+    let unknown_properties = utils::decode_properties_preserve(
+        src,
+        !conformance.is_strict(),
+        |prop_id, prop_src| {
+            match prop_id {
+                pt::UTF8_PAYLOAD => is_utf8_payload.read_value(prop_src)?,
+                pt::MSG_EXPIRY_INT => message_expiry_interval.read_value(prop_src)?,
+                pt::CONTENT_TYPE => content_type.read_value(prop_src)?,
+                pt::RESP_TOPIC => response_topic.read_value(prop_src)?,
+                pt::CORR_DATA => correlation_data.read_value(prop_src)?,
+                pt::SUB_ID => {
+                    let id = utils::decode_variable_length_cursor(prop_src)?;
+                    subscription_ids
+                        .get_or_insert_with(Vec::new)
+                        .push(NonZeroU32::new(id).ok_or(DecodeError::MalformedPacket)?);
+                }
+                pt::TOPIC_ALIAS => topic_alias.read_value(prop_src)?,
+                pt::USER => user_props.push(<(ByteString, ByteString)>::decode(prop_src)?),
+                _ => return Ok(false),
             }
-            pt::TOPIC_ALIAS => topic_alias.read_value(prop_src)?,
-            pt::USER => user_props.push(<(ByteString, ByteString)>::decode(prop_src)?),
-            _ => return Err(DecodeError::MalformedPacket),
-        }
-    }
+            Ok(true)
+        },
+    )?;
 
     Ok(PublishProperties {
         message_expiry_interval,
@@ -127,6 +143,7 @@ fn parse_publish_properties(src: &mut Bytes) -> Result<PublishProperties, Decode
         response_topic,
         is_utf8_payload,
         user_properties: user_props,
+        unknown_properties,
     })
 }
 
@@ -167,7 +184,8 @@ impl EncodeLtd for PublishProperties {
             + self.subscription_ids.as_ref().map_or(0, |v| {
                 v.iter().fold(0, |acc, id| acc + 1 + var_int_len(id.get() as usize) as usize)
             })
-            + self.user_properties.encoded_size();
+            + self.user_properties.encoded_size()
+            + self.unknown_properties.len();
         prop_len + var_int_len(prop_len) as usize
     }
 
@@ -186,6 +204,8 @@ impl EncodeLtd for PublishProperties {
                 sub_id.encode(buf)?;
             }
         }
-        self.user_properties.encode(buf)
+        self.user_properties.encode(buf)?;
+        buf.put(self.unknown_properties.as_ref());
+        Ok(())
     }
 }
@@ -4,7 +4,7 @@ use std::num::{NonZeroU16, NonZeroU32};
 
 use super::ack_props;
 use crate::error::{DecodeError, EncodeError};
-use crate::types::QoS;
+use crate::types::{Conformance, QoS};
 use crate::utils::{self, Decode, Encode};
 use crate::v5::codec::{encode::*, property_type as pt, UserProperties, UserProperty};
 
@@ -28,6 +28,48 @@ pub struct SubscriptionOptions {
     pub retain_handling: RetainHandling,
 }
 
+impl SubscriptionOptions {
+    /// Create subscription options with specified maximum qos
+    pub fn new(qos: QoS) -> Self {
+        SubscriptionOptions {
+            qos,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: RetainHandling::AtSubscribe,
+        }
+    }
+
+    /// Do not forward messages published by this client
+    pub fn no_local(mut self, val: bool) -> Self {
+        self.no_local = val;
+        self
+    }
+
+    /// Keep retain flag of forwarded messages as published
+    pub fn retain_as_published(mut self, val: bool) -> Self {
+        self.retain_as_published = val;
+        self
+    }
+
+    /// Set handling of retained messages at subscribe time
+    pub fn retain_handling(mut self, val: RetainHandling) -> Self {
+        self.retain_handling = val;
+        self
+    }
+}
+
+impl Default for SubscriptionOptions {
+    fn default() -> Self {
+        SubscriptionOptions::new(QoS::AtMostOnce)
+    }
+}
+
+impl From<QoS> for SubscriptionOptions {
+    fn from(qos: QoS) -> Self {
+        SubscriptionOptions::new(qos)
+    }
+}
+
 prim_enum! {
     pub enum RetainHandling {
         AtSubscribe = 0,
@@ -98,13 +140,15 @@ prim_enum! {
 }
 
 impl Subscribe {
-    pub(crate) fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
+    pub(crate) fn decode(
+        src: &mut Bytes,
+        conformance: Conformance,
+    ) -> Result<Self, DecodeError> {
+        let strict = conformance.is_strict();
         let packet_id = NonZeroU16::decode(src)?;
-        let prop_src = &mut utils::take_properties(src)?;
         let mut sub_id = None;
         let mut user_properties = Vec::new();
-        while prop_src.has_remaining() {
-            let prop_id = prop_src.get_u8();
+        utils::decode_properties(src, |prop_id, prop_src| {
             match prop_id {
                 pt::SUB_ID => {
                     ensure!(sub_id.is_none(), DecodeError::MalformedPacket); // can't appear twice
@@ -114,14 +158,20 @@ impl Subscribe {
                 pt::USER => user_properties.push(UserProperty::decode(prop_src)?),
                 _ => return Err(DecodeError::MalformedPacket),
             }
-        }
+            Ok(())
+        })?;
 
         let mut topic_filters = Vec::new();
         while src.has_remaining() {
             let topic = ByteString::decode(src)?;
+            ensure!(
+                !strict || src.as_ref().first().map_or(true, |v| v & 0b1100_0000 == 0),
+                DecodeError::InvalidSubscriptionOptions
+            );
             let opts = SubscriptionOptions::decode(src)?;
             topic_filters.push((topic, opts));
         }
+        ensure!(!strict || !topic_filters.is_empty(), DecodeError::EmptyTopicFilters);
 
         Ok(Self { packet_id, id: sub_id, user_properties, topic_filters })
     }
@@ -140,23 +190,29 @@ impl SubscribeAck {
 }
 
 impl Unsubscribe {
-    pub(crate) fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
+    pub(crate) fn decode(
+        src: &mut Bytes,
+        conformance: Conformance,
+    ) -> Result<Self, DecodeError> {
         let packet_id = NonZeroU16::decode(src)?;
 
-        let prop_src = &mut utils::take_properties(src)?;
         let mut user_properties = Vec::new();
-        while prop_src.has_remaining() {
-            let prop_id = prop_src.get_u8();
+        utils::decode_properties(src, |prop_id, prop_src| {
             match prop_id {
                 pt::USER => user_properties.push(UserProperty::decode(prop_src)?),
                 _ => return Err(DecodeError::MalformedPacket),
             }
-        }
+            Ok(())
+        })?;
 
         let mut topic_filters = Vec::new();
         while src.remaining() > 0 {
             topic_filters.push(ByteString::decode(src)?);
         }
+        ensure!(
+            !conformance.is_strict() || !topic_filters.is_empty(),
+            DecodeError::EmptyTopicFilters
+        );
 
         Ok(Self { packet_id, user_properties, topic_filters })
     }
@@ -205,7 +261,7 @@ impl Decode for SubscriptionOptions {
     fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
         ensure!(src.has_remaining(), DecodeError::InvalidLength);
         let val = src.get_u8();
-        let qos = (val & 0b0000_0011).try_into()?;
+        let qos = (val & 0b0000_0011).try_into().map_err(|_| DecodeError::InvalidQoS)?;
         let retain_handling = ((val & 0b0011_0000) >> 4).try_into()?;
         Ok(SubscriptionOptions {
             qos,
@@ -1,9 +1,10 @@
-use std::marker::PhantomData;
+use std::{any::Any, io, marker::PhantomData, num::NonZeroU16, rc::Rc};
 
-use ntex::util::ByteString;
+use ntex::util::{ByteString, Bytes};
 
 use super::codec::{self, DisconnectReasonCode, QoS, UserProperties};
-use crate::error;
+use super::publish::IntoReasonCode;
+use crate::{error, utils::CloseState};
 
 /// Control plain messages
 #[derive(Debug)]
@@ -11,9 +12,16 @@ pub enum ControlMessage<E> {
     Auth(Auth),
     Ping(Ping),
     Disconnect(Disconnect),
+    PublishRelease(PublishRelease),
     Subscribe(Subscribe),
     Unsubscribe(Unsubscribe),
     Closed(Closed),
+    WillRequested(WillRequested),
+    SessionExpired(SessionExpired),
+    SessionTakenOver(SessionTakenOver),
+    KeepAliveTimeout(KeepAliveTimeout),
+    SlowConsumer(SlowConsumer),
+    Event(Event),
     Error(Error<E>),
     ProtocolError(ProtocolError),
 }
@@ -38,8 +46,39 @@ impl<E> ControlMessage<E> {
         ControlMessage::Disconnect(Disconnect(pkt))
     }
 
-    pub(super) fn closed(is_error: bool) -> Self {
-        ControlMessage::Closed(Closed::new(is_error))
+    pub(super) fn pkt_publish_release(
+        pkt: codec::PublishAck2,
+        publish: Option<codec::Publish>,
+    ) -> Self {
+        ControlMessage::PublishRelease(PublishRelease(pkt, publish))
+    }
+
+    pub(super) fn closed(is_error: bool, state: &CloseState) -> Self {
+        ControlMessage::Closed(Closed::new(is_error, state))
+    }
+
+    pub(super) fn will_requested(will: codec::LastWill) -> Self {
+        ControlMessage::WillRequested(WillRequested(will))
+    }
+
+    pub(super) fn session_expired() -> Self {
+        ControlMessage::SessionExpired(SessionExpired)
+    }
+
+    pub(super) fn session_taken_over() -> Self {
+        ControlMessage::SessionTakenOver(SessionTakenOver)
+    }
+
+    pub(super) fn keepalive_timeout() -> Self {
+        ControlMessage::KeepAliveTimeout(KeepAliveTimeout)
+    }
+
+    pub(super) fn slow_consumer() -> Self {
+        ControlMessage::SlowConsumer(SlowConsumer)
+    }
+
+    pub(super) fn event(event: Event) -> Self {
+        ControlMessage::Event(event)
     }
 
     pub(super) fn error(err: E) -> Self {
@@ -75,6 +114,12 @@ impl Auth {
         &self.0
     }
 
+    #[inline]
+    /// Returns value of the first user property with provided key
+    pub fn get_user_property(&self, key: &str) -> Option<&ByteString> {
+        codec::get_user_property(&self.0.user_properties, key)
+    }
+
     pub fn ack(self, response: codec::Auth) -> ControlResult {
         ControlResult { packet: Some(codec::Packet::Auth(response)), disconnect: false }
     }
@@ -98,24 +143,88 @@ impl Disconnect {
         &self.0
     }
 
+    #[inline]
+    /// Returns value of the first user property with provided key
+    pub fn get_user_property(&self, key: &str) -> Option<&ByteString> {
+        codec::get_user_property(&self.0.user_properties, key)
+    }
+
     /// Ack disconnect message
     pub fn ack(self) -> ControlResult {
         ControlResult { packet: None, disconnect: true }
     }
 }
 
+/// Publish release message
+///
+/// Peer released QoS 2 publish, the publish can be forwarded to subscribers.
+/// PUBCOMP is sent when message is acked. Message is delivered only if
+/// `MqttServer::publish_release()` is enabled.
+#[derive(Debug)]
+pub struct PublishRelease(codec::PublishAck2, Option<codec::Publish>);
+
+impl PublishRelease {
+    #[inline]
+    /// Packet identifier of the released publish
+    pub fn packet_id(&self) -> NonZeroU16 {
+        self.0.packet_id
+    }
+
+    /// Returns reference to publish release packet
+    pub fn packet(&self) -> &codec::PublishAck2 {
+        &self.0
+    }
+
+    /// Returns reference to the released publish
+    ///
+    /// Publish is not available for publishes of restored session.
+    pub fn publish(&self) -> Option<&codec::Publish> {
+        self.1.as_ref()
+    }
+
+    #[inline]
+    /// convert packet to a result, server responds with PUBCOMP
+    pub fn ack(self) -> ControlResult {
+        let pkt = codec::PublishAck2 {
+            packet_id: self.0.packet_id,
+            reason_code: codec::PublishAck2Reason::Success,
+            properties: UserProperties::default(),
+            reason_string: None,
+        };
+        ControlResult { packet: Some(codec::Packet::PublishComplete(pkt)), disconnect: false }
+    }
+}
+
 /// Subscribe message
 #[derive(Debug)]
 pub struct Subscribe {
     packet: codec::Subscribe,
     result: codec::SubscribeAck,
+    rejected: Vec<bool>,
 }
 
 impl Subscribe {
     pub(crate) fn create<E>(packet: codec::Subscribe) -> ControlMessage<E> {
+        Self::create_checked(packet, |_| None)
+    }
+
+    /// Create subscribe message, topic filters rejected by `check` function
+    /// get failed with returned reason and skipped by iterator
+    pub(crate) fn create_checked<E, F>(packet: codec::Subscribe, check: F) -> ControlMessage<E>
+    where
+        F: Fn(&ByteString) -> Option<codec::SubscribeAckReason>,
+    {
         let mut status = Vec::with_capacity(packet.topic_filters.len());
-        (0..packet.topic_filters.len())
-            .for_each(|_| status.push(codec::SubscribeAckReason::UnspecifiedError));
+        let mut rejected = Vec::new();
+        for (idx, (topic, _)) in packet.topic_filters.iter().enumerate() {
+            if let Some(reason) = check(topic) {
+                rejected.resize(idx, false);
+                rejected.push(true);
+                status.push(reason);
+            } else {
+                status.push(codec::SubscribeAckReason::UnspecifiedError);
+            }
+        }
 
         let result = codec::SubscribeAck {
             status,
@@ -124,7 +233,16 @@ impl Subscribe {
             reason_string: None,
         };
 
-        ControlMessage::Subscribe(Self { packet, result })
+        ControlMessage::Subscribe(Self { packet, result, rejected })
+    }
+
+    /// Reject topic filter, rejected filters are skipped by iterator
+    pub(crate) fn reject(&mut self, idx: usize, reason: codec::SubscribeAckReason) {
+        if !self.rejected.get(idx).copied().unwrap_or(false) {
+            self.rejected.resize(self.packet.topic_filters.len(), false);
+            self.rejected[idx] = true;
+            self.result.status[idx] = reason;
+        }
     }
 
     #[inline]
@@ -163,6 +281,19 @@ impl Subscribe {
     pub fn packet(&self) -> &codec::Subscribe {
         &self.packet
     }
+
+    #[inline]
+    /// Returns value of the first user property with provided key
+    pub fn get_user_property(&self, key: &str) -> Option<&ByteString> {
+        codec::get_user_property(&self.packet.user_properties, key)
+    }
+
+    #[inline]
+    /// Add user property to ack packet
+    pub fn ack_user_property(mut self, key: ByteString, value: ByteString) -> Self {
+        self.result.properties.push((key, value));
+        self
+    }
 }
 
 impl<'a> IntoIterator for &'a mut Subscribe {
@@ -185,6 +316,11 @@ impl<'a> SubscribeIter<'a> {
     fn next_unsafe(&mut self) -> Option<Subscription<'a>> {
         let subs = unsafe { &mut *self.subs };
 
+        // skip topic filters rejected by server capabilities
+        while subs.rejected.get(self.entry).copied().unwrap_or(false) {
+            self.entry += 1;
+        }
+
         if self.entry < subs.packet.topic_filters.len() {
             let s = Subscription {
                 topic: &subs.packet.topic_filters[self.entry].0,
@@ -322,6 +458,19 @@ impl Unsubscribe {
     pub fn packet(&self) -> &codec::Unsubscribe {
         &self.packet
     }
+
+    #[inline]
+    /// Returns value of the first user property with provided key
+    pub fn get_user_property(&self, key: &str) -> Option<&ByteString> {
+        codec::get_user_property(&self.packet.user_properties, key)
+    }
+
+    #[inline]
+    /// Add user property to ack packet
+    pub fn ack_user_property(mut self, key: ByteString, value: ByteString) -> Self {
+        self.result.properties.push((key, value));
+        self
+    }
 }
 
 impl<'a> IntoIterator for &'a mut Unsubscribe {
@@ -397,11 +546,14 @@ impl<'a> UnsubscribeItem<'a> {
 #[derive(Debug)]
 pub struct Closed {
     is_error: bool,
+    peer: bool,
+    io_error: Option<Rc<io::Error>>,
 }
 
 impl Closed {
-    pub(crate) fn new(is_error: bool) -> Self {
-        Self { is_error }
+    pub(crate) fn new(is_error: bool, state: &CloseState) -> Self {
+        let (peer, io_error) = state.take(is_error);
+        Self { is_error, peer, io_error }
     }
 
     /// Returns error state on connection close
@@ -409,6 +561,18 @@ impl Closed {
         self.is_error
     }
 
+    /// Returns true if connection is closed by the peer
+    ///
+    /// Peer sent DISCONNECT packet, closed the transport, or transport failed.
+    pub fn is_peer_initiated(&self) -> bool {
+        self.peer
+    }
+
+    /// Returns transport error, if connection is closed because of io error
+    pub fn io_error(&self) -> Option<&io::Error> {
+        self.io_error.as_deref()
+    }
+
     #[inline]
     /// convert packet to a result
     pub fn ack(self) -> ControlResult {
@@ -416,6 +580,204 @@ impl Closed {
     }
 }
 
+/// Will message must be published
+///
+/// Connection is closed without receiving DISCONNECT packet, or DISCONNECT
+/// packet has reason code other than `NormalDisconnection`. Message is emitted
+/// before `Closed` message. Publishing will message after will delay interval
+/// is up to the application.
+#[derive(Debug)]
+pub struct WillRequested(codec::LastWill);
+
+impl WillRequested {
+    #[inline]
+    /// Will topic
+    pub fn topic(&self) -> &ByteString {
+        &self.0.topic
+    }
+
+    #[inline]
+    /// Will message payload
+    pub fn message(&self) -> &Bytes {
+        &self.0.message
+    }
+
+    #[inline]
+    /// the QoS level to be used when publishing the Will Message
+    pub fn qos(&self) -> QoS {
+        self.0.qos
+    }
+
+    #[inline]
+    /// the Will Message is to be Retained when it is published
+    pub fn retain(&self) -> bool {
+        self.0.retain
+    }
+
+    #[inline]
+    /// Will delay interval in seconds
+    pub fn delay_interval(&self) -> Option<u32> {
+        self.0.will_delay_interval_sec
+    }
+
+    #[inline]
+    /// Returns reference to will message
+    pub fn packet(&self) -> &codec::LastWill {
+        &self.0
+    }
+
+    #[inline]
+    /// Consume message and return will message
+    pub fn into_inner(self) -> codec::LastWill {
+        self.0
+    }
+
+    /// Create publish packet from will message
+    pub fn publish(&self) -> codec::Publish {
+        let will = &self.0;
+        codec::Publish {
+            dup: false,
+            retain: will.retain,
+            qos: will.qos,
+            topic: will.topic.clone(),
+            packet_id: None,
+            payload: will.message.clone(),
+            properties: codec::PublishProperties {
+                correlation_data: will.correlation_data.clone(),
+                message_expiry_interval: will.message_expiry_interval,
+                content_type: will.content_type.clone(),
+                user_properties: will.user_properties.clone(),
+                is_utf8_payload: will.is_utf8_payload,
+                response_topic: will.response_topic.clone(),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[inline]
+    /// convert packet to a result
+    pub fn ack(self) -> ControlResult {
+        ControlResult { packet: None, disconnect: false }
+    }
+}
+
+/// Session expired message
+///
+/// Session expiry interval is elapsed after connection has been closed,
+/// session state should be discarded. If session registry is configured,
+/// message is not sent for sessions resumed or taken over by new connection.
+#[derive(Debug)]
+pub struct SessionExpired;
+
+impl SessionExpired {
+    #[inline]
+    /// convert packet to a result
+    pub fn ack(self) -> ControlResult {
+        ControlResult { packet: None, disconnect: false }
+    }
+}
+
+/// Session taken over message
+///
+/// Client connected with the same client id, connection is going to be
+/// disconnected with `SessionTakenOver` reason code. New connection is
+/// acknowledged after message is handled, so session state could be
+/// transferred to the new connection.
+#[derive(Debug)]
+pub struct SessionTakenOver;
+
+impl SessionTakenOver {
+    #[inline]
+    /// convert packet to a result
+    pub fn ack(self) -> ControlResult {
+        ControlResult {
+            packet: Some(codec::Packet::Disconnect(codec::Disconnect::new(
+                DisconnectReasonCode::SessionTakenOver,
+            ))),
+            disconnect: true,
+        }
+    }
+}
+
+/// Keep-alive timeout message
+///
+/// Client did not send any packet within keep-alive period. If keep-alive
+/// is not extended, `ProtocolError` message with `KeepAliveTimeout` error
+/// is emitted and connection get closed.
+#[derive(Debug)]
+pub struct KeepAliveTimeout;
+
+impl KeepAliveTimeout {
+    #[inline]
+    /// convert packet to a result, connection get closed
+    pub fn ack(self) -> ControlResult {
+        ControlResult { packet: None, disconnect: true }
+    }
+
+    #[inline]
+    /// Keep connection open for one more keep-alive period
+    pub fn extend(self) -> ControlResult {
+        ControlResult { packet: None, disconnect: false }
+    }
+}
+
+/// Slow consumer message
+///
+/// Client does not read from connection and write buffer size
+/// exceeds configured limit.
+#[derive(Debug)]
+pub struct SlowConsumer;
+
+impl SlowConsumer {
+    #[inline]
+    /// convert packet to a result, connection get closed
+    pub fn ack(self) -> ControlResult {
+        ControlResult { packet: None, disconnect: true }
+    }
+
+    #[inline]
+    /// Keep connection open for one more timeout period
+    pub fn extend(self) -> ControlResult {
+        ControlResult { packet: None, disconnect: false }
+    }
+}
+
+/// Application event
+///
+/// Event is pushed to connection's control service with `EventSender`,
+/// see `MqttSink::events()`.
+#[derive(Debug)]
+pub struct Event(Box<dyn Any>);
+
+impl Event {
+    pub(super) fn new<T: 'static>(event: T) -> Self {
+        Event(Box::new(event))
+    }
+
+    #[inline]
+    /// Returns true if event is of type `T`
+    pub fn is<T: 'static>(&self) -> bool {
+        self.0.is::<T>()
+    }
+
+    #[inline]
+    /// Returns reference to event if it is of type `T`
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.0.downcast_ref()
+    }
+
+    /// Consume message and return event if it is of type `T`
+    pub fn into_inner<T: 'static>(self) -> Result<T, Self> {
+        self.0.downcast().map(|ev| *ev).map_err(Event)
+    }
+
+    #[inline]
+    /// convert event to a result
+    pub fn ack(self) -> ControlResult {
+        ControlResult { packet: None, disconnect: false }
+    }
+}
+
 /// Service level error
 #[derive(Debug)]
 pub struct Error<E> {
@@ -485,6 +847,19 @@ impl<E> Error<E> {
     }
 }
 
+impl<E: IntoReasonCode> Error<E> {
+    #[inline]
+    /// Ack service error with error's reason code and reason string,
+    /// return disconnect packet and close connection.
+    pub fn ack_reason(mut self) -> ControlResult {
+        if let Some(reason) = self.err.reason_string() {
+            self.pkt.reason_string = Some(reason);
+        }
+        let reason = self.err.disconnect_reason();
+        self.ack(reason)
+    }
+}
+
 /// Connection failed message
 #[derive(Debug)]
 pub struct ProtocolError {
@@ -501,12 +876,21 @@ impl ProtocolError {
                 reason_string: None,
                 user_properties: UserProperties::default(),
                 reason_code: match err {
-                    error::ProtocolError::Decode(error::DecodeError::InvalidLength) => {
-                        DisconnectReasonCode::MalformedPacket
-                    }
-                    error::ProtocolError::Decode(error::DecodeError::MaxSizeExceeded) => {
-                        DisconnectReasonCode::PacketTooLarge
-                    }
+                    error::ProtocolError::Decode(ref err) => match err.kind() {
+                        error::DecodeError::InvalidLength
+                        | error::DecodeError::InvalidPacketFlags
+                        | error::DecodeError::InvalidQoS
+                        | error::DecodeError::InvalidWillFlags
+                        | error::DecodeError::InvalidSubscriptionOptions
+                        | error::DecodeError::EmptyTopicFilters
+                        | error::DecodeError::InvalidUtf8String => {
+                            DisconnectReasonCode::MalformedPacket
+                        }
+                        error::DecodeError::MaxSizeExceeded => {
+                            DisconnectReasonCode::PacketTooLarge
+                        }
+                        _ => DisconnectReasonCode::ImplementationSpecificError,
+                    },
                     error::ProtocolError::Unexpected(_, _) => {
                         DisconnectReasonCode::ProtocolError
                     }
@@ -516,9 +900,19 @@ impl ProtocolError {
                     error::ProtocolError::KeepAliveTimeout => {
                         DisconnectReasonCode::KeepAliveTimeout
                     }
-                    error::ProtocolError::UnknownTopicAlias => {
+                    error::ProtocolError::PublishRateExceeded => {
+                        DisconnectReasonCode::QuotaExceeded
+                    }
+                    error::ProtocolError::UnknownTopicAlias
+                    | error::ProtocolError::MaxTopicAlias => {
                         DisconnectReasonCode::TopicAliasInvalid
                     }
+                    error::ProtocolError::RetainNotSupported => {
+                        DisconnectReasonCode::RetainNotSupported
+                    }
+                    error::ProtocolError::SubscriptionIdentifiersNotSupported => {
+                        DisconnectReasonCode::SubscriptionIdentifiersNotSupported
+                    }
                     error::ProtocolError::Encode(_) => {
                         DisconnectReasonCode::ImplementationSpecificError
                     }
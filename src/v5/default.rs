@@ -87,6 +87,14 @@ impl<S, E: fmt::Debug> Service for DefaultControlService<S, E> {
         match pkt {
             ControlMessage::Ping(pkt) => Ready::Ok(pkt.ack()),
             ControlMessage::Disconnect(pkt) => Ready::Ok(pkt.ack()),
+            ControlMessage::PublishRelease(pkt) => Ready::Ok(pkt.ack()),
+            ControlMessage::WillRequested(pkt) => Ready::Ok(pkt.ack()),
+            ControlMessage::SessionExpired(pkt) => Ready::Ok(pkt.ack()),
+            ControlMessage::SessionTakenOver(pkt) => Ready::Ok(pkt.ack()),
+            ControlMessage::KeepAliveTimeout(pkt) => Ready::Ok(pkt.ack()),
+            ControlMessage::SlowConsumer(pkt) => Ready::Ok(pkt.ack()),
+            ControlMessage::Event(pkt) => Ready::Ok(pkt.ack()),
+            ControlMessage::ProtocolError(pkt) => Ready::Ok(pkt.ack()),
             _ => {
                 log::warn!("MQTT Control service is not configured, pkt: {:?}", pkt);
                 Ready::Ok(pkt.disconnect_with(super::codec::Disconnect::new(
@@ -1,16 +1,21 @@
 use std::cell::{Cell, RefCell};
 use std::task::{Context, Poll};
-use std::{convert::TryFrom, future::Future, marker, num, pin::Pin, rc::Rc};
+use std::{convert::TryFrom, future::Future, marker, num, pin::Pin, rc::Rc, time::Duration};
 
+use ntex::rt::time::delay_for;
 use ntex::service::{fn_factory_with_config, Service, ServiceFactory};
-use ntex::util::{join, Either, HashSet, Ready};
+use ntex::util::{join, ByteString, Either, HashMap, HashSet, Ready};
 
 use crate::error::{MqttError, ProtocolError};
 use crate::io::DispatchItem;
+use crate::ratelimit::{PublishLimiter, PublishRate};
+use crate::trace::Traced;
+use crate::types::packet_type;
 
+use super::acl::{AclControl, AclPublish, AclRef};
 use super::control::{self, ControlMessage, ControlResult};
 use super::publish::{Publish, PublishAck};
-use super::shared::{Ack, MqttShared};
+use super::shared::{Ack, Capabilities, MqttShared};
 use super::sink::MqttSink;
 use super::{codec, Session};
 
@@ -18,6 +23,10 @@ use super::{codec, Session};
 pub(super) fn factory<St, T, C, E>(
     publish: T,
     control: C,
+    rate: PublishRate,
+    acl: Option<AclRef<St>>,
+    stream_threshold: u32,
+    publish_release: bool,
 ) -> impl ServiceFactory<
     Config = Session<St>,
     Request = DispatchItem<Rc<MqttShared>>,
@@ -41,23 +50,33 @@ where
             Error = E,
             InitError = MqttError<E>,
         > + 'static,
+    C::Service: 'static,
     PublishAck: TryFrom<T::Error, Error = E>,
 {
     fn_factory_with_config(move |cfg: Session<St>| {
+        cfg.sink().shared().codec.set_stream_threshold(stream_threshold);
+
         // create services
         let fut = join(publish.new_service(cfg.clone()), control.new_service(cfg.clone()));
 
         let (max_receive, max_topic_alias) = cfg.params();
+        let acl = acl.clone();
+        let span = cfg.sink().shared().span.borrow().clone();
 
         async move {
             let (publish, control) = fut.await;
 
-            Ok(Dispatcher::<_, _, E, T::Error>::new(
-                cfg.sink().clone(),
-                max_receive as usize,
-                max_topic_alias,
-                publish?,
-                control?,
+            Ok(Traced::new(
+                Dispatcher::<_, _, E, T::Error>::new(
+                    cfg.sink().clone(),
+                    max_receive as usize,
+                    max_topic_alias,
+                    AclPublish::new(publish?, acl.clone(), cfg.clone()),
+                    AclControl::new(control?, acl, cfg),
+                    rate.limiter(),
+                    publish_release,
+                ),
+                span,
             ))
         }
     })
@@ -70,26 +89,46 @@ pub(crate) struct Dispatcher<T, C, E, E2> {
     shutdown: Cell<bool>,
     max_receive: usize,
     max_topic_alias: u16,
+    caps: Capabilities,
+    limiter: Option<PublishLimiter>,
+    publish_release: bool,
+    // in-flight application event
+    event: RefCell<Option<EventResponse>>,
     inner: Rc<Inner<C>>,
     _t: marker::PhantomData<(E, E2)>,
 }
 
+type EventResponse = Pin<Box<dyn Future<Output = ControlResult>>>;
+
 struct Inner<C> {
     control: C,
     sink: MqttSink,
     info: RefCell<PublishInfo>,
 }
 
+impl<C, E> Inner<C>
+where
+    C: Service<Request = ControlMessage<E>, Response = ControlResult, Error = E>,
+{
+    async fn session_expired(&self) {
+        log::trace!("Session is expired");
+        let _ = self.control.call(ControlMessage::session_expired()).await;
+    }
+}
+
 struct PublishInfo {
     inflight: HashSet<num::NonZeroU16>,
-    aliases: HashSet<num::NonZeroU16>,
+    // qos2 publishes acknowledged with PUBREC, awaiting PUBREL
+    released: HashMap<num::NonZeroU16, Option<codec::Publish>>,
+    aliases: HashMap<num::NonZeroU16, ByteString>,
 }
 
 impl<T, C, E, E2> Dispatcher<T, C, E, E2>
 where
     T: Service<Request = Publish, Response = PublishAck, Error = E2>,
     PublishAck: TryFrom<E2, Error = E>,
-    C: Service<Request = ControlMessage<E>, Response = ControlResult, Error = E>,
+    C: Service<Request = ControlMessage<E>, Response = ControlResult, Error = E> + 'static,
+    E: 'static,
 {
     fn new(
         sink: MqttSink,
@@ -97,31 +136,127 @@ where
         max_topic_alias: u16,
         publish: T,
         control: C,
+        limiter: Option<PublishLimiter>,
+        publish_release: bool,
     ) -> Self {
+        // not released qos 2 publishes of restored session
+        let mut released = HashMap::default();
+        sink.shared().with_store(|store| {
+            released.extend(store.take_received().into_iter().map(|id| (id, None)))
+        });
+
+        let inner = Rc::new(Inner {
+            control,
+            sink: sink.clone(),
+            info: RefCell::new(PublishInfo {
+                aliases: HashMap::default(),
+                inflight: HashSet::default(),
+                released,
+            }),
+        });
+
+        // session takeover handler, connection get closed after control
+        // service handles `SessionTakenOver` message
+        let weak = Rc::downgrade(&inner);
+        *sink.shared().takeover.borrow_mut() = Some(Box::new(move || {
+            let fut: Pin<Box<dyn Future<Output = ()>>> = Box::pin(async move {
+                if let Some(inner) = weak.upgrade() {
+                    let pkt =
+                        match inner.control.call(ControlMessage::session_taken_over()).await {
+                            Ok(result) => result.packet,
+                            Err(_) => None,
+                        };
+                    inner.sink.send(pkt.unwrap_or_else(|| {
+                        codec::Packet::Disconnect(codec::Disconnect::new(
+                            codec::DisconnectReasonCode::SessionTakenOver,
+                        ))
+                    }));
+                    inner.sink.drop_sink();
+                }
+            });
+            fut
+        }));
+
+        // application events are handled by dispatcher
+        sink.shared().events.open();
+
         Self {
             publish,
             max_receive,
             max_topic_alias,
-            sink: sink.clone(),
+            caps: sink.shared().caps.get(),
+            sink,
+            limiter,
+            publish_release,
+            event: RefCell::new(None),
             shutdown: Cell::new(false),
-            inner: Rc::new(Inner {
-                control,
-                sink,
-                info: RefCell::new(PublishInfo {
-                    aliases: HashSet::default(),
-                    inflight: HashSet::default(),
-                }),
-            }),
+            inner,
             _t: marker::PhantomData,
         }
     }
+
+    /// Handle queued application events
+    ///
+    /// Events are handled one at a time, next event is passed to control
+    /// service when it is ready. Returns true if control service is called.
+    fn poll_event(&self, cx: &mut Context<'_>, ready: bool) -> bool
+    where
+        C::Future: 'static,
+    {
+        let mut event = self.event.borrow_mut();
+        if let Some(fut) = event.as_mut() {
+            match fut.as_mut().poll(cx) {
+                Poll::Ready(result) => {
+                    *event = None;
+                    self.event_result(result);
+                }
+                Poll::Pending => return false,
+            }
+        }
+
+        if !ready {
+            return false;
+        }
+        if let Some(ev) = self.sink.shared().events.poll_next(cx) {
+            let inner = self.inner.clone();
+            let mut fut: EventResponse = Box::pin(async move {
+                match inner.control.call(ControlMessage::event(ev)).await {
+                    Ok(result) => result,
+                    Err(err) => inner
+                        .control
+                        .call(ControlMessage::error(err))
+                        .await
+                        .unwrap_or(ControlResult { packet: None, disconnect: true }),
+                }
+            });
+            if let Poll::Ready(result) = fut.as_mut().poll(cx) {
+                self.event_result(result);
+                // handle next queued event
+                cx.waker().wake_by_ref();
+            } else {
+                *event = Some(fut);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    fn event_result(&self, result: ControlResult) {
+        if let Some(pkt) = result.packet {
+            self.sink.send(pkt);
+        }
+        if result.disconnect {
+            self.sink.drop_sink();
+        }
+    }
 }
 
 impl<T, C, E, E2> Service for Dispatcher<T, C, E, E2>
 where
     T: Service<Request = Publish, Response = PublishAck, Error = E2>,
     PublishAck: TryFrom<E2, Error = E>,
-    C: Service<Request = ControlMessage<E>, Response = ControlResult, Error = E>,
+    C: Service<Request = ControlMessage<E>, Response = ControlResult, Error = E> + 'static,
     C::Future: 'static,
     E: From<E2> + 'static,
 {
@@ -135,9 +270,34 @@ where
 
     fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         let res1 = self.publish.poll_ready(cx).map_err(|e| MqttError::Service(e.into()))?;
-        let res2 = self.inner.control.poll_ready(cx).map_err(MqttError::Service)?;
+        let mut res2 = self.inner.control.poll_ready(cx).map_err(MqttError::Service)?;
+
+        // application events, control service readiness is checked again
+        // after event is passed to it
+        if self.poll_event(cx, res2.is_ready()) {
+            res2 = self.inner.control.poll_ready(cx).map_err(MqttError::Service)?;
+        }
 
-        if res1.is_pending() || res2.is_pending() {
+        // inbound publish rate limit
+        let res3 = self.limiter.as_ref().map_or(Poll::Ready(()), |l| l.poll_ready(cx));
+
+        // paused by application
+        let shared = self.sink.shared();
+        let res4 = shared.read_pause.poll_ready(&shared.state, cx);
+
+        // payload stream of streamed publish is not consumed
+        let res5 = if shared.state.is_open() {
+            shared.codec.poll_payload_ready(cx)
+        } else {
+            Poll::Ready(())
+        };
+
+        if res1.is_pending()
+            || res2.is_pending()
+            || res3.is_pending()
+            || res4.is_pending()
+            || res5.is_pending()
+        {
             Poll::Pending
         } else {
             Poll::Ready(Ok(()))
@@ -146,11 +306,67 @@ where
 
     fn poll_shutdown(&self, _: &mut Context<'_>, is_error: bool) -> Poll<()> {
         if !self.shutdown.get() {
+            let closed = ControlMessage::closed(is_error, &self.sink.shared().close);
             self.inner.sink.drop_sink();
             self.shutdown.set(true);
-            let fut = self.inner.control.call(ControlMessage::closed(is_error));
+
+            // remove connection from session registry
+            let shared = self.sink.shared();
+            shared.takeover.borrow_mut().take();
+            shared.events.close();
+            self.event.borrow_mut().take();
+            shared.released.borrow_mut().take();
+            shared.connection.borrow_mut().take();
+            shared.pool.metrics.connection_closed();
+            let registry = shared.registry.borrow_mut().take();
+            let registered = registry.as_ref().map_or(true, |(r, id)| r.unregister(id, shared));
+
+            let will = shared
+                .last_will
+                .borrow_mut()
+                .take()
+                .map(|will| self.inner.control.call(ControlMessage::will_requested(will)));
+            let fut = self.inner.control.call(closed);
+            let expiry = self.sink.shared().session_expiry.get();
+
+            // session does not expire if expiry interval is u32::MAX,
+            // taken over session is not expired
+            let delay = Duration::from_secs(expiry as u64);
+            let expired = if expiry == u32::MAX || !registered {
+                None
+            } else if let (Some((registry, client_id)), true) = (registry, expiry != 0) {
+                // registry keeps expiry handler until session expires or client reconnects
+                let inner = self.inner.clone();
+                registry.expire(
+                    client_id,
+                    delay,
+                    Box::new(move || {
+                        Box::pin(async move {
+                            inner.sink.shared().with_store(|store| store.expired());
+                            inner.session_expired().await
+                        })
+                    }),
+                );
+                None
+            } else {
+                // stored state of session with zero expiry interval is discarded
+                if expiry == 0 {
+                    shared.with_store(|store| store.expired());
+                }
+                Some(self.inner.clone())
+            };
+
             ntex::rt::spawn(async move {
+                if let Some(will) = will {
+                    let _ = will.await;
+                }
                 let _ = fut.await;
+                if let Some(inner) = expired {
+                    if expiry != 0 {
+                        delay_for(delay).await;
+                    }
+                    inner.session_expired().await;
+                }
             });
         }
         Poll::Ready(())
@@ -160,20 +376,68 @@ where
         log::trace!("Dispatch packet: {:#?}", request);
 
         match request {
-            DispatchItem::Item(codec::Packet::Publish(publish)) => {
+            DispatchItem::Item(codec::Packet::Publish(mut publish)) => {
                 let info = self.inner.clone();
                 let packet_id = publish.packet_id;
+                let qos = publish.qos;
+                let stream = self.sink.shared().codec.take_payload();
+
+                // retain flag is not allowed if retain is not available
+                if publish.retain && !self.caps.retain {
+                    log::trace!("Retain is not supported");
+                    return Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(ProtocolError::RetainNotSupported),
+                        &self.inner,
+                    )));
+                }
+
+                // subscription identifiers are set by server only (MQTT-3.3.4-6)
+                if publish.properties.subscription_ids.is_some() {
+                    log::trace!("Subscription identifier in publish from client");
+                    return Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(ProtocolError::Unexpected(
+                            packet_type::PUBLISH_START,
+                            "Subscription identifier in client publish",
+                        )),
+                        &self.inner,
+                    )));
+                }
+
+                // check inbound publish rate
+                if let Some(ref limiter) = self.limiter {
+                    let size =
+                        stream.as_ref().map(|s| s.size()).unwrap_or(publish.payload.len());
+                    if !limiter.publish(size) {
+                        log::trace!("Inbound publish rate is exceeded");
+                        return Either::Right(Either::Right(ControlResponse::new(
+                            ControlMessage::proto_error(ProtocolError::PublishRateExceeded),
+                            &self.inner,
+                        )));
+                    }
+                }
 
                 {
                     let mut inner = info.info.borrow_mut();
 
                     if let Some(pid) = packet_id {
-                        // check for receive maximum
-                        if self.max_receive != 0 && inner.inflight.len() >= self.max_receive {
+                        // re-delivery of qos2 publish, message is already delivered
+                        if qos == codec::QoS::ExactlyOnce && inner.released.contains_key(&pid) {
+                            log::trace!("Re-delivered publish packet with qos2: {:?}", pid);
+                            return Either::Right(Either::Left(Ready::Ok(Some(
+                                codec::Packet::PublishReceived(codec::PublishAck {
+                                    packet_id: pid,
+                                    ..Default::default()
+                                }),
+                            ))));
+                        }
+
+                        // check for receive maximum, qos2 publishes are counted until PUBCOMP
+                        let inflight = inner.inflight.len() + inner.released.len();
+                        if self.max_receive != 0 && inflight >= self.max_receive {
                             log::trace!(
                                 "Receive maximum exceeded: max: {} inflight: {}",
                                 self.max_receive,
-                                inner.inflight.len()
+                                inflight
                             );
                             return Either::Right(Either::Right(ControlResponse::new(
                                 ControlMessage::proto_error(
@@ -183,22 +447,43 @@ where
                             )));
                         }
 
-                        // check for duplicated packet id
+                        // check for duplicated packet id, qos2 publish is answered with PUBREC
                         if !inner.inflight.insert(pid) {
-                            self.sink.send(codec::Packet::PublishAck(codec::PublishAck {
+                            let ack = codec::PublishAck {
                                 packet_id: pid,
                                 reason_code: codec::PublishAckReason::PacketIdentifierInUse,
                                 ..Default::default()
-                            }));
+                            };
+                            self.sink.send(if qos == codec::QoS::ExactlyOnce {
+                                codec::Packet::PublishReceived(ack)
+                            } else {
+                                codec::Packet::PublishAck(ack)
+                            });
                             return Either::Right(Either::Left(Ready::Ok(None)));
                         }
                     }
 
                     // handle topic aliases
                     if let Some(alias) = publish.properties.topic_alias {
+                        // alias must not exceed advertised topic alias maximum
+                        if alias.get() > self.max_topic_alias {
+                            log::trace!(
+                                "Topic alias is greater than max topic alias: {} > {}",
+                                alias,
+                                self.max_topic_alias
+                            );
+                            return Either::Right(Either::Right(ControlResponse::new(
+                                ControlMessage::proto_error(ProtocolError::MaxTopicAlias),
+                                &self.inner,
+                            )));
+                        }
+
                         // check existing topic
                         if publish.topic.is_empty() {
-                            if !inner.aliases.contains(&alias) {
+                            if let Some(topic) = inner.aliases.get(&alias) {
+                                publish.topic = topic.clone();
+                            } else {
+                                log::trace!("Unknown topic alias: {}", alias);
                                 return Either::Right(Either::Right(ControlResponse::new(
                                     ControlMessage::proto_error(
                                         ProtocolError::UnknownTopicAlias,
@@ -207,24 +492,41 @@ where
                                 )));
                             }
                         } else {
-                            if alias.get() > self.max_topic_alias {
-                                return Either::Right(Either::Right(ControlResponse::new(
-                                    ControlMessage::proto_error(ProtocolError::MaxTopicAlias),
-                                    &self.inner,
-                                )));
-                            }
-
                             // record new alias
-                            inner.aliases.insert(alias);
+                            inner.aliases.insert(alias, publish.topic.clone());
                         }
                     }
                 }
 
+                // original publish is delivered to control service with PUBREL
+                let release = if qos == codec::QoS::ExactlyOnce && self.publish_release {
+                    Some(publish.clone())
+                } else {
+                    None
+                };
+
                 Either::Left(PublishResponse {
+                    qos,
+                    release,
                     packet_id: packet_id.map(|v| v.get()).unwrap_or(0),
                     inner: info,
                     state: PublishResponseState::Publish {
-                        fut: self.publish.call(Publish::new(publish)),
+                        fut: if stream.is_some() {
+                            // streamed payload is not delivered to pending requests
+                            let mut publish = Publish::new(publish);
+                            publish.set_payload_stream(stream);
+                            Either::Left(self.publish.call(publish))
+                        } else {
+                            match self.sink.pkt_response(publish) {
+                                // response for pending request
+                                Ok(()) => Either::Right(Ready::Ok(PublishAck::new(
+                                    codec::PublishAckReason::Success,
+                                ))),
+                                Err(publish) => {
+                                    Either::Left(self.publish.call(Publish::new(publish)))
+                                }
+                            }
+                        },
                     },
                     _t: marker::PhantomData,
                 })
@@ -239,16 +541,102 @@ where
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
+            DispatchItem::Item(codec::Packet::PublishReceived(packet)) => {
+                if let Err(err) = self.sink.pkt_ack(Ack::Receive(packet)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(err),
+                        &self.inner,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishComplete(packet)) => {
+                if let Err(err) = self.sink.pkt_ack(Ack::Complete(packet)) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(err),
+                        &self.inner,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishRelease(ack)) => {
+                let released = self.inner.info.borrow_mut().released.remove(&ack.packet_id);
+                if let Some(publish) = released {
+                    self.sink.shared().with_store(|store| store.release(ack.packet_id));
+                    if self.publish_release {
+                        Either::Right(Either::Right(ControlResponse::new(
+                            ControlMessage::pkt_publish_release(ack, publish),
+                            &self.inner,
+                        )))
+                    } else {
+                        Either::Right(Either::Left(Ready::Ok(Some(
+                            codec::Packet::PublishComplete(codec::PublishAck2 {
+                                reason_code: codec::PublishAck2Reason::Success,
+                                packet_id: ack.packet_id,
+                                properties: codec::UserProperties::default(),
+                                reason_string: None,
+                            }),
+                        ))))
+                    }
+                } else {
+                    // unknown packet id, publish is already released
+                    log::trace!("Unknown packet id for publish release: {:?}", ack.packet_id);
+                    Either::Right(Either::Left(Ready::Ok(Some(
+                        codec::Packet::PublishComplete(codec::PublishAck2 {
+                            reason_code: codec::PublishAck2Reason::PacketIdNotFound,
+                            packet_id: ack.packet_id,
+                            properties: codec::UserProperties::default(),
+                            reason_string: None,
+                        }),
+                    ))))
+                }
+            }
             DispatchItem::Item(codec::Packet::Auth(pkt)) => Either::Right(Either::Right(
                 ControlResponse::new(ControlMessage::auth(pkt), &self.inner),
             )),
             DispatchItem::Item(codec::Packet::PingRequest) => Either::Right(Either::Right(
                 ControlResponse::new(ControlMessage::ping(), &self.inner),
             )),
-            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => Either::Right(Either::Right(
-                ControlResponse::new(ControlMessage::dis(pkt), &self.inner),
-            )),
+            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => {
+                if let Some(secs) = pkt.session_expiry_interval_secs {
+                    let expiry = &self.sink.shared().session_expiry;
+                    // session expiry interval set to 0 in CONNECT cannot be changed
+                    if expiry.get() == 0 && secs != 0 {
+                        log::trace!("Session expiry interval is set in DISCONNECT: {}", secs);
+                        return Either::Right(Either::Right(ControlResponse::new(
+                            ControlMessage::proto_error(ProtocolError::Unexpected(
+                                packet_type::DISCONNECT,
+                                "Session expiry interval is 0 in CONNECT",
+                            )),
+                            &self.inner,
+                        )));
+                    }
+                    expiry.set(secs);
+                }
+                self.sink.shared().close.peer();
+                // will message is discarded on normal disconnect
+                if pkt.reason_code == codec::DisconnectReasonCode::NormalDisconnection {
+                    self.sink.shared().last_will.borrow_mut().take();
+                }
+                Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::dis(pkt),
+                    &self.inner,
+                )))
+            }
             DispatchItem::Item(codec::Packet::Subscribe(pkt)) => {
+                // subscription identifier is not allowed if it is not available
+                if pkt.id.is_some() && !self.caps.subscription_ids {
+                    log::trace!("Subscription identifiers are not supported");
+                    return Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(
+                            ProtocolError::SubscriptionIdentifiersNotSupported,
+                        ),
+                        &self.inner,
+                    )));
+                }
+
                 // register inflight packet id
                 if !self.inner.info.borrow_mut().inflight.insert(pkt.packet_id) {
                     // duplicated packet id
@@ -265,9 +653,19 @@ where
                     return Either::Right(Either::Left(Ready::Ok(None)));
                 }
                 let id = pkt.packet_id;
+                let caps = self.caps;
+                let filters = stored_filters(&self.inner, || {
+                    StoredFilters::Subscribe(pkt.topic_filters.clone(), pkt.id)
+                });
                 Either::Right(Either::Right(
-                    ControlResponse::new(control::Subscribe::create(pkt), &self.inner)
-                        .packet_id(id),
+                    ControlResponse::new(
+                        control::Subscribe::create_checked(pkt, |topic| {
+                            caps.check_filter(topic)
+                        }),
+                        &self.inner,
+                    )
+                    .packet_id(id)
+                    .filters(filters),
                 ))
             }
             DispatchItem::Item(codec::Packet::Unsubscribe(pkt)) => {
@@ -287,9 +685,13 @@ where
                     return Either::Right(Either::Left(Ready::Ok(None)));
                 }
                 let id = pkt.packet_id;
+                let filters = stored_filters(&self.inner, || {
+                    StoredFilters::Unsubscribe(pkt.topic_filters.clone())
+                });
                 Either::Right(Either::Right(
                     ControlResponse::new(control::Unsubscribe::create(pkt), &self.inner)
-                        .packet_id(id),
+                        .packet_id(id)
+                        .filters(filters),
                 ))
             }
             DispatchItem::Item(_) => Either::Right(Either::Left(Ready::Ok(None))),
@@ -299,34 +701,56 @@ where
                     &self.inner,
                 )))
             }
-            DispatchItem::KeepAliveTimeout => {
+            DispatchItem::KeepAliveTimeout => Either::Right(Either::Right(
+                ControlResponse::new(ControlMessage::keepalive_timeout(), &self.inner)
+                    .keepalive(),
+            )),
+            DispatchItem::DecoderError(err) => {
                 Either::Right(Either::Right(ControlResponse::new(
-                    ControlMessage::proto_error(ProtocolError::KeepAliveTimeout),
+                    ControlMessage::proto_error(ProtocolError::Decode(err)),
                     &self.inner,
                 )))
             }
-            DispatchItem::DecoderError(err) => {
+            DispatchItem::IoError(err) => {
+                let err = self.sink.shared().close.io_error(err);
                 Either::Right(Either::Right(ControlResponse::new(
-                    ControlMessage::proto_error(ProtocolError::Decode(err)),
+                    ControlMessage::proto_error(ProtocolError::Io(err)),
                     &self.inner,
                 )))
             }
-            DispatchItem::IoError(err) => Either::Right(Either::Right(ControlResponse::new(
-                ControlMessage::proto_error(ProtocolError::Io(err)),
-                &self.inner,
-            ))),
-            DispatchItem::WBackPressureEnabled | DispatchItem::WBackPressureDisabled => {
-                Either::Right(Either::Left(Ready::Ok(None)))
-            }
+            DispatchItem::WBackPressureEnabled => Either::Right(Either::Right(
+                ControlResponse::new(ControlMessage::slow_consumer(), &self.inner),
+            )),
+            DispatchItem::WBackPressureDisabled => Either::Right(Either::Left(Ready::Ok(None))),
         }
     }
 }
 
+/// Topic filters of subscribe and unsubscribe packets
+enum StoredFilters {
+    Subscribe(Vec<(ByteString, codec::SubscriptionOptions)>, Option<num::NonZeroU32>),
+    Unsubscribe(Vec<ByteString>),
+}
+
+/// Topic filters are collected only if session store is set
+fn stored_filters<C, F>(inner: &Inner<C>, f: F) -> Option<StoredFilters>
+where
+    F: FnOnce() -> StoredFilters,
+{
+    if inner.sink.shared().store.borrow().is_some() {
+        Some(f())
+    } else {
+        None
+    }
+}
+
 pin_project_lite::pin_project! {
     /// Publish service response future
     pub(crate) struct PublishResponse<T: Service, C: Service, E, E2> {
         #[pin]
         state: PublishResponseState<T, C, E>,
+        qos: codec::QoS,
+        release: Option<codec::Publish>,
         packet_id: u16,
         inner: Rc<Inner<C>>,
         _t: marker::PhantomData<(E, E2)>,
@@ -336,7 +760,7 @@ pin_project_lite::pin_project! {
 pin_project_lite::pin_project! {
     #[project = PublishResponseStateProject]
     enum PublishResponseState<T: Service, C: Service, E> {
-        Publish { #[pin] fut: T::Future },
+        Publish { #[pin] fut: Either<T::Future, Ready<T::Response, T::Error>> },
         Control { #[pin] fut: ControlResponse<C, E> },
     }
 }
@@ -384,14 +808,28 @@ where
                     Poll::Pending => return Poll::Pending,
                 };
                 if let Some(id) = num::NonZeroU16::new(*this.packet_id) {
-                    this.inner.info.borrow_mut().inflight.remove(&id);
                     let ack = codec::PublishAck {
                         packet_id: id,
                         reason_code: ack.reason_code,
                         reason_string: ack.reason_string,
                         properties: ack.properties,
                     };
-                    Poll::Ready(Ok(Some(codec::Packet::PublishAck(ack))))
+                    let mut info = this.inner.info.borrow_mut();
+                    info.inflight.remove(&id);
+
+                    if *this.qos == codec::QoS::ExactlyOnce {
+                        // failure reason code completes qos2 flow, PUBREL is not expected
+                        if u8::from(ack.reason_code) < 0x80 {
+                            info.released.insert(id, this.release.take());
+                            this.inner
+                                .sink
+                                .shared()
+                                .with_store(|store| store.store_received(id));
+                        }
+                        Poll::Ready(Ok(Some(codec::Packet::PublishReceived(ack))))
+                    } else {
+                        Poll::Ready(Ok(Some(codec::Packet::PublishAck(ack))))
+                    }
                 } else {
                     Poll::Ready(Ok(None))
                 }
@@ -409,7 +847,9 @@ pin_project_lite::pin_project! {
         fut: C::Future,
         inner: Rc<Inner<C>>,
         error: bool,
+        keepalive: bool,
         packet_id: u16,
+        filters: Option<StoredFilters>,
         _t: marker::PhantomData<E>,
     }
 }
@@ -429,7 +869,9 @@ where
             error,
             fut: inner.control.call(pkt),
             inner: inner.clone(),
+            keepalive: false,
             packet_id: 0,
+            filters: None,
             _t: marker::PhantomData,
         }
     }
@@ -438,6 +880,43 @@ where
         self.packet_id = id.get();
         self
     }
+
+    /// Persist accepted subscriptions in session store
+    fn filters(mut self, filters: Option<StoredFilters>) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    /// Response for keep-alive timeout message
+    fn keepalive(mut self) -> Self {
+        self.keepalive = true;
+        self
+    }
+}
+
+/// Update session store with acknowledged subscriptions
+fn store_filters(sink: &MqttSink, filters: StoredFilters, pkt: &codec::Packet) {
+    sink.shared().with_store(|store| match (filters, pkt) {
+        (StoredFilters::Subscribe(filters, id), codec::Packet::SubscribeAck(ack)) => {
+            for ((filter, mut opts), code) in filters.into_iter().zip(ack.status.iter()) {
+                opts.qos = match code {
+                    codec::SubscribeAckReason::GrantedQos0 => codec::QoS::AtMostOnce,
+                    codec::SubscribeAckReason::GrantedQos1 => codec::QoS::AtLeastOnce,
+                    codec::SubscribeAckReason::GrantedQos2 => codec::QoS::ExactlyOnce,
+                    _ => continue,
+                };
+                store.subscribe(&filter, &opts, id);
+            }
+        }
+        (StoredFilters::Unsubscribe(filters), codec::Packet::UnsubscribeAck(ack)) => {
+            for (filter, code) in filters.iter().zip(ack.status.iter()) {
+                if *code == codec::UnsubscribeAckReason::Success {
+                    store.unsubscribe(filter);
+                }
+            }
+        }
+        _ => (),
+    })
 }
 
 impl<C, E> Future for ControlResponse<C, E>
@@ -451,8 +930,11 @@ where
 
         let result = match this.fut.poll(cx) {
             Poll::Ready(Ok(result)) => {
-                if let Some(id) = num::NonZeroU16::new(self.packet_id) {
-                    self.inner.info.borrow_mut().inflight.remove(&id);
+                if let Some(id) = num::NonZeroU16::new(*this.packet_id) {
+                    this.inner.info.borrow_mut().inflight.remove(&id);
+                }
+                if let (Some(filters), Some(pkt)) = (this.filters.take(), &result.packet) {
+                    store_filters(&this.inner.sink, filters, pkt);
                 }
                 result
             }
@@ -471,6 +953,24 @@ where
             Poll::Pending => return Poll::Pending,
         };
 
+        if self.keepalive {
+            if !result.disconnect {
+                log::trace!("Keep-alive is extended");
+                return Poll::Ready(Ok(result.packet));
+            }
+
+            // keep-alive is not extended, handle as protocol error
+            let this = self.as_mut().project();
+            *this.keepalive = false;
+            *this.error = true;
+            let fut = this
+                .inner
+                .control
+                .call(ControlMessage::proto_error(ProtocolError::KeepAliveTimeout));
+            self.as_mut().project().fut.set(fut);
+            return self.poll(cx);
+        }
+
         if self.error {
             if let Some(pkt) = result.packet {
                 self.inner.sink.send(pkt)
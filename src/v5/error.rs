@@ -1,6 +1,8 @@
 use derive_more::{Display, From};
 use ntex::util::Either;
 
+use crate::topic::TopicError;
+
 pub use crate::error::*;
 pub use crate::v5::codec;
 
@@ -24,7 +26,26 @@ pub enum ClientError {
     Connect(ntex::connect::ConnectError),
 }
 
-impl std::error::Error for ClientError {}
+impl ClientError {
+    /// Connection is refused because of failed authentication or authorization,
+    /// or peer violated authentication exchange
+    pub fn is_auth(&self) -> bool {
+        match self {
+            ClientError::Ack(ack) => ack.reason_code.is_auth(),
+            ClientError::Protocol(err) => err.is_auth(),
+            _ => false,
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Protocol(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 impl From<Either<EncodeError, std::io::Error>> for ClientError {
     fn from(err: Either<EncodeError, std::io::Error>) -> Self {
@@ -48,4 +69,117 @@ pub enum PublishQos1Error {
     /// Peer disconnected
     #[display(fmt = "Peer disconnected")]
     Disconnected,
+    /// Peer did not acknowledge packet in time
+    #[display(fmt = "Acknowledgement timeout")]
+    AckTimeout,
+    /// Receive credit is not available in time
+    #[display(fmt = "Receive credit timeout")]
+    CreditTimeout,
+    /// Receive credit is not available, packet is not sent
+    #[display(fmt = "Receive credit is not available")]
+    NoCredit,
+    /// All packet ids are used by in-flight packets
+    #[display(fmt = "Packet ids are exhausted")]
+    PacketIdsExhausted,
+    /// Topic name is not valid
+    #[display(fmt = "Invalid topic: {}", _0)]
+    InvalidTopic(TopicError),
+    /// Subscription identifier is set in client publish
+    #[display(fmt = "Subscription identifier is not allowed in client publish")]
+    SubscriptionId,
+    /// Payload serialization error
+    #[display(fmt = "Payload serialization error: {}", _0)]
+    Serialize(String),
 }
+
+#[derive(Debug, Display, PartialEq)]
+pub enum PublishQos2Error {
+    /// Negative PUBREC from peer
+    #[display(fmt = "Negative ack: {:?}", _0)]
+    Fail(codec::PublishAck),
+    /// Encoder error
+    Encode(EncodeError),
+    /// Provided packet id is in use
+    #[display(fmt = "Provided packet id is in use")]
+    PacketIdInUse(u16),
+    /// Peer disconnected
+    #[display(fmt = "Peer disconnected")]
+    Disconnected,
+    /// Peer did not acknowledge packet in time
+    #[display(fmt = "Acknowledgement timeout")]
+    AckTimeout,
+    /// Receive credit is not available in time
+    #[display(fmt = "Receive credit timeout")]
+    CreditTimeout,
+    /// Receive credit is not available, packet is not sent
+    #[display(fmt = "Receive credit is not available")]
+    NoCredit,
+    /// All packet ids are used by in-flight packets
+    #[display(fmt = "Packet ids are exhausted")]
+    PacketIdsExhausted,
+    /// Topic name is not valid
+    #[display(fmt = "Invalid topic: {}", _0)]
+    InvalidTopic(TopicError),
+    /// Subscription identifier is set in client publish
+    #[display(fmt = "Subscription identifier is not allowed in client publish")]
+    SubscriptionId,
+    /// Payload serialization error
+    #[display(fmt = "Payload serialization error: {}", _0)]
+    Serialize(String),
+}
+
+impl From<SendPacketError> for PublishQos1Error {
+    fn from(err: SendPacketError) -> Self {
+        match err {
+            SendPacketError::Encode(err) => PublishQos1Error::Encode(err),
+            SendPacketError::PacketIdInUse(idx) => PublishQos1Error::PacketIdInUse(idx),
+            SendPacketError::AckTimeout => PublishQos1Error::AckTimeout,
+            SendPacketError::CreditTimeout => PublishQos1Error::CreditTimeout,
+            SendPacketError::NoCredit => PublishQos1Error::NoCredit,
+            SendPacketError::PacketIdsExhausted => PublishQos1Error::PacketIdsExhausted,
+            SendPacketError::InvalidTopic(err) => PublishQos1Error::InvalidTopic(err),
+            SendPacketError::SubscriptionId => PublishQos1Error::SubscriptionId,
+            SendPacketError::Serialize(err) => PublishQos1Error::Serialize(err),
+            SendPacketError::Disconnected | SendPacketError::AuthInProgress => {
+                PublishQos1Error::Disconnected
+            }
+        }
+    }
+}
+
+impl From<SendPacketError> for PublishQos2Error {
+    fn from(err: SendPacketError) -> Self {
+        match err {
+            SendPacketError::Encode(err) => PublishQos2Error::Encode(err),
+            SendPacketError::PacketIdInUse(idx) => PublishQos2Error::PacketIdInUse(idx),
+            SendPacketError::AckTimeout => PublishQos2Error::AckTimeout,
+            SendPacketError::CreditTimeout => PublishQos2Error::CreditTimeout,
+            SendPacketError::NoCredit => PublishQos2Error::NoCredit,
+            SendPacketError::PacketIdsExhausted => PublishQos2Error::PacketIdsExhausted,
+            SendPacketError::InvalidTopic(err) => PublishQos2Error::InvalidTopic(err),
+            SendPacketError::SubscriptionId => PublishQos2Error::SubscriptionId,
+            SendPacketError::Serialize(err) => PublishQos2Error::Serialize(err),
+            SendPacketError::Disconnected | SendPacketError::AuthInProgress => {
+                PublishQos2Error::Disconnected
+            }
+        }
+    }
+}
+
+#[derive(Debug, Display, From, PartialEq)]
+pub enum RequestError {
+    /// Subscription to response topic failed
+    #[display(fmt = "Subscription to response topic failed: {:?}", _0)]
+    Subscribe(codec::SubscribeAckReason),
+    /// Send subscribe packet error
+    #[display(fmt = "Send packet error: {}", _0)]
+    Send(SendPacketError),
+    /// Publish request error
+    #[display(fmt = "Publish error: {}", _0)]
+    Publish(PublishQos1Error),
+    /// Peer disconnected
+    #[display(fmt = "Peer disconnected")]
+    Disconnected,
+}
+
+impl std::error::Error for RequestError {}
@@ -0,0 +1,131 @@
+//! Thread-safe handle for mqtt sink
+use std::{future::Future, rc::Rc};
+
+use futures_channel::oneshot;
+use ntex::util::{ByteString, Bytes};
+
+use super::{codec, error::SendPacketError, shared::MqttShared, sink::MqttSink};
+use crate::handle::{Handle, HandleSink};
+use crate::io::State;
+
+type Tx<T> = oneshot::Sender<Result<T, SendPacketError>>;
+
+pub(crate) enum Request {
+    Publish(codec::Publish, Tx<codec::PublishAckReason>),
+    Subscribe(Vec<(ByteString, codec::SubscriptionOptions)>, Tx<codec::SubscribeAck>),
+    Unsubscribe(Vec<ByteString>, Tx<codec::UnsubscribeAck>),
+}
+
+/// Thread-safe handle for `MqttSink`
+///
+/// Handle is `Send` and `Sync`, requests are forwarded to the connection's
+/// thread and get executed by connection's sink. Handle does not keep connection
+/// alive, requests fail with `Disconnected` error after connection is closed.
+#[derive(Clone)]
+pub struct MqttSinkHandle(Handle<Request>);
+
+impl MqttSinkHandle {
+    /// Spawn forwarding task on current thread, must be called on connection's thread
+    pub(super) fn new(shared: &Rc<MqttShared>) -> Self {
+        MqttSinkHandle(Handle::new(shared))
+    }
+
+    /// Check if connection's thread accepts requests
+    pub fn is_closed(&self) -> bool {
+        self.0.is_closed()
+    }
+
+    /// Publish message, future resolves with ack reason from the peer
+    pub fn publish(
+        &self,
+        topic: ByteString,
+        payload: Bytes,
+        qos: codec::QoS,
+    ) -> impl Future<Output = Result<codec::PublishAckReason, SendPacketError>> + Send {
+        self.publish_with_properties(topic, payload, qos, codec::PublishProperties::default())
+    }
+
+    /// Publish message with publish properties
+    ///
+    /// Topic alias property is managed by the sink and is ignored.
+    pub fn publish_with_properties(
+        &self,
+        topic: ByteString,
+        payload: Bytes,
+        qos: codec::QoS,
+        properties: codec::PublishProperties,
+    ) -> impl Future<Output = Result<codec::PublishAckReason, SendPacketError>> + Send {
+        let pkt = codec::Publish {
+            topic,
+            payload,
+            qos,
+            properties,
+            dup: false,
+            retain: false,
+            packet_id: None,
+        };
+        let (tx, rx) = oneshot::channel();
+        self.0.request(Request::Publish(pkt, tx), rx)
+    }
+
+    /// Subscribe to topic filters, future resolves with SUBACK packet
+    pub fn subscribe(
+        &self,
+        filters: Vec<(ByteString, codec::SubscriptionOptions)>,
+    ) -> impl Future<Output = Result<codec::SubscribeAck, SendPacketError>> + Send {
+        let (tx, rx) = oneshot::channel();
+        self.0.request(Request::Subscribe(filters, tx), rx)
+    }
+
+    /// Unsubscribe from topic filters, future resolves with UNSUBACK packet
+    pub fn unsubscribe(
+        &self,
+        filters: Vec<ByteString>,
+    ) -> impl Future<Output = Result<codec::UnsubscribeAck, SendPacketError>> + Send {
+        let (tx, rx) = oneshot::channel();
+        self.0.request(Request::Unsubscribe(filters, tx), rx)
+    }
+
+    /// Close mqtt connection after queued requests
+    pub fn close(&self) {
+        self.0.close()
+    }
+}
+
+impl HandleSink for MqttShared {
+    type Request = Request;
+
+    fn io_state(&self) -> &State {
+        &self.state
+    }
+
+    fn execute(self: Rc<Self>, req: Request) {
+        let sink = MqttSink::new(self);
+
+        ntex::rt::spawn(async move {
+            match req {
+                Request::Publish(pkt, tx) => {
+                    let codec::Publish { topic, payload, qos, mut properties, .. } = pkt;
+                    properties.topic_alias = None;
+                    let builder = sink.publish(topic, payload).properties(|p| *p = properties);
+                    let _ = tx.send(builder.send_with_reason(qos).await);
+                }
+                Request::Subscribe(filters, tx) => {
+                    let builder = filters
+                        .into_iter()
+                        .fold(sink.subscribe(None), |b, (f, opts)| b.topic_filter(f, opts));
+                    let _ = tx.send(builder.send().await);
+                }
+                Request::Unsubscribe(filters, tx) => {
+                    let builder =
+                        filters.into_iter().fold(sink.unsubscribe(), |b, f| b.topic_filter(f));
+                    let _ = tx.send(builder.send().await);
+                }
+            }
+        });
+    }
+
+    fn close(self: Rc<Self>) {
+        MqttSink::new(self).close()
+    }
+}
@@ -1,6 +1,9 @@
-use std::{fmt, num::NonZeroU16, rc::Rc};
+use std::{fmt, net::SocketAddr, num::NonZeroU16, rc::Rc};
 
-use super::{codec, shared::MqttShared, sink::MqttSink};
+use ntex::codec::{AsyncRead, AsyncWrite};
+use ntex::util::{ByteString, Bytes};
+
+use super::{codec, shared::MqttShared, sink::MqttSink, store::SessionState};
 
 /// Handshake message
 pub struct Handshake<Io> {
@@ -10,6 +13,7 @@ pub struct Handshake<Io> {
     max_size: u32,
     max_receive: u16,
     max_topic_alias: u16,
+    session_state: Option<SessionState>,
 }
 
 impl<Io> Handshake<Io> {
@@ -20,8 +24,9 @@ impl<Io> Handshake<Io> {
         max_size: u32,
         max_receive: u16,
         max_topic_alias: u16,
+        session_state: Option<SessionState>,
     ) -> Self {
-        Self { pkt, io, shared, max_size, max_receive, max_topic_alias }
+        Self { pkt, io, shared, max_size, max_receive, max_topic_alias, session_state }
     }
 
     pub fn packet(&self) -> &codec::Connect {
@@ -32,6 +37,52 @@ impl<Io> Handshake<Io> {
         &mut self.pkt
     }
 
+    #[inline]
+    /// Session expiry interval in seconds requested by the client
+    pub fn session_expiry_interval(&self) -> u32 {
+        self.pkt.session_expiry_interval_secs.unwrap_or(0)
+    }
+
+    #[inline]
+    /// Returns stored session state, if server session store is set
+    /// and session of the client exists
+    pub fn session_state(&self) -> Option<&SessionState> {
+        self.session_state.as_ref()
+    }
+
+    #[inline]
+    /// Returns value of the first connect user property with provided key
+    pub fn get_user_property(&self, key: &str) -> Option<&ByteString> {
+        codec::get_user_property(&self.pkt.user_properties, key)
+    }
+
+    #[inline]
+    /// Returns peer address of the connection
+    ///
+    /// If PROXY protocol is enabled, original client address is returned.
+    /// Address is available for tcp and tls over tcp transports.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.shared.peer_addr.get()
+    }
+
+    #[inline]
+    /// Returns local address of the connection
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.shared.local_addr.get()
+    }
+
+    /// Returns DER encoded certificate chain of the client
+    ///
+    /// Certificate chain is available for tls over tcp connections accepted
+    /// with `openssl` or `rustls` acceptors, if client provided certificate.
+    /// End-entity certificate goes first. Requires `openssl` or `rustls` feature.
+    pub fn peer_certificates(&self) -> Option<Vec<Bytes>>
+    where
+        Io: 'static,
+    {
+        crate::utils::io_peer_certificates(&self.io)
+    }
+
     #[inline]
     pub fn io(&mut self) -> &mut Io {
         &mut self.io
@@ -82,6 +133,27 @@ impl<Io> Handshake<Io> {
         }
     }
 
+    /// Create handshake ack object that redirects client to another server
+    ///
+    /// Responds with `UseAnotherServer` reason code, or with `ServerMoved`
+    /// if `permanent` is true, and server reference property.
+    pub fn redirect<St>(
+        self,
+        server_reference: ByteString,
+        permanent: bool,
+    ) -> HandshakeAck<Io, St> {
+        let reason_code = if permanent {
+            codec::ConnectAckReason::ServerMoved
+        } else {
+            codec::ConnectAckReason::UseAnotherServer
+        };
+        self.fail_with(codec::ConnectAck {
+            reason_code,
+            server_reference: Some(server_reference),
+            ..codec::ConnectAck::default()
+        })
+    }
+
     /// Create handshake ack object with provided ConnectAck packet
     pub fn fail_with<St>(self, ack: codec::ConnectAck) -> HandshakeAck<Io, St> {
         HandshakeAck {
@@ -97,6 +169,42 @@ impl<Io> Handshake<Io> {
     }
 }
 
+impl<Io: AsyncRead + AsyncWrite + Unpin> Handshake<Io> {
+    /// Continue enhanced authentication
+    ///
+    /// Sends `AUTH` packet with `ContinueAuth` reason code and authentication
+    /// method of the connect packet, then waits for client response.
+    /// Returns `None` if client disconnects or responds with packet other than `AUTH`.
+    pub async fn auth_continue(&mut self, data: Option<Bytes>) -> Option<codec::Auth> {
+        let pkt = codec::Auth {
+            reason_code: codec::AuthReasonCode::ContinueAuth,
+            auth_method: self.pkt.auth_method.clone(),
+            auth_data: data,
+            ..codec::Auth::default()
+        };
+        let state = &self.shared.state;
+        if let Err(err) =
+            state.send(&mut self.io, &*self.shared, codec::Packet::Auth(pkt)).await
+        {
+            log::trace!("Failed to send auth packet: {:?}", err);
+            return None;
+        }
+
+        match state.next(&mut self.io, &*self.shared).await {
+            Ok(Some(codec::Packet::Auth(pkt))) => Some(pkt),
+            Ok(Some(pkt)) => {
+                log::trace!("Unexpected packet during enhanced authentication: {:?}", pkt);
+                None
+            }
+            Ok(None) => None,
+            Err(err) => {
+                log::trace!("Error during enhanced authentication: {:?}", err);
+                None
+            }
+        }
+    }
+}
+
 impl<T> fmt::Debug for Handshake<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         self.pkt.fmt(f)
@@ -167,6 +275,57 @@ impl<Io, St> HandshakeAck<Io, St> {
         self
     }
 
+    /// Set Retain Available flag
+    ///
+    /// If retain is not available, publish packets with retain flag are
+    /// rejected with `RetainNotSupported` disconnect reason code.
+    pub fn retain_available(mut self, val: bool) -> Self {
+        self.packet.retain_available = Some(val);
+        self
+    }
+
+    /// Set Wildcard Subscription Available flag
+    ///
+    /// If wildcard subscriptions are not available, subscriptions to topic filters
+    /// with wildcards are rejected with `WildcardSubscriptionsNotSupported` reason code.
+    pub fn wildcard_subscription_available(mut self, val: bool) -> Self {
+        self.packet.wildcard_subscription_available = Some(val);
+        self
+    }
+
+    /// Set Shared Subscription Available flag
+    ///
+    /// If shared subscriptions are not available, subscriptions to shared topic filters
+    /// are rejected with `SharedSubsriptionNotSupported` reason code.
+    pub fn shared_subscription_available(mut self, val: bool) -> Self {
+        self.packet.shared_subscription_available = Some(val);
+        self
+    }
+
+    /// Set Subscription Identifiers Available flag
+    ///
+    /// If subscription identifiers are not available, subscribe packets with
+    /// subscription identifier are rejected with `SubscriptionIdentifiersNotSupported`
+    /// disconnect reason code.
+    pub fn subscription_identifiers_available(mut self, val: bool) -> Self {
+        self.packet.subscription_identifiers_available = Some(val);
+        self
+    }
+
+    /// Set session expiry interval in seconds
+    ///
+    /// Overrides session expiry interval requested by the client.
+    pub fn session_expiry_interval(mut self, secs: u32) -> Self {
+        self.packet.session_expiry_interval_secs = Some(secs);
+        self
+    }
+
+    /// Add ConnectAck user property
+    pub fn user_property(mut self, key: ByteString, value: ByteString) -> Self {
+        self.packet.user_properties.push((key, value));
+        self
+    }
+
     /// Access to ConnectAck packet
     #[inline]
     pub fn with(mut self, f: impl FnOnce(&mut codec::ConnectAck)) -> Self {
@@ -1,26 +1,33 @@
 //! MQTT5 Client/Server framework
 
+mod acl;
 pub mod client;
 pub mod codec;
 pub mod control;
 mod default;
 mod dispatcher;
 pub mod error;
+mod handle;
 mod handshake;
 mod publish;
+mod registry;
 mod router;
 mod server;
 mod shared;
 mod sink;
+mod store;
 
 pub type Session<St> = crate::Session<MqttSink, St>;
 
 pub use self::control::{ControlMessage, ControlResult};
+pub use self::handle::MqttSinkHandle;
 pub use self::handshake::{Handshake, HandshakeAck};
-pub use self::publish::{Publish, PublishAck};
+pub use self::publish::{IntoReasonCode, Publish, PublishAck, ReasonError};
+pub use self::registry::SessionRegistry;
 pub use self::router::Router;
 pub use self::server::MqttServer;
-pub use self::sink::{MqttSink, PublishBuilder};
+pub use self::sink::{AckFuture, EventSender, MqttSink, PublishBuilder, RequestBuilder};
+pub use self::store::{MemorySessionStore, SessionState, SessionStore};
 
 pub use crate::topic::Topic;
 pub use crate::types::QoS;
@@ -1,21 +1,33 @@
-use std::num::NonZeroU16;
+use std::convert::TryFrom;
+use std::num::{NonZeroU16, NonZeroU32};
+use std::time::{Duration, Instant};
 
 use ntex::router::Path;
 use ntex::util::{ByteString, Bytes};
+#[cfg(any(feature = "json", feature = "cbor"))]
 use serde::de::DeserializeOwned;
+#[cfg(feature = "json")]
 use serde_json::Error as JsonError;
 
 use super::codec;
+use crate::{params::Params, payload::Payload};
 
 /// Publish message
 pub struct Publish {
     publish: codec::Publish,
     topic: Path<ByteString>,
+    received: Instant,
+    stream: Option<Payload>,
 }
 
 impl Publish {
     pub(crate) fn new(publish: codec::Publish) -> Self {
-        Self { topic: Path::new(publish.topic.clone()), publish }
+        Self {
+            topic: Path::new(publish.topic.clone()),
+            publish,
+            received: Instant::now(),
+            stream: None,
+        }
     }
 
     #[inline]
@@ -47,6 +59,78 @@ impl Publish {
         self.publish.packet_id
     }
 
+    #[inline]
+    /// Subscription identifiers of the subscriptions that matched this publish.
+    pub fn subscription_ids(&self) -> &[NonZeroU32] {
+        self.publish.properties.subscription_ids.as_deref().unwrap_or(&[])
+    }
+
+    #[inline]
+    /// Topic name for a response message.
+    pub fn response_topic(&self) -> Option<&ByteString> {
+        self.publish.properties.response_topic.as_ref()
+    }
+
+    #[inline]
+    /// Correlation data, used by the sender of the request message to identify
+    /// which request the response message is for.
+    pub fn correlation_data(&self) -> Option<&Bytes> {
+        self.publish.properties.correlation_data.as_ref()
+    }
+
+    #[inline]
+    /// Time when the packet has been received
+    pub fn received(&self) -> Instant {
+        self.received
+    }
+
+    #[inline]
+    /// Lifetime of the Application Message in seconds, as received from the peer.
+    pub fn message_expiry_interval(&self) -> Option<NonZeroU32> {
+        self.publish.properties.message_expiry_interval
+    }
+
+    /// Remaining lifetime of the Application Message.
+    ///
+    /// Returns `None` if message does not expire.
+    pub fn expires_in(&self) -> Option<Duration> {
+        self.message_expiry_interval().map(|interval| {
+            Duration::from_secs(interval.get() as u64)
+                .checked_sub(self.received.elapsed())
+                .unwrap_or_else(|| Duration::from_secs(0))
+        })
+    }
+
+    /// Check if Application Message is expired.
+    pub fn is_expired(&self) -> bool {
+        self.expires_in().map(|d| d == Duration::from_secs(0)).unwrap_or(false)
+    }
+
+    /// Create publish packet for forwarding the Application Message.
+    ///
+    /// Message expiry interval is decremented by the time message has been waiting
+    /// since it was received. Topic alias, packet id and dup flag are reset.
+    /// Returns `None` if message is expired.
+    pub fn forward(&self) -> Option<codec::Publish> {
+        let mut pkt = self.publish.clone();
+        pkt.dup = false;
+        pkt.packet_id = None;
+        pkt.properties.topic_alias = None;
+
+        if let Some(remaining) = self.expires_in() {
+            // round up partial seconds
+            let secs = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+            pkt.properties.message_expiry_interval = Some(NonZeroU32::new(secs as u32)?);
+        }
+        Some(pkt)
+    }
+
+    #[inline]
+    /// Returns value of the first user property with provided key
+    pub fn get_user_property(&self, key: &str) -> Option<&ByteString> {
+        codec::get_user_property(&self.publish.properties.user_properties, key)
+    }
+
     #[inline]
     pub fn topic(&self) -> &Path<ByteString> {
         &self.topic
@@ -57,6 +141,12 @@ impl Publish {
         &mut self.topic
     }
 
+    #[inline]
+    /// Typed access to parameters of matched router resource
+    pub fn params(&self) -> Params<'_> {
+        Params::new(&self.topic)
+    }
+
     #[inline]
     pub fn packet(&self) -> &codec::Publish {
         &self.publish
@@ -78,11 +168,38 @@ impl Publish {
         self.publish.payload.clone()
     }
 
+    #[inline]
+    /// Check if payload is delivered as a stream
+    pub fn is_streamed(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Take payload stream of large publish packet
+    ///
+    /// Payload of publish packets larger than server's stream threshold is
+    /// delivered via stream while packet is still being received, `payload()`
+    /// is empty for such packets. Received chunks are buffered until they get
+    /// consumed, dropping the stream discards rest of the payload.
+    pub fn take_payload_stream(&mut self) -> Option<Payload> {
+        self.stream.take()
+    }
+
+    pub(crate) fn set_payload_stream(&mut self, stream: Option<Payload>) {
+        self.stream = stream;
+    }
+
+    #[cfg(feature = "json")]
     /// Loads and parse `application/json` encoded body.
     pub fn json<T: DeserializeOwned>(&mut self) -> Result<T, JsonError> {
         serde_json::from_slice(&self.publish.payload)
     }
 
+    #[cfg(feature = "cbor")]
+    /// Loads and parse `application/cbor` encoded body.
+    pub fn cbor<T: DeserializeOwned>(&mut self) -> Result<T, serde_cbor::Error> {
+        serde_cbor::from_slice(&self.publish.payload)
+    }
+
     /// Create acknowledgement for this packet
     pub fn ack(self) -> PublishAck {
         PublishAck {
@@ -137,6 +254,13 @@ impl PublishAck {
         self
     }
 
+    /// Add user property
+    #[inline]
+    pub fn user_property(mut self, key: ByteString, value: ByteString) -> Self {
+        self.properties.push((key, value));
+        self
+    }
+
     /// Set ack reason string
     #[inline]
     pub fn reason(mut self, reason: ByteString) -> Self {
@@ -144,3 +268,192 @@ impl PublishAck {
         self
     }
 }
+
+/// Mapping of service errors to mqtt reason codes
+///
+/// Use `ReasonError` adapter as error type of publish and control services,
+/// failed publishes get acknowledged with `publish_reason()` code, or
+/// connection gets closed if it returns `None`. Control service can ack
+/// errors with `control::Error::ack_reason()`.
+pub trait IntoReasonCode {
+    /// PUBACK or PUBREC reason code for failed publish
+    ///
+    /// `None` means error is not recoverable, and it is passed to
+    /// control service.
+    fn publish_reason(&self) -> Option<codec::PublishAckReason> {
+        Some(codec::PublishAckReason::ImplementationSpecificError)
+    }
+
+    /// DISCONNECT reason code
+    fn disconnect_reason(&self) -> codec::DisconnectReasonCode {
+        codec::DisconnectReasonCode::ImplementationSpecificError
+    }
+
+    /// Reason string for PUBACK, PUBREC or DISCONNECT packet
+    fn reason_string(&self) -> Option<ByteString> {
+        None
+    }
+}
+
+/// Service error adapter for errors that implement `IntoReasonCode`
+#[derive(Debug)]
+pub struct ReasonError<E>(pub E);
+
+impl<E> ReasonError<E> {
+    /// Returns inner error
+    pub fn into_inner(self) -> E {
+        self.0
+    }
+}
+
+impl<E: From<()>> From<()> for ReasonError<E> {
+    fn from(_: ()) -> Self {
+        ReasonError(E::from(()))
+    }
+}
+
+impl<E: IntoReasonCode> IntoReasonCode for ReasonError<E> {
+    fn publish_reason(&self) -> Option<codec::PublishAckReason> {
+        self.0.publish_reason()
+    }
+
+    fn disconnect_reason(&self) -> codec::DisconnectReasonCode {
+        self.0.disconnect_reason()
+    }
+
+    fn reason_string(&self) -> Option<ByteString> {
+        self.0.reason_string()
+    }
+}
+
+impl<E: IntoReasonCode> TryFrom<ReasonError<E>> for PublishAck {
+    type Error = ReasonError<E>;
+
+    fn try_from(err: ReasonError<E>) -> Result<Self, Self::Error> {
+        if let Some(code) = err.publish_reason() {
+            let mut ack = PublishAck::new(code);
+            ack.reason_string = err.reason_string();
+            Ok(ack)
+        } else {
+            Err(err)
+        }
+    }
+}
+
+/// Implement `TryFrom<Error> for PublishAck` for application error type
+///
+/// Listed patterns get acknowledged with specified reason code, any other
+/// error is passed to control service and closes connection.
+///
+/// ```rust
+/// #[derive(Debug)]
+/// enum MyError {
+///     Quota,
+///     Denied(String),
+///     Storage,
+/// }
+///
+/// ntex_mqtt::publish_ack_error!(MyError {
+///     MyError::Quota => QuotaExceeded,
+///     MyError::Denied(_) => NotAuthorized,
+/// });
+///
+/// // all errors close connection
+/// #[derive(Debug)]
+/// struct FatalError;
+///
+/// ntex_mqtt::publish_ack_error!(FatalError);
+/// ```
+#[macro_export]
+macro_rules! publish_ack_error {
+    ($err:ty) => {
+        $crate::publish_ack_error!($err {});
+    };
+    ($err:ty { $($pat:pat => $code:ident),* $(,)? }) => {
+        impl ::std::convert::TryFrom<$err> for $crate::v5::PublishAck {
+            type Error = $err;
+
+            #[allow(unreachable_patterns, clippy::match_single_binding)]
+            fn try_from(err: $err) -> ::std::result::Result<Self, $err> {
+                match err {
+                    $($pat => ::std::result::Result::Ok($crate::v5::PublishAck::new(
+                        $crate::v5::codec::PublishAckReason::$code,
+                    )),)*
+                    err => ::std::result::Result::Err(err),
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn publish(interval: u32, elapsed: u64) -> Publish {
+        let mut publish = Publish::new(codec::Publish {
+            dup: true,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            topic: ByteString::from("test"),
+            packet_id: NonZeroU16::new(1),
+            payload: Bytes::new(),
+            properties: codec::PublishProperties {
+                topic_alias: NonZeroU16::new(1),
+                message_expiry_interval: NonZeroU32::new(interval),
+                ..Default::default()
+            },
+        });
+        publish.received -= Duration::from_secs(elapsed);
+        publish
+    }
+
+    #[test]
+    fn test_message_expiry() {
+        let p = publish(0, 0);
+        assert_eq!(p.expires_in(), None);
+        assert!(!p.is_expired());
+        let pkt = p.forward().unwrap();
+        assert_eq!(pkt.properties.message_expiry_interval, None);
+        assert_eq!(pkt.properties.topic_alias, None);
+        assert_eq!(pkt.packet_id, None);
+        assert!(!pkt.dup);
+
+        let p = publish(10, 4);
+        assert!(p.expires_in().unwrap() <= Duration::from_secs(6));
+        assert!(!p.is_expired());
+        let pkt = p.forward().unwrap();
+        assert_eq!(pkt.properties.message_expiry_interval, NonZeroU32::new(6));
+
+        let p = publish(10, 10);
+        assert_eq!(p.expires_in(), Some(Duration::from_secs(0)));
+        assert!(p.is_expired());
+        assert!(p.forward().is_none());
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum AppError {
+        Quota,
+        Denied(&'static str),
+        Storage,
+    }
+
+    #[derive(Debug)]
+    struct FatalError;
+
+    crate::publish_ack_error!(AppError {
+        AppError::Quota => QuotaExceeded,
+        AppError::Denied(_) => NotAuthorized,
+    });
+    crate::publish_ack_error!(FatalError);
+
+    #[test]
+    fn test_publish_ack_error() {
+        let ack = PublishAck::try_from(AppError::Quota).unwrap();
+        assert_eq!(ack.reason_code, codec::PublishAckReason::QuotaExceeded);
+        let ack = PublishAck::try_from(AppError::Denied("test")).unwrap();
+        assert_eq!(ack.reason_code, codec::PublishAckReason::NotAuthorized);
+        assert_eq!(PublishAck::try_from(AppError::Storage).unwrap_err(), AppError::Storage);
+        assert!(PublishAck::try_from(FatalError).is_err());
+    }
+}
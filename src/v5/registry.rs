@@ -0,0 +1,160 @@
+use std::{cell::Cell, cell::RefCell, future::Future, pin::Pin, rc::Rc, time::Duration};
+
+use ntex::rt::{task::JoinHandle, time::delay_for};
+use ntex::util::{join_all, select, ByteString, HashMap};
+
+use super::{codec, shared::MqttShared, sink::MqttSink};
+use crate::drain::Drain;
+
+/// Registry of connected clients
+///
+/// Registry tracks active connection per client id. If client connects
+/// with client id that is already in use, old connection receives
+/// `ControlMessage::SessionTakenOver` message and get disconnected with
+/// `SessionTakenOver` reason code before new connection is acknowledged.
+///
+/// Registry also keeps session expiry timers of disconnected clients,
+/// timer is cancelled if client connects again before session expires.
+///
+/// Registry is local to the server worker, use `Drain` handle to drain
+/// registries of all workers.
+#[derive(Clone, Default)]
+pub struct SessionRegistry(Rc<Inner>);
+
+pub(super) type Expiry = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()>>>>;
+
+#[derive(Default)]
+struct Inner {
+    sessions: RefCell<HashMap<ByteString, Rc<MqttShared>>>,
+    expiring: RefCell<HashMap<ByteString, (Expiry, JoinHandle<()>)>>,
+    draining: Cell<bool>,
+    drain: Option<Drain>,
+}
+
+impl SessionRegistry {
+    /// Create empty registry
+    pub fn new() -> Self {
+        SessionRegistry::default()
+    }
+
+    /// Create empty registry that is drained by server drain handle
+    ///
+    /// Registry must be created on worker's thread, for example in server
+    /// factory.
+    pub fn with_drain(drain: &Drain) -> Self {
+        let inner = Rc::new(Inner { drain: Some(drain.clone()), ..Default::default() });
+
+        let registry = Rc::downgrade(&inner);
+        drain.register(move |timeout| {
+            registry.upgrade().map(|inner| SessionRegistry(inner).drain(timeout))
+        });
+        SessionRegistry(inner)
+    }
+
+    /// Get sink of connected client
+    pub fn get(&self, client_id: &str) -> Option<MqttSink> {
+        self.0.sessions.borrow().get(client_id).map(|shared| MqttSink::new(shared.clone()))
+    }
+
+    /// Check if client is connected
+    pub fn contains(&self, client_id: &str) -> bool {
+        self.0.sessions.borrow().contains_key(client_id)
+    }
+
+    /// Number of connected clients
+    pub fn len(&self) -> usize {
+        self.0.sessions.borrow().len()
+    }
+
+    /// Check if registry is empty
+    pub fn is_empty(&self) -> bool {
+        self.0.sessions.borrow().is_empty()
+    }
+
+    /// Client ids and sinks of connected clients
+    pub fn sinks(&self) -> Vec<(ByteString, MqttSink)> {
+        self.0
+            .sessions
+            .borrow()
+            .iter()
+            .map(|(id, shared)| (id.clone(), MqttSink::new(shared.clone())))
+            .collect()
+    }
+
+    /// Check if registry is draining
+    pub fn is_draining(&self) -> bool {
+        self.0.draining.get() || self.0.drain.as_ref().map_or(false, |d| d.is_draining())
+    }
+
+    /// Drain connected clients
+    ///
+    /// Registry stops accepting new connections, they get rejected with
+    /// `ServerUnavailable` reason code. Then registry waits until in-flight
+    /// messages of connected clients get acknowledged, but no longer than
+    /// `timeout`, and disconnects clients with `ServerShuttingDown` reason code.
+    ///
+    /// Registry only tracks mqtt sessions, listener should be stopped
+    /// with `ntex::server::Server::stop()`.
+    pub fn drain(&self, timeout: Duration) -> impl Future<Output = ()> {
+        self.0.draining.set(true);
+        let sinks = self.sinks();
+
+        async move {
+            log::trace!("Draining {} mqtt sessions", sinks.len());
+            let flushed = join_all(sinks.iter().map(|(_, sink)| sink.flush()));
+            let _ = select(flushed, delay_for(timeout)).await;
+
+            for (_, sink) in sinks {
+                sink.close_with_reason(codec::Disconnect::new(
+                    codec::DisconnectReasonCode::ServerShuttingDown,
+                ));
+            }
+        }
+    }
+
+    /// Register connection, returns previous connection of the client
+    ///
+    /// Session expiry timer of the client is cancelled.
+    pub(super) fn register(
+        &self,
+        client_id: ByteString,
+        shared: Rc<MqttShared>,
+    ) -> Option<Rc<MqttShared>> {
+        if let Some((_, handle)) = self.0.expiring.borrow_mut().remove(&client_id) {
+            log::trace!("Session expiry is cancelled for {:?}", client_id);
+            handle.abort();
+        }
+        self.0.sessions.borrow_mut().insert(client_id, shared)
+    }
+
+    /// Remove connection, if it is still registered for the client id
+    ///
+    /// Returns `false` if connection has been taken over.
+    pub(super) fn unregister(&self, client_id: &str, shared: &MqttShared) -> bool {
+        let mut sessions = self.0.sessions.borrow_mut();
+        if sessions.get(client_id).map_or(false, |item| std::ptr::eq(&**item, shared)) {
+            sessions.remove(client_id);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Run session expiry handler after delay, unless client connects again
+    pub(super) fn expire(&self, client_id: ByteString, delay: Duration, expiry: Expiry) {
+        let registry = Rc::downgrade(&self.0);
+        let id = client_id.clone();
+        let handle = ntex::rt::spawn(async move {
+            delay_for(delay).await;
+            let item = registry.upgrade().and_then(|r| r.expiring.borrow_mut().remove(&id));
+            if let Some((expiry, _)) = item {
+                expiry().await;
+            }
+        });
+        if let Some((_, prev)) =
+            self.0.expiring.borrow_mut().insert(client_id, (expiry, handle))
+        {
+            prev.abort();
+        }
+    }
+}
@@ -1,23 +1,36 @@
+use std::num::{NonZeroU16, NonZeroU32};
 use std::task::{Context, Poll};
-use std::{cell::Cell, cell::RefCell, future::Future, num::NonZeroU16, pin::Pin, rc::Rc};
+use std::{cell::Cell, cell::RefCell, future::Future, pin::Pin, rc::Rc};
 
 use ntex::router::{IntoPattern, Path, RouterBuilder};
 use ntex::service::boxed::{self, BoxService, BoxServiceFactory};
-use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
+use ntex::service::{apply, IntoServiceFactory, Service, ServiceFactory, Transform};
+use ntex::service::{fn_factory_with_config, fn_service};
 use ntex::task::LocalWaker;
-use ntex::util::{ByteString, HashMap};
+use ntex::util::{ByteString, HashMap, Ready};
 
+use super::codec::PublishAckReason;
 use super::publish::{Publish, PublishAck};
+use crate::routes::RouteTable;
+use crate::{topic::TopicRef, tree::SubscriptionTree};
 
 type Handler<S, E> = BoxServiceFactory<S, Publish, PublishAck, E, E>;
 type HandlerService<E> = BoxService<Publish, PublishAck, E>;
+type Middleware<S, E> = Box<dyn Fn(Handler<S, E>) -> Handler<S, E>>;
 
 /// Router - structure that follows the builder pattern
 /// for building publish packet router instances for mqtt server.
 pub struct Router<S, Err> {
     router: RouterBuilder<usize>,
+    filters: SubscriptionTree<usize>,
     handlers: Vec<Handler<S, Err>>,
     default: Handler<S, Err>,
+    middlewares: Vec<Middleware<S, Err>>,
+    routes: HashMap<ByteString, usize>,
+    table: Option<RouteTable>,
+    subscription_ids: bool,
+    ids: HashMap<NonZeroU32, usize>,
+    next_id: NonZeroU32,
 }
 
 impl<S, Err> Router<S, Err>
@@ -41,11 +54,66 @@ where
     {
         Router {
             router: ntex::router::Router::build(),
+            filters: SubscriptionTree::new(),
             handlers: Vec::new(),
             default: boxed::factory(default_service.into_factory()),
+            middlewares: Vec::new(),
+            routes: HashMap::default(),
+            table: None,
+            subscription_ids: false,
+            ids: HashMap::default(),
+            next_id: NonZeroU32::new(1).unwrap(),
         }
     }
 
+    /// Set default service to be used if no matching resource could be found.
+    pub fn default_service<F, U: 'static>(mut self, service: F) -> Self
+    where
+        F: IntoServiceFactory<U>,
+        U: ServiceFactory<Config = S, Request = Publish, Response = PublishAck, Error = Err>,
+        Err: From<U::InitError>,
+    {
+        self.default = boxed::factory(service.into_factory().map_init_err(Err::from));
+        self
+    }
+
+    /// Acknowledge publish packets that match no resource with specified reason code.
+    ///
+    /// Replaces default service, i.e. `PublishAckReason::NoMatchingSubscribers`
+    /// or `PublishAckReason::TopicNameInvalid` reason could be used.
+    pub fn default_ack(mut self, reason: PublishAckReason) -> Self {
+        self.default = boxed::factory(fn_factory_with_config(move |_: S| {
+            Ready::<_, Err>::Ok(fn_service(move |_: Publish| {
+                Ready::<_, Err>::Ok(PublishAck::new(reason))
+            }))
+        }));
+        self
+    }
+
+    /// Dispatch publish packets by subscription identifier.
+    ///
+    /// Each resource gets subscription identifier assigned in registration order,
+    /// starting from 1, identifiers are never reused. Publish packets that carry
+    /// subscription identifier assigned by this router are dispatched to the resource
+    /// without matching publish topic, in that case topic path parameters are not
+    /// populated. Unknown identifiers are ignored. By default it is disabled.
+    pub fn subscription_ids(mut self) -> Self {
+        self.subscription_ids = true;
+        self
+    }
+
+    /// Subscription identifier for the next registered resource.
+    pub fn next_subscription_id(&self) -> NonZeroU32 {
+        self.next_id
+    }
+
+    fn add_handler(&mut self, hnd: Handler<S, Err>) {
+        self.ids.insert(self.next_id, self.handlers.len());
+        self.next_id = NonZeroU32::new(self.next_id.get().wrapping_add(1))
+            .expect("Subscription identifiers are exhausted");
+        self.handlers.push(hnd);
+    }
+
     /// Configure mqtt resource for a specific topic.
     pub fn resource<T, F, U: 'static>(mut self, address: T, service: F) -> Self
     where
@@ -55,7 +123,94 @@ where
         Err: From<U::InitError>,
     {
         self.router.path(address, self.handlers.len());
-        self.handlers.push(boxed::factory(service.into_factory().map_init_err(Err::from)));
+        self.add_handler(boxed::factory(service.into_factory().map_init_err(Err::from)));
+        self
+    }
+
+    /// Register middleware for all resources and default service.
+    ///
+    /// Middleware is applied to each resource separately. Last registered
+    /// middleware is executed first.
+    pub fn wrap<M>(mut self, mw: M) -> Self
+    where
+        M: Transform<
+                HandlerService<Err>,
+                Request = Publish,
+                Response = PublishAck,
+                Error = Err,
+                InitError = Err,
+            > + Clone
+            + 'static,
+        M::Transform: 'static,
+        M::Future: 'static,
+    {
+        self.middlewares.push(Box::new(move |hnd| boxed::factory(apply(mw.clone(), hnd))));
+        self
+    }
+
+    /// Register middleware for last registered resource.
+    ///
+    /// Panics if no resources are registered.
+    pub fn wrap_resource<M>(mut self, mw: M) -> Self
+    where
+        M: Transform<
+                HandlerService<Err>,
+                Request = Publish,
+                Response = PublishAck,
+                Error = Err,
+                InitError = Err,
+            > + 'static,
+        M::Transform: 'static,
+        M::Future: 'static,
+    {
+        let hnd = self.handlers.pop().expect("No resources are registered");
+        self.handlers.push(boxed::factory(apply(mw, hnd)));
+        self
+    }
+
+    /// Configure mqtt resource for a topic filter.
+    ///
+    /// Topic filter could contain `+` and `#` wildcards. Topic filters are matched
+    /// with a trie of topic levels, so matching cost depends on number of topic levels
+    /// rather than on number of registered resources. If several resources match
+    /// publish topic, resource registered first is used.
+    ///
+    /// Panics if topic filter is not valid or is a shared subscription filter.
+    pub fn topic_filter<F, U: 'static>(mut self, filter: &str, service: F) -> Self
+    where
+        F: IntoServiceFactory<U>,
+        U: ServiceFactory<Config = S, Request = Publish, Response = PublishAck, Error = Err>,
+        Err: From<U::InitError>,
+    {
+        let filter = TopicRef::new(filter)
+            .unwrap_or_else(|_| panic!("Invalid topic filter: {:?}", filter));
+        assert!(!filter.is_shared(), "Shared subscription filter is not supported");
+
+        self.filters.insert(&filter.into(), self.handlers.len());
+        self.add_handler(boxed::factory(service.into_factory().map_init_err(Err::from)));
+        self
+    }
+
+    /// Configure mqtt resource for a named route of routing table.
+    ///
+    /// Routing table is consulted if publish topic does not match any
+    /// of statically configured resources.
+    pub fn route<F, U: 'static>(mut self, name: &str, service: F) -> Self
+    where
+        F: IntoServiceFactory<U>,
+        U: ServiceFactory<Config = S, Request = Publish, Response = PublishAck, Error = Err>,
+        Err: From<U::InitError>,
+    {
+        self.routes.insert(ByteString::from(name), self.handlers.len());
+        self.add_handler(boxed::factory(service.into_factory().map_init_err(Err::from)));
+        self
+    }
+
+    /// Use runtime updatable routing table.
+    ///
+    /// Table maps topic filters to routes registered with `Router::route()` method.
+    pub fn route_table(mut self, table: RouteTable) -> Self {
+        self.table = Some(table);
         self
     }
 }
@@ -66,18 +221,31 @@ where
     Err: 'static,
 {
     fn into_factory(self) -> RouterFactory<S, Err> {
+        let middlewares = self.middlewares;
+        let wrap = |hnd: Handler<S, Err>| middlewares.iter().fold(hnd, |hnd, mw| mw(hnd));
+
         RouterFactory {
             router: self.router.finish(),
-            handlers: Rc::new(self.handlers),
-            default: self.default,
+            filters: Rc::new(self.filters),
+            routes: Rc::new(self.routes),
+            table: self.table,
+            handlers: Rc::new(self.handlers.into_iter().map(wrap).collect()),
+            default: wrap(self.default),
+            subscription_ids: self.subscription_ids,
+            ids: Rc::new(self.ids),
         }
     }
 }
 
 pub struct RouterFactory<S, Err> {
     router: ntex::router::Router<usize>,
+    filters: Rc<SubscriptionTree<usize>>,
+    routes: Rc<HashMap<ByteString, usize>>,
+    table: Option<RouteTable>,
     handlers: Rc<Vec<Handler<S, Err>>>,
     default: Handler<S, Err>,
+    subscription_ids: bool,
+    ids: Rc<HashMap<NonZeroU32, usize>>,
 }
 
 impl<S, Err> ServiceFactory for RouterFactory<S, Err>
@@ -95,8 +263,13 @@ where
 
     fn new_service(&self, session: S) -> Self::Future {
         let router = self.router.clone();
+        let filters = self.filters.clone();
+        let routes = self.routes.clone();
+        let table = self.table.clone();
         let factories = self.handlers.clone();
         let default_fut = self.default.new_service(session.clone());
+        let subscription_ids = self.subscription_ids;
+        let ids = self.ids.clone();
 
         Box::pin(async move {
             let default = default_fut.await?;
@@ -104,7 +277,12 @@ where
 
             Ok(RouterService {
                 router,
+                filters,
+                routes,
+                table,
                 default,
+                subscription_ids,
+                ids,
                 inner: Rc::new(Inner {
                     session,
                     factories,
@@ -121,19 +299,50 @@ where
 pub struct RouterService<S, Err> {
     inner: Rc<Inner<S, Err>>,
     router: ntex::router::Router<usize>,
+    filters: Rc<SubscriptionTree<usize>>,
+    routes: Rc<HashMap<ByteString, usize>>,
+    table: Option<RouteTable>,
     default: HandlerService<Err>,
+    subscription_ids: bool,
+    ids: Rc<HashMap<NonZeroU32, usize>>,
 }
 
 struct Inner<S, Err> {
     session: S,
     handlers: RefCell<Vec<Option<HandlerService<Err>>>>,
     factories: Rc<Vec<Handler<S, Err>>>,
-    aliases: RefCell<HashMap<NonZeroU16, (usize, Path<ByteString>)>>,
+    // resource index, topic and routing table generation of topic alias
+    aliases: RefCell<HashMap<NonZeroU16, (usize, Path<ByteString>, usize)>>,
     waker: LocalWaker,
     creating: Cell<bool>,
 }
 
 impl<S: Clone + 'static, Err: 'static> RouterService<S, Err> {
+    /// Find first registered resource that matches publish topic
+    fn recognize(&self, topic: &mut Path<ByteString>) -> Option<usize> {
+        let name = topic.get_ref().clone();
+        let filter_idx =
+            if self.filters.is_empty() { None } else { self.filters.min_match(&name) };
+
+        match self.router.recognize(topic) {
+            Some((idx, _)) if filter_idx.map_or(true, |f_idx| *idx < f_idx) => Some(*idx),
+            Some(_) => {
+                // topic filter resource is registered first, drop path parameters
+                topic.reset();
+                filter_idx
+            }
+            None => filter_idx.or_else(|| {
+                self.table.as_ref().and_then(|table| table.recognize(&name, &self.routes))
+            }),
+        }
+    }
+
+    /// Routing table generation, cached topic aliases of older generation
+    /// get recognized again
+    fn generation(&self) -> usize {
+        self.table.as_ref().map_or(0, |table| table.generation())
+    }
+
     fn create_handler(
         &self,
         idx: usize,
@@ -193,28 +402,61 @@ impl<S: Clone + 'static, Err: 'static> Service for RouterService<S, Err> {
     }
 
     fn call(&self, mut req: Self::Request) -> Self::Future {
+        // dispatch by subscription identifier
+        if self.subscription_ids {
+            let idx = req.subscription_ids().iter().find_map(|id| self.ids.get(id).copied());
+            if let Some(idx) = idx {
+                if let Some(hnd) = &self.inner.handlers.borrow()[idx] {
+                    return hnd.call(req);
+                } else {
+                    return self.create_handler(idx, req);
+                }
+            }
+        }
+
         if !req.publish_topic().is_empty() {
-            if let Some((idx, _info)) = self.router.recognize(req.topic_mut()) {
+            let generation = self.generation();
+            if let Some(idx) = self.recognize(req.topic_mut()) {
                 // save info for topic alias
                 if let Some(alias) = req.packet().properties.topic_alias {
-                    self.inner.aliases.borrow_mut().insert(alias, (*idx, req.topic().clone()));
+                    self.inner
+                        .aliases
+                        .borrow_mut()
+                        .insert(alias, (idx, req.topic().clone(), generation));
                 }
-                if let Some(hnd) = &self.inner.handlers.borrow()[*idx] {
+                if let Some(hnd) = &self.inner.handlers.borrow()[idx] {
                     return hnd.call(req);
                 } else {
-                    return self.create_handler(*idx, req);
+                    return self.create_handler(idx, req);
                 }
             }
         }
         // handle publish with topic alias
-        else if let Some(ref alias) = req.packet().properties.topic_alias {
-            let aliases = self.inner.aliases.borrow();
-            if let Some(item) = aliases.get(alias) {
-                *req.topic_mut() = item.1.clone();
-                if let Some(hnd) = &self.inner.handlers.borrow()[item.0] {
-                    return hnd.call(req);
+        else if let Some(alias) = req.packet().properties.topic_alias {
+            let item = self.inner.aliases.borrow().get(&alias).cloned();
+            if let Some((idx, topic, gen)) = item {
+                let generation = self.generation();
+                let idx = if gen == generation {
+                    *req.topic_mut() = topic;
+                    Some(idx)
                 } else {
-                    return self.create_handler(item.0, req);
+                    // routing table is updated, route could be removed
+                    let mut topic = Path::new(topic.get_ref().clone());
+                    let idx = self.recognize(&mut topic);
+                    *req.topic_mut() = topic.clone();
+                    if let Some(idx) = idx {
+                        self.inner.aliases.borrow_mut().insert(alias, (idx, topic, generation));
+                    } else {
+                        self.inner.aliases.borrow_mut().remove(&alias);
+                    }
+                    idx
+                };
+                if let Some(idx) = idx {
+                    if let Some(hnd) = &self.inner.handlers.borrow()[idx] {
+                        return hnd.call(req);
+                    } else {
+                        return self.create_handler(idx, req);
+                    }
                 }
             } else {
                 log::error!("Unknown topic alias: {:?}", alias);
@@ -223,3 +465,50 @@ impl<S: Clone + 'static, Err: 'static> Service for RouterService<S, Err> {
         self.default.call(req)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ntex::util::Bytes;
+
+    use super::*;
+    use crate::v5::codec;
+
+    fn publish(topic: &'static str, id: u32) -> Publish {
+        let mut pkt = codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            topic: ByteString::from_static(topic),
+            packet_id: NonZeroU16::new(1),
+            payload: Bytes::new(),
+            properties: Default::default(),
+        };
+        pkt.properties.subscription_ids = NonZeroU32::new(id).map(|id| vec![id]);
+        Publish::new(pkt)
+    }
+
+    #[ntex::test]
+    async fn test_subscription_ids() {
+        let router = Router::<(), ()>::new(|p: Publish| Ready::Ok(p.ack())).subscription_ids();
+        assert_eq!(router.next_subscription_id().get(), 1);
+        let router = router.resource("topic1", |p: Publish| Ready::Ok(p.ack()));
+        assert_eq!(router.next_subscription_id().get(), 2);
+        let router = router.resource("topic2", |p: Publish| {
+            assert_eq!(p.subscription_ids(), &[NonZeroU32::new(2).unwrap()][..]);
+            Ready::Ok(PublishAck::new(PublishAckReason::NoMatchingSubscribers))
+        });
+        let srv = router.into_factory().new_service(()).await.unwrap();
+
+        // dispatched by subscription id
+        let ack = srv.call(publish("other", 2)).await.unwrap();
+        assert_eq!(ack.reason_code, PublishAckReason::NoMatchingSubscribers);
+
+        // unknown subscription id, dispatched by topic
+        let ack = srv.call(publish("topic1", 10)).await.unwrap();
+        assert_eq!(ack.reason_code, PublishAckReason::Success);
+
+        // no subscription id, default service
+        let ack = srv.call(publish("other", 0)).await.unwrap();
+        assert_eq!(ack.reason_code, PublishAckReason::Success);
+    }
+}
@@ -5,19 +5,28 @@ use ntex::framed::{State, WriteTask};
 use ntex::rt::time::Sleep;
 use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
 use ntex::util::timeout::{Timeout, TimeoutError};
+use ntex::util::Either;
 
-use crate::error::{MqttError, ProtocolError};
+use crate::error::{DecodeError, MqttError, ProtocolError};
+use crate::inspect::PacketInspector;
+use crate::metrics::{HandshakeGuard, MqttMetrics};
+use crate::ratelimit::{ConnectionCounter, PublishRate, RateLimitPolicy, RateLimiter};
 use crate::service::{FactoryBuilder, FactoryBuilder2};
-use crate::types::QoS;
+use crate::trace::Span;
+use crate::types::{PoolConfig, QoS};
+use crate::Acl;
 
+use super::acl::AclRef;
 use super::codec as mqtt;
 use super::control::{ControlMessage, ControlResult};
 use super::default::{DefaultControlService, DefaultPublishService};
 use super::dispatcher::factory;
 use super::handshake::{Handshake, HandshakeAck};
 use super::publish::{Publish, PublishAck};
-use super::shared::{MqttShared, MqttSinkPool};
+use super::registry::SessionRegistry;
+use super::shared::{Capabilities, MqttShared, MqttSinkPool};
 use super::sink::MqttSink;
+use super::store::{ConnectionStore, SessionStore};
 use super::Session;
 
 /// Mqtt Server
@@ -26,12 +35,26 @@ pub struct MqttServer<Io, St, C: ServiceFactory, Cn: ServiceFactory, P: ServiceF
     srv_control: Cn,
     srv_publish: P,
     max_size: u32,
+    conformance: mqtt::Conformance,
+    strict_utf8: bool,
     max_receive: u16,
     max_qos: Option<QoS>,
     handshake_timeout: u16,
     disconnect_timeout: u16,
+    keepalive_grace: f32,
+    write_limit: Option<(usize, u16)>,
+    write_coalesce: Option<(usize, usize, Duration)>,
     max_topic_alias: u16,
     pool: Rc<MqttSinkPool>,
+    registry: Option<SessionRegistry>,
+    store: Option<Rc<dyn SessionStore>>,
+    limiter: Option<Rc<RateLimiter>>,
+    connections: Option<ConnectionCounter>,
+    proxy_protocol: bool,
+    publish_rate: PublishRate,
+    acl: Option<AclRef<St>>,
+    stream_threshold: u32,
+    publish_release: bool,
     _t: marker::PhantomData<(Io, St)>,
 }
 
@@ -59,12 +82,26 @@ where
             srv_control: DefaultControlService::default(),
             srv_publish: DefaultPublishService::default(),
             max_size: 0,
+            conformance: mqtt::Conformance::Strict,
+            strict_utf8: true,
             max_receive: 15,
             max_qos: None,
             handshake_timeout: 0,
             disconnect_timeout: 3000,
+            keepalive_grace: 1.5,
+            write_limit: None,
+            write_coalesce: None,
             max_topic_alias: 32,
             pool: Rc::new(MqttSinkPool::default()),
+            registry: None,
+            store: None,
+            limiter: None,
+            connections: None,
+            proxy_protocol: false,
+            publish_rate: PublishRate::default(),
+            acl: None,
+            stream_threshold: 0,
+            publish_release: false,
             _t: marker::PhantomData,
         }
     }
@@ -106,8 +143,44 @@ where
         self
     }
 
+    /// Set keep-alive grace factor
+    ///
+    /// Connection keep-alive timeout is multiplied by grace factor, if client
+    /// does not send any packet within this time `ControlMessage::KeepAliveTimeout`
+    /// message is emitted. By default grace factor is set to 1.5.
+    pub fn keepalive_grace(mut self, factor: f32) -> Self {
+        self.keepalive_grace = factor;
+        self
+    }
+
+    /// Set write buffer limit in bytes and slow consumer timeout in seconds
+    ///
+    /// If client does not read from connection and write buffer size exceeds
+    /// `size` for longer than `timeout`, `ControlMessage::SlowConsumer` message
+    /// is emitted. Write buffer is checked once per `timeout` period.
+    /// By default write buffer is not limited.
+    pub fn write_buffer_limit(mut self, size: usize, timeout: u16) -> Self {
+        self.write_limit = Some((size, timeout));
+        self
+    }
+
+    /// Set write coalescing buffer size in bytes, number of packets and delay
+    /// in microseconds
+    ///
+    /// Writing to connection is delayed for up to `delay` microseconds, packets
+    /// encoded in the meantime are sent with single write call. Buffered data
+    /// is written without delay if it is larger than `size` bytes or if it
+    /// contains at least `packets` packets, `0` disables packets limit. Timer
+    /// resolution is one millisecond. By default writes are not delayed.
+    pub fn write_coalesce(mut self, size: usize, packets: usize, delay: u32) -> Self {
+        self.write_coalesce = Some((size, packets, Duration::from_micros(delay as u64)));
+        self
+    }
+
     /// Set max inbound frame size.
     ///
+    /// Packets which fixed header announces larger size are rejected without
+    /// reading packet's payload, with `PacketTooLarge` reason code.
     /// If max size is set to `0`, size is unlimited.
     /// By default max size is set to `0`
     pub fn max_size(mut self, size: u32) -> Self {
@@ -115,6 +188,52 @@ where
         self
     }
 
+    /// Set codec conformance mode.
+    ///
+    /// In lenient mode server tolerates common deviations from
+    /// the protocol specification.
+    /// By default `Conformance::Strict` mode is used.
+    pub fn conformance(mut self, val: mqtt::Conformance) -> Self {
+        self.conformance = val;
+        self
+    }
+
+    /// Set utf-8 strings strictness.
+    ///
+    /// In strict mode packets with topics or strings containing U+0000 or
+    /// control characters are rejected as malformed.
+    /// By default strict mode is enabled.
+    pub fn strict_utf8(mut self, val: bool) -> Self {
+        self.strict_utf8 = val;
+        self
+    }
+
+    /// Set streaming threshold for inbound publish packets.
+    ///
+    /// Payload of publish packets larger than threshold is delivered via
+    /// `Publish::take_payload_stream()` while packet is still being received,
+    /// instead of buffering whole packet in memory. Up to `size` bytes of
+    /// received chunks are buffered, connection stops reading from the socket
+    /// until they get consumed, so publish service should not wait for other
+    /// messages before reading the stream.
+    /// If threshold is set to `0`, streaming is disabled.
+    /// By default threshold is set to `0`
+    pub fn stream_threshold(mut self, size: u32) -> Self {
+        self.stream_threshold = size;
+        self
+    }
+
+    /// Deliver QoS 2 publish releases to control service.
+    ///
+    /// If enabled, PUBREL packets are delivered to control service as
+    /// `ControlMessage::PublishRelease` along with the original publish,
+    /// PUBCOMP is sent when control service acks the message.
+    /// By default PUBCOMP is sent automatically.
+    pub fn publish_release(mut self, enabled: bool) -> Self {
+        self.publish_release = enabled;
+        self
+    }
+
     /// Set `receive max`
     ///
     /// Number of in-flight publish packets. By default receive max is set to 15 packets.
@@ -140,6 +259,129 @@ where
         self
     }
 
+    /// Set session registry
+    ///
+    /// Registry tracks connected clients. If client connects with client id
+    /// of the existing connection, old connection get disconnected with
+    /// `SessionTakenOver` reason code. Registry could be used for graceful
+    /// server shutdown, see `SessionRegistry::drain()`. By default registry is not set.
+    pub fn session_registry(mut self, registry: SessionRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Set session store
+    ///
+    /// Server loads session state of the client during handshake, session
+    /// present flag is set if stored session exists and client does not
+    /// request clean start. Subscriptions, in-flight outgoing publishes and
+    /// incoming qos 2 state are persisted during connection lifetime, not
+    /// acknowledged publishes are re-sent after client re-connects. Stored
+    /// session state is available via `Handshake::session_state()`.
+    /// Session state expiration requires session registry.
+    pub fn session_store<S>(mut self, store: S) -> Self
+    where
+        S: SessionStore + 'static,
+    {
+        self.store = Some(Rc::new(store));
+        self
+    }
+
+    /// Set connection accept rate limit
+    ///
+    /// Token bucket limiter allows `per_second` new connections per second
+    /// with bursts of up to `burst` connections. Excess connections get
+    /// closed before `CONNECT` packet is read. Limit is applied per server worker.
+    /// By default rate limit is not set.
+    pub fn connection_rate(mut self, per_second: u32, burst: u32) -> Self {
+        self.limiter = Some(Rc::new(RateLimiter::new(per_second, burst)));
+        self
+    }
+
+    /// Set max number of concurrent connections
+    ///
+    /// If limit is reached, new connections get rejected with `CONNACK`
+    /// packet with `ServerBusy` reason code. Limit is applied
+    /// per server worker. By default number of connections is not limited.
+    pub fn max_connections(mut self, num: usize) -> Self {
+        self.connections = Some(ConnectionCounter::new(num));
+        self
+    }
+
+    /// Set connection metrics
+    ///
+    /// Metrics are reported for every connection handled by server worker.
+    /// By default metrics are not collected.
+    pub fn metrics<M: MqttMetrics + 'static>(mut self, metrics: M) -> Self {
+        self.pool = self.pool.with_metrics(Rc::new(metrics));
+        self
+    }
+
+    /// Set sink pool and buffer configuration
+    ///
+    /// Configuration is shared by all connections of server worker.
+    pub fn pool_config(mut self, config: PoolConfig) -> Self {
+        self.pool = self.pool.with_config(config);
+        self
+    }
+
+    /// Set packet inspector
+    ///
+    /// Inspector sees every packet received from and sent to clients,
+    /// including handshake packets. By default packets are not inspected.
+    pub fn inspector<I>(mut self, inspector: I) -> Self
+    where
+        I: PacketInspector<mqtt::Packet> + 'static,
+    {
+        self.pool = self.pool.with_inspector(Rc::new(inspector));
+        self
+    }
+
+    /// Enable PROXY protocol support
+    ///
+    /// Server expects PROXY protocol v1 or v2 header before `CONNECT` packet,
+    /// connections without valid header get dropped. Original client address
+    /// is available via `Handshake::peer_addr()`. Header is read from the
+    /// transport that is passed to the server, for tls connections use
+    /// `ntex_mqtt::ProxyProtocol` acceptor before tls acceptor instead.
+    /// Protocol selector `ntex_mqtt::MqttServer` does not support PROXY protocol.
+    /// By default PROXY protocol is disabled.
+    pub fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    /// Set topic authorization provider
+    ///
+    /// Provider is called for topic of every incoming publish packet and for
+    /// every topic filter of incoming subscribe packet. See `Acl` for details.
+    pub fn acl<A>(mut self, acl: A) -> Self
+    where
+        A: Acl<Session<St>>,
+    {
+        self.acl = Some(Rc::new(acl));
+        self
+    }
+
+    /// Set inbound publish rate limit
+    ///
+    /// Limits number of publish packets and payload bytes per second for
+    /// each connection, `0` disables the limit. Action for clients that exceed
+    /// the limit is defined by `publish_rate_policy()`. By default rate is not limited.
+    pub fn publish_rate(mut self, messages: u32, bytes: u32) -> Self {
+        self.publish_rate.messages = messages;
+        self.publish_rate.bytes = bytes;
+        self
+    }
+
+    /// Set action for clients that exceed inbound publish rate
+    ///
+    /// By default reading from connection is paused until rate drops below the limit.
+    pub fn publish_rate_policy(mut self, policy: RateLimitPolicy) -> Self {
+        self.publish_rate.policy = policy;
+        self
+    }
+
     /// Service to handle control messages
     pub fn control<F, Srv>(self, service: F) -> MqttServer<Io, St, C, Srv, P>
     where
@@ -156,12 +398,26 @@ where
             srv_publish: self.srv_publish,
             srv_control: service.into_factory(),
             max_size: self.max_size,
+            conformance: self.conformance,
+            strict_utf8: self.strict_utf8,
             max_receive: self.max_receive,
             max_topic_alias: self.max_topic_alias,
             max_qos: self.max_qos,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            keepalive_grace: self.keepalive_grace,
+            write_limit: self.write_limit,
+            write_coalesce: self.write_coalesce,
             pool: self.pool,
+            registry: self.registry,
+            store: self.store,
+            limiter: self.limiter,
+            connections: self.connections,
+            proxy_protocol: self.proxy_protocol,
+            publish_rate: self.publish_rate,
+            acl: self.acl,
+            stream_threshold: self.stream_threshold,
+            publish_release: self.publish_release,
             _t: marker::PhantomData,
         }
     }
@@ -181,12 +437,26 @@ where
             srv_publish: publish.into_factory(),
             srv_control: self.srv_control,
             max_size: self.max_size,
+            conformance: self.conformance,
+            strict_utf8: self.strict_utf8,
             max_receive: self.max_receive,
             max_topic_alias: self.max_topic_alias,
             max_qos: self.max_qos,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            keepalive_grace: self.keepalive_grace,
+            write_limit: self.write_limit,
+            write_coalesce: self.write_coalesce,
             pool: self.pool,
+            registry: self.registry,
+            store: self.store,
+            limiter: self.limiter,
+            connections: self.connections,
+            proxy_protocol: self.proxy_protocol,
+            publish_rate: self.publish_rate,
+            acl: self.acl,
+            stream_threshold: self.stream_threshold,
+            publish_release: self.publish_release,
             _t: marker::PhantomData,
         }
     }
@@ -208,6 +478,7 @@ where
             Request = ControlMessage<C::Error>,
             Response = ControlResult,
         > + 'static,
+    Cn::Service: 'static,
     P: ServiceFactory<Config = Session<St>, Request = Publish, Response = PublishAck> + 'static,
     P::Error: fmt::Debug,
     PublishAck: TryFrom<P::Error, Error = C::Error>,
@@ -228,14 +499,31 @@ where
             FactoryBuilder::new(handshake_service_factory(
                 handshake,
                 self.max_size,
+                self.conformance,
+                self.strict_utf8,
                 self.max_receive,
                 self.max_topic_alias,
                 self.max_qos,
                 self.handshake_timeout,
                 self.pool,
+                self.registry,
+                self.store,
+                self.limiter,
+                self.connections,
+                self.proxy_protocol,
             ))
             .disconnect_timeout(self.disconnect_timeout)
-            .build(factory(publish, control)),
+            .keepalive_grace(self.keepalive_grace)
+            .write_limit(self.write_limit)
+            .write_coalesce(self.write_coalesce)
+            .build(factory(
+                publish,
+                control,
+                self.publish_rate,
+                self.acl,
+                self.stream_threshold,
+                self.publish_release,
+            )),
         )
     }
 
@@ -260,26 +548,50 @@ where
             FactoryBuilder2::new(handshake_service_factory2(
                 handshake,
                 self.max_size,
+                self.conformance,
+                self.strict_utf8,
                 self.max_receive,
                 self.max_topic_alias,
                 self.max_qos,
                 self.handshake_timeout,
                 self.pool,
+                self.registry,
+                self.store,
+                self.limiter,
+                self.connections,
             ))
             .disconnect_timeout(self.disconnect_timeout)
-            .build(factory(publish, control)),
+            .keepalive_grace(self.keepalive_grace)
+            .write_limit(self.write_limit)
+            .write_coalesce(self.write_coalesce)
+            .build(factory(
+                publish,
+                control,
+                self.publish_rate,
+                self.acl,
+                self.stream_threshold,
+                self.publish_release,
+            )),
         )
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handshake_service_factory<Io, St, C>(
     factory: C,
     max_size: u32,
+    conformance: mqtt::Conformance,
+    strict_utf8: bool,
     max_receive: u16,
     max_topic_alias: u16,
     max_qos: Option<QoS>,
     handshake_timeout: u16,
     pool: Rc<MqttSinkPool>,
+    registry: Option<SessionRegistry>,
+    store: Option<Rc<dyn SessionStore>>,
+    limiter: Option<Rc<RateLimiter>>,
+    connections: Option<ConnectionCounter>,
+    proxy_protocol: bool,
 ) -> impl ServiceFactory<
     Config = (),
     Request = Io,
@@ -295,11 +607,19 @@ where
         Timeout::new(Duration::from_millis(handshake_timeout as u64)),
         ntex::fn_factory(move || {
             let pool = pool.clone();
+            let registry = registry.clone();
+            let store = store.clone();
+            let limiter = limiter.clone();
+            let connections = connections.clone();
 
             let fut = factory.new_service(());
             async move {
                 let service = fut.await?;
                 let pool = pool.clone();
+                let registry = registry.clone();
+                let store = store.clone();
+                let limiter = limiter.clone();
+                let connections = connections.clone();
                 let service = Rc::new(service.map_err(MqttError::Service));
                 Ok::<_, C::InitError>(ntex::apply_fn(service, move |io: Io, service| {
                     handshake(
@@ -307,10 +627,17 @@ where
                         None,
                         service.clone(),
                         max_size,
+                        conformance,
+                        strict_utf8,
                         max_receive,
                         max_topic_alias,
                         max_qos,
                         pool.clone(),
+                        registry.clone(),
+                        store.clone(),
+                        limiter.clone(),
+                        connections.clone(),
+                        proxy_protocol,
                     )
                 }))
             }
@@ -322,14 +649,21 @@ where
     })
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handshake_service_factory2<Io, St, C>(
     factory: C,
     max_size: u32,
+    conformance: mqtt::Conformance,
+    strict_utf8: bool,
     max_receive: u16,
     max_topic_alias: u16,
     max_qos: Option<QoS>,
     handshake_timeout: u16,
     pool: Rc<MqttSinkPool>,
+    registry: Option<SessionRegistry>,
+    store: Option<Rc<dyn SessionStore>>,
+    limiter: Option<Rc<RateLimiter>>,
+    connections: Option<ConnectionCounter>,
 ) -> impl ServiceFactory<
     Config = (),
     Request = (Io, State),
@@ -346,10 +680,19 @@ where
         Timeout::new(Duration::from_millis(handshake_timeout as u64)),
         ntex::fn_factory(move || {
             let pool = pool.clone();
+            let registry = registry.clone();
+            let store = store.clone();
+            let limiter = limiter.clone();
+            let connections = connections.clone();
+
             let fut = factory.new_service(());
             async move {
                 let service = fut.await?;
                 let pool = pool.clone();
+                let registry = registry.clone();
+                let store = store.clone();
+                let limiter = limiter.clone();
+                let connections = connections.clone();
                 let service = Rc::new(service.map_err(MqttError::Service));
                 Ok::<_, C::InitError>(ntex::apply_fn(service, move |(io, state), service| {
                     handshake(
@@ -357,10 +700,17 @@ where
                         Some(state),
                         service.clone(),
                         max_size,
+                        conformance,
+                        strict_utf8,
                         max_receive,
                         max_topic_alias,
                         max_qos,
                         pool.clone(),
+                        registry.clone(),
+                        store.clone(),
+                        limiter.clone(),
+                        connections.clone(),
+                        false,
                     )
                 }))
             }
@@ -378,58 +728,145 @@ async fn handshake<Io, S, St, E>(
     state: Option<State>,
     service: S,
     max_size: u32,
+    conformance: mqtt::Conformance,
+    strict_utf8: bool,
     mut max_receive: u16,
     mut max_topic_alias: u16,
     max_qos: Option<QoS>,
     pool: Rc<MqttSinkPool>,
+    registry: Option<SessionRegistry>,
+    store: Option<Rc<dyn SessionStore>>,
+    limiter: Option<Rc<RateLimiter>>,
+    connections: Option<ConnectionCounter>,
+    proxy_protocol: bool,
 ) -> Result<(Io, State, Rc<MqttShared>, Session<St>, u16), S::Error>
 where
     Io: AsyncRead + AsyncWrite + Unpin + 'static,
     S: Service<Request = Handshake<Io>, Response = HandshakeAck<Io, St>, Error = MqttError<E>>,
 {
     log::trace!("Starting mqtt v5 handshake");
+    let metrics = HandshakeGuard::new(pool.metrics.clone());
+
+    // connection accept rate limit
+    if let Some(ref limiter) = limiter {
+        if !limiter.acquire() {
+            log::trace!("Connection is rejected by rate limiter");
+            return Err(MqttError::RateLimited);
+        }
+    }
 
-    let state = state.unwrap_or_else(State::new);
-    let shared = Rc::new(MqttShared::new(state.clone(), mqtt::Codec::default(), 0, pool));
+    let state = state.unwrap_or_else(|| pool.state());
+    let shared = Rc::new(MqttShared::new(state.clone(), mqtt::Codec::default(), 0, pool, true));
 
     // set max inbound (decoder) packet size
     shared.codec.set_max_inbound_size(max_size);
+    shared.codec.set_conformance(conformance);
+    shared.codec.set_strict_utf8(strict_utf8);
+
+    let (peer_addr, local_addr) = crate::utils::io_addrs(&io);
+    shared.peer_addr.set(peer_addr);
+    shared.local_addr.set(local_addr);
+
+    // read PROXY protocol header
+    if proxy_protocol {
+        let addr = crate::proxy_protocol::read_header(&mut io).await.map_err(|err| {
+            log::trace!("Invalid PROXY protocol header: {:?}", err);
+            MqttError::Protocol(ProtocolError::Io(err))
+        })?;
+        if addr.is_some() {
+            shared.peer_addr.set(addr);
+        }
+    }
 
     // read first packet
-    let packet = state
-        .next(&mut io, &shared.codec)
-        .await
-        .map_err(|err| {
+    let packet = match state.next(&mut io, &*shared).await {
+        Ok(Some(packet)) => packet,
+        Ok(None) => {
+            log::trace!("Server mqtt is disconnected during handshake");
+            return Err(MqttError::Disconnected);
+        }
+        Err(Either::Left(err)) if *err.kind() == DecodeError::MaxSizeExceeded => {
+            // fixed header announces oversized packet, reject it without
+            // reading packet's payload
+            log::trace!("Oversized packet is received during mqtt handshake");
+            let pkt = mqtt::Packet::ConnectAck(mqtt::ConnectAck {
+                reason_code: mqtt::ConnectAckReason::PacketTooLarge,
+                ..Default::default()
+            });
+            let _ = state.send(&mut io, &*shared, pkt).await;
+            return Err(MqttError::from(Either::Left(err)));
+        }
+        Err(err) => {
             log::trace!("Error is received during mqtt handshake: {:?}", err);
-            MqttError::from(err)
-        })
-        .and_then(|res| {
-            res.ok_or_else(|| {
-                log::trace!("Server mqtt is disconnected during handshake");
-                MqttError::Disconnected
-            })
-        })?;
+            return Err(MqttError::from(err));
+        }
+    };
 
     match packet {
         mqtt::Packet::Connect(connect) => {
+            *shared.span.borrow_mut() =
+                Span::connection(&connect.client_id, shared.peer_addr.get(), "5.0");
+
+            // server is draining connections
+            if registry.as_ref().map_or(false, |r| r.is_draining()) {
+                log::trace!("Server is shutting down, reject connection");
+                let pkt = mqtt::Packet::ConnectAck(mqtt::ConnectAck {
+                    reason_code: mqtt::ConnectAckReason::ServerUnavailable,
+                    ..Default::default()
+                });
+                state.send(&mut io, &*shared, pkt).await?;
+                return Err(MqttError::ShuttingDown);
+            }
+
+            // check max number of connections
+            let guard = match connections.map(|c| c.acquire()) {
+                Some(None) => {
+                    log::trace!("Max number of connections is reached");
+                    let pkt = mqtt::Packet::ConnectAck(mqtt::ConnectAck {
+                        reason_code: mqtt::ConnectAckReason::ServerBusy,
+                        ..Default::default()
+                    });
+                    state.send(&mut io, &*shared, pkt).await?;
+                    return Err(MqttError::ServerBusy);
+                }
+                guard => guard.flatten(),
+            };
+
             // set max outbound (encoder) packet size
             if let Some(size) = connect.max_packet_size {
                 shared.codec.set_max_outbound_size(size.get());
             }
             shared.cap.set(connect.receive_max.map(|v| v.get()).unwrap_or(16) as usize);
+            shared.topic_alias_max.set(connect.topic_alias_max);
+            shared.session_expiry.set(connect.session_expiry_interval_secs.unwrap_or(0));
 
             let keep_alive = connect.keep_alive;
+            let client_id = connect.client_id.clone();
+            let last_will = connect.last_will.clone();
+            let clean_start = connect.clean_start;
+
+            // load stored session, store is not modified until connection is accepted
+            let stored = store.as_ref().map(|store| {
+                if clean_start || client_id.is_empty() {
+                    None
+                } else {
+                    store.load(&client_id)
+                }
+            });
+            let session_state = stored.clone().flatten();
 
-            // authenticate mqtt connection
-            let mut ack = service
-                .call(Handshake::new(
+            // authenticate mqtt connection within connection span
+            let span = shared.span.borrow().clone();
+            let mut ack = span
+                .instrument(service.call(Handshake::new(
                     connect,
                     io,
                     shared,
                     max_size,
                     max_receive,
                     max_topic_alias,
-                ))
+                    session_state,
+                )))
                 .await?;
 
             match ack.session {
@@ -438,6 +875,10 @@ where
                     let shared = ack.shared;
 
                     max_topic_alias = ack.packet.topic_alias_max;
+                    shared.caps.set(Capabilities::new(&ack.packet));
+                    if let Some(secs) = ack.packet.session_expiry_interval_secs {
+                        shared.session_expiry.set(secs);
+                    }
 
                     if ack.packet.max_qos.is_none() {
                         ack.packet.max_qos = max_qos;
@@ -457,11 +898,60 @@ where
                         ack.packet.server_keepalive_sec = Some(ack.keepalive as u16);
                     }
 
+                    // session store defines session present flag
+                    if let Some(ref st) = stored {
+                        ack.packet.session_present = st.is_some();
+                    }
+
+                    // disconnect previous connection of the client
+                    let client_id = ack.packet.assigned_client_id.clone().unwrap_or(client_id);
+                    if let Some(registry) = registry {
+                        if let Some(prev) = registry.register(client_id.clone(), shared.clone())
+                        {
+                            log::trace!("Session is taken over for client: {:?}", client_id);
+                            let hook = prev.takeover.borrow_mut().take();
+                            if let Some(hook) = hook {
+                                hook().await;
+                            } else {
+                                MqttSink::new(prev).close_with_reason(mqtt::Disconnect::new(
+                                    mqtt::DisconnectReasonCode::SessionTakenOver,
+                                ));
+                            }
+                        }
+                        *shared.registry.borrow_mut() = Some((registry, client_id.clone()));
+                    }
+
                     state.set_buffer_params(ack.read_hw, ack.write_hw, ack.lw);
-                    state
-                        .send(&mut ack.io, &shared.codec, mqtt::Packet::ConnectAck(ack.packet))
-                        .await?;
+                    let res = state
+                        .send(&mut ack.io, &*shared, mqtt::Packet::ConnectAck(ack.packet))
+                        .await;
+                    if let Err(err) = res {
+                        if let Some((registry, client_id)) = shared.registry.borrow_mut().take()
+                        {
+                            registry.unregister(&client_id, &shared);
+                        }
+                        return Err(err.into());
+                    }
+                    *shared.connection.borrow_mut() = guard;
+                    *shared.last_will.borrow_mut() = last_will;
+
+                    // previous session is discarded on clean start
+                    if let Some(store) = store {
+                        if clean_start {
+                            store.remove(&client_id);
+                        }
+                        *shared.store.borrow_mut() =
+                            Some(ConnectionStore::new(store, client_id));
+                    }
+
+                    // re-send not acknowledged publishes
+                    let sink = MqttSink::new(shared.clone());
+                    if let Some(Some(st)) = stored {
+                        sink.restore(st);
+                    }
 
+                    let addrs = (shared.peer_addr.get(), shared.local_addr.get());
+                    metrics.complete();
                     Ok((
                         ack.io,
                         shared.state.clone(),
@@ -471,6 +961,7 @@ where
                             MqttSink::new(shared),
                             max_receive,
                             max_topic_alias,
+                            addrs,
                         ),
                         ack.keepalive,
                     ))
@@ -483,7 +974,7 @@ where
                             .shared
                             .state
                             .write()
-                            .encode(mqtt::Packet::ConnectAck(ack.packet), &ack.shared.codec)
+                            .encode(mqtt::Packet::ConnectAck(ack.packet), &*ack.shared)
                             .is_ok()
                     {
                         WriteTask::shutdown(
@@ -1,35 +1,266 @@
-use std::{cell::Cell, cell::RefCell, collections::VecDeque, rc::Rc};
+use std::{cell::Cell, cell::RefCell, collections::VecDeque, future::Future, num::NonZeroU16};
+use std::{mem, net::SocketAddr, pin::Pin, rc::Rc, time::Duration, time::Instant};
 
 use ntex::channel::pool;
 use ntex::codec::{Decoder, Encoder};
-use ntex::util::{BytesMut, HashMap};
+use ntex::rt::time::delay_for;
+use ntex::util::{select, ByteString, Bytes, BytesMut, Either, HashMap, HashSet};
 
-use super::codec;
-use crate::{error, io::State, types::packet_type};
+use super::{codec, control::Event, registry::SessionRegistry, store::ConnectionStore};
+use crate::error::SendPacketError;
+use crate::inspect::{Inspector, PacketInspector};
+use crate::metrics::{self, Metrics};
+use crate::trace::Span;
+use crate::types::{packet_type, AckOrder, CancelPolicy, PoolConfig};
+use crate::utils::{CloseState, EventQueue, PacketIds, ReadPause};
+use crate::{error, io::State, ratelimit::ConnectionGuard};
 
 pub(crate) struct MqttShared {
+    // server side of the connection
+    pub(super) server: bool,
     pub(super) cap: Cell<usize>,
     pub(super) queues: RefCell<MqttSharedQueues>,
     pub(super) inflight_idx: Cell<u16>,
+    pub(super) ack_timeout: Cell<Option<Duration>>,
+    pub(super) cancel_policy: Cell<CancelPolicy>,
+    pub(super) ack_order: Cell<AckOrder>,
+    pub(super) ping_pending: Cell<bool>,
+    pub(super) request_idx: Cell<u32>,
+    pub(super) topic_alias: Cell<bool>,
+    pub(super) topic_alias_max: Cell<u16>,
+    pub(super) caps: Cell<Capabilities>,
+    pub(super) session_expiry: Cell<u32>,
+    pub(super) last_will: RefCell<Option<codec::LastWill>>,
+    // session registry and client id of the connection
+    pub(super) registry: RefCell<Option<(SessionRegistry, ByteString)>>,
+    pub(super) takeover: RefCell<Option<TakeoverHook>>,
+    pub(super) events: EventQueue<Event>,
+    pub(super) released: RefCell<Option<ReleaseHook>>,
+    // client's inbound qos2 publishes, awaiting PUBREL
+    pub(super) received: RefCell<HashSet<NonZeroU16>>,
+    pub(super) received_hook: RefCell<Option<ReceiveHook>>,
+    pub(super) store: RefCell<Option<ConnectionStore>>,
+    pub(super) connection: RefCell<Option<ConnectionGuard>>,
+    pub(super) peer_addr: Cell<Option<SocketAddr>>,
+    pub(super) local_addr: Cell<Option<SocketAddr>>,
+    pub(super) span: RefCell<Span>,
+    pub(super) close: CloseState,
+    pub(super) read_pause: ReadPause,
     pub(super) pool: Rc<MqttSinkPool>,
     pub(super) state: State,
     pub(super) codec: codec::Codec,
 }
 
+/// Session takeover handler of the connection
+pub(super) type TakeoverHook = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()>>>>;
+
+/// QoS 2 publish release handler, called when positive PUBREC is received
+pub(super) type ReleaseHook = Box<dyn Fn(NonZeroU16)>;
+
+/// Inbound QoS 2 publish handler, called with `true` when publish
+/// is received and with `false` when it is released by the peer
+pub(super) type ReceiveHook = Box<dyn Fn(NonZeroU16, bool)>;
+
 pub(super) struct MqttSharedQueues {
     pub(super) inflight: HashMap<u16, (pool::Sender<Ack>, AckType)>,
+    // initial capacity of in-flight queue, see `PoolConfig::inflight_capacity()`
+    pub(super) inflight_cap: usize,
     pub(super) inflight_order: VecDeque<u16>,
+    // released in-flight packets, peer's acks are ignored
+    pub(super) expired: HashSet<u16>,
+    // ids of in-flight and expired packets
+    pub(super) ids: PacketIds,
     pub(super) waiters: VecDeque<pool::Sender<()>>,
+    // waiters for empty in-flight queue
+    pub(super) flush: Vec<pool::Sender<()>>,
+    pub(super) auth: Option<pool::Sender<codec::Auth>>,
+    pub(super) aliases: HashMap<ByteString, NonZeroU16>,
+    // pending requests, by correlation data
+    pub(super) requests: HashMap<Bytes, pool::Sender<codec::Publish>>,
+    // subscribed response topics
+    pub(super) response_topics: HashSet<ByteString>,
+}
+
+#[derive(Copy, Clone, Debug)]
+/// Server capabilities advertised in CONNACK packet
+pub(super) struct Capabilities {
+    pub(super) retain: bool,
+    pub(super) wildcard_subscription: bool,
+    pub(super) shared_subscription: bool,
+    pub(super) subscription_ids: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            retain: true,
+            wildcard_subscription: true,
+            shared_subscription: true,
+            subscription_ids: true,
+        }
+    }
+}
+
+impl Capabilities {
+    pub(super) fn new(pkt: &codec::ConnectAck) -> Self {
+        Self {
+            retain: pkt.retain_available.unwrap_or(true),
+            wildcard_subscription: pkt.wildcard_subscription_available.unwrap_or(true),
+            shared_subscription: pkt.shared_subscription_available.unwrap_or(true),
+            subscription_ids: pkt.subscription_identifiers_available.unwrap_or(true),
+        }
+    }
+
+    /// Check topic filter against subscription capabilities
+    pub(super) fn check_filter(&self, topic: &ByteString) -> Option<codec::SubscribeAckReason> {
+        if !self.shared_subscription && topic.starts_with("$share/") {
+            Some(codec::SubscribeAckReason::SharedSubsriptionNotSupported)
+        } else if !self.wildcard_subscription && topic.contains(|c| c == '+' || c == '#') {
+            Some(codec::SubscribeAckReason::WildcardSubscriptionsNotSupported)
+        } else {
+            None
+        }
+    }
 }
 
 pub(super) struct MqttSinkPool {
     pub(super) queue: pool::Pool<Ack>,
     pub(super) waiters: pool::Pool<()>,
+    pub(super) auth: pool::Pool<codec::Auth>,
+    pub(super) response: pool::Pool<codec::Publish>,
+    pub(super) metrics: Metrics,
+    pub(super) inspector: Inspector<codec::Packet>,
+    pub(super) config: PoolConfig,
 }
 
 impl Default for MqttSinkPool {
     fn default() -> Self {
-        Self { queue: pool::new(), waiters: pool::new() }
+        Self {
+            queue: pool::new(),
+            waiters: pool::new(),
+            auth: pool::new(),
+            response: pool::new(),
+            metrics: metrics::noop(),
+            inspector: None,
+            config: PoolConfig::default(),
+        }
+    }
+}
+
+impl MqttSinkPool {
+    /// New pool with connection metrics, other hooks are preserved
+    pub(super) fn with_metrics(&self, metrics: Metrics) -> Rc<Self> {
+        Rc::new(Self {
+            metrics,
+            inspector: self.inspector.clone(),
+            config: self.config,
+            ..Default::default()
+        })
+    }
+
+    /// New pool with packet inspector, other hooks are preserved
+    pub(super) fn with_inspector(
+        &self,
+        inspector: Rc<dyn PacketInspector<codec::Packet>>,
+    ) -> Rc<Self> {
+        Rc::new(Self {
+            metrics: self.metrics.clone(),
+            inspector: Some(inspector),
+            config: self.config,
+            ..Default::default()
+        })
+    }
+
+    /// New pool with configuration, hooks are preserved
+    pub(super) fn with_config(&self, config: PoolConfig) -> Rc<Self> {
+        Rc::new(Self {
+            metrics: self.metrics.clone(),
+            inspector: self.inspector.clone(),
+            config,
+            ..Default::default()
+        })
+    }
+
+    /// New connection state with configured buffer params
+    pub(super) fn state(&self) -> State {
+        let state = State::new();
+        state.set_buffer_params(self.config.read_hw, self.config.write_hw, self.config.lw);
+        state
+    }
+}
+
+impl MqttSharedQueues {
+    /// Check if in-flight queue outgrew configured capacity
+    pub(super) fn exceeds_capacity(&self) -> bool {
+        self.inflight.capacity() > self.inflight_cap
+    }
+
+    /// Check if packet id is used by in-flight or expired packet
+    pub(super) fn id_in_use(&self, idx: u16) -> bool {
+        self.ids.contains(idx)
+    }
+
+    /// Register in-flight packet
+    pub(super) fn insert_inflight(&mut self, idx: u16, tx: pool::Sender<Ack>, ack: AckType) {
+        self.inflight.insert(idx, (tx, ack));
+        self.inflight_order.push_back(idx);
+        self.ids.insert(idx);
+    }
+
+    /// Remove in-flight packet, id of expired packet stays in use
+    pub(super) fn remove_inflight(&mut self, idx: u16) -> Option<(pool::Sender<Ack>, AckType)> {
+        let item = self.inflight.remove(&idx);
+        if item.is_some() && !self.expired.contains(&idx) {
+            self.ids.remove(idx);
+        }
+        item
+    }
+
+    /// Remove all in-flight packets, ids of expired packets stay in use
+    pub(super) fn clear_inflight(&mut self) {
+        for idx in self.inflight.keys() {
+            if !self.expired.contains(idx) {
+                self.ids.remove(*idx);
+            }
+        }
+        self.inflight.clear();
+    }
+
+    /// Release packet id of in-flight packet, late ack from the peer is ignored
+    pub(super) fn insert_expired(&mut self, idx: u16) {
+        self.expired.insert(idx);
+        self.ids.insert(idx);
+    }
+
+    /// Remove expired packet id, returns false if id is not expired
+    pub(super) fn remove_expired(&mut self, idx: u16) -> bool {
+        if self.expired.remove(&idx) {
+            if !self.inflight.contains_key(&idx) {
+                self.ids.remove(idx);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Remove packet id from in-flight order queue
+    ///
+    /// Returns id of the next expected packet for strict order,
+    /// or `packet_id` if it is in-flight for unordered acks.
+    pub(super) fn pop_inflight(&mut self, packet_id: u16, order: AckOrder) -> Option<u16> {
+        // skip expired in-flight packets
+        while self.inflight_order.front() == Some(&0) {
+            self.inflight_order.pop_front();
+        }
+
+        match order {
+            AckOrder::Strict => self.inflight_order.pop_front(),
+            AckOrder::Unordered => {
+                let pos = self.inflight_order.iter().position(|idx| *idx == packet_id)?;
+                self.inflight_order.remove(pos)
+            }
+        }
     }
 }
 
@@ -39,34 +270,231 @@ impl MqttShared {
         codec: codec::Codec,
         cap: usize,
         pool: Rc<MqttSinkPool>,
+        server: bool,
     ) -> Self {
+        let inflight =
+            HashMap::with_capacity_and_hasher(pool.config.inflight, Default::default());
+
         Self {
+            server,
             state,
             pool,
             codec,
             cap: Cell::new(cap),
             queues: RefCell::new(MqttSharedQueues {
-                inflight: HashMap::default(),
-                inflight_order: VecDeque::with_capacity(8),
+                inflight_cap: inflight.capacity(),
+                inflight,
+                inflight_order: VecDeque::with_capacity(pool.config.inflight),
+                expired: HashSet::default(),
+                ids: PacketIds::default(),
                 waiters: VecDeque::new(),
+                flush: Vec::new(),
+                auth: None,
+                aliases: HashMap::default(),
+                requests: HashMap::default(),
+                response_topics: HashSet::default(),
             }),
             inflight_idx: Cell::new(0),
+            ack_timeout: Cell::new(None),
+            cancel_policy: Cell::new(CancelPolicy::default()),
+            ack_order: Cell::new(AckOrder::default()),
+            ping_pending: Cell::new(false),
+            request_idx: Cell::new(0),
+            topic_alias: Cell::new(true),
+            topic_alias_max: Cell::new(0),
+            caps: Cell::new(Capabilities::default()),
+            session_expiry: Cell::new(0),
+            last_will: RefCell::new(None),
+            registry: RefCell::new(None),
+            takeover: RefCell::new(None),
+            events: EventQueue::default(),
+            released: RefCell::new(None),
+            received: RefCell::new(HashSet::default()),
+            received_hook: RefCell::new(None),
+            store: RefCell::new(None),
+            connection: RefCell::new(None),
+            peer_addr: Cell::new(None),
+            local_addr: Cell::new(None),
+            span: RefCell::new(Span::default()),
+            close: CloseState::default(),
+            read_pause: ReadPause::default(),
+        }
+    }
+
+    /// Call `f` with session store of the connection, if store is set
+    pub(super) fn with_store<F: FnOnce(&ConnectionStore)>(&self, f: F) {
+        if let Some(ref store) = *self.store.borrow() {
+            f(store)
         }
     }
 
     pub(super) fn has_credit(&self) -> bool {
-        self.cap.get() - self.queues.borrow().inflight.len() > 0
+        // in-flight queue could exceed cap, for example after session restore
+        self.queues.borrow().inflight.len() < self.cap.get()
     }
 
-    pub(super) fn next_id(&self) -> u16 {
-        let idx = self.inflight_idx.get() + 1;
-        self.inflight_idx.set(idx);
-        if idx == u16::max_value() {
-            self.inflight_idx.set(0);
-            u16::max_value()
+    /// Wait for receive credit
+    ///
+    /// Returns `CreditTimeout` error if credit is not available in time.
+    pub(super) async fn wait_credit(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<(), SendPacketError> {
+        let (tx, rx) = self.pool.waiters.channel();
+        self.queues.borrow_mut().waiters.push_back(tx);
+
+        if let Some(timeout) = timeout {
+            match select(delay_for(timeout), rx).await {
+                Either::Left(_) => {
+                    // credit could be released at the same time, pass it to next waiter
+                    if self.has_credit() {
+                        let mut queues = self.queues.borrow_mut();
+                        while let Some(tx) = queues.waiters.pop_front() {
+                            if tx.send(()).is_ok() {
+                                break;
+                            }
+                        }
+                    }
+                    Err(SendPacketError::CreditTimeout)
+                }
+                Either::Right(res) => res.map_err(|_| SendPacketError::Disconnected),
+            }
+        } else {
+            rx.await.map_err(|_| SendPacketError::Disconnected)
+        }
+    }
+
+    /// Assign topic alias to outgoing publish packet
+    pub(super) fn topic_alias(&self, queues: &mut MqttSharedQueues, pkt: &mut codec::Publish) {
+        let max = self.topic_alias_max.get();
+        if max == 0
+            || !self.topic_alias.get()
+            || pkt.topic.is_empty()
+            || pkt.properties.topic_alias.is_some()
+        {
+            return;
+        }
+
+        if let Some(alias) = queues.aliases.get(&pkt.topic) {
+            // topic is known to the peer, send alias only
+            pkt.properties.topic_alias = Some(*alias);
+            pkt.topic = ByteString::new();
+        } else if queues.aliases.len() < max as usize {
+            // register new alias
+            let alias = NonZeroU16::new(queues.aliases.len() as u16 + 1).unwrap();
+            queues.aliases.insert(pkt.topic.clone(), alias);
+            pkt.properties.topic_alias = Some(alias);
+        }
+    }
+
+    /// Generate correlation data for request
+    pub(super) fn next_correlation_data(&self) -> Bytes {
+        let idx = self.request_idx.get().wrapping_add(1);
+        self.request_idx.set(idx);
+        Bytes::copy_from_slice(&idx.to_be_bytes())
+    }
+
+    /// Release in-flight packet, late ack from the peer is ignored
+    pub(super) fn expire_inflight(&self, idx: u16) {
+        let mut queues = self.queues.borrow_mut();
+        if queues.remove_inflight(idx).is_some() {
+            log::trace!("Release in-flight packet with id: {}", idx);
+            if let Some(item) = queues.inflight_order.iter_mut().find(|item| **item == idx) {
+                *item = 0;
+            }
+            queues.insert_expired(idx);
+
+            // wake up queued request (receive max limit)
+            while let Some(tx) = queues.waiters.pop_front() {
+                if tx.send(()).is_ok() {
+                    break;
+                }
+            }
+            // notify flush waiters
+            if queues.inflight.is_empty() {
+                for tx in queues.flush.drain(..) {
+                    let _ = tx.send(());
+                }
+            }
+        }
+    }
+
+    /// Wait ack from the peer
+    ///
+    /// In-flight packet is released if ack is not received in time.
+    pub(super) async fn wait_ack(
+        &self,
+        idx: u16,
+        rx: pool::Receiver<Ack>,
+        timeout: Option<Duration>,
+    ) -> Result<Ack, SendPacketError> {
+        // cancel policy is applied if future is dropped
+        let guard = InflightGuard::new(self, idx);
+        let start = Instant::now();
+
+        let result = if let Some(timeout) = timeout.or_else(|| self.ack_timeout.get()) {
+            match select(delay_for(timeout), rx).await {
+                Either::Left(_) => {
+                    self.expire_inflight(idx);
+                    Err(SendPacketError::AckTimeout)
+                }
+                Either::Right(res) => res.map_err(|_| SendPacketError::Disconnected),
+            }
         } else {
-            self.inflight_idx.set(idx);
-            idx
+            rx.await.map_err(|_| SendPacketError::Disconnected)
+        };
+        guard.disarm();
+
+        if let Ok(ref ack) = result {
+            self.pool.metrics.ack_latency(ack.packet_type(), start.elapsed());
+        }
+        result
+    }
+
+    /// Allocate packet id that is not used by in-flight or expired packets
+    ///
+    /// Returns `PacketIdsExhausted` error if all packet ids are in use.
+    pub(super) fn next_id(&self, queues: &MqttSharedQueues) -> Result<u16, SendPacketError> {
+        let idx = queues
+            .ids
+            .next_free(self.inflight_idx.get())
+            .ok_or(SendPacketError::PacketIdsExhausted)?;
+        self.inflight_idx.set(idx);
+        Ok(idx)
+    }
+}
+
+/// Applies cancel policy to in-flight packet if its future is dropped before ack
+pub(super) struct InflightGuard<'a>(&'a MqttShared, u16);
+
+impl<'a> InflightGuard<'a> {
+    pub(super) fn new(shared: &'a MqttShared, idx: u16) -> Self {
+        InflightGuard(shared, idx)
+    }
+
+    /// Ack is received, nothing to do
+    pub(super) fn disarm(self) {
+        mem::forget(self)
+    }
+}
+
+impl<'a> Drop for InflightGuard<'a> {
+    fn drop(&mut self) {
+        if self.0.cancel_policy.get() == CancelPolicy::Forget {
+            self.0.expire_inflight(self.1);
+        }
+    }
+}
+
+impl Drop for MqttShared {
+    fn drop(&mut self) {
+        // pools grow with peak number of in-flight packets, shrink them
+        // only if the connection exceeded configured capacity
+        if self.pool.config.shrink && self.queues.get_mut().exceeds_capacity() {
+            self.pool.queue.shrink_to_fit();
+            self.pool.waiters.shrink_to_fit();
+            self.pool.auth.shrink_to_fit();
+            self.pool.response.shrink_to_fit();
         }
     }
 }
@@ -77,7 +505,14 @@ impl Encoder for MqttShared {
 
     #[inline]
     fn encode(&self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        self.codec.encode(item, dst)
+        if let Some(ref inspector) = self.pool.inspector {
+            inspector.outbound(&item);
+        }
+        let tp = item.packet_type();
+        let len = dst.len();
+        self.codec.encode(item, dst)?;
+        self.pool.metrics.packet_sent(tp, dst.len() - len);
+        Ok(())
     }
 }
 
@@ -87,19 +522,31 @@ impl Decoder for MqttShared {
 
     #[inline]
     fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        self.codec.decode(src)
+        let len = src.len();
+        let item = self.codec.decode(src)?;
+        if let Some(ref pkt) = item {
+            self.pool.metrics.packet_received(pkt.packet_type(), len - src.len());
+            if let Some(ref inspector) = self.pool.inspector {
+                inspector.inbound(pkt);
+            }
+        }
+        Ok(item)
     }
 }
 
 #[derive(Copy, Clone)]
 pub(super) enum AckType {
     Publish,
+    Receive,
+    Complete,
     Subscribe,
     Unsubscribe,
 }
 
 pub(super) enum Ack {
     Publish(codec::PublishAck),
+    Receive(codec::PublishAck),
+    Complete(codec::PublishAck2),
     Subscribe(codec::SubscribeAck),
     Unsubscribe(codec::UnsubscribeAck),
 }
@@ -108,6 +555,8 @@ impl Ack {
     pub(super) fn packet_type(&self) -> u8 {
         match self {
             Ack::Publish(_) => packet_type::PUBACK,
+            Ack::Receive(_) => packet_type::PUBREC,
+            Ack::Complete(_) => packet_type::PUBCOMP,
             Ack::Subscribe(_) => packet_type::SUBACK,
             Ack::Unsubscribe(_) => packet_type::UNSUBACK,
         }
@@ -116,6 +565,8 @@ impl Ack {
     pub(super) fn packet_id(&self) -> u16 {
         match self {
             Ack::Publish(ref pkt) => pkt.packet_id.get(),
+            Ack::Receive(ref pkt) => pkt.packet_id.get(),
+            Ack::Complete(ref pkt) => pkt.packet_id.get(),
             Ack::Subscribe(ref pkt) => pkt.packet_id.get(),
             Ack::Unsubscribe(ref pkt) => pkt.packet_id.get(),
         }
@@ -129,6 +580,14 @@ impl Ack {
         }
     }
 
+    pub(super) fn publish_qos2(self) -> Result<codec::PublishAck2, codec::PublishAck> {
+        match self {
+            Ack::Complete(pkt) => Ok(pkt),
+            Ack::Receive(pkt) => Err(pkt),
+            _ => panic!(),
+        }
+    }
+
     pub(super) fn subscribe(self) -> codec::SubscribeAck {
         if let Ack::Subscribe(pkt) = self {
             pkt
@@ -148,6 +607,8 @@ impl Ack {
     pub(super) fn is_match(&self, tp: AckType) -> bool {
         match (self, tp) {
             (Ack::Publish(_), AckType::Publish) => true,
+            (Ack::Receive(_), AckType::Receive) => true,
+            (Ack::Complete(_), AckType::Complete) => true,
             (Ack::Subscribe(_), AckType::Subscribe) => true,
             (Ack::Unsubscribe(_), AckType::Unsubscribe) => true,
             (_, _) => false,
@@ -159,8 +620,68 @@ impl AckType {
     pub(super) fn name(&self) -> &'static str {
         match self {
             AckType::Publish => "PublishAck",
+            AckType::Receive => "PublishReceived",
+            AckType::Complete => "PublishComplete",
             AckType::Subscribe => "SubscribeAck",
             AckType::Unsubscribe => "UnsubscribeAck",
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_id() {
+        let shared = MqttShared::new(
+            State::new(),
+            codec::Codec::default(),
+            16,
+            Rc::new(MqttSinkPool::default()),
+            true,
+        );
+        let mut queues = shared.queues.borrow_mut();
+        assert_eq!(shared.next_id(&queues), Ok(1));
+
+        // in-use ids are skipped
+        let (tx, _rx) = shared.pool.queue.channel();
+        queues.insert_inflight(2, tx, AckType::Receive);
+        queues.insert_expired(3);
+        assert_eq!(shared.next_id(&queues), Ok(4));
+
+        // wrap-around skips ids that are still in-flight
+        shared.inflight_idx.set(u16::max_value() - 1);
+        queues.insert_expired(1);
+        assert_eq!(shared.next_id(&queues), Ok(u16::max_value()));
+        assert_eq!(shared.next_id(&queues), Ok(4));
+
+        // id of closed connection's in-flight packet is released, expired id is kept
+        queues.clear_inflight();
+        assert!(!queues.id_in_use(2));
+        assert!(queues.id_in_use(3));
+
+        // all ids are in use
+        (1..=u16::max_value()).for_each(|idx| queues.insert_expired(idx));
+        assert_eq!(shared.next_id(&queues), Err(SendPacketError::PacketIdsExhausted));
+    }
+
+    #[test]
+    fn test_credit_overflow() {
+        let shared = Rc::new(MqttShared::new(
+            State::new(),
+            codec::Codec::default(),
+            1,
+            Rc::new(MqttSinkPool::default()),
+            true,
+        ));
+        let mut rxs = Vec::new();
+        for idx in 1..=2 {
+            let (tx, rx) = shared.pool.queue.channel();
+            shared.queues.borrow_mut().insert_inflight(idx, tx, AckType::Receive);
+            rxs.push(rx);
+        }
+        assert!(!shared.has_credit());
+        assert_eq!(super::super::MqttSink::new(shared).credit(), 0);
+    }
+}
@@ -1,11 +1,24 @@
-use std::{fmt, future::Future, num::NonZeroU16, num::NonZeroU32, rc::Rc};
+use std::rc::{Rc, Weak};
+use std::task::{Context, Poll};
+use std::{convert::TryInto, fmt, future::Future, marker, pin::Pin, time::Duration};
+use std::{num::NonZeroU16, num::NonZeroU32};
 
-use ntex::util::{ByteString, Bytes, Either};
+use ntex::channel::pool;
+use ntex::rt::time::delay_for;
+use ntex::util::{join_all, select, ByteString, Bytes, Either, Ready};
+#[cfg(any(feature = "json", feature = "cbor"))]
+use serde::Serialize;
 
-use super::codec;
-use super::error::{ProtocolError, PublishQos1Error, SendPacketError};
-use super::shared::{Ack, AckType, MqttShared};
-use crate::types::QoS;
+use super::error::{
+    ProtocolError, PublishQos1Error, PublishQos2Error, RequestError, SendPacketError,
+};
+use super::handle::MqttSinkHandle;
+use super::publish::Publish;
+use super::shared::{Ack, AckType, InflightGuard, MqttShared};
+use super::store::SessionState;
+use super::{codec, control};
+use crate::topic::{TopicError, TopicFilter, TopicName};
+use crate::types::{AckOrder, CancelPolicy, QoS};
 
 pub struct MqttSink(Rc<MqttShared>);
 
@@ -25,10 +38,57 @@ impl MqttSink {
         self.0.state.is_open()
     }
 
+    /// Get session expiry interval in seconds
+    ///
+    /// Value `u32::MAX` means that session does not expire.
+    pub fn session_expiry_interval(&self) -> u32 {
+        self.0.session_expiry.get()
+    }
+
     /// Get client's receive credit
     pub fn credit(&self) -> usize {
         let cap = self.0.cap.get();
-        cap - self.0.queues.borrow().inflight.len()
+        cap.saturating_sub(self.0.queues.borrow().inflight.len())
+    }
+
+    /// Get number of in-flight packets
+    pub fn inflight(&self) -> usize {
+        self.0.queues.borrow().inflight.len()
+    }
+
+    /// Get number of senders waiting for receive credit
+    ///
+    /// Number could include waiters that are already canceled.
+    pub fn waiters(&self) -> usize {
+        self.0.queues.borrow().waiters.len()
+    }
+
+    /// Get peer's receive maximum
+    pub fn receive_max(&self) -> usize {
+        self.0.cap.get()
+    }
+
+    /// Get peer's maximum packet size, `0` means unlimited
+    pub fn max_packet_size(&self) -> u32 {
+        self.0.codec.get_max_outbound_size()
+    }
+
+    /// Get peer's topic alias maximum
+    pub fn topic_alias_max(&self) -> u16 {
+        self.0.topic_alias_max.get()
+    }
+
+    /// Get notification when packet could be send to the peer, with timeout
+    ///
+    /// Result is `false` if connection is closed or credit is not available in time
+    pub fn ready_timeout(&self, timeout: Duration) -> impl Future<Output = bool> {
+        let fut = self.ready();
+        async move {
+            match select(delay_for(timeout), fut).await {
+                Either::Left(_) => false,
+                Either::Right(res) => res,
+            }
+        }
     }
 
     /// Get notification when packet could be send to the peer.
@@ -48,55 +108,334 @@ impl MqttSink {
         Either::Left(async move { result })
     }
 
+    /// Get notification when all in-flight packets get acknowledged
+    ///
+    /// Result indicates if connection is alive
+    pub fn flush(&self) -> impl Future<Output = bool> {
+        let mut queues = self.0.queues.borrow_mut();
+        let result = if !self.is_open() {
+            false
+        } else if !queues.inflight.is_empty() {
+            let (tx, rx) = self.0.pool.waiters.channel();
+            queues.flush.push(tx);
+            return Either::Right(async move { rx.await.is_ok() });
+        } else {
+            true
+        };
+        Either::Left(async move { result })
+    }
+
+    /// Set ack timeout for publish packets
+    ///
+    /// If peer does not acknowledge publish packet in time, publish future resolves
+    /// with `AckTimeout` error and packet id is released. Late ack from the peer
+    /// is ignored. By default ack timeout is not set.
+    pub fn set_ack_timeout(&self, timeout: Option<Duration>) {
+        self.0.ack_timeout.set(timeout);
+    }
+
+    /// Set in-flight packet handling for dropped publish futures
+    ///
+    /// By default packet id and receive credit are released.
+    pub fn set_cancel_policy(&self, policy: CancelPolicy) {
+        self.0.cancel_policy.set(policy);
+    }
+
+    /// Set matching of peer's acks to in-flight packets
+    ///
+    /// Some brokers acknowledge concurrently processed publishes out of
+    /// order. By default acks must arrive in order.
+    pub fn set_ack_order(&self, order: AckOrder) {
+        self.0.ack_order.set(order);
+    }
+
+    /// Create thread-safe handle for the sink
+    ///
+    /// Must be called on connection's thread. Handle forwards requests
+    /// to connection's thread until all handles are dropped.
+    pub fn handle(&self) -> MqttSinkHandle {
+        MqttSinkHandle::new(&self.0)
+    }
+
+    /// Create sender of application events
+    ///
+    /// Events are delivered to connection's control service as
+    /// `ControlMessage::Event` messages. Sender does not keep connection alive.
+    pub fn events<T: 'static>(&self) -> EventSender<T> {
+        EventSender(Rc::downgrade(&self.0), marker::PhantomData)
+    }
+
+    /// Pause processing of inbound packets
+    ///
+    /// Connection stops reading from the socket once read buffer is full,
+    /// peer's packets, including PINGREQ, are not handled until reading is
+    /// resumed with `resume_read()`.
+    pub fn pause_read(&self) {
+        self.0.read_pause.pause()
+    }
+
+    /// Resume processing of inbound packets
+    pub fn resume_read(&self) {
+        self.0.read_pause.resume()
+    }
+
+    /// Check if processing of inbound packets is paused
+    pub fn is_read_paused(&self) -> bool {
+        self.0.read_pause.is_paused()
+    }
+
     /// Close mqtt connection with default Disconnect message
     pub fn close(&self) {
+        self.0.close.local();
         if self.is_open() {
             let _ = self
                 .0
                 .state
                 .write()
-                .encode(codec::Packet::Disconnect(codec::Disconnect::default()), &self.0.codec);
+                .encode(codec::Packet::Disconnect(codec::Disconnect::default()), &*self.0);
             self.0.state.close();
         }
         let mut queues = self.0.queues.borrow_mut();
         queues.waiters.clear();
-        queues.inflight.clear();
+        queues.flush.clear();
+        queues.clear_inflight();
+        queues.auth.take();
+        queues.requests.clear();
     }
 
     /// Close mqtt connection
     pub fn close_with_reason(&self, pkt: codec::Disconnect) {
+        self.0.close.local();
         if self.is_open() {
-            let _ = self.0.state.write().encode(codec::Packet::Disconnect(pkt), &self.0.codec);
+            let _ = self.0.state.write().encode(codec::Packet::Disconnect(pkt), &*self.0);
             self.0.state.close();
         }
         let mut queues = self.0.queues.borrow_mut();
         queues.waiters.clear();
-        queues.inflight.clear();
+        queues.flush.clear();
+        queues.clear_inflight();
+        queues.auth.take();
+        queues.requests.clear();
+    }
+
+    /// Close mqtt connection with specified reason code
+    ///
+    /// Sends Disconnect packet with reason code, optional reason string
+    /// and user properties before closing connection.
+    pub fn close_with_code(
+        &self,
+        reason_code: codec::DisconnectReasonCode,
+        reason_string: Option<ByteString>,
+        user_properties: codec::UserProperties,
+    ) {
+        self.close_with_reason(codec::Disconnect {
+            reason_code,
+            reason_string,
+            user_properties,
+            ..codec::Disconnect::default()
+        })
+    }
+
+    /// Disconnect from the peer with specified reason code
+    ///
+    /// Disconnect packet properties, i.e. session expiry interval or user
+    /// properties, could be set with `f`. Connection is closed after
+    /// Disconnect packet is sent.
+    pub fn disconnect<F>(&self, reason_code: codec::DisconnectReasonCode, f: F)
+    where
+        F: FnOnce(&mut codec::Disconnect),
+    {
+        let mut pkt = codec::Disconnect { reason_code, ..codec::Disconnect::default() };
+        f(&mut pkt);
+        self.close_with_reason(pkt)
+    }
+
+    /// Close mqtt connection and redirect peer to another server
+    ///
+    /// Sends Disconnect packet with `UseAnotherServer` reason code, or with
+    /// `ServerMoved` if `permanent` is true, and server reference property.
+    pub fn redirect(&self, server_reference: ByteString, permanent: bool) {
+        let reason_code = if permanent {
+            codec::DisconnectReasonCode::ServerMoved
+        } else {
+            codec::DisconnectReasonCode::UseAnotherServer
+        };
+        self.close_with_reason(codec::Disconnect {
+            reason_code,
+            server_reference: Some(server_reference),
+            ..codec::Disconnect::default()
+        })
+    }
+
+    pub(super) fn shared(&self) -> &MqttShared {
+        &self.0
     }
 
     pub(super) fn send(&self, pkt: codec::Packet) {
-        let _ = self.0.state.write().encode(pkt, &self.0.codec);
+        let _ = self.0.state.write().encode(pkt, &*self.0);
     }
 
     /// Send ping
     pub(super) fn ping(&self) -> bool {
-        self.0.state.write().encode(codec::Packet::PingRequest, &self.0.codec).is_ok()
+        self.0.ping_pending.set(true);
+        self.0.state.write().encode(codec::Packet::PingRequest, &*self.0).is_ok()
+    }
+
+    /// Check if ping response is not received yet
+    pub(super) fn is_ping_pending(&self) -> bool {
+        self.0.ping_pending.get()
+    }
+
+    pub(super) fn pkt_ping_response(&self) {
+        self.0.ping_pending.set(false);
     }
 
     /// Close mqtt connection, dont send disconnect message
     pub(super) fn drop_sink(&self) {
+        self.0.close.local();
         let mut queues = self.0.queues.borrow_mut();
         queues.waiters.clear();
-        queues.inflight.clear();
+        queues.flush.clear();
+        queues.clear_inflight();
+        queues.auth.take();
+        queues.requests.clear();
         self.0.state.close();
     }
 
+    /// Enable or disable automatic topic aliases for outgoing publishes
+    ///
+    /// Topic aliases are enabled by default, if peer's topic alias maximum is not 0.
+    /// Repeated publishes to the same topic use topic alias instead of topic name.
+    pub fn topic_alias(&self, enabled: bool) {
+        self.0.topic_alias.set(enabled);
+    }
+
+    /// Re-authenticate client with new authentication data
+    ///
+    /// Sends AUTH packet with re-authenticate reason code and drives authentication
+    /// exchange until server responds with AUTH packet with reason code other
+    /// than `ContinueAuth`. Intermediate AUTH packets are passed to `f` function,
+    /// function must return next AUTH packet.
+    ///
+    /// Only one authentication exchange could be active at a time.
+    pub async fn reauthenticate<F>(
+        &self,
+        auth_method: ByteString,
+        auth_data: Bytes,
+        mut f: F,
+    ) -> Result<codec::Auth, SendPacketError>
+    where
+        F: FnMut(codec::Auth) -> codec::Auth,
+    {
+        let mut pkt = codec::Auth {
+            reason_code: codec::AuthReasonCode::ReAuth,
+            auth_method: Some(auth_method),
+            auth_data: Some(auth_data),
+            ..Default::default()
+        };
+
+        loop {
+            let rx = {
+                let mut queues = self.0.queues.borrow_mut();
+                if !self.is_open() {
+                    return Err(SendPacketError::Disconnected);
+                }
+                if queues.auth.is_some() {
+                    return Err(SendPacketError::AuthInProgress);
+                }
+                let (tx, rx) = self.0.pool.auth.channel();
+                queues.auth = Some(tx);
+
+                log::trace!("Sending auth packet {:#?}", pkt);
+                if let Err(err) =
+                    self.0.state.write().encode(codec::Packet::Auth(pkt), &*self.0)
+                {
+                    queues.auth.take();
+                    return Err(SendPacketError::Encode(err));
+                }
+                rx
+            };
+
+            // wait auth response from peer
+            let ack = rx.await.map_err(|_| SendPacketError::Disconnected)?;
+            if ack.reason_code == codec::AuthReasonCode::ContinueAuth {
+                pkt = f(ack);
+            } else {
+                return Ok(ack);
+            }
+        }
+    }
+
+    /// Deliver response publish to pending request
+    pub(super) fn pkt_response(&self, pkt: codec::Publish) -> Result<(), codec::Publish> {
+        let mut queues = self.0.queues.borrow_mut();
+        if !queues.response_topics.contains(&pkt.topic) {
+            return Err(pkt);
+        }
+        let tx = pkt
+            .properties
+            .correlation_data
+            .as_ref()
+            .and_then(|data| queues.requests.remove(data));
+
+        if let Some(tx) = tx {
+            log::trace!("Response for request: {:?}", pkt.properties.correlation_data);
+            let _ = tx.send(pkt);
+            Ok(())
+        } else {
+            Err(pkt)
+        }
+    }
+
+    /// Deliver AUTH packet to active authentication exchange
+    pub(super) fn pkt_auth(&self, pkt: codec::Auth) -> Result<(), codec::Auth> {
+        if let Some(tx) = self.0.queues.borrow_mut().auth.take() {
+            let _ = tx.send(pkt);
+            Ok(())
+        } else {
+            Err(pkt)
+        }
+    }
+
     pub(super) fn pkt_ack(&self, pkt: Ack) -> Result<(), ProtocolError> {
         let mut queues = self.0.queues.borrow_mut();
 
+        // late ack of expired in-flight packet
+        if queues.remove_expired(pkt.packet_id()) {
+            log::trace!("Ack for expired packet with id: {}", pkt.packet_id());
+            if let Ack::Receive(ref ack) = pkt {
+                if u8::from(ack.reason_code) < 0x80 {
+                    // complete qos2 flow, keep packet id until PUBCOMP
+                    let packet_id = ack.packet_id;
+                    queues.insert_expired(packet_id.get());
+                    drop(queues);
+                    self.0.with_store(|store| store.release_publish(packet_id));
+
+                    return self
+                        .0
+                        .state
+                        .write()
+                        .encode(
+                            codec::Packet::PublishRelease(codec::PublishAck2 {
+                                packet_id,
+                                reason_code: codec::PublishAck2Reason::Success,
+                                properties: codec::UserProperties::default(),
+                                reason_string: None,
+                            }),
+                            &*self.0,
+                        )
+                        .map(|_| ())
+                        .map_err(ProtocolError::Encode);
+                }
+            }
+            drop(queues);
+            self.publish_completed(&pkt);
+            return Ok(());
+        }
+
         loop {
             // check ack order
-            if let Some(idx) = queues.inflight_order.pop_front() {
+            if let Some(idx) = queues.pop_inflight(pkt.packet_id(), self.0.ack_order.get()) {
                 // errored publish
                 if idx == 0 {
                     continue;
@@ -112,7 +451,7 @@ impl MqttSink {
                     // get publish ack channel
                     log::trace!("Ack packet with id: {}", pkt.packet_id());
                     let idx = pkt.packet_id();
-                    if let Some((tx, tp)) = queues.inflight.remove(&idx) {
+                    if let Some((tx, tp)) = queues.remove_inflight(idx) {
                         // cleanup ack queue
                         if !pkt.is_match(tp) {
                             log::trace!("MQTT protocol error, unexpeted packet");
@@ -121,6 +460,38 @@ impl MqttSink {
                                 tp.name(),
                             ));
                         }
+
+                        // qos2 publish is received, release it and keep quota until PUBCOMP
+                        if let Ack::Receive(ref ack) = pkt {
+                            if u8::from(ack.reason_code) < 0x80 {
+                                let packet_id = ack.packet_id;
+                                queues.insert_inflight(idx, tx, AckType::Complete);
+                                drop(queues);
+
+                                if let Some(hook) = &*self.0.released.borrow() {
+                                    hook(packet_id);
+                                }
+                                self.0.with_store(|store| store.release_publish(packet_id));
+
+                                log::trace!("Publish release (QoS2) packet id: {}", idx);
+                                return self
+                                    .0
+                                    .state
+                                    .write()
+                                    .encode(
+                                        codec::Packet::PublishRelease(codec::PublishAck2 {
+                                            packet_id,
+                                            reason_code: codec::PublishAck2Reason::Success,
+                                            properties: codec::UserProperties::default(),
+                                            reason_string: None,
+                                        }),
+                                        &*self.0,
+                                    )
+                                    .map(|_| ())
+                                    .map_err(ProtocolError::Encode);
+                            }
+                        }
+                        self.publish_completed(&pkt);
                         let _ = tx.send(pkt);
 
                         // wake up queued request (receive max limit)
@@ -129,6 +500,13 @@ impl MqttSink {
                                 break;
                             }
                         }
+
+                        // notify flush waiters
+                        if queues.inflight.is_empty() {
+                            for tx in queues.flush.drain(..) {
+                                let _ = tx.send(());
+                            }
+                        }
                         return Ok(());
                     } else {
                         log::error!("Inflight state inconsistency")
@@ -141,25 +519,247 @@ impl MqttSink {
         }
     }
 
+    /// Remove acknowledged or completed publish from session store
+    fn publish_completed(&self, pkt: &Ack) {
+        if let Ack::Publish(_) | Ack::Receive(_) | Ack::Complete(_) = pkt {
+            if let Some(packet_id) = NonZeroU16::new(pkt.packet_id()) {
+                self.0.with_store(|store| store.ack_publish(packet_id));
+            }
+        }
+    }
+
+    /// Restore stored session state
+    pub(super) fn restore(&self, st: SessionState) {
+        self.0.with_store(|store| store.restore_received(st.received));
+
+        // packet ids of stored publishes must not be re-used
+        let ids = st.publishes.iter().filter_map(|p| p.packet_id);
+        if let Some(max) = ids.chain(st.releases.iter().copied()).max() {
+            if max.get() > self.0.inflight_idx.get() {
+                self.0.inflight_idx.set(max.get());
+            }
+        }
+
+        // publishes received by the client get released
+        for packet_id in st.releases {
+            log::trace!("Re-send stored publish release: {:?}", packet_id);
+            let sink = self.clone();
+            ntex::rt::spawn(async move {
+                if let Err(err) = sink.publish_release(packet_id).await {
+                    log::trace!("Stored publish is not completed: {}", err);
+                }
+            });
+        }
+
+        for mut packet in st.publishes {
+            log::trace!("Re-send stored publish: {:?}", packet.packet_id);
+            packet.dup = true;
+            packet.properties.topic_alias = None;
+            let qos = packet.qos;
+            let builder = PublishBuilder {
+                packet,
+                shared: self.0.clone(),
+                ack_timeout: None,
+                credit_timeout: None,
+                error: None,
+            };
+            ntex::rt::spawn(async move {
+                if qos == QoS::ExactlyOnce {
+                    if let Err(err) = builder.send_exactly_once().await {
+                        log::trace!("Cannot re-send stored publish: {}", err);
+                    }
+                } else if let Err(err) = builder.send_at_least_once().await {
+                    log::trace!("Cannot re-send stored publish: {}", err);
+                }
+            });
+        }
+    }
+
     /// Create publish packet builder
+    ///
+    /// Topic could be `TopicName` or any value that converts to it, for example
+    /// `ByteString` or `&str`. Send methods fail with `InvalidTopic` error if
+    /// topic is empty or contains wildcards.
     pub fn publish<U>(&self, topic: U, payload: Bytes) -> PublishBuilder
     where
-        ByteString: From<U>,
+        U: TryInto<TopicName>,
+        TopicError: From<U::Error>,
     {
+        let (topic, error) = match topic.try_into() {
+            Ok(topic) => (topic.into_inner(), None),
+            Err(err) => (ByteString::new(), Some(SendPacketError::InvalidTopic(err.into()))),
+        };
         PublishBuilder {
             packet: codec::Publish {
                 payload,
                 dup: false,
                 retain: false,
-                topic: topic.into(),
+                topic,
                 qos: QoS::AtMostOnce,
                 packet_id: None,
                 properties: codec::PublishProperties::default(),
             },
             shared: self.0.clone(),
+            ack_timeout: None,
+            credit_timeout: None,
+            error,
+        }
+    }
+
+    /// Publish batch of messages
+    ///
+    /// Publish packets are written to the write buffer in one pass and get flushed
+    /// to the peer together. Future resolves when all QoS 1 and QoS 2 messages
+    /// are acknowledged by the peer, with ack reason code for each message of the batch.
+    /// If peer's receive maximum is reached, remaining messages are written as soon
+    /// as credit is available.
+    pub fn publish_batch<I, U>(
+        &self,
+        batch: I,
+    ) -> impl Future<Output = Result<Vec<codec::PublishAckReason>, SendPacketError>>
+    where
+        I: IntoIterator<Item = (U, Bytes, QoS)>,
+        U: TryInto<TopicName>,
+        TopicError: From<U::Error>,
+    {
+        let sink = self.clone();
+        let batch = batch.into_iter();
+
+        async move {
+            let shared = sink.0.clone();
+            let mut acks = Vec::new();
+            let mut guards = Vec::new();
+
+            for (topic, payload, qos) in batch {
+                let mut builder = sink.publish(topic, payload);
+                builder.check_error()?;
+
+                let ack = match qos {
+                    QoS::AtMostOnce => {
+                        builder.send_at_most_once()?;
+                        acks.push(Either::Left(Ready::Ok(codec::PublishAckReason::Success)));
+                        continue;
+                    }
+                    QoS::AtLeastOnce => AckType::Publish,
+                    QoS::ExactlyOnce => AckType::Receive,
+                };
+
+                // handle client receive maximum
+                if shared.state.is_open() && !shared.has_credit() {
+                    shared.wait_credit(None).await?;
+                }
+                let (idx, rx) = builder.enqueue(qos, ack)?;
+                guards.push(InflightGuard::new(&shared, idx));
+                acks.push(Either::Right(wait_batch_ack(shared.clone(), idx, rx)));
+            }
+
+            // ack futures take over in-flight guards on first poll
+            let acks = join_all(acks);
+            guards.into_iter().for_each(InflightGuard::disarm);
+            acks.await.into_iter().collect()
+        }
+    }
+
+    /// Send publish release packet
+    ///
+    /// Resumes delivery of QoS 2 publish that is already received by the peer,
+    /// for example after re-connect. Future resolves with PUBCOMP packet from the peer.
+    pub async fn publish_release(
+        &self,
+        packet_id: NonZeroU16,
+    ) -> Result<codec::PublishAck2, PublishQos2Error> {
+        let shared = &self.0;
+        if !shared.state.is_open() {
+            return Err(PublishQos2Error::Disconnected);
+        }
+        if !shared.has_credit() {
+            shared.wait_credit(None).await?;
+        }
+
+        let idx = packet_id.get();
+        let mut queues = shared.queues.borrow_mut();
+        if queues.id_in_use(idx) {
+            return Err(PublishQos2Error::PacketIdInUse(idx));
+        }
+
+        log::trace!("Publish release (QoS2) packet id: {}", idx);
+        let pkt = codec::Packet::PublishRelease(codec::PublishAck2 {
+            packet_id,
+            reason_code: codec::PublishAck2Reason::Success,
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        });
+        shared.state.write().encode(pkt, &**shared).map_err(PublishQos2Error::Encode)?;
+
+        let (tx, rx) = shared.pool.queue.channel();
+        queues.insert_inflight(idx, tx, AckType::Complete);
+        drop(queues);
+
+        // wait PUBCOMP from peer
+        shared
+            .wait_ack(idx, rx, None)
+            .await
+            .map_err(From::from)
+            .and_then(|pkt| pkt.publish_qos2().map_err(PublishQos2Error::Fail))
+    }
+
+    /// Set QoS 2 publish release hook
+    pub(super) fn on_release<F>(&self, f: F)
+    where
+        F: Fn(NonZeroU16) + 'static,
+    {
+        *self.0.released.borrow_mut() = Some(Box::new(f));
+    }
+
+    /// Set inbound QoS 2 publish hook
+    pub(super) fn on_receive<F>(&self, f: F)
+    where
+        F: Fn(NonZeroU16, bool) + 'static,
+    {
+        *self.0.received_hook.borrow_mut() = Some(Box::new(f));
+    }
+
+    /// Restore packet ids of inbound QoS 2 publishes that are not released yet
+    pub(super) fn restore_received(&self, ids: Vec<NonZeroU16>) {
+        self.0.received.borrow_mut().extend(ids);
+    }
+
+    /// Create request packet builder
+    ///
+    /// Request is a publish packet with response topic and correlation data.
+    /// Response is a publish packet sent by peer to response topic with the same
+    /// correlation data.
+    pub fn request<U>(
+        &self,
+        topic: U,
+        response_topic: ByteString,
+        payload: Bytes,
+    ) -> RequestBuilder
+    where
+        U: TryInto<TopicName>,
+        TopicError: From<U::Error>,
+    {
+        RequestBuilder {
+            publish: self.publish(topic, payload),
+            response_topic,
+            correlation_data: None,
         }
     }
 
+    /// Create response publish packet builder for request publish
+    ///
+    /// Uses response topic and correlation data of the request. Returns `None`
+    /// if request does not contain response topic.
+    pub fn response(&self, request: &Publish, payload: Bytes) -> Option<PublishBuilder> {
+        let props = &request.packet().properties;
+        props.response_topic.clone().map(|topic| {
+            let correlation_data = props.correlation_data.clone();
+            self.publish(topic, payload).properties(|props| {
+                props.correlation_data = correlation_data;
+            })
+        })
+    }
+
     /// Create subscribe packet builder
     pub fn subscribe(&self, id: Option<NonZeroU32>) -> SubscribeBuilder {
         SubscribeBuilder {
@@ -171,6 +771,7 @@ impl MqttSink {
                 topic_filters: Vec::new(),
             },
             shared: self.0.clone(),
+            error: None,
         }
     }
 
@@ -184,10 +785,18 @@ impl MqttSink {
                 topic_filters: Vec::new(),
             },
             shared: self.0.clone(),
+            error: None,
         }
     }
 }
 
+impl<St> crate::Session<MqttSink, St> {
+    /// Get session expiry interval in seconds
+    pub fn session_expiry_interval(&self) -> u32 {
+        self.sink().session_expiry_interval()
+    }
+}
+
 impl fmt::Debug for MqttSink {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt.debug_struct("MqttSink").finish()
@@ -197,6 +806,9 @@ impl fmt::Debug for MqttSink {
 pub struct PublishBuilder {
     shared: Rc<MqttShared>,
     packet: codec::Publish,
+    ack_timeout: Option<Duration>,
+    credit_timeout: Option<Duration>,
+    error: Option<SendPacketError>,
 }
 
 impl PublishBuilder {
@@ -225,6 +837,12 @@ impl PublishBuilder {
         self
     }
 
+    /// Set ack timeout for this packet, overrides sink's ack timeout
+    pub fn ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = Some(timeout);
+        self
+    }
+
     /// Set publish packet properties
     pub fn properties<F>(mut self, f: F) -> Self
     where
@@ -234,6 +852,55 @@ impl PublishBuilder {
         self
     }
 
+    /// Add user property
+    pub fn user_property(mut self, key: ByteString, value: ByteString) -> Self {
+        self.packet.properties.user_properties.push((key, value));
+        self
+    }
+
+    /// Set message expiry interval in seconds
+    pub fn message_expiry_interval(mut self, secs: NonZeroU32) -> Self {
+        self.packet.properties.message_expiry_interval = Some(secs);
+        self
+    }
+
+    /// Set content type of the payload
+    pub fn content_type(mut self, val: ByteString) -> Self {
+        self.packet.properties.content_type = Some(val);
+        self
+    }
+
+    /// Set response topic
+    pub fn response_topic(mut self, val: ByteString) -> Self {
+        self.packet.properties.response_topic = Some(val);
+        self
+    }
+
+    /// Set correlation data
+    pub fn correlation_data(mut self, val: Bytes) -> Self {
+        self.packet.properties.correlation_data = Some(val);
+        self
+    }
+
+    /// Set payload format indicator, `true` for UTF-8 encoded payload
+    pub fn utf8_payload(mut self, val: bool) -> Self {
+        self.packet.properties.is_utf8_payload = Some(val);
+        self
+    }
+
+    /// Add subscription identifier
+    ///
+    /// Server sets identifiers of matching subscriptions when it forwards publish to a client.
+    /// Client must not send subscription identifiers (MQTT-3.3.4-6), publish
+    /// of client sink fails with `SubscriptionId` error.
+    pub fn subscription_id(mut self, id: NonZeroU32) -> Self {
+        if !self.shared.server {
+            self.error = self.error.take().or(Some(SendPacketError::SubscriptionId));
+        }
+        self.packet.properties.subscription_ids.get_or_insert_with(Vec::new).push(id);
+        self
+    }
+
     /// Set publish packet properties
     pub fn set_properties<F>(&mut self, f: F)
     where
@@ -242,16 +909,62 @@ impl PublishBuilder {
         f(&mut self.packet.properties);
     }
 
+    #[cfg(feature = "json")]
+    /// Set payload to `application/json` encoded value, sets content type property
+    pub fn json<T: Serialize>(mut self, value: &T) -> Result<Self, serde_json::Error> {
+        self.packet.payload = Bytes::from(serde_json::to_vec(value)?);
+        self.packet.properties.content_type = Some(ByteString::from_static("application/json"));
+        Ok(self)
+    }
+
+    #[cfg(feature = "cbor")]
+    /// Set payload to `application/cbor` encoded value, sets content type property
+    pub fn cbor<T: Serialize>(mut self, value: &T) -> Result<Self, serde_cbor::Error> {
+        self.packet.payload = Bytes::from(serde_cbor::to_vec(value)?);
+        self.packet.properties.content_type = Some(ByteString::from_static("application/cbor"));
+        Ok(self)
+    }
+
+    #[cfg(feature = "json")]
+    /// Send publish packet with `application/json` encoded payload and QoS 1
+    ///
+    /// Value is encoded immediately, content type property is set to
+    /// `application/json`. Serialization error is returned as
+    /// `PublishQos1Error::Serialize` error.
+    pub fn send_json<T: Serialize>(
+        self,
+        value: &T,
+    ) -> impl Future<Output = Result<codec::PublishAck, PublishQos1Error>> {
+        let res = self.json(value).map_err(|e| PublishQos1Error::Serialize(e.to_string()));
+        async move { res?.send_at_least_once().await }
+    }
+
+    #[cfg(feature = "cbor")]
+    /// Send publish packet with `application/cbor` encoded payload and QoS 1
+    ///
+    /// Value is encoded immediately, content type property is set to
+    /// `application/cbor`. Serialization error is returned as
+    /// `PublishQos1Error::Serialize` error.
+    pub fn send_cbor<T: Serialize>(
+        self,
+        value: &T,
+    ) -> impl Future<Output = Result<codec::PublishAck, PublishQos1Error>> {
+        let res = self.cbor(value).map_err(|e| PublishQos1Error::Serialize(e.to_string()));
+        async move { res?.send_at_least_once().await }
+    }
+
     /// Send publish packet with QoS 0
-    pub fn send_at_most_once(self) -> Result<(), SendPacketError> {
-        let packet = self.packet;
+    pub fn send_at_most_once(mut self) -> Result<(), SendPacketError> {
+        self.check_error()?;
+        let mut packet = self.packet;
 
         if self.shared.state.is_open() {
+            self.shared.topic_alias(&mut self.shared.queues.borrow_mut(), &mut packet);
             log::trace!("Publish (QoS-0) to {:?}", packet.topic);
             self.shared
                 .state
                 .write()
-                .encode(codec::Packet::Publish(packet), &self.shared.codec)
+                .encode(codec::Packet::Publish(packet), &*self.shared)
                 .map_err(SendPacketError::Encode)
                 .map(|_| ())
         } else {
@@ -260,61 +973,310 @@ impl PublishBuilder {
         }
     }
 
-    #[allow(clippy::await_holding_refcell_ref)]
+    /// Send publish packet with QoS 1, wait for receive credit with timeout
+    ///
+    /// Future resolves with `CreditTimeout` error if receive credit is not
+    /// available in time.
+    pub async fn send_at_least_once_timeout(
+        mut self,
+        timeout: Duration,
+    ) -> Result<codec::PublishAck, PublishQos1Error> {
+        self.credit_timeout = Some(timeout);
+        self.send_at_least_once().await
+    }
+
     /// Send publish packet with QoS 1
-    pub async fn send_at_least_once(self) -> Result<codec::PublishAck, PublishQos1Error> {
+    pub async fn send_at_least_once(mut self) -> Result<codec::PublishAck, PublishQos1Error> {
+        self.check_error()?;
+
+        // handle client receive maximum
+        if self.shared.state.is_open() && !self.shared.has_credit() {
+            self.shared.wait_credit(self.credit_timeout).await?;
+        }
+        let shared = self.shared.clone();
+        let timeout = self.ack_timeout;
+        let (idx, rx) = self.enqueue(QoS::AtLeastOnce, AckType::Publish)?;
+        wait_publish_ack(shared, idx, rx, timeout).await
+    }
+
+    /// Send publish packet with QoS 1 without waiting for ack
+    ///
+    /// Packet is encoded immediately, returned future resolves when publish
+    /// is acknowledged by the peer. Ack timeout starts when the future is
+    /// polled first time. Returns `NoCredit` error if receive credit
+    /// is not available.
+    pub fn send_at_least_once_detached(mut self) -> Result<AckFuture, PublishQos1Error> {
+        self.check_error()?;
+        if self.shared.state.is_open() && !self.shared.has_credit() {
+            return Err(PublishQos1Error::NoCredit);
+        }
+        let shared = self.shared.clone();
+        let timeout = self.ack_timeout;
+        let (idx, rx) = self.enqueue(QoS::AtLeastOnce, AckType::Publish)?;
+        Ok(AckFuture(Box::pin(wait_publish_ack(shared, idx, rx, timeout))))
+    }
+
+    /// Send publish packet with specified QoS, negative ack is returned as reason code
+    pub(super) async fn send_with_reason(
+        mut self,
+        qos: QoS,
+    ) -> Result<codec::PublishAckReason, SendPacketError> {
+        self.check_error()?;
+
+        let ack = match qos {
+            QoS::AtMostOnce => {
+                self.send_at_most_once()?;
+                return Ok(codec::PublishAckReason::Success);
+            }
+            QoS::AtLeastOnce => AckType::Publish,
+            QoS::ExactlyOnce => AckType::Receive,
+        };
+
+        // handle client receive maximum
+        if self.shared.state.is_open() && !self.shared.has_credit() {
+            self.shared.wait_credit(self.credit_timeout).await?;
+        }
+        let shared = self.shared.clone();
+        let (idx, rx) = self.enqueue(qos, ack)?;
+        wait_batch_ack(shared, idx, rx).await
+    }
+
+    /// Check for invalid topic or builder misuse
+    fn check_error(&mut self) -> Result<(), SendPacketError> {
+        if let Some(err) = self.error.take() {
+            Err(err)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Register publish as in-flight and encode it
+    fn enqueue(
+        self,
+        qos: QoS,
+        ack: AckType,
+    ) -> Result<(u16, pool::Receiver<Ack>), SendPacketError> {
         let shared = self.shared;
         let mut packet = self.packet;
-        packet.qos = QoS::AtLeastOnce;
+        packet.qos = qos;
 
-        if shared.state.is_open() {
-            // handle client receive maximum
-            if !shared.has_credit() {
-                let (tx, rx) = shared.pool.waiters.channel();
-                shared.queues.borrow_mut().waiters.push_back(tx);
+        if !shared.state.is_open() {
+            return Err(SendPacketError::Disconnected);
+        }
+        let mut queues = shared.queues.borrow_mut();
 
-                if rx.await.is_err() {
-                    return Err(PublishQos1Error::Disconnected);
-                }
-            }
-            let mut queues = shared.queues.borrow_mut();
+        // publish ack channel
+        let (tx, rx) = shared.pool.queue.channel();
 
-            // publish ack channel
-            let (tx, rx) = shared.pool.queue.channel();
+        // packet id
+        let mut idx = packet.packet_id.map(|i| i.get()).unwrap_or(0);
+        if idx == 0 {
+            idx = shared.next_id(&queues)?;
+            packet.packet_id = NonZeroU16::new(idx);
+        }
+        if queues.id_in_use(idx) {
+            return Err(SendPacketError::PacketIdInUse(idx));
+        }
+        queues.insert_inflight(idx, tx, ack);
 
-            // packet id
-            let mut idx = packet.packet_id.map(|i| i.get()).unwrap_or(0);
-            if idx == 0 {
-                idx = shared.next_id();
-                packet.packet_id = NonZeroU16::new(idx);
-            }
-            if queues.inflight.contains_key(&idx) {
-                return Err(PublishQos1Error::PacketIdInUse(idx));
+        // persist publish until it get acknowledged
+        shared.with_store(|store| store.store_publish(&packet));
+        shared.topic_alias(&mut queues, &mut packet);
+
+        // send publish to client
+        log::trace!("Publish ({:?}) to {:#?}", qos, packet);
+
+        shared
+            .state
+            .write()
+            .encode(codec::Packet::Publish(packet), &*shared)
+            .map_err(SendPacketError::Encode)?;
+        Ok((idx, rx))
+    }
+
+    /// Send publish packet with QoS 2
+    ///
+    /// Future resolves with PUBCOMP packet from the peer. Negative PUBREC
+    /// completes delivery with `PublishQos2Error::Fail` error.
+    pub async fn send_exactly_once(mut self) -> Result<codec::PublishAck2, PublishQos2Error> {
+        self.check_error()?;
+
+        // handle client receive maximum
+        if self.shared.state.is_open() && !self.shared.has_credit() {
+            self.shared.wait_credit(self.credit_timeout).await?;
+        }
+        let shared = self.shared.clone();
+        let timeout = self.ack_timeout;
+        let (idx, rx) = self.enqueue(QoS::ExactlyOnce, AckType::Receive)?;
+
+        // wait PUBCOMP from peer
+        shared
+            .wait_ack(idx, rx, timeout)
+            .await
+            .map_err(From::from)
+            .and_then(|pkt| pkt.publish_qos2().map_err(PublishQos2Error::Fail))
+    }
+}
+
+async fn wait_publish_ack(
+    shared: Rc<MqttShared>,
+    idx: u16,
+    rx: pool::Receiver<Ack>,
+    timeout: Option<Duration>,
+) -> Result<codec::PublishAck, PublishQos1Error> {
+    // wait ack from peer
+    let pkt = shared.wait_ack(idx, rx, timeout).await?.publish();
+    match pkt.reason_code {
+        codec::PublishAckReason::Success => Ok(pkt),
+        _ => Err(PublishQos1Error::Fail(pkt)),
+    }
+}
+
+/// Wait ack of batch publish, negative ack is returned as reason code
+async fn wait_batch_ack(
+    shared: Rc<MqttShared>,
+    idx: u16,
+    rx: pool::Receiver<Ack>,
+) -> Result<codec::PublishAckReason, SendPacketError> {
+    let reason = match shared.wait_ack(idx, rx, None).await? {
+        Ack::Publish(pkt) => pkt.reason_code,
+        pkt => match pkt.publish_qos2() {
+            Ok(_) => codec::PublishAckReason::Success,
+            Err(pkt) => pkt.reason_code,
+        },
+    };
+    Ok(reason)
+}
+
+/// Acknowledgement of detached publish
+///
+/// Future resolves when publish is acknowledged by the peer. If future is
+/// dropped before it is polled, packet stays in-flight until ack is received.
+pub struct AckFuture(
+    Pin<Box<dyn Future<Output = Result<codec::PublishAck, PublishQos1Error>>>>,
+);
+
+impl Future for AckFuture {
+    type Output = Result<codec::PublishAck, PublishQos1Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+/// Sender of application events, see `MqttSink::events()`
+pub struct EventSender<T>(Weak<MqttShared>, marker::PhantomData<T>);
+
+impl<T: 'static> EventSender<T> {
+    /// Push event to connection's control service
+    ///
+    /// Events are queued and get handled by connection's dispatcher in order,
+    /// when control service is ready. Event is returned back if connection
+    /// is closed, queued events are dropped on connection close.
+    pub fn send(&self, event: T) -> Result<(), T> {
+        match self.0.upgrade() {
+            Some(shared) if shared.events.is_open() => {
+                shared.events.push(control::Event::new(event));
+                Ok(())
             }
-            queues.inflight.insert(idx, (tx, AckType::Publish));
-            queues.inflight_order.push_back(idx);
+            _ => Err(event),
+        }
+    }
+}
 
-            // send publish to client
-            log::trace!("Publish (QoS1) to {:#?}", packet);
+impl<T> Clone for EventSender<T> {
+    fn clone(&self) -> Self {
+        EventSender(self.0.clone(), marker::PhantomData)
+    }
+}
 
-            match shared.state.write().encode(codec::Packet::Publish(packet), &shared.codec) {
-                Ok(_) => {
-                    // do not borrow cross yield points
-                    drop(queues);
+/// Request packet builder
+pub struct RequestBuilder {
+    publish: PublishBuilder,
+    response_topic: ByteString,
+    correlation_data: Option<Bytes>,
+}
 
-                    // wait ack from peer
-                    rx.await.map_err(|_| PublishQos1Error::Disconnected).and_then(|pkt| {
-                        let pkt = pkt.publish();
-                        match pkt.reason_code {
-                            codec::PublishAckReason::Success => Ok(pkt),
-                            _ => Err(PublishQos1Error::Fail(pkt)),
-                        }
-                    })
-                }
-                Err(err) => Err(PublishQos1Error::Encode(err)),
+impl RequestBuilder {
+    /// Set correlation data.
+    ///
+    /// Note: if correlation data is not set, it gets generated automatically.
+    pub fn correlation_data(mut self, data: Bytes) -> Self {
+        self.correlation_data = Some(data);
+        self
+    }
+
+    /// Set publish packet properties
+    pub fn properties<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut codec::PublishProperties),
+    {
+        self.publish.set_properties(f);
+        self
+    }
+
+    /// Send request and wait for response
+    ///
+    /// Subscribes to response topic if it is not subscribed yet, then sends
+    /// request publish packet with QoS 1. Future resolves with the response publish.
+    pub async fn send(self) -> Result<Publish, RequestError> {
+        let RequestBuilder { mut publish, response_topic, correlation_data } = self;
+        let shared = publish.shared.clone();
+        publish.check_error().map_err(PublishQos1Error::from)?;
+
+        // subscribe to response topic
+        if !shared.queues.borrow().response_topics.contains(&response_topic) {
+            let ack = MqttSink(shared.clone())
+                .subscribe(None)
+                .topic_filter(
+                    response_topic.clone(),
+                    codec::SubscriptionOptions {
+                        qos: QoS::AtLeastOnce,
+                        no_local: false,
+                        retain_as_published: false,
+                        retain_handling: codec::RetainHandling::NoAtSubscribe,
+                    },
+                )
+                .send()
+                .await?;
+            if let Some(status) = ack.status.into_iter().find(|s| u8::from(*s) >= 0x80) {
+                return Err(RequestError::Subscribe(status));
             }
-        } else {
-            Err(PublishQos1Error::Disconnected)
+            shared.queues.borrow_mut().response_topics.insert(response_topic.clone());
+        }
+
+        // register pending request
+        let correlation_data =
+            correlation_data.unwrap_or_else(|| shared.next_correlation_data());
+        let (tx, rx) = shared.pool.response.channel();
+        if !shared.state.is_open() {
+            return Err(RequestError::Disconnected);
+        }
+        shared.queues.borrow_mut().requests.insert(correlation_data.clone(), tx);
+        let _guard =
+            RequestGuard { shared: shared.clone(), correlation_data: correlation_data.clone() };
+
+        publish.set_properties(|props| {
+            props.response_topic = Some(response_topic);
+            props.correlation_data = Some(correlation_data);
+        });
+        publish.send_at_least_once().await?;
+
+        // wait response from peer
+        rx.await.map(Publish::new).map_err(|_| RequestError::Disconnected)
+    }
+}
+
+/// Remove pending request on drop
+struct RequestGuard {
+    shared: Rc<MqttShared>,
+    correlation_data: Bytes,
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        if let Ok(mut queues) = self.shared.queues.try_borrow_mut() {
+            queues.requests.remove(&self.correlation_data);
         }
     }
 }
@@ -324,6 +1286,7 @@ pub struct SubscribeBuilder {
     id: u16,
     packet: codec::Subscribe,
     shared: Rc<MqttShared>,
+    error: Option<TopicError>,
 }
 
 impl SubscribeBuilder {
@@ -338,37 +1301,61 @@ impl SubscribeBuilder {
         self
     }
 
+    /// Set subscription identifier
+    ///
+    /// Identifier applies to all topic filters of the packet.
+    pub fn identifier(mut self, id: NonZeroU32) -> Self {
+        self.packet.id = Some(id);
+        self
+    }
+
     /// Add topic filter
-    pub fn topic_filter(
-        mut self,
-        filter: ByteString,
-        opts: codec::SubscriptionOptions,
-    ) -> Self {
-        self.packet.topic_filters.push((filter, opts));
+    ///
+    /// Filter could be `TopicFilter` or any value that converts to it. Send fails
+    /// with `InvalidTopic` error if filter is not valid. Options could be specified
+    /// as `QoS` or as `SubscriptionOptions` with no local, retain as published
+    /// and retain handling flags.
+    pub fn topic_filter<U, T>(mut self, filter: U, opts: T) -> Self
+    where
+        U: TryInto<TopicFilter>,
+        TopicError: From<U::Error>,
+        T: Into<codec::SubscriptionOptions>,
+    {
+        match filter.try_into() {
+            Ok(filter) => self.packet.topic_filters.push((filter.into_inner(), opts.into())),
+            Err(err) => self.error = self.error.or_else(|| Some(err.into())),
+        }
         self
     }
 
     /// Add user property
-    pub fn property(mut self, key: ByteString, value: ByteString) -> Self {
+    pub fn user_property(mut self, key: ByteString, value: ByteString) -> Self {
         self.packet.user_properties.push((key, value));
         self
     }
 
+    #[doc(hidden)]
+    /// Add user property
+    pub fn property(self, key: ByteString, value: ByteString) -> Self {
+        self.user_property(key, value)
+    }
+
     #[allow(clippy::await_holding_refcell_ref)]
     /// Send subscribe packet
+    ///
+    /// Returned SUBACK contains reason code for each topic filter,
+    /// in the same order as filters were added.
     pub async fn send(self) -> Result<codec::SubscribeAck, SendPacketError> {
+        if let Some(err) = self.error {
+            return Err(SendPacketError::InvalidTopic(err));
+        }
         let shared = self.shared;
         let mut packet = self.packet;
 
         if shared.state.is_open() {
             // handle client receive maximum
             if !shared.has_credit() {
-                let (tx, rx) = shared.pool.waiters.channel();
-                shared.queues.borrow_mut().waiters.push_back(tx);
-
-                if rx.await.is_err() {
-                    return Err(SendPacketError::Disconnected);
-                }
+                shared.wait_credit(None).await?;
             }
             let mut queues = shared.queues.borrow_mut();
 
@@ -376,18 +1363,17 @@ impl SubscribeBuilder {
             let (tx, rx) = shared.pool.queue.channel();
 
             // allocate packet id
-            let idx = if self.id == 0 { shared.next_id() } else { self.id };
-            if queues.inflight.contains_key(&idx) {
+            let idx = if self.id == 0 { shared.next_id(&queues)? } else { self.id };
+            if queues.id_in_use(idx) {
                 return Err(SendPacketError::PacketIdInUse(idx));
             }
-            queues.inflight.insert(idx, (tx, AckType::Subscribe));
-            queues.inflight_order.push_back(idx);
+            queues.insert_inflight(idx, tx, AckType::Subscribe);
             packet.packet_id = NonZeroU16::new(idx).unwrap();
 
             // send subscribe to client
             log::trace!("Sending subscribe packet {:#?}", packet);
 
-            match shared.state.write().encode(codec::Packet::Subscribe(packet), &shared.codec) {
+            match shared.state.write().encode(codec::Packet::Subscribe(packet), &*shared) {
                 Ok(_) => {
                     // do not borrow cross yield points
                     drop(queues);
@@ -410,6 +1396,7 @@ pub struct UnsubscribeBuilder {
     id: u16,
     packet: codec::Unsubscribe,
     shared: Rc<MqttShared>,
+    error: Option<TopicError>,
 }
 
 impl UnsubscribeBuilder {
@@ -425,32 +1412,49 @@ impl UnsubscribeBuilder {
     }
 
     /// Add topic filter
-    pub fn topic_filter(mut self, filter: ByteString) -> Self {
-        self.packet.topic_filters.push(filter);
+    ///
+    /// Filter could be `TopicFilter` or any value that converts to it. Send fails
+    /// with `InvalidTopic` error if filter is not valid.
+    pub fn topic_filter<U>(mut self, filter: U) -> Self
+    where
+        U: TryInto<TopicFilter>,
+        TopicError: From<U::Error>,
+    {
+        match filter.try_into() {
+            Ok(filter) => self.packet.topic_filters.push(filter.into_inner()),
+            Err(err) => self.error = self.error.or_else(|| Some(err.into())),
+        }
         self
     }
 
     /// Add user property
-    pub fn property(mut self, key: ByteString, value: ByteString) -> Self {
+    pub fn user_property(mut self, key: ByteString, value: ByteString) -> Self {
         self.packet.user_properties.push((key, value));
         self
     }
 
+    #[doc(hidden)]
+    /// Add user property
+    pub fn property(self, key: ByteString, value: ByteString) -> Self {
+        self.user_property(key, value)
+    }
+
     #[allow(clippy::await_holding_refcell_ref)]
     /// Send unsubscribe packet
+    ///
+    /// Returned UNSUBACK contains reason code for each topic filter, in the same
+    /// order as filters were added, reason string and user properties.
     pub async fn send(self) -> Result<codec::UnsubscribeAck, SendPacketError> {
+        if let Some(err) = self.error {
+            return Err(SendPacketError::InvalidTopic(err));
+        }
         let shared = self.shared;
         let mut packet = self.packet;
 
         if shared.state.is_open() {
             // handle client receive maximum
             if !shared.has_credit() {
-                let (tx, rx) = shared.pool.waiters.channel();
-                shared.queues.borrow_mut().waiters.push_back(tx);
-
-                if rx.await.is_err() {
-                    return Err(SendPacketError::Disconnected);
-                }
+                shared.wait_credit(None).await?;
             }
             let mut queues = shared.queues.borrow_mut();
 
@@ -458,19 +1462,17 @@ impl UnsubscribeBuilder {
             let (tx, rx) = shared.pool.queue.channel();
 
             // allocate packet id
-            let idx = if self.id == 0 { shared.next_id() } else { self.id };
-            if queues.inflight.contains_key(&idx) {
+            let idx = if self.id == 0 { shared.next_id(&queues)? } else { self.id };
+            if queues.id_in_use(idx) {
                 return Err(SendPacketError::PacketIdInUse(idx));
             }
-            queues.inflight.insert(idx, (tx, AckType::Unsubscribe));
-            queues.inflight_order.push_back(idx);
+            queues.insert_inflight(idx, tx, AckType::Unsubscribe);
             packet.packet_id = NonZeroU16::new(idx).unwrap();
 
             // send unsubscribe to client
             log::trace!("Sending unsubscribe packet {:#?}", packet);
 
-            match shared.state.write().encode(codec::Packet::Unsubscribe(packet), &shared.codec)
-            {
+            match shared.state.write().encode(codec::Packet::Unsubscribe(packet), &*shared) {
                 Ok(_) => {
                     // do not borrow cross yield points
                     drop(queues);
@@ -4,13 +4,15 @@ use ntex::codec::{Decoder, Encoder};
 use ntex::util::BytesMut;
 
 use crate::error::{DecodeError, EncodeError};
-use crate::types::{packet_type, MQTT, MQTT_LEVEL_3, MQTT_LEVEL_5};
+use crate::types::{packet_type, MQISDP, MQISDP_LEVEL, MQTT, MQTT_LEVEL_3, MQTT_LEVEL_5};
 use crate::utils;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub(super) enum ProtocolVersion {
     MQTT3,
     MQTT5,
+    /// Valid protocol name, unsupported protocol level
+    Unsupported(u8),
 }
 
 #[derive(Debug)]
@@ -39,6 +41,22 @@ impl Decoder for VersionCodec {
 
                     let len =
                         u16::from_be_bytes(src[consumed..consumed + 2].try_into().unwrap());
+
+                    // mqtt 3.1, "MQIsdp" protocol name
+                    if len == 6 {
+                        if src.len() <= consumed + 8 {
+                            return Ok(None);
+                        }
+                        ensure!(
+                            &src[consumed + 2..consumed + 8] == MQISDP,
+                            DecodeError::InvalidProtocol
+                        );
+                        return Ok(Some(match src[consumed + 8] {
+                            MQISDP_LEVEL => ProtocolVersion::MQTT3,
+                            level => ProtocolVersion::Unsupported(level),
+                        }));
+                    }
+
                     ensure!(
                         len == 4 && &src[consumed + 2..consumed + 6] == MQTT,
                         DecodeError::InvalidProtocol
@@ -47,7 +65,7 @@ impl Decoder for VersionCodec {
                     match src[consumed + 6] {
                         MQTT_LEVEL_3 => Ok(Some(ProtocolVersion::MQTT3)),
                         MQTT_LEVEL_5 => Ok(Some(ProtocolVersion::MQTT5)),
-                        _ => Err(DecodeError::InvalidProtocol),
+                        level => Ok(Some(ProtocolVersion::Unsupported(level))),
                     }
                 } else {
                     Err(DecodeError::UnsupportedPacketType)
@@ -77,6 +95,15 @@ mod tests {
             b"\x10\x7f\x7f\x00\x04MQTT\x06\xC0\x00\x3C\x00\x0512345\x00\x04user\x00\x04pass"
                 .as_ref(),
         );
+        assert_eq!(
+            ProtocolVersion::Unsupported(6),
+            VersionCodec.decode(&mut buf).unwrap().unwrap()
+        );
+
+        let mut buf = BytesMut::from(
+            b"\x10\x7f\x7f\x00\x04MQTX\x04\xC0\x00\x3C\x00\x0512345\x00\x04user\x00\x04pass"
+                .as_ref(),
+        );
         assert_eq!(Err(DecodeError::InvalidProtocol), VersionCodec.decode(&mut buf));
 
         let mut buf =
@@ -86,5 +113,18 @@ mod tests {
         let mut buf =
             BytesMut::from(b"\x10\x98\x02\0\x04MQTT\x05\xc0\0\x0f\0\x02d1\0|testhub.".as_ref());
         assert_eq!(ProtocolVersion::MQTT5, VersionCodec.decode(&mut buf).unwrap().unwrap());
+
+        let mut buf = BytesMut::from(
+            b"\x10\x98\x02\0\x06MQIsdp\x03\xc0\0\x0f\0\x02d1\0|testhub.".as_ref(),
+        );
+        assert_eq!(ProtocolVersion::MQTT3, VersionCodec.decode(&mut buf).unwrap().unwrap());
+
+        let mut buf = BytesMut::from(
+            b"\x10\x98\x02\0\x06MQIsdp\x04\xc0\0\x0f\0\x02d1\0|testhub.".as_ref(),
+        );
+        assert_eq!(
+            ProtocolVersion::Unsupported(4),
+            VersionCodec.decode(&mut buf).unwrap().unwrap()
+        );
     }
 }
@@ -0,0 +1,584 @@
+//! MQTT over WebSocket transport
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::task::{Context, Poll};
+use std::{cmp, fmt, future::Future, io, pin::Pin};
+
+use futures_channel::mpsc;
+use ntex::codec::{poll_read_buf, AsyncRead, AsyncWrite, Decoder, Encoder, ReadBuf};
+use ntex::connect::{Address, Connect, ConnectError};
+use ntex::http::{error::PayloadError, header, ws::HandshakeError};
+use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
+use ntex::util::{poll_fn, Buf, ByteString, Bytes, BytesMut};
+use ntex::web::{HttpRequest, HttpResponse};
+use ntex::{rt, ws, Stream};
+
+/// Max size of websocket handshake response
+const MAX_HEADERS_SIZE: usize = 8 * 1024;
+
+/// Max size of websocket frame, max mqtt packet size plus fixed header
+const MAX_FRAME_SIZE: usize = 268_435_455 + 5;
+
+/// Number of response body chunks queued before writes are paused
+const WRITE_BUFFER: usize = 16;
+
+/// Max size of websocket frame for mqtt packet size limit, `0` means unlimited
+fn frame_size(max_size: u32) -> usize {
+    if max_size == 0 {
+        MAX_FRAME_SIZE
+    } else {
+        cmp::min(max_size as usize + 5, MAX_FRAME_SIZE)
+    }
+}
+
+/// WebSocket connector
+///
+/// Opens connection with inner connector and performs WebSocket handshake
+/// with `mqtt` subprotocol. Mqtt packets are sent as binary WebSocket messages.
+pub struct WsConnector<T> {
+    connector: T,
+    path: ByteString,
+}
+
+impl<T> WsConnector<T> {
+    /// Create WebSocket connector
+    ///
+    /// Default request path is `/mqtt`.
+    pub fn new(connector: T) -> Self {
+        WsConnector { connector, path: ByteString::from_static("/mqtt") }
+    }
+
+    /// Set request path
+    pub fn path<U>(mut self, path: U) -> Self
+    where
+        ByteString: From<U>,
+    {
+        self.path = ByteString::from(path);
+        self
+    }
+}
+
+impl<A, T> Service for WsConnector<T>
+where
+    A: Address,
+    T: Service<Request = Connect<A>, Error = ConnectError>,
+    T::Future: 'static,
+    T::Response: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    type Request = Connect<A>;
+    type Response = WsStream<T::Response>;
+    type Error = ConnectError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    #[inline]
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.connector.poll_ready(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(&self, cx: &mut Context<'_>, is_error: bool) -> Poll<()> {
+        self.connector.poll_shutdown(cx, is_error)
+    }
+
+    fn call(&self, req: Connect<A>) -> Self::Future {
+        let host = req.host().to_string();
+        let path = self.path.clone();
+        let fut = self.connector.call(req);
+
+        Box::pin(async move {
+            let io = fut.await?;
+            handshake(io, &host, &path).await.map_err(ConnectError::Io)
+        })
+    }
+}
+
+/// Perform client WebSocket handshake
+pub(crate) async fn handshake<Io>(
+    mut io: Io,
+    host: &str,
+    path: &str,
+) -> io::Result<WsStream<Io>>
+where
+    Io: AsyncRead + AsyncWrite + Unpin,
+{
+    let key = ws_key();
+    let req = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\
+         Sec-WebSocket-Protocol: mqtt\r\n\r\n",
+        path, host, key
+    );
+    write_all(&mut io, req.as_bytes()).await?;
+
+    // read response headers
+    let mut buf = BytesMut::with_capacity(1024);
+    let pos = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+        if buf.len() > MAX_HEADERS_SIZE {
+            return Err(invalid("WebSocket handshake response is too large"));
+        }
+        buf.reserve(1024);
+        if poll_fn(|cx| poll_read_buf(Pin::new(&mut io), cx, &mut buf)).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    };
+    let headers = buf.split_to(pos + 4);
+    let headers = std::str::from_utf8(&headers)
+        .map_err(|_| invalid("WebSocket handshake response is not valid utf8"))?;
+
+    let mut lines = headers.split("\r\n");
+    let status = lines.next().unwrap_or("");
+    if !status.starts_with("HTTP/1.1 101") {
+        log::trace!("Unexpected WebSocket handshake response: {}", status);
+        return Err(invalid("WebSocket upgrade is rejected"));
+    }
+
+    let accept = ws::hash_key(key.as_bytes());
+    let accepted = lines.any(|line| {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = parts.next().unwrap_or("").trim();
+        name.eq_ignore_ascii_case("sec-websocket-accept") && value == accept
+    });
+    if !accepted {
+        return Err(invalid("Invalid Sec-WebSocket-Accept header"));
+    }
+
+    Ok(WsStream::new(io, buf, ws::Codec::new().client_mode().max_size(MAX_FRAME_SIZE)))
+}
+
+/// Mqtt over WebSocket stream
+///
+/// Outgoing data is sent as binary WebSocket messages, incoming binary
+/// messages are read as a continuous byte stream.
+pub struct WsStream<Io> {
+    io: Io,
+    codec: ws::Codec,
+    read_buf: BytesMut,
+    payload: BytesMut,
+    write_buf: BytesMut,
+    closed: bool,
+    close_sent: bool,
+}
+
+impl<Io> WsStream<Io> {
+    pub(crate) fn new(io: Io, read_buf: BytesMut, codec: ws::Codec) -> Self {
+        WsStream {
+            io,
+            read_buf,
+            codec,
+            payload: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            closed: false,
+            close_sent: false,
+        }
+    }
+
+    /// Get reference to the underlying io
+    pub fn get_ref(&self) -> &Io {
+        &self.io
+    }
+}
+
+impl<Io: AsyncWrite + Unpin> WsStream<Io> {
+    fn encode(&mut self, msg: ws::Message) -> io::Result<()> {
+        self.codec
+            .encode(msg, &mut self.write_buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+
+    fn poll_flush_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            match Pin::new(&mut self.io).poll_write(cx, &self.write_buf)? {
+                Poll::Ready(0) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(n) => self.write_buf.advance(n),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<Io: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<Io> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if !this.payload.is_empty() {
+                let n = cmp::min(buf.remaining(), this.payload.len());
+                buf.put_slice(&this.payload.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+            if this.closed {
+                return Poll::Ready(Ok(()));
+            }
+
+            let frame = this
+                .codec
+                .decode(&mut this.read_buf)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+            match frame {
+                Some(ws::Frame::Binary(data))
+                | Some(ws::Frame::Continuation(ws::Item::FirstBinary(data)))
+                | Some(ws::Frame::Continuation(ws::Item::Continue(data)))
+                | Some(ws::Frame::Continuation(ws::Item::Last(data))) => {
+                    this.payload.extend_from_slice(&data)
+                }
+                Some(ws::Frame::Text(_))
+                | Some(ws::Frame::Continuation(ws::Item::FirstText(_))) => {
+                    return Poll::Ready(Err(invalid(
+                        "Text WebSocket messages are not supported",
+                    )));
+                }
+                Some(ws::Frame::Ping(data)) => {
+                    this.encode(ws::Message::Pong(data))?;
+                    let _ = this.poll_flush_buf(cx)?;
+                }
+                Some(ws::Frame::Pong(_)) => (),
+                Some(ws::Frame::Close(_)) => {
+                    log::trace!("WebSocket close frame is received");
+                    this.closed = true;
+                }
+                None => {
+                    this.read_buf.reserve(4096);
+                    match poll_read_buf(Pin::new(&mut this.io), cx, &mut this.read_buf)? {
+                        Poll::Ready(0) => this.closed = true,
+                        Poll::Ready(_) => (),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<Io: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<Io> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        // previous messages must be flushed first
+        if this.poll_flush_buf(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        this.encode(ws::Message::Binary(Bytes::copy_from_slice(buf)))?;
+        let _ = this.poll_flush_buf(cx)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.poll_flush_buf(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.close_sent {
+            this.close_sent = true;
+            this.encode(ws::Message::Close(None))?;
+        }
+        if this.poll_flush_buf(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
+
+/// Start mqtt server for WebSocket upgrade request
+///
+/// Adapter allows to mount mqtt server as a route of ntex web `App`.
+/// Request must use `mqtt` WebSocket subprotocol, binary WebSocket messages
+/// are passed to the mqtt server as a continuous byte stream.
+///
+/// WebSocket frames are limited by `max_size`, it should be set to the max
+/// packet size of the mqtt server, so peer could not make server buffer
+/// oversized frames. If `max_size` is `0`, frames are limited by max mqtt
+/// packet size only.
+///
+/// ```rust,ignore
+/// App::new().route(
+///     "/mqtt",
+///     web::get().to(|req: HttpRequest, payload: web::types::Payload| {
+///         ws::start(req, payload, 4096, v3::MqttServer::new(handshake).finish(publish))
+///     }),
+/// )
+/// ```
+pub async fn start<T, F, S>(
+    req: HttpRequest,
+    payload: S,
+    max_size: u32,
+    factory: F,
+) -> Result<HttpResponse, HandshakeError>
+where
+    F: IntoServiceFactory<T>,
+    T: ServiceFactory<Config = (), Request = WsStream<WebIo<S>>, Response = ()>,
+    T::InitError: fmt::Debug,
+    T::Service: 'static,
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin + 'static,
+{
+    let mut res = ntex::http::ws::handshake(req.head())?;
+
+    // mqtt subprotocol is required
+    let mqtt = req
+        .headers()
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .filter_map(|val| val.to_str().ok())
+        .flat_map(|val| val.split(','))
+        .any(|proto| proto.trim() == "mqtt");
+    if !mqtt {
+        log::trace!("WebSocket request does not support mqtt subprotocol");
+        return Ok(HttpResponse::BadRequest().finish());
+    }
+
+    let srv = match factory.into_factory().new_service(()).await {
+        Ok(srv) => srv,
+        Err(e) => {
+            log::error!("Cannot create mqtt service: {:?}", e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    let (tx, rx) = mpsc::channel(WRITE_BUFFER);
+    let io = WsStream::new(
+        WebIo { payload, tx, buf: Bytes::new() },
+        BytesMut::new(),
+        ws::Codec::new().max_size(frame_size(max_size)),
+    );
+    rt::spawn(async move {
+        if poll_fn(|cx| srv.poll_ready(cx)).await.is_ok() && srv.call(io).await.is_err() {
+            log::trace!("Mqtt over WebSocket connection is closed with error");
+        }
+    });
+
+    Ok(res.header(header::SEC_WEBSOCKET_PROTOCOL, "mqtt").streaming(rx))
+}
+
+/// Io adapter for ntex web request payload and response body
+///
+/// Request payload is read as an incoming byte stream, written data
+/// is sent as a response body chunk. Writes are pending while response
+/// body queue is full.
+pub struct WebIo<S> {
+    payload: S,
+    tx: mpsc::Sender<Result<Bytes, io::Error>>,
+    buf: Bytes,
+}
+
+impl<S> AsyncRead for WebIo<S>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.buf.is_empty() {
+            match Pin::new(&mut this.payload).poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => this.buf = chunk,
+                Poll::Ready(Some(Err(e))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        e.to_string(),
+                    )))
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = cmp::min(buf.remaining(), this.buf.len());
+        buf.put_slice(&this.buf.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: Unpin> AsyncWrite for WebIo<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        match this.tx.poll_ready(cx) {
+            Poll::Ready(Ok(_)) => (),
+            Poll::Ready(Err(_)) => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            Poll::Pending => return Poll::Pending,
+        }
+        match this.tx.start_send(Ok(Bytes::copy_from_slice(buf))) {
+            Ok(_) => Poll::Ready(Ok(buf.len())),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().tx.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub(crate) async fn write_all<Io: AsyncWrite + Unpin>(
+    io: &mut Io,
+    mut buf: &[u8],
+) -> io::Result<()> {
+    while !buf.is_empty() {
+        let n = poll_fn(|cx| Pin::new(&mut *io).poll_write(cx, buf)).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        buf = &buf[n..];
+    }
+    poll_fn(|cx| Pin::new(&mut *io).poll_flush(cx)).await
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Generate random base64 encoded `Sec-WebSocket-Key`
+fn ws_key() -> String {
+    let mut key = [0u8; 16];
+    key[..8].copy_from_slice(&RandomState::new().build_hasher().finish().to_le_bytes());
+    key[8..].copy_from_slice(&RandomState::new().build_hasher().finish().to_le_bytes());
+    base64(&key)
+}
+
+pub(crate) fn base64(data: &[u8]) -> String {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut res = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize;
+        res.push(CHARS[n >> 18 & 63] as char);
+        res.push(CHARS[n >> 12 & 63] as char);
+        res.push(if chunk.len() > 1 { CHARS[n >> 6 & 63] as char } else { '=' });
+        res.push(if chunk.len() > 2 { CHARS[n & 63] as char } else { '=' });
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use ntex::testing::Io;
+
+    use super::*;
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(ws_key().len(), 24);
+    }
+
+    #[ntex::test]
+    async fn test_handshake() {
+        let (peer, io) = Io::create();
+        peer.remote_buffer_cap(1024);
+
+        let srv = ntex::rt::spawn(async move {
+            let req = peer.read().await.unwrap();
+            let req = String::from_utf8(req.to_vec()).unwrap();
+            assert!(req.starts_with("GET /mqtt HTTP/1.1\r\n"));
+            assert!(req.contains("Sec-WebSocket-Protocol: mqtt\r\n"));
+
+            let key = req
+                .split("\r\n")
+                .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+                .unwrap();
+            peer.write(format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                 Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                ws::hash_key(key.as_bytes())
+            ));
+
+            let mut buf = BytesMut::new();
+            let msg = ws::Message::Binary(Bytes::from_static(b"data"));
+            ws::Codec::new().encode(msg, &mut buf).unwrap();
+            peer.write(buf);
+            peer
+        });
+
+        let mut stream = handshake(io, "localhost", "/mqtt").await.unwrap();
+        let mut buf = BytesMut::with_capacity(16);
+        let n = poll_fn(|cx| poll_read_buf(Pin::new(&mut stream), cx, &mut buf)).await.unwrap();
+        assert_eq!(n, 4);
+        assert_eq!(&buf[..], b"data");
+
+        // outgoing data is sent as masked binary frame
+        write_all(&mut stream, b"test").await.unwrap();
+        let peer = srv.await.unwrap();
+        let mut buf = peer.read().await.unwrap();
+        assert_eq!(
+            ws::Codec::new().decode(&mut buf).unwrap(),
+            Some(ws::Frame::Binary(Bytes::from_static(b"test")))
+        );
+    }
+
+    #[ntex::test]
+    async fn test_frame_size() {
+        assert_eq!(frame_size(0), MAX_FRAME_SIZE);
+        assert_eq!(frame_size(16), 21);
+
+        let (peer, io) = Io::create();
+        peer.remote_buffer_cap(1024);
+
+        let mut buf = BytesMut::new();
+        let msg = ws::Message::Binary(Bytes::from_static(&[0; 32]));
+        ws::Codec::new().client_mode().encode(msg, &mut buf).unwrap();
+        peer.write(buf);
+
+        // frame is larger than max packet size
+        let mut stream =
+            WsStream::new(io, BytesMut::new(), ws::Codec::new().max_size(frame_size(16)));
+        let mut buf = BytesMut::with_capacity(64);
+        let res = poll_fn(|cx| poll_read_buf(Pin::new(&mut stream), cx, &mut buf)).await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[ntex::test]
+    async fn test_web_io_backpressure() {
+        let (tx, mut rx) = mpsc::channel(WRITE_BUFFER);
+        let payload = futures::stream::empty::<Result<Bytes, PayloadError>>();
+        let mut io = WebIo { payload, tx, buf: Bytes::new() };
+
+        // writes are pending when response body queue is full
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut written = 0;
+        while let Poll::Ready(res) = Pin::new(&mut io).poll_write(&mut cx, b"data") {
+            assert_eq!(res.unwrap(), 4);
+            written += 1;
+        }
+        assert!(written >= WRITE_BUFFER);
+
+        // reading response body resumes writes
+        let _ = poll_fn(|cx| Pin::new(&mut rx).poll_next(cx)).await;
+        let res = poll_fn(|cx| Pin::new(&mut io).poll_write(cx, b"data")).await;
+        assert_eq!(res.unwrap(), 4);
+    }
+}
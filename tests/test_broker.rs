@@ -0,0 +1,159 @@
+#![cfg(feature = "broker")]
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::ok;
+use ntex::rt::time::delay_for;
+use ntex::server;
+use ntex::util::{ByteString, Bytes};
+
+use ntex_mqtt::broker::{Broker, SysPublisher};
+use ntex_mqtt::v5::{client, codec};
+
+fn options(qos: codec::QoS) -> codec::SubscriptionOptions {
+    codec::SubscriptionOptions {
+        qos,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    }
+}
+
+async fn subscriber(
+    addr: std::net::SocketAddr,
+    client_id: &'static str,
+    filter: &'static str,
+) -> (client::MqttSink, Arc<Mutex<Vec<(ByteString, bool)>>>) {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+
+    let client = client::MqttConnector::new(addr).client_id(client_id).connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start(move |msg: client::ControlMessage<()>| match msg {
+        client::ControlMessage::Publish(pkt) => {
+            let p = pkt.packet();
+            received2.lock().unwrap().push((p.topic.clone(), p.retain));
+            ok::<_, ()>(pkt.ack(None))
+        }
+        _ => ok(msg.disconnect()),
+    }));
+
+    sink.subscribe(None)
+        .topic_filter(ByteString::from(filter), options(codec::QoS::AtMostOnce))
+        .send()
+        .await
+        .unwrap();
+    (sink, received)
+}
+
+#[ntex::test]
+async fn test_broker() -> std::io::Result<()> {
+    let srv = server::test_server(|| Broker::new().server());
+
+    let (sink1, received1) = subscriber(srv.addr(), "client1", "sensors/+").await;
+    let (sink2, received2) = subscriber(srv.addr(), "client2", "sensors/1/#").await;
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("pub").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish(ByteString::from_static("sensors/1"), Bytes::from_static(b"10"))
+        .retain()
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sink.publish(ByteString::from_static("sensors/1/temp"), Bytes::from_static(b"20"))
+        .send_at_least_once()
+        .await
+        .unwrap();
+    delay_for(Duration::from_millis(50)).await;
+
+    assert_eq!(*received1.lock().unwrap(), vec![(ByteString::from_static("sensors/1"), false)]);
+    assert_eq!(
+        *received2.lock().unwrap(),
+        vec![
+            (ByteString::from_static("sensors/1"), false),
+            (ByteString::from_static("sensors/1/temp"), false)
+        ]
+    );
+
+    // retained message is sent to new subscriber
+    let (sink3, received3) = subscriber(srv.addr(), "client3", "sensors/#").await;
+    delay_for(Duration::from_millis(50)).await;
+    assert_eq!(*received3.lock().unwrap(), vec![(ByteString::from_static("sensors/1"), true)]);
+
+    sink1.close();
+    sink2.close();
+    sink3.close();
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sys_publisher() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        let broker = Broker::new();
+        ntex::rt::spawn(
+            SysPublisher::new(&broker)
+                .interval(Duration::from_millis(25))
+                .prefix("$SYS/test/")
+                .run(),
+        );
+        broker.server()
+    });
+
+    let (sink, received) = subscriber(srv.addr(), "client1", "$SYS/test/clients/+").await;
+    delay_for(Duration::from_millis(100)).await;
+    assert!(received
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|(topic, _)| topic == "$SYS/test/clients/connected"));
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_will_delay() -> std::io::Result<()> {
+    let srv = server::test_server(|| Broker::new().server());
+    let (sink, received) = subscriber(srv.addr(), "client1", "will/#").await;
+
+    let addr = srv.addr();
+    let connect = move || async move {
+        let client = client::MqttConnector::new(addr)
+            .client_id("client2")
+            .session_expiry_interval(Duration::from_secs(60))
+            .will(ByteString::from_static("will/client2"), Bytes::from_static(b"0"), |will| {
+                will.will_delay_interval_sec = Some(1)
+            })
+            .connect()
+            .await
+            .unwrap();
+        let sink = client.sink();
+        ntex::rt::spawn(client.start_default());
+        sink
+    };
+    let disconnect =
+        codec::Disconnect::new(codec::DisconnectReasonCode::DisconnectWithWillMessage);
+
+    // client connects again before will delay interval elapses
+    connect().await.close_with_reason(disconnect.clone());
+    delay_for(Duration::from_millis(100)).await;
+    let client = connect().await;
+    delay_for(Duration::from_millis(1200)).await;
+    assert!(received.lock().unwrap().is_empty());
+
+    client.close_with_reason(disconnect);
+    delay_for(Duration::from_millis(100)).await;
+    assert!(received.lock().unwrap().is_empty());
+    delay_for(Duration::from_millis(1100)).await;
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![(ByteString::from_static("will/client2"), false)]
+    );
+
+    sink.close();
+    Ok(())
+}
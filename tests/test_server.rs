@@ -1,15 +1,21 @@
-use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc};
-use std::{num::NonZeroU16, time::Duration};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::{num::NonZeroU16, pin::Pin, time::Duration};
 
 use futures::{future::ok, FutureExt, SinkExt, StreamExt};
-use ntex::codec::Framed;
+use ntex::codec::{AsyncWrite, Framed};
 use ntex::rt::time::sleep;
-use ntex::server;
-use ntex::util::{ByteString, Bytes};
+use ntex::util::{poll_fn, ByteString, Bytes};
+use ntex::{pipeline_factory, server, ServiceFactory};
 
+use ntex_mqtt::auth::{self, AuthError, AuthProvider, AuthRequest, AuthResult};
+use ntex_mqtt::error::{ClientError, MqttError, ProtocolError, SendPacketError};
+use ntex_mqtt::types::{AckOrder, PoolConfig};
 use ntex_mqtt::v3::{
-    client, codec, ControlMessage, Handshake, HandshakeAck, MqttServer, Publish, Session,
+    client, codec, ControlMessage, Handshake, HandshakeAck, MemorySessionStore, MqttServer,
+    Publish, Session, SessionRegistry,
 };
+use ntex_mqtt::{Drain, ProxyProtocol, TopicError, TopicName};
 
 struct St;
 
@@ -34,7 +40,41 @@ async fn test_simple() -> std::io::Result<()> {
     ntex::rt::spawn(client.start_default());
 
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_invalid_topic() -> std::io::Result<()> {
+    let srv = server::test_server(|| MqttServer::new(handshake).publish(|_t| ok(())).finish());
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink.publish("test/#", Bytes::new()).send_at_most_once();
+    assert_eq!(res, Err(SendPacketError::InvalidTopic(TopicError::WildcardInName)));
+    let res = sink.publish("", Bytes::new()).send_at_least_once().await;
+    assert_eq!(res, Err(SendPacketError::InvalidTopic(TopicError::EmptyTopic)));
+    let res = sink.publish_batch(vec![("test", Bytes::new(), codec::QoS::AtLeastOnce)]).await;
+    assert!(res.is_ok());
+
+    let res = sink
+        .subscribe()
+        .topic_filter("test", codec::QoS::AtLeastOnce)
+        .topic_filter("test/a#", codec::QoS::AtLeastOnce)
+        .send()
+        .await;
+    assert_eq!(res, Err(SendPacketError::InvalidTopic(TopicError::InvalidLevel)));
+    let res = sink.unsubscribe().topic_filter("test/#/a").send().await;
+    assert_eq!(res, Err(SendPacketError::InvalidTopic(TopicError::InvalidTopic)));
+
+    let topic: TopicName = "test".parse().unwrap();
+    let res = sink.publish(topic, Bytes::new()).send_at_least_once().await;
     assert!(res.is_ok());
 
     sink.close();
@@ -217,6 +257,63 @@ async fn test_ack_order() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_publish_qos2() -> std::io::Result<()> {
+    let released = Arc::new(AtomicBool::new(false));
+    let released2 = released.clone();
+
+    let srv = server::test_server(move || {
+        let released = released2.clone();
+        MqttServer::new(handshake)
+            .publish(|_| ok(()))
+            .control(move |msg| match msg {
+                ControlMessage::PublishRelease(msg) => {
+                    assert_eq!(msg.packet_id(), NonZeroU16::new(1).unwrap());
+                    released.store(true, Relaxed);
+                    ok(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let publish = codec::Publish {
+        dup: false,
+        retain: false,
+        qos: codec::QoS::ExactlyOnce,
+        topic: ByteString::from("test"),
+        packet_id: Some(NonZeroU16::new(1).unwrap()),
+        payload: Bytes::new(),
+    };
+    framed.send(publish.clone().into()).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishReceived { packet_id: NonZeroU16::new(1).unwrap() });
+
+    // re-delivery is acknowledged without release
+    framed.send(codec::Publish { dup: true, ..publish }.into()).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishReceived { packet_id: NonZeroU16::new(1).unwrap() });
+    assert!(!released.load(Relaxed));
+
+    framed
+        .send(codec::Packet::PublishRelease { packet_id: NonZeroU16::new(1).unwrap() })
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishComplete { packet_id: NonZeroU16::new(1).unwrap() });
+    assert!(released.load(Relaxed));
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_ack_order_sink() -> std::io::Result<()> {
     let srv = server::test_server(move || {
@@ -245,6 +342,32 @@ async fn test_ack_order_sink() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_send_exactly_once() -> std::io::Result<()> {
+    let srv =
+        server::test_server(move || MqttServer::new(handshake).publish(|_| ok(())).finish());
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let topic = ByteString::from_static("test");
+    let fut1 = sink.publish(topic.clone(), Bytes::from_static(b"pkt1")).send_exactly_once();
+    let fut2 = sink.publish(topic.clone(), Bytes::from_static(b"pkt2")).send_at_least_once();
+    let fut3 = sink.publish(topic.clone(), Bytes::from_static(b"pkt3")).send_exactly_once();
+
+    let (res1, res2, res3) = futures::future::join3(fut1, fut2, fut3).await;
+    assert!(res1.is_ok());
+    assert!(res2.is_ok());
+    assert!(res3.is_ok());
+    assert_eq!(sink.credit(), 16);
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_disconnect() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "ntex_mqtt=trace,ntex_codec=info,ntex=trace");
@@ -273,8 +396,1691 @@ async fn test_disconnect() -> std::io::Result<()> {
     ntex::rt::spawn(client.start_default());
 
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_err());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_will() -> std::io::Result<()> {
+    let triggered = Arc::new(AtomicBool::new(false));
+    let suppressed = Arc::new(AtomicBool::new(false));
+    let triggered2 = triggered.clone();
+    let suppressed2 = suppressed.clone();
+
+    let srv = server::test_server(move || {
+        let triggered = triggered2.clone();
+        let suppressed = suppressed2.clone();
+        MqttServer::new(|con: Handshake<_>| {
+            let will = con.last_will().unwrap();
+            assert_eq!(will.topic, ByteString::from_static("will"));
+            assert_eq!(will.qos, codec::QoS::AtLeastOnce);
+            ok::<_, ()>(con.ack(St, false))
+        })
+        .publish(|_| ok(()))
+        .control(move |msg| match msg {
+            ControlMessage::Will(msg) => {
+                assert_eq!(msg.topic(), &ByteString::from_static("will"));
+                assert_eq!(msg.message(), &Bytes::from_static(b"bye"));
+                assert!(msg.retain());
+                if msg.is_triggered() {
+                    triggered.store(true, Relaxed);
+                } else {
+                    suppressed.store(true, Relaxed);
+                }
+                ok(msg.ack())
+            }
+            _ => ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    let connect = || {
+        let mut pkt = codec::Connect::default().client_id("user");
+        pkt.last_will = Some(codec::LastWill {
+            qos: codec::QoS::AtLeastOnce,
+            retain: true,
+            topic: ByteString::from_static("will"),
+            message: Bytes::from_static(b"bye"),
+        });
+        codec::Packet::Connect(pkt)
+    };
+
+    // clean disconnect, will is discarded
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(connect()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed.send(codec::Packet::Disconnect).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    assert!(suppressed.load(Relaxed));
+    assert!(!triggered.load(Relaxed));
+    drop(framed);
+
+    // connection is dropped, will must be published
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(connect()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    drop(framed);
+    sleep(Duration::from_millis(100)).await;
+    assert!(triggered.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_present() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| {
+            let clean_session = con.clean_session();
+            ok::<_, ()>(con.ack(St, false).session_present(!clean_session))
+        })
+        .publish(|_| ok(()))
+        .finish()
+    });
+
+    for clean_session in &[true, false] {
+        let io = srv.connect().await.unwrap();
+        let mut framed = Framed::new(io, codec::Codec::default());
+        let mut pkt = codec::Connect::default().client_id("user");
+        pkt.clean_session = *clean_session;
+        framed.send(codec::Packet::Connect(pkt)).await.unwrap();
+        assert_eq!(
+            framed.next().await.unwrap().unwrap(),
+            codec::Packet::ConnectAck {
+                session_present: !*clean_session,
+                return_code: codec::ConnectAckReason::ConnectionAccepted,
+            }
+        );
+    }
+
+    // session present flag is ignored for clean session
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| ok::<_, ()>(con.ack(St, true)))
+            .publish(|_| ok(()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    let mut pkt = codec::Connect::default().client_id("user");
+    pkt.clean_session = true;
+    framed.send(codec::Packet::Connect(pkt)).await.unwrap();
+    assert_eq!(
+        framed.next().await.unwrap().unwrap(),
+        codec::Packet::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::ConnectionAccepted,
+        }
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_stream() -> std::io::Result<()> {
+    let acked = Arc::new(AtomicBool::new(false));
+    let acked2 = acked.clone();
+
+    // server echoes publishes back to the client
+    let srv = server::test_server(move || {
+        let acked = acked2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::fn_factory_with_config(move |session: Session<St>| {
+                let acked = acked.clone();
+                ok::<_, ()>(ntex::fn_service(move |pkt: Publish| {
+                    let acked = acked.clone();
+                    let fut = session
+                        .sink()
+                        .publish(ByteString::from(pkt.publish_topic()), pkt.payload().clone())
+                        .send_at_least_once();
+                    async move {
+                        if fut.await.is_ok() {
+                            acked.store(true, Relaxed);
+                        }
+                        Ok::<_, ()>(())
+                    }
+                }))
+            }))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let (sink, mut stream) = client.into_stream();
+
+    sink.publish(ByteString::from_static("test"), Bytes::from_static(b"data"))
+        .send_at_most_once()
+        .unwrap();
+    let pkt = stream.next().await.unwrap();
+    assert_eq!(pkt.publish_topic(), "test");
+    assert_eq!(pkt.payload(), &Bytes::from_static(b"data"));
+    assert_eq!(pkt.qos(), codec::QoS::AtLeastOnce);
+
+    sleep(Duration::from_millis(50)).await;
+    assert!(acked.load(Relaxed));
+
+    // stream terminates after connection get closed
+    sink.close();
+    assert!(stream.next().await.is_none());
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_route() -> std::io::Result<()> {
+    let unsubscribed = Arc::new(AtomicBool::new(false));
+    let unsubscribed2 = unsubscribed.clone();
+
+    // server echoes publishes back to the client
+    let srv = server::test_server(move || {
+        let unsubscribed = unsubscribed2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::fn_factory_with_config(|session: Session<St>| {
+                ok::<_, ()>(ntex::fn_service(move |pkt: Publish| {
+                    let _ = session
+                        .sink()
+                        .publish(ByteString::from(pkt.publish_topic()), pkt.payload().clone())
+                        .send_at_most_once();
+                    ok(())
+                }))
+            }))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        sub.confirm(sub.qos());
+                    }
+                    ok(msg.ack())
+                }
+                ControlMessage::Unsubscribe(msg) => {
+                    unsubscribed.store(true, Relaxed);
+                    ok(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let received = Arc::new(AtomicUsize::new(0));
+    let received2 = received.clone();
+    let unhandled = Arc::new(AtomicUsize::new(0));
+    let unhandled2 = unhandled.clone();
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    let router = client.route("sensors/+/temp", codec::QoS::AtMostOnce, move |pkt: Publish| {
+        assert_eq!(pkt.publish_topic(), "sensors/1/temp");
+        received2.fetch_add(1, Relaxed);
+        ok::<_, ()>(())
+    });
+    let routes = router.routes();
+    ntex::rt::spawn(router.start(move |msg| match msg {
+        client::ControlMessage::Publish(msg) => {
+            unhandled2.fetch_add(1, Relaxed);
+            ok::<_, ()>(msg.ack())
+        }
+        _ => ok(msg.disconnect()),
+    }));
+
+    sink.publish(ByteString::from_static("sensors/1/temp"), Bytes::new())
+        .send_at_most_once()
+        .unwrap();
+    sink.publish(ByteString::from_static("sensors/1/humidity"), Bytes::new())
+        .send_at_most_once()
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(received.load(Relaxed), 1);
+    assert_eq!(unhandled.load(Relaxed), 1);
+
+    // remove handler
+    routes.remove("sensors/+/temp").await.unwrap();
+    assert!(unsubscribed.load(Relaxed));
+
+    sink.publish(ByteString::from_static("sensors/1/temp"), Bytes::new())
+        .send_at_most_once()
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(received.load(Relaxed), 1);
+    assert_eq!(unhandled.load(Relaxed), 2);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_managed_client() -> std::io::Result<()> {
+    let subscribed = Arc::new(AtomicUsize::new(0));
+    let subscribed2 = subscribed.clone();
+
+    let srv = server::test_server(move || {
+        let subscribed = subscribed2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::fn_factory_with_config(|session: Session<St>| {
+                ok::<_, ()>(ntex::fn_service(move |_: Publish| {
+                    session.sink().force_close();
+                    ok(())
+                }))
+            }))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    subscribed.fetch_add(1, Relaxed);
+                    for mut sub in &mut msg {
+                        sub.confirm(sub.qos());
+                    }
+                    ok(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .into_managed()
+        .backoff(Duration::from_millis(50), Duration::from_millis(100));
+    let sink = client.sink();
+    ntex::rt::spawn(client.start(|msg: client::ControlMessage| ok::<_, ()>(msg.disconnect())));
+
+    assert!(sink.connected().await.is_some());
+    let res = sink.subscribe(ByteString::from_static("topic"), codec::QoS::AtLeastOnce).await;
+    assert_eq!(res.unwrap(), codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce));
+    assert_eq!(subscribed.load(Relaxed), 1);
+
+    // server drops connection
+    let res = sink
+        .publish(ByteString::from_static("topic"), Bytes::new(), codec::QoS::AtLeastOnce)
+        .await;
     assert!(res.is_err());
 
+    // client re-connects and re-subscribes
+    sleep(Duration::from_millis(300)).await;
+    assert!(sink.connected().await.is_some());
+    assert_eq!(subscribed.load(Relaxed), 2);
+
+    sink.close();
+    assert!(sink.connected().await.is_none());
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_managed_client_abort() -> std::io::Result<()> {
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let attempts2 = attempts.clone();
+
+    let srv = server::test_server(move || {
+        let attempts = attempts2.clone();
+        MqttServer::new(move |conn: Handshake<_>| {
+            attempts.fetch_add(1, Relaxed);
+            ok::<_, ()>(conn.bad_username_or_pwd::<St>())
+        })
+        .publish(|_t| ok(()))
+        .finish()
+    });
+
+    // stop re-connecting on auth failure
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .into_managed()
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .abort_if(|err| match err {
+            client::ClientError::Ack { return_code, .. } => {
+                *return_code == codec::ConnectAckReason::BadUserNameOrPassword
+            }
+            _ => false,
+        });
+    let sink = client.sink();
+    client.start(|msg: client::ControlMessage| ok::<_, ()>(msg.disconnect())).await;
+    assert!(sink.connected().await.is_none());
+    assert_eq!(attempts.load(Relaxed), 1);
+
+    // stop re-connecting after max attempts
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .into_managed()
+        .backoff_strategy(client::FixedBackoff::new(Duration::from_millis(10)).max_attempts(2));
+    let sink = client.sink();
+    client.start(|msg: client::ControlMessage| ok::<_, ()>(msg.disconnect())).await;
+    assert!(sink.connected().await.is_none());
+    assert_eq!(attempts.load(Relaxed), 4);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_managed_client_offline() -> std::io::Result<()> {
+    let topics = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let topics2 = topics.clone();
+
+    let srv = server::test_server(move || {
+        let topics = topics2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::fn_factory_with_config(move |session: Session<St>| {
+                let topics = topics.clone();
+                ok::<_, ()>(ntex::fn_service(move |p: Publish| {
+                    topics.lock().unwrap().push(p.publish_topic().to_string());
+                    if p.publish_topic() == "close" {
+                        session.sink().force_close();
+                    }
+                    ok(())
+                }))
+            }))
+            .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .into_managed()
+        .backoff(Duration::from_millis(100), Duration::from_millis(100))
+        .offline_buffer(3, client::OfflinePolicy::DropOldest);
+    let sink = client.sink();
+    ntex::rt::spawn(client.start(|msg: client::ControlMessage| ok::<_, ()>(msg.disconnect())));
+
+    assert!(sink.connected().await.is_some());
+    sink.publish_at_most_once(ByteString::from_static("close"), Bytes::new()).unwrap();
+    while sink.is_connected() {
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    // publishes are buffered while client is disconnected
+    let publish = |topic: &'static str| {
+        let sink = sink.clone();
+        let topic = ByteString::from_static(topic);
+        ntex::rt::spawn(async move {
+            sink.publish(topic, Bytes::new(), codec::QoS::AtLeastOnce).await
+        })
+    };
+    let res1 = publish("a");
+    sleep(Duration::from_millis(10)).await;
+    sink.publish_at_most_once(ByteString::from_static("b"), Bytes::new()).unwrap();
+    let res2 = publish("c");
+    sleep(Duration::from_millis(10)).await;
+    sink.publish_at_most_once(ByteString::from_static("d"), Bytes::new()).unwrap();
+
+    // oldest publish is dropped, rest is sent in order after re-connect
+    assert_eq!(res1.await.unwrap(), Err(SendPacketError::Disconnected));
+    assert!(sink.connected().await.is_some());
+    assert_eq!(res2.await.unwrap(), Ok(()));
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(*topics.lock().unwrap(), vec!["close", "b", "c", "d"]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_ping_timeout() -> std::io::Result<()> {
+    // server responds to pings
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(|_| ok(()))
+            .control(|msg| match msg {
+                ControlMessage::Ping(msg) => ok::<_, ()>(msg.ack()),
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .keep_alive(1)
+        .ping_timeout(200)
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sleep(Duration::from_millis(1500)).await;
+    assert!(sink.is_open());
+    sink.close();
+
+    // server does not respond to pings
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(|_| ok(()))
+            .control(|msg| async move {
+                match msg {
+                    ControlMessage::Ping(msg) => {
+                        sleep(Duration::from_secs(10)).await;
+                        Ok::<_, ()>(msg.ack())
+                    }
+                    _ => Ok(msg.disconnect()),
+                }
+            })
+            .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .keep_alive(1)
+        .ping_timeout(200)
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sleep(Duration::from_millis(1500)).await;
+    assert!(!sink.is_open());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_ack_timeout() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(|_| async {
+                sleep(Duration::from_secs(10)).await;
+                Ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .connect_timeout(1000)
+        .ack_timeout(Duration::from_millis(100))
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert_eq!(res, Err(ntex_mqtt::error::SendPacketError::AckTimeout));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_websocket() -> std::io::Result<()> {
+    use ntex::web::{self, App, HttpRequest};
+
+    let publish = Arc::new(AtomicUsize::new(0));
+    let publish2 = publish.clone();
+
+    let srv = web::test::server(move || {
+        let publish = publish2.clone();
+        App::new().route(
+            "/mqtt",
+            web::get().to(move |req: HttpRequest, payload: web::types::Payload| {
+                let publish = publish.clone();
+                ntex_mqtt::ws::start(
+                    req,
+                    payload,
+                    1024,
+                    MqttServer::new(handshake)
+                        .publish(move |_| {
+                            publish.fetch_add(1, Relaxed);
+                            ok(())
+                        })
+                        .finish(),
+                )
+            }),
+        )
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .websocket("/mqtt")
+        .client_id("user")
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    assert_eq!(publish.load(Relaxed), 1);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_store() -> std::io::Result<()> {
+    let restored = Arc::new(AtomicUsize::new(0));
+    let restored2 = restored.clone();
+
+    let srv = server::test_server(move || {
+        let restored = restored2.clone();
+        MqttServer::new(move |con: Handshake<_>| {
+            if let Some(st) = con.session_state() {
+                restored.store(st.subscriptions.len(), Relaxed);
+            }
+            if con.packet().username.as_ref().map_or(false, |name| name == "bad") {
+                ok::<_, ()>(con.bad_username_or_pwd())
+            } else {
+                ok::<_, ()>(con.ack(St, false))
+            }
+        })
+        .session_store(MemorySessionStore::default())
+        .publish(|_| ok::<_, ()>(()))
+        .control(|msg| match msg {
+            ControlMessage::Subscribe(mut msg) => {
+                for mut sub in &mut msg {
+                    sub.confirm(sub.qos());
+                }
+                ok(msg.ack())
+            }
+            _ => ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    assert!(!client.session_present());
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let codes = sink
+        .subscribe()
+        .topic_filter(ByteString::from_static("topic1"), codec::QoS::AtLeastOnce)
+        .topic_filter(ByteString::from_static("topic2"), codec::QoS::AtMostOnce)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(codes.len(), 2);
+    sink.close();
+    sleep(Duration::from_millis(100)).await;
+
+    // stored session
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    assert!(client.session_present());
+    assert_eq!(restored.load(Relaxed), 2);
+    client.sink().close();
+    sleep(Duration::from_millis(100)).await;
+
+    // rejected connection does not touch stored session
+    let res = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .username("bad")
+        .clean_session()
+        .connect()
+        .await;
+    assert!(res.is_err());
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    assert!(client.session_present());
+    client.sink().close();
+
+    // clean session removes stored session
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .clean_session()
+        .connect()
+        .await
+        .unwrap();
+    assert!(!client.session_present());
+    client.sink().close();
+    sleep(Duration::from_millis(100)).await;
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    assert!(!client.session_present());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_connection_rate() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake).connection_rate(1, 2).publish(|_t| ok(())).finish()
+    });
+
+    let client1 = client::MqttConnector::new(srv.addr()).client_id("user1").connect().await;
+    assert!(client1.is_ok());
+    let client2 = client::MqttConnector::new(srv.addr()).client_id("user2").connect().await;
+    assert!(client2.is_ok());
+
+    // burst is exhausted
+    let client3 = client::MqttConnector::new(srv.addr()).client_id("user3").connect().await;
+    assert!(client3.is_err());
+
+    sleep(Duration::from_millis(1100)).await;
+    let client4 = client::MqttConnector::new(srv.addr()).client_id("user4").connect().await;
+    assert!(client4.is_ok());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_rate_pause() -> std::io::Result<()> {
+    let received = Arc::new(AtomicUsize::new(0));
+    let received2 = received.clone();
+
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(handshake)
+            .publish_rate(2, 0)
+            .publish(move |_| {
+                received.fetch_add(1, Relaxed);
+                ok(())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    for _ in 0..4 {
+        sink.publish(ByteString::from_static("test"), Bytes::from_static(b"data"))
+            .send_at_most_once()
+            .unwrap();
+    }
+
+    // reading is paused until bucket gets refilled
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(received.load(Relaxed), 2);
+    sleep(Duration::from_millis(1000)).await;
+    assert_eq!(received.load(Relaxed), 4);
+    assert!(sink.is_open());
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_connections() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake).max_connections(1).publish(|_t| ok(())).finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user1").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = client::MqttConnector::new(srv.addr()).client_id("user2").connect().await;
+    match res {
+        Err(client::ClientError::Ack { return_code, .. }) => {
+            assert_eq!(return_code, codec::ConnectAckReason::ServiceUnavailable)
+        }
+        _ => panic!("Expect connect ack error"),
+    }
+
+    // slot is released after connection is closed
+    sink.close();
+    sleep(Duration::from_millis(100)).await;
+    let res = client::MqttConnector::new(srv.addr()).client_id("user2").connect().await;
+    assert!(res.is_ok());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_keepalive_extend() -> std::io::Result<()> {
+    let count = Arc::new(AtomicUsize::new(0));
+    let count2 = count.clone();
+
+    let srv = server::test_server(move || {
+        let count = count2.clone();
+        MqttServer::new(|con: Handshake<_>| ok::<_, ()>(con.ack(St, false).idle_timeout(1)))
+            .publish(|_| ok(()))
+            .control(move |msg| match msg {
+                ControlMessage::KeepAliveTimeout(msg) => {
+                    // extend first timeout only
+                    if count.fetch_add(1, Relaxed) == 0 {
+                        ok(msg.extend())
+                    } else {
+                        ok(msg.ack())
+                    }
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    framed.next().await.unwrap().unwrap();
+
+    // connection is closed after second timeout
+    assert!(framed.next().await.is_none());
+    assert_eq!(count.load(Relaxed), 2);
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_proxy_protocol() -> std::io::Result<()> {
+    let addr = Arc::new(Mutex::new(None));
+    let addr2 = addr.clone();
+
+    let srv = server::test_server(move || {
+        let addr = addr2.clone();
+        MqttServer::new(move |con: Handshake<_>| {
+            *addr.lock().unwrap() = con.peer_addr();
+            ok::<_, ()>(con.ack(St, false))
+        })
+        .proxy_protocol(true)
+        .publish(|_| ok(()))
+        .finish()
+    });
+
+    let mut io = srv.connect().await.unwrap();
+    let header = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1883\r\n";
+    let n = poll_fn(|cx| Pin::new(&mut io).poll_write(cx, header)).await?;
+    assert_eq!(n, header.len());
+
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::ConnectAck { .. }));
+    assert_eq!(*addr.lock().unwrap(), Some("192.168.0.1:56324".parse().unwrap()));
+
+    // connection without header is dropped
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    assert!(framed.next().await.is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_proxy_protocol_acceptor() -> std::io::Result<()> {
+    let addr = Arc::new(Mutex::new(None));
+    let addr2 = addr.clone();
+
+    // acceptor reads header from raw stream, tls acceptor could be chained after it
+    let srv = server::test_server(move || {
+        let addr = addr2.clone();
+        pipeline_factory(ProxyProtocol::new())
+            .map_err(|e| MqttError::Protocol(ProtocolError::Io(e)))
+            .and_then(
+                MqttServer::new(move |con: Handshake<_>| {
+                    *addr.lock().unwrap() = con.peer_addr();
+                    ok::<_, ()>(con.ack(St, false))
+                })
+                .publish(|_| ok(()))
+                .finish()
+                .map_init_err(|_| ()),
+            )
+    });
+
+    let mut io = srv.connect().await.unwrap();
+    let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 1883\r\n";
+    let n = poll_fn(|cx| Pin::new(&mut io).poll_write(cx, header)).await?;
+    assert_eq!(n, header.len());
+
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::ConnectAck { .. }));
+    assert_eq!(*addr.lock().unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_peer_addr() -> std::io::Result<()> {
+    let addrs = Arc::new(Mutex::new(Vec::new()));
+    let addrs2 = addrs.clone();
+
+    let srv = server::test_server(move || {
+        let addrs = addrs2.clone();
+        let addrs2 = addrs2.clone();
+        MqttServer::new(move |con: Handshake<_>| {
+            addrs.lock().unwrap().push((con.peer_addr(), con.local_addr()));
+            ok::<_, ()>(con.ack(St, false))
+        })
+        .publish(ntex::fn_factory_with_config(move |session: Session<St>| {
+            addrs2.lock().unwrap().push((session.peer_addr(), session.local_addr()));
+            ok(ntex::fn_service(|_: Publish| ok::<_, ()>(())))
+        }))
+        .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish(ByteString::from_static("test"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+
+    let addrs = addrs.lock().unwrap();
+    assert_eq!(addrs.len(), 2);
+    assert_eq!(addrs[0], addrs[1]);
+    assert_eq!(addrs[0].1, Some(srv.addr()));
+    assert!(addrs[0].0.is_some());
+    assert_ne!(addrs[0].0, addrs[0].1);
+
+    Ok(())
+}
+
+struct TestAuth;
+
+impl AuthProvider for TestAuth {
+    type State = St;
+    type Error = ();
+    type Future = futures::future::Ready<Result<AuthResult<St>, ()>>;
+
+    fn authenticate(&self, req: AuthRequest) -> Self::Future {
+        if req.username.as_deref() == Some("user")
+            && req.password == Some(Bytes::from_static(b"pwd"))
+        {
+            ok(AuthResult::Accept(St))
+        } else {
+            ok(AuthResult::Reject(AuthError::BadCredentials))
+        }
+    }
+}
+
+#[ntex::test]
+async fn test_auth_provider() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(auth::v3_handshake(TestAuth)).publish(|_| ok(())).finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect {
+            client_id: ByteString::from_static("user"),
+            username: Some(ByteString::from_static("user")),
+            password: Some(Bytes::from_static(b"pwd")),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::ConnectionAccepted,
+        }
+    );
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect {
+            client_id: ByteString::from_static("user"),
+            username: Some(ByteString::from_static("user")),
+            password: Some(Bytes::from_static(b"pass")),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::BadUserNameOrPassword,
+        }
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_stream_payload() -> std::io::Result<()> {
+    let data = Arc::new(Mutex::new(Vec::new()));
+    let data2 = data.clone();
+
+    let srv = server::test_server(move || {
+        let data = data2.clone();
+        MqttServer::new(handshake)
+            .stream_threshold(1024)
+            .publish(move |mut p: Publish| {
+                let data = data.clone();
+                async move {
+                    let streamed = p.is_streamed();
+                    let payload = match p.take_payload_stream() {
+                        Some(stream) => stream.read_all().await.unwrap(),
+                        None => p.take_payload(),
+                    };
+                    data.lock().unwrap().push((streamed, payload));
+                    Ok::<_, ()>(())
+                }
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let payload = Bytes::from(vec![b'a'; 256 * 1024]);
+    sink.publish(ByteString::from_static("test"), payload.clone())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sink.publish(ByteString::from_static("test"), Bytes::from_static(b"small"))
+        .send_at_least_once()
+        .await
+        .unwrap();
+
+    let data = data.lock().unwrap();
+    assert_eq!(data.len(), 2);
+    assert_eq!(data[0], (true, payload));
+    assert_eq!(data[1], (false, Bytes::from_static(b"small")));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_conformance() -> std::io::Result<()> {
+    // reserved connect flag, empty client id without clean session, pingreq flags
+    let data = b"\x10\x0c\x00\x04MQTT\x04\x01\x00\x3c\x00\x00\xc1\x00";
+
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .conformance(codec::Conformance::Lenient)
+            .publish(|_| ok(()))
+            .finish()
+    });
+    let mut io = srv.connect().await.unwrap();
+    let n = poll_fn(|cx| Pin::new(&mut io).poll_write(cx, data)).await?;
+    assert_eq!(n, data.len());
+
+    let mut framed = Framed::new(io, codec::Codec::default());
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::ConnectAck {
+            session_present: false,
+            return_code: codec::ConnectAckReason::ConnectionAccepted,
+        }
+    );
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PingResponse);
+
+    let srv = server::test_server(|| MqttServer::new(handshake).publish(|_| ok(())).finish());
+    let mut io = srv.connect().await.unwrap();
+    let n = poll_fn(|cx| Pin::new(&mut io).poll_write(cx, data)).await?;
+    assert_eq!(n, data.len());
+
+    let mut framed = Framed::new(io, codec::Codec::default());
+    assert!(!matches!(framed.next().await, Some(Ok(_))));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_batch() -> std::io::Result<()> {
+    let publishes = Arc::new(AtomicUsize::new(0));
+    let publishes2 = publishes.clone();
+
+    let srv = server::test_server(move || {
+        let publishes = publishes2.clone();
+        MqttServer::new(handshake)
+            .publish(move |_| {
+                publishes.fetch_add(1, Relaxed);
+                ok(())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let topic = ByteString::from_static("test");
+    let res = sink
+        .publish_batch(vec![
+            (topic.clone(), Bytes::from_static(b"pkt1"), codec::QoS::AtMostOnce),
+            (topic.clone(), Bytes::from_static(b"pkt2"), codec::QoS::AtLeastOnce),
+            (topic.clone(), Bytes::from_static(b"pkt3"), codec::QoS::ExactlyOnce),
+        ])
+        .await;
+    assert!(res.is_ok());
+    assert_eq!(publishes.load(Relaxed), 3);
+    assert_eq!(sink.credit(), 16);
+
+    Ok(())
+}
+
+#[cfg(feature = "json")]
+#[ntex::test]
+async fn test_publish_json() -> std::io::Result<()> {
+    let received = Arc::new(AtomicUsize::new(0));
+    let received2 = received.clone();
+
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(handshake)
+            .publish(move |mut p: Publish| {
+                assert_eq!(p.json::<Vec<u32>>().unwrap(), vec![1, 2, 3]);
+                received.fetch_add(1, Relaxed);
+                ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .send_json(&[1u32, 2, 3])
+        .await;
+    assert!(res.is_ok());
+    assert_eq!(received.load(Relaxed), 1);
+
+    // json object keys must be strings
+    let mut value = std::collections::BTreeMap::new();
+    value.insert((1u32, 2u32), 3u32);
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_json(&value).await;
+    assert!(matches!(res, Err(SendPacketError::Serialize(_))));
+    assert_eq!(received.load(Relaxed), 1);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_pool_config() -> std::io::Result<()> {
+    let config =
+        PoolConfig::new().inflight_capacity(2).buffer_params(1024, 1024, 256).shrink(true);
+
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake).pool_config(config).publish(|_| ok(())).finish()
+    });
+
+    // connect to server
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .pool_config(config)
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let payload = Bytes::from(vec![b'*'; 4 * 1024]);
+    for _ in 0..4 {
+        let res =
+            sink.publish(ByteString::from_static("test"), payload.clone()).send_at_least_once();
+        assert!(res.await.is_ok());
+    }
+
+    // burst exceeds configured in-flight capacity
+    let batch = (0..8).map(|_| ("test", payload.clone(), codec::QoS::AtLeastOnce));
+    assert!(sink.publish_batch(batch).await.is_ok());
+    assert_eq!(sink.inflight(), 0);
+    assert_eq!(sink.waiters(), 0);
+    assert_eq!(sink.credit(), 16);
+    sink.close();
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_unordered_acks() -> std::io::Result<()> {
+    // broker acknowledges publishes in reverse order
+    let srv = server::test_server(|| {
+        ntex::fn_service(|io: ntex::rt::net::TcpStream| async move {
+            let mut framed = Framed::new(io, codec::Codec::default());
+            if let Some(Ok(codec::Packet::Connect(_))) = framed.next().await {
+                let ack = codec::Packet::ConnectAck {
+                    session_present: false,
+                    return_code: codec::ConnectAckReason::ConnectionAccepted,
+                };
+                framed.send(ack).await.unwrap();
+            }
+
+            let mut ids = Vec::new();
+            while ids.len() < 2 {
+                match framed.next().await {
+                    Some(Ok(codec::Packet::Publish(pkt))) => ids.push(pkt.packet_id.unwrap()),
+                    _ => return Ok::<_, ()>(()),
+                }
+            }
+            for packet_id in ids.into_iter().rev() {
+                framed.send(codec::Packet::PublishAck { packet_id }).await.unwrap();
+            }
+            let _ = framed.next().await;
+            Ok(())
+        })
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    sink.set_ack_order(AckOrder::Unordered);
+
+    ntex::rt::spawn(client.start_default());
+
+    let topic = ByteString::from_static("test");
+    let fut1 = sink.publish(topic.clone(), Bytes::from_static(b"pkt1")).send_at_least_once();
+    let fut2 = sink.publish(topic.clone(), Bytes::from_static(b"pkt2")).send_at_least_once();
+
+    let (res1, res2) = futures::future::join(fut1, fut2).await;
+    assert!(res1.is_ok());
+    assert!(res2.is_ok());
+    assert_eq!(sink.credit(), 16);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_qos2() -> std::io::Result<()> {
+    let acks = Arc::new(Mutex::new(Vec::new()));
+    let acks2 = acks.clone();
+
+    // broker re-sends qos2 publish and publish release
+    let srv = server::test_server(move || {
+        let acks = acks2.clone();
+        ntex::fn_service(move |io: ntex::rt::net::TcpStream| {
+            let acks = acks.clone();
+            async move {
+                let mut framed = Framed::new(io, codec::Codec::default());
+                if let Some(Ok(codec::Packet::Connect(_))) = framed.next().await {
+                    let ack = codec::Packet::ConnectAck {
+                        session_present: false,
+                        return_code: codec::ConnectAckReason::ConnectionAccepted,
+                    };
+                    framed.send(ack).await.unwrap();
+                }
+
+                let packet_id = NonZeroU16::new(1).unwrap();
+                let publish = codec::Publish {
+                    dup: false,
+                    retain: false,
+                    qos: codec::QoS::ExactlyOnce,
+                    topic: ByteString::from_static("test"),
+                    packet_id: Some(packet_id),
+                    payload: Bytes::from_static(b"data"),
+                };
+                for pkt in vec![
+                    codec::Packet::Publish(publish.clone()),
+                    codec::Packet::Publish(codec::Publish { dup: true, ..publish }),
+                    codec::Packet::PublishRelease { packet_id },
+                    codec::Packet::PublishRelease { packet_id },
+                ] {
+                    framed.send(pkt).await.unwrap();
+                    if let Some(Ok(pkt)) = framed.next().await {
+                        acks.lock().unwrap().push(pkt);
+                    }
+                }
+                let _ = framed.next().await;
+                Ok::<_, ()>(())
+            }
+        })
+    });
+
+    let delivered = Arc::new(AtomicUsize::new(0));
+    let delivered2 = delivered.clone();
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start(move |msg: client::ControlMessage| match msg {
+        client::ControlMessage::Publish(msg) => {
+            delivered2.fetch_add(1, Relaxed);
+            ok::<_, ()>(msg.ack())
+        }
+        msg => ok(msg.disconnect()),
+    }));
+
+    sleep(Duration::from_millis(100)).await;
+    let packet_id = NonZeroU16::new(1).unwrap();
+    assert_eq!(
+        &acks.lock().unwrap()[..],
+        &[
+            codec::Packet::PublishReceived { packet_id },
+            codec::Packet::PublishReceived { packet_id },
+            codec::Packet::PublishComplete { packet_id },
+            codec::Packet::PublishComplete { packet_id },
+        ]
+    );
+    assert_eq!(delivered.load(Relaxed), 1);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_send_detached() -> std::io::Result<()> {
+    let publishes = Arc::new(AtomicUsize::new(0));
+    let publishes2 = publishes.clone();
+
+    let srv = server::test_server(move || {
+        let publishes = publishes2.clone();
+        MqttServer::new(handshake)
+            .publish(move |_| {
+                publishes.fetch_add(1, Relaxed);
+                ok(())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let topic = ByteString::from_static("test");
+    let acks: Vec<_> = (0..16)
+        .map(|_| {
+            sink.publish(topic.clone(), Bytes::from_static(b"data"))
+                .send_at_least_once_detached()
+                .unwrap()
+        })
+        .collect();
+    assert_eq!(sink.inflight(), 16);
+
+    // detached publish does not wait for receive credit
+    let res = sink.publish(topic.clone(), Bytes::new()).send_at_least_once_detached();
+    assert_eq!(res.err(), Some(SendPacketError::NoCredit));
+
+    for res in futures::future::join_all(acks).await {
+        assert!(res.is_ok());
+    }
+    assert_eq!(publishes.load(Relaxed), 16);
+    assert_eq!(sink.inflight(), 0);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_subscribe_return_codes() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| ok(()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        match sub.topic().as_ref() {
+                            "fail" => sub.fail(),
+                            "limited" => sub.grant(codec::QoS::AtMostOnce),
+                            _ => sub.grant(codec::QoS::ExactlyOnce),
+                        }
+                    }
+                    ok(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let codes = sink
+        .subscribe()
+        .topic_filter(ByteString::from_static("fail"), codec::QoS::AtLeastOnce)
+        .topic_filter(ByteString::from_static("limited"), codec::QoS::ExactlyOnce)
+        .topic_filter(ByteString::from_static("topic"), codec::QoS::AtLeastOnce)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        codes,
+        vec![
+            codec::SubscribeReturnCode::Failure,
+            codec::SubscribeReturnCode::Success(codec::QoS::AtMostOnce),
+            codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce),
+        ]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_pause_read() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(ntex::fn_factory_with_config(|session: Session<St>| {
+                ok::<_, ()>(ntex::fn_service(move |p: Publish| {
+                    if p.publish_topic() == "pause" {
+                        let sink = session.sink().clone();
+                        sink.pause_read();
+                        assert!(sink.is_read_paused());
+                        ntex::rt::spawn(async move {
+                            sleep(Duration::from_millis(200)).await;
+                            sink.resume_read();
+                        });
+                    }
+                    ok(())
+                }))
+            }))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("pause"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+
+    let start = std::time::Instant::now();
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    assert!(start.elapsed() >= Duration::from_millis(150));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_app_events() -> std::io::Result<()> {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+
+    let srv = server::test_server(move || {
+        let events = events2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::fn_factory_with_config(|session: Session<St>| {
+                let sender = session.sink().events::<u32>();
+                ok::<_, ()>(ntex::fn_service(move |_: Publish| {
+                    for ev in 1..4 {
+                        assert!(sender.send(ev).is_ok());
+                    }
+                    ok(())
+                }))
+            }))
+            .control(move |msg| {
+                if let ControlMessage::Event(ref ev) = msg {
+                    events.lock().unwrap().push(*ev.get::<u32>().unwrap());
+                }
+                match msg {
+                    ControlMessage::Event(ev) if ev.get::<u32>() != Some(&3) => ok(ev.ack()),
+                    _ => ok(msg.disconnect()),
+                }
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sleep(Duration::from_millis(100)).await;
+
+    // events are handled in order, last event closes connection
+    assert_eq!(*events.lock().unwrap(), vec![1, 2, 3]);
+    assert!(!sink.is_open());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_drain() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        let registry = SessionRegistry::new();
+        let registry2 = registry.clone();
+        MqttServer::new(handshake)
+            .session_registry(registry)
+            .publish(move |p: Publish| {
+                if p.publish_topic() == "drain" {
+                    ntex::rt::spawn(registry2.drain(Duration::from_millis(500)));
+                }
+                ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    // previous connection of the client is closed
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+    assert!(!sink.is_open());
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish(ByteString::from_static("drain"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+    assert!(!sink.is_open());
+
+    // new connections are rejected
+    let res = client::MqttConnector::new(srv.addr()).client_id("user2").connect().await;
+    match res {
+        Err(ClientError::Ack { return_code, .. }) => {
+            assert_eq!(return_code, codec::ConnectAckReason::ServiceUnavailable)
+        }
+        _ => panic!("Expect connect ack error"),
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_drain_handle() -> std::io::Result<()> {
+    let drain = Drain::new();
+    let drain2 = drain.clone();
+
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .session_registry(SessionRegistry::with_drain(&drain2))
+            .publish(|_| ok::<_, ()>(()))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    assert!(!drain.is_draining());
+
+    // sessions of server workers are drained from test thread
+    let (tx, rx) = std::sync::mpsc::channel();
+    let drain3 = drain.clone();
+    std::thread::spawn(move || {
+        futures::executor::block_on(drain3.drain(Duration::from_millis(500)));
+        let _ = tx.send(());
+    });
+    sleep(Duration::from_millis(100)).await;
+    assert!(rx.try_recv().is_ok());
+    assert!(drain.is_draining());
+    assert!(!sink.is_open());
+
+    let res = client::MqttConnector::new(srv.addr()).client_id("user2").connect().await;
+    assert!(matches!(
+        res,
+        Err(ClientError::Ack { return_code: codec::ConnectAckReason::ServiceUnavailable, .. })
+    ));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_handle() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|_| ok::<_, ()>(()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        sub.confirm(sub.qos());
+                    }
+                    ok(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    let handle = sink.handle();
+    ntex::rt::spawn(client.start_default());
+
+    // requests are executed on connection's thread
+    let (tx, rx) = futures::channel::oneshot::channel();
+    let handle2 = handle.clone();
+    std::thread::spawn(move || {
+        let res = futures::executor::block_on(async move {
+            let qos0 = handle2
+                .publish(ByteString::from_static("test"), Bytes::new(), codec::QoS::AtMostOnce)
+                .await;
+            let qos2 = handle2
+                .publish(ByteString::from_static("test"), Bytes::new(), codec::QoS::ExactlyOnce)
+                .await;
+            let subscribe = handle2
+                .subscribe(vec![(ByteString::from_static("test"), codec::QoS::AtLeastOnce)])
+                .await;
+            let unsubscribe = handle2.unsubscribe(vec![ByteString::from_static("test")]).await;
+            (qos0, qos2, subscribe, unsubscribe)
+        });
+        let _ = tx.send(res);
+    });
+
+    let (qos0, qos2, subscribe, unsubscribe) = rx.await.unwrap();
+    assert_eq!(qos0, Ok(()));
+    assert_eq!(qos2, Ok(()));
+    assert_eq!(
+        subscribe,
+        Ok(vec![codec::SubscribeReturnCode::Success(codec::QoS::AtLeastOnce)])
+    );
+    assert_eq!(unsubscribe, Ok(()));
+
+    // handle does not keep connection alive
+    sink.close();
+    sleep(Duration::from_millis(50)).await;
+    assert!(handle.is_closed());
+    let res = handle
+        .publish(ByteString::from_static("test"), Bytes::new(), codec::QoS::AtMostOnce)
+        .await;
+    assert_eq!(res, Err(SendPacketError::Disconnected));
+
+    Ok(())
+}
+
+#[cfg(feature = "openssl")]
+#[ntex::test]
+async fn test_tls_peer_certificates() -> std::io::Result<()> {
+    use ntex::server::openssl::Acceptor;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::ssl::{SslAcceptor, SslConnector, SslMethod, SslVerifyMode};
+    use openssl::x509::{extension::BasicConstraints, X509NameBuilder, X509};
+
+    fn cert(name: &str, issuer: Option<(&X509, &PKey<Private>)>) -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        if let Some((ca, ca_key)) = issuer {
+            builder.set_issuer_name(ca.subject_name()).unwrap();
+            builder.sign(ca_key, MessageDigest::sha256()).unwrap();
+        } else {
+            let ext = BasicConstraints::new().critical().ca().build().unwrap();
+            builder.append_extension(ext).unwrap();
+            builder.set_issuer_name(&subject).unwrap();
+            builder.sign(&key, MessageDigest::sha256()).unwrap();
+        }
+        (builder.build(), key)
+    }
+
+    let (ca, ca_key) = cert("ca", None);
+    let (server_cert, server_key) = cert("localhost", Some((&ca, &ca_key)));
+    let (client_cert, client_key) = cert("client", Some((&ca, &ca_key)));
+
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+    builder.set_private_key(&server_key).unwrap();
+    builder.set_certificate(&server_cert).unwrap();
+    builder.cert_store_mut().add_cert(ca.clone()).unwrap();
+    builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    let acceptor = builder.build();
+
+    let certs = Arc::new(Mutex::new(None));
+    let certs2 = certs.clone();
+    let srv = server::test_server(move || {
+        let certs = certs2.clone();
+        pipeline_factory(Acceptor::new(acceptor.clone()))
+            .map_err(|_| MqttError::Service(()))
+            .and_then(
+                MqttServer::new(move |con: Handshake<_>| {
+                    *certs.lock().unwrap() = con.peer_certificates();
+                    ok::<_, ()>(con.ack(St, false))
+                })
+                .publish(|_| ok::<_, ()>(()))
+                .finish()
+                .map_init_err(|_| ()),
+            )
+    });
+
+    // client provides certificate chain
+    let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+    builder.set_verify(SslVerifyMode::NONE);
+    builder.set_certificate(&client_cert).unwrap();
+    builder.set_private_key(&client_key).unwrap();
+    builder.add_extra_chain_cert(ca.clone()).unwrap();
+
+    let client = client::MqttConnector::new(format!("127.0.0.1:{}", srv.addr().port()))
+        .openssl(builder.build())
+        .client_id("user")
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    // end-entity certificate goes first
+    let certs = certs.lock().unwrap().take().unwrap();
+    assert_eq!(certs.len(), 2);
+    assert_eq!(certs[0], Bytes::from(client_cert.to_der().unwrap()));
+    assert_eq!(certs[1], Bytes::from(ca.to_der().unwrap()));
+    sink.close();
+
     Ok(())
 }
@@ -1,9 +1,11 @@
-use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::{cell::RefCell, convert::TryFrom, rc::Rc};
 
 use futures::future::ok;
 use ntex::server;
 use ntex::util::{ByteString, Bytes};
 
+use ntex_mqtt::auth::{self, AuthProvider, AuthRequest, AuthResult, Protocol};
 use ntex_mqtt::{v3, v5, MqttServer};
 
 struct St;
@@ -47,7 +49,7 @@ async fn test_simple() -> std::io::Result<()> {
     ntex::rt::spawn(client.start_default());
 
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_ok());
     sink.close();
 
@@ -58,7 +60,114 @@ async fn test_simple() -> std::io::Result<()> {
     ntex::rt::spawn(client.start_default());
 
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sink.close();
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_unsupported_version() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new().v3(v3::MqttServer::new(|con: v3::Handshake<_>| {
+            ok::<_, TestError>(con.ack(St, false))
+        })
+        .publish(|_| ok::<_, TestError>(())))
+    });
+
+    // v5 is not served
+    let err = v5::client::MqttConnector::new(srv.addr()).client_id("user").connect().await;
+    match err {
+        Err(v5::error::ClientError::Ack(pkt)) => {
+            assert_eq!(pkt.reason_code, v5::codec::ConnectAckReason::UnsupportedProtocolVersion)
+        }
+        _ => panic!("CONNACK is expected"),
+    }
+
+    let srv = server::test_server(|| {
+        MqttServer::new()
+            .v5(v5::MqttServer::new(|con: v5::Handshake<_>| ok::<_, TestError>(con.ack(St)))
+                .publish(|p: v5::Publish| ok::<_, TestError>(p.ack())))
+    });
+
+    // v3 is not served
+    let err = v3::client::MqttConnector::new(srv.addr()).client_id("user").connect().await;
+    match err {
+        Err(v3::client::ClientError::Ack { return_code, .. }) => {
+            assert_eq!(return_code, v3::codec::ConnectAckReason::UnacceptableProtocolVersion)
+        }
+        _ => panic!("CONNACK is expected"),
+    }
+
+    // unknown protocol level gets v3 CONNACK
+    let mut io = std::net::TcpStream::connect(srv.addr())?;
+    io.write_all(b"\x10\x10\x00\x04MQTT\x06\x02\x00\x3C\x00\x04user")?;
+    let mut buf = [0; 4];
+    io.read_exact(&mut buf)?;
+    assert_eq!(&buf, b"\x20\x02\x00\x01");
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct SharedAuth(RefCell<Vec<(Protocol, u16)>>);
+
+impl AuthProvider for SharedAuth {
+    type State = St;
+    type Error = TestError;
+    type Future = futures::future::Ready<Result<AuthResult<St>, TestError>>;
+
+    fn authenticate(&self, req: AuthRequest) -> Self::Future {
+        self.0.borrow_mut().push((req.protocol, req.keep_alive));
+        futures::future::ready(Ok(AuthResult::Accept(St)))
+    }
+}
+
+#[ntex::test]
+async fn test_shared_auth() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        let provider = Rc::new(SharedAuth::default());
+        let provider2 = provider.clone();
+
+        MqttServer::new()
+            .v3(v3::MqttServer::new(auth::v3_handshake(provider.clone())).publish(move |_| {
+                assert_eq!(&provider.0.borrow()[..], &[(Protocol::V5, 10), (Protocol::V3, 20)]);
+                ok::<_, TestError>(())
+            }))
+            .v5(v5::MqttServer::new(auth::v5_handshake(provider2.clone())).publish(
+                move |p: v5::Publish| {
+                    assert_eq!(&provider2.0.borrow()[..], &[(Protocol::V5, 10)]);
+                    ok::<_, TestError>(p.ack())
+                },
+            ))
+    });
+
+    let client = v5::client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .keep_alive(10)
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sink.close();
+
+    let client = v3::client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .keep_alive(20)
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_ok());
     sink.close();
 
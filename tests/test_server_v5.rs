@@ -1,16 +1,25 @@
-use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::{cell::RefCell, rc::Rc};
 use std::{convert::TryFrom, num::NonZeroU16, time::Duration};
 
-use futures::{future::ok, FutureExt, SinkExt, StreamExt};
+use futures::future::{ok, LocalBoxFuture};
+use futures::{FutureExt, SinkExt, StreamExt};
 use ntex::codec::Framed;
 use ntex::rt::time::delay_for;
 use ntex::server;
-use ntex::util::{ByteString, Bytes};
+use ntex::service::{Service, Transform};
+use ntex::util::{select, ByteString, Bytes, Ready};
 
+use ntex_mqtt::auth::{self, AuthError, AuthProvider, AuthRequest, AuthResult};
 use ntex_mqtt::v5::{
-    client, codec, error, ControlMessage, Handshake, HandshakeAck, MqttServer, Publish,
-    PublishAck, Session,
+    client, codec, error, ControlMessage, Handshake, HandshakeAck, IntoReasonCode,
+    MemorySessionStore, MqttServer, Publish, PublishAck, ReasonError, Router, Session,
+    SessionRegistry,
 };
+use ntex_mqtt::{types::CancelPolicy, Acl, Drain, MqttMetrics, PacketInspector, RouteTable};
+use ntex_mqtt::{TopicError, TopicFilter};
 
 struct St;
 
@@ -65,13 +74,383 @@ async fn test_simple() -> std::io::Result<()> {
     ntex::rt::spawn(client.start_default());
 
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_ok());
 
     sink.close();
     Ok(())
 }
 
+#[ntex::test]
+async fn test_invalid_topic() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake).publish(|p: Publish| ok::<_, TestError>(p.ack())).finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink.publish("test/+", Bytes::new()).send_at_least_once().await;
+    assert_eq!(res, Err(error::PublishQos1Error::InvalidTopic(TopicError::WildcardInName)));
+    let res = sink.publish(String::new(), Bytes::new()).send_exactly_once().await;
+    assert_eq!(res, Err(error::PublishQos2Error::InvalidTopic(TopicError::EmptyTopic)));
+
+    let res =
+        sink.subscribe(None).topic_filter("test/a+", codec::QoS::AtLeastOnce).send().await;
+    assert_eq!(res, Err(error::SendPacketError::InvalidTopic(TopicError::InvalidLevel)));
+
+    let filter = TopicFilter::try_from("test/+").unwrap();
+    let res = sink.subscribe(None).topic_filter(filter, codec::QoS::AtLeastOnce).send().await;
+    assert!(res.is_ok());
+
+    // client sink does not send subscription identifiers
+    let id = std::num::NonZeroU32::new(1).unwrap();
+    let res = sink.publish("test", Bytes::new()).subscription_id(id).send_at_most_once();
+    assert_eq!(res, Err(error::SendPacketError::SubscriptionId));
+    let res = sink.publish("test", Bytes::new()).subscription_id(id).send_exactly_once().await;
+    assert_eq!(res, Err(error::PublishQos2Error::SubscriptionId));
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_send_exactly_once() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| {
+                if p.publish_topic() == "fail" {
+                    ok::<_, TestError>(
+                        p.ack().reason_code(codec::PublishAckReason::NotAuthorized),
+                    )
+                } else {
+                    ok::<_, TestError>(p.ack())
+                }
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_exactly_once().await;
+    assert_eq!(res.unwrap().reason_code, codec::PublishAck2Reason::Success);
+
+    let res =
+        sink.publish(ByteString::from_static("fail"), Bytes::new()).send_exactly_once().await;
+    match res {
+        Err(error::PublishQos2Error::Fail(ack)) => {
+            assert_eq!(ack.reason_code, codec::PublishAckReason::NotAuthorized)
+        }
+        _ => panic!("unexpected result"),
+    }
+    assert_eq!(sink.credit(), 15);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_receive_exactly_once() -> std::io::Result<()> {
+    let completed = Arc::new(AtomicBool::new(false));
+    let completed2 = completed.clone();
+
+    let srv = server::test_server(move || {
+        let completed = completed2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::fn_factory_with_config(move |session: Session<St>| {
+                let completed = completed.clone();
+                ok::<_, TestError>(ntex::fn_service(move |p: Publish| {
+                    // send qos2 publish to the client
+                    let sink = session.sink().clone();
+                    let completed = completed.clone();
+                    ntex::rt::spawn(async move {
+                        let res = sink
+                            .publish(ByteString::from_static("qos2"), Bytes::new())
+                            .send_exactly_once()
+                            .await;
+                        if res.map(|ack| ack.reason_code)
+                            == Ok(codec::PublishAck2Reason::Success)
+                        {
+                            completed.store(true, Relaxed);
+                        }
+                    });
+                    ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let received = Rc::new(RefCell::new(0));
+    let received2 = received.clone();
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(
+        client
+            .resource("qos2", move |p: Publish| {
+                *received2.borrow_mut() += 1;
+                ok::<_, TestError>(p.ack())
+            })
+            .start_default(),
+    );
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    delay_for(Duration::from_millis(100)).await;
+    assert_eq!(*received.borrow(), 1);
+    assert!(completed.load(Relaxed));
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_reauthenticate() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(|msg| match msg {
+                ControlMessage::Auth(msg) => {
+                    let reason_code = if msg.packet().auth_data.as_ref().unwrap() == "first" {
+                        codec::AuthReasonCode::ContinueAuth
+                    } else {
+                        codec::AuthReasonCode::Success
+                    };
+                    ok::<_, TestError>(
+                        msg.ack(codec::Auth { reason_code, ..Default::default() }),
+                    )
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .reauthenticate(ByteString::from_static("test"), Bytes::from_static(b"first"), |ack| {
+            assert_eq!(ack.reason_code, codec::AuthReasonCode::ContinueAuth);
+            codec::Auth {
+                reason_code: codec::AuthReasonCode::ContinueAuth,
+                auth_method: Some(ByteString::from_static("test")),
+                auth_data: Some(Bytes::from_static(b"second")),
+                ..Default::default()
+            }
+        })
+        .await;
+    assert_eq!(res.unwrap().reason_code, codec::AuthReasonCode::Success);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_topic_alias() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| {
+                assert_eq!(p.publish_topic(), "test/alias");
+                assert_eq!(p.packet().properties.topic_alias, NonZeroU16::new(1));
+                ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    for _ in 0..3 {
+        let res = sink
+            .publish(ByteString::from_static("test/alias"), Bytes::new())
+            .send_at_least_once()
+            .await;
+        assert!(res.is_ok());
+    }
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_request_response() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(ntex::fn_factory_with_config(|session: Session<St>| {
+                ok::<_, TestError>(ntex::fn_service(move |p: Publish| {
+                    assert_eq!(p.response_topic().unwrap(), "response/user");
+                    assert!(p.correlation_data().is_some());
+                    let mut payload = p.payload().to_vec();
+                    payload.extend_from_slice(b"-response");
+                    session
+                        .sink()
+                        .response(&p, Bytes::from(payload))
+                        .unwrap()
+                        .send_at_most_once()
+                        .unwrap();
+                    ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        assert_eq!(sub.topic(), "response/user");
+                        sub.confirm(codec::QoS::AtLeastOnce);
+                    }
+                    ok::<_, TestError>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    for req in &["request1", "request2"] {
+        let res = sink
+            .request(
+                ByteString::from_static("test/request"),
+                ByteString::from_static("response/user"),
+                Bytes::from_static(req.as_bytes()),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.publish_topic(), "response/user");
+        assert_eq!(res.payload().as_ref(), format!("{}-response", req).as_bytes());
+    }
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_will() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| {
+            let will = con.packet().last_will.as_ref().unwrap();
+            assert_eq!(will.topic, "will/topic");
+            assert_eq!(will.message, Bytes::from_static(b"offline"));
+            assert_eq!(will.qos, codec::QoS::AtLeastOnce);
+            assert!(will.retain);
+            assert_eq!(will.will_delay_interval_sec, Some(5));
+            assert_eq!(will.message_expiry_interval, std::num::NonZeroU32::new(60));
+            assert_eq!(will.content_type, Some(ByteString::from_static("text/plain")));
+            assert_eq!(
+                will.user_properties,
+                vec![(ByteString::from_static("key"), ByteString::from_static("value"))]
+            );
+            ok::<_, TestError>(con.ack(St))
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    // connect to server
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .will(ByteString::from_static("will/topic"), Bytes::from_static(b"offline"), |will| {
+            will.qos = codec::QoS::AtLeastOnce;
+            will.retain = true;
+            will.will_delay_interval_sec = Some(5);
+            will.message_expiry_interval = std::num::NonZeroU32::new(60);
+            will.content_type = Some(ByteString::from_static("text/plain"));
+            will.user_properties
+                .push((ByteString::from_static("key"), ByteString::from_static("value")));
+        })
+        .connect()
+        .await
+        .unwrap();
+
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_redirect() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| {
+            ok::<_, TestError>(con.redirect::<St>(ByteString::from_static("srv2:1883"), false))
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let err =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.err().unwrap();
+    if let error::ClientError::Ack(pkt) = err {
+        assert_eq!(pkt.reason_code, codec::ConnectAckReason::UseAnotherServer);
+        assert_eq!(pkt.server_reference, Some(ByteString::from_static("srv2:1883")));
+    } else {
+        panic!("Expected ConnectAck error");
+    }
+
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(ntex::fn_factory_with_config(|session: Session<St>| {
+                ok::<_, TestError>(ntex::fn_service(move |p: Publish| {
+                    session.sink().redirect(ByteString::from_static("srv2:1883"), true);
+                    ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed.send(pkt_publish().into()).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::ServerMoved,
+            server_reference: Some(ByteString::from_static("srv2:1883")),
+            ..Default::default()
+        })
+    );
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_disconnect() -> std::io::Result<()> {
     let srv = server::test_server(|| {
@@ -97,7 +476,7 @@ async fn test_disconnect() -> std::io::Result<()> {
     ntex::rt::spawn(client.start_default());
 
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_err());
 
     Ok(())
@@ -132,7 +511,7 @@ async fn test_disconnect_with_reason() -> std::io::Result<()> {
     ntex::rt::spawn(client.start_default());
 
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_err());
 
     Ok(())
@@ -258,6 +637,65 @@ async fn test_ack_order() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_publish_qos2() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| {
+                ok::<_, TestError>(
+                    p.ack().reason_code(codec::PublishAckReason::NoMatchingSubscribers),
+                )
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed
+        .send(codec::Publish { qos: codec::QoS::ExactlyOnce, ..pkt_publish() }.into())
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishReceived(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::NoMatchingSubscribers,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    );
+
+    let rel = codec::PublishAck2 {
+        packet_id: NonZeroU16::new(1).unwrap(),
+        reason_code: codec::PublishAck2Reason::Success,
+        properties: Default::default(),
+        reason_string: None,
+    };
+    framed.send(codec::Packet::PublishRelease(rel.clone())).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishComplete(rel.clone()));
+
+    // packet id is already released
+    framed.send(codec::Packet::PublishRelease(rel.clone())).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishComplete(codec::PublishAck2 {
+            reason_code: codec::PublishAck2Reason::PacketIdNotFound,
+            ..rel
+        })
+    );
+
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_dups() {
     let srv = server::test_server(move || {
@@ -371,7 +809,115 @@ async fn test_dups() {
 }
 
 #[ntex::test]
-async fn test_max_receive() {
+async fn test_dups_qos2() {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| {
+                delay_for(Duration::from_millis(10000))
+                    .map(move |_| Ok::<_, TestError>(p.ack()))
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(
+            codec::Connect::default().client_id("user").receive_max(2),
+        ))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let pkt = codec::Publish { qos: codec::QoS::ExactlyOnce, ..pkt_publish() };
+    framed.send(pkt.clone().into()).await.unwrap();
+
+    // qos2 packet_id dup is answered with PUBREC
+    framed.send(pkt.into()).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishReceived(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::PacketIdentifierInUse,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    );
+}
+
+#[ntex::test]
+async fn test_client_dups() -> std::io::Result<()> {
+    let acks = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let acks2 = acks.clone();
+
+    // server sends duplicated qos1 and qos2 publishes
+    let srv = server::test_server(move || {
+        let acks = acks2.clone();
+        ntex::fn_service(move |io: ntex::rt::net::TcpStream| {
+            let acks = acks.clone();
+            async move {
+                let mut framed = Framed::new(io, codec::Codec::default());
+                if let Some(Ok(codec::Packet::Connect(_))) = framed.next().await {
+                    let ack = codec::ConnectAck {
+                        reason_code: codec::ConnectAckReason::Success,
+                        ..Default::default()
+                    };
+                    framed.send(codec::Packet::ConnectAck(ack)).await.unwrap();
+                }
+
+                let qos1 = pkt_publish();
+                let qos2 = codec::Publish {
+                    qos: codec::QoS::ExactlyOnce,
+                    packet_id: Some(NonZeroU16::new(2).unwrap()),
+                    ..pkt_publish()
+                };
+                for pkt in vec![qos1.clone(), qos1, qos2.clone(), qos2] {
+                    framed.send(pkt.into()).await.unwrap();
+                }
+                while let Some(Ok(pkt)) = framed.next().await {
+                    acks.lock().unwrap().push(pkt);
+                }
+                Ok::<_, ()>(())
+            }
+        })
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(
+        client
+            .resource("test", |p: Publish| {
+                delay_for(Duration::from_millis(10000))
+                    .map(move |_| Ok::<_, TestError>(p.ack()))
+            })
+            .start_default(),
+    );
+
+    delay_for(Duration::from_millis(100)).await;
+    assert_eq!(
+        &acks.lock().unwrap()[..],
+        &[
+            codec::Packet::PublishAck(codec::PublishAck {
+                packet_id: NonZeroU16::new(1).unwrap(),
+                reason_code: codec::PublishAckReason::PacketIdentifierInUse,
+                ..Default::default()
+            }),
+            codec::Packet::PublishReceived(codec::PublishAck {
+                packet_id: NonZeroU16::new(2).unwrap(),
+                reason_code: codec::PublishAckReason::PacketIdentifierInUse,
+                ..Default::default()
+            }),
+        ]
+    );
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_receive() {
     let srv = server::test_server(move || {
         MqttServer::new(handshake)
             .receive_max(1)
@@ -432,6 +978,76 @@ async fn test_max_receive() {
     );
 }
 
+#[ntex::test]
+async fn test_topic_alias_invalid() {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .max_topic_alias(4)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::ProtocolError(msg) => ok::<_, TestError>(msg.ack()),
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    for (topic, alias) in &[("test", 5), ("", 2)] {
+        let io = srv.connect().await.unwrap();
+        let mut framed = Framed::new(io, codec::Codec::default());
+        framed
+            .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+            .await
+            .unwrap();
+        let _ = framed.next().await.unwrap().unwrap();
+
+        let mut pkt = codec::Publish { topic: ByteString::from(*topic), ..pkt_publish() };
+        pkt.properties.topic_alias = NonZeroU16::new(*alias);
+        framed.send(pkt.into()).await.unwrap();
+
+        let pkt = framed.next().await.unwrap().unwrap();
+        assert_eq!(
+            pkt,
+            codec::Packet::Disconnect(codec::Disconnect::new(
+                codec::DisconnectReasonCode::TopicAliasInvalid
+            ))
+        );
+    }
+}
+
+#[ntex::test]
+async fn test_client_subscription_id() {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::ProtocolError(msg) => ok::<_, TestError>(msg.ack()),
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    // client must not send subscription identifiers (MQTT-3.3.4-6)
+    let mut pkt = pkt_publish();
+    pkt.properties.subscription_ids = Some(vec![std::num::NonZeroU32::new(1).unwrap()]);
+    framed.send(pkt.into()).await.unwrap();
+
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::ProtocolError
+        ))
+    );
+}
+
 #[ntex::test]
 async fn test_keepalive() {
     let ka = Arc::new(AtomicBool::new(false));
@@ -500,11 +1116,11 @@ async fn test_keepalive2() {
 
     assert!(sink.is_open());
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_ok());
     delay_for(Duration::from_millis(1200)).await;
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_ok());
     delay_for(Duration::from_millis(2500)).await;
 
@@ -554,7 +1170,7 @@ async fn test_sink_encoder_error_pub_qos1() {
     ntex::rt::spawn(client.start_default());
 
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_ok());
 }
 
@@ -598,7 +1214,7 @@ async fn test_sink_encoder_error_pub_qos0() {
     ntex::rt::spawn(client.start_default());
 
     let res =
-        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
     assert!(res.is_ok());
 }
 
@@ -635,7 +1251,7 @@ async fn test_request_problem_info() {
     ntex::rt::spawn(client.start_default());
 
     let res = sink
-        .publish(ByteString::from_static("#"), Bytes::new())
+        .publish(ByteString::from_static("test"), Bytes::new())
         .send_at_least_once()
         .await
         .unwrap();
@@ -697,3 +1313,2634 @@ async fn test_suback_with_reason() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[ntex::test]
+async fn test_capabilities() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|con: Handshake<_>| {
+            ok::<_, TestError>(
+                con.ack(St)
+                    .retain_available(false)
+                    .wildcard_subscription_available(false)
+                    .shared_subscription_available(false)
+                    .subscription_identifiers_available(false),
+            )
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .control(move |msg| match msg {
+            ControlMessage::Subscribe(mut msg) => {
+                for mut sub in &mut msg {
+                    assert_eq!(sub.topic(), "topic1");
+                    sub.confirm(codec::QoS::AtLeastOnce);
+                }
+                ok::<_, TestError>(msg.ack())
+            }
+            ControlMessage::ProtocolError(msg) => ok::<_, TestError>(msg.ack()),
+            _ => ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    if let codec::Packet::ConnectAck(ack) = pkt {
+        assert_eq!(ack.retain_available, Some(false));
+        assert_eq!(ack.wildcard_subscription_available, Some(false));
+        assert_eq!(ack.shared_subscription_available, Some(false));
+        assert_eq!(ack.subscription_identifiers_available, Some(false));
+    } else {
+        panic!("Expected ConnectAck packet");
+    }
+
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtLeastOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    framed
+        .send(codec::Packet::Subscribe(codec::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![
+                ("topic1".into(), opts.clone()),
+                ("topic1/+".into(), opts.clone()),
+                ("$share/group/topic1".into(), opts.clone()),
+            ],
+            id: None,
+            user_properties: codec::UserProperties::default(),
+        }))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::SubscribeAck(codec::SubscribeAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            status: vec![
+                codec::SubscribeAckReason::GrantedQos1,
+                codec::SubscribeAckReason::WildcardSubscriptionsNotSupported,
+                codec::SubscribeAckReason::SharedSubsriptionNotSupported,
+            ],
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        })
+    );
+
+    framed.send(codec::Publish { retain: true, ..pkt_publish() }.into()).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::RetainNotSupported
+        ))
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_user_properties() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| {
+            assert_eq!(con.get_user_property("client"), Some(&ByteString::from("test")));
+            ok::<_, TestError>(con.ack(St).user_property("server".into(), "ntex-mqtt".into()))
+        })
+        .publish(|p: Publish| {
+            assert_eq!(p.get_user_property("key1"), Some(&ByteString::from("value1")));
+            assert_eq!(p.get_user_property("key2"), None);
+            ok::<_, TestError>(p.ack().user_property("ack".into(), "value".into()))
+        })
+        .finish()
+    });
+
+    // connect to server
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .properties(|props| props.push(("client".into(), "test".into())))
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(
+        client.packet().user_properties,
+        vec![(ByteString::from("server"), ByteString::from("ntex-mqtt"))]
+    );
+
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .user_property("key1".into(), "value1".into())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    assert_eq!(res.properties, vec![(ByteString::from("ack"), ByteString::from("value"))]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_expiry() -> std::io::Result<()> {
+    let expired = Arc::new(AtomicBool::new(false));
+    let expired2 = expired.clone();
+
+    let srv = server::test_server(move || {
+        let expired = expired2.clone();
+        MqttServer::new(|con: Handshake<_>| {
+            assert_eq!(con.session_expiry_interval(), 10);
+            ok::<_, TestError>(con.ack(St))
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .control(move |msg| match msg {
+            ControlMessage::SessionExpired(msg) => {
+                expired.store(true, Relaxed);
+                ok::<_, TestError>(msg.ack())
+            }
+            ControlMessage::Disconnect(msg) => ok(msg.ack()),
+            ControlMessage::Closed(msg) => ok(msg.ack()),
+            _ => ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    let mut connect = codec::Connect::default().client_id("user");
+    connect.session_expiry_interval_secs = Some(10);
+    framed.send(codec::Packet::Connect(connect)).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed
+        .send(codec::Packet::Disconnect(codec::Disconnect {
+            session_expiry_interval_secs: Some(0),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    delay_for(Duration::from_millis(200)).await;
+    assert!(expired.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_expiry_cancelled() -> std::io::Result<()> {
+    let expired = Arc::new(AtomicUsize::new(0));
+    let expired2 = expired.clone();
+
+    let srv = server::test_server(move || {
+        let expired = expired2.clone();
+        MqttServer::new(|con: Handshake<_>| ok::<_, TestError>(con.ack(St)))
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::SessionExpired(msg) => {
+                    expired.fetch_add(1, Relaxed);
+                    ok::<_, TestError>(msg.ack())
+                }
+                ControlMessage::Closed(msg) => ok(msg.ack()),
+                _ => ok(msg.disconnect()),
+            })
+            .session_registry(SessionRegistry::new())
+            .finish()
+    });
+
+    let srv = &srv;
+    let connect = move || async move {
+        let io = srv.connect().await.unwrap();
+        let mut framed = Framed::new(io, codec::Codec::default());
+        let mut connect = codec::Connect::default().client_id("user");
+        connect.session_expiry_interval_secs = Some(1);
+        framed.send(codec::Packet::Connect(connect)).await.unwrap();
+        let _ = framed.next().await.unwrap().unwrap();
+        framed
+    };
+
+    // client reconnects before session expires
+    let framed = connect().await;
+    drop(framed);
+    delay_for(Duration::from_millis(200)).await;
+    let framed = connect().await;
+    delay_for(Duration::from_millis(1200)).await;
+    assert_eq!(expired.load(Relaxed), 0);
+
+    // session expires after last connection is closed
+    drop(framed);
+    delay_for(Duration::from_millis(1300)).await;
+    assert_eq!(expired.load(Relaxed), 1);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_store() -> std::io::Result<()> {
+    let restored = Arc::new(AtomicUsize::new(0));
+    let restored2 = restored.clone();
+
+    let srv = server::test_server(move || {
+        let restored = restored2.clone();
+        MqttServer::new(move |con: Handshake<_>| {
+            if let Some(st) = con.session_state() {
+                restored.store(st.subscriptions.len(), Relaxed);
+            }
+            ok::<_, TestError>(con.ack(St))
+        })
+        .publish(ntex::fn_factory_with_config(|session: Session<St>| {
+            ok::<_, TestError>(ntex::fn_service(move |p: Publish| {
+                // deliver qos 2 publish to the client
+                let pkt = p.packet();
+                let builder = session.sink().publish(pkt.topic.clone(), pkt.payload.clone());
+                ntex::rt::spawn(async move {
+                    let _ = builder.send_exactly_once().await;
+                });
+                ok::<_, TestError>(p.ack())
+            }))
+        }))
+        .control(|msg| match msg {
+            ControlMessage::Subscribe(mut msg) => {
+                for mut sub in &mut msg {
+                    sub.confirm(codec::QoS::ExactlyOnce);
+                }
+                ok::<_, TestError>(msg.ack())
+            }
+            ControlMessage::Closed(msg) => ok(msg.ack()),
+            _ => ok(msg.disconnect()),
+        })
+        .session_registry(SessionRegistry::new())
+        .session_store(MemorySessionStore::default())
+        .finish()
+    });
+
+    let srv = &srv;
+    let connect = move |clean_start| async move {
+        let io = srv.connect().await.unwrap();
+        let mut framed = Framed::new(io, codec::Codec::default());
+        let mut connect = codec::Connect::default().client_id("user");
+        connect.clean_start = clean_start;
+        connect.session_expiry_interval_secs = Some(30);
+        framed.send(codec::Packet::Connect(connect)).await.unwrap();
+        let session_present = match framed.next().await.unwrap().unwrap() {
+            codec::Packet::ConnectAck(ack) => ack.session_present,
+            pkt => panic!("Unexpected packet: {:?}", pkt),
+        };
+        (framed, session_present)
+    };
+
+    let (mut framed, session_present) = connect(false).await;
+    assert!(!session_present);
+
+    framed
+        .send(codec::Packet::Subscribe(codec::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![(
+                "topic1".into(),
+                codec::SubscriptionOptions::new(codec::QoS::ExactlyOnce),
+            )],
+            id: None,
+            user_properties: codec::UserProperties::default(),
+        }))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::SubscribeAck(_)));
+
+    // server publishes qos 2 message, client receives it but does not complete
+    framed
+        .send(codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from_static("topic1"),
+            packet_id: None,
+            payload: Bytes::new(),
+            properties: Default::default(),
+        }))
+        .await
+        .unwrap();
+    let packet_id = match framed.next().await.unwrap().unwrap() {
+        codec::Packet::Publish(pkt) => pkt.packet_id.unwrap(),
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    };
+    framed
+        .send(codec::Packet::PublishReceived(codec::PublishAck {
+            packet_id,
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::PublishRelease(_)));
+    drop(framed);
+    delay_for(Duration::from_millis(100)).await;
+
+    // PUBREL is re-sent after reconnect
+    let (mut framed, session_present) = connect(false).await;
+    assert!(session_present);
+    assert_eq!(restored.load(Relaxed), 1);
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::PublishRelease(pkt) => assert_eq!(pkt.packet_id, packet_id),
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    }
+    framed
+        .send(codec::Packet::PublishComplete(codec::PublishAck2 {
+            packet_id,
+            reason_code: codec::PublishAck2Reason::Success,
+            properties: codec::UserProperties::default(),
+            reason_string: None,
+        }))
+        .await
+        .unwrap();
+    delay_for(Duration::from_millis(100)).await;
+    drop(framed);
+    delay_for(Duration::from_millis(100)).await;
+
+    // clean start discards stored session
+    let (framed, session_present) = connect(true).await;
+    assert!(!session_present);
+    drop(framed);
+    delay_for(Duration::from_millis(100)).await;
+    let (_, session_present) = connect(false).await;
+    assert!(!session_present);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_close_with_code() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(ntex::fn_factory_with_config(|session: Session<St>| {
+                ok::<_, TestError>(ntex::fn_service(move |p: Publish| {
+                    session.sink().close_with_code(
+                        codec::DisconnectReasonCode::QuotaExceeded,
+                        Some(ByteString::from_static("quota")),
+                        vec![(ByteString::from_static("k"), ByteString::from_static("v"))],
+                    );
+                    ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed.send(pkt_publish().into()).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::QuotaExceeded,
+            reason_string: Some(ByteString::from_static("quota")),
+            user_properties: vec![(ByteString::from_static("k"), ByteString::from_static("v"))],
+            ..Default::default()
+        })
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_managed_client() -> std::io::Result<()> {
+    let subscribed = Arc::new(AtomicUsize::new(0));
+    let published = Arc::new(AtomicUsize::new(0));
+    let subscribed2 = subscribed.clone();
+    let published2 = published.clone();
+
+    let srv = server::test_server(move || {
+        let subscribed = subscribed2.clone();
+        let published = published2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::fn_factory_with_config(move |session: Session<St>| {
+                let published = published.clone();
+                ok::<_, TestError>(ntex::fn_service(move |p: Publish| {
+                    // drop connection on first publish
+                    if published.fetch_add(1, Relaxed) == 0 {
+                        session.sink().close();
+                    } else {
+                        assert!(p.packet().dup);
+                    }
+                    async move {
+                        delay_for(Duration::from_millis(50)).await;
+                        Ok::<_, TestError>(p.ack())
+                    }
+                }))
+            }))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    subscribed.fetch_add(1, Relaxed);
+                    for mut sub in &mut msg {
+                        sub.confirm(codec::QoS::AtLeastOnce);
+                    }
+                    ok::<_, TestError>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .into_managed()
+        .backoff(Duration::from_millis(50), Duration::from_millis(100));
+    let sink = client.sink();
+    ntex::rt::spawn(client.start(|msg: client::ControlMessage| ok::<_, ()>(msg.disconnect())));
+
+    assert!(sink.connected().await.is_some());
+    let res = sink
+        .subscribe(
+            ByteString::from_static("topic"),
+            codec::SubscriptionOptions {
+                qos: codec::QoS::AtLeastOnce,
+                no_local: false,
+                retain_as_published: false,
+                retain_handling: codec::RetainHandling::AtSubscribe,
+            },
+        )
+        .await;
+    assert!(res.is_ok());
+    assert_eq!(subscribed.load(Relaxed), 1);
+
+    // publish is re-sent after re-connect
+    let res = sink.publish_at_least_once(ByteString::from_static("topic"), Bytes::new()).await;
+    assert!(res.is_ok());
+    assert_eq!(published.load(Relaxed), 2);
+
+    // server does not keep session, topic filters are re-subscribed
+    assert_eq!(subscribed.load(Relaxed), 2);
+
+    sink.close();
+    assert!(sink.connected().await.is_none());
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_managed_client_offline() -> std::io::Result<()> {
+    let topics = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let topics2 = topics.clone();
+
+    let srv = server::test_server(move || {
+        let topics = topics2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::fn_factory_with_config(move |session: Session<St>| {
+                let topics = topics.clone();
+                ok::<_, TestError>(ntex::fn_service(move |p: Publish| {
+                    topics.lock().unwrap().push(p.publish_topic().to_string());
+                    if p.publish_topic() == "close" {
+                        session.sink().close();
+                    }
+                    ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .into_managed()
+        .backoff(Duration::from_millis(100), Duration::from_millis(100))
+        .offline_buffer(3, client::OfflinePolicy::Reject);
+    let sink = client.sink();
+    ntex::rt::spawn(client.start(|msg: client::ControlMessage| ok::<_, ()>(msg.disconnect())));
+
+    assert!(sink.connected().await.is_some());
+    sink.publish_at_most_once(ByteString::from_static("close"), Bytes::new()).unwrap();
+    while sink.is_connected() {
+        delay_for(Duration::from_millis(10)).await;
+    }
+
+    // qos1 and qos2 publishes are buffered while client is disconnected
+    let sink2 = sink.clone();
+    let res1 = ntex::rt::spawn(async move {
+        sink2.publish_at_least_once(ByteString::from_static("a"), Bytes::new()).await
+    });
+    delay_for(Duration::from_millis(10)).await;
+    let sink2 = sink.clone();
+    let res2 = ntex::rt::spawn(async move {
+        sink2.publish_exactly_once(ByteString::from_static("b"), Bytes::new()).await
+    });
+    delay_for(Duration::from_millis(10)).await;
+    sink.publish_at_most_once(ByteString::from_static("c"), Bytes::new()).unwrap();
+
+    // buffer is full
+    let res = sink.publish_at_least_once(ByteString::from_static("d"), Bytes::new()).await;
+    assert_eq!(res, Err(error::PublishQos1Error::Disconnected));
+
+    // buffered publishes are sent in order after re-connect
+    assert!(res1.await.unwrap().is_ok());
+    assert!(res2.await.unwrap().is_ok());
+    delay_for(Duration::from_millis(50)).await;
+    assert_eq!(*topics.lock().unwrap(), vec!["close", "a", "b", "c"]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_managed_client_store() -> std::io::Result<()> {
+    let ids = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let ids2 = ids.clone();
+
+    let srv = server::test_server(move || {
+        let ids = ids2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                ids.lock().unwrap().push((p.id().map(|id| id.get()), p.packet().dup));
+                ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // unacknowledged publish from previous run
+    let mut store = client::MemoryStore::default();
+    client::ClientStore::store_publish(&mut store, &pkt_publish());
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").into_managed().store(store);
+    let sink = client.sink();
+    ntex::rt::spawn(client.start(|msg: client::ControlMessage| ok::<_, ()>(msg.disconnect())));
+
+    assert!(sink.connected().await.is_some());
+    delay_for(Duration::from_millis(50)).await;
+    assert_eq!(*ids.lock().unwrap(), vec![(Some(1), true)]);
+
+    // packet ids do not collide with stored publishes
+    let res = sink.publish_at_least_once(ByteString::from_static("test"), Bytes::new()).await;
+    assert!(res.is_ok());
+    assert_eq!(*ids.lock().unwrap(), vec![(Some(1), true), (Some(2), false)]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_managed_client_store_release() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake).publish(|p: Publish| ok::<_, TestError>(p.ack())).finish()
+    });
+
+    // qos2 publish from previous run is received by the server
+    let mut store = client::MemoryStore::default();
+    let pkt = codec::Publish { qos: codec::QoS::ExactlyOnce, ..pkt_publish() };
+    client::ClientStore::store_publish(&mut store, &pkt);
+    client::ClientStore::release_publish(&mut store, NonZeroU16::new(1).unwrap());
+
+    let inspector = TestInspector::default();
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .inspector(inspector.clone())
+        .into_managed()
+        .store(store);
+    let sink = client.sink();
+    ntex::rt::spawn(client.start(|msg: client::ControlMessage| ok::<_, ()>(msg.disconnect())));
+
+    assert!(sink.connected().await.is_some());
+    delay_for(Duration::from_millis(50)).await;
+
+    // CONNECT, PUBREL
+    assert_eq!(&*inspector.outbound.borrow(), &[0x10, 0x62]);
+    // CONNACK, PUBCOMP
+    assert_eq!(&*inspector.inbound.borrow(), &[0x20, 0x70]);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_session() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|packet: Handshake<_>| async move {
+            let session = !packet.packet().clean_start
+                && packet.packet().session_expiry_interval_secs == Some(60);
+            Ok::<_, TestError>(packet.ack(St).with(|ack| {
+                ack.session_present = session;
+                ack.assigned_client_id = Some(ByteString::from_static("assigned"));
+            }))
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .clean_start(false)
+        .session_expiry_interval(Duration::from_secs(60))
+        .connect()
+        .await
+        .unwrap();
+    assert!(client.session_present());
+    assert_eq!(client.assigned_client_id(), Some(&ByteString::from_static("assigned")));
+
+    let client =
+        client::MqttConnector::new(srv.addr()).clean_start(true).connect().await.unwrap();
+    assert!(!client.session_present());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_takeover() -> std::io::Result<()> {
+    let taken_over = Arc::new(AtomicUsize::new(0));
+    let taken_over2 = taken_over.clone();
+
+    let srv = server::test_server(move || {
+        let taken_over = taken_over2.clone();
+        MqttServer::new(handshake)
+            .session_registry(SessionRegistry::new())
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::SessionTakenOver(msg) => {
+                    taken_over.fetch_add(1, Relaxed);
+                    ok::<_, TestError>(msg.ack())
+                }
+                ControlMessage::Closed(msg) => ok(msg.ack()),
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let client2 =
+        client::MqttConnector::new(srv.addr()).client_id("other").connect().await.unwrap();
+    let sink2 = client2.sink();
+    ntex::rt::spawn(client2.start_default());
+    delay_for(Duration::from_millis(50)).await;
+    assert_eq!(taken_over.load(Relaxed), 0);
+
+    // same client id, previous connection get disconnected
+    let client3 =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink3 = client3.sink();
+    ntex::rt::spawn(client3.start_default());
+    delay_for(Duration::from_millis(50)).await;
+
+    assert_eq!(taken_over.load(Relaxed), 1);
+    assert!(!sink.is_open());
+    assert!(sink2.is_open());
+    assert!(sink3.is_open());
+
+    sink2.close();
+    sink3.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_rate_disconnect() -> std::io::Result<()> {
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let exceeded2 = exceeded.clone();
+
+    let srv = server::test_server(move || {
+        let exceeded = exceeded2.clone();
+        MqttServer::new(handshake)
+            .publish_rate(2, 0)
+            .publish_rate_policy(ntex_mqtt::RateLimitPolicy::Disconnect)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::ProtocolError(msg) => {
+                    if let &error::ProtocolError::PublishRateExceeded = msg.get_ref() {
+                        exceeded.store(true, Relaxed);
+                    }
+                    ok::<_, TestError>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    for _ in 0..3 {
+        let _ = sink
+            .publish(ByteString::from_static("test"), Bytes::from_static(b"data"))
+            .send_at_most_once();
+    }
+    delay_for(Duration::from_millis(100)).await;
+
+    assert!(exceeded.load(Relaxed));
+    assert!(!sink.is_open());
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_connections() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .max_connections(1)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user1").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = client::MqttConnector::new(srv.addr()).client_id("user2").connect().await;
+    match res {
+        Err(error::ClientError::Ack(ack)) => {
+            assert_eq!(ack.reason_code, codec::ConnectAckReason::ServerBusy)
+        }
+        _ => panic!("Expect connect ack error"),
+    }
+
+    // slot is released after connection is closed
+    sink.close();
+    delay_for(Duration::from_millis(100)).await;
+    let res = client::MqttConnector::new(srv.addr()).client_id("user2").connect().await;
+    assert!(res.is_ok());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_keepalive_extend() {
+    let count = Arc::new(AtomicUsize::new(0));
+    let count2 = count.clone();
+
+    let srv = server::test_server(move || {
+        let count = count2.clone();
+
+        MqttServer::new(|con: Handshake<_>| async move { Ok(con.ack(St).keep_alive(1)) })
+            .publish(|p: Publish| async move { Ok::<_, TestError>(p.ack()) })
+            .control(move |msg| match msg {
+                ControlMessage::KeepAliveTimeout(msg) => {
+                    // extend first timeout only
+                    if count.fetch_add(1, Relaxed) == 0 {
+                        ok::<_, TestError>(msg.extend())
+                    } else {
+                        ok(msg.ack())
+                    }
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    delay_for(Duration::from_millis(1500)).await;
+    assert!(sink.is_open());
+    delay_for(Duration::from_millis(3000)).await;
+    assert!(!sink.is_open());
+    assert_eq!(count.load(Relaxed), 2);
+}
+
+#[ntex::test]
+async fn test_drain() -> std::io::Result<()> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let shutdown2 = shutdown.clone();
+
+    let srv = server::test_server(move || {
+        let registry = SessionRegistry::new();
+        let registry2 = registry.clone();
+        MqttServer::new(handshake)
+            .session_registry(registry)
+            .publish(move |p: Publish| {
+                if p.publish_topic() == "drain" {
+                    ntex::rt::spawn(registry2.drain(Duration::from_millis(500)));
+                }
+                ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start(move |msg: client::ControlMessage<()>| match msg {
+        client::ControlMessage::Disconnect(msg) => {
+            if msg.packet().reason_code == codec::DisconnectReasonCode::ServerShuttingDown {
+                shutdown2.store(true, Relaxed);
+            }
+            ok(msg.ack())
+        }
+        _ => ok(msg.disconnect()),
+    }));
+
+    let _ = sink.publish(ByteString::from_static("drain"), Bytes::new()).send_at_most_once();
+    delay_for(Duration::from_millis(100)).await;
+    assert!(!sink.is_open());
+    assert!(shutdown.load(Relaxed));
+
+    // new connections are rejected
+    let res = client::MqttConnector::new(srv.addr()).client_id("user2").connect().await;
+    match res {
+        Err(error::ClientError::Ack(ack)) => {
+            assert_eq!(ack.reason_code, codec::ConnectAckReason::ServerUnavailable)
+        }
+        _ => panic!("Expect connect ack error"),
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_drain_handle() -> std::io::Result<()> {
+    let drain = Drain::new();
+    let drain2 = drain.clone();
+
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .session_registry(SessionRegistry::with_drain(&drain2))
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    // sessions of server workers are drained from another thread
+    let drain3 = drain.clone();
+    let handle = std::thread::spawn(move || {
+        futures::executor::block_on(drain3.drain(Duration::from_millis(500)))
+    });
+    delay_for(Duration::from_millis(100)).await;
+    assert!(handle.join().is_ok());
+    assert!(!sink.is_open());
+
+    let res = client::MqttConnector::new(srv.addr()).client_id("user2").connect().await;
+    match res {
+        Err(error::ClientError::Ack(ack)) => {
+            assert_eq!(ack.reason_code, codec::ConnectAckReason::ServerUnavailable)
+        }
+        _ => panic!("Expect connect ack error"),
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_slow_consumer() -> std::io::Result<()> {
+    let slow = Arc::new(AtomicBool::new(false));
+    let slow2 = slow.clone();
+
+    let srv = server::test_server(move || {
+        let slow = slow2.clone();
+        MqttServer::new(|con: Handshake<_>| {
+            let sink = con.sink();
+            ntex::rt::spawn(async move {
+                delay_for(Duration::from_millis(50)).await;
+                let payload = Bytes::from(vec![0; 64 * 1024]);
+                for _ in 0..256 {
+                    let _ = sink
+                        .publish(ByteString::from_static("test"), payload.clone())
+                        .send_at_most_once();
+                }
+            });
+            ok::<_, TestError>(con.ack(St))
+        })
+        .write_buffer_limit(1024, 1)
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .control(move |msg| match msg {
+            ControlMessage::SlowConsumer(msg) => {
+                slow.store(true, Relaxed);
+                ok::<_, TestError>(msg.ack())
+            }
+            _ => ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    // client does not read from connection
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    delay_for(Duration::from_millis(3500)).await;
+    assert!(slow.load(Relaxed));
+    Ok(())
+}
+
+struct TestAuth;
+
+impl AuthProvider for TestAuth {
+    type State = St;
+    type Error = TestError;
+    type Future = futures::future::Ready<Result<AuthResult<St>, TestError>>;
+
+    fn authenticate(&self, req: AuthRequest) -> Self::Future {
+        let res = match (req.method.as_deref(), req.step) {
+            (None, _) => {
+                if req.username.as_deref() == Some("user")
+                    && req.password == Some(Bytes::from_static(b"pwd"))
+                {
+                    AuthResult::Accept(St)
+                } else {
+                    AuthResult::Reject(AuthError::BadCredentials)
+                }
+            }
+            (Some("test"), 0) => AuthResult::Continue(Some(Bytes::from_static(b"challenge"))),
+            (Some("test"), _) if req.data == Some(Bytes::from_static(b"response")) => {
+                AuthResult::Accept(St)
+            }
+            (Some("test"), _) => AuthResult::Reject(AuthError::NotAuthorized),
+            (Some("loop"), _) => AuthResult::Continue(None),
+            _ => AuthResult::Reject(AuthError::BadAuthMethod),
+        };
+        ok(res)
+    }
+
+    fn auth_timeout(&self) -> Duration {
+        Duration::from_millis(300)
+    }
+}
+
+#[ntex::test]
+async fn test_auth_provider() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(auth::v5_handshake(TestAuth))
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    // user name and password
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect {
+            client_id: ByteString::from_static("user"),
+            username: Some(ByteString::from_static("user")),
+            password: Some(Bytes::from_static(b"pwd")),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(
+        matches!(pkt, codec::Packet::ConnectAck(ack) if ack.reason_code == codec::ConnectAckReason::Success)
+    );
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect {
+            client_id: ByteString::from_static("user"),
+            username: Some(ByteString::from_static("user")),
+            password: Some(Bytes::from_static(b"pass")),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(
+        matches!(pkt, codec::Packet::ConnectAck(ack) if ack.reason_code == codec::ConnectAckReason::BadUserNameOrPassword)
+    );
+
+    // enhanced authentication
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect {
+            client_id: ByteString::from_static("user"),
+            auth_method: Some(ByteString::from_static("test")),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Auth(codec::Auth {
+            reason_code: codec::AuthReasonCode::ContinueAuth,
+            auth_method: Some(ByteString::from_static("test")),
+            auth_data: Some(Bytes::from_static(b"challenge")),
+            ..Default::default()
+        })
+    );
+    framed
+        .send(codec::Packet::Auth(codec::Auth {
+            reason_code: codec::AuthReasonCode::ContinueAuth,
+            auth_method: Some(ByteString::from_static("test")),
+            auth_data: Some(Bytes::from_static(b"response")),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    match pkt {
+        codec::Packet::ConnectAck(ack) => {
+            assert_eq!(ack.reason_code, codec::ConnectAckReason::Success);
+            assert_eq!(ack.auth_method, Some(ByteString::from_static("test")));
+        }
+        _ => panic!("Unexpected packet: {:?}", pkt),
+    }
+
+    // unsupported method
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect {
+            client_id: ByteString::from_static("user"),
+            auth_method: Some(ByteString::from_static("unknown")),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(
+        matches!(pkt, codec::Packet::ConnectAck(ack) if ack.reason_code == codec::ConnectAckReason::BadAuthenticationMethod)
+    );
+
+    // number of authentication steps is limited
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect {
+            client_id: ByteString::from_static("user"),
+            auth_method: Some(ByteString::from_static("loop")),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    let mut steps = 0;
+    loop {
+        match framed.next().await.unwrap().unwrap() {
+            codec::Packet::Auth(_) => {
+                steps += 1;
+                framed
+                    .send(codec::Packet::Auth(codec::Auth {
+                        reason_code: codec::AuthReasonCode::ContinueAuth,
+                        auth_method: Some(ByteString::from_static("loop")),
+                        ..Default::default()
+                    }))
+                    .await
+                    .unwrap();
+            }
+            codec::Packet::ConnectAck(ack) => {
+                assert_eq!(ack.reason_code, codec::ConnectAckReason::NotAuthorized);
+                break;
+            }
+            pkt => panic!("Unexpected packet: {:?}", pkt),
+        }
+    }
+    assert_eq!(steps, 16);
+
+    // client does not respond in time
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect {
+            client_id: ByteString::from_static("user"),
+            auth_method: Some(ByteString::from_static("loop")),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::Auth(_)));
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(
+        matches!(pkt, codec::Packet::ConnectAck(ack) if ack.reason_code == codec::ConnectAckReason::NotAuthorized)
+    );
+
+    Ok(())
+}
+
+struct TestAcl;
+
+impl Acl<Session<St>> for TestAcl {
+    type Future = futures::future::Ready<bool>;
+
+    fn allow_publish(&self, _: &Session<St>, topic: &ByteString) -> Self::Future {
+        futures::future::ready(!topic.starts_with("private/"))
+    }
+
+    fn allow_subscribe(&self, _: &Session<St>, filter: &ByteString) -> Self::Future {
+        futures::future::ready(!filter.starts_with("private/") && filter != "#")
+    }
+}
+
+#[ntex::test]
+async fn test_acl() -> std::io::Result<()> {
+    let publishes = Arc::new(AtomicUsize::new(0));
+    let publishes2 = publishes.clone();
+
+    let srv = server::test_server(move || {
+        let publishes = publishes2.clone();
+        MqttServer::new(handshake)
+            .acl(TestAcl)
+            .control(|msg: ControlMessage<TestError>| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in msg.iter_mut() {
+                        sub.confirm(codec::QoS::AtLeastOnce);
+                    }
+                    ok::<_, TestError>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .publish(move |p: Publish| {
+                publishes.fetch_add(1, Relaxed);
+                ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .publish(ByteString::from_static("private/test"), Bytes::new())
+        .send_at_least_once()
+        .await;
+    match res {
+        Err(error::PublishQos1Error::Fail(ack)) => {
+            assert_eq!(ack.reason_code, codec::PublishAckReason::NotAuthorized)
+        }
+        _ => panic!("Unexpected result: {:?}", res),
+    }
+    sink.publish(ByteString::from_static("test"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    assert_eq!(publishes.load(Relaxed), 1);
+
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtLeastOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    let res = sink
+        .subscribe(None)
+        .topic_filter(ByteString::from_static("test"), opts.clone())
+        .topic_filter(ByteString::from_static("private/test"), opts.clone())
+        .topic_filter(ByteString::from_static("#"), opts)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        res.status,
+        vec![
+            codec::SubscribeAckReason::GrantedQos1,
+            codec::SubscribeAckReason::NotAuthorized,
+            codec::SubscribeAckReason::NotAuthorized,
+        ]
+    );
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_stream_payload() -> std::io::Result<()> {
+    let received = Arc::new(AtomicUsize::new(0));
+    let received2 = received.clone();
+
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(handshake)
+            .stream_threshold(1024)
+            .publish(move |mut p: Publish| {
+                let received = received.clone();
+                async move {
+                    let mut stream = p.take_payload_stream().unwrap();
+                    assert_eq!(stream.size(), 256 * 1024);
+                    while let Some(chunk) = stream.next().await {
+                        let chunk = chunk.unwrap();
+                        assert!(chunk.iter().all(|b| *b == b'a'));
+                        received.fetch_add(chunk.len(), Relaxed);
+                    }
+                    Ok::<_, TestError>(p.ack())
+                }
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish(ByteString::from_static("test"), Bytes::from(vec![b'a'; 256 * 1024]))
+        .send_at_least_once()
+        .await
+        .unwrap();
+    assert_eq!(received.load(Relaxed), 256 * 1024);
+
+    sink.close();
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_packet_too_large() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake).max_size(1024).publish(|p: Publish| ok(p.ack())).finish()
+    });
+
+    // oversized connect packet
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("a".repeat(2048))))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    if let codec::Packet::ConnectAck(ack) = pkt {
+        assert_eq!(ack.reason_code, codec::ConnectAckReason::PacketTooLarge);
+    } else {
+        panic!("Expected ConnectAck packet");
+    }
+    assert!(!matches!(framed.next().await, Some(Ok(_))));
+
+    // oversized publish packet
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed
+        .send(codec::Publish { payload: Bytes::from(vec![b'*'; 4096]), ..pkt_publish() }.into())
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::PacketTooLarge
+        ))
+    );
+    assert!(!matches!(framed.next().await, Some(Ok(_))));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_strict_utf8() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake).publish(|p: Publish| ok(p.ack())).finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed
+        .send(codec::Publish { topic: ByteString::from("test\u{1}"), ..pkt_publish() }.into())
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::MalformedPacket
+        ))
+    );
+
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake).strict_utf8(false).publish(|p: Publish| ok(p.ack())).finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed
+        .send(codec::Publish { topic: ByteString::from("test\u{1}"), ..pkt_publish() }.into())
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_router_topic_filter() {
+    let srv = server::test_server(|| {
+        let router = Router::new(ntex::fn_factory_with_config(|_: Session<St>| {
+            ok::<_, TestError>(ntex::fn_service(|p: Publish| ok::<_, TestError>(p.ack())))
+        }))
+        .topic_filter("devices/+/telemetry/#", |_: Publish| {
+            ok::<_, TestError>(PublishAck::new(codec::PublishAckReason::NoMatchingSubscribers))
+        })
+        .resource("devices/{id}/telemetry/temp", |_: Publish| {
+            ok::<_, TestError>(PublishAck::new(codec::PublishAckReason::QuotaExceeded))
+        })
+        .resource("devices/{id}/state", |p: Publish| {
+            assert_eq!(p.topic().get("id"), Some("dev1"));
+            ok::<_, TestError>(PublishAck::new(codec::PublishAckReason::PayloadFormatInvalid))
+        });
+
+        MqttServer::new(handshake).publish(router).finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    for (id, topic, reason) in [
+        (1, "devices/dev1/telemetry/temp", codec::PublishAckReason::NoMatchingSubscribers),
+        (2, "devices/dev1/telemetry", codec::PublishAckReason::NoMatchingSubscribers),
+        (3, "devices/dev1/state", codec::PublishAckReason::PayloadFormatInvalid),
+        (4, "devices/dev1", codec::PublishAckReason::Success),
+    ]
+    .iter()
+    {
+        let pkt = codec::Publish {
+            topic: ByteString::from(*topic),
+            packet_id: NonZeroU16::new(*id),
+            ..pkt_publish()
+        };
+        framed.send(pkt.into()).await.unwrap();
+
+        let pkt = framed.next().await.unwrap().unwrap();
+        assert_eq!(
+            pkt,
+            codec::Packet::PublishAck(codec::PublishAck {
+                packet_id: NonZeroU16::new(*id).unwrap(),
+                reason_code: *reason,
+                properties: Default::default(),
+                reason_string: None,
+            })
+        );
+    }
+}
+
+#[ntex::test]
+async fn test_router_default_ack() {
+    let srv = server::test_server(|| {
+        let router = Router::new(ntex::fn_factory_with_config(|_: Session<St>| {
+            ok::<_, TestError>(ntex::fn_service(|p: Publish| ok::<_, TestError>(p.ack())))
+        }))
+        .default_ack(codec::PublishAckReason::NoMatchingSubscribers)
+        .resource("topic1", |p: Publish| ok::<_, TestError>(p.ack()));
+
+        MqttServer::new(handshake).publish(router).finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    for (id, topic, reason) in [
+        (1, "topic1", codec::PublishAckReason::Success),
+        (2, "topic2", codec::PublishAckReason::NoMatchingSubscribers),
+    ]
+    .iter()
+    {
+        let pkt = codec::Publish {
+            topic: ByteString::from(*topic),
+            packet_id: NonZeroU16::new(*id),
+            ..pkt_publish()
+        };
+        framed.send(pkt.into()).await.unwrap();
+
+        let pkt = framed.next().await.unwrap().unwrap();
+        assert_eq!(
+            pkt,
+            codec::Packet::PublishAck(codec::PublishAck {
+                packet_id: NonZeroU16::new(*id).unwrap(),
+                reason_code: *reason,
+                properties: Default::default(),
+                reason_string: None,
+            })
+        );
+    }
+}
+
+#[derive(Clone)]
+struct AckProperty(&'static str);
+
+impl<S> Transform<S> for AckProperty
+where
+    S: Service<Request = Publish, Response = PublishAck, Error = TestError>,
+    S::Future: 'static,
+{
+    type Request = Publish;
+    type Response = PublishAck;
+    type Error = TestError;
+    type InitError = TestError;
+    type Transform = AckPropertyService<S>;
+    type Future = Ready<Self::Transform, TestError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        Ready::Ok(AckPropertyService(service, self.0))
+    }
+}
+
+struct AckPropertyService<S>(S, &'static str);
+
+impl<S> Service for AckPropertyService<S>
+where
+    S: Service<Request = Publish, Response = PublishAck, Error = TestError>,
+    S::Future: 'static,
+{
+    type Request = Publish;
+    type Response = PublishAck;
+    type Error = TestError;
+    type Future = LocalBoxFuture<'static, Result<PublishAck, TestError>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&self, req: Publish) -> Self::Future {
+        let name = self.1;
+        let fut = self.0.call(req);
+        Box::pin(async move {
+            let ack = fut.await?;
+            Ok(ack.user_property("mw".into(), ByteString::from_static(name)))
+        })
+    }
+}
+
+#[ntex::test]
+async fn test_router_middleware() {
+    let srv = server::test_server(|| {
+        let router = Router::new(|p: Publish| ok::<_, TestError>(p.ack()))
+            .resource("topic1", |p: Publish| ok::<_, TestError>(p.ack()))
+            .wrap_resource(AckProperty("resource"))
+            .wrap(AckProperty("all"));
+
+        MqttServer::new(handshake).publish(router).finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let prop = |name| (ByteString::from_static("mw"), ByteString::from_static(name));
+    for (id, topic, props) in
+        [(1, "topic1", vec![prop("resource"), prop("all")]), (2, "topic2", vec![prop("all")])]
+            .iter()
+    {
+        let pkt = codec::Publish {
+            topic: ByteString::from(*topic),
+            packet_id: NonZeroU16::new(*id),
+            ..pkt_publish()
+        };
+        framed.send(pkt.into()).await.unwrap();
+
+        let pkt = framed.next().await.unwrap().unwrap();
+        assert_eq!(
+            pkt,
+            codec::Packet::PublishAck(codec::PublishAck {
+                packet_id: NonZeroU16::new(*id).unwrap(),
+                reason_code: codec::PublishAckReason::Success,
+                properties: props.clone(),
+                reason_string: None,
+            })
+        );
+    }
+}
+
+#[ntex::test]
+async fn test_router_route_table() {
+    let table = RouteTable::new();
+    let srv_table = table.clone();
+    let srv = server::test_server(move || {
+        let router = Router::new(|p: Publish| ok::<_, TestError>(p.ack()))
+            .default_ack(codec::PublishAckReason::NoMatchingSubscribers)
+            .route("devices", |p: Publish| ok::<_, TestError>(p.ack()))
+            .route_table(srv_table.clone());
+
+        MqttServer::new(handshake).publish(router).finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    for id in 1..4u16 {
+        let reason = match id {
+            2 => {
+                table.add("devices/+/state", "devices").unwrap();
+                codec::PublishAckReason::Success
+            }
+            3 => {
+                assert!(table.remove("devices/+/state", "devices"));
+                codec::PublishAckReason::NoMatchingSubscribers
+            }
+            _ => codec::PublishAckReason::NoMatchingSubscribers,
+        };
+
+        let pkt = codec::Publish {
+            topic: ByteString::from_static("devices/1/state"),
+            packet_id: NonZeroU16::new(id),
+            ..pkt_publish()
+        };
+        framed.send(pkt.into()).await.unwrap();
+
+        let pkt = framed.next().await.unwrap().unwrap();
+        assert_eq!(
+            pkt,
+            codec::Packet::PublishAck(codec::PublishAck {
+                packet_id: NonZeroU16::new(id).unwrap(),
+                reason_code: reason,
+                properties: Default::default(),
+                reason_string: None,
+            })
+        );
+    }
+}
+
+#[ntex::test]
+async fn test_publish_batch() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| {
+                if p.publish_topic() == "fail" {
+                    ok::<_, TestError>(
+                        p.ack().reason_code(codec::PublishAckReason::QuotaExceeded),
+                    )
+                } else {
+                    ok::<_, TestError>(p.ack())
+                }
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .publish_batch(vec![
+            (
+                ByteString::from_static("test"),
+                Bytes::from_static(b"pkt1"),
+                codec::QoS::AtMostOnce,
+            ),
+            (
+                ByteString::from_static("fail"),
+                Bytes::from_static(b"pkt2"),
+                codec::QoS::AtLeastOnce,
+            ),
+            (
+                ByteString::from_static("test"),
+                Bytes::from_static(b"pkt3"),
+                codec::QoS::ExactlyOnce,
+            ),
+            (
+                ByteString::from_static("test"),
+                Bytes::from_static(b"pkt4"),
+                codec::QoS::AtLeastOnce,
+            ),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(
+        res,
+        vec![
+            codec::PublishAckReason::Success,
+            codec::PublishAckReason::QuotaExceeded,
+            codec::PublishAckReason::Success,
+            codec::PublishAckReason::Success,
+        ]
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "json")]
+#[ntex::test]
+async fn test_publish_json() -> std::io::Result<()> {
+    let received = Arc::new(AtomicUsize::new(0));
+    let received2 = received.clone();
+
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(handshake)
+            .publish(move |mut p: Publish| {
+                assert_eq!(
+                    p.packet().properties.content_type,
+                    Some(ByteString::from_static("application/json"))
+                );
+                assert_eq!(p.json::<Vec<u32>>().unwrap(), vec![1, 2, 3]);
+                received.fetch_add(1, Relaxed);
+                ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .json(&vec![1u32, 2, 3])
+        .unwrap()
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+    assert_eq!(received.load(Relaxed), 1);
+
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .send_json(&[1u32, 2, 3])
+        .await;
+    assert!(res.is_ok());
+    assert_eq!(received.load(Relaxed), 2);
+
+    // json object keys must be strings
+    let mut value = std::collections::BTreeMap::new();
+    value.insert((1u32, 2u32), 3u32);
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_json(&value).await;
+    assert!(matches!(res, Err(error::PublishQos1Error::Serialize(_))));
+    assert_eq!(received.load(Relaxed), 2);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_ack_timeout() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| async move {
+                if p.publish_topic() == "slow" {
+                    delay_for(Duration::from_millis(300)).await;
+                }
+                Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    let credit = sink.credit();
+
+    ntex::rt::spawn(client.start_default());
+
+    sink.set_ack_timeout(Some(Duration::from_millis(100)));
+    let res =
+        sink.publish(ByteString::from_static("slow"), Bytes::new()).send_at_least_once().await;
+    assert_eq!(res, Err(error::PublishQos1Error::AckTimeout));
+    assert_eq!(sink.credit(), credit);
+
+    // late ack is ignored
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .ack_timeout(Duration::from_secs(5))
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+    assert!(sink.is_open());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_cancel_policy() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| async move {
+                if p.publish_topic() == "slow" {
+                    delay_for(Duration::from_millis(300)).await;
+                }
+                Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    let credit = sink.credit();
+
+    ntex::rt::spawn(client.start_default());
+
+    // dropped publish releases credit
+    let fut = sink.publish(ByteString::from_static("slow"), Bytes::new()).send_at_least_once();
+    let _ = select(delay_for(Duration::from_millis(50)), Box::pin(fut)).await;
+    assert_eq!(sink.credit(), credit);
+
+    // keep tracking packet until ack is received
+    sink.set_cancel_policy(CancelPolicy::Keep);
+    let fut = sink.publish(ByteString::from_static("slow"), Bytes::new()).send_at_least_once();
+    let _ = select(delay_for(Duration::from_millis(50)), Box::pin(fut)).await;
+    assert_eq!(sink.credit(), credit - 1);
+
+    delay_for(Duration::from_millis(500)).await;
+    assert_eq!(sink.credit(), credit);
+    assert!(sink.is_open());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_subscribe_options() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    assert_eq!(msg.packet().id, std::num::NonZeroU32::new(5));
+                    assert_eq!(msg.get_user_property("prop"), Some(&"val".into()));
+                    for mut sub in &mut msg {
+                        let opts = sub.options().clone();
+                        if sub.topic() == "test" {
+                            assert!(opts.no_local);
+                            assert!(opts.retain_as_published);
+                            assert_eq!(
+                                opts.retain_handling,
+                                codec::RetainHandling::NoAtSubscribe
+                            );
+                            sub.confirm(opts.qos);
+                        } else {
+                            assert!(!opts.no_local);
+                            sub.fail(codec::SubscribeAckReason::TopicFilterInvalid);
+                        }
+                    }
+                    ok::<_, TestError>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .subscribe(None)
+        .identifier(std::num::NonZeroU32::new(5).unwrap())
+        .user_property("prop".into(), "val".into())
+        .topic_filter(
+            ByteString::from_static("test"),
+            codec::SubscriptionOptions::new(codec::QoS::AtLeastOnce)
+                .no_local(true)
+                .retain_as_published(true)
+                .retain_handling(codec::RetainHandling::NoAtSubscribe),
+        )
+        .topic_filter(ByteString::from_static("invalid"), codec::QoS::AtMostOnce)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        res.status,
+        vec![
+            codec::SubscribeAckReason::GrantedQos1,
+            codec::SubscribeAckReason::TopicFilterInvalid,
+        ]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_unsubscribe_ack() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Unsubscribe(mut msg) => {
+                    assert_eq!(msg.get_user_property("prop"), Some(&"val".into()));
+                    for mut item in &mut msg {
+                        if item.topic() == "test" {
+                            item.success();
+                        } else {
+                            item.fail(codec::UnsubscribeAckReason::NoSubscriptionExisted);
+                        }
+                    }
+                    ok::<_, TestError>(
+                        msg.ack_reason("unsubscribed".into())
+                            .ack_user_property("ack".into(), "val".into())
+                            .ack(),
+                    )
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .unsubscribe()
+        .user_property("prop".into(), "val".into())
+        .topic_filter(ByteString::from_static("test"))
+        .topic_filter(ByteString::from_static("unknown"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        res.status,
+        vec![
+            codec::UnsubscribeAckReason::Success,
+            codec::UnsubscribeAckReason::NoSubscriptionExisted,
+        ]
+    );
+    assert_eq!(res.reason_string, Some("unsubscribed".into()));
+    assert_eq!(res.properties, vec![("ack".into(), "val".into())]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_properties() -> std::io::Result<()> {
+    let received = Arc::new(AtomicBool::new(false));
+    let received2 = received.clone();
+
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                let props = &p.packet().properties;
+                assert_eq!(props.message_expiry_interval, std::num::NonZeroU32::new(30));
+                assert_eq!(props.content_type, Some("text/plain".into()));
+                assert_eq!(props.response_topic, Some("response".into()));
+                assert_eq!(props.correlation_data, Some(Bytes::from_static(b"data")));
+                assert_eq!(props.is_utf8_payload, Some(true));
+                assert!(p.subscription_ids().is_empty());
+                received.store(true, Relaxed);
+                ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::from_static(b"payload"))
+        .message_expiry_interval(std::num::NonZeroU32::new(30).unwrap())
+        .content_type("text/plain".into())
+        .response_topic("response".into())
+        .correlation_data(Bytes::from_static(b"data"))
+        .utf8_payload(true)
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+    assert!(received.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_handle() -> std::io::Result<()> {
+    let content_type = Arc::new(std::sync::Mutex::new(None));
+    let content_type2 = content_type.clone();
+
+    let srv = server::test_server(move || {
+        let content_type = content_type2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                *content_type.lock().unwrap() = p.packet().properties.content_type.clone();
+                ok::<_, TestError>(p.ack())
+            })
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        sub.confirm(codec::QoS::AtLeastOnce);
+                    }
+                    ok::<_, TestError>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    let handle = sink.handle();
+    let handle2 = handle.clone();
+
+    ntex::rt::spawn(client.start_default());
+
+    let (tx, rx) = futures::channel::oneshot::channel();
+    std::thread::spawn(move || {
+        let res = futures::executor::block_on(async move {
+            let properties = codec::PublishProperties {
+                content_type: Some(ByteString::from_static("text/plain")),
+                ..Default::default()
+            };
+            let publish = handle
+                .publish_with_properties(
+                    ByteString::from_static("test"),
+                    Bytes::new(),
+                    codec::QoS::AtLeastOnce,
+                    properties,
+                )
+                .await;
+            let subscribe = handle
+                .subscribe(vec![(
+                    ByteString::from_static("test"),
+                    codec::QoS::AtLeastOnce.into(),
+                )])
+                .await;
+            handle.close();
+            (publish, subscribe)
+        });
+        let _ = tx.send(res);
+    });
+
+    let (publish, subscribe) = rx.await.unwrap();
+    assert_eq!(publish, Ok(codec::PublishAckReason::Success));
+    assert_eq!(subscribe.unwrap().status, vec![codec::SubscribeAckReason::GrantedQos1]);
+    assert_eq!(*content_type.lock().unwrap(), Some(ByteString::from_static("text/plain")));
+
+    delay_for(Duration::from_millis(50)).await;
+    assert!(!sink.is_open());
+    assert!(handle2.is_closed());
+    let res = handle2
+        .publish(ByteString::from_static("test"), Bytes::new(), codec::QoS::AtLeastOnce)
+        .await;
+    assert_eq!(res, Err(error::SendPacketError::Disconnected));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sink_introspection() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .receive_max(3)
+            .max_topic_alias(4)
+            .publish(|p: Publish| async move {
+                delay_for(Duration::from_millis(100)).await;
+                Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    assert_eq!(sink.receive_max(), 3);
+    assert_eq!(sink.topic_alias_max(), 4);
+    assert_eq!(sink.max_packet_size(), 0);
+    assert_eq!(sink.inflight(), 0);
+    assert_eq!(sink.waiters(), 0);
+
+    for _ in 0..4 {
+        let sink = sink.clone();
+        ntex::rt::spawn(async move {
+            let _ = sink
+                .publish(ByteString::from_static("test"), Bytes::new())
+                .send_at_least_once()
+                .await;
+        });
+    }
+    delay_for(Duration::from_millis(25)).await;
+    assert_eq!(sink.inflight(), 3);
+    assert_eq!(sink.credit(), 0);
+    assert_eq!(sink.waiters(), 1);
+
+    delay_for(Duration::from_millis(300)).await;
+    assert_eq!(sink.inflight(), 0);
+    assert_eq!(sink.credit(), 3);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_credit_timeout() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .receive_max(1)
+            .publish(|p: Publish| async move {
+                delay_for(Duration::from_millis(200)).await;
+                Ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let sink2 = sink.clone();
+    ntex::rt::spawn(async move {
+        let _ = sink2
+            .publish(ByteString::from_static("test"), Bytes::new())
+            .send_at_least_once()
+            .await;
+    });
+    delay_for(Duration::from_millis(25)).await;
+    assert_eq!(sink.credit(), 0);
+
+    assert!(!sink.ready_timeout(Duration::from_millis(25)).await);
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .send_at_least_once_timeout(Duration::from_millis(25))
+        .await;
+    assert_eq!(res, Err(error::PublishQos1Error::CreditTimeout));
+
+    // credit is available after ack
+    assert!(sink.ready_timeout(Duration::from_millis(500)).await);
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .send_at_least_once_timeout(Duration::from_millis(500))
+        .await;
+    assert!(res.is_ok());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_disconnect() -> std::io::Result<()> {
+    let disconnect = Arc::new(std::sync::Mutex::new(None));
+    let disconnect2 = disconnect.clone();
+
+    let srv = server::test_server(move || {
+        let disconnect = disconnect2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Disconnect(msg) => {
+                    *disconnect.lock().unwrap() = Some(msg.packet().clone());
+                    ok::<_, TestError>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // connect to server
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .session_expiry_interval(Duration::from_secs(10))
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    sink.disconnect(codec::DisconnectReasonCode::DisconnectWithWillMessage, |pkt| {
+        pkt.session_expiry_interval_secs = Some(60);
+        pkt.user_properties.push(("prop".into(), "val".into()));
+    });
+    assert!(!sink.is_open());
+
+    delay_for(Duration::from_millis(50)).await;
+    let pkt = disconnect.lock().unwrap().take().unwrap();
+    assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::DisconnectWithWillMessage);
+    assert_eq!(pkt.session_expiry_interval_secs, Some(60));
+    assert_eq!(pkt.user_properties, vec![("prop".into(), "val".into())]);
+
+    Ok(())
+}
+
+#[derive(Clone, Default)]
+struct TestMetrics {
+    opened: Arc<AtomicUsize>,
+    closed: Arc<AtomicUsize>,
+    failed: Arc<AtomicUsize>,
+    publishes: Arc<AtomicUsize>,
+    acks: Arc<AtomicUsize>,
+}
+
+impl MqttMetrics for TestMetrics {
+    fn packet_received(&self, packet_type: u8, size: usize) {
+        assert!(size > 0);
+        if packet_type == 0x30 {
+            self.publishes.fetch_add(1, Relaxed);
+        }
+    }
+
+    fn connection_opened(&self) {
+        self.opened.fetch_add(1, Relaxed);
+    }
+
+    fn connection_closed(&self) {
+        self.closed.fetch_add(1, Relaxed);
+    }
+
+    fn handshake_failed(&self) {
+        self.failed.fetch_add(1, Relaxed);
+    }
+
+    fn ack_latency(&self, packet_type: u8, _: Duration) {
+        assert_eq!(packet_type, 0x40);
+        self.acks.fetch_add(1, Relaxed);
+    }
+}
+
+#[ntex::test]
+async fn test_metrics() -> std::io::Result<()> {
+    let metrics = TestMetrics::default();
+    let srv_metrics = metrics.clone();
+
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .metrics(srv_metrics.clone())
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    // connect to server
+    let client_metrics = TestMetrics::default();
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .metrics(client_metrics.clone())
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    assert_eq!(metrics.opened.load(Relaxed), 1);
+    assert_eq!(metrics.publishes.load(Relaxed), 1);
+    assert_eq!(client_metrics.opened.load(Relaxed), 1);
+    assert_eq!(client_metrics.acks.load(Relaxed), 1);
+
+    sink.close();
+    delay_for(Duration::from_millis(50)).await;
+    assert_eq!(metrics.closed.load(Relaxed), 1);
+    assert_eq!(client_metrics.closed.load(Relaxed), 1);
+
+    // failed handshake
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Packet::PingRequest).await.unwrap();
+    delay_for(Duration::from_millis(50)).await;
+    assert_eq!(metrics.failed.load(Relaxed), 1);
+    assert_eq!(metrics.opened.load(Relaxed), 1);
+
+    Ok(())
+}
+
+#[derive(Clone, Default)]
+struct TestInspector {
+    inbound: Rc<RefCell<Vec<u8>>>,
+    outbound: Rc<RefCell<Vec<u8>>>,
+}
+
+impl PacketInspector<codec::Packet> for TestInspector {
+    fn inbound(&self, packet: &codec::Packet) {
+        self.inbound.borrow_mut().push(packet.packet_type());
+    }
+
+    fn outbound(&self, packet: &codec::Packet) {
+        self.outbound.borrow_mut().push(packet.packet_type());
+    }
+}
+
+#[ntex::test]
+async fn test_packet_inspector() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake).publish(|p: Publish| ok::<_, TestError>(p.ack())).finish()
+    });
+
+    let inspector = TestInspector::default();
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .metrics(ntex_mqtt::NoopMetrics)
+        .inspector(inspector.clone())
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+
+    // CONNECT, PUBLISH
+    assert_eq!(&*inspector.outbound.borrow(), &[0x10, 0x30]);
+    // CONNACK, PUBACK
+    assert_eq!(&*inspector.inbound.borrow(), &[0x20, 0x40]);
+
+    Ok(())
+}
+
+#[derive(Debug)]
+enum ReasonTestError {
+    Quota,
+    Fatal,
+    Init,
+}
+
+impl From<()> for ReasonTestError {
+    fn from(_: ()) -> Self {
+        ReasonTestError::Init
+    }
+}
+
+impl IntoReasonCode for ReasonTestError {
+    fn publish_reason(&self) -> Option<codec::PublishAckReason> {
+        match self {
+            ReasonTestError::Quota => Some(codec::PublishAckReason::QuotaExceeded),
+            _ => None,
+        }
+    }
+
+    fn disconnect_reason(&self) -> codec::DisconnectReasonCode {
+        codec::DisconnectReasonCode::AdministrativeAction
+    }
+
+    fn reason_string(&self) -> Option<ByteString> {
+        Some(ByteString::from(format!("{:?}", self)))
+    }
+}
+
+async fn reason_handshake<Io>(
+    packet: Handshake<Io>,
+) -> Result<HandshakeAck<Io, St>, ReasonError<ReasonTestError>> {
+    Ok(packet.ack(St))
+}
+
+#[ntex::test]
+async fn test_into_reason_code() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(reason_handshake)
+            .control(|msg| match msg {
+                ControlMessage::Error(err) => {
+                    ok::<_, ReasonError<ReasonTestError>>(err.ack_reason())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .publish(|p: Publish| {
+                futures::future::ready(match p.publish_topic() {
+                    "quota" => Err(ReasonError(ReasonTestError::Quota)),
+                    "fatal" => Err(ReasonError(ReasonTestError::Fatal)),
+                    _ => Ok(p.ack()),
+                })
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed
+        .send(codec::Publish { topic: ByteString::from("quota"), ..pkt_publish() }.into())
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::QuotaExceeded,
+            properties: Default::default(),
+            reason_string: Some(ByteString::from("Quota")),
+        })
+    );
+
+    framed
+        .send(
+            codec::Publish {
+                topic: ByteString::from("fatal"),
+                packet_id: Some(NonZeroU16::new(2).unwrap()),
+                ..pkt_publish()
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::AdministrativeAction,
+            reason_string: Some(ByteString::from("Fatal")),
+            ..Default::default()
+        })
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_release() -> std::io::Result<()> {
+    let released = Arc::new(AtomicUsize::new(0));
+    let released2 = released.clone();
+
+    let srv = server::test_server(move || {
+        let released = released2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .publish_release(true)
+            .control(move |msg| match msg {
+                ControlMessage::PublishRelease(msg) => {
+                    assert_eq!(msg.publish().unwrap().topic, "test");
+                    released.store(msg.packet_id().get() as usize, Relaxed);
+                    ok::<_, TestError>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed
+        .send(
+            codec::Publish {
+                qos: codec::QoS::ExactlyOnce,
+                packet_id: Some(NonZeroU16::new(3).unwrap()),
+                ..pkt_publish()
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::PublishReceived(_)));
+    assert_eq!(released.load(Relaxed), 0);
+
+    let rel = codec::PublishAck2 {
+        packet_id: NonZeroU16::new(3).unwrap(),
+        reason_code: codec::PublishAck2Reason::Success,
+        properties: Default::default(),
+        reason_string: None,
+    };
+    framed.send(codec::Packet::PublishRelease(rel.clone())).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PublishComplete(rel));
+    assert_eq!(released.load(Relaxed), 3);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_will_requested() -> std::io::Result<()> {
+    let wills = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let wills2 = wills.clone();
+
+    let srv = server::test_server(move || {
+        let wills = wills2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::WillRequested(msg) => {
+                    wills.lock().unwrap().push(msg.publish().payload);
+                    ok::<_, TestError>(msg.ack())
+                }
+                ControlMessage::Disconnect(msg) => ok(msg.ack()),
+                ControlMessage::Closed(msg) => ok(msg.ack()),
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let connect = |reason: &'static str| codec::Connect {
+        last_will: Some(codec::LastWill {
+            qos: codec::QoS::AtMostOnce,
+            retain: false,
+            topic: ByteString::from_static("will"),
+            message: Bytes::from_static(reason.as_bytes()),
+            will_delay_interval_sec: None,
+            correlation_data: None,
+            message_expiry_interval: None,
+            content_type: None,
+            user_properties: Default::default(),
+            is_utf8_payload: None,
+            response_topic: None,
+            unknown_properties: Bytes::new(),
+        }),
+        ..codec::Connect::default().client_id("user")
+    };
+
+    // connection is dropped
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Packet::Connect(connect("dropped"))).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    drop(framed);
+    delay_for(Duration::from_millis(50)).await;
+
+    // normal disconnect, will is discarded
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Packet::Connect(connect("normal"))).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed.send(codec::Packet::Disconnect(codec::Disconnect::default())).await.unwrap();
+    delay_for(Duration::from_millis(50)).await;
+    drop(framed);
+
+    // disconnect with will message
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Packet::Connect(connect("with_will"))).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed
+        .send(codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::DisconnectWithWillMessage,
+        )))
+        .await
+        .unwrap();
+    delay_for(Duration::from_millis(50)).await;
+    drop(framed);
+    delay_for(Duration::from_millis(50)).await;
+
+    assert_eq!(
+        &*wills.lock().unwrap(),
+        &[Bytes::from_static(b"dropped"), Bytes::from_static(b"with_will")]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_closed_details() -> std::io::Result<()> {
+    let closed = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let closed2 = closed.clone();
+
+    let srv = server::test_server(move || {
+        let closed = closed2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::fn_factory_with_config(|session: Session<St>| {
+                ok::<_, TestError>(ntex::fn_service(move |p: Publish| {
+                    session.sink().close();
+                    ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .control(move |msg| match msg {
+                ControlMessage::Closed(msg) => {
+                    closed
+                        .lock()
+                        .unwrap()
+                        .push((msg.is_peer_initiated(), msg.io_error().is_some()));
+                    ok::<_, TestError>(msg.ack())
+                }
+                ControlMessage::Disconnect(msg) => ok(msg.ack()),
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // peer closes connection
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed.send(codec::Packet::Disconnect(codec::Disconnect::default())).await.unwrap();
+    delay_for(Duration::from_millis(50)).await;
+    drop(framed);
+    delay_for(Duration::from_millis(50)).await;
+
+    // server closes connection
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed.send(pkt_publish().into()).await.unwrap();
+    delay_for(Duration::from_millis(50)).await;
+    drop(framed);
+    delay_for(Duration::from_millis(50)).await;
+
+    assert_eq!(&*closed.lock().unwrap(), &[(true, false), (false, false)]);
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+struct Revoked(&'static str);
+
+#[ntex::test]
+async fn test_app_events() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(ntex::fn_factory_with_config(|session: Session<St>| {
+                let events = session.sink().events::<Revoked>();
+                ok::<_, TestError>(ntex::fn_service(move |p: Publish| {
+                    assert!(events.send(Revoked("token")).is_ok());
+                    ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .control(move |msg: ControlMessage<TestError>| {
+                if let ControlMessage::Event(ref ev) = msg {
+                    assert!(ev.is::<Revoked>());
+                    if ev.get::<Revoked>() == Some(&Revoked("token")) {
+                        return ok(msg.disconnect_with(codec::Disconnect::new(
+                            codec::DisconnectReasonCode::AdministrativeAction,
+                        )));
+                    }
+                }
+                ok(msg.disconnect())
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(codec::Connect::default().client_id("user")))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    framed.send(pkt_publish().into()).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::PublishAck(_)));
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::AdministrativeAction
+        ))
+    );
+
+    Ok(())
+}
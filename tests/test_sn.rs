@@ -0,0 +1,455 @@
+#![cfg(feature = "mqtt-sn")]
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::{net::SocketAddr, time::Duration};
+
+use futures::future::ok;
+use ntex::rt::net::UdpSocket;
+use ntex::rt::time::delay_for;
+use ntex::server;
+use ntex::util::{ByteString, Bytes};
+
+use ntex_mqtt::sn::codec::{
+    Codec, Connect, Packet, Publish, QoS, ReturnCode, Subscribe, Topic, TopicFilter,
+};
+use ntex_mqtt::{sn::Gateway, v3};
+
+async fn send(socket: &UdpSocket, pkt: Packet) {
+    let buf = Codec::new().encode_packet(pkt).unwrap();
+    socket.send(&buf).await.unwrap();
+}
+
+async fn recv(socket: &UdpSocket) -> Packet {
+    let mut buf = vec![0; 1024];
+    let size = socket.recv(&mut buf).await.unwrap();
+    Codec::new().decode_packet(&Bytes::copy_from_slice(&buf[..size])).unwrap().unwrap().0
+}
+
+type Publishes = Arc<Mutex<Vec<(ByteString, v3::QoS, Bytes)>>>;
+
+/// Upstream server records publishes and echoes QoS 0 publishes to `<topic>/echo`,
+/// publish to `close` topic closes connection
+fn upstream(publishes: Publishes, closed: Arc<AtomicUsize>) -> server::TestServer {
+    server::test_server(move || {
+        let publishes = publishes.clone();
+        let closed = closed.clone();
+        v3::MqttServer::new(|con: v3::Handshake<_>| ok::<_, ()>(con.ack((), false)))
+            .publish(ntex::fn_factory_with_config(move |session: v3::Session<()>| {
+                let publishes = publishes.clone();
+                ok::<_, ()>(ntex::fn_service(move |p: v3::Publish| {
+                    let topic = p.packet().topic.clone();
+                    if p.qos() == v3::QoS::AtMostOnce {
+                        let _ = session
+                            .sink()
+                            .publish(
+                                ByteString::from(format!("{}/echo", topic)),
+                                p.payload().clone(),
+                            )
+                            .send_at_most_once();
+                    }
+                    if topic == "close" {
+                        session.sink().force_close();
+                    }
+                    publishes.lock().unwrap().push((topic, p.qos(), p.take_payload()));
+                    ok::<_, ()>(())
+                }))
+            }))
+            .control(move |msg| match msg {
+                v3::ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        sub.confirm(sub.qos());
+                    }
+                    ok(msg.ack())
+                }
+                v3::ControlMessage::Unsubscribe(msg) => ok(msg.ack()),
+                v3::ControlMessage::Ping(msg) => ok(msg.ack()),
+                v3::ControlMessage::Closed(msg) => {
+                    closed.fetch_add(1, Relaxed);
+                    ok(msg.ack())
+                }
+                msg => ok(msg.disconnect()),
+            })
+            .finish()
+    })
+}
+
+async fn start(gateway: Gateway<SocketAddr>) -> std::io::Result<SocketAddr> {
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let gw_addr = socket.local_addr()?;
+    ntex::rt::spawn(async move {
+        let _ = gateway.run(socket).await;
+    });
+    Ok(gw_addr)
+}
+
+async fn bind(gw_addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    client.connect(gw_addr).await?;
+    Ok(client)
+}
+
+fn connect(duration: u16) -> Packet {
+    Packet::Connect(Connect {
+        will: false,
+        clean_session: true,
+        duration,
+        client_id: ByteString::from_static("sensor"),
+    })
+}
+
+fn register(msg_id: u16, topic: &'static str) -> Packet {
+    Packet::Register { topic_id: 0, msg_id, topic_name: ByteString::from_static(topic) }
+}
+
+fn publish(qos: QoS, topic_id: u16, msg_id: u16, data: &'static [u8]) -> Packet {
+    Packet::Publish(Publish {
+        dup: false,
+        retain: false,
+        qos,
+        topic: Topic::Id(topic_id),
+        msg_id,
+        data: Bytes::from_static(data),
+    })
+}
+
+#[ntex::test]
+async fn test_gateway() -> std::io::Result<()> {
+    let publishes = Arc::new(Mutex::new(Vec::new()));
+    let publishes2 = publishes.clone();
+
+    let srv = server::test_server(move || {
+        let publishes = publishes2.clone();
+        v3::MqttServer::new(|con: v3::Handshake<_>| ok::<_, ()>(con.ack((), false)))
+            .publish(move |p: v3::Publish| {
+                publishes.lock().unwrap().push((
+                    p.packet().topic.clone(),
+                    p.qos(),
+                    p.take_payload(),
+                ));
+                ok::<_, ()>(())
+            })
+            .finish()
+    });
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let gw_addr = socket.local_addr()?;
+    let gateway = Gateway::new(srv.addr()).predefined_topic(1, "sensors/temp");
+    ntex::rt::spawn(async move {
+        let _ = gateway.run(socket).await;
+    });
+
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    client.connect(gw_addr).await?;
+
+    send(&client, Packet::SearchGateway { radius: 0 }).await;
+    assert_eq!(recv(&client).await, Packet::GatewayInfo { gw_id: 1, gw_addr: Bytes::new() });
+
+    send(
+        &client,
+        Packet::Connect(Connect {
+            will: false,
+            clean_session: true,
+            duration: 30,
+            client_id: ByteString::from_static("sensor"),
+        }),
+    )
+    .await;
+    assert_eq!(recv(&client).await, Packet::ConnectAck(ReturnCode::Accepted));
+
+    send(
+        &client,
+        Packet::Register {
+            topic_id: 0,
+            msg_id: 1,
+            topic_name: ByteString::from_static("sensors/hum"),
+        },
+    )
+    .await;
+    assert_eq!(
+        recv(&client).await,
+        Packet::RegisterAck { topic_id: 1, msg_id: 1, return_code: ReturnCode::Accepted }
+    );
+
+    send(
+        &client,
+        Packet::Publish(Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtLeastOnce,
+            topic: Topic::Id(1),
+            msg_id: 2,
+            data: Bytes::from_static(b"55"),
+        }),
+    )
+    .await;
+    assert_eq!(
+        recv(&client).await,
+        Packet::PublishAck { topic_id: 1, msg_id: 2, return_code: ReturnCode::Accepted }
+    );
+
+    // unknown topic id
+    send(
+        &client,
+        Packet::Publish(Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtLeastOnce,
+            topic: Topic::Id(9),
+            msg_id: 3,
+            data: Bytes::from_static(b"55"),
+        }),
+    )
+    .await;
+    assert_eq!(
+        recv(&client).await,
+        Packet::PublishAck { topic_id: 9, msg_id: 3, return_code: ReturnCode::InvalidTopicId }
+    );
+
+    // QoS -1 publish from unconnected client
+    let sensor = UdpSocket::bind("127.0.0.1:0").await?;
+    sensor.connect(gw_addr).await?;
+    send(
+        &sensor,
+        Packet::Publish(Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::NoConnection,
+            topic: Topic::Predefined(1),
+            msg_id: 0,
+            data: Bytes::from_static(b"21.5"),
+        }),
+    )
+    .await;
+    delay_for(Duration::from_millis(100)).await;
+
+    assert_eq!(
+        &publishes.lock().unwrap()[..],
+        &[
+            (
+                ByteString::from_static("sensors/hum"),
+                v3::QoS::AtLeastOnce,
+                Bytes::from_static(b"55")
+            ),
+            (
+                ByteString::from_static("sensors/temp"),
+                v3::QoS::AtMostOnce,
+                Bytes::from_static(b"21.5")
+            ),
+        ]
+    );
+
+    send(&client, Packet::Disconnect { duration: None }).await;
+    assert_eq!(recv(&client).await, Packet::Disconnect { duration: None });
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_gateway_subscribe() -> std::io::Result<()> {
+    let srv = upstream(Publishes::default(), Arc::new(AtomicUsize::new(0)));
+    let gw_addr = start(Gateway::new(srv.addr())).await?;
+    let client = bind(gw_addr).await?;
+
+    send(&client, connect(30)).await;
+    assert_eq!(recv(&client).await, Packet::ConnectAck(ReturnCode::Accepted));
+
+    send(
+        &client,
+        Packet::Subscribe(Subscribe {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            msg_id: 1,
+            topic: TopicFilter::Name(ByteString::from_static("sensors/#")),
+        }),
+    )
+    .await;
+    assert_eq!(
+        recv(&client).await,
+        Packet::SubscribeAck {
+            qos: QoS::AtLeastOnce,
+            topic_id: 0,
+            msg_id: 1,
+            return_code: ReturnCode::Accepted
+        }
+    );
+
+    send(&client, register(2, "sensors/hum")).await;
+    assert_eq!(
+        recv(&client).await,
+        Packet::RegisterAck { topic_id: 1, msg_id: 2, return_code: ReturnCode::Accepted }
+    );
+
+    // upstream server echoes publish, gateway registers topic before forwarding
+    send(&client, publish(QoS::AtMostOnce, 1, 0, b"55")).await;
+    assert_eq!(
+        recv(&client).await,
+        Packet::Register {
+            topic_id: 2,
+            msg_id: 1,
+            topic_name: ByteString::from_static("sensors/hum/echo")
+        }
+    );
+    send(
+        &client,
+        Packet::RegisterAck { topic_id: 2, msg_id: 1, return_code: ReturnCode::Accepted },
+    )
+    .await;
+    assert_eq!(recv(&client).await, publish(QoS::AtMostOnce, 2, 0, b"55"));
+
+    // registered topic is reused
+    send(&client, publish(QoS::AtMostOnce, 1, 0, b"56")).await;
+    assert_eq!(recv(&client).await, publish(QoS::AtMostOnce, 2, 0, b"56"));
+
+    send(
+        &client,
+        Packet::Unsubscribe {
+            msg_id: 3,
+            topic: TopicFilter::Name(ByteString::from_static("sensors/#")),
+        },
+    )
+    .await;
+    assert_eq!(recv(&client).await, Packet::UnsubscribeAck { msg_id: 3 });
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_gateway_qos2() -> std::io::Result<()> {
+    let publishes = Publishes::default();
+    let srv = upstream(publishes.clone(), Arc::new(AtomicUsize::new(0)));
+    let gw_addr = start(Gateway::new(srv.addr()).max_pending(1)).await?;
+    let client = bind(gw_addr).await?;
+
+    send(&client, connect(30)).await;
+    assert_eq!(recv(&client).await, Packet::ConnectAck(ReturnCode::Accepted));
+    send(&client, register(1, "sensors/hum")).await;
+    assert_eq!(
+        recv(&client).await,
+        Packet::RegisterAck { topic_id: 1, msg_id: 1, return_code: ReturnCode::Accepted }
+    );
+
+    send(&client, publish(QoS::ExactlyOnce, 1, 2, b"55")).await;
+    assert_eq!(recv(&client).await, Packet::PublishReceived { msg_id: 2 });
+
+    // max number of pending publishes is reached
+    send(&client, publish(QoS::ExactlyOnce, 1, 3, b"56")).await;
+    assert_eq!(
+        recv(&client).await,
+        Packet::PublishAck { topic_id: 1, msg_id: 3, return_code: ReturnCode::Congestion }
+    );
+
+    // message is published after PUBREL
+    delay_for(Duration::from_millis(100)).await;
+    assert!(publishes.lock().unwrap().is_empty());
+
+    // PUBCOMP is sent after upstream publish is completed
+    send(&client, Packet::PublishRelease { msg_id: 2 }).await;
+    assert_eq!(recv(&client).await, Packet::PublishComplete { msg_id: 2 });
+    assert_eq!(
+        &publishes.lock().unwrap()[..],
+        &[(
+            ByteString::from_static("sensors/hum"),
+            v3::QoS::ExactlyOnce,
+            Bytes::from_static(b"55")
+        )]
+    );
+
+    // re-transmitted PUBREL
+    send(&client, Packet::PublishRelease { msg_id: 2 }).await;
+    assert_eq!(recv(&client).await, Packet::PublishComplete { msg_id: 2 });
+    assert_eq!(publishes.lock().unwrap().len(), 1);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_gateway_clients() -> std::io::Result<()> {
+    let closed = Arc::new(AtomicUsize::new(0));
+    let srv = upstream(Publishes::default(), closed.clone());
+    let gw_addr = start(Gateway::new(srv.addr()).max_clients(1)).await?;
+    let client = bind(gw_addr).await?;
+
+    send(&client, connect(30)).await;
+    assert_eq!(recv(&client).await, Packet::ConnectAck(ReturnCode::Accepted));
+    send(&client, register(1, "a/b")).await;
+    assert_eq!(
+        recv(&client).await,
+        Packet::RegisterAck { topic_id: 1, msg_id: 1, return_code: ReturnCode::Accepted }
+    );
+
+    // reconnect closes previous upstream connection and starts new session
+    send(&client, connect(30)).await;
+    assert_eq!(recv(&client).await, Packet::ConnectAck(ReturnCode::Accepted));
+    delay_for(Duration::from_millis(100)).await;
+    assert_eq!(closed.load(Relaxed), 1);
+    send(&client, register(2, "c/d")).await;
+    assert_eq!(
+        recv(&client).await,
+        Packet::RegisterAck { topic_id: 1, msg_id: 2, return_code: ReturnCode::Accepted }
+    );
+
+    // max number of clients is reached
+    let other = bind(gw_addr).await?;
+    send(&other, connect(30)).await;
+    assert_eq!(recv(&other).await, Packet::ConnectAck(ReturnCode::Congestion));
+
+    // client expires after one and a half times of keep-alive
+    send(&client, connect(1)).await;
+    assert_eq!(recv(&client).await, Packet::ConnectAck(ReturnCode::Accepted));
+    delay_for(Duration::from_millis(2000)).await;
+    assert_eq!(closed.load(Relaxed), 3);
+    send(&client, register(3, "a/b")).await;
+    assert_eq!(
+        recv(&client).await,
+        Packet::RegisterAck { topic_id: 0, msg_id: 3, return_code: ReturnCode::NotSupported }
+    );
+
+    send(&other, connect(30)).await;
+    assert_eq!(recv(&other).await, Packet::ConnectAck(ReturnCode::Accepted));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_gateway_upstream_reconnect() -> std::io::Result<()> {
+    let publishes = Publishes::default();
+    let closed = Arc::new(AtomicUsize::new(0));
+    let srv = upstream(publishes.clone(), closed.clone());
+    let gateway = Gateway::new(srv.addr())
+        .predefined_topic(1, "close")
+        .predefined_topic(2, "sensors/temp");
+    let gw_addr = start(gateway).await?;
+    let sensor = bind(gw_addr).await?;
+    delay_for(Duration::from_millis(100)).await;
+
+    let qos_1 = |id, data| {
+        Packet::Publish(Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::NoConnection,
+            topic: Topic::Predefined(id),
+            msg_id: 0,
+            data: Bytes::from_static(data),
+        })
+    };
+
+    // upstream server drops gateway's connection
+    send(&sensor, qos_1(1, b"")).await;
+    delay_for(Duration::from_millis(100)).await;
+    assert_eq!(closed.load(Relaxed), 1);
+
+    // gateway re-connects to upstream server
+    delay_for(Duration::from_millis(1500)).await;
+    send(&sensor, qos_1(2, b"21.5")).await;
+    delay_for(Duration::from_millis(100)).await;
+    assert_eq!(
+        publishes.lock().unwrap().last(),
+        Some(&(
+            ByteString::from_static("sensors/temp"),
+            v3::QoS::AtMostOnce,
+            Bytes::from_static(b"21.5")
+        ))
+    );
+
+    Ok(())
+}